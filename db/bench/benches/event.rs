@@ -18,7 +18,7 @@ fn bench_event(c: &mut Criterion) {
 
     group.bench_function("content token", |b| {
         b.iter(|| {
-            let s: &str = event.content().as_ref();
+            let s = event.content();
             let tokens = s.tokenize();
             for t in tokens {
                 black_box(t.lemma());
@@ -27,7 +27,7 @@ fn bench_event(c: &mut Criterion) {
     });
     group.bench_function("content segment", |b| {
         b.iter(|| {
-            let s: &str = event.content().as_ref();
+            let s = event.content();
             let tokens = s.segment();
             for t in tokens {
                 black_box(t.lemma());
@@ -36,7 +36,7 @@ fn bench_event(c: &mut Criterion) {
     });
     group.bench_function("content segment with hash", |b| {
         b.iter(|| {
-            let s: &str = event.content().as_ref();
+            let s = event.content();
             let tokens = s.segment();
             for t in tokens {
                 let mut hasher = XxHash32::with_seed(0);
@@ -0,0 +1,157 @@
+//! Bloom filter of the stored event ids, the ids not in it are new events,
+//! so the existence check in the database is skipped for them.
+//!
+//! The filter is saved in the file `bloom` of the database directory with the next uid of the events,
+//! the events stored later are added when the database is opened.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"nostrbf1";
+const MIN_CAPACITY: u64 = 100_000;
+
+/// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    /// The filter of the capacity with the false positive rate
+    pub fn new(capacity: u64, fp_rate: f64) -> Self {
+        let fp_rate = fp_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (capacity.max(MIN_CAPACITY) as f64 * -fp_rate.ln() / (ln2 * ln2)).ceil();
+        let hashes = (-fp_rate.log2()).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes,
+        }
+    }
+
+    /// the positions by double hashing of the mixed words of the id
+    fn positions(id: &[u8], hashes: u32, len: usize) -> impl Iterator<Item = usize> {
+        let (mut h1, mut h2) = (0u64, 0u64);
+        for (i, chunk) in id.chunks(8).enumerate() {
+            let mut buf = [0u8; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            let word = mix(u64::from_le_bytes(buf) ^ i as u64);
+            h1 = mix(h1 ^ word);
+            h2 = h2.rotate_left(17) ^ word;
+        }
+        let h2 = mix(h2) | 1;
+        let len = len as u64 * 64;
+        (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub fn insert(&mut self, id: &[u8]) {
+        for p in Self::positions(id, self.hashes, self.bits.len()) {
+            self.bits[p / 64] |= 1 << (p % 64);
+        }
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        Self::positions(id, self.hashes, self.bits.len())
+            .all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+
+    /// the size of the bits in bytes
+    pub fn size(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Save with the next uid, replace the file after it's written
+    pub fn save(&self, path: &Path, seq: u64) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            w.write_all(MAGIC)?;
+            w.write_all(&seq.to_le_bytes())?;
+            w.write_all(&self.hashes.to_le_bytes())?;
+            w.write_all(&(self.bits.len() as u64).to_le_bytes())?;
+            for word in &self.bits {
+                w.write_all(&word.to_le_bytes())?;
+            }
+            w.into_inner()?.sync_all()?;
+        }
+        fs::rename(tmp, path)
+    }
+
+    /// Load the filter and the next uid of the events in it
+    pub fn load(path: &Path) -> io::Result<(Self, u64)> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid bloom file",
+            ));
+        }
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)?;
+        let seq = u64::from_le_bytes(buf);
+        let mut hashes = [0u8; 4];
+        r.read_exact(&mut hashes)?;
+        let hashes = u32::from_le_bytes(hashes);
+        r.read_exact(&mut buf)?;
+        let len = u64::from_le_bytes(buf) as usize;
+        if hashes == 0 || len == 0 || len as u64 * 8 != fs::metadata(path)?.len() - 28 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid bloom file",
+            ));
+        }
+        let mut bits = Vec::with_capacity(len);
+        for _ in 0..len {
+            r.read_exact(&mut buf)?;
+            bits.push(u64::from_le_bytes(buf));
+        }
+        Ok((Self { bits, hashes }, seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use secp256k1::rand::random;
+
+    #[test]
+    fn bloom() -> Result<()> {
+        let mut bloom = Bloom::new(1000, 0.01);
+        assert_eq!(bloom.hashes, 7);
+        let ids = (0..1000).map(|_| random::<[u8; 32]>()).collect::<Vec<_>>();
+        for id in &ids {
+            bloom.insert(id);
+        }
+        assert!(ids.iter().all(|id| bloom.contains(id)));
+        let positives = (0..10000)
+            .filter(|_| bloom.contains(&random::<[u8; 32]>()))
+            .count();
+        assert!(positives < 200, "{}", positives);
+        // the ids differ in the last bytes
+        bloom.insert(&[1; 32]);
+        let mut id = [1; 32];
+        id[31] = 2;
+        assert!(!bloom.contains(&id));
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("bloom");
+        bloom.save(&path, 1000)?;
+        assert_eq!(Bloom::load(&path)?, (bloom, 1000));
+        fs::write(&path, b"nostrbf1")?;
+        assert!(Bloom::load(&path).is_err());
+        Ok(())
+    }
+}
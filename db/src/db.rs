@@ -1,17 +1,24 @@
 use crate::{
+    bloom::Bloom,
     error::Error,
     key::{concat, concat_sep, encode_replace_key, u16_to_ver, u64_to_ver, IndexKey},
-    ArchivedEventIndex, Event, EventIndex, Filter, FromEventData, Stats,
+    now, ArchivedEventIndex, Event, EventIndex, Filter, FromEventData, Stats,
 };
 use nostr_kv::{
     lmdb::{Db as Lmdb, Iter as LmdbIter, *},
-    scanner::{Group, GroupItem, MatchResult, Scanner},
+    scanner::{Group, GroupItem, MatchResult, Scanner, TimeKey},
 };
-
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::BTreeMap,
+    fmt::{self, Display},
     marker::PhantomData,
-    ops::Bound,
-    path::Path,
+    ops::{Bound, Range},
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -60,7 +67,36 @@ pub struct Db {
     t_expiration: Tree,
     // word time
     t_word: Tree,
+    // pubkey => created_at of the vanish request
+    t_vanish: Tree,
+    // kind:value => ban
+    t_ban: Tree,
+    // id => tombstone of the taken down event
+    t_tombstone: Tree,
+    // kind:value => reputation
+    t_reputation: Tree,
+    // code:<code> => invite, member:<pubkey> => member
+    t_invite: Tree,
+    // counter:time[:kind] => count, the rolled-up statistics of the stored events
+    t_stat: Tree,
     seq: Arc<AtomicU64>,
+    // the bloom filter of the stored ids, saved in the file `bloom`
+    path: PathBuf,
+    bloom: Arc<RwLock<BloomState>>,
+    // called with the id of each deleted event, ie: to forget the cached ids
+    on_delete: Arc<RwLock<Option<DeleteHook>>>,
+    // called before and after the deleting writes of the db are committed, ie: to drop the cached results
+    on_delete_commit: Arc<RwLock<Option<(CommitHook, CommitHook)>>>,
+}
+
+type DeleteHook = Box<dyn Fn(&[u8]) + Send + Sync>;
+type CommitHook = Box<dyn Fn() + Send + Sync>;
+
+#[derive(Default)]
+struct BloomState {
+    filter: Option<Bloom>,
+    // the ids put while the filter is rebuilt
+    pending: Option<Vec<Vec<u8>>>,
 }
 
 fn u64_from_bytes(bytes: &[u8]) -> Result<u64, Error> {
@@ -71,13 +107,13 @@ fn u16_from_bytes(bytes: &[u8]) -> Result<u16, Error> {
     Ok(u16::from_be_bytes(bytes.try_into()?))
 }
 
-// Get the latest seq from db
-fn latest_seq(db: &Lmdb, tree: &Tree) -> Result<u64, Error> {
+// Get the next seq from db
+fn next_seq(db: &Lmdb, tree: &Tree) -> Result<u64, Error> {
     let txn = db.reader()?;
     let mut iter = txn.iter_from(tree, Bound::Unbounded::<Vec<u8>>, true);
     if let Some(item) = iter.next() {
         let (k, _) = item?;
-        u64_from_bytes(k)
+        Ok(u64_from_bytes(k)? + 1)
     } else {
         Ok(0)
     }
@@ -103,19 +139,14 @@ impl Db {
         let pubkey = index_event.pubkey();
 
         // word
-        let bytes = writer.get(&self.t_uid_word, uid)?;
-        if let Some(bytes) = bytes {
-            let bytes = bytes.to_vec();
-            writer.del(&self.t_uid_word, uid, None)?;
-            let word = unsafe { rkyv::archived_root::<Vec<Vec<u8>>>(&bytes) };
-            for item in word.as_slice() {
-                writer.del(&self.t_word, IndexKey::encode_word(item, time), Some(uid))?;
-            }
-        }
+        self.del_words(writer, uid, time)?;
 
         writer.del(&self.t_data, uid, None)?;
         writer.del(&self.t_index, uid, None)?;
         writer.del(&self.t_id_uid, index_event.id(), None)?;
+        if let Some(hook) = self.on_delete.read().as_ref() {
+            hook(index_event.id());
+        }
 
         writer.del(
             &self.t_id,
@@ -180,17 +211,19 @@ impl Db {
         event: &Event,
         uid: &Vec<u8>,
         replace_key: &Option<Vec<u8>>,
-    ) -> Result<(), Error> {
+    ) -> Result<u64, Error> {
         let index_event = event.index();
 
         // put event
         let time = index_event.created_at();
         let json = encode_event(event)?;
+        let mut size = json.len();
 
         writer.put(&self.t_data, uid, json)?;
 
         // put index
         let bytes = index_event.to_bytes()?;
+        size += bytes.len();
         writer.put(&self.t_index, uid, bytes)?;
 
         // put view
@@ -249,7 +282,62 @@ impl Db {
         }
 
         // word
+        self.put_words(writer, uid, event)?;
+        Ok(size as u64)
+    }
+
+    /// The pubkey has stored events
+    fn has_pubkey(&self, writer: &Writer, pubkey: &[u8]) -> Result<bool, Error> {
+        let mut iter = writer.iter_from(&self.t_pubkey, Bound::Included(pubkey), false);
+        Ok(match iter.next() {
+            Some(item) => item?.0.starts_with(pubkey),
+            None => false,
+        })
+    }
+
+    fn add_stat(&self, writer: &mut Writer, key: &[u8], value: u64) -> Result<(), Error> {
+        let old = match writer.get(&self.t_stat, key)? {
+            Some(v) => u64_from_bytes(v)?,
+            None => 0,
+        };
+        writer.put(&self.t_stat, key, (old + value).to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Count the stored event in the rolled-up statistics at the time
+    fn put_stats(
+        &self,
+        writer: &mut Writer,
+        kind: u16,
+        bytes: u64,
+        new_pubkey: bool,
+        time: u64,
+    ) -> Result<(), Error> {
+        let hour = time / HOUR * HOUR;
+        self.add_stat(writer, &stat_key(STAT_EVENTS, hour, Some(kind)), 1)?;
+        self.add_stat(writer, &stat_key(STAT_BYTES, hour, None), bytes)?;
+        if new_pubkey {
+            self.add_stat(writer, &stat_key(STAT_PUBKEYS, time / DAY * DAY, None), 1)?;
+        }
+        Ok(())
+    }
+
+    fn del_words(&self, writer: &mut Writer, uid: &[u8], time: u64) -> Result<(), Error> {
+        let bytes = writer.get(&self.t_uid_word, uid)?;
+        if let Some(bytes) = bytes {
+            let bytes = bytes.to_vec();
+            writer.del(&self.t_uid_word, uid, None)?;
+            let word = unsafe { rkyv::archived_root::<Vec<Vec<u8>>>(&bytes) };
+            for item in word.as_slice() {
+                writer.del(&self.t_word, IndexKey::encode_word(item, time), Some(uid))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn put_words(&self, writer: &mut Writer, uid: &[u8], event: &Event) -> Result<(), Error> {
         let words = &event.words;
+        let time = event.created_at();
         if !words.is_empty() {
             let bytes =
                 rkyv::to_bytes::<_, 256>(words).map_err(|e| Error::Serialization(e.to_string()))?;
@@ -320,12 +408,109 @@ fn get_uid<K: AsRef<[u8]>, T: Transaction>(
     Ok(reader.get(id_tree, event_id)?.map(|v| v.to_vec()))
 }
 
+/// A ban of a pubkey, an ip or an event id, kept by the management api
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ban {
+    pub reason: String,
+    /// unix timestamp, never expires when 0
+    pub expires_at: u64,
+}
+
+impl Ban {
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+/// The tombstone of an event taken down by the operator, ie: illegal content,
+/// the event is removed and its id is never stored again
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tombstone {
+    pub reason: String,
+    /// unix timestamp of the takedown
+    pub created_at: u64,
+}
+
+/// The reputation of an ip or a pubkey, the score grows with the abuse signals
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Reputation {
+    pub score: f64,
+    /// unix timestamp of the score
+    pub updated_at: u64,
+    /// unix timestamp, banned by the score until it
+    pub banned_until: u64,
+}
+
+/// An invite code minted by the operator or a member, redeemed to admit a pubkey
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Invite {
+    pub note: String,
+    /// the pubkey of the member minting it, empty for the operator
+    #[serde(default)]
+    pub created_by: String,
+    pub created_at: u64,
+    /// unix timestamp, never expires when 0
+    pub expires_at: u64,
+    /// the times it can be redeemed, unlimited when 0
+    pub max_uses: u32,
+    pub uses: u32,
+}
+
+impl Invite {
+    pub fn usable(&self, now: u64) -> bool {
+        (self.expires_at == 0 || self.expires_at > now)
+            && (self.max_uses == 0 || self.uses < self.max_uses)
+    }
+}
+
+/// A pubkey admitted by an invite code
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Member {
+    pub code: String,
+    pub admitted_at: u64,
+    /// the member minting the code, empty for the operator
+    #[serde(default)]
+    pub invited_by: String,
+}
+
+const HOUR: u64 = 3600;
+const DAY: u64 = 86400;
+/// events stored by hour and kind
+const STAT_EVENTS: u8 = b'e';
+/// bytes stored by hour
+const STAT_BYTES: u8 = b'b';
+/// new pubkeys by day
+const STAT_PUBKEYS: u8 = b'p';
+
+/// The rolled-up counters of the stored events by the start time of the hour or the day,
+/// counted when the events are stored, the deleted events are not subtracted
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeSeries {
+    /// events stored in each hour by kind
+    pub events: BTreeMap<u64, BTreeMap<u16, u64>>,
+    /// bytes of the events stored in each hour
+    pub bytes: BTreeMap<u64, u64>,
+    /// pubkeys with the first stored event in each day
+    pub pubkeys: BTreeMap<u64, u64>,
+}
+
+fn stat_key(counter: u8, time: u64, kind: Option<u16>) -> Vec<u8> {
+    let mut key = vec![counter];
+    key.extend_from_slice(&time.to_be_bytes());
+    if let Some(kind) = kind {
+        key.extend_from_slice(&kind.to_be_bytes());
+    }
+    key
+}
+
 #[derive(Debug, Clone)]
 pub enum CheckEventResult {
     Invald(String),
     Duplicate,
     Deleted,
     ReplaceIgnored,
+    /// the id has a tombstone
+    TakenDown,
     Ok(usize),
 }
 
@@ -350,8 +535,10 @@ impl Db {
         Ok(())
     }
 
+    /// Open the database, the saved bloom filter of the ids is loaded with the events stored after it
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let inner = Lmdb::open_with(path, Some(20), Some(100), Some(1_000_000_000_000), 0)?;
+        let path = path.as_ref().to_path_buf();
+        let inner = Lmdb::open_with(&path, Some(32), Some(100), Some(1_000_000_000_000), 0)?;
 
         let default_opts = 0;
         // let integer_default_opts = ffi::MDB_INTEGERKEY;
@@ -368,8 +555,8 @@ impl Db {
         let t_data = inner.open_tree(Some("t_data"), integer_default_opts)?;
         let t_meta = inner.open_tree(Some("t_meta"), default_opts)?;
 
-        Ok(Self {
-            seq: Arc::new(AtomicU64::new(latest_seq(&inner, &t_data)?)),
+        let db = Self {
+            seq: Arc::new(AtomicU64::new(next_seq(&inner, &t_data)?)),
             t_data,
             t_meta,
             t_index: inner.open_tree(Some("t_index"), integer_default_opts)?,
@@ -385,16 +572,156 @@ impl Db {
             t_tag: inner.open_tree(Some("t_tag"), ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED)?,
             t_expiration: inner.open_tree(Some("t_expiration"), integer_index_opts)?,
             t_word: inner.open_tree(Some("t_word"), index_opts)?,
+            t_vanish: inner.open_tree(Some("t_vanish"), default_opts)?,
+            t_ban: inner.open_tree(Some("t_ban"), default_opts)?,
+            t_tombstone: inner.open_tree(Some("t_tombstone"), default_opts)?,
+            t_reputation: inner.open_tree(Some("t_reputation"), default_opts)?,
+            t_invite: inner.open_tree(Some("t_invite"), default_opts)?,
+            t_stat: inner.open_tree(Some("t_stat"), default_opts)?,
+            path,
+            bloom: Default::default(),
+            on_delete: Default::default(),
+            on_delete_commit: Default::default(),
 
             inner,
-        })
+        };
+        // rebuilt by `rebuild_bloom` when the file is missing or invalid
+        if let Ok(filter) = db.load_bloom() {
+            db.bloom.write().filter = Some(filter);
+        }
+        Ok(db)
+    }
+
+    fn load_bloom(&self) -> Result<Bloom> {
+        let (mut filter, seq) = Bloom::load(&self.path.join("bloom"))?;
+        if seq > self.seq.load(Ordering::SeqCst) {
+            return Err(Error::Message(
+                "the bloom filter is newer than the db".to_owned(),
+            ));
+        }
+        let reader = self.reader()?;
+        for item in reader.iter_from(&self.t_index, Bound::Included(u64_to_ver(seq)), false) {
+            let (_, v) = item?;
+            filter.insert(EventIndex::from_zeroes(v)?.id());
+        }
+        Ok(filter)
     }
 
-    pub fn writer(&self) -> Result<Writer> {
+    /// Rebuild the bloom filter of the stored ids and save it, the writes are not blocked.
+    /// The filter is sized for twice the stored events. Returns the number of the ids and the size in bytes.
+    pub fn rebuild_bloom(&self, fp_rate: f64) -> Result<(usize, usize)> {
+        {
+            // the writes after the writer is released are recorded
+            let _writer = self.writer()?;
+            self.bloom.write().pending = Some(vec![]);
+        }
+        let built = (|| {
+            let reader = self.reader()?;
+            let count = reader.stat(&self.t_id_uid)?.entries;
+            let mut filter = Bloom::new(count as u64 * 2, fp_rate);
+            for item in reader.iter_from(&self.t_id_uid, Bound::Unbounded::<Vec<u8>>, false) {
+                filter.insert(item?.0);
+            }
+            Ok::<_, Error>((filter, count))
+        })();
+        let mut state = self.bloom.write();
+        let pending = state.pending.take().unwrap_or_default();
+        let (mut filter, count) = built?;
+        for id in pending {
+            filter.insert(&id);
+        }
+        filter.save(&self.path.join("bloom"), self.seq.load(Ordering::SeqCst))?;
+        let size = filter.size();
+        state.filter = Some(filter);
+        Ok((count, size))
+    }
+
+    /// Call the hook with the id of each deleted event, ie: the deletions, the replaced events,
+    /// the vanished events and the expired events. It is called before the write is committed.
+    pub fn on_delete<F: Fn(&[u8]) + Send + Sync + 'static>(&self, hook: F) {
+        *self.on_delete.write() = Some(Box::new(hook));
+    }
+
+    /// Call `begin` before and `end` after committing the events deleted by the db,
+    /// ie: `batch_del`, `vanish` and `takedown`. The events deleted by `put` are committed by the caller.
+    pub fn on_delete_commit<B, E>(&self, begin: B, end: E)
+    where
+        B: Fn() + Send + Sync + 'static,
+        E: Fn() + Send + Sync + 'static,
+    {
+        *self.on_delete_commit.write() = Some((Box::new(begin), Box::new(end)));
+    }
+
+    /// Commit the deleting write between the hooks
+    fn commit_delete(&self, writer: Writer) -> Result<()> {
+        let hooks = self.on_delete_commit.read();
+        if let Some((begin, _)) = hooks.as_ref() {
+            begin();
+        }
+        let res = writer.commit();
+        if let Some((_, end)) = hooks.as_ref() {
+            end();
+        }
+        Ok(res?)
+    }
+
+    /// The event may be stored, false when the id is not in the bloom filter
+    pub fn may_exist<K: AsRef<[u8]>>(&self, event_id: K) -> bool {
+        let state = self.bloom.read();
+        state
+            .filter
+            .as_ref()
+            .is_none_or(|f| f.contains(event_id.as_ref()))
+    }
+
+    pub fn writer(&self) -> Result<Writer<'_>> {
         Ok(self.inner.writer()?)
     }
 
-    pub fn reader(&self) -> Result<Reader> {
+    /// Copy the database to the empty directory, the readers and the writer are not blocked
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.inner.copy(path)?;
+        Ok(())
+    }
+
+    /// Statistics and information of the lmdb environment
+    pub fn env_stat(&self) -> Result<(Stat, Info)> {
+        Ok((self.inner.stat()?, self.inner.info()?))
+    }
+
+    /// Statistics of each tree
+    pub fn tree_stats(&self) -> Result<Vec<(&'static str, Stat)>> {
+        let reader = self.reader()?;
+        let trees = [
+            ("meta", &self.t_meta),
+            ("data", &self.t_data),
+            ("index", &self.t_index),
+            ("id_uid", &self.t_id_uid),
+            ("uid_word", &self.t_uid_word),
+            ("id", &self.t_id),
+            ("pubkey", &self.t_pubkey),
+            ("kind", &self.t_kind),
+            ("pubkey_kind", &self.t_pubkey_kind),
+            ("created_at", &self.t_created_at),
+            ("tag", &self.t_tag),
+            ("deletion", &self.t_deletion),
+            ("replacement", &self.t_replacement),
+            ("expiration", &self.t_expiration),
+            ("word", &self.t_word),
+            ("vanish", &self.t_vanish),
+            ("ban", &self.t_ban),
+            ("tombstone", &self.t_tombstone),
+            ("reputation", &self.t_reputation),
+            ("invite", &self.t_invite),
+            ("stat", &self.t_stat),
+        ];
+        trees
+            .into_iter()
+            .map(|(name, tree)| Ok((name, reader.stat(tree)?)))
+            .collect()
+    }
+
+    pub fn reader(&self) -> Result<Reader<'_>> {
         Ok(self.inner.reader()?)
     }
 
@@ -415,10 +742,14 @@ impl Db {
         let event_id = event.id();
         let pubkey = event.pubkey();
 
+        if writer.get(&self.t_tombstone, event_id)?.is_some() {
+            return Ok(CheckEventResult::TakenDown);
+        }
+
         // Check duplicate event.
         {
-            // dup in the db.
-            if get_uid(writer, &self.t_id_uid, event_id)?.is_some() {
+            // dup in the db, the ids not in the bloom filter are new
+            if self.contains(writer, event_id)? {
                 return Ok(CheckEventResult::Duplicate);
             }
         }
@@ -431,6 +762,19 @@ impl Db {
             return Ok(CheckEventResult::Deleted);
         }
 
+        // [NIP-62](https://nips.be/62)
+        // the events created before the vanish request of the pubkey
+        if event.kind() != 62 {
+            if let Some(v) = writer.get(&self.t_vanish, pubkey)? {
+                if event.created_at() <= u64_from_bytes(v)? {
+                    return Ok(CheckEventResult::Deleted);
+                }
+            }
+        }
+
+        // before the old events of the pubkey are deleted or replaced
+        let new_pubkey = !self.has_pubkey(writer, pubkey)?;
+
         // [NIP-09](https://nips.be/9)
         // delete event
         if event.kind() == 5 {
@@ -497,11 +841,27 @@ impl Db {
         count += 1;
 
         let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        {
+            let mut state = self.bloom.write();
+            if let Some(pending) = state.pending.as_mut() {
+                pending.push(event_id.to_vec());
+            }
+            if let Some(filter) = state.filter.as_mut() {
+                filter.insert(event_id);
+            }
+        }
         let seq = u64_to_ver(seq);
-        self.put_event(writer, event, &seq, &replace_key)?;
+        let bytes = self.put_event(writer, event, &seq, &replace_key)?;
+        self.put_stats(writer, event.kind(), bytes, new_pubkey, now())?;
         Ok(CheckEventResult::Ok(count))
     }
 
+    /// The event is stored, only the index of the ids is read
+    pub fn contains<K: AsRef<[u8]>, T: Transaction>(&self, txn: &T, event_id: K) -> Result<bool> {
+        let event_id = event_id.as_ref();
+        Ok(self.may_exist(event_id) && get_uid(txn, &self.t_id_uid, event_id)?.is_some())
+    }
+
     pub fn get<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
         &self,
         txn: &T,
@@ -578,16 +938,466 @@ impl Db {
         for id in event_ids.into_iter() {
             self.del(&mut writer, &id)?;
         }
+        self.commit_delete(writer)
+    }
+
+    /// The time of the [NIP-62](https://nips.be/62) vanish request of the pubkey,
+    /// the events of the pubkey created until it are rejected
+    pub fn vanished<T: Transaction, K: AsRef<[u8]>>(
+        &self,
+        txn: &T,
+        pubkey: K,
+    ) -> Result<Option<u64>> {
+        txn.get(&self.t_vanish, pubkey)?
+            .map(u64_from_bytes)
+            .transpose()
+    }
+
+    /// The vanished pubkeys with the time of the request
+    pub fn vanished_list<T: Transaction>(&self, txn: &T) -> Result<Vec<(Vec<u8>, u64)>> {
+        txn.iter_from(&self.t_vanish, Bound::<&[u8]>::Unbounded, false)
+            .map(|r| {
+                let (k, v) = r?;
+                Ok((k.to_vec(), u64_from_bytes(v)?))
+            })
+            .collect()
+    }
+
+    /// Record the vanish request of the pubkey, the latest request is kept
+    pub fn put_vanish(&self, writer: &mut Writer, pubkey: &[u8], time: u64) -> Result<()> {
+        if self.vanished(writer, pubkey)?.is_none_or(|t| t < time) {
+            writer.put(&self.t_vanish, pubkey, time.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Delete a batch of the events of the vanished pubkey created until the time,
+    /// and the gift wraps tagged to it. The vanish requests are kept.
+    /// Returns the number of the deleted events, 0 when all are deleted.
+    pub fn vanish(&self, pubkey: &[u8], until: u64, batch: usize) -> Result<usize> {
+        let pubkey = hex::encode(pubkey);
+        let filters = [
+            json!({ "authors": [pubkey], "until": until }),
+            json!({ "kinds": [1059], "#p": [pubkey], "until": until }),
+        ];
+        let mut ids = vec![];
+        {
+            let reader = self.reader()?;
+            for filter in filters {
+                let filter = Filter::from_str(&filter.to_string())?;
+                for event in self.iter::<Event, _>(&reader, &filter)? {
+                    let event = event?;
+                    if event.kind() != 62 && ids.len() < batch {
+                        ids.push(event.id().to_vec());
+                    }
+                }
+            }
+        }
+        self.batch_del(&ids)?;
+        Ok(ids.len())
+    }
+
+    /// Save the ban of the value of the kind, ie: "pubkey", "ip" or "event"
+    pub fn put_ban(&self, kind: &str, value: &str, ban: &Ban) -> Result<()> {
+        let mut writer = self.writer()?;
+        writer.put(
+            &self.t_ban,
+            format!("{}:{}", kind, value),
+            serde_json::to_vec(ban)?,
+        )?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Remove the ban, returns false if the value is not banned
+    pub fn del_ban(&self, kind: &str, value: &str) -> Result<bool> {
+        let key = format!("{}:{}", kind, value);
+        let mut writer = self.writer()?;
+        let exists = writer.get(&self.t_ban, &key)?.is_some();
+        if exists {
+            writer.del(&self.t_ban, &key, None)?;
+        }
+        writer.commit()?;
+        Ok(exists)
+    }
+
+    /// All bans with the kind and the value
+    pub fn bans(&self) -> Result<Vec<(String, String, Ban)>> {
+        let reader = self.reader()?;
+        let mut bans = vec![];
+        for item in reader.iter_from(&self.t_ban, Bound::<&[u8]>::Unbounded, false) {
+            let (k, v) = item?;
+            let key = String::from_utf8_lossy(k);
+            if let Some((kind, value)) = key.split_once(':') {
+                bans.push((
+                    kind.to_owned(),
+                    value.to_owned(),
+                    serde_json::from_slice(v)?,
+                ));
+            }
+        }
+        Ok(bans)
+    }
+
+    /// Remove the event and keep the tombstone of its id, returns false if the event is not stored
+    pub fn takedown<K: AsRef<[u8]>>(&self, event_id: K, tombstone: &Tombstone) -> Result<bool> {
+        let mut writer = self.writer()?;
+        let removed = self.del(&mut writer, &event_id)?;
+        writer.put(
+            &self.t_tombstone,
+            event_id.as_ref(),
+            serde_json::to_vec(tombstone)?,
+        )?;
+        self.commit_delete(writer)?;
+        Ok(removed)
+    }
+
+    /// All tombstones with the hex event id
+    pub fn tombstones(&self) -> Result<Vec<(String, Tombstone)>> {
+        let reader = self.reader()?;
+        let mut list = vec![];
+        for item in reader.iter_from(&self.t_tombstone, Bound::<&[u8]>::Unbounded, false) {
+            let (k, v) = item?;
+            list.push((hex::encode(k), serde_json::from_slice(v)?));
+        }
+        Ok(list)
+    }
+
+    /// Save the invite code
+    pub fn put_invite(&self, code: &str, invite: &Invite) -> Result<()> {
+        let mut writer = self.writer()?;
+        writer.put(
+            &self.t_invite,
+            format!("code:{}", code),
+            serde_json::to_vec(invite)?,
+        )?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Remove the invite code, the admitted members are kept
+    pub fn del_invite(&self, code: &str) -> Result<bool> {
+        let key = format!("code:{}", code);
+        let mut writer = self.writer()?;
+        let exists = writer.get(&self.t_invite, &key)?.is_some();
+        if exists {
+            writer.del(&self.t_invite, &key, None)?;
+        }
+        writer.commit()?;
+        Ok(exists)
+    }
+
+    /// Remove the members and the codes minted by them in one transaction, returns the removed members
+    pub fn del_members(&self, pubkeys: &[String]) -> Result<Vec<String>> {
+        let mut writer = self.writer()?;
+        let mut removed = vec![];
+        for pubkey in pubkeys {
+            let key = format!("member:{}", pubkey);
+            if writer.get(&self.t_invite, &key)?.is_some() {
+                writer.del(&self.t_invite, &key, None)?;
+                removed.push(pubkey.clone());
+            }
+        }
+        let mut codes = vec![];
+        for item in writer.iter_from(&self.t_invite, Bound::Included(b"code:"), false) {
+            let (k, v) = item?;
+            if !k.starts_with(b"code:") {
+                break;
+            }
+            let invite: Invite = serde_json::from_slice(v)?;
+            if pubkeys.contains(&invite.created_by) {
+                codes.push(k.to_vec());
+            }
+        }
+        for key in codes {
+            writer.del(&self.t_invite, key, None)?;
+        }
+        writer.commit()?;
+        Ok(removed)
+    }
+
+    /// All invite codes
+    pub fn invites(&self) -> Result<Vec<(String, Invite)>> {
+        self.invite_list("code:")
+    }
+
+    /// All admitted pubkeys
+    pub fn members(&self) -> Result<Vec<(String, Member)>> {
+        self.invite_list("member:")
+    }
+
+    fn invite_list<T: serde::de::DeserializeOwned>(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, T)>> {
+        let reader = self.reader()?;
+        let mut list = vec![];
+        for item in reader.iter_from(&self.t_invite, Bound::Included(prefix.as_bytes()), false) {
+            let (k, v) = item?;
+            match k.strip_prefix(prefix.as_bytes()) {
+                Some(key) => list.push((
+                    String::from_utf8_lossy(key).into_owned(),
+                    serde_json::from_slice(v)?,
+                )),
+                None => break,
+            }
+        }
+        Ok(list)
+    }
+
+    /// Admit the pubkey with the invite code in one transaction, a member does not use the code again.
+    /// Returns false if the code is unknown, expired or used up.
+    pub fn redeem_invite(&self, code: &str, pubkey: &str, time: u64) -> Result<bool> {
+        let code_key = format!("code:{}", code);
+        let member_key = format!("member:{}", pubkey);
+        let mut writer = self.writer()?;
+        if writer.get(&self.t_invite, &member_key)?.is_some() {
+            return Ok(true);
+        }
+        let mut invite: Invite = match writer.get(&self.t_invite, &code_key)? {
+            Some(v) => serde_json::from_slice(v)?,
+            None => return Ok(false),
+        };
+        if !invite.usable(time) {
+            return Ok(false);
+        }
+        invite.uses += 1;
+        let member = Member {
+            code: code.to_owned(),
+            admitted_at: time,
+            invited_by: invite.created_by.clone(),
+        };
+        writer.put(&self.t_invite, code_key, serde_json::to_vec(&invite)?)?;
+        writer.put(&self.t_invite, member_key, serde_json::to_vec(&member)?)?;
         writer.commit()?;
+        Ok(true)
+    }
+
+    /// Save the reputations by the key, ie: "ip:127.0.0.1", the reputation is removed when None
+    pub fn put_reputations<'a, I>(&self, items: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'a str, Option<&'a Reputation>)>,
+    {
+        let mut writer = self.writer()?;
+        for (key, reputation) in items {
+            match reputation {
+                Some(r) => writer.put(&self.t_reputation, key, serde_json::to_vec(r)?)?,
+                None => {
+                    if writer.get(&self.t_reputation, key)?.is_some() {
+                        writer.del(&self.t_reputation, key, None)?;
+                    }
+                }
+            }
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// All reputations with the key
+    pub fn reputations(&self) -> Result<Vec<(String, Reputation)>> {
+        let reader = self.reader()?;
+        reader
+            .iter_from(&self.t_reputation, Bound::<&[u8]>::Unbounded, false)
+            .map(|item| {
+                let (k, v) = item?;
+                Ok((
+                    String::from_utf8_lossy(k).into_owned(),
+                    serde_json::from_slice(v)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// The rolled-up statistics between the times, the hours or days starting in the range
+    pub fn time_series<T: Transaction>(
+        &self,
+        txn: &T,
+        since: u64,
+        until: u64,
+    ) -> Result<TimeSeries> {
+        let mut series = TimeSeries::default();
+        for counter in [STAT_EVENTS, STAT_BYTES, STAT_PUBKEYS] {
+            let start = stat_key(counter, since, None);
+            for item in txn.iter_from(&self.t_stat, Bound::Included(start.as_slice()), false) {
+                let (k, v) = item?;
+                if k.len() < 9 || k[0] != counter {
+                    break;
+                }
+                let time = u64_from_bytes(&k[1..9])?;
+                if time > until {
+                    break;
+                }
+                let value = u64_from_bytes(v)?;
+                match counter {
+                    STAT_EVENTS => {
+                        let kind = u16_from_bytes(&k[9..])?;
+                        series.events.entry(time).or_default().insert(kind, value);
+                    }
+                    STAT_BYTES => {
+                        series.bytes.insert(time, value);
+                    }
+                    _ => {
+                        series.pubkeys.insert(time, value);
+                    }
+                }
+            }
+        }
+        Ok(series)
+    }
+
+    /// The uid of the newest stored event in the snapshot, the uids increase with each stored event
+    pub fn last_uid<T: Transaction>(&self, txn: &T) -> Result<Option<u64>> {
+        match txn
+            .iter_from(&self.t_index, Bound::<&[u8]>::Unbounded, true)
+            .next()
+        {
+            Some(item) => Ok(Some(u64_from_bytes(item?.0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The events matching the filter stored from the uid in the stored order with the uid of the newest,
+    /// none if more than `max_scan` events were stored since then
+    pub fn iter_stored_from<R: FromEventData, T: Transaction>(
+        &self,
+        txn: &T,
+        uid: u64,
+        filter: &Filter,
+        max_scan: usize,
+    ) -> Result<Option<(Vec<R>, Option<u64>)>> {
+        self.iter_stored_range(txn, uid..u64::MAX, filter, max_scan)
+    }
+
+    /// The events matching the filter stored in the uid range, see [`Db::iter_stored_from`]
+    pub fn iter_stored_range<R: FromEventData, T: Transaction>(
+        &self,
+        txn: &T,
+        uids: Range<u64>,
+        filter: &Filter,
+        max_scan: usize,
+    ) -> Result<Option<(Vec<R>, Option<u64>)>> {
+        let mut events = vec![];
+        let mut last = None;
+        let start = u64_to_ver(uids.start);
+        for (scanned, item) in txn
+            .iter_from(&self.t_index, Bound::Included(&start), false)
+            .enumerate()
+        {
+            let (k, v) = item?;
+            let uid = u64_from_bytes(k)?;
+            if uid >= uids.end {
+                break;
+            }
+            if scanned >= max_scan {
+                return Ok(None);
+            }
+            last = Some(uid);
+            if !filter.match_archived(EventIndex::from_zeroes(v)?) {
+                continue;
+            }
+            if filter.search.is_some() {
+                let event: Option<Event> = get_event_by_uid(txn, &self.t_data, &self.t_index, k)?;
+                if !event.is_some_and(|e| filter.match_event(&e)) {
+                    continue;
+                }
+            }
+            if let Some(event) = get_event_by_uid(txn, &self.t_data, &self.t_index, k)? {
+                events.push(event);
+            }
+        }
+        if let Some(limit) = filter.limit {
+            // the newest
+            let skip = events.len().saturating_sub(limit as usize);
+            events.drain(..skip);
+        }
+        Ok(Some((events, last)))
+    }
+
+    #[cfg(feature = "search")]
+    /// Expand the prefix and fuzzy patterns of the search to the indexed words,
+    /// the scanning stops when the limits of the options are reached.
+    /// The query expands the patterns of the filter built with `Filter::build_search`.
+    pub fn expand_patterns<T: Transaction>(
+        &self,
+        txn: &T,
+        filter: &mut Filter,
+        options: &crate::PatternOptions,
+    ) -> Result<()> {
+        let mut scanned = 0;
+        for (pattern, words) in filter.patterns.iter_mut() {
+            words.clear();
+            let prefix = pattern.scan_prefix();
+            let mut iter = txn.iter_from(&self.t_word, Bound::Included(prefix), false);
+            while scanned < options.max_scan && words.len() < options.max_expansions {
+                let Some(item) = iter.next() else {
+                    break;
+                };
+                // word + sep + time
+                let key = item?.0;
+                if key.len() < 9 || !key.starts_with(prefix) {
+                    break;
+                }
+                scanned += 1;
+                let word = &key[..key.len() - 9];
+                if pattern.matches(word) {
+                    words.push(word.to_vec());
+                }
+                // skip the other events of the word
+                iter.seek(Bound::Included(concat(word, [1])), false);
+            }
+        }
         Ok(())
     }
 
+    #[cfg(feature = "search")]
+    /// Remove the search index, the events are searchable again after `reindex_words`
+    pub fn clear_words(&self) -> Result<()> {
+        let mut writer = self.writer()?;
+        writer.clear(&self.t_word)?;
+        writer.clear(&self.t_uid_word)?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "search")]
+    /// Rebuild the search words of the events after the uid with the current tokenizer and index options.
+    /// Returns the uid of the last event and the number of the events in the batch, 0 when all events are done.
+    pub fn reindex_words(&self, after: Option<&[u8]>, batch: usize) -> Result<(Vec<u8>, usize)> {
+        let mut writer = self.writer()?;
+        let uids = {
+            let from = after.map_or(Bound::Unbounded, Bound::Excluded);
+            writer
+                .iter_from(&self.t_index, from, false)
+                .take(batch)
+                .map(|r| r.map(|(k, _)| k.to_vec()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        for uid in &uids {
+            let event: Option<Event> = get_event_by_uid(&writer, &self.t_data, &self.t_index, uid)?;
+            if let Some(mut event) = event {
+                let time = event.created_at();
+                event.build_note_words();
+                self.del_words(&mut writer, uid, time)?;
+                self.put_words(&mut writer, uid, &event)?;
+            }
+        }
+        writer.commit()?;
+        Ok((uids.last().cloned().unwrap_or_default(), uids.len()))
+    }
+
     /// iter events by filter
     pub fn iter<'txn, J: FromEventData, T: Transaction>(
         &self,
         txn: &'txn T,
         filter: &Filter,
     ) -> Result<Iter<'txn, T, J>> {
+        #[cfg(feature = "search")]
+        if let Some(options) = &filter.pattern_options {
+            let mut filter = filter.clone();
+            filter.pattern_options = None;
+            self.expand_patterns(txn, &mut filter, options)?;
+            return self.iter(txn, &filter);
+        }
         if filter.search.as_ref().is_some() {
             let match_index = if !filter.ids.is_empty()
                 || !filter.tags.is_empty()
@@ -635,6 +1445,48 @@ impl Db {
     }
 
     /// iter expired events
+    /// iter events by filter after the cursor of a previous iter, pages a large filter
+    /// across read transactions with the filter limit as the page size
+    pub fn iter_after<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        filter: &Filter,
+        cursor: Option<&Cursor>,
+    ) -> Result<Iter<'txn, T, J>> {
+        let Some(cursor) = cursor else {
+            return self.iter(txn, filter);
+        };
+        let mut filter = filter.clone();
+        if filter.desc {
+            filter.until = Some(filter.until.map_or(cursor.time, |t| t.min(cursor.time)));
+        } else {
+            filter.since = Some(filter.since.map_or(cursor.time, |t| t.max(cursor.time)));
+        }
+        let mut iter = self.iter(txn, &filter)?;
+        iter.after = Some(*cursor);
+        Ok(iter)
+    }
+
+    /// Iterate the stored events with their uid in the write order after the uid,
+    /// ie: stream the changes to a replica
+    pub fn iter_uid<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        after: Option<u64>,
+    ) -> impl Iterator<Item = Result<(u64, J)>> + 'txn {
+        let from = match after {
+            Some(uid) => Bound::Excluded(u64_to_ver(uid)),
+            None => Bound::Unbounded,
+        };
+        txn.iter_from(&self.t_data, from, false).map(|item| {
+            let (k, v) = item?;
+            Ok((
+                u64_from_bytes(k)?,
+                J::from_data(v).map_err(|e| Error::Message(e.to_string()))?,
+            ))
+        })
+    }
+
     pub fn iter_expiration<'txn, J: FromEventData, T: Transaction>(
         &self,
         txn: &'txn T,
@@ -698,6 +1550,8 @@ impl Db {
 enum MatchIndex {
     All,
     Pubkey,
+    /// only the negative fields of the filter
+    Exclude,
     None,
 }
 
@@ -706,12 +1560,52 @@ impl MatchIndex {
         match &self {
             MatchIndex::Pubkey => {
                 Filter::match_author(&filter.authors, event.pubkey(), event.delegator())
+                    && !filter.excludes_archived(event)
             }
+            MatchIndex::Exclude => !filter.excludes_archived(event),
             _ => filter.match_archived(event),
         }
     }
 }
 
+/// Opaque position of an iter, resume the scan after it with [`Db::iter_after`].
+/// It is formatted as hex for passing through apis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    time: u64,
+    uid: u64,
+}
+
+impl Cursor {
+    fn cmp_key(&self, key: &IndexKey) -> CmpOrdering {
+        self.time
+            .cmp(&key.time())
+            .then_with(|| self.uid.cmp(&key.uid()))
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(
+            [self.time.to_be_bytes(), self.uid.to_be_bytes()].concat(),
+        ))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s)?;
+        if bytes.len() != 16 {
+            return Err(Error::InvalidLength);
+        }
+        Ok(Self {
+            time: u64::from_be_bytes(bytes[..8].try_into()?),
+            uid: u64::from_be_bytes(bytes[8..].try_into()?),
+        })
+    }
+}
+
 pub struct Iter<'txn, R, J>
 where
     R: Transaction,
@@ -727,6 +1621,12 @@ where
     _r: PhantomData<J>,
     // need get index data for filter
     match_index: MatchIndex,
+    // skip the keys until the cursor
+    after: Option<Cursor>,
+    // the key of the last returned event
+    last: Option<Cursor>,
+    // skip the events stored from the uid
+    stored_before: Option<u64>,
 }
 
 fn create_iter<'a, R: Transaction>(
@@ -755,8 +1655,12 @@ where
         reader: &'txn R,
         filter: &Filter,
         group: Group<'txn, IndexKey, Error>,
-        match_index: MatchIndex,
+        mut match_index: MatchIndex,
     ) -> Result<Self, Error> {
+        // the index scan matches all but the negative fields
+        if matches!(match_index, MatchIndex::None) && !filter.exclude.is_empty() {
+            match_index = MatchIndex::Exclude;
+        }
         Ok(Self {
             view_data: kv_db.t_data.clone(),
             view_index: kv_db.t_index.clone(),
@@ -768,6 +1672,9 @@ where
             // checker: None,
             _r: PhantomData,
             match_index,
+            after: None,
+            last: None,
+            stored_before: None,
         })
     }
 
@@ -980,6 +1887,33 @@ where
             );
             group.add(Box::new(scanner))?;
         }
+        // any of the expanded words
+        for (_, words) in filter.patterns.iter() {
+            let mut sub = Group::new(filter.desc, false, true);
+            for word in words {
+                let prefix = concat_sep(word, []);
+                let klen = prefix.len() + 8;
+                let iter = create_iter(reader, view, &prefix, filter.desc);
+                let scanner = Scanner::new(
+                    iter,
+                    vec![],
+                    prefix,
+                    filter.desc,
+                    filter.since,
+                    filter.until,
+                    Box::new(move |s, r| {
+                        let k = r.0;
+                        Ok(if k.len() == klen && k.starts_with(&s.prefix) {
+                            MatchResult::Found(IndexKey::from(k, r.1)?)
+                        } else {
+                            MatchResult::Stop
+                        })
+                    }),
+                );
+                sub.add(Box::new(scanner))?;
+            }
+            group.add(Box::new(sub))?;
+        }
         Self::new(kv_db, reader, filter, group, match_index)
     }
 
@@ -1005,12 +1939,35 @@ where
         }
     }
 
+    /// the key is not after the cursor
+    fn skip_key(&self, key: &IndexKey) -> bool {
+        self.before_cursor(key) || self.stored_before.is_some_and(|uid| key.uid() >= uid)
+    }
+
+    fn before_cursor(&self, key: &IndexKey) -> bool {
+        self.after.is_some_and(|cursor| {
+            let ord = cursor.cmp_key(key);
+            if self.filter.desc {
+                ord != CmpOrdering::Greater
+            } else {
+                ord != CmpOrdering::Less
+            }
+        })
+    }
+
     fn next_inner(&mut self) -> Result<Option<J>, Error> {
         while let Some(item) = self.group.next() {
             let key = item?;
+            if self.skip_key(&key) {
+                continue;
+            }
             if matches!(self.match_index, MatchIndex::None) {
                 self.get_data += 1;
                 if let Some(event) = self.document(&key)? {
+                    self.last = Some(Cursor {
+                        time: key.time(),
+                        uid: key.uid(),
+                    });
                     return Ok(Some(event));
                 }
             } else {
@@ -1021,6 +1978,10 @@ where
                     if self.match_index.r#match(&self.filter, event) {
                         self.get_data += 1;
                         if let Some(event) = self.document(&key)? {
+                            self.last = Some(Cursor {
+                                time: key.time(),
+                                uid: key.uid(),
+                            });
                             return Ok(Some(event));
                         }
                     }
@@ -1038,20 +1999,43 @@ where
 {
     /// Limit the total scan time and report [`Error::ScanTimeout`] if it is exceeded
     pub fn scan_time(&mut self, timeout: Duration, check_step: u64) {
+        self.scan_limits(Some(timeout), None, check_step);
+    }
+
+    /// Limit the total scan time checked every `check_step` scanned index entries and
+    /// the number of scanned index entries, report [`Error::ScanTimeout`] or [`Error::ScanBudget`]
+    /// if one is exceeded. The events returned before the error are valid.
+    pub fn scan_limits(&mut self, timeout: Option<Duration>, budget: Option<u64>, check_step: u64) {
         let start = Instant::now();
         let mut last = check_step;
         self.group.watcher(Box::new(move |count| {
-            if count > last {
-                // check
-                if start.elapsed() > timeout {
-                    return Err(Error::ScanTimeout);
+            if budget.is_some_and(|budget| count > budget) {
+                return Err(Error::ScanBudget);
+            }
+            if let Some(timeout) = timeout {
+                if count > last {
+                    // check
+                    if start.elapsed() > timeout {
+                        return Err(Error::ScanTimeout);
+                    }
+                    last = count + check_step;
                 }
-                last = count + check_step;
             }
             Ok(())
         }));
     }
 
+    /// Only the events stored before the uid, ie: the events of an earlier snapshot,
+    /// see [`Db::last_uid`]
+    pub fn stored_before(&mut self, uid: u64) {
+        self.stored_before = Some(uid);
+    }
+
+    /// The cursor after the last returned event, resume with [`Db::iter_after`]
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.last
+    }
+
     /// The stats after scan
     pub fn stats(&self) -> Stats {
         Stats {
@@ -1066,6 +2050,9 @@ where
         let mut len = 0;
         while let Some(item) = self.group.next() {
             let key = item?;
+            if self.skip_key(&key) {
+                continue;
+            }
             if matches!(self.match_index, MatchIndex::None) {
                 len += 1;
                 if self.limit(len) {
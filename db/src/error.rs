@@ -26,6 +26,8 @@ pub enum Error {
     Message(String),
     #[error("Scan timeout")]
     ScanTimeout,
+    #[error("Scan budget exceeded")]
+    ScanBudget,
     #[error("The database schema has been modified. Please run export first, move the old database file, then import and start the program.
       Find the rnostr command at https://github.com/rnostr/rnostr#commands
       rnostr export data/events > events.json
@@ -1,15 +1,21 @@
 use crate::error::Error;
+use bytestring::ByteString;
 use rkyv::{
     vec::ArchivedVec, AlignedVec, Archive, Archived, Deserialize as RkyvDeserialize,
     Serialize as RkyvSerialize,
 };
 use secp256k1::{schnorr::Signature, KeyPair, Message, XOnlyPublicKey, SECP256K1};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde::{
+    de::{self, DeserializeSeed},
+    ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::value::RawValue;
 use sha2::{Digest, Sha256};
 use std::{
+    borrow::Cow,
     fmt::Display,
     str::FromStr,
+    sync::OnceLock,
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -75,7 +81,7 @@ impl EventIndex {
         pubkey: [u8; 32],
         created_at: u64,
         kind: u16,
-        tags: &Vec<Vec<String>>,
+        tags: &[Vec<impl AsRef<str>>],
     ) -> Result<Self, Error> {
         let (tags, expiration, delegator) = Self::build_index_tags(tags)?;
         Ok(Self {
@@ -89,38 +95,39 @@ impl EventIndex {
         })
     }
 
-    pub fn build_index_tags(tags: &Vec<Vec<String>>) -> Result<BuildTags, Error> {
+    pub fn build_index_tags(tags: &[Vec<impl AsRef<str>>]) -> Result<BuildTags, Error> {
         let mut t = vec![];
         let mut expiration = None;
         let mut delegator = None;
 
         for tag in tags {
             if tag.len() > 1 {
-                if tag[0] == "expiration" {
+                let (name, value) = (tag[0].as_ref(), tag[1].as_ref());
+                if name == "expiration" {
                     expiration = Some(
-                        u64::from_str(&tag[1])
+                        u64::from_str(value)
                             .map_err(|_| Error::Invalid("invalid expiration".to_string()))?,
                     );
-                } else if tag[0] == "delegation" {
+                } else if name == "delegation" {
                     let mut h = [0u8; 32];
-                    hex::decode_to_slice(&tag[1], &mut h)?;
+                    hex::decode_to_slice(value, &mut h)?;
                     delegator = Some(h);
                 }
 
-                let key = tag[0].as_bytes().to_vec();
+                let key = name.as_bytes().to_vec();
                 // only index key length 1
                 // 0 will break the index separator, ignore
                 if key.len() == 1 && key[0] != 0 {
                     let v;
                     // fixed length 32 e and p
-                    if tag[0] == "e" || tag[0] == "p" {
-                        let h = hex::decode(&tag[1])?;
+                    if name == "e" || name == "p" {
+                        let h = hex::decode(value)?;
                         if h.len() != 32 {
                             return Err(Error::Invalid("invalid e or p tag value".to_string()));
                         }
                         v = h;
                     } else {
-                        v = tag[1].as_bytes().to_vec();
+                        v = value.as_bytes().to_vec();
                         // 0 will break the index separator, ignore
                         // lmdb max_key_size 511 bytes
                         // we only index tag value length < 255
@@ -237,16 +244,93 @@ struct _Event {
     // index: IndexEvent,
 }
 
+// the shadow event borrowed from the json text
+#[derive(Deserialize)]
+struct _EventRef<'a> {
+    #[serde(with = "hex::serde")]
+    id: [u8; 32],
+    #[serde(with = "hex::serde")]
+    pubkey: [u8; 32],
+    created_at: u64,
+    kind: u16,
+    #[serde(borrow, default = "empty_tags")]
+    tags: &'a RawValue,
+    #[serde(borrow, default)]
+    content: Cow<'a, str>,
+    #[serde(with = "hex::serde")]
+    sig: [u8; 64],
+}
+
+fn empty_tags() -> &'static RawValue {
+    serde_json::from_str("[]").unwrap()
+}
+
+/// The tag value borrowed from the json text when it has no escapes
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct TagValue<'a>(#[serde(borrow)] Cow<'a, str>);
+
+impl AsRef<str> for TagValue<'_> {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The tags of the event.
+///
+/// The tags of an event parsed by [`EventSeed`] are kept as the json array in the text,
+/// they are parsed to strings when they are read.
+#[derive(Debug, Clone, Default)]
+pub struct EventTags {
+    raw: Option<ByteString>,
+    parsed: OnceLock<Vec<Vec<String>>>,
+}
+
+impl EventTags {
+    /// parse the json array without copying the values
+    fn borrow(raw: &str) -> Result<Vec<Vec<TagValue<'_>>>, serde_json::Error> {
+        serde_json::from_str(raw)
+    }
+
+    pub fn get(&self) -> &Vec<Vec<String>> {
+        self.parsed.get_or_init(|| {
+            // the json array is checked when the event is parsed
+            self.raw
+                .as_ref()
+                .and_then(|raw| serde_json::from_str(raw).ok())
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl From<Vec<Vec<String>>> for EventTags {
+    fn from(tags: Vec<Vec<String>>) -> Self {
+        Self {
+            raw: None,
+            parsed: OnceLock::from(tags),
+        }
+    }
+}
+
+impl Serialize for EventTags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self.parsed.get(), &self.raw) {
+            (None, Some(raw)) => {
+                Serialize::serialize(&Self::borrow(raw).map_err(ser::Error::custom)?, serializer)
+            }
+            _ => Serialize::serialize(self.get(), serializer),
+        }
+    }
+}
+
 /// The default event document.
 // TODO: validate index tag value length 255
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(try_from = "_Event")]
 pub struct Event {
-    #[serde(default)]
-    tags: Vec<Vec<String>>,
+    tags: EventTags,
 
-    #[serde(default)]
-    content: String,
+    content: ByteString,
 
     #[serde(with = "hex::serde")]
     sig: [u8; 64],
@@ -263,7 +347,7 @@ impl TryFrom<_Event> for Event {
 
     fn try_from(value: _Event) -> Result<Self, Self::Error> {
         let event = Event {
-            content: value.content,
+            content: value.content.into(),
             sig: value.sig,
             index: EventIndex::new(
                 value.id,
@@ -272,13 +356,59 @@ impl TryFrom<_Event> for Event {
                 value.kind,
                 &value.tags,
             )?,
-            tags: value.tags,
+            tags: value.tags.into(),
             words: Default::default(),
         };
         Ok(event)
     }
 }
 
+/// Deserialize the event from the json text of a message by serde_json.
+///
+/// The content and the tags share the buffer of the text instead of being copied to strings,
+/// the tags are parsed to strings when they are read.
+pub struct EventSeed<'a>(pub &'a ByteString);
+
+impl EventSeed<'_> {
+    fn share(&self, s: &str) -> ByteString {
+        let (text, start) = (self.0.as_ptr() as usize, s.as_ptr() as usize);
+        if !s.is_empty() && start >= text && start + s.len() <= text + self.0.len() {
+            self.0.slice_ref(s)
+        } else {
+            s.into()
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for EventSeed<'_> {
+    type Value = Event;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value: _EventRef = Deserialize::deserialize(deserializer)?;
+        let tags = EventTags::borrow(value.tags.get()).map_err(de::Error::custom)?;
+        let index = EventIndex::new(value.id, value.pubkey, value.created_at, value.kind, &tags)
+            .map_err(de::Error::custom)?;
+        let content = match value.content {
+            Cow::Borrowed(content) => self.share(content),
+            // unescaped
+            Cow::Owned(content) => content.into(),
+        };
+        Ok(Event {
+            tags: EventTags {
+                raw: Some(self.share(value.tags.get())),
+                parsed: OnceLock::new(),
+            },
+            content,
+            sig: value.sig,
+            index,
+            words: Default::default(),
+        })
+    }
+}
+
 impl Event {
     pub fn new(
         id: [u8; 32],
@@ -291,8 +421,8 @@ impl Event {
     ) -> Result<Self, Error> {
         let index = EventIndex::new(id, pubkey, created_at, kind, &tags)?;
         let event = Self {
-            tags,
-            content,
+            tags: tags.into(),
+            content: content.into(),
             sig,
             index,
             words: Default::default(),
@@ -420,12 +550,10 @@ impl FromEventData for Event {
 
 #[cfg(feature = "search")]
 impl Event {
-    /// build keywords for search ability
+    /// build keywords for search ability, the kinds and length of the content are limited by `set_index_options`
     pub fn build_note_words(&mut self) {
-        if self.kind() == 1 {
-            let mut words = crate::segment(&self.content);
-            self.words.append(&mut words);
-        }
+        let mut words = crate::segment_event(self.kind(), &self.content);
+        self.words.append(&mut words);
     }
 }
 
@@ -464,10 +592,10 @@ impl Event {
     }
 
     pub fn tags(&self) -> &Vec<Vec<String>> {
-        &self.tags
+        self.tags.get()
     }
 
-    pub fn content(&self) -> &String {
+    pub fn content(&self) -> &str {
         &self.content
     }
 
@@ -487,12 +615,16 @@ fn hash(
     pubkey: &[u8],
     created_at: u64,
     kind: u16,
-    tags: &Vec<Vec<String>>,
-    content: &String,
+    tags: &impl Serialize,
+    content: &str,
 ) -> [u8; 32] {
-    let json: Value = json!([0, hex::encode(pubkey), created_at, kind, tags, content]);
     let mut hasher = Sha256::new();
-    hasher.update(json.to_string());
+    // the compact json is written to the hasher
+    serde_json::to_writer(
+        &mut hasher,
+        &(0, hex::encode(pubkey), created_at, kind, tags, content),
+    )
+    .unwrap();
     hasher.finalize().into()
 }
 
@@ -502,7 +634,7 @@ impl Event {
             self.pubkey(),
             self.created_at(),
             self.kind(),
-            self.tags(),
+            &self.tags,
             self.content(),
         )
     }
@@ -557,13 +689,19 @@ impl Event {
     }
 
     pub fn validate(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
+        self.validate_trusted(now, older, newer)?;
+        self.verify_sign()?;
+        self.verify_delegation()?;
+        Ok(())
+    }
+
+    /// Validate the event of a trusted source, the id is checked but not the signatures
+    pub fn validate_trusted(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
         if self.index.is_expired(now) {
             return Err(Error::Invalid("event is expired".to_owned()));
         }
         self.verify_time(now, older, newer)?;
         self.verify_id()?;
-        self.verify_sign()?;
-        self.verify_delegation()?;
         Ok(())
     }
 }
@@ -727,7 +865,7 @@ mod tests {
             "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d"
         );
         assert_eq!(event.index().id().len(), 32);
-        assert_eq!(&event.tags, &vec![vec!["t", "nostr"]]);
+        assert_eq!(event.tags(), &vec![vec!["t", "nostr"]]);
         assert_eq!(event.index().tags.len(), 1);
 
         // null tag
@@ -768,7 +906,7 @@ mod tests {
         "#;
         let event: Event = serde_json::from_str(note)?;
         assert_eq!(&event.content, "");
-        assert_eq!(&event.tags, &Vec::<Vec<String>>::new());
+        assert_eq!(event.tags(), &Vec::<Vec<String>>::new());
         Ok(())
     }
 
@@ -796,6 +934,20 @@ mod tests {
         assert!(event.verify_time(20, 1, 1).is_err());
         assert!(event.verify_time(5, 1, 1).is_err());
 
+        // the signature of a trusted event is not verified, the id is
+        let mut event = Event::create(
+            &KeyPair::new_global(&mut thread_rng()),
+            10,
+            1,
+            vec![],
+            "".to_string(),
+        )?;
+        event.sig = [0; 64];
+        assert!(event.validate(10, 0, 0).is_err());
+        assert!(event.validate_trusted(10, 0, 0).is_ok());
+        let event = Event::new([0; 32], [0; 32], 10, 1, vec![], "".to_string(), [0; 64])?;
+        assert!(event.validate_trusted(10, 0, 0).is_err());
+
         let note = r#"
         {
             "id": "e93c6095c3db1c31d15ac771f8fc5fb672f6e52cd25505099f62cd055523224f",
@@ -834,4 +986,48 @@ mod tests {
         assert!(event.verify_id().is_ok());
         Ok(())
     }
+
+    #[test]
+    fn seed() -> Result<()> {
+        let parse = |text: &ByteString| -> Result<Event> {
+            let mut de = serde_json::Deserializer::from_str(text);
+            Ok(EventSeed(text).deserialize(&mut de)?)
+        };
+        let note = r#"{"content":"bgQih8o+R83t00qvueD7twglJRvvabI+nDu+bTvRsAs=?iv=92TlqnpEeiUMzDtUxsZeUA==","created_at":1682257003,"id":"dba1951f0959dfea6e3123ad916d191a07b35392c4b541d4b4814e77113de14a","kind":4,"pubkey":"3f770d65d3a764a9c5cb503ae123e62ec7598ad035d836e2a810f3877a745b24","sig":"15dcc89bca7d037d6a5282c1e63ea40ca4f76d81821ca1260898a324c99516a0cb577617cf18a3febe6303ed32e7a1a08382eecde5a7183195ca8f186a0cb037","tags":[ ["p", "6efb74e66b7ed7fb9fb7b8b8f12e1fbbabe7f45823a33a14ac60cc9241285536"] ]}"#;
+        let text = ByteString::from(note);
+        let event = parse(&text)?;
+        let range = text.as_bytes().as_ptr_range();
+        // shares the text
+        assert!(range.contains(&event.content.as_ptr()));
+        assert!(range.contains(&event.tags.raw.as_ref().unwrap().as_ptr()));
+        assert_eq!(event.index(), Event::from_str(note)?.index());
+        assert!(event.verify_id().is_ok());
+        assert!(event.verify_sign().is_ok());
+        assert_eq!(event.to_json()?, Event::from_str(note)?.to_json()?);
+        // the tags are parsed when they are read
+        assert!(event.tags.parsed.get().is_none());
+        assert_eq!(
+            event.tags(),
+            &vec![vec![
+                "p",
+                "6efb74e66b7ed7fb9fb7b8b8f12e1fbbabe7f45823a33a14ac60cc9241285536"
+            ]]
+        );
+
+        // escaped content
+        let note = r#"{"content":"{\"display_name\": \"maglevclient\", \"uptime\": 103180, \"maglev\": \"1a98030114cf\"}","created_at":1682258083,"id":"153a480d7bb9d7564147241b330a8667b19c3f9178b8179e64bf57f200654cb0","kind":0,"pubkey":"fb7324a1b807b48756be8df06bd9ccf11741a9678b120e91e044b5137734dcb2","sig":"08c0ffa072fd49f405df467ccab25152a54073fc0639ea0952e1eabff7962e008c54cb8f4d2d55dc4398703df4a5654d2ae3e93f68a801bcbabcdb8050a918ef","tags":[["t","TESTmaglev"],["expiration","1682258683"]]}"#;
+        let event = parse(&ByteString::from(note))?;
+        assert!(event.content().starts_with(r#"{"display_name""#));
+        assert!(event.verify_id().is_ok());
+        assert!(event.index().expiration().is_some());
+
+        // default and invalid tags
+        let note = r#"{"created_at":10,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f"}"#;
+        let event = parse(&ByteString::from(note))?;
+        assert_eq!(event.content(), "");
+        assert!(event.tags().is_empty());
+        let note = note.replace(r#""kind":1"#, r#""kind":1,"tags":[["e",null]]"#);
+        assert!(parse(&ByteString::from(note)).is_err());
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{error::Error, ArchivedEventIndex, EventIndex};
+use crate::{error::Error, ArchivedEventIndex, Event, EventIndex};
 use serde::Deserialize;
 use serde_json::Value;
 use std::cmp::Ord;
@@ -69,6 +69,162 @@ pub struct Filter {
 
     #[serde(skip)]
     pub words: Vec<Vec<u8>>,
+
+    /// The prefix and fuzzy keywords of the search, with the indexed words expanded by `Db::expand_patterns`
+    #[serde(skip)]
+    pub patterns: Vec<(Pattern, Vec<Vec<u8>>)>,
+
+    /// The options of the patterns expanded by the query in its transaction
+    #[cfg(feature = "search")]
+    #[serde(skip)]
+    pub pattern_options: Option<crate::PatternOptions>,
+
+    /// Order the search results by relevance instead of time
+    #[serde(skip)]
+    pub rank: Option<Rank>,
+
+    /// The events excluded after the standard matching
+    #[serde(skip)]
+    pub exclude: Exclude,
+}
+
+/// The negative fields of a filter, `"!authors"`, `"!kinds"` and `"!#<tag>"`.
+/// An event is excluded if it matches any of them.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Exclude {
+    pub authors: SortList<[u8; 32]>,
+    pub kinds: SortList<u16>,
+    pub tags: HashMap<Vec<u8>, SortList<Vec<u8>>>,
+}
+
+impl Exclude {
+    /// Parse the negative fields of the raw filter, the other fields are ignored
+    pub fn from_value(value: &Value) -> Result<Self, Error> {
+        let mut exclude = Self::default();
+        let Some(map) = value.as_object() else {
+            return Ok(exclude);
+        };
+        for (key, value) in map {
+            match key.as_str() {
+                "!authors" => {
+                    exclude.authors = Vec::<_HexString>::deserialize(value)?
+                        .into_iter()
+                        .map(|s| s.hex)
+                        .collect::<Vec<_>>()
+                        .into();
+                }
+                "!kinds" => exclude.kinds = Vec::<u16>::deserialize(value)?.into(),
+                _ => {
+                    if let Some(key) = key.strip_prefix("!#") {
+                        let list = parse_tag(key.as_bytes(), value)?;
+                        if key.len() == 1 && !list.is_empty() {
+                            exclude.tags.insert(key.as_bytes().to_vec(), list.into());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(exclude)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.authors.is_empty() && self.kinds.is_empty() && self.tags.is_empty()
+    }
+
+    /// The number of the excluded values
+    pub fn len(&self) -> usize {
+        self.authors.len() + self.kinds.len() + self.tags.values().map(|v| v.len()).sum::<usize>()
+    }
+
+    /// The event is excluded by the author, delegator, kind or any tag
+    pub fn excludes<V: AsRef<[u8]>, I: AsRef<[(V, V)]>>(
+        &self,
+        pubkey: &[u8; 32],
+        delegator: Option<&[u8; 32]>,
+        kind: u16,
+        tags: I,
+    ) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        (!self.authors.is_empty() && Filter::match_author(&self.authors, pubkey, delegator))
+            || self.kinds.contains(&kind)
+            || self
+                .tags
+                .iter()
+                .any(|(name, list)| Filter::tag_contains(&tags, name, list))
+    }
+}
+
+/// The newest matched events are scored, then sorted by the score
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Rank {
+    /// the max events scored
+    pub candidates: u64,
+    /// the score of an event halves every `half_life` seconds
+    pub half_life: u64,
+}
+
+/// The search keyword matches the indexed words by prefix or edit distance
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Pattern {
+    /// `oly*` matches the words start with "oly"
+    Prefix(Vec<u8>),
+    /// `word~2` matches the words within the edit distance,
+    /// the leading `prefix` bytes must be the same
+    Fuzzy {
+        word: Vec<u8>,
+        distance: u8,
+        prefix: usize,
+    },
+}
+
+impl Pattern {
+    pub fn matches(&self, word: &[u8]) -> bool {
+        match self {
+            Pattern::Prefix(prefix) => word.starts_with(prefix),
+            Pattern::Fuzzy {
+                word: w,
+                distance,
+                prefix,
+            } => word.starts_with(&w[..*prefix]) && within_distance(w, word, *distance as usize),
+        }
+    }
+
+    /// All the matched words start with the prefix
+    pub fn scan_prefix(&self) -> &[u8] {
+        match self {
+            Pattern::Prefix(prefix) => prefix,
+            Pattern::Fuzzy { word, prefix, .. } => &word[..*prefix],
+        }
+    }
+}
+
+/// The levenshtein distance of the characters is not bigger than max
+fn within_distance(a: &[u8], b: &[u8], max: usize) -> bool {
+    let (Ok(a), Ok(b)) = (std::str::from_utf8(a), std::str::from_utf8(b)) else {
+        return false;
+    };
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    if a.len().abs_diff(b.len()) > max {
+        return false;
+    }
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            cur[j + 1] = (prev[j] + usize::from(ca != cb))
+                .min(prev[j + 1] + 1)
+                .min(cur[j] + 1);
+        }
+        // the distance never decreases in the next rows
+        if cur.iter().all(|d| *d > max) {
+            return false;
+        }
+        prev = cur;
+    }
+    prev[b.len()] <= max
 }
 
 impl FromStr for Filter {
@@ -81,8 +237,8 @@ impl FromStr for Filter {
 #[derive(Deserialize, Default)]
 #[serde(default)]
 struct _Filter {
-    pub ids: Vec<_HexString>,
-    pub authors: Vec<_HexString>,
+    pub ids: Vec<String>,
+    pub authors: Vec<String>,
     pub kinds: Vec<u16>,
     pub since: Option<u64>,
     pub until: Option<u64>,
@@ -100,6 +256,51 @@ struct _HexString {
     hex: [u8; 32],
 }
 
+/// The 32 bytes hex values of the field, the error names the field
+fn parse_hex_list(field: &str, list: Vec<String>) -> Result<SortList<[u8; 32]>, Error> {
+    list.into_iter()
+        .map(|s| {
+            let mut hex = [0; 32];
+            hex::decode_to_slice(s, &mut hex).map_err(|_| {
+                Error::Invalid(format!("{} must be 64 character hex strings", field))
+            })?;
+            Ok(hex)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Into::into)
+}
+
+/// The values of the tag, the e and p tags are hex
+fn parse_tag(key: &[u8], value: &Value) -> Result<Vec<Vec<u8>>, Error> {
+    let val = Vec::<String>::deserialize(value).map_err(|_| {
+        Error::Invalid(format!(
+            "#{} must be a list of strings",
+            String::from_utf8_lossy(key)
+        ))
+    })?;
+    let mut list = vec![];
+    for s in val {
+        if key == b"e" || key == b"p" {
+            match hex::decode(&s) {
+                Ok(h) if h.len() == 32 => list.push(h),
+                _ => {
+                    return Err(Error::Invalid(format!(
+                        "#{} must be 64 character hex strings",
+                        key[0] as char
+                    )))
+                }
+            }
+        } else {
+            list.push(s.into_bytes());
+            // if s.len() < 255 {
+            // } else {
+            //     return Err(Error::Invald("invalid value length".to_string()));
+            // }
+        }
+    }
+    Ok(list)
+}
+
 impl TryFrom<_Filter> for Filter {
     type Error = Error;
     fn try_from(filter: _Filter) -> Result<Self, Self::Error> {
@@ -114,48 +315,24 @@ impl TryFrom<_Filter> for Filter {
         for item in filter.tags {
             let key = item.0;
             if let Some(key) = key.strip_prefix('#') {
-                let key = key.as_bytes();
                 // only index for key len 1
-                if key.len() == 1 {
-                    let val = Vec::<String>::deserialize(&item.1)?;
-                    let mut list = vec![];
-                    for s in val {
-                        if key == b"e" || key == b"p" {
-                            let h = hex::decode(&s)?;
-                            if h.len() != 32 {
-                                // ignore
-                                return Err(Error::Invalid("invalid e or p tag value".to_string()));
-                            } else {
-                                list.push(h);
-                            }
-                        } else {
-                            list.push(s.into_bytes());
-                            // if s.len() < 255 {
-                            // } else {
-                            //     return Err(Error::Invald("invalid value length".to_string()));
-                            // }
-                        }
-                    }
-                    if !list.is_empty() {
-                        tags.insert(key.to_vec(), list.into());
-                    }
+                if key.chars().count() != 1 {
+                    return Err(Error::Invalid(format!(
+                        "unsupported tag filter \"#{}\", expected # and a single letter",
+                        key
+                    )));
+                }
+                let key = key.as_bytes();
+                let list = parse_tag(key, &item.1)?;
+                if !list.is_empty() {
+                    tags.insert(key.to_vec(), list.into());
                 }
             }
         }
 
         let f = Filter {
-            ids: filter
-                .ids
-                .into_iter()
-                .map(|s| s.hex)
-                .collect::<Vec<_>>()
-                .into(),
-            authors: filter
-                .authors
-                .into_iter()
-                .map(|s| s.hex)
-                .collect::<Vec<_>>()
-                .into(),
+            ids: parse_hex_list("ids", filter.ids)?,
+            authors: parse_hex_list("authors", filter.authors)?,
             kinds: filter.kinds.into(),
             since: filter.since,
             until: filter.until,
@@ -164,6 +341,11 @@ impl TryFrom<_Filter> for Filter {
             tags,
             desc: filter.limit.is_some(),
             words: vec![],
+            patterns: vec![],
+            #[cfg(feature = "search")]
+            pattern_options: None,
+            rank: None,
+            exclude: Exclude::default(),
         };
 
         Ok(f)
@@ -175,13 +357,24 @@ impl Filter {
     /// build keywords for search ability
     pub fn build_words(&mut self) {
         if let Some(search) = &self.search {
-            let words = crate::segment(search);
+            let words = crate::segment_query(search);
             if !words.is_empty() {
                 self.words = words;
             }
         }
     }
 
+    #[cfg(feature = "search")]
+    /// build keywords and the prefix, fuzzy patterns for search ability
+    pub fn build_search(&mut self, options: &crate::PatternOptions) {
+        if let Some(search) = &self.search {
+            let (words, patterns) = options.parse(search);
+            self.words = words;
+            self.patterns = patterns.into_iter().map(|p| (p, vec![])).collect();
+            self.pattern_options = (!self.patterns.is_empty()).then(|| options.clone());
+        }
+    }
+
     pub fn default_limit(&mut self, limit: u64) {
         if self.limit.is_none() {
             self.limit = Some(limit);
@@ -270,35 +463,121 @@ impl Filter {
     }
 
     pub fn r#match(&self, event: &EventIndex) -> bool {
-        self.match_except_tag(event) && Self::match_tag(&self.tags, event.tags())
+        self.match_except_tag(event)
+            && Self::match_tag(&self.tags, event.tags())
+            && !self.exclude.excludes(
+                event.pubkey(),
+                event.delegator(),
+                event.kind(),
+                event.tags(),
+            )
     }
 
     pub fn match_except_tag(&self, event: &EventIndex) -> bool {
         Self::match_id(&self.ids, event.id())
-            && self.since.map_or(true, |t| event.created_at() >= t)
-            && self.until.map_or(true, |t| event.created_at() <= t)
+            && self.since.is_none_or(|t| event.created_at() >= t)
+            && self.until.is_none_or(|t| event.created_at() <= t)
             && Self::match_kind(&self.kinds, event.kind())
             && Self::match_author(&self.authors, event.pubkey(), event.delegator())
     }
 
+    /// Match the event with the same semantics as the database query:
+    /// ids, authors with delegation, kinds, tags, since, until and search.
+    /// All the search words and patterns must be in the note content, the search never matches without the `search` feature.
+    pub fn match_event(&self, event: &Event) -> bool {
+        self.r#match(event.index()) && self.match_search(event)
+    }
+
+    #[cfg(feature = "search")]
+    fn match_search(&self, event: &Event) -> bool {
+        let Some(search) = &self.search else {
+            return true;
+        };
+        let words = if self.words.is_empty() && self.patterns.is_empty() {
+            crate::segment_query(search)
+        } else {
+            self.words.clone()
+        };
+        // the same words as `Event::build_note_words`
+        let note_words;
+        let event_words = if event.words.is_empty() {
+            note_words = crate::segment_event(event.kind(), event.content());
+            &note_words
+        } else {
+            &event.words
+        };
+        !(words.is_empty() && self.patterns.is_empty())
+            && words.iter().all(|w| event_words.contains(w))
+            && self
+                .patterns
+                .iter()
+                .all(|(p, _)| event_words.iter().any(|w| p.matches(w)))
+    }
+
+    #[cfg(feature = "search")]
+    /// The relevance of the event to the search, the frequency of the keywords
+    /// normalized by the length of the note and decayed by the age
+    pub fn relevance(&self, event: &Event, now: u64, half_life: u64) -> f64 {
+        let tokens = crate::segment_tokens(event.content());
+        if tokens.is_empty() {
+            return 0.0;
+        }
+        let frequency = |count: usize| {
+            if count == 0 {
+                0.0
+            } else {
+                1.0 + (count as f64).ln()
+            }
+        };
+        let words = self
+            .words
+            .iter()
+            .map(|w| frequency(tokens.iter().filter(|t| *t == w).count()));
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|(p, _)| frequency(tokens.iter().filter(|t| p.matches(t)).count()));
+        let score = words.chain(patterns).sum::<f64>() / (tokens.len() as f64).sqrt();
+        let age = now.saturating_sub(event.created_at()) as f64;
+        score * 0.5f64.powf(age / half_life.max(1) as f64)
+    }
+
+    #[cfg(not(feature = "search"))]
+    fn match_search(&self, _event: &Event) -> bool {
+        self.search.is_none()
+    }
+
     pub fn match_archived(&self, event: &ArchivedEventIndex) -> bool {
-        self.match_archived_except_tag(event) && Self::match_tag(&self.tags, event.tags())
+        self.match_archived_except_tag(event)
+            && Self::match_tag(&self.tags, event.tags())
+            && !self.excludes_archived(event)
+    }
+
+    /// The stored event is excluded by the negative fields
+    pub fn excludes_archived(&self, event: &ArchivedEventIndex) -> bool {
+        self.exclude.excludes(
+            event.pubkey(),
+            event.delegator(),
+            event.kind(),
+            event.tags(),
+        )
     }
 
     pub fn match_archived_except_tag(&self, event: &ArchivedEventIndex) -> bool {
         Self::match_id(&self.ids, event.id())
-            && self.since.map_or(true, |t| event.created_at() >= t)
-            && self.until.map_or(true, |t| event.created_at() <= t)
+            && self.since.is_none_or(|t| event.created_at() >= t)
+            && self.until.is_none_or(|t| event.created_at() <= t)
             && Self::match_kind(&self.kinds, event.kind())
             && Self::match_author(&self.authors, event.pubkey(), event.delegator())
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_get_then_check, clippy::unnecessary_to_owned)]
 mod tests {
     use std::{collections::HashMap, str::FromStr};
 
-    use super::Filter;
+    use super::{Exclude, Filter};
     use crate::{filter::SortList, ArchivedEventIndex, Event, EventIndex};
     use anyhow::Result;
 
@@ -414,6 +693,14 @@ mod tests {
         let filter: Result<Filter, _> = serde_json::from_str(note);
         assert!(filter.is_err());
 
+        let note = r##"{"#tt": ["ab"]}"##;
+        let err = Filter::from_str(note).unwrap_err().to_string();
+        assert!(err.starts_with("invalid: unsupported tag filter \"#tt\""));
+
+        let note = r#"{"authors": ["xyz"]}"#;
+        let err = Filter::from_str(note).unwrap_err().to_string();
+        assert!(err.starts_with("invalid: authors must be 64 character hex strings"));
+
         let note = r###"
         {
             "#e": ["ab"],
@@ -454,13 +741,51 @@ mod tests {
         if matched {
             assert!(filter.r#match(event.index()));
             assert!(filter.match_archived(archived));
+            assert!(filter.match_event(event));
         } else {
             assert!(!filter.r#match(event.index()));
             assert!(!filter.match_archived(archived));
+            assert!(!filter.match_event(event));
         }
         Ok(())
     }
 
+    #[test]
+    fn exclude() -> Result<()> {
+        let note = r#"
+        {
+            "content": "Good morning everyone 😃",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": [["t", "nostr"]]
+          }
+        "#;
+        let event: Event = serde_json::from_str(note)?;
+        let bytes = event.index().to_bytes()?;
+        let archived = EventIndex::from_zeroes(&bytes)?;
+        let check = |json: &str, matched: bool| -> Result<()> {
+            let mut filter = Filter::from_str(json)?;
+            filter.exclude = Exclude::from_value(&serde_json::from_str(json)?)?;
+            assert_eq!(filter.match_event(&event), matched);
+            assert_eq!(filter.match_archived(archived), matched);
+            Ok(())
+        };
+        check(r#"{"kinds": [1], "!kinds": [7]}"#, true)?;
+        check(r#"{"kinds": [1], "!kinds": [1]}"#, false)?;
+        check(
+            r#"{"!authors": ["7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef"]}"#,
+            false,
+        )?;
+        check(r#"{"!#t": ["spam"]}"#, true)?;
+        check(r#"{"!#t": ["spam", "nostr"]}"#, false)?;
+        // the other fields are ignored
+        assert!(Exclude::from_value(&serde_json::from_str(r#"{"kinds": [1]}"#)?)?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn match_event() -> Result<()> {
         let note = r#"
@@ -495,7 +820,6 @@ mod tests {
             "authors": ["7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef", "0000000000000000000000000000000000000000000000000000000000000000"],
             "kind": [1, 2],
             "#t": ["nostr", "other"],
-            "#subject": ["db", "other"],
             "since": 1680690000,
             "util": 2680690000
         }
@@ -553,6 +877,59 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn match_search() -> Result<()> {
+        let note = r#"
+        {
+            "content": "Good morning everyone 😃",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": []
+          }
+        "#;
+        let event: Event = serde_json::from_str(note)?;
+        let filter = Filter::from_str(r#"{"search": "morning good"}"#)?;
+        assert_eq!(filter.match_event(&event), cfg!(feature = "search"));
+        let filter = Filter::from_str(r#"{"search": "morning night"}"#)?;
+        assert!(!filter.match_event(&event));
+        let filter = Filter::from_str(r#"{"search": "morning", "kinds": [2]}"#)?;
+        assert!(!filter.match_event(&event));
+        Ok(())
+    }
+
+    #[cfg(feature = "search")]
+    #[test]
+    fn relevance() -> Result<()> {
+        let note = |content: &str, created_at: u64| -> Result<Event> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "content": content,
+                "created_at": created_at,
+                "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+                "kind": 1,
+                "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+                "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+                "tags": []
+            }))?)
+        };
+        let mut filter = Filter::from_str(r#"{"search": "nostr relay"}"#)?;
+        filter.build_words();
+        let day = 86400;
+        let now = 100 * day;
+        let once = note("a nostr relay and a lot of other words", now)?;
+        let twice = note("nostr relay, the nostr relay", now)?;
+        let short = note("nostr relay", now)?;
+        let old = note("nostr relay", now - 7 * day)?;
+        let score = |e: &Event| filter.relevance(e, now, 7 * day);
+        assert!(score(&twice) > score(&once));
+        assert!(score(&short) > score(&once));
+        assert!((score(&old) - score(&short) / 2.0).abs() < 1e-9);
+        assert_eq!(score(&note("other", now)?), 0.0);
+        Ok(())
+    }
+
     #[test]
     fn tag_contains() -> Result<()> {
         let note = r#"
@@ -114,7 +114,7 @@ pub fn encode_replace_key(kind: u16, pubkey: &[u8; 32], tags: &[Vec<String>]) ->
         let k = u16_to_ver(kind);
         let p: &[u8] = pubkey.as_ref();
         let tag = tags
-            .get(0)
+            .first()
             .map(|tag| {
                 if tag.len() > 1 && tag[0] == "d" {
                     tag.get(1).unwrap().clone()
@@ -145,11 +145,11 @@ pub fn decode_replace_key<'a>(val: &'a [u8], time: &'a [u8]) -> Result<ReplaceKe
 
 // pad '0' at beginning if has not enough length
 #[allow(unused)]
-pub fn pad_start(id: &Vec<u8>, len: usize) -> Vec<u8> {
+pub fn pad_start(id: &[u8], len: usize) -> Vec<u8> {
     let num = len as i32 - id.len() as i32;
     match num.cmp(&0) {
         std::cmp::Ordering::Less => id[0..len].to_vec(),
-        std::cmp::Ordering::Equal => id.clone(),
+        std::cmp::Ordering::Equal => id.to_vec(),
         std::cmp::Ordering::Greater => {
             let num = num as usize;
             let mut ret = vec![0; len];
@@ -167,17 +167,17 @@ mod tests {
     #[test]
     fn pad() {
         assert_eq!(
-            pad_start(&vec![1, 2, 3], 32),
+            pad_start(&[1, 2, 3], 32),
             [vec![0u8; 29], vec![1, 2, 3]].concat()
         );
-        assert_eq!(pad_start(&vec![1; 33], 32), vec![1; 32]);
-        assert_eq!(pad_start(&vec![2; 32], 32), vec![2; 32]);
+        assert_eq!(pad_start(&[1; 33], 32), vec![1; 32]);
+        assert_eq!(pad_start(&[2; 32], 32), vec![2; 32]);
     }
 
     #[test]
     fn index_key() -> Result<()> {
         let time = 20u64;
-        let id = pad_start(&vec![1, 2, 3], 32);
+        let id = pad_start(&[1, 2, 3], 32);
         let kind = 10u16;
         let pubkey = vec![1; 32];
         let tag_key = "d";
@@ -0,0 +1,188 @@
+//! Sets of event kinds in the configuration, merged into sorted intervals
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::fmt;
+
+/// The groups of kinds by [NIP-01](https://nips.be/1)
+const GROUPS: &[(&str, &[(u16, u16)])] = &[
+    ("regular", &[(1, 2), (4, 44), (1000, 9999)]),
+    ("replaceable", &[(0, 0), (3, 3), (10000, 19999)]),
+    ("ephemeral", &[(20000, 29999)]),
+    ("addressable", &[(30000, 39999)]),
+];
+
+/// A set of kinds, the items of the list are
+///
+/// - a kind: `1`
+/// - an included range: `"30000-39999"`
+/// - a group: `"regular"`, `"replaceable"`, `"ephemeral"` or `"addressable"`
+/// - a range included(start) to excluded(end): `[30000, 40000]`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Kinds(Vec<(u16, u16)>);
+
+impl Kinds {
+    /// merge the included ranges
+    pub fn new(mut ranges: Vec<(u16, u16)>) -> Self {
+        ranges.retain(|r| r.0 <= r.1);
+        ranges.sort_unstable();
+        let mut merged: Vec<(u16, u16)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start as u32 <= last.1 as u32 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Self(merged)
+    }
+
+    pub fn contains(&self, kind: u16) -> bool {
+        let i = self.0.partition_point(|r| r.1 < kind);
+        self.0.get(i).is_some_and(|r| r.0 <= kind)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// the included ranges
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.0
+    }
+
+    /// all kinds of the set
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().flat_map(|r| r.0..=r.1)
+    }
+}
+
+impl From<Vec<u16>> for Kinds {
+    fn from(kinds: Vec<u16>) -> Self {
+        Self::new(kinds.into_iter().map(|k| (k, k)).collect())
+    }
+}
+
+/// parse a kind, a range or a group
+fn parse(s: &str) -> Option<Vec<(u16, u16)>> {
+    let s = s.trim();
+    if let Some((_, ranges)) = GROUPS.iter().find(|g| g.0 == s) {
+        return Some(ranges.to_vec());
+    }
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end).then(|| vec![(start, end)])
+}
+
+/// The items of the list
+struct Item(Vec<(u16, u16)>);
+
+struct ItemVisitor;
+impl<'de> Visitor<'de> for ItemVisitor {
+    type Value = Item;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a kind, a range or a group of kinds")
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let kind = u16::try_from(v).map_err(|_| E::custom(format!("invalid kind {}", v)))?;
+        Ok(Item(vec![(kind, kind)]))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let v = u64::try_from(v).map_err(|_| E::custom(format!("invalid kind {}", v)))?;
+        self.visit_u64(v)
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        parse(v)
+            .map(Item)
+            .ok_or_else(|| E::custom(format!("invalid kinds \"{}\"", v)))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let start: u32 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let end: u32 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if start >= end || end > u16::MAX as u32 + 1 {
+            return Err(de::Error::custom(format!(
+                "invalid kind range [{}, {}]",
+                start, end
+            )));
+        }
+        Ok(Item(vec![(start as u16, (end - 1) as u16)]))
+    }
+}
+
+impl<'de> Deserialize<'de> for Item {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ItemVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for Kinds {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let items = Vec::<Item>::deserialize(deserializer)?;
+        Ok(Self::new(items.into_iter().flat_map(|i| i.0).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn deser() -> Result<()> {
+        let kinds: Kinds = serde_json::from_str(r#"[1, 2, [30000, 40000], "5-7", "6-9"]"#)?;
+        assert_eq!(kinds.ranges(), &[(1, 2), (5, 9), (30000, 39999)]);
+        assert!(kinds.contains(1));
+        assert!(!kinds.contains(0));
+        assert!(!kinds.contains(3));
+        assert!(kinds.contains(8));
+        assert!(kinds.contains(30000));
+        assert!(kinds.contains(39999));
+        assert!(!kinds.contains(40000));
+        assert_eq!(kinds.iter().take(4).collect::<Vec<_>>(), vec![1, 2, 5, 6]);
+
+        let kinds: Kinds = serde_json::from_str(r#"["replaceable", "ephemeral", "1"]"#)?;
+        assert_eq!(kinds.ranges(), &[(0, 1), (3, 3), (10000, 29999)]);
+        let kinds: Kinds = serde_json::from_str(r#"["0-65535"]"#)?;
+        assert!(kinds.contains(u16::MAX));
+
+        for json in [
+            r#"["9-1"]"#,
+            r#"["replace"]"#,
+            "[65536]",
+            "[-1]",
+            "[[2, 2]]",
+            "[[1]]",
+        ] {
+            assert!(serde_json::from_str::<Kinds>(json).is_err(), "{}", json);
+        }
+        Ok(())
+    }
+}
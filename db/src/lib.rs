@@ -1,15 +1,20 @@
 //! Nostr event database
 
+mod bloom;
 mod db;
 mod error;
 mod event;
 mod filter;
 mod key;
+mod kinds;
 pub use secp256k1;
 
 pub use {
-    db::CheckEventResult, db::Db, db::Iter, error::Error, event::now, event::ArchivedEventIndex,
-    event::Event, event::EventIndex, event::FromEventData, filter::Filter, filter::SortList,
+    db::Ban, db::CheckEventResult, db::Cursor, db::Db, db::Invite, db::Iter, db::Member,
+    db::Reputation, db::TimeSeries, db::Tombstone, error::Error, event::now,
+    event::ArchivedEventIndex, event::Event, event::EventIndex, event::EventSeed, event::EventTags,
+    event::FromEventData, filter::Exclude, filter::Filter, filter::Pattern, filter::Rank,
+    filter::SortList, kinds::Kinds,
 };
 
 pub use nostr_kv as kv;
@@ -23,25 +28,9 @@ pub struct Stats {
 }
 
 #[cfg(feature = "search")]
-use charabia::Segment;
-
+mod tokenizer;
 #[cfg(feature = "search")]
-/// segment keywords by charabia
-pub fn segment(content: &str) -> Vec<Vec<u8>> {
-    let iter = content.segment_str();
-    let mut words = iter
-        .filter_map(|s| {
-            let s = s.to_lowercase();
-            let bytes = s.as_bytes();
-            // limit size
-            if bytes.len() < 255 {
-                Some(bytes.to_vec())
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-    words.sort();
-    words.dedup();
-    words
-}
+pub use tokenizer::{
+    segment, segment_event, segment_query, segment_tokens, set_index_options, set_tokenizer, Cjk,
+    IndexOptions, PatternOptions, Tokenizer,
+};
@@ -0,0 +1,434 @@
+//! Split the note content and the search keywords into the indexed words
+use crate::{filter::Pattern, Kinds};
+use charabia::Segment;
+use serde::Deserialize;
+use std::sync::{LazyLock, RwLock};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// The max bytes of an indexed word
+const MAX_WORD_LEN: usize = 255;
+
+static TOKENIZER: RwLock<Tokenizer> = RwLock::new(Tokenizer::DEFAULT);
+
+static INDEX: LazyLock<RwLock<IndexOptions>> = LazyLock::new(Default::default);
+
+/// Which events are indexed, the content of the other events is not searchable
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct IndexOptions {
+    /// the kinds, ranges or groups of the indexed events
+    pub kinds: Kinds,
+    /// only the leading bytes of the longer content are indexed, 0 is unlimited
+    pub max_content_length: usize,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            kinds: vec![1].into(),
+            max_content_length: 0,
+        }
+    }
+}
+
+impl IndexOptions {
+    /// the indexed part of the content
+    pub fn content<'a>(&self, kind: u16, content: &'a str) -> Option<&'a str> {
+        if !self.kinds.contains(kind) {
+            return None;
+        }
+        if self.max_content_length == 0 || content.len() <= self.max_content_length {
+            return Some(content);
+        }
+        let mut end = self.max_content_length;
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        Some(&content[..end])
+    }
+}
+
+/// How to split the text of the languages without spaces
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Cjk {
+    /// segment the words by the dictionaries of charabia, ie: jieba for chinese
+    #[default]
+    Dictionary,
+    /// index the characters and the overlapping pairs of characters,
+    /// the keywords match any part of the text without dictionaries
+    Bigram,
+}
+
+/// The words of the stored events are not changed with the tokenizer,
+/// reindex the events by `Db::reindex_words` with the new options.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Tokenizer {
+    pub cjk: Cjk,
+    /// fold the accents of the latin, greek and cyrillic letters, ie: "café" matches "cafe"
+    pub fold_diacritics: bool,
+    /// index the emoji as words
+    pub emoji: bool,
+}
+
+impl Tokenizer {
+    pub const DEFAULT: Tokenizer = Tokenizer {
+        cjk: Cjk::Dictionary,
+        fold_diacritics: false,
+        emoji: true,
+    };
+
+    /// the sorted words of the content
+    pub fn words(&self, content: &str, query: bool) -> Vec<Vec<u8>> {
+        let mut words = self.tokens(content, query);
+        words.sort();
+        words.dedup();
+        words
+    }
+
+    /// the words in the order of the content, with the repeated words
+    pub fn tokens(&self, content: &str, query: bool) -> Vec<Vec<u8>> {
+        let mut words = Vec::new();
+        let mut add = |word: &str| {
+            let word = self.normalize(word);
+            if word.len() < MAX_WORD_LEN && self.is_word(&word) {
+                words.push(word.into_bytes());
+            }
+        };
+        match self.cjk {
+            Cjk::Dictionary => content.segment_str().for_each(add),
+            Cjk::Bigram => {
+                let mut rest = content;
+                while !rest.is_empty() {
+                    let cjk = rest.starts_with(is_cjk);
+                    let end = rest.find(|c| is_cjk(c) != cjk).unwrap_or(rest.len());
+                    let (run, next) = rest.split_at(end);
+                    if cjk {
+                        bigrams(run, query, &mut add);
+                    } else {
+                        run.segment_str().for_each(&mut add);
+                    }
+                    rest = next;
+                }
+            }
+        }
+        words
+    }
+
+    fn normalize(&self, word: &str) -> String {
+        let word = word.to_lowercase();
+        if self.fold_diacritics {
+            word.chars()
+                .flat_map(|c| {
+                    let fold = !is_cjk(c);
+                    Some(c)
+                        .into_iter()
+                        .nfd()
+                        .filter(move |c| !(fold && is_combining_mark(*c)))
+                })
+                .nfc()
+                .collect()
+        } else {
+            word
+        }
+    }
+
+    /// skip the spaces and punctuation
+    fn is_word(&self, word: &str) -> bool {
+        word.chars()
+            .any(|c| c.is_alphanumeric() || (self.emoji && is_emoji(c)))
+    }
+}
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// the pairs of characters, the single characters are indexed for the one character keywords
+fn bigrams(run: &str, query: bool, add: &mut impl FnMut(&str)) {
+    let mut bounds = run.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+    bounds.push(run.len());
+    if !query || bounds.len() == 2 {
+        bounds.windows(2).for_each(|w| add(&run[w[0]..w[1]]));
+    }
+    bounds.windows(3).for_each(|w| add(&run[w[0]..w[2]]));
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x11FF // hangul jamo
+        | 0x3040..=0x30FF // hiragana, katakana
+        | 0x3130..=0x318F // hangul compatibility jamo
+        | 0x3400..=0x4DBF // cjk extension a
+        | 0x4E00..=0x9FFF // cjk unified ideographs
+        | 0xAC00..=0xD7AF // hangul syllables
+        | 0xF900..=0xFAFF // cjk compatibility ideographs
+        | 0x20000..=0x2FA1F // cjk extensions
+    )
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x2300..=0x23FF | 0x2600..=0x27BF | 0x2B00..=0x2BFF | 0x1F000..=0x1FAFF
+    )
+}
+
+/// Set the tokenizer of the process, the same options are required by the indexing and the search
+pub fn set_tokenizer(tokenizer: Tokenizer) {
+    *TOKENIZER.write().unwrap_or_else(|e| e.into_inner()) = tokenizer;
+}
+
+/// Set the indexed events of the process
+pub fn set_index_options(options: IndexOptions) {
+    *INDEX.write().unwrap_or_else(|e| e.into_inner()) = options;
+}
+
+/// the words of the event content, empty if the event is not indexed
+pub fn segment_event(kind: u16, content: &str) -> Vec<Vec<u8>> {
+    let index = INDEX.read().unwrap_or_else(|e| e.into_inner());
+    index
+        .content(kind, content)
+        .map(segment)
+        .unwrap_or_default()
+}
+
+/// the words of the note content
+pub fn segment(content: &str) -> Vec<Vec<u8>> {
+    TOKENIZER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .words(content, false)
+}
+
+/// the words of the note content with the repeated words, for the term frequency
+pub fn segment_tokens(content: &str) -> Vec<Vec<u8>> {
+    TOKENIZER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .tokens(content, false)
+}
+
+/// the words of the search keywords, all of them must be in the content
+pub fn segment_query(search: &str) -> Vec<Vec<u8>> {
+    TOKENIZER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .words(search, true)
+}
+
+/// The prefix `oly*` and the fuzzy `word~`, `word~1` keywords of the search.
+/// The keywords are matched exactly when the options are disabled or the limits are reached.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PatternOptions {
+    pub prefix: bool,
+    pub fuzzy: bool,
+    /// the min characters of the pattern keyword
+    pub min_len: usize,
+    /// the max edit distance of the fuzzy keyword, the default distance of `word~`
+    pub max_distance: u8,
+    /// the leading characters of the fuzzy keyword must be the same
+    pub fuzzy_prefix_len: usize,
+    /// the max patterns of a search
+    pub max_patterns: usize,
+    /// the max indexed words matched by a pattern
+    pub max_expansions: usize,
+    /// the max indexed words scanned by a search
+    pub max_scan: usize,
+}
+
+impl Default for PatternOptions {
+    fn default() -> Self {
+        Self {
+            prefix: false,
+            fuzzy: false,
+            min_len: 3,
+            max_distance: 2,
+            fuzzy_prefix_len: 1,
+            max_patterns: 3,
+            max_expansions: 50,
+            max_scan: 10_000,
+        }
+    }
+}
+
+impl PatternOptions {
+    /// split the search into the exact words and the patterns
+    pub fn parse(&self, search: &str) -> (Vec<Vec<u8>>, Vec<Pattern>) {
+        let mut exact = vec![];
+        let mut patterns = vec![];
+        for item in search.split_whitespace() {
+            match self.split(item) {
+                Some((stem, distance)) => match self.pattern(stem, distance) {
+                    Some(p) if patterns.len() < self.max_patterns => patterns.push(p),
+                    _ => exact.push(stem),
+                },
+                None => exact.push(item),
+            }
+        }
+        (segment_query(&exact.join(" ")), patterns)
+    }
+
+    /// the stem and the distance of the enabled pattern syntax
+    fn split<'a>(&self, item: &'a str) -> Option<(&'a str, Option<u8>)> {
+        match item.strip_suffix('*') {
+            Some(stem) if self.prefix => Some((stem, None)),
+            _ if self.fuzzy => {
+                let (stem, distance) = item.rsplit_once('~')?;
+                let distance = if distance.is_empty() {
+                    self.max_distance
+                } else {
+                    distance.parse::<u8>().ok()?.min(self.max_distance)
+                };
+                Some((stem, Some(distance)))
+            }
+            _ => None,
+        }
+    }
+
+    fn pattern(&self, stem: &str, distance: Option<u8>) -> Option<Pattern> {
+        let mut words = segment_query(stem);
+        if words.len() != 1 {
+            return None;
+        }
+        let word = words.pop()?;
+        let text = std::str::from_utf8(&word).ok()?;
+        if text.chars().count() < self.min_len {
+            return None;
+        }
+        Some(match distance {
+            None => Pattern::Prefix(word),
+            Some(distance) => {
+                let prefix = text
+                    .char_indices()
+                    .nth(self.fuzzy_prefix_len)
+                    .map_or(text.len(), |(i, _)| i);
+                Pattern::Fuzzy {
+                    word,
+                    distance,
+                    prefix,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(tokenizer: &Tokenizer, content: &str, query: bool) -> Vec<String> {
+        tokenizer
+            .words(content, query)
+            .into_iter()
+            .map(|w| String::from_utf8(w).unwrap())
+            .collect()
+    }
+
+    fn matches(tokenizer: &Tokenizer, content: &str, search: &str) -> bool {
+        let content = tokenizer.words(content, false);
+        let search = tokenizer.words(search, true);
+        !search.is_empty() && search.iter().all(|w| content.contains(w))
+    }
+
+    #[test]
+    fn dictionary() {
+        let t = Tokenizer::default();
+        assert_eq!(
+            words(&t, "Hello, World! 🚀", false),
+            vec!["hello", "world", "🚀"]
+        );
+        assert!(matches(&t, "我爱北京天安门", "北京"));
+        // the dictionary segments do not match the part of a word
+        assert!(!matches(&t, "今天天气很好", "天气"));
+        assert!(!matches(&t, "Café", "cafe"));
+        let t = Tokenizer {
+            emoji: false,
+            ..Default::default()
+        };
+        assert_eq!(words(&t, "ok 🚀", false), vec!["ok"]);
+    }
+
+    #[test]
+    fn bigram() {
+        let t = Tokenizer {
+            cjk: Cjk::Bigram,
+            ..Default::default()
+        };
+        assert_eq!(words(&t, "北京abc", false), vec!["abc", "京", "北", "北京"]);
+        assert_eq!(words(&t, "天安门", true), vec!["天安", "安门"]);
+        assert!(matches(&t, "今天天气很好", "天气"));
+        assert!(matches(&t, "今天天气很好", "天"));
+        assert!(matches(&t, "我爱北京天安门", "北京天安门"));
+        assert!(!matches(&t, "我爱北京天安门", "京北"));
+        assert!(matches(&t, "東京タワーに行きました", "タワー"));
+        assert!(matches(&t, "안녕하세요 세계", "하세요"));
+    }
+
+    #[test]
+    fn fold_diacritics() {
+        let t = Tokenizer {
+            fold_diacritics: true,
+            ..Default::default()
+        };
+        assert!(matches(&t, "Café au lait, naïve résumé", "cafe resume"));
+        assert!(matches(&t, "Ελλάδα", "ελλαδα"));
+        // the japanese sound marks are kept
+        assert_eq!(words(&t, "ガ", false), vec!["ガ"]);
+    }
+
+    #[test]
+    fn index_options() {
+        let options = IndexOptions {
+            kinds: vec![1, 30023].into(),
+            max_content_length: 4,
+        };
+        assert_eq!(options.content(1, "abc"), Some("abc"));
+        assert_eq!(options.content(30023, "abcdef"), Some("abcd"));
+        assert_eq!(options.content(1, "abcé"), Some("abc"));
+        assert_eq!(options.content(0, "abc"), None);
+        assert_eq!(IndexOptions::default().content(1, "abcdef"), Some("abcdef"));
+    }
+
+    #[test]
+    fn patterns() {
+        let options = PatternOptions::default();
+        assert_eq!(
+            options.parse("oly* word~"),
+            (vec![b"oly".to_vec(), b"word".to_vec()], vec![])
+        );
+        let options = PatternOptions {
+            prefix: true,
+            fuzzy: true,
+            max_patterns: 2,
+            ..Default::default()
+        };
+        let (words, patterns) = options.parse("Oly* nostr~5 ab* relay~1 more*");
+        assert_eq!(
+            words,
+            vec![b"ab".to_vec(), b"more".to_vec(), b"relay".to_vec()]
+        );
+        assert_eq!(
+            patterns,
+            vec![
+                Pattern::Prefix(b"oly".to_vec()),
+                Pattern::Fuzzy {
+                    word: b"nostr".to_vec(),
+                    distance: 2,
+                    prefix: 1
+                }
+            ]
+        );
+        assert!(patterns[0].matches(b"olympics"));
+        assert!(!patterns[0].matches(b"ol"));
+        assert!(patterns[1].matches(b"nostr"));
+        assert!(patterns[1].matches(b"nost"));
+        assert!(patterns[1].matches(b"noster"));
+        assert!(patterns[1].matches(b"nsotr"));
+        assert!(!patterns[1].matches(b"ostr"));
+        assert!(!patterns[1].matches(b"nxxxr"));
+    }
+}
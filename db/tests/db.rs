@@ -1,4 +1,13 @@
-use nostr_db::{Db, Error, Event, Filter, Stats};
+#![allow(
+    clippy::get_first,
+    clippy::needless_borrow,
+    clippy::while_let_on_iterator
+)]
+
+use nostr_db::{
+    now, Ban, CheckEventResult, Cursor, Db, Error, Event, Filter, Invite, Member, PatternOptions,
+    Reputation, Stats, Tombstone,
+};
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::thread::sleep;
@@ -455,6 +464,66 @@ pub fn test_events_dup() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_events_reopen() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-events-reopen")
+        .tempdir()
+        .unwrap();
+    let event = |index| -> Event {
+        MyEvent {
+            id: id(0, index),
+            pubkey: author(1),
+            kind: 1,
+            created_at: index as u64,
+            ..Default::default()
+        }
+        .into()
+    };
+    {
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![event(1), event(2)])?;
+    }
+    // the first event after reopen must not reuse the uid of the latest event
+    let db = Db::open(dir.path())?;
+    db.batch_put(vec![event(3)])?;
+    {
+        let reader = db.reader()?;
+        for index in 1..=3 {
+            let e: Option<Event> = db.get(&reader, id(0, index))?;
+            assert_eq!(e.unwrap().id(), &id(0, index));
+        }
+    }
+    let (events, _) = all(&db, &Filter::default())?;
+    assert_eq!(events.len(), 3);
+    Ok(())
+}
+
+#[test]
+pub fn test_stat() -> Result<()> {
+    let db = create_db("test_stat")?;
+    let events: Vec<Event> = (1..=3)
+        .map(|index| {
+            MyEvent {
+                id: id(0, index),
+                pubkey: author(1),
+                kind: 1,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect();
+    db.batch_put(&events)?;
+    let (env, info) = db.env_stat()?;
+    let trees = db.tree_stats()?;
+    assert!(env.page_size > 0);
+    assert!(info.map_size > 0);
+    let data = trees.iter().find(|(name, _)| *name == "data").unwrap();
+    assert_eq!(data.1.entries, 3);
+    assert!(data.1.size() > 0);
+    Ok(())
+}
+
 #[test]
 pub fn test_events_delegator() -> Result<()> {
     let db = create_db("test_events_delegator")?;
@@ -1272,6 +1341,147 @@ pub fn test_query_search() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_search_patterns() -> Result<()> {
+    let db = create_db("test_query_search_patterns")?;
+    let events = [
+        "olympic games",
+        "the olympics",
+        "only nostr",
+        "noster relay",
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, content)| {
+        MyEvent {
+            id: id(10, i as u8),
+            pubkey: author(1),
+            kind: 1,
+            content: content.to_owned(),
+            created_at: i as u64 * 1000,
+            ..Default::default()
+        }
+        .into_and_build_words()
+    })
+    .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    // the stored events and the events matched by the subscription
+    let search = |search: &str, options: &PatternOptions| -> Result<(Vec<String>, Vec<String>)> {
+        let mut filter = Filter {
+            search: Some(search.to_string()),
+            desc: false,
+            ..Default::default()
+        };
+        // expanded by the query
+        filter.build_search(options);
+        let mut events = all(&db, &filter)?
+            .0
+            .into_iter()
+            .map(|e| e.content().to_owned())
+            .collect::<Vec<_>>();
+        events.sort();
+        let matched = all(&db, &Filter::default())?
+            .0
+            .into_iter()
+            .filter(|e| filter.match_event(e))
+            .map(|e| e.content().to_owned())
+            .collect::<Vec<_>>();
+        Ok((events, matched))
+    };
+    let query = |s: &str, options: &PatternOptions| -> Result<Vec<String>> {
+        let (events, mut matched) = search(s, options)?;
+        matched.sort();
+        assert_eq!(events, matched);
+        Ok(events)
+    };
+
+    let options = PatternOptions {
+        prefix: true,
+        fuzzy: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        query("oly*", &options)?,
+        vec!["olympic games", "the olympics"]
+    );
+    assert_eq!(query("oly* games", &options)?, vec!["olympic games"]);
+    assert_eq!(
+        query("nostr~1", &options)?,
+        vec!["noster relay", "only nostr"]
+    );
+    assert_eq!(query("oly* nostr~", &options)?, Vec::<String>::new());
+    assert_eq!(query("xyz*", &options)?, Vec::<String>::new());
+    // disabled
+    assert_eq!(
+        query("oly*", &PatternOptions::default())?,
+        Vec::<String>::new()
+    );
+    // too short
+    assert_eq!(query("ol*", &options)?, Vec::<String>::new());
+    // limits
+    let limited = PatternOptions {
+        max_expansions: 1,
+        ..options.clone()
+    };
+    assert_eq!(search("oly*", &limited)?.0, vec!["olympic games"]);
+    let limited = PatternOptions {
+        max_scan: 1,
+        ..options
+    };
+    let (events, matched) = search("oly* games", &limited)?;
+    assert_eq!(events, vec!["olympic games"]);
+    assert_eq!(matched, vec!["olympic games"]);
+    assert_eq!(search("oly* nostr~1", &limited)?.0, Vec::<String>::new());
+    Ok(())
+}
+
+#[test]
+pub fn test_reindex_words() -> Result<()> {
+    let db = create_db("test_reindex_words")?;
+    let events = (0..10u8)
+        .map(|i| {
+            MyEvent {
+                id: id(10, i),
+                pubkey: author(1),
+                kind: 1,
+                content: "reindex note".to_owned(),
+                created_at: i as u64 * 1000,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let mut filter = Filter {
+        search: Some("reindex".to_string()),
+        ..Default::default()
+    };
+    filter.build_words();
+    assert_eq!(all(&db, &filter)?.0.len(), 0);
+
+    let mut after = None;
+    let mut batches = 0;
+    loop {
+        let (last, num) = db.reindex_words(after.as_deref(), 3)?;
+        if num == 0 {
+            break;
+        }
+        after = Some(last);
+        batches += 1;
+    }
+    assert_eq!(batches, 4);
+    assert_eq!(all(&db, &filter)?.0.len(), 10);
+    // reindex again without duplicates
+    db.reindex_words(None, 100)?;
+    assert_eq!(all(&db, &filter)?.0.len(), 10);
+
+    db.clear_words()?;
+    assert_eq!(all(&db, &filter)?.0.len(), 0);
+    Ok(())
+}
+
 #[test]
 pub fn test_query_scan_limit_time() -> Result<()> {
     let db = create_db("test_query_scan_limit_time")?;
@@ -1310,6 +1520,44 @@ pub fn test_query_scan_limit_time() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_scan_budget() -> Result<()> {
+    let db = create_db("test_query_scan_budget")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(0, i),
+                pubkey: author(10),
+                kind: 1,
+                content: "author 1".to_owned(),
+                created_at: i as u64 * 1000,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let reader = db.reader()?;
+    // the budget stops the scan before the end
+    let filter = Filter {
+        kinds: vec![1].into(),
+        authors: vec![author(10)].into(),
+        ..Default::default()
+    };
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.scan_limits(None, Some(10), 2000);
+    let mut found = 0;
+    let res = iter.try_for_each(|e| e.map(|_| found += 1));
+    assert!(matches!(res, Err(Error::ScanBudget)));
+    assert!(found > 0 && found <= 10);
+
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.scan_limits(None, Some(PER_NUM as u64 * 2), 2000);
+    assert_eq!(iter.count(), PER_NUM as usize);
+    Ok(())
+}
+
 #[test]
 pub fn test_events_ephemeral() -> Result<()> {
     let db = create_db("test_events_ephemeral")?;
@@ -1391,3 +1639,467 @@ pub fn test_events_ephemeral() -> Result<()> {
     }
     Ok(())
 }
+
+fn page(db: &Db, filter: &Filter) -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    let mut cursor: Option<Cursor> = None;
+    loop {
+        // a new read transaction per page
+        let reader = db.reader()?;
+        let mut iter = db.iter_after::<Event, _>(&reader, filter, cursor.as_ref())?;
+        let mut len = 0;
+        for e in iter.by_ref() {
+            events.push(e?);
+            len += 1;
+        }
+        if len == 0 {
+            break;
+        }
+        // pass through apis as string
+        cursor = Some(iter.cursor().unwrap().to_string().parse()?);
+    }
+    Ok(events)
+}
+
+#[test]
+pub fn test_query_cursor() -> Result<()> {
+    let db = create_db("test_query_cursor")?;
+    // events share the created_at
+    let events = (0..25)
+        .map(|i| {
+            MyEvent {
+                id: id(31, i),
+                pubkey: author(251),
+                kind: 1 + (i % 2) as u16,
+                content: "cursor".to_owned(),
+                created_at: (i / 3) as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    for desc in [true, false] {
+        let filter = Filter {
+            desc,
+            limit: Some(4),
+            authors: vec![author(251)].into(),
+            ..Default::default()
+        };
+        let events = page(&db, &filter)?;
+        assert_eq!(events.len(), 25);
+        let mut ids = events.iter().map(|e| *e.id()).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 25);
+        let times = events.iter().map(|e| e.created_at()).collect::<Vec<_>>();
+        let mut sorted = times.clone();
+        sorted.sort();
+        if desc {
+            sorted.reverse();
+        }
+        assert_eq!(times, sorted);
+
+        // match the index
+        let filter = Filter {
+            kinds: vec![2].into(),
+            ..filter
+        };
+        let events = page(&db, &filter)?;
+        assert_eq!(events.len(), 12);
+        assert!(events.iter().all(|e| e.kind() == 2));
+    }
+
+    assert!("00".parse::<Cursor>().is_err());
+    Ok(())
+}
+
+#[test]
+pub fn test_iter_uid() -> Result<()> {
+    let db = create_db("test_iter_uid")?;
+    let events = (0..5)
+        .map(|i| {
+            MyEvent {
+                id: id(32, i),
+                pubkey: author(252),
+                kind: 1,
+                content: "uid".to_owned(),
+                created_at: 10 - i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+
+    let reader = db.reader()?;
+    let list = db
+        .iter_uid::<Event, _>(&reader, None)
+        .collect::<Result<Vec<_>, _>>()?;
+    // the write order
+    assert_eq!(
+        list.iter().map(|(_, e)| *e.id()).collect::<Vec<_>>(),
+        events.iter().map(|e| *e.id()).collect::<Vec<_>>()
+    );
+    let uids = list.iter().map(|(uid, _)| *uid).collect::<Vec<_>>();
+    assert!(uids.windows(2).all(|w| w[0] < w[1]));
+
+    let rest = db
+        .iter_uid::<Event, _>(&reader, Some(uids[2]))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(rest.len(), 2);
+    assert_eq!(rest[0].0, uids[3]);
+    assert_eq!(db.iter_uid::<Event, _>(&reader, Some(uids[4])).count(), 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_events_vanish() -> Result<()> {
+    let db = create_db("test_events_vanish")?;
+    let event =
+        |index: u8, pubkey: u8, created_at: u64, kind: u16, tags: Vec<Vec<String>>| -> Event {
+            MyEvent {
+                id: id(0, index),
+                pubkey: author(pubkey),
+                created_at,
+                kind,
+                tags,
+                ..Default::default()
+            }
+            .into()
+        };
+    let p = vec!["p".to_owned(), hex::encode(author(1))];
+    let events = vec![
+        event(1, 1, 10, 1, vec![]),
+        event(2, 1, 20, 0, vec![]),
+        event(3, 1, 40, 1, vec![]),
+        // the vanish request
+        event(
+            4,
+            1,
+            30,
+            62,
+            vec![vec!["relay".to_owned(), "ALL_RELAYS".to_owned()]],
+        ),
+        // gift wrap to the pubkey
+        event(5, 2, 20, 1059, vec![p.clone()]),
+        event(6, 2, 20, 1, vec![p]),
+    ];
+    db.batch_put(&events)?;
+
+    {
+        let mut writer = db.writer()?;
+        db.put_vanish(&mut writer, &author(1), 30)?;
+        // keep the latest
+        db.put_vanish(&mut writer, &author(1), 25)?;
+        db.commit(writer)?;
+    }
+    assert_eq!(db.vanish(&author(1), 30, 2)?, 2);
+    assert_eq!(db.vanish(&author(1), 30, 2)?, 1);
+    assert_eq!(db.vanish(&author(1), 30, 2)?, 0);
+    {
+        let reader = db.reader()?;
+        assert_eq!(db.vanished(&reader, author(1))?, Some(30));
+        assert_eq!(db.vanished(&reader, author(2))?, None);
+        assert_eq!(db.vanished_list(&reader)?, vec![(author(1).to_vec(), 30)]);
+        let left = [1, 2, 3, 4, 5, 6]
+            .into_iter()
+            .filter(|i| db.get::<Event, _, _>(&reader, id(0, *i)).unwrap().is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(left, vec![3, 4, 6]);
+    }
+
+    // the older events are rejected
+    let mut writer = db.writer()?;
+    assert!(matches!(
+        db.put(&mut writer, event(7, 1, 30, 1, vec![]))?,
+        CheckEventResult::Deleted
+    ));
+    assert!(matches!(
+        db.put(&mut writer, event(8, 1, 31, 1, vec![]))?,
+        CheckEventResult::Ok(_)
+    ));
+    Ok(())
+}
+
+#[test]
+pub fn test_bans() -> Result<()> {
+    let db = create_db("test_bans")?;
+    let ban = Ban {
+        reason: "spam".to_owned(),
+        expires_at: 100,
+    };
+    db.put_ban("pubkey", &hex::encode(author(1)), &ban)?;
+    db.put_ban("ip", "::1", &Ban::default())?;
+    assert_eq!(
+        db.bans()?,
+        vec![
+            ("ip".to_owned(), "::1".to_owned(), Ban::default()),
+            ("pubkey".to_owned(), hex::encode(author(1)), ban.clone()),
+        ]
+    );
+    assert!(!ban.expired(99));
+    assert!(ban.expired(100));
+    assert!(!Ban::default().expired(100));
+
+    assert!(db.del_ban("ip", "::1")?);
+    assert!(!db.del_ban("ip", "::1")?);
+    assert_eq!(db.bans()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+pub fn test_tombstones() -> Result<()> {
+    let db = create_db("test_tombstones")?;
+    let event: Event = MyEvent {
+        id: id(0, 1),
+        pubkey: author(1),
+        created_at: 10,
+        kind: 1,
+        ..Default::default()
+    }
+    .into();
+    db.batch_put([&event])?;
+    let tombstone = Tombstone {
+        reason: "illegal".to_owned(),
+        created_at: 20,
+    };
+    assert!(db.takedown(id(0, 1), &tombstone)?);
+    // the tombstone of an unknown event
+    assert!(!db.takedown(id(0, 2), &Tombstone::default())?);
+    {
+        let reader = db.reader()?;
+        assert!(db.get::<Event, _, _>(&reader, id(0, 1))?.is_none());
+    }
+    let mut writer = db.writer()?;
+    assert!(matches!(
+        db.put(&mut writer, &event)?,
+        CheckEventResult::TakenDown
+    ));
+    db.commit(writer)?;
+    assert_eq!(
+        db.tombstones()?,
+        vec![
+            (hex::encode(id(0, 1)), tombstone),
+            (hex::encode(id(0, 2)), Tombstone::default())
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_invites() -> Result<()> {
+    let db = create_db("test_invites")?;
+    let invite = Invite {
+        note: "friends".to_owned(),
+        created_at: 10,
+        expires_at: 100,
+        max_uses: 2,
+        ..Default::default()
+    };
+    db.put_invite("a", &invite)?;
+    db.put_invite("b", &Invite::default())?;
+    assert!(!db.redeem_invite("c", &hex::encode(author(1)), 20)?);
+    assert!(!db.redeem_invite("a", &hex::encode(author(1)), 100)?);
+    assert!(db.redeem_invite("a", &hex::encode(author(1)), 20)?);
+    // a member does not use it again
+    assert!(db.redeem_invite("a", &hex::encode(author(1)), 20)?);
+    assert!(db.redeem_invite("a", &hex::encode(author(2)), 20)?);
+    assert!(!db.redeem_invite("a", &hex::encode(author(3)), 20)?);
+    assert!(db.redeem_invite("b", &hex::encode(author(3)), 20)?);
+
+    let invites = db.invites()?;
+    assert_eq!(invites.len(), 2);
+    assert_eq!(invites[0], ("a".to_owned(), Invite { uses: 2, ..invite }));
+    assert_eq!(invites[1].1.uses, 1);
+    assert_eq!(db.members()?.len(), 3);
+
+    // minted by a member
+    let by = hex::encode(author(3));
+    let minted = Invite {
+        created_by: by.clone(),
+        ..Default::default()
+    };
+    db.put_invite("d", &minted)?;
+    assert!(db.redeem_invite("d", &hex::encode(author(4)), 30)?);
+    assert!(db.members()?.contains(&(
+        hex::encode(author(4)),
+        Member {
+            code: "d".to_owned(),
+            admitted_at: 30,
+            invited_by: by.clone(),
+        }
+    )));
+
+    assert!(db.del_invite("a")?);
+    assert!(!db.del_invite("a")?);
+    // the codes of the removed members are removed
+    assert_eq!(
+        db.del_members(&[by.clone(), hex::encode(author(5))])?,
+        vec![by]
+    );
+    assert_eq!(db.members()?.len(), 3);
+    assert_eq!(db.invites()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+pub fn test_reputations() -> Result<()> {
+    let db = create_db("test_reputations")?;
+    let reputation = Reputation {
+        score: 1.5,
+        updated_at: 10,
+        banned_until: 0,
+    };
+    db.put_reputations([("ip:::1", Some(&reputation)), ("pubkey:00", None)])?;
+    assert_eq!(db.reputations()?, vec![("ip:::1".to_owned(), reputation)]);
+    db.put_reputations([("ip:::1", None)])?;
+    assert!(db.reputations()?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn test_stored_after() -> Result<()> {
+    let db = create_db("test_stored_after")?;
+    let event = |index: u8, kind: u16| -> Event {
+        MyEvent {
+            id: id(1, index),
+            pubkey: author(1),
+            created_at: 10 - index as u64,
+            kind,
+            ..Default::default()
+        }
+        .into()
+    };
+    assert_eq!(db.last_uid(&db.reader()?)?, None);
+    db.batch_put([event(1, 1), event(2, 2)])?;
+    let uid = db.last_uid(&db.reader()?)?.unwrap() + 1;
+
+    // stored after the uid, even if created earlier
+    db.batch_put([event(3, 1), event(4, 2), event(5, 1)])?;
+    let reader = db.reader()?;
+    let filter = Filter::from_str(r#"{"kinds": [1]}"#)?;
+    let (events, last) = db
+        .iter_stored_from::<Event, _>(&reader, uid, &filter, 10)?
+        .unwrap();
+    assert_eq!(
+        events.iter().map(|e| *e.id()).collect::<Vec<_>>(),
+        vec![id(1, 3), id(1, 5)]
+    );
+    assert_eq!(last, db.last_uid(&reader)?);
+
+    let filter = Filter::from_str(r#"{"kinds": [1], "limit": 1}"#)?;
+    let (events, _) = db
+        .iter_stored_from::<Event, _>(&reader, uid, &filter, 10)?
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].id(), &id(1, 5));
+
+    // too many stored since
+    assert!(db
+        .iter_stored_from::<Event, _>(&reader, uid, &filter, 2)?
+        .is_none());
+
+    // stored before the end of the range
+    let filter = Filter::from_str(r#"{"kinds": [1]}"#)?;
+    let (events, last) = db
+        .iter_stored_range::<Event, _>(&reader, uid..uid + 2, &filter, 2)?
+        .unwrap();
+    assert_eq!(
+        events.iter().map(|e| *e.id()).collect::<Vec<_>>(),
+        vec![id(1, 3)]
+    );
+    assert_eq!(last, Some(uid + 1));
+
+    // the events of the earlier snapshot
+    let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+    iter.stored_before(uid);
+    let events = iter.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        events.iter().map(|e| *e.id()).collect::<Vec<_>>(),
+        vec![id(1, 1)]
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_time_series() -> Result<()> {
+    let db = create_db("test_time_series")?;
+    let event = |index: u8, pubkey: u8, kind: u16| -> Event {
+        MyEvent {
+            id: id(1, index),
+            pubkey: author(pubkey),
+            created_at: 10 + index as u64,
+            kind,
+            ..Default::default()
+        }
+        .into()
+    };
+    let time = now();
+    db.batch_put([event(1, 1, 1), event(2, 1, 0), event(3, 2, 1)])?;
+    // the replaced profile is not a new pubkey
+    db.batch_put([event(4, 1, 0), event(1, 1, 1)])?;
+
+    let day = time / 86400 * 86400;
+    let series = db.time_series(&db.reader()?, day, time + 3600)?;
+    let events = series
+        .events
+        .values()
+        .fold(HashMap::new(), |mut map, kinds| {
+            for (kind, count) in kinds {
+                *map.entry(*kind).or_insert(0) += count;
+            }
+            map
+        });
+    assert_eq!(events, HashMap::from([(0, 2), (1, 2)]));
+    assert!(series.bytes.values().sum::<u64>() > 0);
+    assert_eq!(series.pubkeys.get(&day), Some(&2));
+
+    // the range is by the start of the hours and the days
+    let series = db.time_series(&db.reader()?, time + 7200, u64::MAX)?;
+    assert!(series.events.is_empty());
+    assert!(series.pubkeys.is_empty());
+    Ok(())
+}
+
+#[test]
+pub fn test_bloom() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let event = |index: u8| -> Event {
+        MyEvent {
+            id: id(0, index),
+            pubkey: author(1),
+            created_at: 10,
+            kind: 1,
+            ..Default::default()
+        }
+        .into()
+    };
+    {
+        let db = Db::open(dir.path())?;
+        // no filter
+        assert!(db.may_exist(id(0, 1)));
+        db.batch_put([&event(1), &event(2)])?;
+        assert_eq!(db.rebuild_bloom(0.01)?.0, 2);
+        assert!(db.may_exist(id(0, 1)));
+        assert!(!db.may_exist(id(0, 3)));
+        db.batch_put([&event(3)])?;
+        assert!(db.may_exist(id(0, 3)));
+        let mut writer = db.writer()?;
+        assert!(matches!(
+            db.put(&mut writer, event(1))?,
+            CheckEventResult::Duplicate
+        ));
+        db.commit(writer)?;
+        db.batch_put([&event(4)])?;
+    }
+    // the events stored after the filter is saved
+    let db = Db::open(dir.path())?;
+    assert!((1..5).all(|i| db.may_exist(id(0, i))));
+    assert!(!db.may_exist(id(0, 5)));
+    let reader = db.reader()?;
+    assert!(db.contains(&reader, id(0, 4))?);
+    assert!(!db.contains(&reader, id(0, 5))?);
+    Ok(())
+}
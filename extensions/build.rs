@@ -0,0 +1,13 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+    // the code of the grpc service, protox parses the proto file so protoc is not required
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/relay.proto");
+        let fds = protox::compile(["proto/relay.proto"], ["proto"])?;
+        tonic_prost_build::configure()
+            .build_transport(false)
+            .compile_fds(fds)?;
+    }
+    Ok(())
+}
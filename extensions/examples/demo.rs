@@ -6,16 +6,14 @@ use tracing::info;
 async fn main() -> nostr_relay::Result<()> {
     tracing_subscriber::fmt::init();
     info!("Start relay server");
-    let app_data = App::create(
-        Some("../rnostr.example.toml"),
-        true,
-        Some("NOSTR".to_owned()),
-        None,
-    )?;
-    app_data
-        .add_extension(nostr_extensions::Metrics::new())
-        .add_extension(nostr_extensions::Auth::new())
-        .add_extension(nostr_extensions::Ratelimiter::new())
+    App::builder()
+        .config("../rnostr.example.toml")
+        .watch(true)
+        .env_prefix("NOSTR")
+        .extension(nostr_extensions::Metrics::new())
+        .extension(nostr_extensions::Auth::new())
+        .extension(nostr_extensions::Ratelimiter::new())
+        .build()?
         .web_server()?
         .await?;
     info!("Relay server shutdown");
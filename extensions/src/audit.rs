@@ -0,0 +1,272 @@
+//! Append-only audit log of the accepted and rejected events, one json line per event
+//! in a file per day (UTC), ie: `2024-01-31.jsonl`
+use crate::auth::AuthState;
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{db::now, setting::SettingWrapper, EventResult, Extension, Session};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+use tracing::error;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuditSetting {
+    pub enabled: bool,
+    /// the directory of the log files, default "audit" in the data path
+    pub path: Option<PathBuf>,
+    /// log the accepted events, otherwise the rejected only
+    pub accepted: bool,
+    /// the log files older than it are removed, kept forever when 0
+    #[serde(with = "nostr_relay::duration")]
+    pub retention: Duration,
+}
+
+impl Default for AuditSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            accepted: true,
+            retention: Duration::from_secs(90 * 86400),
+        }
+    }
+}
+
+/// A line of the audit log
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub time: u64,
+    pub id: String,
+    pub pubkey: String,
+    pub ip: String,
+    /// the pubkey authenticated by NIP-42
+    pub auth: Option<String>,
+    pub accepted: bool,
+    pub message: String,
+    /// the extension that decided, "relay" for the validation and the writer
+    pub by: String,
+}
+
+/// The log file of the current day
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    day: u64,
+    file: Option<File>,
+}
+
+impl Log {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            day: 0,
+            file: None,
+        }
+    }
+
+    /// Append the record to the file of its day, the old files are removed when the day changes
+    fn write(&mut self, record: &Record, retention: Duration) -> io::Result<()> {
+        let day = record.time / 86400;
+        if self.file.is_none() || self.day != day {
+            fs::create_dir_all(&self.path)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path.join(format!("{}.jsonl", date(day))))?;
+            self.file = Some(file);
+            self.day = day;
+            self.clean(retention)?;
+        }
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        // one write per line keeps the lines whole
+        self.file.as_mut().unwrap().write_all(&line)
+    }
+
+    /// Remove the files of the days before the retention
+    fn clean(&self, retention: Duration) -> io::Result<()> {
+        if retention.is_zero() {
+            return Ok(());
+        }
+        let keep = retention.as_secs().div_ceil(86400);
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let day = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".jsonl"))
+                .and_then(parse_date);
+            if day.is_some_and(|d| d + keep < self.day) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The UTC date of the days since the unix epoch
+fn date(day: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = day as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// The days since the unix epoch of the date
+fn parse_date(s: &str) -> Option<u64> {
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let mut parts = s.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (y, m, d) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    u64::try_from(era * 146097 + doe - 719468).ok()
+}
+
+#[derive(Debug)]
+pub struct Audit {
+    pub setting: AuditSetting,
+    log: Mutex<Option<Log>>,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Audit {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_audit_error_total",
+            "The total count of audit log write errors"
+        );
+        Self {
+            setting: AuditSetting::default(),
+            log: Mutex::new(None),
+        }
+    }
+
+    pub fn write(&self, record: &Record) {
+        if let Some(log) = self.log.lock().as_mut() {
+            if let Err(e) = log.write(record, self.setting.retention) {
+                increment_counter!("nostr_relay_audit_error_total");
+                error!(error = e.to_string(), "failed to write the audit log");
+            }
+        }
+    }
+}
+
+impl Extension for Audit {
+    fn name(&self) -> &'static str {
+        "audit"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        let path = self
+            .setting
+            .path
+            .get_or_insert_with(|| w.data.path.join("audit"))
+            .clone();
+        w.set_extension(self.setting.clone());
+        let mut log = self.log.lock();
+        if !self.setting.enabled {
+            *log = None;
+        } else if log.as_ref().is_none_or(|l| l.path != path) {
+            *log = Some(Log::new(path));
+        }
+    }
+
+    fn event_result(&self, result: &EventResult, session: &Session) {
+        if !self.setting.enabled || (result.accepted && !self.setting.accepted) {
+            return;
+        }
+        self.write(&Record {
+            time: now(),
+            id: result.id.to_owned(),
+            pubkey: result.pubkey.to_owned(),
+            ip: session.ip().clone(),
+            auth: session.get::<AuthState>().and_then(|s| s.pubkey()).cloned(),
+            accepted: result.accepted,
+            message: result.message.to_owned(),
+            by: result.by.to_owned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_data_path;
+    use anyhow::Result;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(19753), "2024-01-31");
+        assert_eq!(date(19782), "2024-02-29");
+        for day in [0, 59, 365, 11016, 19753, 19782, 30000] {
+            assert_eq!(parse_date(&date(day)), Some(day));
+        }
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("audit"), None);
+    }
+
+    #[test]
+    fn log() -> Result<()> {
+        let dir = temp_data_path("audit")?;
+        let mut log = Log::new(dir.path().to_path_buf());
+        let record = |time: u64, accepted: bool| Record {
+            time,
+            id: "0".repeat(64),
+            pubkey: "1".repeat(64),
+            ip: "127.0.0.1".to_owned(),
+            auth: None,
+            accepted,
+            message: if accepted { "" } else { "blocked: spam" }.to_owned(),
+            by: "relay".to_owned(),
+        };
+        let retention = Duration::from_secs(2 * 86400);
+        let day = 19753 * 86400;
+        log.write(&record(day, true), retention)?;
+        log.write(&record(day + 10, false), retention)?;
+        log.write(&record(day + 86400, true), retention)?;
+
+        let file = File::open(dir.path().join("2024-01-31.jsonl"))?;
+        let lines = BufReader::new(file)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<Record>(&lines[1])?,
+            record(day + 10, false)
+        );
+        assert!(dir.path().join("2024-02-01.jsonl").exists());
+
+        // removed after the retention
+        log.write(&record(day + 3 * 86400, true), retention)?;
+        assert!(!dir.path().join("2024-01-31.jsonl").exists());
+        assert!(dir.path().join("2024-02-01.jsonl").exists());
+        Ok(())
+    }
+}
@@ -127,6 +127,18 @@ impl Extension for Auth {
     fn setting(&mut self, setting: &SettingWrapper) {
         let mut w = setting.write();
         self.setting = w.parse_extension(self.name());
+        // the session ips are hashed in the privacy mode
+        for permission in [&mut self.setting.req, &mut self.setting.event]
+            .into_iter()
+            .flatten()
+        {
+            for list in [&mut permission.ip_whitelist, &mut permission.ip_blacklist]
+                .into_iter()
+                .flatten()
+            {
+                list.iter_mut().for_each(|ip| *ip = w.network.client_ip(ip));
+            }
+        }
         if self.setting.enabled {
             w.add_nip(42);
         }
@@ -137,7 +149,32 @@ impl Extension for Auth {
             let uuid = Uuid::new_v4().to_string();
             let state = AuthState::Challenge(uuid.clone());
             session.set(state);
-            ctx.text(format!(r#"["AUTH", "{uuid}"]"#));
+            session.text(ctx, format!(r#"["AUTH", "{uuid}"]"#));
+        }
+    }
+
+    fn reloaded(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {
+        if !self.setting.enabled {
+            return;
+        }
+        // auth is enabled after the session connected
+        if session.get::<AuthState>().is_none() {
+            self.connected(session, ctx);
+        }
+        let state = session.get::<AuthState>();
+        if let Err(err) = Self::verify_permission(
+            self.setting.req.as_ref(),
+            state.and_then(|s| s.pubkey()),
+            None,
+            session.ip(),
+        ) {
+            increment_counter!("nostr_relay_auth_unauthorized", "command" => "RELOAD", "reason" => err);
+            // stop the live events of the existing subscriptions
+            session.unsubscribe_all();
+            session.text(
+                ctx,
+                OutgoingMessage::notice(&format!("restricted: {}", err)),
+            );
         }
     }
 
@@ -158,6 +195,8 @@ impl Extension for Auth {
                             for tag in event.tags() {
                                 if tag.len() > 1 && tag[0] == "challenge" && &tag[1] == challenge {
                                     session.set(AuthState::Pubkey(event.pubkey_str()));
+                                    session.authenticated(event.pubkey_str());
+                                    session.add_nip(42);
                                     return OutgoingMessage::notice("auth success").into();
                                 }
                             }
@@ -200,6 +239,7 @@ impl Extension for Auth {
 }
 
 #[cfg(test)]
+#[allow(clippy::clone_on_copy, clippy::to_string_in_format_args)]
 mod tests {
     use super::*;
     use crate::create_test_app;
@@ -212,6 +252,7 @@ mod tests {
         secp256k1::{rand::thread_rng, KeyPair, XOnlyPublicKey},
         Event,
     };
+    use nostr_relay::message::{Control, ControlAction, ControlSessions};
 
     fn parse_text<T: serde::de::DeserializeOwned>(frame: &ws::Frame) -> Result<T> {
         if let ws::Frame::Text(text) = &frame {
@@ -431,6 +472,128 @@ mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn reload() -> Result<()> {
+        let app = create_test_app("auth-reload")?;
+        let app = web::Data::new(app.add_extension(Auth::new()));
+        let c_app = app.clone();
+
+        let mut srv = actix_test::start(move || create_web_app(c_app.clone()));
+
+        // client service
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let eose: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(eose.0, "EOSE");
+
+        // blacklist the connected client
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "auth": {
+                    "enabled": true,
+                    "req": {
+                        "ip_blacklist": ["127.0.0.1"]
+                    }
+                }
+            }"#,
+            )?;
+        }
+        app.extensions.write().call_setting(&app.setting);
+        app.server.do_send(nostr_relay::message::Reload);
+
+        let state: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(state.0, "AUTH");
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(notice.0, "NOTICE");
+        assert!(notice.1.contains("restricted: ip in blacklist"));
+        let sessions = app
+            .server
+            .send(ControlSessions(Control {
+                filter: Default::default(),
+                action: ControlAction::List,
+            }))
+            .await?;
+        assert!(sessions[0].subscriptions.is_empty());
+
+        framed
+            .send(ws::Message::Text(r#"["REQ", "2", {}]"#.into()))
+            .await?;
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.1.contains("restricted"));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn roles() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let pubkey = XOnlyPublicKey::from_keypair(&key_pair).0;
+
+        let app = create_test_app("auth-roles")?;
+        {
+            let mut w = app.setting.write();
+            w.limitation.max_subscriptions = 1;
+            w.limitation.max_message_length = 1000;
+            w.roles = serde_json::from_value(serde_json::json!({
+                "admin": {
+                    "pubkeys": [pubkey.to_string()],
+                    "max_subscriptions": 3,
+                    "max_message_length": 2000
+                }
+            }))?;
+            w.extra = serde_json::from_str(r#"{"auth": {"enabled": true}}"#)?;
+        }
+        let app = app.add_extension(Auth::new());
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let state: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+
+        let long = format!(r##"["REQ", "3", {{"#t": ["{}"]}}]"##, "a".repeat(1500));
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let eose: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(eose.0, "EOSE");
+        framed
+            .send(ws::Message::Text(r#"["REQ", "2", {}]"#.into()))
+            .await?;
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.1.contains("exceeds limit"));
+        framed.send(ws::Message::Text(long.clone().into())).await?;
+        let closed: (String, String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(closed.0, "CLOSED");
+        assert!(closed.2.contains("max_message_length"));
+
+        // the limits of the role apply after auth
+        let event = Event::create(
+            &key_pair,
+            now(),
+            22242,
+            vec![vec!["challenge".to_owned(), state.1.clone()]],
+            "".to_owned(),
+        )?;
+        framed
+            .send(ws::Message::Text(
+                format!(r#"["AUTH", {}]"#, event.to_string()).into(),
+            ))
+            .await?;
+        let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.1.contains("success"));
+        framed
+            .send(ws::Message::Text(r#"["REQ", "2", {}]"#.into()))
+            .await?;
+        let eose: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(eose, ("EOSE".to_owned(), "2".to_owned()));
+        framed.send(ws::Message::Text(long.into())).await?;
+        let eose: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(eose, ("EOSE".to_owned(), "3".to_owned()));
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn pubkey_whitelist() -> Result<()> {
         let mut rng = thread_rng();
@@ -513,9 +676,7 @@ mod tests {
             "".to_owned(),
         )?;
         framed
-            .send(ws::Message::Text(
-                format!(r#"["AUTH", {}]"#, event.to_string()).into(),
-            ))
+            .send(ws::Message::Text(format!(r#"["AUTH", {}]"#, event).into()))
             .await?;
         let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
         assert!(notice.1.contains("success"));
@@ -0,0 +1,315 @@
+use crate::client::{connect, Framed, MIN_BACKOFF};
+use actix::clock::sleep;
+use awc::ws::{Frame, Message};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::{
+    future::{select, Either},
+    SinkExt, StreamExt,
+};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{Event, Filter},
+    duration::NonZeroDuration,
+    setting::SettingWrapper,
+    Extension, Session,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::{collections::VecDeque, time::Duration};
+use tracing::{debug, info, warn};
+
+type Senders = Vec<(BroadcastRelay, UnboundedSender<Event>)>;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BroadcastRelay {
+    /// websocket url of the downstream relay
+    pub url: String,
+    /// only republish the events matching one of the filters, all events when empty
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+}
+
+impl BroadcastRelay {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|f| f.match_event(event))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct BroadcastSetting {
+    pub enabled: bool,
+    pub relays: Vec<BroadcastRelay>,
+    /// events kept for each relay while it is disconnected, the oldest are dropped when full
+    pub queue_size: usize,
+    /// the retry delay doubles from 1 second to the max backoff
+    pub max_backoff: NonZeroDuration,
+}
+
+impl Default for BroadcastSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            relays: Vec::new(),
+            queue_size: 10000,
+            max_backoff: Duration::from_secs(300).try_into().unwrap(),
+        }
+    }
+}
+
+/// Republish the events accepted from the sessions to the downstream relays
+#[derive(Default)]
+pub struct Broadcast {
+    pub setting: BroadcastSetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    /// the relay tasks are started by the first event, as they need the actix runtime
+    senders: Mutex<Option<Senders>>,
+}
+
+impl Broadcast {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_broadcast_sent_total",
+            "The total count of events sent to the downstream relays"
+        );
+        describe_counter!(
+            "nostr_relay_broadcast_dropped_total",
+            "The total count of events dropped by the full retry queue"
+        );
+        Self::default()
+    }
+
+    fn start(&self) -> Senders {
+        self.setting
+            .relays
+            .iter()
+            .map(|relay| {
+                let (tx, rx) = unbounded();
+                actix::spawn(run(
+                    relay.url.clone(),
+                    self.proxy.clone(),
+                    rx,
+                    self.setting.queue_size,
+                    self.setting.max_backoff.into(),
+                ));
+                (relay.clone(), tx)
+            })
+            .collect()
+    }
+}
+
+impl Extension for Broadcast {
+    fn name(&self) -> &'static str {
+        "broadcast"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        let broadcast: BroadcastSetting = r.parse_extension(self.name());
+        if broadcast != self.setting || r.network.proxy != self.proxy {
+            self.setting = broadcast;
+            self.proxy = r.network.proxy.clone();
+            // stop the tasks of the old relays
+            *self.senders.lock() = None;
+        }
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        _session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        if !self.setting.enabled || self.setting.relays.is_empty() {
+            return;
+        }
+        let mut senders = self.senders.lock();
+        for (relay, tx) in senders.get_or_insert_with(|| self.start()).iter() {
+            if relay.matches(event) {
+                let _ = tx.unbounded_send(event.clone());
+            }
+        }
+    }
+}
+
+fn push(queue: &mut VecDeque<Event>, event: Event, size: usize, url: &str) {
+    if queue.len() >= size {
+        queue.pop_front();
+        increment_counter!("nostr_relay_broadcast_dropped_total", "relay" => url.to_owned());
+    }
+    queue.push_back(event);
+}
+
+/// keep the connection to the relay, reconnect with backoff until the sender is dropped
+async fn run(
+    url: String,
+    proxy: Option<String>,
+    mut rx: UnboundedReceiver<Event>,
+    size: usize,
+    max_backoff: Duration,
+) {
+    let mut queue = VecDeque::new();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match connect(&url, proxy.as_deref()).await {
+            Ok(mut framed) => {
+                info!(relay = url, "broadcast connected");
+                backoff = MIN_BACKOFF;
+                match forward(&url, &mut framed, &mut queue, &mut rx, size).await {
+                    Ok(()) => {
+                        let _ = framed.close().await;
+                        return;
+                    }
+                    Err(err) => warn!(relay = url, error = err, "broadcast disconnected"),
+                }
+            }
+            Err(err) => warn!(relay = url, error = err, "broadcast connect failed"),
+        }
+
+        // keep queueing until the retry
+        let mut delay = Box::pin(sleep(backoff));
+        loop {
+            match select(delay, rx.next()).await {
+                Either::Left(_) => break,
+                Either::Right((Some(event), d)) => {
+                    push(&mut queue, event, size, &url);
+                    delay = d;
+                }
+                Either::Right((None, _)) => return,
+            }
+        }
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+/// send the queued and new events, returns ok when the sender is dropped
+async fn forward(
+    url: &str,
+    framed: &mut Framed,
+    queue: &mut VecDeque<Event>,
+    rx: &mut UnboundedReceiver<Event>,
+    size: usize,
+) -> Result<(), String> {
+    loop {
+        while let Some(event) = queue.front() {
+            let text = format!(r#"["EVENT",{}]"#, event);
+            framed
+                .send(Message::Text(text.into()))
+                .await
+                .map_err(|e| e.to_string())?;
+            queue.pop_front();
+            increment_counter!("nostr_relay_broadcast_sent_total", "relay" => url.to_owned());
+        }
+
+        let next = match select(rx.next(), framed.next()).await {
+            Either::Left((event, _)) => Either::Left(event),
+            Either::Right((frame, _)) => Either::Right(frame),
+        };
+        match next {
+            Either::Left(Some(event)) => push(queue, event, size, url),
+            Either::Left(None) => return Ok(()),
+            Either::Right(Some(Ok(frame))) => match frame {
+                Frame::Ping(msg) => framed
+                    .send(Message::Pong(msg))
+                    .await
+                    .map_err(|e| e.to_string())?,
+                Frame::Text(text) => {
+                    debug!(relay = url, msg = ?text, "broadcast response");
+                }
+                Frame::Close(reason) => return Err(format!("closed {:?}", reason)),
+                _ => {}
+            },
+            Either::Right(Some(Err(err))) => return Err(err.to_string()),
+            Either::Right(None) => return Err("closed".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web::web;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use nostr_relay::{
+        create_web_app,
+        db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+        },
+    };
+
+    #[test]
+    fn queue() {
+        let event: Event = serde_json::from_str(
+            r#"{"kind":1, "id": "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef", "pubkey": "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef", "created_at": 1, "sig": "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"}"#,
+        )
+        .unwrap();
+        let mut queue = VecDeque::new();
+        for _ in 0..3 {
+            push(&mut queue, event.clone(), 2, "ws://localhost");
+        }
+        assert_eq!(queue.len(), 2);
+
+        let relay: BroadcastRelay =
+            serde_json::from_str(r#"{"url": "ws://localhost", "filters": [{"kinds": [2]}]}"#)
+                .unwrap();
+        assert!(!relay.matches(&event));
+        let relay: BroadcastRelay = serde_json::from_str(r#"{"url": "ws://localhost"}"#).unwrap();
+        assert!(relay.matches(&event));
+    }
+
+    #[actix_rt::test]
+    async fn broadcast() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+
+        let downstream = web::Data::new(create_test_app("broadcast_downstream")?);
+        let db = downstream.db.clone();
+        let down_srv = actix_test::start(move || create_web_app(downstream.clone()));
+
+        let app = create_test_app("broadcast")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(serde_json::json!({
+                "broadcast": {
+                    "enabled": true,
+                    "relays": [{
+                        "url": down_srv.url("/").replace("http", "ws"),
+                        "filters": [{"kinds": [1]}]
+                    }]
+                }
+            }))?;
+        }
+        let app = web::Data::new(app.add_extension(Broadcast::new()));
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let note = Event::create(&key_pair, now(), 1, vec![], "note".to_owned())?;
+        let other = Event::create(&key_pair, now(), 2, vec![], "other".to_owned())?;
+        for event in [&note, &other] {
+            framed
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+                .await?;
+            let item = framed.next().await.unwrap()?;
+            assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+        }
+
+        let filter = Filter::default();
+        let mut ids = vec![];
+        for _ in 0..50 {
+            sleep(Duration::from_millis(100)).await;
+            let reader = db.reader()?;
+            ids = db
+                .iter::<Event, _>(&reader, &filter)?
+                .map(|e| e.map(|e| e.id_str()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !ids.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(ids, vec![note.id_str()]);
+        Ok(())
+    }
+}
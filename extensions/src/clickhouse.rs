@@ -0,0 +1,418 @@
+//! Export the metadata of the stored events to ClickHouse in batches on an interval,
+//! ie: retention analysis or spam forensics without the heavy queries on the serving database.
+//! The events are read in the write order after the saved cursor, the deleted events are not exported.
+use crate::client::http_client;
+use actix::{clock::sleep, Arbiter};
+use futures_channel::oneshot;
+use futures_util::future::select;
+use metrics::{counter, describe_counter};
+use nostr_relay::{
+    db::{Db, Event},
+    duration::NonZeroDuration,
+    setting::SettingWrapper,
+    App, Extension,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClickhouseSetting {
+    pub enabled: bool,
+    /// the http interface
+    pub url: String,
+    pub database: String,
+    /// created when it does not exist
+    pub table: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// wait for the new events after exporting all
+    pub interval: NonZeroDuration,
+    /// max events of an insert
+    pub batch: usize,
+    /// the file saving the uid of the last exported event, default "clickhouse.cursor" in the data path
+    pub cursor_path: Option<PathBuf>,
+    /// the retry delay doubles from the interval to the max backoff
+    pub max_backoff: NonZeroDuration,
+}
+
+impl Default for ClickhouseSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "http://127.0.0.1:8123".to_owned(),
+            database: "default".to_owned(),
+            table: "nostr_events".to_owned(),
+            user: None,
+            password: None,
+            interval: Duration::from_secs(10).try_into().unwrap(),
+            batch: 10000,
+            cursor_path: None,
+            max_backoff: Duration::from_secs(300).try_into().unwrap(),
+        }
+    }
+}
+
+impl ClickhouseSetting {
+    fn table(&self) -> String {
+        format!("`{}`.`{}`", self.database, self.table)
+    }
+
+    /// the events are replaced by the id, the exported events may be sent again after a crash
+    fn create_table(&self) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                uid UInt64,
+                id String,
+                pubkey String,
+                kind UInt16,
+                created_at DateTime,
+                tags Map(String, UInt32),
+                size UInt32
+            ) ENGINE = ReplacingMergeTree ORDER BY (kind, created_at, id)",
+            self.table()
+        )
+    }
+}
+
+/// The metadata of an event
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Row {
+    pub uid: u64,
+    pub id: String,
+    pub pubkey: String,
+    pub kind: u16,
+    pub created_at: u64,
+    /// the number of the tags by the name
+    pub tags: BTreeMap<String, u32>,
+    /// the bytes of the event json
+    pub size: u32,
+}
+
+impl Row {
+    pub fn new(uid: u64, event: &Event) -> Self {
+        let mut tags = BTreeMap::new();
+        for tag in event.tags() {
+            if let Some(name) = tag.first() {
+                *tags.entry(name.clone()).or_default() += 1;
+            }
+        }
+        Self {
+            uid,
+            id: event.id_str(),
+            pubkey: event.pubkey_str(),
+            kind: event.kind(),
+            created_at: event.created_at(),
+            tags,
+            size: event.to_string().len() as u32,
+        }
+    }
+}
+
+pub struct Clickhouse {
+    pub setting: ClickhouseSetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    app: App,
+    /// runs the export task, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    /// stop the export task when dropped
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Clickhouse {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_clickhouse_exported_total",
+            "The total count of events exported to clickhouse"
+        );
+        Self {
+            setting: ClickhouseSetting::default(),
+            proxy: None,
+            app,
+            arbiter: Arbiter::new(),
+            stop: None,
+        }
+    }
+}
+
+impl Extension for Clickhouse {
+    fn name(&self) -> &'static str {
+        "clickhouse"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let w = setting.read();
+        let clickhouse: ClickhouseSetting = w.parse_extension(self.name());
+        if clickhouse == self.setting && w.network.proxy == self.proxy {
+            return;
+        }
+        self.setting = clickhouse;
+        self.proxy = w.network.proxy.clone();
+        self.stop = None;
+        if !self.setting.enabled {
+            return;
+        }
+        let path = self
+            .setting
+            .cursor_path
+            .clone()
+            .unwrap_or_else(|| w.data.path.join("clickhouse.cursor"));
+        let (stop, stopped) = oneshot::channel();
+        let (setting, db) = (self.setting.clone(), self.app.db.clone());
+        let proxy = self.proxy.clone();
+        // the http client is not Send, create the task in the arbiter
+        self.arbiter.spawn_fn(move || {
+            actix::spawn(async move {
+                select(Box::pin(export(setting, proxy, db, path)), stopped).await;
+            });
+        });
+        self.stop = Some(stop);
+    }
+}
+
+fn load_cursor(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn save_cursor(path: &Path, uid: u64) {
+    let tmp = path.with_extension("tmp");
+    if let Err(err) = fs::write(&tmp, uid.to_string()).and_then(|_| fs::rename(&tmp, path)) {
+        error!(error = err.to_string(), "failed to save clickhouse cursor");
+    }
+}
+
+/// export through the proxy until stopped, retry with backoff
+async fn export(setting: ClickhouseSetting, proxy: Option<String>, db: Arc<Db>, path: PathBuf) {
+    let client = match http_client(proxy.as_deref(), Duration::from_secs(60)) {
+        Ok(client) => client,
+        Err(err) => {
+            error!(error = err, "clickhouse client failed");
+            return;
+        }
+    };
+    let interval: Duration = setting.interval.into();
+    let mut backoff = interval;
+    let mut created = false;
+    let mut cursor = load_cursor(&path);
+    loop {
+        let result = async {
+            if !created {
+                query(&client, &setting, setting.create_table(), "").await?;
+                created = true;
+            }
+            export_batch(&client, &setting, &db, &mut cursor).await
+        }
+        .await;
+        match result {
+            Ok(num) => {
+                backoff = interval;
+                if let Some(uid) = cursor.filter(|_| num > 0) {
+                    save_cursor(&path, uid);
+                    counter!("nostr_relay_clickhouse_exported_total", num as u64);
+                }
+                // more events
+                if num >= setting.batch.max(1) {
+                    continue;
+                }
+                sleep(interval).await;
+            }
+            Err(err) => {
+                warn!(error = err, "clickhouse export failed");
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(setting.max_backoff.into());
+            }
+        }
+    }
+}
+
+/// insert the next batch after the cursor, returns the number of the events
+async fn export_batch(
+    client: &awc::Client,
+    setting: &ClickhouseSetting,
+    db: &Db,
+    cursor: &mut Option<u64>,
+) -> Result<usize, String> {
+    let mut body = String::new();
+    let mut last = *cursor;
+    let mut num = 0;
+    {
+        let reader = db.reader().map_err(|e| e.to_string())?;
+        for item in db
+            .iter_uid::<Event, _>(&reader, *cursor)
+            .take(setting.batch.max(1))
+        {
+            let (uid, event) = item.map_err(|e| e.to_string())?;
+            body.push_str(&serde_json::to_string(&Row::new(uid, &event)).unwrap_or_default());
+            body.push('\n');
+            last = Some(uid);
+            num += 1;
+        }
+    }
+    if num == 0 {
+        return Ok(0);
+    }
+    let sql = format!("INSERT INTO {} FORMAT JSONEachRow", setting.table());
+    query(client, setting, sql, body).await?;
+    if cursor.is_none() {
+        info!("clickhouse export started");
+    }
+    *cursor = last;
+    Ok(num)
+}
+
+async fn query<B: Into<String>>(
+    client: &awc::Client,
+    setting: &ClickhouseSetting,
+    sql: String,
+    body: B,
+) -> Result<(), String> {
+    let mut req = client
+        .post(setting.url.trim_end_matches('/'))
+        .query(&[("query", sql)])
+        .map_err(|e| e.to_string())?;
+    if let Some(user) = &setting.user {
+        req = req.insert_header(("X-ClickHouse-User", user.as_str()));
+    }
+    if let Some(password) = &setting.password {
+        req = req.insert_header(("X-ClickHouse-Key", password.as_str()));
+    }
+    let mut res = req
+        .send_body(body.into())
+        .await
+        .map_err(|e| e.to_string())?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        let text = res.body().await.unwrap_or_default();
+        Err(format!(
+            "status {}: {}",
+            res.status(),
+            String::from_utf8_lossy(&text).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_test_app, temp_data_path};
+    use actix_web::{web, HttpRequest, HttpResponse};
+    use anyhow::Result;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use parking_lot::Mutex;
+
+    #[test]
+    fn row() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let tags = vec![
+            vec!["t".to_owned(), "a".to_owned()],
+            vec!["t".to_owned(), "b".to_owned()],
+            vec!["r".to_owned(), "c".to_owned()],
+        ];
+        let event = Event::create(&key_pair, 10, 1, tags, "hi".to_owned())?;
+        let row = Row::new(7, &event);
+        assert_eq!(row.uid, 7);
+        assert_eq!(row.kind, 1);
+        assert_eq!(row.created_at, 10);
+        assert_eq!(
+            row.tags,
+            BTreeMap::from([("r".to_owned(), 1), ("t".to_owned(), 2)])
+        );
+        assert_eq!(row.size as usize, event.to_string().len());
+        Ok(())
+    }
+
+    /// (query, body) of the received requests
+    type Queries = Arc<Mutex<Vec<(String, String)>>>;
+
+    #[actix_rt::test]
+    async fn export() -> Result<()> {
+        let queries = Queries::default();
+        let srv = actix_test::start({
+            let queries = queries.clone();
+            move || {
+                let queries = queries.clone();
+                actix_web::App::new().default_service(web::to(
+                    move |req: HttpRequest, body: String| {
+                        let queries = queries.clone();
+                        async move {
+                            assert_eq!(req.headers().get("X-ClickHouse-User").unwrap(), "nostr");
+                            let query = web::Query::<BTreeMap<String, String>>::from_query(
+                                req.query_string(),
+                            )
+                            .unwrap();
+                            queries.lock().push((query["query"].clone(), body));
+                            HttpResponse::Ok().finish()
+                        }
+                    },
+                ))
+            }
+        });
+
+        let app = create_test_app("clickhouse")?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..3)
+            .map(|i| Event::create(&key_pair, now(), i, vec![], "".to_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        app.db.batch_put(events.clone())?;
+
+        let dir = temp_data_path("clickhouse_cursor")?;
+        let cursor_path = dir.path().join("clickhouse.cursor");
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(serde_json::json!({
+                "clickhouse": {
+                    "enabled": true,
+                    "url": srv.url("/"),
+                    "user": "nostr",
+                    "batch": 2,
+                    "interval": "50ms",
+                    "cursor_path": cursor_path,
+                }
+            }))?;
+        }
+        let _app = app.clone().add_extension(Clickhouse::new(app));
+        for _ in 0..50 {
+            if queries.lock().len() >= 3 {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        let list = queries.lock().clone();
+        assert_eq!(list.len(), 3);
+        assert!(list[0]
+            .0
+            .starts_with("CREATE TABLE IF NOT EXISTS `default`.`nostr_events`"));
+        assert_eq!(
+            list[1].0,
+            "INSERT INTO `default`.`nostr_events` FORMAT JSONEachRow"
+        );
+        let rows = list[1..]
+            .iter()
+            .flat_map(|(_, body)| {
+                body.lines()
+                    .map(|l| serde_json::from_str::<Row>(l).unwrap())
+            })
+            .collect::<Vec<_>>();
+        // the events of a batch are not written in the given order
+        let mut ids = events.iter().map(|e| e.id_str()).collect::<Vec<_>>();
+        ids.sort();
+        let mut exported = rows.iter().map(|r| r.id.clone()).collect::<Vec<_>>();
+        exported.sort();
+        assert_eq!(exported, ids);
+        assert!(rows.windows(2).all(|w| w[0].uid < w[1].uid));
+        assert_eq!(load_cursor(&cursor_path), Some(rows[2].uid));
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! Outbound websocket connections to other relays and http requests
+use actix_service::fn_service;
+use actix_tls::connect::{ConnectError, ConnectInfo, Connection};
+use awc::{
+    http::{Uri, Version},
+    Client, Connector,
+};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[cfg(feature = "client")]
+pub(crate) type Framed = actix_codec::Framed<awc::BoxedSocket, awc::ws::Codec>;
+
+#[cfg(any(feature = "client", feature = "replication"))]
+/// the first retry delay, doubles to the max backoff of the extension
+pub(crate) const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+#[cfg(any(feature = "client", feature = "replication"))]
+/// timeout of the websocket handshake and the response of a request, the default of awc
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "client")]
+/// max size of the received frames, the events of other relays may be larger than the default 64K
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// The http client, through the SOCKS5 proxy of the `network.proxy` setting
+pub(crate) fn http_client(proxy: Option<&str>, timeout: Duration) -> Result<Client, String> {
+    Ok(match proxy {
+        Some(proxy) => {
+            let proxy = proxy
+                .strip_prefix("socks5://")
+                .or_else(|| proxy.strip_prefix("socks5h://"))
+                .ok_or_else(|| format!("invalid socks5 proxy {:?}", proxy))?
+                .trim_end_matches('/')
+                .to_owned();
+            let connector = fn_service(move |req: ConnectInfo<Uri>| {
+                let proxy = proxy.clone();
+                async move {
+                    let mut stream = TcpStream::connect(proxy).await.map_err(ConnectError::Io)?;
+                    socks5(&mut stream, req.hostname(), req.port())
+                        .await
+                        .map_err(ConnectError::Io)?;
+                    Ok::<_, ConnectError>(Connection::new(req.request().clone(), stream))
+                }
+            });
+            Client::builder()
+                .connector(
+                    Connector::new()
+                        .connector(connector)
+                        .max_http_version(Version::HTTP_11),
+                )
+                .timeout(timeout)
+                .finish()
+        }
+        None => Client::builder()
+            .max_http_version(Version::HTTP_11)
+            .timeout(timeout)
+            .finish(),
+    })
+}
+
+#[cfg(feature = "client")]
+/// connect the websocket, through the SOCKS5 proxy of the `network.proxy` setting
+pub(crate) async fn connect(url: &str, proxy: Option<&str>) -> Result<Framed, String> {
+    let (_, framed) = http_client(proxy, CONNECT_TIMEOUT)?
+        .ws(url)
+        .max_frame_size(MAX_FRAME_SIZE)
+        .connect()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(framed)
+}
+
+/// SOCKS5 handshake without auth, the proxy resolves the host
+async fn socks5(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let err = |msg: &str| io::Error::other(format!("socks5: {}", msg));
+    if host.len() > 255 {
+        return Err(err("host too long"));
+    }
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf != [5, 0] {
+        return Err(err("no acceptable auth method"));
+    }
+
+    let mut req = vec![5, 1, 0, 3, host.len() as u8];
+    req.extend_from_slice(host.as_bytes());
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    if buf[1] != 0 {
+        return Err(err(&format!("connect failed with reply {}", buf[1])));
+    }
+    // skip the bound address
+    let len = match buf[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(err("invalid address type")),
+    };
+    let mut addr = vec![0u8; len + 2];
+    stream.read_exact(&mut addr).await?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+    use futures_util::{SinkExt, StreamExt};
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// a SOCKS5 proxy accepting one connection, records the requested host
+    async fn proxy(target: String) -> Result<(String, Arc<Mutex<Option<String>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let host = Arc::new(Mutex::new(None));
+        let requested = host.clone();
+        actix_rt::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            client.read_exact(&mut buf).await.unwrap();
+            client.write_all(&[5, 0]).await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            let mut name = vec![0u8; buf[4] as usize + 2];
+            client.read_exact(&mut name).await.unwrap();
+            let port = u16::from_be_bytes([name[name.len() - 2], name[name.len() - 1]]);
+            name.truncate(name.len() - 2);
+            *host.lock() = Some(format!("{}:{}", String::from_utf8(name).unwrap(), port));
+            client
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            let mut server = TcpStream::connect(target).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+        });
+        Ok((addr, requested))
+    }
+
+    #[actix_rt::test]
+    async fn connect_proxy() -> Result<()> {
+        let app = create_test_app("client_proxy")?;
+        let srv = actix_test::start(move || app.clone().web_app());
+        let target = srv.addr().to_string();
+        let (addr, requested) = proxy(target).await?;
+
+        let url = format!("ws://relay.onion:{}/", srv.addr().port());
+        let mut framed = connect(&url, Some(&format!("socks5://{}", addr)))
+            .await
+            .unwrap();
+        assert_eq!(
+            requested.lock().clone(),
+            Some(format!("relay.onion:{}", srv.addr().port()))
+        );
+        framed
+            .send(awc::ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(item, awc::ws::Frame::Text(r#"["EOSE","1"]"#.into()));
+
+        assert!(connect(&url, Some("http://127.0.0.1:1")).await.is_err());
+        Ok(())
+    }
+}
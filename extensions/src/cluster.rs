@@ -0,0 +1,406 @@
+use actix::{clock::sleep, Arbiter};
+use futures_channel::{
+    mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use futures_util::{future::select, StreamExt};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::Event, duration::NonZeroDuration, message::Dispatch, setting::SettingWrapper, App,
+    Extension, Session,
+};
+use parking_lot::Mutex;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+/// the first retry delay, doubles to the max backoff
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClusterSetting {
+    pub enabled: bool,
+    /// redis pub/sub url, ie: "redis://:password@127.0.0.1:6379"
+    pub redis: String,
+    /// the channel shared by the instances
+    pub channel: String,
+    /// write the events of the other instances, for the instances with their own data path.
+    /// only send them to the subscriptions when the instances share the database
+    pub store: bool,
+    /// the retry delay doubles from 1 second to the max backoff
+    pub max_backoff: NonZeroDuration,
+}
+
+impl Default for ClusterSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            redis: "redis://127.0.0.1:6379".to_owned(),
+            channel: "rnostr:events".to_owned(),
+            store: false,
+            max_backoff: Duration::from_secs(60).try_into().unwrap(),
+        }
+    }
+}
+
+/// The message on the bus
+#[derive(Serialize, Deserialize, Debug)]
+struct ClusterMessage {
+    /// the instance accepted the event
+    node: String,
+    event: Event,
+}
+
+/// Fan out the events accepted by the sessions and published by [`App::publish`], ie: gRPC, mirror, replication
+/// or negentropy, to the other instances through redis pub/sub
+pub struct Cluster {
+    pub setting: ClusterSetting,
+    /// the random id of this instance, skip the own messages
+    node: String,
+    /// the events of the other instances being written, not published again
+    received: Arc<Mutex<HashSet<[u8; 32]>>>,
+    app: App,
+    /// runs the bus tasks, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    sender: Mutex<Option<UnboundedSender<Event>>>,
+    /// stop the subscribe task when dropped
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Cluster {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_cluster_published_total",
+            "The total count of events published to the other instances"
+        );
+        describe_counter!(
+            "nostr_relay_cluster_received_total",
+            "The total count of events received from the other instances"
+        );
+        Self {
+            setting: ClusterSetting::default(),
+            node: uuid::Uuid::new_v4().to_string(),
+            received: Default::default(),
+            app,
+            arbiter: Arbiter::new(),
+            sender: Mutex::new(None),
+            stop: None,
+        }
+    }
+}
+
+impl Extension for Cluster {
+    fn name(&self) -> &'static str {
+        "cluster"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let cluster: ClusterSetting = setting.read().parse_extension(self.name());
+        if cluster == self.setting {
+            return;
+        }
+        self.setting = cluster;
+        *self.sender.lock() = None;
+        self.stop = None;
+        if !self.setting.enabled {
+            return;
+        }
+
+        let (tx, rx) = unbounded();
+        let (stop, stopped) = oneshot::channel();
+        let (setting, node, app) = (self.setting.clone(), self.node.clone(), self.app.clone());
+        let received = self.received.clone();
+        self.arbiter.spawn_fn(move || {
+            actix::spawn(publish(setting.clone(), node.clone(), rx));
+            actix::spawn(async move {
+                select(Box::pin(subscribe(setting, node, app, received)), stopped).await;
+            });
+        });
+        *self.sender.lock() = Some(tx);
+        self.stop = Some(stop);
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        _session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        self.send(event);
+    }
+
+    /// the event is sent before it is written, the stored events are skipped, ie: fetched again by the mirror
+    fn publish(&self, event: &Event) -> Result<(), String> {
+        if self.sender.lock().is_none() || self.received.lock().contains(event.id()) {
+            return Ok(());
+        }
+        let db = &self.app.db;
+        let stored = db
+            .reader()
+            .and_then(|reader| db.contains(&reader, event.id()));
+        if !matches!(stored, Ok(true)) {
+            self.send(event);
+        }
+        Ok(())
+    }
+}
+
+impl Cluster {
+    fn send(&self, event: &Event) {
+        if let Some(tx) = self.sender.lock().as_ref() {
+            let _ = tx.unbounded_send(event.clone());
+        }
+    }
+}
+
+/// publish the events until the sender is dropped, the events are dropped while disconnected
+async fn publish(setting: ClusterSetting, node: String, mut rx: UnboundedReceiver<Event>) {
+    let mut conn: Option<MultiplexedConnection> = None;
+    while let Some(event) = rx.next().await {
+        if conn.is_none() {
+            match connect(&setting.redis).await {
+                Ok(c) => conn = Some(c),
+                Err(err) => {
+                    warn!(error = err.to_string(), "cluster publish connect failed");
+                    continue;
+                }
+            }
+        }
+        let msg = serde_json::to_string(&ClusterMessage {
+            node: node.clone(),
+            event,
+        })
+        .unwrap_or_default();
+        if let Some(c) = conn.as_mut() {
+            match c.publish::<_, _, i64>(&setting.channel, msg).await {
+                Ok(_) => increment_counter!("nostr_relay_cluster_published_total"),
+                Err(err) => {
+                    warn!(error = err.to_string(), "cluster publish failed");
+                    conn = None;
+                }
+            }
+        }
+    }
+}
+
+async fn connect(url: &str) -> RedisResult<MultiplexedConnection> {
+    Client::open(url)?.get_multiplexed_async_connection().await
+}
+
+/// receive the events of the other instances, reconnect with backoff
+async fn subscribe(
+    setting: ClusterSetting,
+    node: String,
+    app: App,
+    received: Arc<Mutex<HashSet<[u8; 32]>>>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match receive(&setting, &node, &app, &received, &mut backoff).await {
+            Ok(()) => warn!("cluster disconnected"),
+            Err(err) => warn!(error = err.to_string(), "cluster subscribe failed"),
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(setting.max_backoff.into());
+    }
+}
+
+/// receive until the connection is closed
+async fn receive(
+    setting: &ClusterSetting,
+    node: &str,
+    app: &App,
+    received: &Mutex<HashSet<[u8; 32]>>,
+    backoff: &mut Duration,
+) -> RedisResult<()> {
+    let mut pubsub = Client::open(setting.redis.as_str())?
+        .get_async_pubsub()
+        .await?;
+    pubsub.subscribe(&setting.channel).await?;
+    info!(channel = setting.channel, "cluster subscribed");
+    *backoff = MIN_BACKOFF;
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        let Ok(msg) = serde_json::from_slice::<ClusterMessage>(msg.get_payload_bytes()) else {
+            continue;
+        };
+        if msg.node == node {
+            continue;
+        }
+        increment_counter!("nostr_relay_cluster_received_total");
+        if setting.store {
+            let id = *msg.event.id();
+            received.lock().insert(id);
+            let _ = app.publish(msg.event).await;
+            received.lock().remove(&id);
+        } else {
+            app.server.do_send(Dispatch {
+                id: 0,
+                event: msg.event,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::SinkExt;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use redis::{Parser, Value};
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+    };
+
+    type Subscribers = Arc<Mutex<Vec<TcpStream>>>;
+
+    fn bulk(data: &[u8]) -> Vec<u8> {
+        [format!("${}\r\n", data.len()).as_bytes(), data, b"\r\n"].concat()
+    }
+
+    /// a fake redis of one channel, the RESP2 commands are parsed by the redis crate.
+    /// the tests run with the real server of the `RNOSTR_TEST_REDIS` url if it's set
+    fn redis() -> Result<String> {
+        if let Ok(url) = std::env::var("RNOSTR_TEST_REDIS") {
+            return Ok(url);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+        let subscribers: Subscribers = Default::default();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let subscribers = subscribers.clone();
+                thread::spawn(move || {
+                    let mut parser = Parser::new();
+                    let mut reader = stream.try_clone().unwrap();
+                    while let Ok(Value::Array(args)) = parser.parse_value(&mut reader) {
+                        let args = args
+                            .into_iter()
+                            .map(|v| match v {
+                                Value::BulkString(data) => data,
+                                _ => vec![],
+                            })
+                            .collect::<Vec<_>>();
+                        let reply = match args[0].to_ascii_uppercase().as_slice() {
+                            b"SUBSCRIBE" => {
+                                let reply = [
+                                    b"*3\r\n".to_vec(),
+                                    bulk(b"subscribe"),
+                                    bulk(&args[1]),
+                                    b":1\r\n".to_vec(),
+                                ];
+                                stream.write_all(&reply.concat()).unwrap();
+                                subscribers.lock().push(stream);
+                                return;
+                            }
+                            b"PUBLISH" => {
+                                let msg = [
+                                    b"*3\r\n".to_vec(),
+                                    bulk(b"message"),
+                                    bulk(&args[1]),
+                                    bulk(&args[2]),
+                                ]
+                                .concat();
+                                let mut list = subscribers.lock();
+                                list.retain_mut(|sub| sub.write_all(&msg).is_ok());
+                                format!(":{}\r\n", list.len())
+                            }
+                            // CLIENT SETINFO of the connections
+                            _ => "+OK\r\n".to_owned(),
+                        };
+                        stream.write_all(reply.as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        Ok(format!("redis://{}", addr))
+    }
+
+    #[actix_rt::test]
+    async fn fanout() -> Result<()> {
+        let url = redis()?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+
+        let mut apps = vec![];
+        for (i, store) in [false, true].into_iter().enumerate() {
+            let app = create_test_app(&format!("cluster_{}", i))?;
+            {
+                let mut w = app.setting.write();
+                w.extra = serde_json::from_value(serde_json::json!({
+                    "cluster": {
+                        "enabled": true,
+                        "redis": url,
+                        "store": store,
+                    }
+                }))?;
+            }
+            apps.push(app.clone().add_extension(Cluster::new(app)));
+        }
+        // wait the subscriptions of the bus
+        sleep(Duration::from_millis(200)).await;
+
+        let store_app = apps[1].clone();
+        let mut srvs = apps
+            .into_iter()
+            .map(|app| actix_test::start(move || app.clone().web_app()))
+            .collect::<Vec<_>>();
+        let mut sub = srvs[0].ws_at("/").await.unwrap();
+        sub.send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let item = sub.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"EOSE\"")));
+
+        // publish to the other instance
+        let mut publisher = srvs[1].ws_at("/").await.unwrap();
+        let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
+        publisher
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let item = publisher.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+
+        let item = sub.next().await.unwrap()?;
+        assert!(
+            matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"EVENT\"") && std::str::from_utf8(&t).unwrap().contains(&event.id_str()))
+        );
+
+        // stored by the other instance
+        let mut publisher = srvs[0].ws_at("/").await.unwrap();
+        let event = Event::create(&key_pair, now(), 1, vec![], "store".to_owned())?;
+        publisher
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        publisher.next().await.unwrap()?;
+        sleep(Duration::from_millis(300)).await;
+        let reader = store_app.db.reader()?;
+        assert!(store_app
+            .db
+            .get::<Event, _, _>(&reader, event.id())?
+            .is_some());
+        let item = sub.next().await.unwrap()?;
+        assert!(
+            matches!(item, ws::Frame::Text(t) if std::str::from_utf8(&t).unwrap().contains(&event.id_str()))
+        );
+
+        // published without a session, the stored events of the bus are not published again
+        let event = Event::create(&key_pair, now(), 1, vec![], "publish".to_owned())?;
+        store_app.publish(event.clone()).await?;
+        let item = sub.next().await.unwrap()?;
+        assert!(
+            matches!(item, ws::Frame::Text(t) if std::str::from_utf8(&t).unwrap().contains(&event.id_str()))
+        );
+        Ok(())
+    }
+}
@@ -1,47 +1,203 @@
-use metrics::{describe_histogram, histogram};
+use actix::{ActorFutureExt, AsyncContext, Handler, WrapFuture};
+use actix_web::web;
+use metrics::{describe_counter, describe_histogram, histogram, increment_counter};
 use nostr_relay::{
-    db::{Db, Filter},
+    db::{Db, Event, Filter},
     duration::NonZeroDuration,
     message::{ClientMessage, IncomingMessage, OutgoingMessage},
     setting::SettingWrapper,
     Error, Extension, ExtensionMessageResult, Session,
 };
+use parking_lot::Mutex;
 use serde::Deserialize;
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-#[derive(Deserialize, Default, Debug)]
+#[derive(Deserialize, Debug)]
+#[serde(default)]
 pub struct CountSetting {
     pub enabled: bool,
+    /// max cached results, the cache is disabled when 0
+    pub cache_size: usize,
+    /// how long a cached result is served, the results of the filters with kinds
+    /// are dropped earlier when a matching event is stored
+    pub cache_ttl: NonZeroDuration,
+    /// the counts of a session scanned at the same time, the others are closed
+    pub max_pending: usize,
 }
 
+impl Default for CountSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cache_size: 10_000,
+            cache_ttl: Duration::from_secs(10).try_into().unwrap(),
+            max_pending: 2,
+        }
+    }
+}
+
+struct Entry {
+    filter: Filter,
+    count: u64,
+    time: Instant,
+}
+
+/// The count results keyed by the hash of the normalized filter
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<u64, Entry>,
+    /// the keys of the filters with kinds, dropped when an event of the kind is stored
+    kinds: HashMap<u16, HashSet<u64>>,
+}
+
+impl Cache {
+    /// the filter lists are sorted, sort the tags for the same key of the equal filters
+    fn key(filter: &Filter) -> u64 {
+        let mut tags = filter
+            .tags
+            .iter()
+            .map(|(k, v)| (k, &v[..]))
+            .collect::<Vec<_>>();
+        tags.sort();
+        let mut hasher = DefaultHasher::new();
+        (
+            &filter.ids[..],
+            &filter.authors[..],
+            &filter.kinds[..],
+            filter.since,
+            filter.until,
+            filter.limit,
+            &filter.search,
+            tags,
+        )
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get(&self, key: u64, filter: &Filter, ttl: Duration) -> Option<u64> {
+        self.entries
+            .get(&key)
+            .filter(|e| e.time.elapsed() < ttl && e.filter == *filter)
+            .map(|e| e.count)
+    }
+
+    fn insert(&mut self, key: u64, filter: &Filter, count: u64, size: usize, ttl: Duration) {
+        // the expired result of the same key is unindexed before the new one is indexed
+        self.remove(key);
+        if self.entries.len() >= size {
+            let expired = self
+                .entries
+                .iter()
+                .filter(|(_, e)| e.time.elapsed() >= ttl)
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>();
+            expired.into_iter().for_each(|k| self.remove(k));
+            if self.entries.len() >= size {
+                return;
+            }
+        }
+        for kind in filter.kinds.iter() {
+            self.kinds.entry(*kind).or_default().insert(key);
+        }
+        let entry = Entry {
+            filter: filter.clone(),
+            count,
+            time: Instant::now(),
+        };
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: u64) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.unindex(key, &entry.filter);
+        }
+    }
+
+    fn unindex(&mut self, key: u64, filter: &Filter) {
+        for kind in filter.kinds.iter() {
+            if let Some(keys) = self.kinds.get_mut(kind) {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    self.kinds.remove(kind);
+                }
+            }
+        }
+    }
+
+    /// drop the results of the filters with kinds matching the event,
+    /// the other results are served until they expire
+    fn invalidate(&mut self, event: &Event) {
+        let Some(keys) = self.kinds.get(&event.kind()) else {
+            return;
+        };
+        let matched = keys
+            .iter()
+            .filter(|k| {
+                self.entries
+                    .get(k)
+                    .is_some_and(|e| e.filter.match_event(event))
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        matched.into_iter().for_each(|k| self.remove(k));
+    }
+}
+
+/// The counts of the session being scanned
+#[derive(Default)]
+struct Pending(usize);
+
 pub struct Count {
     setting: CountSetting,
     db: Arc<Db>,
+    cache: Arc<Mutex<Cache>>,
 }
 
 impl Count {
     pub fn new(db: Arc<Db>) -> Self {
         describe_histogram!("nostr_relay_count_size", "The time of per filter count");
+        describe_counter!(
+            "nostr_relay_count_cache_hit_total",
+            "The total count of count results served from the cache"
+        );
         Self {
             setting: CountSetting::default(),
             db,
+            cache: Default::default(),
         }
     }
 
-    fn count(&self, filter: &Filter, timeout: Option<NonZeroDuration>) -> Result<u64, Error> {
-        let reader = self.db.reader()?;
-        let start = Instant::now();
-        let mut iter = self.db.iter::<String, _>(&reader, filter)?;
-        if let Some(time) = timeout {
-            iter.scan_time(time.into(), 2000);
+    /// The count of the filter served from the cache
+    fn cached(&self, filter: &Filter) -> Option<u64> {
+        if self.setting.cache_size == 0 {
+            return None;
         }
-        let (size, _) = iter.size()?;
-        histogram!("nostr_relay_count_size", start.elapsed());
-        Ok(size)
+        let ttl = self.setting.cache_ttl.into();
+        let count = self.cache.lock().get(Cache::key(filter), filter, ttl);
+        if count.is_some() {
+            increment_counter!("nostr_relay_count_cache_hit_total");
+        }
+        count
     }
 }
 
+fn count(db: &Db, filter: &Filter, timeout: Duration) -> Result<u64, Error> {
+    let reader = db.reader()?;
+    let start = Instant::now();
+    let mut iter = db.iter::<String, _>(&reader, filter)?;
+    iter.scan_time(timeout, 2000);
+    let (size, _) = iter.size()?;
+    histogram!("nostr_relay_count_size", start.elapsed());
+    Ok(size)
+}
+
+fn count_message(sub_id: &str, size: u64) -> OutgoingMessage {
+    OutgoingMessage(format!(r#"["COUNT","{}",{{"count": {}}}]"#, sub_id, size))
+}
+
 impl Extension for Count {
     fn name(&self) -> &'static str {
         "count"
@@ -53,32 +209,84 @@ impl Extension for Count {
         if self.setting.enabled {
             w.add_nip(45);
         }
+        *self.cache.lock() = Cache::default();
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        _session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        self.cache.lock().invalidate(event);
+    }
+
+    fn publish(&self, event: &Event) -> Result<(), String> {
+        self.cache.lock().invalidate(event);
+        Ok(())
     }
 
     fn message(
         &self,
         msg: ClientMessage,
         session: &mut Session,
-        _ctx: &mut <Session as actix::Actor>::Context,
+        ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
         if self.setting.enabled {
             if let IncomingMessage::Count(sub) = &msg.msg {
+                session.add_nip(45);
                 if !sub.filters.is_empty() {
-                    let timeout = session.app.setting.read().data.db_query_timeout;
-                    match self.count(&sub.filters[0], timeout) {
-                        Ok(size) => {
-                            return ExtensionMessageResult::Stop(OutgoingMessage(format!(
-                                r#"["COUNT","{}",{{"count": {}}}]"#,
-                                sub.id, size
-                            )))
-                        }
-                        Err(err) => {
-                            return ExtensionMessageResult::Stop(OutgoingMessage::notice(&format!(
-                                "count event error: {}",
-                                err
-                            )))
-                        }
+                    let filter = sub.filters[0].clone();
+                    if let Some(size) = self.cached(&filter) {
+                        return count_message(&sub.id, size).into();
+                    }
+                    let pending = session.get::<Pending>().map_or(0, |p| p.0);
+                    if pending >= self.setting.max_pending {
+                        return OutgoingMessage::closed(&sub.id, "error: too many counts").into();
                     }
+                    session.set(Pending(pending + 1));
+                    // the scan stops at the timeout, it is not waited by the session
+                    let timeout = {
+                        let r = session.app.setting.read();
+                        r.data
+                            .db_query_timeout
+                            .unwrap_or(r.extension.timeout)
+                            .into()
+                    };
+                    let (db, cache) = (self.db.clone(), self.cache.clone());
+                    let (size, ttl) = (self.setting.cache_size, self.setting.cache_ttl.into());
+                    let sub_id = sub.id.clone();
+                    // the scan runs on the blocking threads, the session handles the next messages
+                    ctx.spawn(
+                        async move {
+                            let res = web::block(move || {
+                                count(&db, &filter, timeout).map(|count| (count, filter))
+                            })
+                            .await
+                            .map_err(|e| e.to_string())
+                            .and_then(|res| res.map_err(|e| e.to_string()));
+                            match res {
+                                Ok((count, filter)) => {
+                                    if size > 0 {
+                                        let key = Cache::key(&filter);
+                                        cache.lock().insert(key, &filter, count, size, ttl);
+                                    }
+                                    count_message(&sub_id, count)
+                                }
+                                Err(err) => {
+                                    OutgoingMessage::notice(&format!("count event error: {}", err))
+                                }
+                            }
+                        }
+                        .into_actor(session)
+                        .map(|msg, act, ctx| {
+                            if let Some(pending) = act.get_mut::<Pending>() {
+                                pending.0 = pending.0.saturating_sub(1);
+                            }
+                            act.handle(msg, ctx);
+                        }),
+                    );
+                    return ExtensionMessageResult::Ignore;
                 }
             }
         }
@@ -152,7 +360,7 @@ mod tests {
                 vec![],
                 "test".to_owned(),
             )?;
-            let msg = format!(r#"["EVENT", {}]"#, event.to_string());
+            let msg = format!(r#"["EVENT", {}]"#, event);
             framed.send(ws::Message::Text(msg.into())).await?;
             let notice: (String, String, bool, String) =
                 parse_text(&framed.next().await.unwrap()?)?;
@@ -174,6 +382,20 @@ mod tests {
         let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
         assert_eq!(res.2.count, 3);
 
+        // the cached results of the kind are dropped by the new event
+        let event = Event::create(&key_pair, start, 1002, vec![], "new".to_owned())?;
+        let msg = format!(r#"["EVENT", {}]"#, event);
+        framed.send(ws::Message::Text(msg.into())).await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+        for (filter, count) in [(r#"{"kinds": [1002]}"#, 4), ("{}", 10)] {
+            let msg = format!(r#"["COUNT", "1", {}]"#, filter);
+            framed.send(ws::Message::Text(msg.into())).await?;
+            let res: (String, String, CountResult) = parse_text(&framed.next().await.unwrap()?)?;
+            // the filters without kinds are served until expired
+            assert_eq!(res.2.count, count);
+        }
+
         // close
         framed
             .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
@@ -183,4 +405,78 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn pending() -> Result<()> {
+        let app = create_test_app("count-pending")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "count": {
+                    "enabled": true,
+                    "max_pending": 0
+                }
+            }"#,
+            )?;
+        }
+        let db = app.db.clone();
+        let app = web::Data::new(app.add_extension(Count::new(db)));
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        framed
+            .send(ws::Message::Text(r#"["COUNT", "1", {}]"#.into()))
+            .await?;
+        let res: (String, String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.0, "CLOSED");
+        assert_eq!(res.2, "error: too many counts");
+        Ok(())
+    }
+
+    #[test]
+    fn cache() -> Result<()> {
+        let ttl = Duration::from_secs(10);
+        let mut cache = Cache::default();
+        let filter: Filter = r##"{"kinds": [1, 7], "#t": ["a"], "#d": ["b"]}"##.parse()?;
+        let same: Filter = r##"{"#d": ["b"], "#t": ["a"], "kinds": [7, 1, 7]}"##.parse()?;
+        let key = Cache::key(&filter);
+        assert_eq!(key, Cache::key(&same));
+        cache.insert(key, &filter, 5, 2, ttl);
+        assert_eq!(cache.get(key, &same, ttl), Some(5));
+        assert_eq!(cache.get(key, &filter, Duration::ZERO), None);
+
+        // full
+        let other: Filter = r#"{"kinds": [1]}"#.parse()?;
+        let third: Filter = r#"{"kinds": [2]}"#.parse()?;
+        cache.insert(Cache::key(&other), &other, 1, 2, ttl);
+        cache.insert(Cache::key(&third), &third, 1, 2, ttl);
+        assert_eq!(cache.entries.len(), 2);
+
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, now(), 1, vec![], "".to_owned())?;
+        cache.invalidate(&event);
+        assert_eq!(cache.get(key, &filter, ttl), Some(5));
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.kinds.get(&1).unwrap().contains(&key));
+
+        let tags = vec![
+            vec!["t".to_owned(), "a".to_owned()],
+            vec!["d".to_owned(), "b".to_owned()],
+        ];
+        let event = Event::create(&key_pair, now(), 7, tags, "".to_owned())?;
+        cache.invalidate(&event);
+        assert!(cache.entries.is_empty());
+        assert!(cache.kinds.is_empty());
+
+        // the result inserted again after it expired is still dropped by a matching event
+        cache.insert(key, &filter, 5, 2, ttl);
+        assert_eq!(cache.get(key, &filter, Duration::ZERO), None);
+        cache.insert(key, &filter, 6, 2, ttl);
+        assert_eq!(cache.get(key, &filter, ttl), Some(6));
+        cache.invalidate(&event);
+        assert_eq!(cache.get(key, &filter, ttl), None);
+        assert!(cache.kinds.is_empty());
+        Ok(())
+    }
 }
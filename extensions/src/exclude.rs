@@ -0,0 +1,220 @@
+//! Negative filter fields, `"!authors"`, `"!kinds"` and `"!#<tag>"` exclude the matched events
+//! from the results after the standard matching, ie: the spam kinds or the muted authors.
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::Exclude,
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ExcludeSetting {
+    pub enabled: bool,
+    /// the max excluded values of a filter
+    pub max_values: usize,
+}
+
+impl Default for ExcludeSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_values: 1000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Excluder {
+    pub setting: ExcludeSetting,
+}
+
+impl Default for Excluder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Excluder {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_exclude_total",
+            "The total count of filters with negative fields"
+        );
+        Self {
+            setting: ExcludeSetting::default(),
+        }
+    }
+
+    /// Parse the negative fields of the raw filters, the last items of the message
+    fn parse(&self, text: &str, len: usize) -> Result<Vec<Exclude>, String> {
+        let list: Vec<Value> = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let filters = list
+            .get(list.len().saturating_sub(len)..)
+            .unwrap_or_default();
+        filters
+            .iter()
+            .map(|f| {
+                let exclude = Exclude::from_value(f).map_err(|e| e.to_string())?;
+                if exclude.len() > self.setting.max_values {
+                    return Err(format!(
+                        "too many excluded values, max {}",
+                        self.setting.max_values
+                    ));
+                }
+                Ok(exclude)
+            })
+            .collect()
+    }
+}
+
+impl Extension for Excluder {
+    fn name(&self) -> &'static str {
+        "exclude"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        w.set_extension(self.setting.clone());
+    }
+
+    fn message(
+        &self,
+        mut msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        if let IncomingMessage::Req(sub) = &mut msg.msg {
+            // the filters without negative fields are parsed once more
+            if !msg.text.contains("\"!") {
+                return ExtensionMessageResult::Continue(msg);
+            }
+            match self.parse(&msg.text, sub.filters.len()) {
+                Ok(list) => {
+                    for (filter, exclude) in sub.filters.iter_mut().zip(list) {
+                        if !exclude.is_empty() {
+                            increment_counter!("nostr_relay_exclude_total");
+                            filter.exclude = exclude;
+                        }
+                    }
+                }
+                Err(err) => {
+                    return OutgoingMessage::closed(&sub.id, &format!("invalid: {}", err)).into()
+                }
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web::web;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::{
+        create_web_app,
+        db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        },
+    };
+
+    fn parse_text<T: serde::de::DeserializeOwned>(frame: &ws::Frame) -> Result<T> {
+        if let ws::Frame::Text(text) = &frame {
+            let data: T = serde_json::from_slice(text)?;
+            Ok(data)
+        } else {
+            Err(nostr_relay::Error::Message("invalid frame type".to_string()).into())
+        }
+    }
+
+    #[test]
+    fn parse() -> Result<()> {
+        let excluder = Excluder {
+            setting: ExcludeSetting {
+                enabled: true,
+                max_values: 2,
+            },
+        };
+        let text = r#"["REQ", "1", {"kinds": [1]}, {"!kinds": [7], "!#t": ["spam"]}]"#;
+        let list = excluder.parse(text, 2).unwrap();
+        assert!(list[0].is_empty());
+        assert_eq!(list[1].kinds.to_vec(), vec![7]);
+        assert_eq!(list[1].len(), 2);
+        let text = r#"["REQ", "1", {"!kinds": [1, 2, 3]}]"#;
+        assert!(excluder.parse(text, 1).is_err());
+        let text = r#"["REQ", "1", {"!authors": ["ab"]}]"#;
+        assert!(excluder.parse(text, 1).is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn exclude() -> Result<()> {
+        let app = create_test_app("exclude")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(r#"{"exclude": {"enabled": true}}"#)?;
+        }
+        let muted = KeyPair::new_global(&mut thread_rng());
+        let other = KeyPair::new_global(&mut thread_rng());
+        let note = |key_pair: &KeyPair, kind: u16, content: &str| {
+            Event::create(key_pair, now(), kind, vec![], content.to_owned())
+        };
+        app.db.batch_put([
+            note(&muted, 1, "muted")?,
+            note(&other, 1, "note")?,
+            note(&other, 7, "reaction")?,
+        ])?;
+        let muted = muted.x_only_public_key().0.to_string();
+
+        let app = app.add_extension(Excluder::new());
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let text = format!(
+            r#"["REQ", "1", {{"!authors": ["{}"], "!kinds": [7]}}]"#,
+            muted
+        );
+        framed.send(ws::Message::Text(text.into())).await?;
+        let mut contents = vec![];
+        loop {
+            let list: Vec<Value> = parse_text(&framed.next().await.unwrap()?)?;
+            if list[0] == "EOSE" {
+                break;
+            }
+            contents.push(list[2]["content"].as_str().unwrap_or_default().to_owned());
+        }
+        assert_eq!(contents, vec!["note"]);
+
+        // excluded from the live events
+        let event = note(&other, 7, "live reaction")?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let event = note(&other, 1, "live note")?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        loop {
+            let list: Vec<Value> = parse_text(&framed.next().await.unwrap()?)?;
+            if list[0] == "EVENT" {
+                assert_eq!(list[2]["content"], "live note");
+                break;
+            }
+        }
+        Ok(())
+    }
+}
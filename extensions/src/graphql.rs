@@ -0,0 +1,784 @@
+//! A read-only GraphQL endpoint over the stored events at `/graphql` for the dashboards and explorers.
+//! The queries are executed by async-graphql on the blocking threads, the mutations and subscriptions are not supported,
+//! the schema is served by `GET /graphql` without a query.
+use actix_web::{
+    http::{header::AUTHORIZATION, StatusCode},
+    web,
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, InputObject, Object, Request, Schema, Variables,
+};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{Db, Event, Filter},
+    setting::SettingWrapper,
+    App, Extension,
+};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The schema of the endpoint
+pub type RelaySchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GraphqlSetting {
+    pub enabled: bool,
+    /// require the `Authorization: Bearer <token>` header when set
+    pub token: Option<String>,
+    /// max and default limit of a list of events
+    pub max_limit: u64,
+    /// max depth of the nested fields
+    pub max_depth: usize,
+    /// max events read by one query, the nested lists multiply
+    pub max_events: usize,
+}
+
+impl Default for GraphqlSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token: None,
+            max_limit: 100,
+            max_depth: 10,
+            max_events: 1000,
+        }
+    }
+}
+
+pub struct Graphql {
+    pub setting: GraphqlSetting,
+    /// rebuilt when the max depth changes
+    schema: Arc<RwLock<RelaySchema>>,
+}
+
+impl Graphql {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_graphql_request_total",
+            "The total count of GraphQL requests by the result"
+        );
+        let setting = GraphqlSetting::default();
+        Self {
+            schema: Arc::new(RwLock::new(schema(&setting))),
+            setting,
+        }
+    }
+}
+
+impl Default for Graphql {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The schema limited to the max depth
+pub fn schema(setting: &GraphqlSetting) -> RelaySchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(setting.max_depth)
+        .finish()
+}
+
+impl Extension for Graphql {
+    fn name(&self) -> &'static str {
+        "graphql"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        let graphql: GraphqlSetting = w.parse_extension(self.name());
+        if graphql.max_depth != self.setting.max_depth {
+            *self.schema.write() = schema(&graphql);
+        }
+        self.setting = graphql;
+        w.set_extension(self.setting.clone());
+    }
+
+    fn config_web(&mut self, cfg: &mut web::ServiceConfig) {
+        cfg.service(
+            web::resource("/graphql")
+                .app_data(web::Data::from(self.schema.clone()))
+                .route(web::get().to(route_get))
+                .route(web::post().to(route_post)),
+        );
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GetRequest {
+    query: Option<String>,
+    /// json
+    variables: Option<String>,
+    operation_name: Option<String>,
+}
+
+async fn route_get(
+    req: HttpRequest,
+    query: web::Query<GetRequest>,
+    app: web::Data<App>,
+    schema: web::Data<RwLock<RelaySchema>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let schema = schema.read().clone();
+    let Some(text) = query.query else {
+        if let Err(status) = setting(&req, &app) {
+            return HttpResponse::new(status);
+        }
+        return HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(schema.sdl());
+    };
+    let variables = match query
+        .variables
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose()
+    {
+        Ok(variables) => variables.map(Variables::from_json).unwrap_or_default(),
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let mut request = Request::new(text).variables(variables);
+    if let Some(name) = query.operation_name {
+        request = request.operation_name(name);
+    }
+    run(&req, &app, schema, request).await
+}
+
+async fn route_post(
+    req: HttpRequest,
+    body: Bytes,
+    app: web::Data<App>,
+    schema: web::Data<RwLock<RelaySchema>>,
+) -> HttpResponse {
+    let schema = schema.read().clone();
+    match serde_json::from_slice(&body) {
+        Ok(request) => run(&req, &app, schema, request).await,
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+/// the setting and the query timeout of the enabled endpoint, the response of the rejected request
+fn setting(req: &HttpRequest, app: &App) -> Result<(GraphqlSetting, Option<Duration>), StatusCode> {
+    let r = app.setting.read();
+    let Some(setting) = r.get_extension::<GraphqlSetting>().filter(|s| s.enabled) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if let Some(token) = &setting.token {
+        let auth = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if auth != Some(token.as_str()) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    Ok((setting.clone(), r.data.db_query_timeout.map(Into::into)))
+}
+
+async fn run(req: &HttpRequest, app: &App, schema: RelaySchema, request: Request) -> HttpResponse {
+    let (setting, timeout) = match setting(req, app) {
+        Ok(s) => s,
+        Err(status) => return HttpResponse::new(status),
+    };
+    let request = request.data(Store {
+        db: app.db.clone(),
+        setting,
+        timeout,
+        events: AtomicUsize::new(0),
+    });
+    // the resolvers read the database, the query runs on the blocking threads
+    match web::block(move || futures_executor::block_on(schema.execute(request))).await {
+        Ok(res) => {
+            let result = if res.is_ok() { "ok" } else { "error" };
+            increment_counter!("nostr_relay_graphql_request_total", "result" => result);
+            HttpResponse::Ok().json(res)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// match the events with any of the values of the tag, ie: {name: "t", values: ["nostr"]}
+#[derive(InputObject, Debug)]
+pub struct TagFilter {
+    name: String,
+    values: Vec<String>,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// the stored events matching the NIP-01 filter, newest first
+    #[allow(clippy::too_many_arguments)]
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        ids: Option<Vec<String>>,
+        authors: Option<Vec<String>>,
+        kinds: Option<Vec<u16>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<u64>,
+        search: Option<String>,
+        tags: Option<Vec<TagFilter>>,
+    ) -> async_graphql::Result<Vec<Node>> {
+        let filter = json!({
+            "ids": ids, "authors": authors, "kinds": kinds, "since": since, "until": until, "search": search
+        });
+        Ok(ctx.data::<Store>()?.events(filter, limit, tags)?)
+    }
+
+    async fn event(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<Option<Node>> {
+        Ok(ctx.data::<Store>()?.get(&id)?.map(Node))
+    }
+
+    /// the profile of the latest metadata event
+    async fn profile(&self, ctx: &Context<'_>, pubkey: String) -> async_graphql::Result<Profile> {
+        Ok(ctx.data::<Store>()?.profile(&pubkey)?)
+    }
+
+    /// the root and the replies found by the e tags, oldest first, the replies are kind 1 by default
+    async fn thread(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        kinds: Option<Vec<u16>>,
+        limit: Option<u64>,
+    ) -> async_graphql::Result<Vec<Node>> {
+        let store = ctx.data::<Store>()?;
+        let kinds = kinds.unwrap_or_else(|| vec![1]);
+        Ok(store.thread(&id, kinds, store.limit(limit) as usize)?)
+    }
+}
+
+/// The event
+pub struct Node(Event);
+
+#[Object(name = "Event", rename_fields = "snake_case")]
+impl Node {
+    async fn id(&self) -> String {
+        self.0.id_str()
+    }
+
+    async fn pubkey(&self) -> String {
+        self.0.pubkey_str()
+    }
+
+    async fn created_at(&self) -> u64 {
+        self.0.created_at()
+    }
+
+    async fn kind(&self) -> u16 {
+        self.0.kind()
+    }
+
+    async fn tags(&self) -> &Vec<Vec<String>> {
+        self.0.tags()
+    }
+
+    async fn content(&self) -> &str {
+        self.0.content()
+    }
+
+    async fn sig(&self) -> String {
+        hex::encode(self.0.sig())
+    }
+
+    /// the event json
+    async fn json(&self) -> String {
+        self.0.to_string()
+    }
+
+    async fn author(&self, ctx: &Context<'_>) -> async_graphql::Result<Profile> {
+        Ok(ctx.data::<Store>()?.profile(&self.0.pubkey_str())?)
+    }
+
+    /// the thread root by the NIP-10 e tags
+    async fn root(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Node>> {
+        self.reference(ctx, references(&self.0).0)
+    }
+
+    /// the replied event by the NIP-10 e tags
+    async fn parent(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Node>> {
+        self.reference(ctx, references(&self.0).1)
+    }
+
+    /// the events referencing it with an e tag, newest first
+    async fn replies(
+        &self,
+        ctx: &Context<'_>,
+        kinds: Option<Vec<u16>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<u64>,
+    ) -> async_graphql::Result<Vec<Node>> {
+        let filter =
+            json!({ "#e": [self.0.id_str()], "kinds": kinds, "since": since, "until": until });
+        Ok(ctx.data::<Store>()?.events(filter, limit, None)?)
+    }
+}
+
+impl Node {
+    fn reference(
+        &self,
+        ctx: &Context<'_>,
+        id: Option<String>,
+    ) -> async_graphql::Result<Option<Node>> {
+        match id {
+            Some(id) if valid_hex(&id) => Ok(ctx.data::<Store>()?.get(&id)?.map(Node)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The profile of the pubkey
+pub struct Profile {
+    pubkey: String,
+    /// the latest metadata event
+    metadata: Option<Event>,
+}
+
+impl Profile {
+    fn info(&self, key: &str) -> Option<String> {
+        self.metadata
+            .as_ref()
+            .and_then(|e| serde_json::from_str::<Json>(e.content()).ok())
+            .and_then(|info| info.get(key)?.as_str().map(ToOwned::to_owned))
+    }
+}
+
+#[Object(rename_fields = "snake_case")]
+impl Profile {
+    async fn pubkey(&self) -> &str {
+        &self.pubkey
+    }
+
+    async fn name(&self) -> Option<String> {
+        self.info("name")
+    }
+
+    async fn display_name(&self) -> Option<String> {
+        self.info("display_name")
+    }
+
+    async fn about(&self) -> Option<String> {
+        self.info("about")
+    }
+
+    async fn picture(&self) -> Option<String> {
+        self.info("picture")
+    }
+
+    async fn banner(&self) -> Option<String> {
+        self.info("banner")
+    }
+
+    async fn website(&self) -> Option<String> {
+        self.info("website")
+    }
+
+    async fn nip05(&self) -> Option<String> {
+        self.info("nip05")
+    }
+
+    async fn lud16(&self) -> Option<String> {
+        self.info("lud16")
+    }
+
+    /// the content of the metadata event
+    async fn metadata(&self) -> Option<&str> {
+        self.metadata.as_ref().map(|e| e.content())
+    }
+
+    async fn created_at(&self) -> Option<u64> {
+        self.metadata.as_ref().map(|e| e.created_at())
+    }
+
+    async fn event(&self) -> Option<Node> {
+        self.metadata.clone().map(Node)
+    }
+
+    /// the events of the author, newest first
+    #[allow(clippy::too_many_arguments)]
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        kinds: Option<Vec<u16>>,
+        since: Option<u64>,
+        until: Option<u64>,
+        limit: Option<u64>,
+        search: Option<String>,
+        tags: Option<Vec<TagFilter>>,
+    ) -> async_graphql::Result<Vec<Node>> {
+        let filter = json!({
+            "authors": [self.pubkey], "kinds": kinds, "since": since, "until": until, "search": search
+        });
+        Ok(ctx.data::<Store>()?.events(filter, limit, tags)?)
+    }
+
+    /// the p tags of the latest contact list
+    async fn follows(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<String>> {
+        let filter = json!({ "authors": [self.pubkey], "kinds": [3], "limit": 1 });
+        let follows = ctx
+            .data::<Store>()?
+            .query(filter)?
+            .first()
+            .map(|e| {
+                e.tags()
+                    .iter()
+                    .filter(|t| t.len() > 1 && t[0] == "p")
+                    .map(|t| t[1].clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(follows)
+    }
+}
+
+/// the root and the replied event ids by the NIP-10 e tags, the marked or the positional
+fn references(event: &Event) -> (Option<String>, Option<String>) {
+    let refs = event
+        .tags()
+        .iter()
+        .filter(|t| t.len() > 1 && t[0] == "e")
+        .map(|t| (&t[1], t.get(3).map(String::as_str).unwrap_or_default()))
+        .collect::<Vec<_>>();
+    let marked = |marker: &str| refs.iter().find(|r| r.1 == marker).map(|r| r.0.clone());
+    if refs.iter().any(|r| r.1 == "root" || r.1 == "reply") {
+        let root = marked("root");
+        let parent = marked("reply").or_else(|| root.clone());
+        (root, parent)
+    } else {
+        (
+            refs.first().map(|r| r.0.clone()),
+            refs.last().map(|r| r.0.clone()),
+        )
+    }
+}
+
+fn valid_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The database of a request, the events read by the query are counted
+struct Store {
+    db: Arc<Db>,
+    setting: GraphqlSetting,
+    timeout: Option<Duration>,
+    events: AtomicUsize,
+}
+
+impl Store {
+    /// the limit is bounded by max_limit
+    fn limit(&self, limit: Option<u64>) -> u64 {
+        limit.map_or(self.setting.max_limit, |l| l.min(self.setting.max_limit))
+    }
+
+    /// query the events by the filter without the null arguments
+    fn events(
+        &self,
+        mut filter: Json,
+        limit: Option<u64>,
+        tags: Option<Vec<TagFilter>>,
+    ) -> Result<Vec<Node>, String> {
+        if let Json::Object(map) = &mut filter {
+            map.retain(|_, v| !v.is_null());
+        }
+        filter["limit"] = self.limit(limit).into();
+        for tag in tags.unwrap_or_default() {
+            filter[format!("#{}", tag.name)] = tag.values.into();
+        }
+        Ok(self.query(filter)?.into_iter().map(Node).collect())
+    }
+
+    fn query(&self, filter: Json) -> Result<Vec<Event>, String> {
+        #[cfg_attr(not(feature = "search"), allow(unused_mut))]
+        let mut filter: Filter = serde_json::from_value(filter).map_err(|e| e.to_string())?;
+        #[cfg(feature = "search")]
+        filter.build_words();
+        let reader = self.db.reader().map_err(|e| e.to_string())?;
+        let mut iter = self
+            .db
+            .iter::<Event, _>(&reader, &filter)
+            .map_err(|e| e.to_string())?;
+        if let Some(timeout) = self.timeout {
+            iter.scan_time(timeout, 2000);
+        }
+        let events = iter
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        if self.events.fetch_add(events.len(), Ordering::Relaxed) + events.len()
+            > self.setting.max_events
+        {
+            return Err(format!(
+                "the query reads more than {} events",
+                self.setting.max_events
+            ));
+        }
+        Ok(events)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Event>, String> {
+        if !valid_hex(id) {
+            return Err(format!("invalid id {:?}", id));
+        }
+        Ok(self.query(json!({ "ids": [id], "limit": 1 }))?.pop())
+    }
+
+    fn profile(&self, pubkey: &str) -> Result<Profile, String> {
+        if !valid_hex(pubkey) {
+            return Err(format!("invalid pubkey {:?}", pubkey));
+        }
+        let filter = json!({ "authors": [pubkey], "kinds": [0], "limit": 1 });
+        Ok(Profile {
+            pubkey: pubkey.to_owned(),
+            metadata: self.query(filter)?.pop(),
+        })
+    }
+
+    /// the root and the replies found breadth first by the e tags
+    fn thread(&self, id: &str, kinds: Vec<u16>, limit: usize) -> Result<Vec<Node>, String> {
+        let Some(event) = self.get(id)? else {
+            return Ok(vec![]);
+        };
+        let root_id = references(&event)
+            .0
+            .filter(|id| valid_hex(id))
+            .unwrap_or_else(|| event.id_str());
+        let root = if root_id == event.id_str() {
+            Some(event)
+        } else {
+            self.get(&root_id)?
+        };
+        let mut seen = HashSet::from([root_id.clone()]);
+        let mut replies = vec![];
+        let mut frontier = vec![root_id];
+        while !frontier.is_empty() && replies.len() < limit {
+            let filter = json!({ "#e": frontier, "kinds": kinds, "limit": limit - replies.len() });
+            frontier = vec![];
+            for event in self.query(filter)? {
+                if seen.insert(event.id_str()) {
+                    frontier.push(event.id_str());
+                    replies.push(event);
+                }
+            }
+        }
+        replies.sort_by_key(|e| (e.created_at(), *e.id()));
+        Ok(root.into_iter().chain(replies).map(Node).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+
+    #[test]
+    fn nip10() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let tags = |tags: &[&[&str]]| {
+            tags.iter()
+                .map(|t| t.iter().map(|s| s.to_string()).collect())
+                .collect::<Vec<_>>()
+        };
+        let event = |t: &[&[&str]]| Event::create(&key_pair, 0, 1, tags(t), "".to_owned());
+        let refs = |e: Event| references(&e);
+        let (a, b, c) = ("a".repeat(64), "b".repeat(64), "c".repeat(64));
+        assert_eq!(
+            refs(event(&[&["e", &a], &["e", &b], &["e", &c]])?),
+            (Some(a.clone()), Some(c.clone()))
+        );
+        assert_eq!(
+            refs(event(&[&["e", &b, "", "reply"], &["e", &a, "", "root"]])?),
+            (Some(a.clone()), Some(b.clone()))
+        );
+        assert_eq!(
+            refs(event(&[&["e", &a, "", "root"], &["e", &c, "", "mention"]])?),
+            (Some(a.clone()), Some(a.clone()))
+        );
+        assert_eq!(refs(event(&[&["t", "a"]])?), (None, None));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn endpoint() -> Result<()> {
+        let app = create_test_app("graphql")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({
+                "graphql": { "enabled": true, "max_depth": 5, "max_events": 20 }
+            }))?;
+        }
+        let app = app.add_extension(Graphql::new());
+
+        let alice = KeyPair::new_global(&mut thread_rng());
+        let bob = KeyPair::new_global(&mut thread_rng());
+        let tags = |tags: &[&[&str]]| {
+            tags.iter()
+                .map(|t| t.iter().map(|s| s.to_string()).collect())
+                .collect::<Vec<_>>()
+        };
+        let time = now();
+        let profile = Event::create(
+            &alice,
+            time,
+            0,
+            vec![],
+            r#"{"name":"alice","about":"hi"}"#.to_owned(),
+        )?;
+        let root = Event::create(&alice, time, 1, tags(&[&["t", "nostr"]]), "root".to_owned())?;
+        let reply = Event::create(
+            &bob,
+            time + 1,
+            1,
+            tags(&[&["e", &root.id_str(), "", "root"]]),
+            "reply".to_owned(),
+        )?;
+        // positional e tags of the old clients
+        let nested = Event::create(
+            &alice,
+            time + 2,
+            1,
+            tags(&[&["e", &root.id_str()], &["e", &reply.id_str()]]),
+            "nested".to_owned(),
+        )?;
+        let reaction = Event::create(
+            &bob,
+            time + 3,
+            7,
+            tags(&[&["e", &root.id_str()]]),
+            "+".to_owned(),
+        )?;
+        for event in [&profile, &root, &reply, &nested, &reaction] {
+            app.publish(event.clone()).await?;
+        }
+
+        let srv = actix_test::start({
+            let app = app.clone();
+            move || app.clone().web_app()
+        });
+        let post = |query: &str, variables: Json| {
+            let body = json!({ "query": query, "variables": variables });
+            let req = awc::Client::default().post(srv.url("/graphql"));
+            async move {
+                let mut res = req.send_json(&body).await.unwrap();
+                assert_eq!(res.status(), 200);
+                res.json::<Json>().await.unwrap()
+            }
+        };
+
+        let res = post(
+            r#"query ($tag: String!) {
+              events(kinds: 1, tags: [{name: "t", values: [$tag]}]) {
+                id
+                __typename
+                author { name about follows }
+                replies(limit: 1) { content parent { content } }
+              }
+            }"#,
+            json!({ "tag": "nostr" }),
+        )
+        .await;
+        assert_eq!(
+            res,
+            json!({ "data": { "events": [{
+                "id": root.id_str(),
+                "__typename": "Event",
+                "author": { "name": "alice", "about": "hi", "follows": [] },
+                "replies": [{ "content": "+", "parent": { "content": "root" } }],
+            }]}})
+        );
+        // the fields are in the order of the selections
+        let pubkey = alice.x_only_public_key().0.to_string();
+        let query = format!(r#"{{ profile(pubkey: "{}") {{ pubkey name }} }}"#, pubkey);
+        let mut res = awc::Client::default()
+            .post(srv.url("/graphql"))
+            .send_json(&json!({ "query": query }))
+            .await
+            .unwrap();
+        assert_eq!(
+            res.body().await?,
+            format!(
+                r#"{{"data":{{"profile":{{"pubkey":"{}","name":"alice"}}}}}}"#,
+                pubkey
+            )
+        );
+
+        let res = post(
+            "query Thread($id: String!) { thread(id: $id) { content root { content } } }",
+            json!({ "id": nested.id_str() }),
+        )
+        .await;
+        assert_eq!(
+            res,
+            json!({ "data": { "thread": [
+                { "content": "root", "root": null },
+                { "content": "reply", "root": { "content": "root" } },
+                { "content": "nested", "root": { "content": "root" } },
+            ]}})
+        );
+
+        let res = post("{ event(id: \"00\") { id } }", json!({})).await;
+        assert_eq!(res["errors"][0]["message"], "invalid id \"00\"");
+        let res = post(
+            "{ events { author { event { author { event { id } } } } } }",
+            json!({}),
+        )
+        .await;
+        assert_eq!(res["errors"][0]["message"], "Query is nested too deep.");
+        let res = post(
+            "{ events { replies { author { events { id } } } } }",
+            json!({}),
+        )
+        .await;
+        assert_eq!(
+            res["errors"][0]["message"],
+            "the query reads more than 20 events"
+        );
+        let res = post("{ events { name } }", json!({})).await;
+        assert_eq!(
+            res["errors"][0]["message"],
+            r#"Unknown field "name" on type "Event"."#
+        );
+        let res = post("mutation { events { id } }", json!({})).await;
+        assert_eq!(
+            res["errors"][0]["message"],
+            "Schema is not configured for mutations."
+        );
+
+        let mut res = awc::Client::default()
+            .get(srv.url("/graphql?query=%7Bevent(id%3A%22") + &root.id_str() + "%22)%7Bkind%7D%7D")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            res.json::<Json>().await?,
+            json!({ "data": { "event": { "kind": 1 } } })
+        );
+        let mut res = awc::Client::default()
+            .get(srv.url("/graphql"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.body().await?, schema(&GraphqlSetting::default()).sdl());
+        Ok(())
+    }
+}
@@ -0,0 +1,670 @@
+use nostr_relay::{
+    db::{
+        now,
+        secp256k1::{KeyPair, SECP256K1},
+        Event, Filter,
+    },
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    App, Extension, ExtensionMessageResult, List, Session,
+};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use tracing::{error, info, warn};
+
+/// [NIP-29](https://nips.be/29) moderation event kinds
+pub const PUT_USER: u16 = 9000;
+pub const REMOVE_USER: u16 = 9001;
+pub const EDIT_METADATA: u16 = 9002;
+pub const DELETE_EVENT: u16 = 9005;
+pub const CREATE_GROUP: u16 = 9007;
+pub const DELETE_GROUP: u16 = 9008;
+pub const CREATE_INVITE: u16 = 9009;
+pub const JOIN_REQUEST: u16 = 9021;
+pub const LEAVE_REQUEST: u16 = 9022;
+
+/// group state events signed by the relay
+pub const GROUP_METADATA: u16 = 39000;
+pub const GROUP_ADMINS: u16 = 39001;
+pub const GROUP_MEMBERS: u16 = 39002;
+pub const GROUP_ROLES: u16 = 39003;
+
+const MODERATION_KINDS: &[u16] = &[
+    PUT_USER,
+    REMOVE_USER,
+    EDIT_METADATA,
+    DELETE_EVENT,
+    CREATE_GROUP,
+    DELETE_GROUP,
+    CREATE_INVITE,
+    JOIN_REQUEST,
+    LEAVE_REQUEST,
+];
+
+/// The moderation actions granted to the roles
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    PutUser,
+    RemoveUser,
+    EditMetadata,
+    DeleteEvent,
+    DeleteGroup,
+    CreateInvite,
+}
+
+impl Capability {
+    /// the capability required by the moderation event kind
+    pub fn of_kind(kind: u16) -> Option<Self> {
+        match kind {
+            PUT_USER => Some(Self::PutUser),
+            REMOVE_USER => Some(Self::RemoveUser),
+            EDIT_METADATA => Some(Self::EditMetadata),
+            DELETE_EVENT => Some(Self::DeleteEvent),
+            DELETE_GROUP => Some(Self::DeleteGroup),
+            CREATE_INVITE => Some(Self::CreateInvite),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GroupsSetting {
+    pub enabled: bool,
+    /// hex secret key of the relay signing the group state events, or read it from a file with secret_key_file.
+    /// The state events are not published when unset
+    pub secret_key: Option<String>,
+    /// pubkeys allowed to create groups, anyone when unset
+    pub creators: Option<List>,
+    /// role of the group creator
+    pub creator_role: String,
+    /// capabilities of each role, a member without roles can only post
+    pub roles: BTreeMap<String, BTreeSet<Capability>>,
+}
+
+impl Default for GroupsSetting {
+    fn default() -> Self {
+        use Capability::*;
+        Self {
+            enabled: false,
+            secret_key: None,
+            creators: None,
+            creator_role: "owner".to_owned(),
+            roles: BTreeMap::from([
+                (
+                    "owner".to_owned(),
+                    BTreeSet::from([
+                        PutUser,
+                        RemoveUser,
+                        EditMetadata,
+                        DeleteEvent,
+                        DeleteGroup,
+                        CreateInvite,
+                    ]),
+                ),
+                (
+                    "admin".to_owned(),
+                    BTreeSet::from([PutUser, RemoveUser, EditMetadata, DeleteEvent, CreateInvite]),
+                ),
+                (
+                    "moderator".to_owned(),
+                    BTreeSet::from([RemoveUser, DeleteEvent]),
+                ),
+                ("member".to_owned(), BTreeSet::new()),
+            ]),
+        }
+    }
+}
+
+impl GroupsSetting {
+    /// capabilities of all the roles
+    fn capabilities<'a, I: IntoIterator<Item = &'a String>>(
+        &self,
+        roles: I,
+    ) -> BTreeSet<Capability> {
+        roles
+            .into_iter()
+            .filter_map(|r| self.roles.get(r))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub about: String,
+    pub picture: String,
+    /// everyone can read the group
+    pub public: bool,
+    /// everyone can join the group without an invite code
+    pub open: bool,
+    /// pubkey => roles
+    pub members: BTreeMap<String, BTreeSet<String>>,
+    pub invites: HashSet<String>,
+    /// created_at of the last state events, the replacements must be newer
+    published: u64,
+}
+
+impl Group {
+    fn edit(&mut self, event: &Event) {
+        for tag in event.tags() {
+            match (tag.first().map(String::as_str), tag.get(1)) {
+                (Some("name"), Some(v)) => self.name = v.clone(),
+                (Some("about"), Some(v)) => self.about = v.clone(),
+                (Some("picture"), Some(v)) => self.picture = v.clone(),
+                (Some("public"), _) => self.public = true,
+                (Some("private"), _) => self.public = false,
+                (Some("open"), _) => self.open = true,
+                (Some("closed"), _) => self.open = false,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn tag_values<'a>(event: &'a Event, name: &'a str) -> impl Iterator<Item = &'a Vec<String>> {
+    event
+        .tags()
+        .iter()
+        .filter(move |t| t.len() > 1 && t[0] == name)
+}
+
+/// the group id of the `h` tag
+fn group_id(event: &Event) -> Option<&str> {
+    tag_values(event, "h").next().map(|t| t[1].as_str())
+}
+
+fn valid_group_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+}
+
+/// [NIP-29](https://nips.be/29) relay-based groups with the moderation roles of the setting.
+/// The group state is kept in memory, rebuilt from the stored moderation events when the setting is loaded.
+pub struct Groups {
+    pub setting: GroupsSetting,
+    key_pair: Option<KeyPair>,
+    app: App,
+    groups: RwLock<HashMap<String, Group>>,
+}
+
+impl Groups {
+    pub fn new(app: App) -> Self {
+        Self {
+            setting: GroupsSetting::default(),
+            key_pair: None,
+            app,
+            groups: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn group(&self, id: &str) -> Option<Group> {
+        self.groups.read().get(id).cloned()
+    }
+
+    /// validate the event against the group state and the roles of the author
+    pub fn check(&self, event: &Event) -> Result<(), String> {
+        let kind = event.kind();
+        if (GROUP_METADATA..=GROUP_ROLES).contains(&kind) {
+            return match &self.key_pair {
+                Some(key_pair) if event.pubkey() == &key_pair.x_only_public_key().0.serialize() => {
+                    Ok(())
+                }
+                _ => Err("blocked: group state is published by the relay".to_owned()),
+            };
+        }
+        let Some(id) = group_id(event) else {
+            if MODERATION_KINDS.contains(&kind) {
+                return Err("invalid: missing group id".to_owned());
+            }
+            return Ok(());
+        };
+        let author = event.pubkey_str();
+        let groups = self.groups.read();
+
+        if kind == CREATE_GROUP {
+            if !valid_group_id(id) {
+                return Err("invalid: group id".to_owned());
+            }
+            if groups.contains_key(id) {
+                return Err("duplicate: group already exists".to_owned());
+            }
+            if !self
+                .setting
+                .creators
+                .as_ref()
+                .is_none_or(|c| c.contains(&author))
+            {
+                return Err("restricted: not allowed to create groups".to_owned());
+            }
+            return Ok(());
+        }
+
+        let group = groups
+            .get(id)
+            .ok_or_else(|| "invalid: group not found".to_owned())?;
+        match kind {
+            JOIN_REQUEST => {
+                if group.members.contains_key(&author) {
+                    return Err("duplicate: already a member".to_owned());
+                }
+                let code = tag_values(event, "code").next().map(|t| &t[1]);
+                if !group.open && !code.is_some_and(|c| group.invites.contains(c)) {
+                    return Err("restricted: invite code required".to_owned());
+                }
+                return Ok(());
+            }
+            LEAVE_REQUEST => {
+                return match group.members.contains_key(&author) {
+                    true => Ok(()),
+                    false => Err("invalid: not a member".to_owned()),
+                };
+            }
+            _ => {}
+        }
+
+        let roles = group
+            .members
+            .get(&author)
+            .ok_or_else(|| "restricted: not a member of the group".to_owned())?;
+        let Some(capability) = Capability::of_kind(kind) else {
+            return Ok(());
+        };
+        let capabilities = self.setting.capabilities(roles);
+        if !capabilities.contains(&capability) {
+            return Err(format!("restricted: {:?} is not allowed", capability));
+        }
+
+        // the actor can only manage the members with less or equal capabilities
+        let manage = |pubkey: &String, roles: Option<&BTreeSet<String>>| {
+            let current = group
+                .members
+                .get(pubkey)
+                .map(|r| self.setting.capabilities(r))
+                .unwrap_or_default();
+            let new = roles
+                .map(|r| self.setting.capabilities(r))
+                .unwrap_or_default();
+            if current.is_subset(&capabilities) && new.is_subset(&capabilities) {
+                Ok(())
+            } else {
+                Err(format!("restricted: can not manage {}", pubkey))
+            }
+        };
+        match kind {
+            PUT_USER => {
+                for tag in tag_values(event, "p") {
+                    let roles = tag[2..].iter().cloned().collect::<BTreeSet<_>>();
+                    if let Some(role) = roles.iter().find(|r| !self.setting.roles.contains_key(*r))
+                    {
+                        return Err(format!("invalid: unknown role {}", role));
+                    }
+                    manage(&tag[1], Some(&roles))?;
+                }
+            }
+            REMOVE_USER => {
+                for tag in tag_values(event, "p") {
+                    manage(&tag[1], None)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// apply the stored event to the group state, returns the changed group id
+    fn apply(&self, event: &Event) -> Option<String> {
+        let kind = event.kind();
+        if !MODERATION_KINDS.contains(&kind) {
+            return None;
+        }
+        let id = group_id(event)?.to_owned();
+        let author = event.pubkey_str();
+        let mut groups = self.groups.write();
+        if kind == CREATE_GROUP {
+            let mut group = Group::default();
+            group
+                .members
+                .insert(author, BTreeSet::from([self.setting.creator_role.clone()]));
+            groups.insert(id.clone(), group);
+            return Some(id);
+        }
+        if kind == DELETE_GROUP {
+            groups.remove(&id);
+            return None;
+        }
+        let group = groups.get_mut(&id)?;
+        match kind {
+            PUT_USER => {
+                for tag in tag_values(event, "p") {
+                    group
+                        .members
+                        .insert(tag[1].clone(), tag[2..].iter().cloned().collect());
+                }
+            }
+            REMOVE_USER => {
+                for tag in tag_values(event, "p") {
+                    group.members.remove(&tag[1]);
+                }
+            }
+            EDIT_METADATA => group.edit(event),
+            DELETE_EVENT => {
+                let ids = tag_values(event, "e")
+                    .filter_map(|t| hex::decode(&t[1]).ok())
+                    .collect::<Vec<_>>();
+                if let Err(err) = self.app.db.batch_del(ids) {
+                    error!(error = err.to_string(), "groups delete events");
+                }
+                return None;
+            }
+            CREATE_INVITE => {
+                for tag in tag_values(event, "code") {
+                    group.invites.insert(tag[1].clone());
+                }
+                return None;
+            }
+            JOIN_REQUEST => {
+                group.members.insert(author, BTreeSet::new());
+            }
+            LEAVE_REQUEST => {
+                group.members.remove(&author);
+            }
+            _ => return None,
+        }
+        Some(id)
+    }
+
+    /// the group state events signed by the relay
+    fn state_events(&self, id: &str) -> Vec<Event> {
+        let Some(key_pair) = &self.key_pair else {
+            return vec![];
+        };
+        let mut groups = self.groups.write();
+        let Some(group) = groups.get_mut(id) else {
+            return vec![];
+        };
+        // the replacements must be newer
+        group.published = now().max(group.published + 1);
+        let d = || vec!["d".to_owned(), id.to_owned()];
+        let flag = |b: bool, t: &str, f: &str| vec![if b { t } else { f }.to_owned()];
+
+        let metadata = vec![
+            d(),
+            vec!["name".to_owned(), group.name.clone()],
+            vec!["about".to_owned(), group.about.clone()],
+            vec!["picture".to_owned(), group.picture.clone()],
+            flag(group.public, "public", "private"),
+            flag(group.open, "open", "closed"),
+        ];
+        let admins = std::iter::once(d())
+            .chain(
+                group
+                    .members
+                    .iter()
+                    .filter(|(_, roles)| !self.setting.capabilities(*roles).is_empty())
+                    .map(|(pubkey, roles)| {
+                        let mut tag = vec!["p".to_owned(), pubkey.clone()];
+                        tag.extend(roles.iter().cloned());
+                        tag
+                    }),
+            )
+            .collect();
+        let members = std::iter::once(d())
+            .chain(
+                group
+                    .members
+                    .keys()
+                    .map(|pubkey| vec!["p".to_owned(), pubkey.clone()]),
+            )
+            .collect();
+        let roles = std::iter::once(d())
+            .chain(self.setting.roles.iter().map(|(name, capabilities)| {
+                let capabilities = capabilities
+                    .iter()
+                    .map(|c| format!("{:?}", c))
+                    .collect::<Vec<_>>();
+                vec!["role".to_owned(), name.clone(), capabilities.join(", ")]
+            }))
+            .collect();
+
+        [
+            (GROUP_METADATA, metadata),
+            (GROUP_ADMINS, admins),
+            (GROUP_MEMBERS, members),
+            (GROUP_ROLES, roles),
+        ]
+        .into_iter()
+        .filter_map(|(kind, tags)| {
+            Event::create(key_pair, group.published, kind, tags, String::new())
+                .map_err(|err| error!(error = err.to_string(), "groups sign state"))
+                .ok()
+        })
+        .collect()
+    }
+
+    /// apply the event, publish the new state of the changed group
+    fn update(&self, event: &Event) {
+        let Some(id) = self.apply(event) else {
+            return;
+        };
+        let events = self.state_events(&id);
+        if events.is_empty() {
+            return;
+        }
+        let app = self.app.clone();
+        actix::spawn(async move {
+            for event in events {
+                if let Err(err) = app.publish(event).await {
+                    warn!(error = err.to_string(), "groups publish state");
+                }
+            }
+        });
+    }
+
+    /// rebuild the group state from the stored moderation events
+    fn load(&self) -> Result<usize, nostr_relay::Error> {
+        let filter = Filter {
+            kinds: MODERATION_KINDS.to_vec().into(),
+            ..Default::default()
+        };
+        let reader = self.app.db.reader()?;
+        let mut events = self
+            .app
+            .db
+            .iter::<Event, _>(&reader, &filter)?
+            .collect::<Result<Vec<_>, _>>()?;
+        // the groups are created before the other events of the same second
+        events.sort_by_key(|e| (e.created_at(), e.kind() != CREATE_GROUP, *e.id()));
+        self.groups.write().clear();
+        for event in &events {
+            if self.check(event).is_ok() {
+                self.apply(event);
+            }
+        }
+        Ok(events.len())
+    }
+}
+
+impl Extension for Groups {
+    fn name(&self) -> &'static str {
+        "groups"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        if !self.setting.enabled {
+            return;
+        }
+        w.add_nip(29);
+        drop(w);
+        self.key_pair = self.setting.secret_key.as_ref().and_then(|key| {
+            KeyPair::from_seckey_str(SECP256K1, key.trim())
+                .map_err(|err| error!(error = err.to_string(), "groups invalid secret key"))
+                .ok()
+        });
+        match self.load() {
+            Ok(num) => info!("groups loaded {} moderation events", num),
+            Err(err) => error!(error = err.to_string(), "groups load"),
+        }
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if self.setting.enabled {
+            if let IncomingMessage::Event(event) = &msg.msg {
+                if let Err(err) = self.check(event) {
+                    return OutgoingMessage::ok(&event.id_str(), false, &err).into();
+                }
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        _session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        if self.setting.enabled {
+            self.update(event);
+        }
+    }
+
+    fn publish(&self, event: &Event) -> Result<(), String> {
+        if self.setting.enabled {
+            self.check(event)?;
+            self.update(event);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::rand::thread_rng;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn roles() -> Result<()> {
+        let relay_key = KeyPair::new_global(&mut thread_rng());
+        let [alice, bob, carol] = [(); 3].map(|_| KeyPair::new_global(&mut thread_rng()));
+        let pubkey = |key: &KeyPair| hex::encode(key.x_only_public_key().0.serialize());
+
+        let app = create_test_app("groups")?;
+        app.setting.write().extra = serde_json::from_value(json!({
+            "groups": {
+                "enabled": true,
+                "secret_key": hex::encode(relay_key.secret_bytes()),
+            }
+        }))?;
+        let app = app.clone().add_extension(Groups::new(app));
+
+        let start = now();
+        let mut time = 0;
+        let mut event = |key: &KeyPair, kind: u16, tags: &[&[&str]]| {
+            time += 1;
+            let tags = tags
+                .iter()
+                .map(|t| t.iter().map(|s| s.to_string()).collect())
+                .collect();
+            Event::create(key, start + time, kind, tags, String::new()).unwrap()
+        };
+        let bob_pubkey = pubkey(&bob);
+        let alice_pubkey = pubkey(&alice);
+
+        for (event, ok) in [
+            (event(&alice, CREATE_GROUP, &[&["h", "pub"]]), true),
+            (event(&bob, CREATE_GROUP, &[&["h", "pub"]]), false),
+            (
+                event(
+                    &alice,
+                    EDIT_METADATA,
+                    &[&["h", "pub"], &["name", "Pub"], &["open"]],
+                ),
+                true,
+            ),
+            (
+                event(
+                    &alice,
+                    PUT_USER,
+                    &[&["h", "pub"], &["p", &bob_pubkey, "moderator"]],
+                ),
+                true,
+            ),
+            // moderator can not put users
+            (
+                event(
+                    &bob,
+                    PUT_USER,
+                    &[&["h", "pub"], &["p", &bob_pubkey, "owner"]],
+                ),
+                false,
+            ),
+            // can not remove the owner
+            (
+                event(&bob, REMOVE_USER, &[&["h", "pub"], &["p", &alice_pubkey]]),
+                false,
+            ),
+            (event(&carol, 9, &[&["h", "pub"]]), false),
+            (event(&carol, JOIN_REQUEST, &[&["h", "pub"]]), true),
+            // the group state is signed by the relay
+            (event(&carol, GROUP_MEMBERS, &[&["d", "pub"]]), false),
+        ] {
+            assert_eq!(app.publish(event).await.is_ok(), ok);
+        }
+
+        let post = event(&carol, 9, &[&["h", "pub"]]);
+        app.publish(post.clone()).await?;
+        let delete = event(&bob, DELETE_EVENT, &[&["h", "pub"], &["e", &post.id_str()]]);
+        app.publish(delete).await?;
+        assert!(app.db.batch_get::<Event, _, _>([post.id()])?.is_empty());
+
+        // the relay published the state
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        let filter = Filter {
+            kinds: vec![GROUP_MEMBERS].into(),
+            ..Default::default()
+        };
+        let members = app
+            .db
+            .iter::<Event, _>(&app.db.reader()?, &filter)?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(members.len(), 1);
+        assert_eq!(
+            members[0].pubkey(),
+            &relay_key.x_only_public_key().0.serialize()
+        );
+        assert_eq!(members[0].tags().len(), 4);
+
+        // rebuilt from the stored events
+        let mut groups = Groups::new(app.clone());
+        groups.setting(&app.setting);
+        let group = groups.group("pub").unwrap();
+        assert_eq!(group.name, "Pub");
+        assert_eq!(
+            group.members,
+            BTreeMap::from([
+                (alice_pubkey, BTreeSet::from(["owner".to_owned()])),
+                (bob_pubkey, BTreeSet::from(["moderator".to_owned()])),
+                (pubkey(&carol), BTreeSet::new()),
+            ])
+        );
+        Ok(())
+    }
+}
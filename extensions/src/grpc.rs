@@ -0,0 +1,471 @@
+//! A gRPC service with the relay semantics for the backend services, ie: typed publish, query,
+//! subscribe and count multiplexed on one connection without the websocket text protocol.
+//! It is served by tonic on its own cleartext HTTP/2 port, the messages are defined in `proto/relay.proto`.
+//! The extensions are not called for the queries, the published events go through the publish hooks.
+use actix::{Arbiter, ArbiterHandle};
+use futures_channel::oneshot;
+use futures_util::{
+    future::{ready, select},
+    Stream, StreamExt,
+};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{CheckEventResult, Event, Filter},
+    setting::SettingWrapper,
+    App, EventStream, Extension, StreamMessage,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+use tracing::{error, info};
+
+/// The messages and the service generated from `proto/relay.proto`
+pub mod pb {
+    tonic::include_proto!("rnostr");
+}
+
+use pb::{
+    relay_server::{Relay, RelayServer},
+    subscribe_response::Message,
+};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GrpcSetting {
+    pub enabled: bool,
+    /// the address of the cleartext HTTP/2 listener, keep it private or set the token
+    pub listen: String,
+    /// require the `authorization: Bearer <token>` metadata when set
+    pub token: Option<String>,
+}
+
+impl Default for GrpcSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:50051".to_owned(),
+            token: None,
+        }
+    }
+}
+
+pub struct Grpc {
+    pub setting: GrpcSetting,
+    app: App,
+    /// runs the server, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    /// stop the server when dropped
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Grpc {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_grpc_request_total",
+            "The total count of gRPC requests by the method and the status code"
+        );
+        Self {
+            setting: GrpcSetting::default(),
+            app,
+            arbiter: Arbiter::new(),
+            stop: None,
+        }
+    }
+}
+
+impl Extension for Grpc {
+    fn name(&self) -> &'static str {
+        "grpc"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let grpc: GrpcSetting = setting.read().parse_extension(self.name());
+        if grpc == self.setting {
+            return;
+        }
+        self.setting = grpc;
+        self.stop = None;
+        if !self.setting.enabled {
+            return;
+        }
+        let (stop, stopped) = oneshot::channel();
+        let (setting, app) = (self.setting.clone(), self.app.clone());
+        self.arbiter.spawn_fn(move || {
+            actix::spawn(async move {
+                select(Box::pin(serve(setting, app)), stopped).await;
+            });
+        });
+        self.stop = Some(stop);
+    }
+}
+
+async fn serve(setting: GrpcSetting, app: App) {
+    let listener = match TcpListener::bind(&setting.listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(error = err.to_string(), "grpc listen on {}", setting.listen);
+            return;
+        }
+    };
+    info!("gRPC listening on {}", setting.listen);
+    let max = app.setting.read().limitation.max_message_length;
+    let service = RelayServer::new(Service {
+        app,
+        token: setting.token,
+        arbiter: Arbiter::current(),
+    })
+    .max_decoding_message_size(max);
+    if let Err(err) = Server::builder()
+        .add_service(service)
+        .serve_with_incoming(TcpIncoming::from(listener))
+        .await
+    {
+        error!(error = err.to_string(), "grpc server");
+    }
+}
+
+struct Service {
+    app: App,
+    token: Option<String>,
+    /// the requests run on the tokio tasks, the actors are started on the arbiter
+    arbiter: ArbiterHandle,
+}
+
+impl Service {
+    fn authorize<T>(&self, req: &Request<T>) -> Result<(), Status> {
+        let authorized = self.token.as_ref().is_none_or(|token| {
+            req.metadata()
+                .get("authorization")
+                .is_some_and(|h| h.as_bytes() == format!("Bearer {}", token).as_bytes())
+        });
+        if authorized {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("invalid token"))
+        }
+    }
+
+    /// the subscription of the filters json
+    async fn subscribe(&self, req: Request<pb::Filters>) -> Result<EventStream, Status> {
+        self.authorize(&req)?;
+        let filters = req
+            .into_inner()
+            .filters
+            .iter()
+            .map(|f| serde_json::from_str::<Filter>(f).map_err(invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (tx, rx) = oneshot::channel();
+        let app = self.app.clone();
+        self.arbiter.spawn(async move {
+            let _ = tx.send(app.subscribe(filters).await);
+        });
+        rx.await
+            .map_err(|_| Status::unavailable("stopped"))?
+            .map_err(invalid)
+    }
+
+    async fn count(&self, req: Request<pb::CountRequest>) -> Result<u64, Status> {
+        self.authorize(&req)?;
+        #[cfg_attr(not(feature = "search"), allow(unused_mut))]
+        let mut filter =
+            serde_json::from_str::<Filter>(&req.into_inner().filter).map_err(invalid)?;
+        #[cfg(feature = "search")]
+        filter.build_words();
+        let timeout = self.app.setting.read().data.db_query_timeout;
+        let db = self.app.db.clone();
+        // the scan runs on the blocking threads, not on the thread of the streams
+        actix_web::web::block(move || {
+            let reader = db.reader()?;
+            let mut iter = db.iter::<String, _>(&reader, &filter)?;
+            if let Some(time) = timeout {
+                iter.scan_time(time.into(), 2000);
+            }
+            Ok::<_, nostr_relay::db::Error>(iter.size()?.0)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+fn invalid<T: ToString>(err: T) -> Status {
+    Status::invalid_argument(err.to_string())
+}
+
+/// count the request by the method and the status code
+fn record<T>(method: &'static str, result: Result<T, Status>) -> Result<T, Status> {
+    let code = result.as_ref().err().map_or(0, |s| s.code() as i32);
+    increment_counter!("nostr_relay_grpc_request_total", "method" => method, "status" => code.to_string());
+    result
+}
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Relay for Service {
+    async fn publish(
+        &self,
+        req: Request<pb::Event>,
+    ) -> Result<Response<pb::PublishResponse>, Status> {
+        let result = async {
+            self.authorize(&req)?;
+            let event = decode_event(req.into_inner())?;
+            let id = event.id_str();
+            let (accepted, message) = match self.app.publish(event).await {
+                Ok(CheckEventResult::Ok(_)) => (true, String::new()),
+                Ok(CheckEventResult::Duplicate) => (true, "duplicate: event exists".to_owned()),
+                Ok(CheckEventResult::Invald(msg)) => (false, format!("invalid: {}", msg)),
+                Ok(CheckEventResult::Deleted) => {
+                    (false, "deleted: user requested deletion".to_owned())
+                }
+                Ok(CheckEventResult::ReplaceIgnored) => {
+                    (false, "replaced: have newer event".to_owned())
+                }
+                Ok(CheckEventResult::TakenDown) => {
+                    (false, "blocked: event was taken down".to_owned())
+                }
+                Err(err) => (false, err.to_string()),
+            };
+            Ok(Response::new(pb::PublishResponse {
+                id,
+                accepted,
+                message,
+            }))
+        };
+        record("Publish", result.await)
+    }
+
+    type QueryStream = ResponseStream<pb::Event>;
+
+    /// the stored events, the stream ends at the eose
+    async fn query(
+        &self,
+        req: Request<pb::Filters>,
+    ) -> Result<Response<Self::QueryStream>, Status> {
+        let stream = record("Query", self.subscribe(req).await)?;
+        let stream = stream
+            .take_while(|msg| ready(!matches!(msg, StreamMessage::Eose)))
+            .map(|msg| match msg {
+                StreamMessage::Event(event) => Ok(encode_event(&event)),
+                StreamMessage::Notice(notice) => Err(Status::failed_precondition(notice)),
+                StreamMessage::Eose => unreachable!(),
+            });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type SubscribeStream = ResponseStream<pb::SubscribeResponse>;
+
+    /// the stored events, the eose and the live events until the client cancels
+    async fn subscribe(
+        &self,
+        req: Request<pb::Filters>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let stream = record("Subscribe", Service::subscribe(self, req).await)?;
+        let stream = stream.map(|msg| {
+            let message = match msg {
+                StreamMessage::Event(event) => Message::Event(encode_event(&event)),
+                StreamMessage::Eose => Message::Eose(true),
+                StreamMessage::Notice(notice) => return Err(Status::failed_precondition(notice)),
+            };
+            Ok(pb::SubscribeResponse {
+                message: Some(message),
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn count(
+        &self,
+        req: Request<pb::CountRequest>,
+    ) -> Result<Response<pb::CountResponse>, Status> {
+        let count = record("Count", Service::count(self, req).await)?;
+        Ok(Response::new(pb::CountResponse { count }))
+    }
+}
+
+fn encode_event(event: &Event) -> pb::Event {
+    pb::Event {
+        id: event.id_str(),
+        pubkey: event.pubkey_str(),
+        created_at: event.created_at() as i64,
+        kind: event.kind() as u32,
+        tags: event
+            .tags()
+            .iter()
+            .map(|values| pb::Tag {
+                values: values.clone(),
+            })
+            .collect(),
+        content: event.content().to_owned(),
+        sig: hex::encode(event.sig()),
+    }
+}
+
+/// the event is verified when published
+fn decode_event(event: pb::Event) -> Result<Event, Status> {
+    let tags = event
+        .tags
+        .into_iter()
+        .map(|tag| tag.values)
+        .collect::<Vec<_>>();
+    serde_json::from_value(json!({
+        "id": event.id,
+        "pubkey": event.pubkey,
+        "created_at": event.created_at,
+        "kind": event.kind,
+        "tags": tags,
+        "content": event.content,
+        "sig": event.sig,
+    }))
+    .map_err(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix::clock::sleep;
+    use anyhow::Result;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use pb::relay_client::RelayClient;
+    use std::time::Duration;
+    use tonic::{transport::Channel, Code};
+
+    #[test]
+    fn convert() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let tags = vec![
+            vec!["t".to_owned(), "nostr".to_owned()],
+            vec!["r".to_owned()],
+        ];
+        let event = Event::create(&key_pair, now(), 30000, tags, "héllo".to_owned())?;
+        let decoded = decode_event(encode_event(&event)).unwrap();
+        assert_eq!(decoded.to_string(), event.to_string());
+        assert!(decode_event(pb::Event::default()).is_err());
+        Ok(())
+    }
+
+    fn request<T>(msg: T, token: Option<&str>) -> Request<T> {
+        let mut req = Request::new(msg);
+        if let Some(token) = token {
+            req.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+        req
+    }
+
+    fn filters(list: &[&str]) -> pb::Filters {
+        pb::Filters {
+            filters: list.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn service() -> Result<()> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let app = create_test_app("grpc")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({
+                "grpc": {
+                    "enabled": true,
+                    "listen": format!("127.0.0.1:{}", port),
+                    "token": "secret",
+                }
+            }))?;
+        }
+        let app = app.clone().add_extension(Grpc::new(app));
+        let endpoint = Channel::from_shared(format!("http://127.0.0.1:{}", port))?;
+        let mut channel = None;
+        for _ in 0..50 {
+            if let Ok(c) = endpoint.connect().await {
+                channel = Some(c);
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        let mut client = RelayClient::new(channel.unwrap());
+        let token = Some("secret");
+
+        let err = client
+            .count(request(pb::CountRequest::default(), None))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, now(), 1, vec![], "grpc".to_owned())?;
+        let res = client
+            .publish(request(encode_event(&event), token))
+            .await?
+            .into_inner();
+        assert_eq!(
+            res,
+            pb::PublishResponse {
+                id: event.id_str(),
+                accepted: true,
+                message: String::new(),
+            }
+        );
+
+        let res = client
+            .query(request(filters(&["{}"]), token))
+            .await?
+            .into_inner()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(res, vec![encode_event(&event)]);
+
+        let req = pb::CountRequest {
+            filter: r#"{"kinds":[1]}"#.to_owned(),
+        };
+        let res = client.count(request(req, token)).await?.into_inner();
+        assert_eq!(res.count, 1);
+
+        let err = client
+            .query(request(filters(&["{"]), token))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        // the stored event, eose and the live event
+        let live = Event::create(&key_pair, now(), 1, vec![], "live".to_owned())?;
+        let publisher = app.clone();
+        let published = live.clone();
+        actix::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            publisher.publish(published).await.unwrap();
+        });
+        let res = client
+            .subscribe(request(filters(&["{}"]), token))
+            .await?
+            .into_inner()
+            .take(3)
+            .map(|msg| msg.unwrap().message.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            res,
+            vec![
+                Message::Event(encode_event(&event)),
+                Message::Eose(true),
+                Message::Event(encode_event(&live)),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,585 @@
+//! Invite-code admission of the semi-private relays, the operator mints the codes with the management api.
+//! A new pubkey is admitted by redeeming a code in the invite tag of an event or with a NIP-98 signed request to `/invite`,
+//! the events of the pubkeys not admitted are rejected.
+//! The members may mint codes of their own with `/invite/new`, the relay records who invited whom
+//! and a member can be removed together with everyone invited by them.
+use crate::nip98::verify_auth;
+use actix_web::{
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{
+        now,
+        secp256k1::rand::{thread_rng, Rng},
+        Db, Event, Invite, Member,
+    },
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    App, Extension, ExtensionMessageResult, Session,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use tracing::error;
+
+/// the letters of the codes without the similar ones
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+const CODE_LENGTH: usize = 16;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InviteSetting {
+    pub enabled: bool,
+    /// the name of the tag with the code, ie: ["invite", "<code>"]
+    pub tag: String,
+    /// the count of codes a member can mint, 0 for the operator only
+    pub member_invites: u32,
+}
+
+impl Default for InviteSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tag: "invite".to_owned(),
+            member_invites: 0,
+        }
+    }
+}
+
+/// A member and the members invited by them
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InviteNode {
+    pub pubkey: String,
+    pub code: String,
+    pub admitted_at: u64,
+    pub invited: Vec<InviteNode>,
+}
+
+impl InviteNode {
+    fn build(
+        pubkey: &str,
+        members: &HashMap<String, Member>,
+        children: &HashMap<&str, Vec<&str>>,
+        visited: &mut HashSet<String>,
+    ) -> Self {
+        visited.insert(pubkey.to_owned());
+        let member = members.get(pubkey);
+        let invited = children
+            .get(pubkey)
+            .map(|list| {
+                list.iter()
+                    .filter(|p| !visited.contains(**p))
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| Self::build(p, members, children, visited))
+            .collect();
+        Self {
+            pubkey: pubkey.to_owned(),
+            code: member.map(|m| m.code.clone()).unwrap_or_default(),
+            admitted_at: member.map(|m| m.admitted_at).unwrap_or_default(),
+            invited,
+        }
+    }
+}
+
+/// The invite codes and the admitted pubkeys, saved in the db
+pub struct Invites {
+    db: Arc<Db>,
+    members: RwLock<HashSet<String>>,
+}
+
+impl Invites {
+    /// Load the admitted pubkeys of the db
+    pub fn load(db: Arc<Db>) -> nostr_relay::Result<Self> {
+        let members = db
+            .members()?
+            .into_iter()
+            .map(|(pubkey, _)| pubkey)
+            .collect();
+        Ok(Self {
+            db,
+            members: RwLock::new(members),
+        })
+    }
+
+    pub fn is_member(&self, pubkey: &str) -> bool {
+        self.members.read().contains(pubkey)
+    }
+
+    /// Mint the codes of the invite
+    pub fn create(&self, count: usize, invite: &Invite) -> nostr_relay::Result<Vec<String>> {
+        let mut rng = thread_rng();
+        let mut codes = vec![];
+        for _ in 0..count {
+            let code = (0..CODE_LENGTH)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect::<String>();
+            self.db.put_invite(&code, invite)?;
+            codes.push(code);
+        }
+        Ok(codes)
+    }
+
+    /// Mint a single-use code for the member, returns None over the limit of the member codes
+    pub fn create_by_member(
+        &self,
+        pubkey: &str,
+        limit: u32,
+    ) -> nostr_relay::Result<Option<String>> {
+        let minted = self
+            .db
+            .invites()?
+            .iter()
+            .filter(|(_, invite)| invite.created_by == pubkey)
+            .count();
+        if minted >= limit as usize {
+            return Ok(None);
+        }
+        let invite = Invite {
+            created_at: now(),
+            max_uses: 1,
+            created_by: pubkey.to_owned(),
+            ..Default::default()
+        };
+        Ok(self.create(1, &invite)?.pop())
+    }
+
+    /// Remove the code, the admitted pubkeys are kept
+    pub fn revoke(&self, code: &str) -> nostr_relay::Result<bool> {
+        Ok(self.db.del_invite(code)?)
+    }
+
+    /// All codes sorted by the creation time
+    pub fn list(&self) -> nostr_relay::Result<Vec<(String, Invite)>> {
+        let mut list = self.db.invites()?;
+        list.sort_by_key(|(_, invite)| invite.created_at);
+        Ok(list)
+    }
+
+    /// Admit the pubkey, returns false if the code is unknown, expired or used up
+    pub fn redeem(&self, code: &str, pubkey: &str) -> nostr_relay::Result<bool> {
+        if self.is_member(pubkey) {
+            return Ok(true);
+        }
+        let admitted = self.db.redeem_invite(code, pubkey, now())?;
+        if admitted {
+            self.members.write().insert(pubkey.to_owned());
+            increment_counter!("nostr_relay_invite_redeemed_total");
+        }
+        Ok(admitted)
+    }
+
+    /// All admitted pubkeys sorted by the admission time
+    pub fn members(&self) -> nostr_relay::Result<Vec<(String, Member)>> {
+        let mut list = self.db.members()?;
+        list.sort_by_key(|(_, member)| member.admitted_at);
+        Ok(list)
+    }
+
+    /// The members by the inviters, the members of the operator codes are the roots
+    pub fn tree(&self) -> nostr_relay::Result<Vec<InviteNode>> {
+        let list = self.members()?;
+        let members = list.iter().cloned().collect::<HashMap<_, _>>();
+        let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut roots = vec![];
+        for (pubkey, member) in &list {
+            if members.contains_key(&member.invited_by) {
+                children
+                    .entry(member.invited_by.as_str())
+                    .or_default()
+                    .push(pubkey);
+            } else {
+                roots.push(pubkey.as_str());
+            }
+        }
+        let mut visited = HashSet::new();
+        let mut tree = roots
+            .into_iter()
+            .map(|p| InviteNode::build(p, &members, &children, &mut visited))
+            .collect::<Vec<_>>();
+        // the members in a cycle of inviters
+        for (pubkey, _) in &list {
+            if !visited.contains(pubkey) {
+                tree.push(InviteNode::build(pubkey, &members, &children, &mut visited));
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Remove the admitted pubkey and the codes minted by it,
+    /// with everyone invited by it in the cascade, returns the removed members
+    pub fn remove_member(&self, pubkey: &str, cascade: bool) -> nostr_relay::Result<Vec<String>> {
+        let mut pubkeys = vec![pubkey.to_owned()];
+        if cascade {
+            let members = self.db.members()?;
+            let mut seen = HashSet::from([pubkey.to_owned()]);
+            let mut queue = VecDeque::from([pubkey.to_owned()]);
+            while let Some(inviter) = queue.pop_front() {
+                for (p, member) in &members {
+                    if member.invited_by == inviter && seen.insert(p.clone()) {
+                        pubkeys.push(p.clone());
+                        queue.push_back(p.clone());
+                    }
+                }
+            }
+        }
+        let removed = self.db.del_members(&pubkeys)?;
+        let mut cache = self.members.write();
+        for p in &pubkeys {
+            cache.remove(p);
+        }
+        Ok(removed)
+    }
+}
+
+pub struct InviteOnly {
+    pub setting: InviteSetting,
+    invites: Arc<Invites>,
+}
+
+impl InviteOnly {
+    pub fn new(db: Arc<Db>) -> Self {
+        describe_counter!(
+            "nostr_relay_invite_redeemed_total",
+            "The total count of pubkeys admitted by the invite codes"
+        );
+        let invites = Invites::load(db.clone()).unwrap_or_else(|e| {
+            error!(error = e.to_string(), "failed to load the invite members");
+            Invites {
+                db,
+                members: RwLock::default(),
+            }
+        });
+        Self {
+            setting: InviteSetting::default(),
+            invites: Arc::new(invites),
+        }
+    }
+
+    pub fn invites(&self) -> Arc<Invites> {
+        Arc::clone(&self.invites)
+    }
+
+    /// admit the event of a member or with a valid invite code
+    fn admit(&self, event: &Event) -> Result<(), &'static str> {
+        let pubkey = event.pubkey_str();
+        if self.invites.is_member(&pubkey) {
+            return Ok(());
+        }
+        let code = event
+            .tags()
+            .iter()
+            .find(|t| t.len() > 1 && t[0] == self.setting.tag)
+            .map(|t| t[1].as_str())
+            .ok_or("restricted: an invite code is required")?;
+        // the code is used by the signer only
+        event
+            .verify_id()
+            .and_then(|_| event.verify_sign())
+            .map_err(|_| "invalid: signature is wrong")?;
+        match self.invites.redeem(code, &pubkey) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("restricted: the invite code is invalid or used up"),
+            Err(e) => {
+                error!(error = e.to_string(), "failed to redeem the invite code");
+                Err("error: failed to redeem the invite code")
+            }
+        }
+    }
+}
+
+impl Extension for InviteOnly {
+    fn name(&self) -> &'static str {
+        "invite"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        w.set_extension(self.setting.clone());
+    }
+
+    fn config_web(&mut self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::from(self.invites()))
+            .service(web::resource("/invite").route(web::post().to(route_redeem)))
+            .service(web::resource("/invite/new").route(web::post().to(route_mint)));
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if let IncomingMessage::Event(event) = &msg.msg {
+            if self.setting.enabled {
+                if let Err(err) = self.admit(event) {
+                    return OutgoingMessage::ok(&event.id_str(), false, err).into();
+                }
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RedeemRequest {
+    code: String,
+}
+
+/// admit the pubkey signing the request, the body is {"code": "<code>"}
+async fn route_redeem(
+    req: HttpRequest,
+    body: Bytes,
+    app: web::Data<App>,
+    invites: web::Data<Invites>,
+) -> HttpResponse {
+    let enabled = app
+        .setting
+        .read()
+        .get_extension::<InviteSetting>()
+        .is_some_and(|s| s.enabled);
+    if !enabled {
+        return HttpResponse::NotFound().finish();
+    }
+    let pubkey = match verify_auth(&req, &body) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return HttpResponse::Unauthorized().json(json!({ "error": e })),
+    };
+    let code = match serde_json::from_slice::<RedeemRequest>(&body) {
+        Ok(r) => r.code,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+    };
+    match invites.redeem(&code, &pubkey) {
+        Ok(true) => HttpResponse::Ok().json(json!({ "pubkey": pubkey })),
+        Ok(false) => HttpResponse::Forbidden()
+            .json(json!({ "error": "the invite code is invalid or used up" })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
+
+/// mint a single-use code for the member signing the request
+async fn route_mint(
+    req: HttpRequest,
+    body: Bytes,
+    app: web::Data<App>,
+    invites: web::Data<Invites>,
+) -> HttpResponse {
+    let setting = app.setting.read().get_extension::<InviteSetting>().cloned();
+    let Some(setting) = setting.filter(|s| s.enabled) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if setting.member_invites == 0 {
+        return HttpResponse::Forbidden()
+            .json(json!({ "error": "the members can not mint invite codes" }));
+    }
+    let pubkey = match verify_auth(&req, &body) {
+        Ok(pubkey) => pubkey,
+        Err(e) => return HttpResponse::Unauthorized().json(json!({ "error": e })),
+    };
+    if !invites.is_member(&pubkey) {
+        return HttpResponse::Unauthorized().json(json!({ "error": "not a member" }));
+    }
+    match invites.create_by_member(&pubkey, setting.member_invites) {
+        Ok(Some(code)) => HttpResponse::Ok().json(json!({ "code": code })),
+        Ok(None) => HttpResponse::Forbidden()
+            .json(json!({ "error": "the invite codes of the member are used up" })),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_test_app, nip98::auth_header};
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::db::secp256k1::KeyPair;
+
+    #[actix_rt::test]
+    async fn admission() -> Result<()> {
+        let app = create_test_app("invite")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({ "invite": { "enabled": true } }))?;
+        }
+        let extension = InviteOnly::new(app.db.clone());
+        let invites = extension.invites();
+        let app = app.add_extension(extension);
+        let codes = invites.create(
+            2,
+            &Invite {
+                max_uses: 1,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(codes.len(), 2);
+        assert_eq!(codes[0].len(), CODE_LENGTH);
+        assert_eq!(invites.list()?.len(), 2);
+
+        let mut srv = actix_test::start({
+            let app = app.clone();
+            move || app.clone().web_app()
+        });
+        // the message of the OK
+        async fn send(
+            framed: &mut actix_codec::Framed<
+                impl actix_codec::AsyncRead + actix_codec::AsyncWrite + Unpin,
+                awc::ws::Codec,
+            >,
+            event: Event,
+        ) -> String {
+            let msg = ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into());
+            framed.send(msg).await.unwrap();
+            let ws::Frame::Text(text) = framed.next().await.unwrap().unwrap() else {
+                panic!("invalid frame type");
+            };
+            let ok: (String, String, bool, String) = serde_json::from_slice(&text).unwrap();
+            ok.3
+        }
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        let alice = KeyPair::new_global(&mut thread_rng());
+        let bob = KeyPair::new_global(&mut thread_rng());
+        let event = |key_pair: &KeyPair, tags: Vec<Vec<String>>| {
+            Event::create(key_pair, now(), 1, tags, "".to_owned()).unwrap()
+        };
+        let tag = |code: &str| vec![vec!["invite".to_owned(), code.to_owned()]];
+        assert_eq!(
+            send(&mut framed, event(&alice, vec![])).await,
+            "restricted: an invite code is required"
+        );
+        assert_eq!(
+            send(&mut framed, event(&alice, tag("wrong"))).await,
+            "restricted: the invite code is invalid or used up"
+        );
+        assert_eq!(send(&mut framed, event(&alice, tag(&codes[0]))).await, "");
+        assert_eq!(send(&mut framed, event(&alice, vec![])).await, "");
+        assert_eq!(
+            send(&mut framed, event(&bob, tag(&codes[0]))).await,
+            "restricted: the invite code is invalid or used up"
+        );
+
+        // http
+        let url = srv.url("/invite");
+        let redeem = |key_pair: &KeyPair, code: &str| {
+            let body = json!({ "code": code }).to_string();
+            let auth = auth_header(key_pair, &url, "POST", body.as_bytes()).unwrap();
+            awc::Client::default()
+                .post(&url)
+                .insert_header(("Authorization", auth))
+                .send_body(body)
+        };
+        let res = awc::Client::default()
+            .post(&url)
+            .send_body(json!({ "code": codes[1] }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 401);
+        assert_eq!(redeem(&bob, "wrong").await.unwrap().status(), 403);
+        let mut res = redeem(&bob, &codes[1]).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bob_pubkey = bob.x_only_public_key().0.to_string();
+        assert_eq!(
+            res.json::<serde_json::Value>().await?,
+            json!({ "pubkey": bob_pubkey })
+        );
+        assert_eq!(send(&mut framed, event(&bob, vec![])).await, "");
+
+        assert_eq!(invites.members()?.len(), 2);
+        assert_eq!(
+            invites.remove_member(&bob_pubkey, false)?,
+            vec![bob_pubkey.clone()]
+        );
+        assert_eq!(
+            send(&mut framed, event(&bob, vec![])).await,
+            "restricted: an invite code is required"
+        );
+        assert!(invites.revoke(&codes[1])?);
+        assert!(!invites.revoke(&codes[1])?);
+
+        // reloaded from the db
+        let invites = Invites::load(app.db.clone())?;
+        assert!(invites.is_member(&alice.x_only_public_key().0.to_string()));
+        assert!(!invites.is_member(&bob_pubkey));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn tree() -> Result<()> {
+        let app = create_test_app("invite_tree")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(
+                json!({ "invite": { "enabled": true, "member_invites": 1 } }),
+            )?;
+        }
+        let extension = InviteOnly::new(app.db.clone());
+        let invites = extension.invites();
+        let app = app.add_extension(extension);
+        let srv = actix_test::start({
+            let app = app.clone();
+            move || app.clone().web_app()
+        });
+        let url = srv.url("/invite/new");
+        let mint = |key_pair: &KeyPair| {
+            let auth = auth_header(key_pair, &url, "POST", b"").unwrap();
+            awc::Client::default()
+                .post(&url)
+                .insert_header(("Authorization", auth))
+                .send()
+        };
+        let keys = (0..4)
+            .map(|_| KeyPair::new_global(&mut thread_rng()))
+            .collect::<Vec<_>>();
+        let pubkeys = keys
+            .iter()
+            .map(|k| k.x_only_public_key().0.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(mint(&keys[0]).await.unwrap().status(), 401);
+
+        // 0 -> 1 -> 2, 3 by the operator
+        let code = invites.create(1, &Invite::default())?.remove(0);
+        assert!(invites.redeem(&code, &pubkeys[0])?);
+        assert!(invites.redeem(&code, &pubkeys[3])?);
+        for i in 0..2 {
+            let mut res = mint(&keys[i]).await.unwrap();
+            assert_eq!(res.status(), 200);
+            let body = res.json::<serde_json::Value>().await?;
+            assert!(invites.redeem(body["code"].as_str().unwrap(), &pubkeys[i + 1])?);
+            // over the limit
+            assert_eq!(mint(&keys[i]).await.unwrap().status(), 403);
+        }
+
+        let tree = invites.tree()?;
+        assert_eq!(tree.len(), 2);
+        let root = tree.iter().find(|n| n.pubkey == pubkeys[0]).unwrap();
+        assert_eq!(root.invited.len(), 1);
+        assert_eq!(root.invited[0].pubkey, pubkeys[1]);
+        assert_eq!(root.invited[0].invited[0].pubkey, pubkeys[2]);
+        assert!(root.invited[0].invited[0].invited.is_empty());
+
+        let mut removed = invites.remove_member(&pubkeys[0], true)?;
+        removed.sort();
+        let mut expected = pubkeys[0..3].to_vec();
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(!invites.is_member(&pubkeys[2]));
+        assert!(invites.is_member(&pubkeys[3]));
+        assert_eq!(invites.tree()?.len(), 1);
+        // the codes minted by the removed members are removed
+        assert_eq!(invites.list()?.len(), 1);
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 pub mod auth;
 pub use auth::Auth;
 
+#[cfg(feature = "nip98")]
+pub mod nip98;
+
 #[cfg(feature = "metrics")]
 pub mod metrics;
 #[cfg(feature = "metrics")]
@@ -21,6 +24,134 @@ pub mod search;
 #[cfg(feature = "search")]
 pub use search::Search;
 
+#[cfg(feature = "management")]
+pub mod management;
+#[cfg(feature = "management")]
+pub use management::Management;
+
+#[cfg(feature = "proxy")]
+mod client;
+
+#[cfg(feature = "broadcast")]
+pub mod broadcast;
+#[cfg(feature = "broadcast")]
+pub use broadcast::Broadcast;
+
+#[cfg(feature = "mirror")]
+pub mod mirror;
+#[cfg(feature = "mirror")]
+pub use mirror::Mirror;
+
+#[cfg(feature = "cluster")]
+pub mod cluster;
+#[cfg(feature = "cluster")]
+pub use cluster::Cluster;
+
+#[cfg(feature = "replication")]
+pub mod replication;
+#[cfg(feature = "replication")]
+pub use replication::Replication;
+
+#[cfg(feature = "negentropy")]
+pub mod negentropy;
+#[cfg(feature = "negentropy")]
+pub use negentropy::Negentropy;
+
+#[cfg(feature = "groups")]
+pub mod groups;
+#[cfg(feature = "groups")]
+pub use groups::Groups;
+
+#[cfg(feature = "blossom")]
+pub mod blossom;
+#[cfg(feature = "blossom")]
+pub use blossom::Blossom;
+
+#[cfg(feature = "audit")]
+pub mod audit;
+#[cfg(feature = "audit")]
+pub use audit::Audit;
+
+#[cfg(feature = "vanish")]
+pub mod vanish;
+#[cfg(feature = "vanish")]
+pub use vanish::Vanish;
+
+#[cfg(feature = "reputation")]
+pub mod reputation;
+#[cfg(feature = "reputation")]
+pub use reputation::ReputationScores;
+
+#[cfg(feature = "pow")]
+pub mod pow;
+#[cfg(feature = "pow")]
+pub use pow::Pow;
+
+#[cfg(feature = "resume")]
+pub mod resume;
+#[cfg(feature = "resume")]
+pub use resume::ResumeTokens;
+
+#[cfg(feature = "exclude")]
+pub mod exclude;
+#[cfg(feature = "exclude")]
+pub use exclude::Excluder;
+
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+#[cfg(feature = "scheduler")]
+pub use scheduler::Scheduler;
+
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
+#[cfg(feature = "webhooks")]
+pub use webhooks::Webhooks;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub use mqtt::Mqtt;
+
+#[cfg(any(feature = "firehose-nats", feature = "firehose-kafka"))]
+pub mod firehose;
+#[cfg(any(feature = "firehose-nats", feature = "firehose-kafka"))]
+pub use firehose::Firehose;
+
+#[cfg(feature = "clickhouse")]
+pub mod clickhouse;
+#[cfg(feature = "clickhouse")]
+pub use clickhouse::Clickhouse;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "grpc")]
+pub use grpc::Grpc;
+
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "graphql")]
+pub use graphql::Graphql;
+
+#[cfg(feature = "invite")]
+pub mod invite;
+#[cfg(feature = "invite")]
+pub use invite::InviteOnly;
+
+#[cfg(feature = "onboarding")]
+pub mod onboarding;
+#[cfg(feature = "onboarding")]
+pub use onboarding::Onboarding;
+
+#[cfg(feature = "maintenance")]
+pub mod maintenance;
+#[cfg(feature = "maintenance")]
+pub use maintenance::Maintenance;
+
+#[cfg(feature = "validation")]
+pub mod validation;
+#[cfg(feature = "validation")]
+pub use validation::Validator;
+
 #[cfg(test)]
 pub fn temp_data_path(p: &str) -> anyhow::Result<tempfile::TempDir> {
     Ok(tempfile::Builder::new()
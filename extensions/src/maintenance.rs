@@ -0,0 +1,243 @@
+//! Read-only mode for the migrations, backups and disk emergencies, the EVENT messages and the published events
+//! are rejected or silently accepted and dropped while the REQs are served.
+//! The mode is set by the config file or at runtime with the management api.
+use actix_web::web;
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::Event,
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+use tracing::info;
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MaintenanceSetting {
+    pub enabled: bool,
+    /// answer the events with OK true and drop them instead of rejecting them
+    pub drop: bool,
+    /// the message of the rejected events
+    pub message: String,
+}
+
+impl Default for MaintenanceSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            drop: false,
+            message: "error: relay is in maintenance".to_owned(),
+        }
+    }
+}
+
+impl MaintenanceSetting {
+    pub fn mode(&self) -> Mode {
+        match (self.enabled, self.drop) {
+            (false, _) => Mode::Off,
+            (true, false) => Mode::Reject,
+            (true, true) => Mode::Drop,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Off,
+    Reject,
+    Drop,
+}
+
+/// The current mode, shared with the management api
+#[derive(Debug)]
+pub struct MaintenanceState(AtomicU8);
+
+impl Default for MaintenanceState {
+    fn default() -> Self {
+        Self(AtomicU8::new(Mode::Off as u8))
+    }
+}
+
+impl MaintenanceState {
+    pub fn mode(&self) -> Mode {
+        match self.0.load(Ordering::Relaxed) {
+            1 => Mode::Reject,
+            2 => Mode::Drop,
+            _ => Mode::Off,
+        }
+    }
+
+    pub fn set(&self, mode: Mode) {
+        if self.0.swap(mode as u8, Ordering::Relaxed) != mode as u8 {
+            info!(mode = ?mode, "maintenance mode changed");
+        }
+    }
+}
+
+pub struct Maintenance {
+    pub setting: MaintenanceSetting,
+    state: Arc<MaintenanceState>,
+    /// the mode of the config file last applied, a reload keeps the runtime mode unless the file changes it
+    applied: Option<Mode>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_maintenance_events_total",
+            "The total count of events rejected or dropped in the maintenance mode"
+        );
+        Self {
+            setting: MaintenanceSetting::default(),
+            state: Default::default(),
+            applied: None,
+        }
+    }
+
+    pub fn state(&self) -> Arc<MaintenanceState> {
+        Arc::clone(&self.state)
+    }
+}
+
+impl Extension for Maintenance {
+    fn name(&self) -> &'static str {
+        "maintenance"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        w.set_extension(self.setting.clone());
+        let mode = self.setting.mode();
+        if self.applied != Some(mode) {
+            self.state.set(mode);
+            self.applied = Some(mode);
+        }
+    }
+
+    fn config_web(&mut self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::from(self.state()));
+    }
+
+    fn publish(&self, _event: &Event) -> Result<(), String> {
+        match self.state.mode() {
+            Mode::Off => Ok(()),
+            // the publisher has no answer to drop
+            _ => {
+                increment_counter!("nostr_relay_maintenance_events_total", "mode" => "reject");
+                Err(self.setting.message.clone())
+            }
+        }
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if let IncomingMessage::Event(event) = &msg.msg {
+            match self.state.mode() {
+                Mode::Off => {}
+                Mode::Reject => {
+                    increment_counter!("nostr_relay_maintenance_events_total", "mode" => "reject");
+                    return OutgoingMessage::ok(&event.id_str(), false, &self.setting.message)
+                        .into();
+                }
+                Mode::Drop => {
+                    increment_counter!("nostr_relay_maintenance_events_total", "mode" => "drop");
+                    return OutgoingMessage::ok(&event.id_str(), true, "").into();
+                }
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use serde_json::{json, Value};
+
+    async fn send(
+        framed: &mut actix_codec::Framed<
+            impl actix_codec::AsyncRead + actix_codec::AsyncWrite + Unpin,
+            awc::ws::Codec,
+        >,
+        text: String,
+    ) -> Value {
+        framed.send(ws::Message::Text(text.into())).await.unwrap();
+        let ws::Frame::Text(text) = framed.next().await.unwrap().unwrap() else {
+            panic!("invalid frame type");
+        };
+        serde_json::from_slice(&text).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn mode() -> Result<()> {
+        let app = create_test_app("maintenance")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({ "maintenance": { "enabled": true } }))?;
+        }
+        let extension = Maintenance::new();
+        let state = extension.state();
+        let app = app.add_extension(extension);
+        assert_eq!(state.mode(), Mode::Reject);
+        let mut srv = actix_test::start({
+            let app = app.clone();
+            move || app.clone().web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let note = |content: &str| {
+            let event = Event::create(&key_pair, now(), 1, vec![], content.to_owned()).unwrap();
+            format!(r#"["EVENT", {}]"#, event)
+        };
+        assert_eq!(
+            send(&mut framed, note("a")).await[3],
+            json!("error: relay is in maintenance")
+        );
+        // the reading is served
+        let eose = send(&mut framed, r#"["REQ", "1", {}]"#.to_owned()).await;
+        assert_eq!(eose[0], json!("EOSE"));
+
+        state.set(Mode::Drop);
+        assert_eq!(send(&mut framed, note("b")).await[2], json!(true));
+        state.set(Mode::Off);
+        assert_eq!(send(&mut framed, note("c")).await[2], json!(true));
+        let events = send(&mut framed, r#"["REQ", "2", {}]"#.to_owned()).await;
+        assert_eq!(events[2]["content"], json!("c"));
+
+        // a reload keeps the runtime mode unless the file changes it
+        app.extensions.write().call_setting(&app.setting);
+        assert_eq!(state.mode(), Mode::Off);
+        app.setting.write().extra.clear();
+        app.extensions.write().call_setting(&app.setting);
+        assert_eq!(state.mode(), Mode::Off);
+        state.set(Mode::Reject);
+        app.extensions.write().call_setting(&app.setting);
+        assert_eq!(state.mode(), Mode::Reject);
+        Ok(())
+    }
+}
@@ -0,0 +1,951 @@
+use crate::auth::AuthState;
+use crate::nip98::verify_admin;
+pub use crate::nip98::{verify_auth, HTTP_AUTH_KIND};
+use actix_web::{
+    guard,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use nostr_relay::db::{now, Ban, Db, Event, Tombstone};
+use nostr_relay::{
+    message::{
+        ClientMessage, Control, ControlAction, ControlSessions, IncomingMessage, OutgoingMessage,
+        Reload, SessionFilter,
+    },
+    privacy::is_ip_hash,
+    setting::SettingWrapper,
+    App, Extension, ExtensionMessageResult, List, Session,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc};
+use tracing::error;
+
+/// NIP-86 content type
+pub const CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+const METHODS: &[&str] = &[
+    "supportedmethods",
+    "banpubkey",
+    "unbanpubkey",
+    "listbannedpubkeys",
+    "banevent",
+    "allowevent",
+    "listbannedevents",
+    "takedownevent",
+    "listtombstones",
+    "blockip",
+    "unblockip",
+    "listblockedips",
+    "notice",
+    "kicksession",
+    "listsessions",
+    "listextensions",
+    "enableextension",
+    "disableextension",
+    #[cfg(feature = "vanish")]
+    "listvanished",
+    #[cfg(feature = "reputation")]
+    "listreputations",
+    #[cfg(feature = "scheduler")]
+    "listjobs",
+    #[cfg(feature = "scheduler")]
+    "runjob",
+    #[cfg(feature = "invite")]
+    "createinvites",
+    #[cfg(feature = "invite")]
+    "listinvites",
+    #[cfg(feature = "invite")]
+    "revokeinvite",
+    #[cfg(feature = "invite")]
+    "listmembers",
+    #[cfg(feature = "invite")]
+    "removemember",
+    #[cfg(feature = "invite")]
+    "invitetree",
+    #[cfg(feature = "maintenance")]
+    "maintenance",
+];
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct ManagementSetting {
+    pub enabled: bool,
+    /// pubkeys allowed to call the management api with NIP-98 signed requests
+    pub admin_pubkeys: Option<List>,
+}
+
+/// Banned pubkeys, ips and event ids, saved in the db
+#[derive(Default, Debug)]
+pub struct Bans {
+    pub pubkeys: HashMap<String, Ban>,
+    pub ips: HashMap<String, Ban>,
+    pub events: HashMap<String, Ban>,
+}
+
+impl Bans {
+    /// Load the bans of the db, the expired bans are removed
+    pub fn load(db: &Db) -> nostr_relay::Result<Self> {
+        let mut bans = Self::default();
+        let time = now();
+        for (kind, value, ban) in db.bans()? {
+            if ban.expired(time) {
+                db.del_ban(&kind, &value)?;
+            } else if let Some(map) = bans.map(&kind) {
+                map.insert(value, ban);
+            }
+        }
+        Ok(bans)
+    }
+
+    fn map(&mut self, kind: &str) -> Option<&mut HashMap<String, Ban>> {
+        match kind {
+            "pubkey" => Some(&mut self.pubkeys),
+            "ip" => Some(&mut self.ips),
+            "event" => Some(&mut self.events),
+            _ => None,
+        }
+    }
+
+    /// Save the ban of the value of the kind: "pubkey", "ip" or "event"
+    pub fn add(&mut self, db: &Db, kind: &str, value: &str, ban: Ban) -> nostr_relay::Result<()> {
+        db.put_ban(kind, value, &ban)?;
+        if let Some(map) = self.map(kind) {
+            map.insert(value.to_owned(), ban);
+        }
+        Ok(())
+    }
+
+    /// Remove the ban, returns false if the value is not banned
+    pub fn remove(&mut self, db: &Db, kind: &str, value: &str) -> nostr_relay::Result<bool> {
+        let removed = self.map(kind).and_then(|m| m.remove(value)).is_some();
+        Ok(db.del_ban(kind, value)? || removed)
+    }
+
+    /// The bans not expired of the kind, sorted by the value
+    pub fn list(&self, kind: &str) -> Vec<(&String, &Ban)> {
+        let map = match kind {
+            "pubkey" => &self.pubkeys,
+            "ip" => &self.ips,
+            _ => &self.events,
+        };
+        let time = now();
+        let mut list = map
+            .iter()
+            .filter(|(_, b)| !b.expired(time))
+            .collect::<Vec<_>>();
+        list.sort_by_key(|(v, _)| *v);
+        list
+    }
+}
+
+fn banned(map: &HashMap<String, Ban>, value: &str) -> bool {
+    map.get(value).is_some_and(|b| !b.expired(now()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    fn result(result: Value) -> Self {
+        Self {
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error<S: Into<String>>(error: S) -> Self {
+        Self {
+            result: None,
+            error: Some(error.into()),
+        }
+    }
+}
+
+/// [NIP-86](https://nips.be/86) relay management api, bans are saved in the db
+pub struct Management {
+    setting: ManagementSetting,
+    bans: Arc<RwLock<Bans>>,
+}
+
+impl Management {
+    pub fn new(db: Arc<Db>) -> Self {
+        let bans = Bans::load(&db).unwrap_or_else(|e| {
+            error!(error = e.to_string(), "failed to load the bans");
+            Bans::default()
+        });
+        Self {
+            setting: ManagementSetting::default(),
+            bans: Arc::new(RwLock::new(bans)),
+        }
+    }
+
+    pub fn bans(&self) -> Arc<RwLock<Bans>> {
+        Arc::clone(&self.bans)
+    }
+
+    /// reject the events of banned pubkeys and the banned events
+    fn check(&self, event: &Event) -> Result<(), &'static str> {
+        if self.setting.enabled {
+            let bans = self.bans.read();
+            if banned(&bans.pubkeys, &event.pubkey_str()) {
+                return Err("blocked: pubkey is banned");
+            }
+            if banned(&bans.events, &event.id_str()) {
+                return Err("blocked: event is banned");
+            }
+        }
+        Ok(())
+    }
+
+    /// reject the messages of the banned ips and authenticated pubkeys
+    fn check_session(&self, session: &Session) -> Result<(), &'static str> {
+        if self.setting.enabled {
+            let bans = self.bans.read();
+            if banned(&bans.ips, session.ip()) {
+                return Err("blocked: ip is banned");
+            }
+            let pubkey = session.get::<AuthState>().and_then(|s| s.pubkey());
+            if pubkey.is_some_and(|p| banned(&bans.pubkeys, p)) {
+                return Err("blocked: pubkey is banned");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Extension for Management {
+    fn name(&self) -> &'static str {
+        "management"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        if self.setting.enabled {
+            w.add_nip(86);
+        }
+        w.set_extension(self.setting.clone());
+    }
+
+    fn config_web(&mut self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::from(self.bans())).service(
+            web::resource("/")
+                .guard(guard::Header("content-type", CONTENT_TYPE))
+                .route(web::post().to(route_management)),
+        );
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        let res = self.check_session(session);
+        if let IncomingMessage::Event(event) = &msg.msg {
+            if let Err(err) = res.and_then(|_| self.check(event)) {
+                return OutgoingMessage::ok(&event.id_str(), false, err).into();
+            }
+        } else if let Err(err) = res {
+            return OutgoingMessage::notice(err).into();
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+
+    fn publish(&self, event: &Event) -> Result<(), String> {
+        self.check(event).map_err(ToOwned::to_owned)
+    }
+}
+
+fn valid_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+async fn route_management(
+    http: HttpRequest,
+    body: Bytes,
+    app: web::Data<App>,
+    bans: web::Data<RwLock<Bans>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    {
+        let setting = app.setting.read();
+        let Some(s) = setting
+            .get_extension::<ManagementSetting>()
+            .filter(|s| s.enabled)
+        else {
+            return Ok(HttpResponse::NotFound().finish());
+        };
+        if let Err(e) = verify_admin(&http, &body, s.admin_pubkeys.as_ref()) {
+            return Ok(HttpResponse::Unauthorized().json(Response::error(e)));
+        }
+    }
+
+    let req: Request = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(Response::error(e.to_string()))),
+    };
+    Ok(HttpResponse::Ok().json(call(req, &http, &app, &bans).await))
+}
+
+#[cfg_attr(
+    not(any(
+        feature = "vanish",
+        feature = "reputation",
+        feature = "scheduler",
+        feature = "invite",
+        feature = "maintenance"
+    )),
+    allow(unused_variables)
+)]
+async fn call(req: Request, http: &HttpRequest, app: &App, bans: &RwLock<Bans>) -> Response {
+    let param = |i: usize| req.params.get(i).and_then(Value::as_str);
+    // the optional expiry is an extension of NIP-86
+    let ban = Ban {
+        reason: param(1).unwrap_or_default().to_owned(),
+        expires_at: req
+            .params
+            .get(2)
+            .and_then(Value::as_u64)
+            .unwrap_or_default(),
+    };
+    let db = &app.db;
+    let add = |kind: &str, value: &str| match bans.write().add(db, kind, value, ban.clone()) {
+        Ok(()) => Response::result(json!(true)),
+        Err(e) => Response::error(e.to_string()),
+    };
+    let remove = |kind: &str, value: &str| match bans.write().remove(db, kind, value) {
+        Ok(removed) => Response::result(json!(removed)),
+        Err(e) => Response::error(e.to_string()),
+    };
+    let list = |kind: &str, key: &str| {
+        Response::result(json!(bans
+            .read()
+            .list(kind)
+            .into_iter()
+            .map(|(value, ban)| {
+                let mut item = json!({ key: value, "reason": ban.reason });
+                if ban.expires_at > 0 {
+                    item["expires_at"] = json!(ban.expires_at);
+                }
+                item
+            })
+            .collect::<Vec<_>>()))
+    };
+    match req.method.as_str() {
+        "supportedmethods" => Response::result(json!(METHODS)),
+        "banpubkey" => match param(0).filter(|p| valid_hex(p)) {
+            Some(pubkey) => add("pubkey", pubkey),
+            None => Response::error("invalid pubkey"),
+        },
+        "unbanpubkey" => match param(0) {
+            Some(pubkey) => remove("pubkey", pubkey),
+            None => Response::error("missing pubkey"),
+        },
+        "listbannedpubkeys" => list("pubkey", "pubkey"),
+        "banevent" => match param(0).filter(|p| valid_hex(p)) {
+            Some(id) => {
+                // checked by valid_hex
+                let key = hex::decode(id).unwrap();
+                if let Err(e) = app.db.batch_del([&key]) {
+                    return Response::error(e.to_string());
+                }
+                add("event", id)
+            }
+            None => Response::error("invalid event id"),
+        },
+        "allowevent" => match param(0) {
+            Some(id) => remove("event", id),
+            None => Response::error("missing event id"),
+        },
+        "listbannedevents" => list("event", "id"),
+        // removed for good, the tombstone rejects the event from any source, ie: the mirrors and the imports
+        "takedownevent" => match param(0).filter(|p| valid_hex(p)) {
+            Some(id) => {
+                let tombstone = Tombstone {
+                    reason: param(1).unwrap_or_default().to_owned(),
+                    created_at: now(),
+                };
+                // checked by valid_hex
+                let key = hex::decode(id).unwrap();
+                match app.db.takedown(&key, &tombstone) {
+                    Ok(removed) => Response::result(json!(removed)),
+                    Err(e) => Response::error(e.to_string()),
+                }
+            }
+            None => Response::error("invalid event id"),
+        },
+        "listtombstones" => match app.db.tombstones() {
+            Ok(list) => Response::result(json!(list
+                .into_iter()
+                .map(|(id, t)| json!({"id": id, "reason": t.reason, "created_at": t.created_at}))
+                .collect::<Vec<_>>())),
+            Err(e) => Response::error(e.to_string()),
+        },
+        // the ips or the ip hashes of the privacy mode
+        "blockip" => {
+            match param(0).filter(|p| p.parse::<std::net::IpAddr>().is_ok() || is_ip_hash(p)) {
+                Some(ip) => add("ip", &app.setting.read().network.client_ip(ip)),
+                None => Response::error("invalid ip"),
+            }
+        }
+        "unblockip" => match param(0) {
+            Some(ip) => remove("ip", &app.setting.read().network.client_ip(ip)),
+            None => Response::error("missing ip"),
+        },
+        "listblockedips" => list("ip", "ip"),
+        "notice" => {
+            let Some(message) = param(0) else {
+                return Response::error("missing message");
+            };
+            let authed = match param(1) {
+                None | Some("all") => None,
+                Some("authed") => Some(true),
+                Some("unauthed") => Some(false),
+                Some(p) => return Response::error(format!("invalid sessions {}", p)),
+            };
+            let control = Control {
+                filter: SessionFilter {
+                    authed,
+                    ..Default::default()
+                },
+                action: ControlAction::Notice(message.to_owned()),
+            };
+            match app.server.send(ControlSessions(control)).await {
+                Ok(sessions) => Response::result(json!(sessions.len())),
+                Err(e) => Response::error(e.to_string()),
+            }
+        }
+        "listsessions" => {
+            let filter = match param(0) {
+                None | Some("all") => Some(SessionFilter::default()),
+                Some("authed") => Some(SessionFilter {
+                    authed: Some(true),
+                    ..Default::default()
+                }),
+                Some("unauthed") => Some(SessionFilter {
+                    authed: Some(false),
+                    ..Default::default()
+                }),
+                Some(p) => SessionFilter::parse(p),
+            };
+            let Some(mut filter) = filter else {
+                return Response::error("invalid session id, ip or pubkey");
+            };
+            filter.ip = filter
+                .ip
+                .map(|ip| app.setting.read().network.client_ip(&ip));
+            let control = Control {
+                filter,
+                action: ControlAction::List,
+            };
+            match app.server.send(ControlSessions(control)).await {
+                Ok(sessions) => Response::result(json!(sessions)),
+                Err(e) => Response::error(e.to_string()),
+            }
+        }
+        "kicksession" => {
+            let Some(mut filter) = param(0).and_then(SessionFilter::parse) else {
+                return Response::error("invalid session id, ip or pubkey");
+            };
+            filter.ip = filter
+                .ip
+                .map(|ip| app.setting.read().network.client_ip(&ip));
+            let control = Control {
+                filter,
+                action: ControlAction::Kick(param(1).map(ToOwned::to_owned)),
+            };
+            match app.server.send(ControlSessions(control)).await {
+                Ok(sessions) => Response::result(json!(sessions.len())),
+                Err(e) => Response::error(e.to_string()),
+            }
+        }
+        "listextensions" => {
+            let extensions = app.extensions.read();
+            Response::result(json!(extensions
+                .names()
+                .into_iter()
+                .map(|name| json!({"name": name, "enabled": extensions.enabled(name)}))
+                .collect::<Vec<_>>()))
+        }
+        "enableextension" | "disableextension" => match param(0) {
+            Some(name) => {
+                let enabled = req.method == "enableextension";
+                if !app.extensions.write().set_enabled(name, enabled) {
+                    return Response::error(format!("unknown extension {}", name));
+                }
+                // re-evaluate the connected sessions, ie: send the auth challenge
+                app.server.do_send(Reload);
+                Response::result(json!(true))
+            }
+            None => Response::error("missing extension name"),
+        },
+        // get or set the maintenance mode, params: [mode], the mode is "off", "reject" or "drop"
+        #[cfg(feature = "maintenance")]
+        "maintenance" => match http.app_data::<web::Data<crate::maintenance::MaintenanceState>>() {
+            Some(state) => match req.params.first().filter(|v| !v.is_null()) {
+                Some(mode) => match serde_json::from_value(mode.clone()) {
+                    Ok(mode) => {
+                        state.set(mode);
+                        Response::result(json!(mode))
+                    }
+                    Err(_) => Response::error(format!("invalid mode {}", mode)),
+                },
+                None => Response::result(json!(state.mode())),
+            },
+            None => Response::error("maintenance is not available"),
+        },
+        // the deletion progress of the NIP-62 requests to vanish
+        #[cfg(feature = "vanish")]
+        "listvanished" => match http.app_data::<web::Data<crate::vanish::VanishJobs>>() {
+            Some(jobs) => Response::result(json!(jobs
+                .read()
+                .iter()
+                .map(|(pubkey, job)| json!({
+                    "pubkey": pubkey,
+                    "until": job.until,
+                    "started_at": job.started_at,
+                    "deleted": job.deleted,
+                    "done": job.done,
+                    "error": job.error,
+                }))
+                .collect::<Vec<_>>())),
+            None => Response::error("vanish is not available"),
+        },
+        // the decayed scores of the ips and pubkeys, params[0] is the optional minimum score
+        #[cfg(feature = "reputation")]
+        "listreputations" => {
+            match http.app_data::<web::Data<RwLock<crate::reputation::Scores>>>() {
+                Some(scores) => {
+                    let min = req.params.first().and_then(Value::as_f64).unwrap_or(0.0);
+                    let time = now();
+                    Response::result(json!(scores
+                        .read()
+                        .list(time)
+                        .into_iter()
+                        .filter(|(_, score, _)| *score >= min)
+                        .map(|(key, score, r)| json!({
+                            "key": key,
+                            "score": score,
+                            "updated_at": r.updated_at,
+                            "banned_until": r.banned_until,
+                        }))
+                        .collect::<Vec<_>>()))
+                }
+                None => Response::error("reputation is not available"),
+            }
+        }
+        #[cfg(feature = "scheduler")]
+        "listjobs" => match http.app_data::<web::Data<crate::scheduler::Jobs>>() {
+            Some(jobs) => Response::result(json!(jobs.list())),
+            None => Response::error("scheduler is not available"),
+        },
+        // run the job now with the parameters of the setting, params[0] is the name
+        #[cfg(feature = "scheduler")]
+        "runjob" => match (
+            http.app_data::<web::Data<crate::scheduler::Jobs>>(),
+            req.params.first().and_then(Value::as_str),
+        ) {
+            (Some(jobs), Some(name)) => {
+                let params = app
+                    .setting
+                    .read()
+                    .get_extension::<crate::scheduler::SchedulerSetting>()
+                    .map(|s| s.params(name))
+                    .unwrap_or_default();
+                match jobs.run(name, app, params) {
+                    Ok(()) => Response::result(json!(true)),
+                    Err(e) => Response::error(e),
+                }
+            }
+            (None, _) => Response::error("scheduler is not available"),
+            (_, None) => Response::error("missing job name"),
+        },
+        // mint the codes, params: [count, note, expires_at, max_uses, created_by], the max uses is 1 by default
+        #[cfg(feature = "invite")]
+        "createinvites" => match http.app_data::<web::Data<crate::invite::Invites>>() {
+            Some(invites) => {
+                let count = req.params.first().and_then(Value::as_u64).unwrap_or(1);
+                let invite = nostr_relay::db::Invite {
+                    note: param(1).unwrap_or_default().to_owned(),
+                    created_at: now(),
+                    expires_at: req.params.get(2).and_then(Value::as_u64).unwrap_or(0),
+                    max_uses: req.params.get(3).and_then(Value::as_u64).unwrap_or(1) as u32,
+                    uses: 0,
+                    created_by: param(4).unwrap_or_default().to_owned(),
+                };
+                match invites.create(count.min(1000) as usize, &invite) {
+                    Ok(codes) => Response::result(json!(codes)),
+                    Err(e) => Response::error(e.to_string()),
+                }
+            }
+            None => Response::error("invite is not available"),
+        },
+        #[cfg(feature = "invite")]
+        "listinvites" => match http.app_data::<web::Data<crate::invite::Invites>>() {
+            Some(invites) => match invites.list() {
+                Ok(list) => Response::result(json!(list
+                    .into_iter()
+                    .map(|(code, invite)| json!({
+                        "code": code,
+                        "note": invite.note,
+                        "created_at": invite.created_at,
+                        "expires_at": invite.expires_at,
+                        "max_uses": invite.max_uses,
+                        "uses": invite.uses,
+                        "created_by": invite.created_by,
+                    }))
+                    .collect::<Vec<_>>())),
+                Err(e) => Response::error(e.to_string()),
+            },
+            None => Response::error("invite is not available"),
+        },
+        #[cfg(feature = "invite")]
+        "revokeinvite" => match (
+            http.app_data::<web::Data<crate::invite::Invites>>(),
+            param(0),
+        ) {
+            (Some(invites), Some(code)) => match invites.revoke(code) {
+                Ok(removed) => Response::result(json!(removed)),
+                Err(e) => Response::error(e.to_string()),
+            },
+            (None, _) => Response::error("invite is not available"),
+            (_, None) => Response::error("missing parameter"),
+        },
+        // remove the member, params: [pubkey, cascade], returns the removed pubkeys
+        #[cfg(feature = "invite")]
+        "removemember" => match (
+            http.app_data::<web::Data<crate::invite::Invites>>(),
+            param(0),
+        ) {
+            (Some(invites), Some(pubkey)) => {
+                let cascade = req.params.get(1).and_then(Value::as_bool).unwrap_or(false);
+                match invites.remove_member(pubkey, cascade) {
+                    Ok(removed) => Response::result(json!(removed)),
+                    Err(e) => Response::error(e.to_string()),
+                }
+            }
+            (None, _) => Response::error("invite is not available"),
+            (_, None) => Response::error("missing parameter"),
+        },
+        #[cfg(feature = "invite")]
+        "invitetree" => match http.app_data::<web::Data<crate::invite::Invites>>() {
+            Some(invites) => match invites.tree() {
+                Ok(tree) => Response::result(json!(tree)),
+                Err(e) => Response::error(e.to_string()),
+            },
+            None => Response::error("invite is not available"),
+        },
+        #[cfg(feature = "invite")]
+        "listmembers" => match http.app_data::<web::Data<crate::invite::Invites>>() {
+            Some(invites) => match invites.members() {
+                Ok(list) => Response::result(json!(list
+                    .into_iter()
+                    .map(|(pubkey, member)| json!({
+                        "pubkey": pubkey,
+                        "code": member.code,
+                        "admitted_at": member.admitted_at,
+                        "invited_by": member.invited_by,
+                    }))
+                    .collect::<Vec<_>>())),
+                Err(e) => Response::error(e.to_string()),
+            },
+            None => Response::error("invite is not available"),
+        },
+        _ => Response::error(format!("unsupported method {}", req.method)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_test_app, nip98::auth_header};
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::create_web_app;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+        CheckEventResult, Filter,
+    };
+    use std::time::Duration;
+
+    async fn call(
+        srv: &actix_test::TestServer,
+        key_pair: &KeyPair,
+        body: Value,
+    ) -> Result<(u16, Value)> {
+        let body = body.to_string();
+        let auth = auth_header(key_pair, &srv.url("/"), "POST", body.as_bytes())?;
+        let mut res = srv
+            .post("/")
+            .insert_header(("Content-Type", CONTENT_TYPE))
+            .insert_header(("Authorization", auth))
+            .send_body(body)
+            .await
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        Ok((res.status().as_u16(), res.json().await?))
+    }
+
+    #[actix_rt::test]
+    async fn management() -> Result<()> {
+        let mut rng = thread_rng();
+        let admin = KeyPair::new_global(&mut rng);
+        let user = KeyPair::new_global(&mut rng);
+        let admin_pubkey = admin.x_only_public_key().0.to_string();
+        let user_pubkey = user.x_only_public_key().0.to_string();
+
+        let app = create_test_app("management")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(&format!(
+                r#"{{
+                "management": {{
+                    "enabled": true,
+                    "admin_pubkeys": ["{}"]
+                }}
+            }}"#,
+                admin_pubkey
+            ))?;
+        }
+        let event = Event::create(&user, now(), 1, vec![], "test".to_owned())?;
+        app.db.batch_put([&event])?;
+        let db = app.db.clone();
+        let app = web::Data::new(app.add_extension(Management::new(db.clone())));
+        let c_app = app.clone();
+        let mut srv = actix_test::start(move || create_web_app(c_app.clone()));
+
+        // not admin
+        let (status, _) = call(&srv, &user, json!({"method": "supportedmethods"})).await?;
+        assert_eq!(status, 401);
+
+        let body = json!({"method": "banpubkey", "params": [user_pubkey, "spam"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+
+        let (_, res) = call(&srv, &admin, json!({"method": "listbannedpubkeys"})).await?;
+        assert_eq!(
+            res["result"],
+            json!([{"pubkey": user_pubkey, "reason": "spam"}])
+        );
+
+        // ips, the expired bans are not listed
+        let body = json!({"method": "blockip", "params": ["10.0.0.1", "abuse", now() + 60]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+        let body = json!({"method": "blockip", "params": ["10.0.0.2", "abuse", 1]});
+        call(&srv, &admin, body).await?;
+        let body = json!({"method": "blockip", "params": ["invalid"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert!(res["error"].is_string());
+        let (_, res) = call(&srv, &admin, json!({"method": "listblockedips"})).await?;
+        assert_eq!(
+            res["result"],
+            json!([{"ip": "10.0.0.1", "reason": "abuse", "expires_at": now() + 60}])
+        );
+        let body = json!({"method": "unblockip", "params": ["10.0.0.1"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+
+        // saved in the db, the expired bans are removed at loading
+        let bans = Bans::load(&db)?;
+        assert_eq!(bans.pubkeys[&user_pubkey].reason, "spam");
+        assert!(bans.ips.is_empty());
+        assert!(db.bans()?.iter().all(|(kind, _, _)| kind != "ip"));
+
+        let body = json!({"method": "banevent", "params": [event.id_str()]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+        {
+            let reader = db.reader()?;
+            let filter = Filter::default();
+            assert_eq!(db.iter::<String, _>(&reader, &filter)?.count(), 0);
+        }
+
+        // the tombstone rejects the event published again
+        let event = Event::create(&admin, now(), 1, vec![], "illegal".to_owned())?;
+        app.publish(event.clone()).await?;
+        let body = json!({"method": "takedownevent", "params": [event.id_str(), "court order"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+        assert!(matches!(
+            app.publish(event.clone()).await?,
+            CheckEventResult::TakenDown
+        ));
+        let (_, res) = call(&srv, &admin, json!({"method": "listtombstones"})).await?;
+        assert_eq!(res["result"][0]["id"], json!(event.id_str()));
+        assert_eq!(res["result"][0]["reason"], json!("court order"));
+
+        let (_, res) = call(&srv, &admin, json!({"method": "unknown"})).await?;
+        assert!(res["error"].is_string());
+
+        // banned pubkey
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let event = Event::create(&user, now(), 1, vec![], "test".to_owned())?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+            panic!("invalid frame type");
+        };
+        let ok: (String, String, bool, String) = serde_json::from_slice(&text)?;
+        assert!(!ok.2);
+        assert!(ok.3.contains("banned"));
+        assert!(app.publish(event.clone()).await.is_err());
+
+        // notice
+        let body = json!({"method": "notice", "params": ["restart"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(1));
+        let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+            panic!("invalid frame type");
+        };
+        assert!(String::from_utf8(text.to_vec())?.contains("restart"));
+        // the session is not authed
+        let body = json!({"method": "notice", "params": ["policy", "authed"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(0));
+        let body = json!({"method": "notice", "params": ["policy", "unauthed"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(1));
+        let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+            panic!("invalid frame type");
+        };
+        assert!(String::from_utf8(text.to_vec())?.contains("policy"));
+        let body = json!({"method": "notice", "params": ["policy", "some"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert!(res["error"].is_string());
+
+        // disable the message hook, the api keeps working
+        let body = json!({"method": "disableextension", "params": ["management"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+        let (_, res) = call(&srv, &admin, json!({"method": "listextensions"})).await?;
+        assert_eq!(
+            res["result"],
+            json!([{"name": "management", "enabled": false}])
+        );
+        let body = json!({"method": "disableextension", "params": ["unknown"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert!(res["error"].is_string());
+
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+            panic!("invalid frame type");
+        };
+        let ok: (String, String, bool, String) = serde_json::from_slice(&text)?;
+        assert!(ok.2);
+
+        // list the sessions with the subscriptions
+        framed
+            .send(ws::Message::Text(
+                r#"["REQ", "sub1", {"kinds": [12345]}, {"kinds": [12346], "limit": 1}]"#.into(),
+            ))
+            .await?;
+        let _eose = framed.next().await.unwrap()?;
+        let (_, res) = call(&srv, &admin, json!({"method": "listsessions"})).await?;
+        let sessions = res["result"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["ip"], json!("127.0.0.1"));
+        assert_eq!(sessions[0]["pubkey"], Value::Null);
+        assert!(sessions[0]["messages_received"].as_u64().unwrap() > 1);
+        assert_eq!(
+            sessions[0]["subscriptions"],
+            json!([{
+                "id": "sub1",
+                "filters": [{"kinds": [12345]}, {"kinds": [12346], "limit": 1}]
+            }])
+        );
+        let body = json!({"method": "listsessions", "params": ["authed"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!([]));
+
+        // kick the session with a final notice
+        let body = json!({"method": "kicksession", "params": ["203.0.113.7"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(0));
+        let body = json!({"method": "kicksession", "params": ["abc"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert!(res["error"].is_string());
+        let body = json!({"method": "kicksession", "params": ["127.0.0.1", "bye"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(1));
+        let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+            panic!("invalid frame type");
+        };
+        assert!(String::from_utf8(text.to_vec())?.contains("bye"));
+        assert_eq!(
+            framed.next().await.unwrap()?,
+            ws::Frame::Close(Some(ws::CloseCode::Policy.into()))
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn takedown_cache() -> Result<()> {
+        let admin = KeyPair::new_global(&mut thread_rng());
+        let app = create_test_app("management-takedown")?;
+        {
+            let mut w = app.setting.write();
+            w.data.result_cache = Some(Duration::from_secs(60).try_into().unwrap());
+            w.extra = serde_json::from_str(&format!(
+                r#"{{
+                "management": {{
+                    "enabled": true,
+                    "admin_pubkeys": ["{}"]
+                }}
+            }}"#,
+                admin.x_only_public_key().0
+            ))?;
+        }
+        let db = app.db.clone();
+        let app = web::Data::new(app.add_extension(Management::new(db)));
+        let event = Event::create(&admin, now(), 1, vec![], "illegal".to_owned())?;
+        app.publish(event.clone()).await?;
+        let c_app = app.clone();
+        let mut srv = actix_test::start(move || create_web_app(c_app.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        // the stored events of the REQ, the results are cached by the first one
+        let mut req = async |sub_id: &str| -> Result<Vec<String>> {
+            let msg = format!(r#"["REQ", "{}", {{"kinds": [1]}}]"#, sub_id);
+            framed.send(ws::Message::Text(msg.into())).await?;
+            let mut messages = vec![];
+            loop {
+                let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+                    panic!("invalid frame type");
+                };
+                let text = String::from_utf8(text.to_vec())?;
+                if text.starts_with(r#"["EOSE""#) {
+                    return Ok(messages);
+                }
+                messages.push(text);
+            }
+        };
+        assert_eq!(req("1").await?.len(), 1);
+        assert_eq!(req("2").await?.len(), 1);
+
+        let body = json!({"method": "takedownevent", "params": [event.id_str(), "court order"]});
+        let (_, res) = call(&srv, &admin, body).await?;
+        assert_eq!(res["result"], json!(true));
+        // not served from the cache
+        assert!(req("3").await?.is_empty());
+        Ok(())
+    }
+}
@@ -1,17 +1,251 @@
-use actix_web::{web, HttpResponse};
-use metrics::{describe_counter, describe_gauge, describe_histogram};
+use crate::nip98::verify_admin;
+use actix_web::{http::header::AUTHORIZATION, web, HttpRequest, HttpResponse};
+use metrics::{describe_counter, describe_gauge, describe_histogram, gauge};
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use nostr_relay::{setting::SettingWrapper, App, Extension};
-use serde::Deserialize;
+use nostr_relay::{
+    db::{now, Db, Event},
+    duration::NonZeroDuration,
+    message::{ClientMessage, IncomingMessage},
+    setting::SettingWrapper,
+    App, Extension, ExtensionMessageResult, List, Session,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write as _,
+    sync::Arc,
+    time::Duration,
+};
 
 #[derive(Deserialize, Default, Debug)]
 pub struct MetricsSetting {
     pub enabled: bool,
+    /// the token in the auth query or the bearer authorization header
     pub auth: Option<String>,
+    /// pubkeys allowed to get the metrics with NIP-98 signed requests
+    pub admin_pubkeys: Option<List>,
+    #[serde(default)]
+    pub top_talkers: TopTalkersSetting,
+}
+
+/// The pubkeys and ips submitting the most events and storing the most bytes
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TopTalkersSetting {
+    pub enabled: bool,
+    /// the sliding window of the counts
+    pub window: NonZeroDuration,
+    /// the number of the exported pubkeys and ips of each metric
+    pub top: usize,
+    /// the max pubkeys and ips counted in a slot of the window, the others are counted as "other"
+    pub max_keys: usize,
+}
+
+impl Default for TopTalkersSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: NonZeroDuration::new(Duration::from_secs(3600)).unwrap(),
+            top: 10,
+            max_keys: 10000,
+        }
+    }
+}
+
+impl MetricsSetting {
+    /// the metrics are public when neither the token nor the admin pubkeys are set
+    fn authorized(&self, req: &HttpRequest, query: &Info) -> bool {
+        if self.auth.is_none() && self.admin_pubkeys.is_none() {
+            return query.auth.is_none();
+        }
+        let bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let token = query.auth.as_deref().or(bearer);
+        (self.auth.is_some() && self.auth.as_deref() == token)
+            || verify_admin(req, &[], self.admin_pubkeys.as_ref()).is_ok()
+    }
+}
+
+/// The events and bytes of a pubkey or an ip
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+struct Usage {
+    events: u64,
+    bytes: u64,
+}
+
+/// The number of the slots of the window, the counts expire by slot
+const SLOTS: u64 = 12;
+const OTHER: &str = "other";
+
+/// The keys with the values in descending order
+type Ranking = Vec<(String, u64)>;
+
+/// The usage of the keys in the slots of the sliding window
+#[derive(Default, Debug)]
+struct Talkers {
+    slots: VecDeque<(u64, HashMap<String, Usage>)>,
+}
+
+impl Talkers {
+    fn slot(time: u64, setting: &TopTalkersSetting) -> u64 {
+        time / (setting.window.as_secs() / SLOTS).max(1)
+    }
+
+    fn expire(&mut self, slot: u64) {
+        while self.slots.front().is_some_and(|s| s.0 + SLOTS <= slot) {
+            self.slots.pop_front();
+        }
+    }
+
+    fn add(&mut self, key: &str, usage: Usage, time: u64, setting: &TopTalkersSetting) {
+        let slot = Self::slot(time, setting);
+        self.expire(slot);
+        if self.slots.back().is_none_or(|s| s.0 != slot) {
+            self.slots.push_back((slot, HashMap::new()));
+        }
+        if let Some((_, keys)) = self.slots.back_mut() {
+            let key = if keys.contains_key(key) || keys.len() < setting.max_keys {
+                key
+            } else {
+                OTHER
+            };
+            let item = keys.entry(key.to_owned()).or_default();
+            item.events += usage.events;
+            item.bytes += usage.bytes;
+        }
+    }
+
+    /// the top keys by events and by bytes in the window
+    fn top(&mut self, time: u64, setting: &TopTalkersSetting) -> (Ranking, Ranking) {
+        self.expire(Self::slot(time, setting));
+        let mut total: HashMap<&str, Usage> = HashMap::new();
+        for (_, keys) in &self.slots {
+            for (key, usage) in keys {
+                let item = total.entry(key).or_default();
+                item.events += usage.events;
+                item.bytes += usage.bytes;
+            }
+        }
+        let top = |by: fn(&Usage) -> u64| {
+            let mut list = total
+                .iter()
+                .map(|(k, u)| (k.to_string(), by(u)))
+                .filter(|(_, v)| *v > 0)
+                .collect::<Vec<_>>();
+            list.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            list.truncate(setting.top);
+            list
+        };
+        (top(|u| u.events), top(|u| u.bytes))
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct TopTalkers {
+    setting: TopTalkersSetting,
+    pubkeys: Talkers,
+    ips: Talkers,
+}
+
+impl TopTalkers {
+    fn add(&mut self, pubkey: &str, ip: &str, usage: Usage) {
+        let time = now();
+        self.pubkeys.add(pubkey, usage, time, &self.setting);
+        self.ips.add(ip, usage, time, &self.setting);
+    }
+
+    /// the gauges in the prometheus text format
+    fn render(&mut self) -> String {
+        let time = now();
+        let mut text = String::new();
+        for (label, talkers) in [("pubkey", &mut self.pubkeys), ("ip", &mut self.ips)] {
+            let (events, bytes) = talkers.top(time, &self.setting);
+            for (name, help, list) in [
+                ("events", "The events submitted", events),
+                ("bytes", "The bytes of the stored events", bytes),
+            ] {
+                let metric = format!("nostr_relay_top_{}_{}", label, name);
+                let _ = writeln!(
+                    text,
+                    "# HELP {} {} by the top {}s in the window",
+                    metric, help, label
+                );
+                let _ = writeln!(text, "# TYPE {} gauge", metric);
+                for (key, value) in list {
+                    let key = key.replace('\\', "\\\\").replace('"', "\\\"");
+                    let _ = writeln!(text, "{}{{{}=\"{}\"}} {}", metric, label, key, value);
+                }
+            }
+        }
+        text
+    }
+}
+
+/// The statistics of the lmdb environment, read when the metrics are requested
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LmdbStats {
+    pub page_size: u32,
+    pub map_size: usize,
+    /// pages allocated in the file
+    pub used_pages: usize,
+    /// pages of the map not allocated yet, the writes fail with MDB_MAP_FULL when they run out
+    pub free_pages: usize,
+    /// allocated pages not holding tree data, reused by the later writes
+    pub reusable_pages: usize,
+    pub last_txn_id: usize,
+    pub readers: u32,
+    pub max_readers: u32,
+    /// entries of each database
+    pub entries: BTreeMap<&'static str, usize>,
+}
+
+impl LmdbStats {
+    pub fn read(db: &Db) -> nostr_relay::Result<Self> {
+        let (env, info) = db.env_stat()?;
+        let trees = db.tree_stats()?;
+        let page_size = env.page_size.max(1);
+        let used_pages = info.last_page + 1;
+        // the main tree holds the names of the sub trees
+        let tree_pages = env.pages() + trees.iter().map(|(_, t)| t.pages()).sum::<usize>();
+        Ok(Self {
+            page_size,
+            map_size: info.map_size,
+            used_pages,
+            free_pages: (info.map_size / page_size as usize).saturating_sub(used_pages),
+            reusable_pages: used_pages.saturating_sub(tree_pages),
+            last_txn_id: info.last_txn,
+            readers: info.num_readers,
+            max_readers: info.max_readers,
+            entries: trees.into_iter().map(|(n, t)| (n, t.entries)).collect(),
+        })
+    }
+
+    fn record(&self) {
+        gauge!("nostr_relay_lmdb_page_size", self.page_size as f64);
+        gauge!("nostr_relay_lmdb_map_size_bytes", self.map_size as f64);
+        gauge!("nostr_relay_lmdb_used_pages", self.used_pages as f64);
+        gauge!("nostr_relay_lmdb_free_pages", self.free_pages as f64);
+        gauge!(
+            "nostr_relay_lmdb_reusable_pages",
+            self.reusable_pages as f64
+        );
+        gauge!("nostr_relay_lmdb_last_txn_id", self.last_txn_id as f64);
+        gauge!("nostr_relay_lmdb_readers", self.readers as f64);
+        gauge!("nostr_relay_lmdb_max_readers", self.max_readers as f64);
+        for (tree, entries) in &self.entries {
+            gauge!("nostr_relay_lmdb_entries", *entries as f64, "tree" => *tree);
+        }
+    }
 }
 
 pub struct Metrics {
     pub handle: web::Data<PrometheusHandle>,
+    talkers: Arc<Mutex<TopTalkers>>,
+    enabled: bool,
 }
 
 impl Metrics {
@@ -20,6 +254,8 @@ impl Metrics {
         describe_metrics();
         Self {
             handle: web::Data::new(handle),
+            talkers: Default::default(),
+            enabled: false,
         }
     }
 }
@@ -38,12 +274,59 @@ impl Extension for Metrics {
     fn setting(&mut self, setting: &SettingWrapper) {
         let mut w = setting.write();
         let s: MetricsSetting = w.parse_extension(self.name());
+        self.enabled = s.enabled && s.top_talkers.enabled;
+        let mut talkers = self.talkers.lock();
+        if talkers.setting.window != s.top_talkers.window {
+            *talkers = TopTalkers::default();
+        }
+        talkers.setting = s.top_talkers.clone();
+        drop(talkers);
         w.set_extension(s);
     }
 
     fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
         cfg.app_data(self.handle.clone())
-            .service(web::resource("/metrics").route(web::get().to(route_metrics)));
+            .app_data(web::Data::from(self.talkers.clone()))
+            .service(web::resource("/metrics").route(web::get().to(route_metrics)))
+            .service(web::resource("/metrics/lmdb").route(web::get().to(route_lmdb)))
+            .service(web::resource("/metrics/series").route(web::get().to(route_series)));
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        if self.enabled {
+            let usage = Usage {
+                events: 0,
+                bytes: event.to_string().len() as u64,
+            };
+            self.talkers
+                .lock()
+                .add(&event.pubkey_str(), session.ip(), usage);
+        }
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if self.enabled {
+            if let IncomingMessage::Event(event) = &msg.msg {
+                let usage = Usage {
+                    events: 1,
+                    bytes: 0,
+                };
+                self.talkers
+                    .lock()
+                    .add(&event.pubkey_str(), session.ip(), usage);
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
     }
 }
 
@@ -62,8 +345,76 @@ pub fn describe_metrics() {
         "The total count of message from client"
     );
     describe_counter!("nostr_relay_new_event", "The total count of new event");
+    describe_counter!(
+        "nostr_relay_extension_timeout_total",
+        "The total count of async extension messages timed out"
+    );
     describe_histogram!("nostr_relay_db_get", "The time of per filter get");
+    describe_counter!(
+        "nostr_relay_result_cache_hit_total",
+        "The total count of filters served from the shared result cache"
+    );
+    describe_counter!(
+        "nostr_relay_seen_duplicate_total",
+        "The total count of events answered as duplicate by the recently seen ids"
+    );
+    describe_counter!(
+        "nostr_relay_scan_budget_exceeded_total",
+        "The total count of subscriptions closed because the filters scanned more index entries than the budget"
+    );
+    describe_counter!(
+        "nostr_relay_backfill_closed_total",
+        "The total count of subscriptions closed because the client did not read the stored events"
+    );
+    describe_gauge!(
+        "nostr_relay_read_pending",
+        "The REQs waiting for a reader thread"
+    );
+    describe_gauge!(
+        "nostr_relay_write_pending",
+        "The events waiting for the next write transaction"
+    );
+    describe_counter!(
+        "nostr_relay_fanout_deduplicated_total",
+        "The total count of new events not sent again to the sessions with several matching subscriptions"
+    );
+    describe_counter!(
+        "nostr_relay_dropped_total",
+        "The total count of messages dropped or rejected for the load and sessions stopped, by the reason and the authed or anon client"
+    );
+    describe_counter!(
+        "nostr_relay_write_shed_total",
+        "The total count of events rejected because the write queue is full"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_free_pages",
+        "The pages of the lmdb map not allocated yet"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_readers",
+        "The reader slots in use of the lmdb environment"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_entries",
+        "The entries of each lmdb database"
+    );
     describe_histogram!("nostr_relay_db_write", "The time of per write transaction");
+    describe_gauge!(
+        "nostr_relay_subscriptions",
+        "The number of active subscriptions"
+    );
+    describe_histogram!(
+        "nostr_relay_subscription_filters",
+        "The number of filters per subscription"
+    );
+    describe_counter!(
+        "nostr_relay_expensive_filter_total",
+        "The total count of subscribed filters by expensive shape"
+    );
+    describe_counter!(
+        "nostr_relay_subscription_duplicate_total",
+        "The total count of resent subscriptions kept without reading the stored events"
+    );
 }
 
 pub fn create_prometheus_handle() -> PrometheusHandle {
@@ -80,19 +431,73 @@ pub fn create_prometheus_handle() -> PrometheusHandle {
 #[derive(Deserialize, Default)]
 struct Info {
     auth: Option<String>,
+    /// the range of the series
+    since: Option<u64>,
+    until: Option<u64>,
 }
 
 async fn route_metrics(
+    req: HttpRequest,
     handle: web::Data<PrometheusHandle>,
+    talkers: web::Data<Mutex<TopTalkers>>,
     app: web::Data<App>,
     query: web::Query<Info>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let setting = app.setting.read();
     if let Some(s) = setting.get_extension::<MetricsSetting>() {
-        if s.enabled && s.auth == query.auth {
+        if s.enabled && s.authorized(&req, &query) {
+            if let Ok(stats) = LmdbStats::read(&app.db) {
+                stats.record();
+            }
+            let mut body = handle.render();
+            if s.top_talkers.enabled {
+                body.push_str(&talkers.lock().render());
+            }
             return Ok(HttpResponse::Ok()
                 .insert_header(("Content-Type", "text/plain"))
-                .body(handle.render()));
+                .body(body));
+        }
+    }
+    Ok(HttpResponse::NotFound().finish())
+}
+
+async fn route_lmdb(
+    req: HttpRequest,
+    app: web::Data<App>,
+    query: web::Query<Info>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let setting = app.setting.read();
+    if let Some(s) = setting.get_extension::<MetricsSetting>() {
+        if s.enabled && s.authorized(&req, &query) {
+            let stats =
+                LmdbStats::read(&app.db).map_err(actix_web::error::ErrorInternalServerError)?;
+            return Ok(HttpResponse::Ok().json(stats));
+        }
+    }
+    Ok(HttpResponse::NotFound().finish())
+}
+
+/// The rolled-up counters stored in the database, the last 7 days by default
+async fn route_series(
+    req: HttpRequest,
+    app: web::Data<App>,
+    query: web::Query<Info>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let setting = app.setting.read();
+    if let Some(s) = setting.get_extension::<MetricsSetting>() {
+        if s.enabled && s.authorized(&req, &query) {
+            let since = query
+                .since
+                .unwrap_or_else(|| now().saturating_sub(7 * 86400));
+            let series = app
+                .db
+                .reader()
+                .and_then(|reader| {
+                    app.db
+                        .time_series(&reader, since, query.until.unwrap_or(u64::MAX))
+                })
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            return Ok(HttpResponse::Ok().json(series));
         }
     }
     Ok(HttpResponse::NotFound().finish())
@@ -100,30 +505,40 @@ async fn route_metrics(
 
 #[cfg(test)]
 pub mod tests {
-    use super::Metrics;
-    use crate::create_test_app;
+    use super::{Metrics, Talkers, TopTalkersSetting, Usage};
+    use crate::{create_test_app, nip98::auth_header};
     use actix_rt::time::sleep;
     use actix_web::{
         dev::Service,
         test::{init_service, read_body, TestRequest},
     };
     use anyhow::Result;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+        Event, TimeSeries,
+    };
     use std::time::Duration;
 
     #[actix_rt::test]
     async fn metrics() -> Result<()> {
+        let admin = KeyPair::new_global(&mut thread_rng());
         let data = create_test_app("")?;
         {
             let mut w = data.setting.write();
-            w.extra = serde_json::from_str(
-                r#"{
-                "metrics": {
+            w.extra = serde_json::from_str(&format!(
+                r#"{{
+                "metrics": {{
                     "enabled": true,
-                    "auth": "auth_key"
-                }
-            }"#,
-            )?;
+                    "auth": "auth_key",
+                    "admin_pubkeys": ["{}"],
+                    "top_talkers": {{ "enabled": true }}
+                }}
+            }}"#,
+                admin.x_only_public_key().0
+            ))?;
         }
+        let db = data.db.clone();
         let data = data.add_extension(Metrics::new());
 
         let app = init_service(data.web_app()).await;
@@ -140,6 +555,83 @@ pub mod tests {
         let result = read_body(res).await;
         let result = String::from_utf8(result.to_vec())?;
         assert!(result.contains("test_metric"));
+        assert!(result.contains("# TYPE nostr_relay_top_ip_bytes gauge"));
+        assert!(result.contains(r#"nostr_relay_lmdb_entries{tree="data"} 0"#));
+
+        let req = TestRequest::with_uri("/metrics/lmdb?auth=auth_key").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let stats: serde_json::Value = serde_json::from_slice(&read_body(res).await)?;
+        assert!(stats["free_pages"].as_u64().unwrap() > 0);
+        assert_eq!(stats["entries"]["data"], 0);
+        let req = TestRequest::with_uri("/metrics/lmdb").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        // the rolled-up counters
+        db.batch_put([Event::create(&admin, now(), 1, vec![], "".to_owned())?])?;
+        let req = TestRequest::with_uri("/metrics/series?auth=auth_key").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let series: TimeSeries = serde_json::from_slice(&read_body(res).await)?;
+        assert_eq!(series.pubkeys.values().sum::<u64>(), 1);
+        assert_eq!(series.events.values().next().unwrap().get(&1), Some(&1));
+        let req =
+            TestRequest::with_uri("/metrics/series?auth=auth_key&since=0&until=0").to_request();
+        let res = app.call(req).await.unwrap();
+        let series: TimeSeries = serde_json::from_slice(&read_body(res).await)?;
+        assert!(series.events.is_empty());
+
+        let req = TestRequest::with_uri("/metrics")
+            .insert_header(("Authorization", "Bearer auth_key"))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // signed by the admin
+        let auth = auth_header(&admin, "http://localhost:8080/metrics", "GET", &[])?;
+        let req = TestRequest::with_uri("/metrics")
+            .insert_header(("Authorization", auth))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        let other = KeyPair::new_global(&mut thread_rng());
+        let auth = auth_header(&other, "http://localhost:8080/metrics", "GET", &[])?;
+        let req = TestRequest::with_uri("/metrics")
+            .insert_header(("Authorization", auth))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 404);
         Ok(())
     }
+
+    #[test]
+    fn top_talkers() {
+        let setting = TopTalkersSetting {
+            enabled: true,
+            window: serde_json::from_str("120").unwrap(),
+            top: 2,
+            max_keys: 3,
+        };
+        let usage = |events, bytes| Usage { events, bytes };
+        let mut talkers = Talkers::default();
+        talkers.add("a", usage(1, 100), 0, &setting);
+        talkers.add("b", usage(2, 10), 5, &setting);
+        talkers.add("c", usage(1, 0), 5, &setting);
+        // over the max keys of the slot
+        talkers.add("d", usage(5, 0), 9, &setting);
+        talkers.add("a", usage(1, 0), 10, &setting);
+        let (events, bytes) = talkers.top(10, &setting);
+        assert_eq!(events, vec![("other".to_owned(), 5), ("a".to_owned(), 2)]);
+        assert_eq!(bytes, vec![("a".to_owned(), 100), ("b".to_owned(), 10)]);
+
+        // the first slot expires
+        talkers.add("c", usage(1, 0), 125, &setting);
+        let (events, _) = talkers.top(125, &setting);
+        assert_eq!(events, vec![("a".to_owned(), 1), ("c".to_owned(), 1)]);
+        let (events, bytes) = talkers.top(235, &setting);
+        assert_eq!(events, vec![("c".to_owned(), 1)]);
+        assert!(bytes.is_empty());
+    }
 }
@@ -0,0 +1,657 @@
+use crate::client::{connect, Framed, MIN_BACKOFF};
+use actix::{clock::sleep, Arbiter};
+use actix_web::web;
+use awc::ws::{Frame, Message};
+use futures_channel::oneshot;
+use futures_util::{future::select, SinkExt, StreamExt};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{Db, Event, Filter, Kinds},
+    duration::NonZeroDuration,
+    setting::SettingWrapper,
+    App, Extension,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+/// min interval of saving the cursors while receiving events
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// [NIP-65](https://nips.be/65) relay list
+const RELAY_LIST_KIND: u16 = 10002;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct MirrorRelay {
+    /// websocket url of the upstream relay
+    pub url: String,
+    /// [NIP-01](https://nips.be/1) filters of the subscription, all events when empty
+    #[serde(default)]
+    pub filters: Vec<Value>,
+    /// skip the signature verification of its events, ie: a relay of the same operator, the ids are still checked
+    #[serde(default)]
+    pub trusted: bool,
+}
+
+impl MirrorRelay {
+    /// the REQ message, since the cursor when it is newer than the since of the filters
+    pub fn req(&self, since: Option<u64>) -> String {
+        let mut filters = self.filters.clone();
+        if filters.is_empty() {
+            filters.push(Value::Object(Default::default()));
+        }
+        let mut req = vec![Value::from("REQ"), Value::from("mirror")];
+        for mut filter in filters {
+            if let (Some(since), Some(map)) = (since, filter.as_object_mut()) {
+                if map.get("since").and_then(Value::as_u64).unwrap_or(0) < since {
+                    map.insert("since".to_owned(), since.into());
+                }
+            }
+            req.push(filter);
+        }
+        Value::Array(req).to_string()
+    }
+
+    /// check the filters
+    pub fn parse_filters(&self) -> Result<Vec<Filter>, serde_json::Error> {
+        self.filters
+            .iter()
+            .map(|f| serde_json::from_value(f.clone()))
+            .collect()
+    }
+}
+
+/// Mirror the events of the members from the write relays of their [NIP-65](https://nips.be/65) relay lists
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct OutboxSetting {
+    /// hex pubkeys of the members, disabled when empty
+    pub pubkeys: Vec<String>,
+    /// websocket urls of the relays to fetch the relay lists of the members
+    pub discovery: Vec<String>,
+    /// kinds, ranges or groups of the mirrored events, all when empty,
+    /// the ranges are sent to the relays as the list of kinds
+    pub kinds: Kinds,
+    /// max write relays of each member
+    pub max_relays: usize,
+    /// interval of reading the relay lists from the database
+    pub refresh: NonZeroDuration,
+}
+
+impl Default for OutboxSetting {
+    fn default() -> Self {
+        Self {
+            pubkeys: Vec::new(),
+            discovery: Vec::new(),
+            kinds: Kinds::default(),
+            max_relays: 5,
+            refresh: Duration::from_secs(600).try_into().unwrap(),
+        }
+    }
+}
+
+impl OutboxSetting {
+    /// the subscription of the members on a write relay
+    fn relay(&self, url: String, authors: &BTreeSet<String>) -> MirrorRelay {
+        let mut filter = json!({ "authors": authors });
+        if !self.kinds.is_empty() {
+            filter["kinds"] = json!(self.kinds.iter().collect::<Vec<_>>());
+        }
+        MirrorRelay {
+            url,
+            filters: vec![filter],
+            trusted: false,
+        }
+    }
+}
+
+/// the write relays of the members from the latest relay lists, url => members
+pub fn write_relays(
+    db: &Db,
+    pubkeys: &[String],
+    max_relays: usize,
+) -> Result<BTreeMap<String, BTreeSet<String>>, nostr_relay::Error> {
+    let filter: Filter =
+        serde_json::from_value(json!({ "kinds": [RELAY_LIST_KIND], "authors": pubkeys }))?;
+    let reader = db.reader()?;
+    let mut latest: HashMap<String, Event> = HashMap::new();
+    for event in db.iter::<Event, _>(&reader, &filter)? {
+        let event = event?;
+        let pubkey = event.pubkey_str();
+        if latest
+            .get(&pubkey)
+            .is_none_or(|e| e.created_at() < event.created_at())
+        {
+            latest.insert(pubkey, event);
+        }
+    }
+
+    let mut relays: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (pubkey, event) in latest {
+        event
+            .tags()
+            .iter()
+            .filter(|t| t.len() > 1 && t[0] == "r")
+            .filter(|t| t.get(2).is_none_or(|m| m == "write"))
+            .map(|t| t[1].trim_end_matches('/'))
+            .filter(|url| url.starts_with("ws://") || url.starts_with("wss://"))
+            .take(max_relays)
+            .for_each(|url| {
+                relays
+                    .entry(url.to_owned())
+                    .or_default()
+                    .insert(pubkey.clone());
+            });
+    }
+    Ok(relays)
+}
+
+/// the cursor key of an outbox subscription, changes with the filters to fetch the history of the new members
+fn cursor_key(relay: &MirrorRelay) -> String {
+    // FNV-1a, stable across restarts
+    let hash = Value::from(relay.filters.clone())
+        .to_string()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{}#{:016x}", relay.url, hash)
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MirrorSetting {
+    pub enabled: bool,
+    pub relays: Vec<MirrorRelay>,
+    pub outbox: OutboxSetting,
+    /// the retry delay doubles from 1 second to the max backoff
+    pub max_backoff: NonZeroDuration,
+    /// the file saving the created_at of the last received event of each relay,
+    /// default "mirror.json" in the data path
+    pub cursor_path: Option<PathBuf>,
+}
+
+impl Default for MirrorSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            relays: Vec::new(),
+            outbox: OutboxSetting::default(),
+            max_backoff: Duration::from_secs(300).try_into().unwrap(),
+            cursor_path: None,
+        }
+    }
+}
+
+/// The created_at of the last received event of each subscription, resume from it after reconnecting
+#[derive(Debug)]
+pub struct Cursors {
+    path: PathBuf,
+    map: Mutex<HashMap<String, u64>>,
+    saved: Mutex<Instant>,
+}
+
+impl Cursors {
+    pub fn load(path: PathBuf) -> Self {
+        let map = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            map: Mutex::new(map),
+            saved: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Option<u64> {
+        self.map.lock().get(url).copied()
+    }
+
+    pub fn update(&self, url: &str, time: u64) {
+        {
+            let mut map = self.map.lock();
+            let cursor = map.entry(url.to_owned()).or_default();
+            if *cursor >= time {
+                return;
+            }
+            *cursor = time;
+        }
+        if self.saved.lock().elapsed() > SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    pub fn save(&self) {
+        *self.saved.lock() = Instant::now();
+        let data = serde_json::to_vec(&*self.map.lock()).unwrap_or_default();
+        let tmp = self.path.with_extension("tmp");
+        if let Err(err) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, &self.path)) {
+            error!(error = err.to_string(), "failed to save mirror cursors");
+        }
+    }
+}
+
+/// Ingest the events of the upstream relays and the write relays of the members,
+/// the events are written through [`App::publish`]
+pub struct Mirror {
+    pub setting: MirrorSetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    app: App,
+    /// runs the relay and outbox tasks, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    /// stop the relay and outbox tasks when dropped
+    stops: Vec<oneshot::Sender<()>>,
+}
+
+impl Mirror {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_mirror_received_total",
+            "The total count of events received from the upstream relays"
+        );
+        describe_counter!(
+            "nostr_relay_mirror_known_total",
+            "The total count of received events already stored"
+        );
+        Self {
+            setting: MirrorSetting::default(),
+            proxy: None,
+            app,
+            arbiter: Arbiter::new(),
+            stops: Vec::new(),
+        }
+    }
+}
+
+impl Extension for Mirror {
+    fn name(&self) -> &'static str {
+        "mirror"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        let mirror: MirrorSetting = r.parse_extension(self.name());
+        if mirror == self.setting && r.network.proxy == self.proxy {
+            return;
+        }
+        self.setting = mirror;
+        self.proxy = r.network.proxy.clone();
+        self.stops.clear();
+        if !self.setting.enabled {
+            return;
+        }
+
+        let path = self
+            .setting
+            .cursor_path
+            .clone()
+            .unwrap_or_else(|| r.data.path.join("mirror.json"));
+        let cursors = Arc::new(Cursors::load(path));
+        let max_backoff: Duration = self.setting.max_backoff.into();
+        let mut relays = self.setting.relays.clone();
+        let outbox = &self.setting.outbox;
+        if !outbox.pubkeys.is_empty() {
+            // fetch the relay lists of the members
+            let filter = json!({ "kinds": [RELAY_LIST_KIND], "authors": outbox.pubkeys });
+            relays.extend(outbox.discovery.iter().map(|url| MirrorRelay {
+                url: url.clone(),
+                filters: vec![filter.clone()],
+                trusted: false,
+            }));
+        }
+        for relay in relays {
+            let key = relay.url.clone();
+            let (tx, rx) = oneshot::channel();
+            let (app, cursors) = (self.app.clone(), cursors.clone());
+            let proxy = self.proxy.clone();
+            // the websocket client is not Send, create the task in the arbiter
+            self.arbiter.spawn_fn(move || {
+                actix::spawn(async move {
+                    let task = run(relay, key, proxy, app, cursors, max_backoff);
+                    select(Box::pin(task), rx).await;
+                });
+            });
+            self.stops.push(tx);
+        }
+
+        if !outbox.pubkeys.is_empty() {
+            let (tx, rx) = oneshot::channel();
+            let (outbox, app, cursors) = (outbox.clone(), self.app.clone(), cursors.clone());
+            let proxy = self.proxy.clone();
+            self.arbiter.spawn_fn(move || {
+                actix::spawn(async move {
+                    let task = follow(outbox, proxy, app, cursors, max_backoff);
+                    select(Box::pin(task), rx).await;
+                });
+            });
+            self.stops.push(tx);
+        }
+    }
+}
+
+/// keep the subscriptions of the members on their write relays up to date with the relay lists
+async fn follow(
+    outbox: OutboxSetting,
+    proxy: Option<String>,
+    app: App,
+    cursors: Arc<Cursors>,
+    max_backoff: Duration,
+) {
+    // url => (subscription, stop sender), the subscriptions stop when dropped
+    let mut tasks: HashMap<String, (MirrorRelay, oneshot::Sender<()>)> = HashMap::new();
+    loop {
+        let db = app.db.clone();
+        let (pubkeys, max_relays) = (outbox.pubkeys.clone(), outbox.max_relays);
+        match web::block(move || write_relays(&db, &pubkeys, max_relays)).await {
+            Ok(Ok(relays)) => {
+                let relays = relays
+                    .into_iter()
+                    .map(|(url, authors)| (url.clone(), outbox.relay(url, &authors)))
+                    .collect::<HashMap<_, _>>();
+                tasks.retain(|url, (relay, _)| relays.get(url) == Some(&*relay));
+                for (url, relay) in relays {
+                    if tasks.contains_key(&url) {
+                        continue;
+                    }
+                    debug!(relay = url, filters = ?relay.filters, "mirror outbox subscribe");
+                    let (tx, rx) = oneshot::channel();
+                    let task = run(
+                        relay.clone(),
+                        cursor_key(&relay),
+                        proxy.clone(),
+                        app.clone(),
+                        cursors.clone(),
+                        max_backoff,
+                    );
+                    actix::spawn(async move {
+                        select(Box::pin(task), rx).await;
+                    });
+                    tasks.insert(url, (relay, tx));
+                }
+            }
+            Ok(Err(err)) => error!(error = err.to_string(), "mirror outbox read relay lists"),
+            Err(err) => error!(error = err.to_string(), "mirror outbox read relay lists"),
+        }
+        sleep(outbox.refresh.into()).await;
+    }
+}
+
+/// keep the subscription to the relay, reconnect with backoff until stopped
+async fn run(
+    relay: MirrorRelay,
+    key: String,
+    proxy: Option<String>,
+    app: App,
+    cursors: Arc<Cursors>,
+    max_backoff: Duration,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match connect(&relay.url, proxy.as_deref()).await {
+            Ok(mut framed) => {
+                info!(relay = relay.url, "mirror connected");
+                backoff = MIN_BACKOFF;
+                if let Err(err) = mirror(&relay, &key, &app, &cursors, &mut framed).await {
+                    warn!(relay = relay.url, error = err, "mirror disconnected");
+                }
+                cursors.save();
+            }
+            Err(err) => warn!(relay = relay.url, error = err, "mirror connect failed"),
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+fn known(db: &Db, event: &Event) -> bool {
+    db.reader()
+        .and_then(|r| db.contains(&r, event.id()))
+        .unwrap_or_default()
+}
+
+async fn mirror(
+    relay: &MirrorRelay,
+    key: &str,
+    app: &App,
+    cursors: &Cursors,
+    framed: &mut Framed,
+) -> Result<(), String> {
+    let url = relay.url.as_str();
+    framed
+        .send(Message::Text(relay.req(cursors.get(key)).into()))
+        .await
+        .map_err(|e| e.to_string())?;
+    while let Some(frame) = framed.next().await {
+        match frame.map_err(|e| e.to_string())? {
+            Frame::Text(text) => {
+                let Ok(mut msg) = serde_json::from_slice::<Vec<Value>>(&text) else {
+                    continue;
+                };
+                match msg.first().and_then(Value::as_str) {
+                    Some("EVENT") if msg.len() > 2 => {
+                        match serde_json::from_value::<Event>(msg[2].take()) {
+                            Ok(event) => {
+                                increment_counter!("nostr_relay_mirror_received_total", "relay" => url.to_owned());
+                                let time = event.created_at();
+                                // the stored events of a large sync are skipped before the signature is verified,
+                                // the bloom filter answers most of the new ones without reading the db
+                                if known(&app.db, &event) {
+                                    increment_counter!("nostr_relay_mirror_known_total", "relay" => url.to_owned());
+                                    cursors.update(key, time);
+                                    continue;
+                                }
+                                let res = if relay.trusted {
+                                    app.publish_trusted(event).await
+                                } else {
+                                    app.publish(event).await
+                                };
+                                if let Err(err) = res {
+                                    debug!(relay = url, error = err.to_string(), "mirror rejected");
+                                }
+                                cursors.update(key, time);
+                            }
+                            Err(err) => {
+                                debug!(relay = url, error = err.to_string(), "mirror invalid event")
+                            }
+                        }
+                    }
+                    Some("EOSE") => cursors.save(),
+                    Some("CLOSED") => return Err(format!("subscription closed {:?}", msg.get(2))),
+                    Some("NOTICE") => warn!(relay = url, msg = ?msg.get(1), "mirror notice"),
+                    _ => {}
+                }
+            }
+            Frame::Ping(msg) => framed
+                .send(Message::Pong(msg))
+                .await
+                .map_err(|e| e.to_string())?,
+            Frame::Close(reason) => return Err(format!("closed {:?}", reason)),
+            _ => {}
+        }
+    }
+    Err("closed".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_test_app, temp_data_path};
+    use actix_web::web;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use nostr_relay::{
+        create_web_app,
+        db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+        },
+    };
+
+    #[test]
+    fn req() -> Result<()> {
+        let relay: MirrorRelay = serde_json::from_str(
+            r#"{"url": "ws://localhost", "filters": [{"kinds": [1]}, {"since": 20}]}"#,
+        )?;
+        assert_eq!(relay.parse_filters()?.len(), 2);
+        assert_eq!(
+            relay.req(None),
+            r#"["REQ","mirror",{"kinds":[1]},{"since":20}]"#
+        );
+        assert_eq!(
+            relay.req(Some(10)),
+            r#"["REQ","mirror",{"kinds":[1],"since":10},{"since":20}]"#
+        );
+        let relay: MirrorRelay = serde_json::from_str(r#"{"url": "ws://localhost"}"#)?;
+        assert_eq!(relay.req(None), r#"["REQ","mirror",{}]"#);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn mirror() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+
+        let upstream = web::Data::new(create_test_app("mirror_upstream")?);
+        let mut up_srv = actix_test::start({
+            let upstream = upstream.clone();
+            move || create_web_app(upstream.clone())
+        });
+        let mut framed = up_srv.ws_at("/").await.unwrap();
+        let note = Event::create(&key_pair, now(), 1, vec![], "note".to_owned())?;
+        let other = Event::create(&key_pair, now(), 2, vec![], "other".to_owned())?;
+        for event in [&note, &other] {
+            framed
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+                .await?;
+            framed.next().await.unwrap()?;
+        }
+
+        let dir = temp_data_path("mirror_cursor")?;
+        let cursor_path = dir.path().join("mirror.json");
+        let app = create_test_app("mirror")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(serde_json::json!({
+                "mirror": {
+                    "enabled": true,
+                    "cursor_path": cursor_path,
+                    "relays": [{
+                        "url": up_srv.url("/").replace("http", "ws"),
+                        "filters": [{"kinds": [1]}]
+                    }]
+                }
+            }))?;
+        }
+        let db = app.db.clone();
+        let app = app.clone().add_extension(Mirror::new(app));
+
+        let filter = Filter::default();
+        let mut ids = vec![];
+        for _ in 0..50 {
+            sleep(Duration::from_millis(100)).await;
+            let reader = db.reader()?;
+            ids = db
+                .iter::<Event, _>(&reader, &filter)?
+                .map(|e| e.map(|e| e.id_str()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !ids.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(ids, vec![note.id_str()]);
+
+        // saved after EOSE
+        sleep(Duration::from_millis(100)).await;
+        let cursors = Cursors::load(cursor_path);
+        assert_eq!(
+            cursors.get(&up_srv.url("/").replace("http", "ws")),
+            Some(note.created_at())
+        );
+        drop(app);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn outbox() -> Result<()> {
+        let member = KeyPair::new_global(&mut thread_rng());
+        let other = KeyPair::new_global(&mut thread_rng());
+
+        let upstream = web::Data::new(create_test_app("mirror_outbox_upstream")?);
+        let note = Event::create(&member, now(), 1, vec![], "note".to_owned())?;
+        let other_note = Event::create(&other, now(), 1, vec![], "other".to_owned())?;
+        upstream.db.batch_put([&note, &other_note])?;
+        let up_srv = actix_test::start({
+            let upstream = upstream.clone();
+            move || create_web_app(upstream.clone())
+        });
+        let url = up_srv.url("/").replace("http", "ws");
+
+        let app = create_test_app("mirror_outbox")?;
+        let relay_list = Event::create(
+            &member,
+            now(),
+            10002,
+            vec![
+                vec!["r".to_owned(), url.clone()],
+                vec![
+                    "r".to_owned(),
+                    "wss://read.example.com".to_owned(),
+                    "read".to_owned(),
+                ],
+            ],
+            "".to_owned(),
+        )?;
+        app.db.batch_put([&relay_list])?;
+        let member_pubkey = relay_list.pubkey_str();
+        let relays = write_relays(&app.db, std::slice::from_ref(&member_pubkey), 5)?;
+        assert_eq!(
+            relays.into_iter().collect::<Vec<_>>(),
+            vec![(
+                url.trim_end_matches('/').to_owned(),
+                BTreeSet::from([member_pubkey.clone()])
+            )]
+        );
+
+        let dir = temp_data_path("mirror_outbox_cursor")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({
+                "mirror": {
+                    "enabled": true,
+                    "cursor_path": dir.path().join("mirror.json"),
+                    "outbox": { "pubkeys": [member_pubkey], "kinds": [1] }
+                }
+            }))?;
+        }
+        let db = app.db.clone();
+        let app = app.clone().add_extension(Mirror::new(app));
+
+        let filter = Filter {
+            kinds: vec![1].into(),
+            ..Default::default()
+        };
+        let mut ids = vec![];
+        for _ in 0..50 {
+            sleep(Duration::from_millis(100)).await;
+            let reader = db.reader()?;
+            ids = db
+                .iter::<Event, _>(&reader, &filter)?
+                .map(|e| e.map(|e| e.id_str()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !ids.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(ids, vec![note.id_str()]);
+        drop(app);
+        Ok(())
+    }
+}
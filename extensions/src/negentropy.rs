@@ -0,0 +1,1030 @@
+use crate::client::{connect, Framed};
+use actix::{
+    clock::{sleep, timeout},
+    Arbiter,
+};
+use actix_web::web;
+use awc::ws::{Frame, Message};
+use futures_channel::oneshot;
+use futures_util::{future::select, SinkExt, StreamExt};
+use metrics::{counter, describe_counter};
+use nostr_relay::{
+    db::{Db, Event, Filter},
+    duration::NonZeroDuration,
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    App, Extension, ExtensionMessageResult, Session,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+/// [negentropy](https://github.com/hoytech/negentropy) protocol version 1
+const PROTOCOL_VERSION: u8 = 0x61;
+const FINGERPRINT_SIZE: usize = 16;
+/// split a mismatched range into buckets, send the ids when it is small
+const BUCKETS: usize = 16;
+const MODE_SKIP: u64 = 0;
+const MODE_FINGERPRINT: u64 = 1;
+const MODE_ID_LIST: u64 = 2;
+
+/// open reconciliations of a session
+const MAX_RECONCILIATIONS: usize = 4;
+/// ids of each REQ fetching or EVENT batch pushing the difference
+const BATCH_SIZE: usize = 100;
+/// wait for the response of the peer
+const RECV_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Id = [u8; 32];
+
+/// An event of the reconciled set, ordered by created_at then id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Item {
+    pub timestamp: u64,
+    pub id: Id,
+}
+
+/// The upper bound of a range, the id is a prefix padded with zeros
+#[derive(Debug, Clone)]
+struct Bound {
+    item: Item,
+    len: usize,
+}
+
+impl Bound {
+    fn new(timestamp: u64) -> Self {
+        Self {
+            item: Item {
+                timestamp,
+                id: [0; 32],
+            },
+            len: 0,
+        }
+    }
+
+    fn infinity() -> Self {
+        Self::new(u64::MAX)
+    }
+
+    fn from_item(item: &Item) -> Self {
+        Self {
+            item: *item,
+            len: 32,
+        }
+    }
+
+    /// the shortest bound between the two items
+    fn minimal(prev: &Item, curr: &Item) -> Self {
+        if curr.timestamp != prev.timestamp {
+            return Self::new(curr.timestamp);
+        }
+        let len = prev
+            .id
+            .iter()
+            .zip(curr.id.iter())
+            .take_while(|(a, b)| a == b)
+            .count()
+            + 1;
+        let mut bound = Self::new(curr.timestamp);
+        bound.item.id[..len].copy_from_slice(&curr.id[..len]);
+        bound.len = len;
+        bound
+    }
+}
+
+fn encode_varint(mut n: u64, out: &mut Vec<u8>) {
+    let mut buf = [0u8; 10];
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = (n & 0x7f) as u8 | if i == buf.len() - 1 { 0 } else { 0x80 };
+        n >>= 7;
+        if n == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(&buf[i..]);
+}
+
+/// the timestamps are delta encoded in a message
+#[derive(Default)]
+struct Encoder {
+    last: u64,
+}
+
+impl Encoder {
+    fn bound(&mut self, bound: &Bound, out: &mut Vec<u8>) {
+        let timestamp = bound.item.timestamp;
+        if timestamp == u64::MAX {
+            self.last = u64::MAX;
+            encode_varint(0, out);
+        } else {
+            encode_varint(timestamp.wrapping_sub(self.last) + 1, out);
+            self.last = timestamp;
+        }
+        encode_varint(bound.len as u64, out);
+        out.extend_from_slice(&bound.item.id[..bound.len]);
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    last: u64,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("parse ends prematurely".to_owned());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n = 0u64;
+        loop {
+            let byte = self.bytes(1)?[0];
+            if n > u64::MAX >> 7 {
+                return Err("varint overflow".to_owned());
+            }
+            n = (n << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn timestamp(&mut self) -> Result<u64, String> {
+        let timestamp = match self.varint()? {
+            0 => u64::MAX,
+            n => n - 1,
+        };
+        if self.last == u64::MAX || timestamp == u64::MAX {
+            self.last = u64::MAX;
+        } else {
+            self.last = self.last.saturating_add(timestamp);
+        }
+        Ok(self.last)
+    }
+
+    fn bound(&mut self) -> Result<Bound, String> {
+        let mut bound = Bound::new(self.timestamp()?);
+        let len = self.varint()? as usize;
+        if len > 32 {
+            return Err("bound key too long".to_owned());
+        }
+        bound.item.id[..len].copy_from_slice(self.bytes(len)?);
+        bound.len = len;
+        Ok(bound)
+    }
+}
+
+/// sha256 of the sum of the ids and the count
+fn fingerprint(items: &[Item]) -> [u8; FINGERPRINT_SIZE] {
+    let mut sum = [0u8; 32];
+    for item in items {
+        // add as little-endian 256-bit numbers
+        let mut carry = 0u16;
+        for (s, b) in sum.iter_mut().zip(item.id.iter()) {
+            let n = *s as u16 + *b as u16 + carry;
+            *s = n as u8;
+            carry = n >> 8;
+        }
+    }
+    let mut count = vec![];
+    encode_varint(items.len() as u64, &mut count);
+    let hash = Sha256::new()
+        .chain_update(sum)
+        .chain_update(count)
+        .finalize();
+    let mut fp = [0u8; FINGERPRINT_SIZE];
+    fp.copy_from_slice(&hash[..FINGERPRINT_SIZE]);
+    fp
+}
+
+/// Negentropy set reconciliation of the items,
+/// the initiator finds the ids only it has and the ids only the other side has.
+#[derive(Debug)]
+pub struct Reconciler {
+    items: Vec<Item>,
+    /// max size of a message, unlimited when 0
+    frame_size_limit: usize,
+    initiator: bool,
+}
+
+impl Reconciler {
+    pub fn new(mut items: Vec<Item>, frame_size_limit: usize) -> Self {
+        items.sort_unstable();
+        items.dedup();
+        Self {
+            items,
+            frame_size_limit: if frame_size_limit == 0 {
+                0
+            } else {
+                frame_size_limit.max(4096)
+            },
+            initiator: false,
+        }
+    }
+
+    /// the first message of the initiator
+    pub fn initiate(&mut self) -> Vec<u8> {
+        self.initiator = true;
+        let mut out = vec![PROTOCOL_VERSION];
+        self.split_range(
+            &mut Encoder::default(),
+            0,
+            self.items.len(),
+            &Bound::infinity(),
+            &mut out,
+        );
+        out
+    }
+
+    /// respond to the message of the initiator
+    pub fn reconcile(&self, query: &[u8]) -> Result<Vec<u8>, String> {
+        if self.initiator {
+            return Err("initiator must not respond".to_owned());
+        }
+        self.reconcile_aux(query, &mut vec![], &mut vec![])
+    }
+
+    /// handle the response, collect the differences, returns the next message or none when finished
+    pub fn reconcile_with_ids(
+        &self,
+        query: &[u8],
+        have: &mut Vec<Id>,
+        need: &mut Vec<Id>,
+    ) -> Result<Option<Vec<u8>>, String> {
+        if !self.initiator {
+            return Err("non-initiator asking for have/need ids".to_owned());
+        }
+        let out = self.reconcile_aux(query, have, need)?;
+        Ok(if out.len() == 1 { None } else { Some(out) })
+    }
+
+    fn lower_bound(&self, lower: usize, upper: usize, bound: &Bound) -> usize {
+        lower + self.items[lower..upper].partition_point(|item| *item < bound.item)
+    }
+
+    fn exceeded(&self, len: usize) -> bool {
+        self.frame_size_limit != 0 && len > self.frame_size_limit - 200
+    }
+
+    fn reconcile_aux(
+        &self,
+        query: &[u8],
+        have: &mut Vec<Id>,
+        need: &mut Vec<Id>,
+    ) -> Result<Vec<u8>, String> {
+        let mut decoder = Decoder {
+            data: query,
+            last: 0,
+        };
+        let mut encoder = Encoder::default();
+        let mut full = vec![PROTOCOL_VERSION];
+
+        let version = decoder.bytes(1)?[0];
+        if !(0x60..=0x6f).contains(&version) {
+            return Err("invalid negentropy protocol version byte".to_owned());
+        }
+        if version != PROTOCOL_VERSION {
+            if self.initiator {
+                return Err(format!(
+                    "unsupported negentropy protocol version {:#x}",
+                    version
+                ));
+            }
+            // tell the initiator the supported version
+            return Ok(full);
+        }
+
+        let size = self.items.len();
+        let mut prev_bound = Bound::new(0);
+        let mut prev_index = 0;
+        let mut skip = false;
+
+        while !decoder.data.is_empty() {
+            let mut out = vec![];
+            let curr_bound = decoder.bound()?;
+            let mode = decoder.varint()?;
+            let lower = prev_index;
+            let mut upper = self.lower_bound(prev_index, size, &curr_bound);
+
+            let do_skip = |skip: &mut bool, encoder: &mut Encoder, out: &mut Vec<u8>| {
+                if *skip {
+                    *skip = false;
+                    encoder.bound(&prev_bound, out);
+                    encode_varint(MODE_SKIP, out);
+                }
+            };
+
+            match mode {
+                MODE_SKIP => skip = true,
+                MODE_FINGERPRINT => {
+                    let theirs = decoder.bytes(FINGERPRINT_SIZE)?;
+                    if theirs != fingerprint(&self.items[lower..upper]) {
+                        do_skip(&mut skip, &mut encoder, &mut out);
+                        self.split_range(&mut encoder, lower, upper, &curr_bound, &mut out);
+                    } else {
+                        skip = true;
+                    }
+                }
+                MODE_ID_LIST => {
+                    let num = decoder.varint()?;
+                    let mut theirs = HashSet::new();
+                    for _ in 0..num {
+                        let mut id = [0u8; 32];
+                        id.copy_from_slice(decoder.bytes(32)?);
+                        theirs.insert(id);
+                    }
+                    for item in &self.items[lower..upper] {
+                        if !theirs.remove(&item.id) && self.initiator {
+                            have.push(item.id);
+                        }
+                    }
+
+                    if self.initiator {
+                        skip = true;
+                        need.extend(theirs);
+                    } else {
+                        do_skip(&mut skip, &mut encoder, &mut out);
+                        let mut ids = vec![];
+                        let mut end_bound = curr_bound.clone();
+                        for (index, item) in self.items[lower..upper].iter().enumerate() {
+                            if self.exceeded(full.len() + ids.len()) {
+                                end_bound = Bound::from_item(item);
+                                upper = lower + index;
+                                break;
+                            }
+                            ids.extend_from_slice(&item.id);
+                        }
+                        encoder.bound(&end_bound, &mut out);
+                        encode_varint(MODE_ID_LIST, &mut out);
+                        encode_varint((ids.len() / 32) as u64, &mut out);
+                        out.extend(ids);
+                        full.append(&mut out);
+                    }
+                }
+                _ => return Err("unexpected mode".to_owned()),
+            }
+
+            if self.exceeded(full.len() + out.len()) {
+                // send a fingerprint of the remaining range
+                encoder.bound(&Bound::infinity(), &mut full);
+                encode_varint(MODE_FINGERPRINT, &mut full);
+                full.extend(fingerprint(&self.items[upper..size]));
+                break;
+            }
+            full.append(&mut out);
+            prev_index = upper;
+            prev_bound = curr_bound;
+        }
+        Ok(full)
+    }
+
+    fn split_range(
+        &self,
+        encoder: &mut Encoder,
+        lower: usize,
+        upper: usize,
+        upper_bound: &Bound,
+        out: &mut Vec<u8>,
+    ) {
+        let num = upper - lower;
+        if num < BUCKETS * 2 {
+            encoder.bound(upper_bound, out);
+            encode_varint(MODE_ID_LIST, out);
+            encode_varint(num as u64, out);
+            for item in &self.items[lower..upper] {
+                out.extend_from_slice(&item.id);
+            }
+            return;
+        }
+        let per_bucket = num / BUCKETS;
+        let with_extra = num % BUCKETS;
+        let mut curr = lower;
+        for i in 0..BUCKETS {
+            let size = per_bucket + usize::from(i < with_extra);
+            let fp = fingerprint(&self.items[curr..curr + size]);
+            curr += size;
+            let next = if curr == upper {
+                upper_bound.clone()
+            } else {
+                Bound::minimal(&self.items[curr - 1], &self.items[curr])
+            };
+            encoder.bound(&next, out);
+            encode_varint(MODE_FINGERPRINT, out);
+            out.extend(fp);
+        }
+    }
+}
+
+/// read the items of the stored events matching the filter
+fn load(
+    db: &Db,
+    filter: &Filter,
+    max_items: usize,
+    time: Option<NonZeroDuration>,
+) -> Result<Vec<Item>, String> {
+    let reader = db.reader().map_err(|e| e.to_string())?;
+    let mut iter = db
+        .iter::<Event, _>(&reader, filter)
+        .map_err(|e| e.to_string())?;
+    if let Some(time) = time {
+        iter.scan_time(time.into(), 2000);
+    }
+    let mut items = vec![];
+    for event in iter {
+        let event = event.map_err(|e| e.to_string())?;
+        if items.len() >= max_items {
+            return Err("blocked: too many events, narrow the filter".to_owned());
+        }
+        items.push(Item {
+            timestamp: event.created_at(),
+            id: *event.id(),
+        });
+    }
+    Ok(items)
+}
+
+/// parse the filter of the reconciliation, the limit is ignored
+fn parse_filter(value: &Value) -> Result<Filter, String> {
+    let mut filter: Filter = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+    filter.limit = None;
+    Ok(filter)
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// fetch the missing events and push the events the peer misses
+    #[default]
+    Both,
+    Fetch,
+    Push,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct NegentropyPeer {
+    /// websocket url of the peer relay supporting [NIP-77](https://nips.be/77)
+    pub url: String,
+    /// [NIP-01](https://nips.be/1) filters reconciled one by one, all events when empty
+    #[serde(default)]
+    pub filters: Vec<Value>,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+impl NegentropyPeer {
+    /// check the filters
+    pub fn parse_filters(&self) -> Result<Vec<Filter>, String> {
+        self.filters.iter().map(parse_filter).collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NegentropySetting {
+    pub enabled: bool,
+    /// max events of a reconciliation, the larger ones are rejected
+    pub max_items: usize,
+    /// max bytes of a reconciliation message before hex encoding
+    pub frame_size_limit: usize,
+    /// delay between the reconciliations with the peers
+    pub interval: NonZeroDuration,
+    pub peers: Vec<NegentropyPeer>,
+}
+
+impl Default for NegentropySetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_items: 1_000_000,
+            frame_size_limit: 60_000,
+            interval: Duration::from_secs(3600).try_into().unwrap(),
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// The open reconciliations of a session
+#[derive(Default)]
+struct Reconciliations(HashMap<String, Reconciler>);
+
+/// Serve [NIP-77](https://nips.be/77) negentropy syncing,
+/// and reconcile the events with the peer relays on schedule
+pub struct Negentropy {
+    pub setting: NegentropySetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    app: App,
+    /// runs the peer tasks, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    /// stop the peer tasks when dropped
+    stops: Vec<oneshot::Sender<()>>,
+}
+
+impl Negentropy {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_negentropy_fetched_total",
+            "The total count of missing events fetched from the peers"
+        );
+        describe_counter!(
+            "nostr_relay_negentropy_pushed_total",
+            "The total count of events pushed to the peers"
+        );
+        Self {
+            setting: NegentropySetting::default(),
+            proxy: None,
+            app,
+            arbiter: Arbiter::new(),
+            stops: Vec::new(),
+        }
+    }
+
+    fn open(&self, session: &mut Session, id: &str, args: &[Value]) -> Result<Vec<u8>, String> {
+        let (Some(filter), Some(query)) = (args.get(1), args.get(2).and_then(Value::as_str)) else {
+            return Err("invalid: NEG-OPEN requires a filter and a message".to_owned());
+        };
+        let filter = parse_filter(filter).map_err(|e| format!("invalid: {}", e))?;
+        let query = hex::decode(query).map_err(|e| format!("invalid: {}", e))?;
+
+        let reconciliations = session.get::<Reconciliations>();
+        if reconciliations.map_or(0, |r| r.0.len()) >= MAX_RECONCILIATIONS
+            && !reconciliations.is_some_and(|r| r.0.contains_key(id))
+        {
+            return Err("blocked: too many open reconciliations".to_owned());
+        }
+
+        let time = session.app.setting.read().data.db_query_timeout;
+        let items = load(&session.app.db, &filter, self.setting.max_items, time)?;
+        let reconciler = Reconciler::new(items, self.setting.frame_size_limit);
+        let out = reconciler.reconcile(&query)?;
+        if session.get::<Reconciliations>().is_none() {
+            session.set(Reconciliations::default());
+        }
+        if let Some(r) = session.get_mut::<Reconciliations>() {
+            r.0.insert(id.to_owned(), reconciler);
+        }
+        Ok(out)
+    }
+
+    fn reconcile(
+        &self,
+        session: &mut Session,
+        id: &str,
+        args: &[Value],
+    ) -> Result<Vec<u8>, String> {
+        let Some(query) = args.get(1).and_then(Value::as_str) else {
+            return Err("invalid: NEG-MSG requires a message".to_owned());
+        };
+        let query = hex::decode(query).map_err(|e| format!("invalid: {}", e))?;
+        session
+            .get::<Reconciliations>()
+            .and_then(|r| r.0.get(id))
+            .ok_or_else(|| "closed: unknown subscription".to_owned())?
+            .reconcile(&query)
+    }
+}
+
+impl Extension for Negentropy {
+    fn name(&self) -> &'static str {
+        "negentropy"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        let negentropy: NegentropySetting = w.parse_extension(self.name());
+        if negentropy.enabled {
+            w.add_nip(77);
+        }
+        if negentropy == self.setting && w.network.proxy == self.proxy {
+            return;
+        }
+        self.setting = negentropy;
+        self.proxy = w.network.proxy.clone();
+        self.stops.clear();
+        if !self.setting.enabled {
+            return;
+        }
+
+        for peer in &self.setting.peers {
+            let (tx, rx) = oneshot::channel();
+            let (peer, app, proxy) = (peer.clone(), self.app.clone(), self.proxy.clone());
+            let setting = self.setting.clone();
+            // the websocket client is not Send, create the task in the arbiter
+            self.arbiter.spawn_fn(move || {
+                actix::spawn(async move {
+                    select(Box::pin(run(peer, proxy, app, setting)), rx).await;
+                });
+            });
+            self.stops.push(tx);
+        }
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        let IncomingMessage::Unknown(cmd, args) = &msg.msg else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        if !matches!(cmd.as_str(), "NEG-OPEN" | "NEG-MSG" | "NEG-CLOSE") {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        session.add_nip(77);
+        let Some(id) = args.first().and_then(Value::as_str) else {
+            return OutgoingMessage::notice(&format!(
+                "invalid: {} requires a subscription id",
+                cmd
+            ))
+            .into();
+        };
+
+        let result = match cmd.as_str() {
+            "NEG-OPEN" => self.open(session, id, args),
+            "NEG-MSG" => self.reconcile(session, id, args),
+            _ => {
+                if let Some(r) = session.get_mut::<Reconciliations>() {
+                    r.0.remove(id);
+                }
+                return ExtensionMessageResult::Ignore;
+            }
+        };
+        match result {
+            Ok(out) => OutgoingMessage(json!(["NEG-MSG", id, hex::encode(out)]).to_string()).into(),
+            Err(err) => {
+                if let Some(r) = session.get_mut::<Reconciliations>() {
+                    r.0.remove(id);
+                }
+                OutgoingMessage(json!(["NEG-ERR", id, err]).to_string()).into()
+            }
+        }
+    }
+}
+
+/// reconcile with the peer on schedule until stopped
+async fn run(peer: NegentropyPeer, proxy: Option<String>, app: App, setting: NegentropySetting) {
+    loop {
+        match connect(&peer.url, proxy.as_deref()).await {
+            Ok(mut framed) => {
+                match sync(&peer, &app, &setting, &mut framed).await {
+                    Ok((fetched, pushed)) => {
+                        info!(peer = peer.url, fetched, pushed, "negentropy synced")
+                    }
+                    Err(err) => warn!(peer = peer.url, error = err, "negentropy sync failed"),
+                }
+                let _ = framed.close().await;
+            }
+            Err(err) => warn!(peer = peer.url, error = err, "negentropy connect failed"),
+        }
+        sleep(setting.interval.into()).await;
+    }
+}
+
+async fn send(framed: &mut Framed, msg: Value) -> Result<(), String> {
+    framed
+        .send(Message::Text(msg.to_string().into()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// the next json message of the peer
+async fn recv(framed: &mut Framed) -> Result<Vec<Value>, String> {
+    loop {
+        let frame = timeout(RECV_TIMEOUT, framed.next())
+            .await
+            .map_err(|_| "timeout".to_owned())?
+            .ok_or_else(|| "closed".to_owned())?;
+        match frame.map_err(|e| e.to_string())? {
+            Frame::Text(text) => {
+                if let Ok(msg) = serde_json::from_slice(&text) {
+                    return Ok(msg);
+                }
+            }
+            Frame::Ping(msg) => framed
+                .send(Message::Pong(msg))
+                .await
+                .map_err(|e| e.to_string())?,
+            Frame::Close(reason) => return Err(format!("closed {:?}", reason)),
+            _ => {}
+        }
+    }
+}
+
+/// reconcile each filter, returns the count of fetched and pushed events
+async fn sync(
+    peer: &NegentropyPeer,
+    app: &App,
+    setting: &NegentropySetting,
+    framed: &mut Framed,
+) -> Result<(usize, usize), String> {
+    let mut filters = peer.filters.clone();
+    if filters.is_empty() {
+        filters.push(Value::Object(Default::default()));
+    }
+    let (mut fetched, mut pushed) = (0, 0);
+    for value in filters {
+        let filter = parse_filter(&value)?;
+        let db = app.db.clone();
+        let max_items = setting.max_items;
+        let items = web::block(move || load(&db, &filter, max_items, None))
+            .await
+            .map_err(|e| e.to_string())??;
+
+        let mut reconciler = Reconciler::new(items, setting.frame_size_limit);
+        let (mut have, mut need) = (vec![], vec![]);
+        let msg = reconciler.initiate();
+        send(framed, json!(["NEG-OPEN", "neg", value, hex::encode(msg)])).await?;
+        loop {
+            let msg = recv(framed).await?;
+            match msg.first().and_then(Value::as_str) {
+                Some("NEG-MSG") => {
+                    let query = msg
+                        .get(2)
+                        .and_then(Value::as_str)
+                        .and_then(|s| hex::decode(s).ok())
+                        .ok_or_else(|| "invalid NEG-MSG".to_owned())?;
+                    match reconciler.reconcile_with_ids(&query, &mut have, &mut need)? {
+                        Some(next) => {
+                            send(framed, json!(["NEG-MSG", "neg", hex::encode(next)])).await?
+                        }
+                        None => break,
+                    }
+                }
+                Some("NEG-ERR") => return Err(format!("negentropy error {:?}", msg.get(2))),
+                Some("NOTICE") => return Err(format!("notice {:?}", msg.get(1))),
+                _ => {}
+            }
+        }
+        send(framed, json!(["NEG-CLOSE", "neg"])).await?;
+        debug!(
+            peer = peer.url,
+            have = have.len(),
+            need = need.len(),
+            "negentropy reconciled"
+        );
+
+        if peer.direction != Direction::Push {
+            fetched += fetch(peer, app, framed, &need).await?;
+        }
+        if peer.direction != Direction::Fetch {
+            pushed += push(peer, app, framed, &have).await?;
+        }
+    }
+    Ok((fetched, pushed))
+}
+
+/// request the missing events and write them through [`App::publish`]
+async fn fetch(
+    peer: &NegentropyPeer,
+    app: &App,
+    framed: &mut Framed,
+    ids: &[Id],
+) -> Result<usize, String> {
+    let mut count = 0;
+    for chunk in ids.chunks(BATCH_SIZE) {
+        let ids = chunk.iter().map(hex::encode).collect::<Vec<_>>();
+        send(framed, json!(["REQ", "neg", {"ids": ids}])).await?;
+        loop {
+            let mut msg = recv(framed).await?;
+            match msg.first().and_then(Value::as_str) {
+                Some("EVENT") if msg.len() > 2 => {
+                    match serde_json::from_value::<Event>(msg[2].take()) {
+                        Ok(event) => {
+                            count += 1;
+                            if let Err(err) = app.publish(event).await {
+                                debug!(
+                                    peer = peer.url,
+                                    error = err.to_string(),
+                                    "negentropy rejected"
+                                );
+                            }
+                        }
+                        Err(err) => {
+                            debug!(
+                                peer = peer.url,
+                                error = err.to_string(),
+                                "negentropy invalid event"
+                            )
+                        }
+                    }
+                }
+                Some("EOSE") => break,
+                Some("CLOSED") => return Err(format!("subscription closed {:?}", msg.get(2))),
+                _ => {}
+            }
+        }
+        send(framed, json!(["CLOSE", "neg"])).await?;
+    }
+    counter!("nostr_relay_negentropy_fetched_total", count as u64, "relay" => peer.url.clone());
+    Ok(count)
+}
+
+/// send the events the peer misses, wait for the results
+async fn push(
+    peer: &NegentropyPeer,
+    app: &App,
+    framed: &mut Framed,
+    ids: &[Id],
+) -> Result<usize, String> {
+    let mut count = 0;
+    for chunk in ids.chunks(BATCH_SIZE) {
+        let db = app.db.clone();
+        let chunk = chunk.to_vec();
+        let events = web::block(move || db.batch_get::<String, _, _>(chunk))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        for event in &events {
+            framed
+                .send(Message::Text(format!(r#"["EVENT",{}]"#, event).into()))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        let mut results = 0;
+        while results < events.len() {
+            let msg = recv(framed).await?;
+            if msg.first().and_then(Value::as_str) == Some("OK") {
+                results += 1;
+            }
+        }
+        count += events.len();
+    }
+    counter!("nostr_relay_negentropy_pushed_total", count as u64, "relay" => peer.url.clone());
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+    use nostr_relay::{
+        create_web_app,
+        db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+        },
+    };
+
+    fn item(timestamp: u64, n: u64) -> Item {
+        let mut id = [0u8; 32];
+        id[..8].copy_from_slice(&n.to_be_bytes());
+        // spread the ids
+        id[8..].copy_from_slice(&Sha256::digest(n.to_be_bytes())[..24]);
+        Item { timestamp, id }
+    }
+
+    /// reconcile in memory, returns the sorted have and need ids
+    fn run_sync(client: Vec<Item>, server: Vec<Item>, limit: usize) -> (Vec<Id>, Vec<Id>) {
+        let mut client = Reconciler::new(client, limit);
+        let server = Reconciler::new(server, limit);
+        let (mut have, mut need) = (vec![], vec![]);
+        let mut msg = client.initiate();
+        let mut rounds = 0;
+        loop {
+            let res = server.reconcile(&msg).unwrap();
+            if limit != 0 {
+                assert!(res.len() <= limit);
+            }
+            match client
+                .reconcile_with_ids(&res, &mut have, &mut need)
+                .unwrap()
+            {
+                Some(next) => msg = next,
+                None => break,
+            }
+            rounds += 1;
+            assert!(rounds < 100);
+        }
+        have.sort();
+        need.sort();
+        (have, need)
+    }
+
+    #[test]
+    fn varint() {
+        for n in [0u64, 1, 127, 128, 255, 16384, u64::MAX] {
+            let mut out = vec![];
+            encode_varint(n, &mut out);
+            let mut decoder = Decoder {
+                data: &out,
+                last: 0,
+            };
+            assert_eq!(decoder.varint().unwrap(), n);
+            assert!(decoder.data.is_empty());
+        }
+        let mut out = vec![];
+        encode_varint(300, &mut out);
+        assert_eq!(out, vec![0x82, 0x2c]);
+    }
+
+    #[test]
+    fn reconcile() {
+        // small sets are sent as id lists
+        let (have, need) = run_sync(
+            vec![item(1, 1), item(2, 2), item(3, 3)],
+            vec![item(2, 2), item(3, 3), item(4, 4)],
+            0,
+        );
+        assert_eq!(have, vec![item(1, 1).id]);
+        assert_eq!(need, vec![item(4, 4).id]);
+
+        let (have, need) = run_sync(vec![], vec![], 0);
+        assert!(have.is_empty() && need.is_empty());
+
+        // fingerprint buckets, shared timestamps and frame size limit
+        for limit in [0, 4096] {
+            let mut client = vec![];
+            let mut server = vec![];
+            let mut expect_have = vec![];
+            let mut expect_need = vec![];
+            for n in 0..5000u64 {
+                let it = item(n / 7, n);
+                match n % 13 {
+                    0 => {
+                        client.push(it);
+                        expect_have.push(it.id);
+                    }
+                    1 => {
+                        server.push(it);
+                        expect_need.push(it.id);
+                    }
+                    _ => {
+                        client.push(it);
+                        server.push(it);
+                    }
+                }
+            }
+            expect_have.sort();
+            expect_need.sort();
+            let (have, need) = run_sync(client, server, limit);
+            assert_eq!(have, expect_have);
+            assert_eq!(need, expect_need);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn sync() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..3)
+            .map(|i| Event::create(&key_pair, now(), 1, vec![], format!("note {}", i)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let peer = create_test_app("negentropy_peer")?;
+        peer.setting.write().extra =
+            serde_json::from_value(json!({"negentropy": {"enabled": true}}))?;
+        let peer = peer.clone().add_extension(Negentropy::new(peer));
+        let peer_db = peer.db.clone();
+        peer_db.batch_put([&events[0], &events[1]])?;
+        let peer = web::Data::new(peer);
+        let srv = actix_test::start(move || create_web_app(peer.clone()));
+
+        let app = create_test_app("negentropy")?;
+        app.db.batch_put([&events[1], &events[2]])?;
+        app.setting.write().extra = serde_json::from_value(json!({
+            "negentropy": {
+                "enabled": true,
+                "peers": [{
+                    "url": srv.url("/").replace("http", "ws"),
+                    "filters": [{"kinds": [1]}]
+                }]
+            }
+        }))?;
+        let db = app.db.clone();
+        let app = app.clone().add_extension(Negentropy::new(app));
+
+        let filter = Filter::default();
+        let mut counts = (0, 0);
+        for _ in 0..50 {
+            sleep(Duration::from_millis(100)).await;
+            counts = (
+                db.iter::<Event, _>(&db.reader()?, &filter)?.count(),
+                peer_db
+                    .iter::<Event, _>(&peer_db.reader()?, &filter)?
+                    .count(),
+            );
+            if counts == (3, 3) {
+                break;
+            }
+        }
+        assert_eq!(counts, (3, 3));
+        drop(app);
+        Ok(())
+    }
+}
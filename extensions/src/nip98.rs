@@ -0,0 +1,104 @@
+//! [NIP-98](https://nips.be/98) signed http requests of the admin endpoints
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use nostr_relay::{
+    db::{now, secp256k1::KeyPair, Event},
+    List,
+};
+use sha2::{Digest, Sha256};
+
+/// NIP-98 event kind
+pub const HTTP_AUTH_KIND: u16 = 27235;
+
+/// Verify the authorization header of the request, returns the pubkey.
+/// The scheme of the url is not compared, the relay may be behind a tls proxy.
+/// The payload tag is required by the requests with a body.
+pub fn verify_auth(req: &HttpRequest, body: &[u8]) -> Result<String, &'static str> {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .ok_or("missing authorization header")?;
+    let token = header
+        .strip_prefix("Nostr ")
+        .ok_or("invalid authorization scheme")?;
+    let data = STANDARD
+        .decode(token.trim())
+        .map_err(|_| "invalid authorization token")?;
+    let event: Event = serde_json::from_slice(&data).map_err(|_| "invalid authorization event")?;
+
+    if event.kind() != HTTP_AUTH_KIND {
+        return Err("invalid authorization event kind");
+    }
+    if now().abs_diff(event.created_at()) > 60 {
+        return Err("authorization event expired");
+    }
+    event
+        .verify_id()
+        .and_then(|_| event.verify_sign())
+        .map_err(|_| "invalid authorization signature")?;
+
+    let tag = |name: &str| {
+        event
+            .tags()
+            .iter()
+            .find(|t| t.len() > 1 && t[0] == name)
+            .map(|t| t[1].as_str())
+    };
+    let info = req.connection_info();
+    let url = format!("{}{}", info.host(), req.uri());
+    if tag("u").map(strip_url) != Some(strip_url(&url)) {
+        return Err("authorization url mismatch");
+    }
+    if tag("method") != Some(req.method().as_str()) {
+        return Err("authorization method mismatch");
+    }
+    let payload = format!("{:x}", Sha256::digest(body));
+    match tag("payload") {
+        Some(p) if p != payload => return Err("authorization payload mismatch"),
+        None if !body.is_empty() => return Err("authorization payload mismatch"),
+        _ => {}
+    }
+    Ok(event.pubkey_str())
+}
+
+/// Verify the authorization header is signed by one of the admin pubkeys, returns the pubkey.
+pub fn verify_admin(
+    req: &HttpRequest,
+    body: &[u8],
+    admin_pubkeys: Option<&List>,
+) -> Result<String, &'static str> {
+    let pubkey = verify_auth(req, body)?;
+    if admin_pubkeys.is_some_and(|l| l.contains(&pubkey)) {
+        Ok(pubkey)
+    } else {
+        Err("pubkey is not an admin")
+    }
+}
+
+/// The authorization header of the request, the payload tag is added for the body.
+pub fn auth_header(
+    key_pair: &KeyPair,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> nostr_relay::Result<String> {
+    let mut tags = vec![
+        vec!["u".to_owned(), url.to_owned()],
+        vec!["method".to_owned(), method.to_owned()],
+    ];
+    if !body.is_empty() {
+        tags.push(vec![
+            "payload".to_owned(),
+            format!("{:x}", Sha256::digest(body)),
+        ]);
+    }
+    let event = Event::create(key_pair, now(), HTTP_AUTH_KIND, tags, "".to_owned())?;
+    Ok(format!("Nostr {}", STANDARD.encode(event.to_string())))
+}
+
+fn strip_url(url: &str) -> &str {
+    url.split_once("://")
+        .map_or(url, |(_, u)| u)
+        .trim_end_matches('/')
+}
@@ -0,0 +1,443 @@
+//! Onboarding requirements of the new pubkeys, the events other than the kind-0 profile are rejected
+//! until the author has a stored profile, optionally with a [NIP-05](https://nips.be/05) identifier verified by its domain.
+//! The authors with stored events of the other kinds are grandfathered.
+use crate::client::http_client;
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{now, Db, Event, Exclude, Filter},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::error;
+
+/// prune the expired results when the cache is larger
+const CACHE_SIZE: usize = 10_000;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OnboardingSetting {
+    pub enabled: bool,
+    /// the profile must have a NIP-05 identifier verified by its domain
+    pub nip05: bool,
+    /// accept the authors with stored events of the other kinds, ie: published before the requirement
+    pub grandfather: bool,
+    /// the document of the identifier, `{domain}` and `{name}` are replaced
+    pub nip05_url: String,
+    /// timeout of the verification request
+    #[serde(with = "nostr_relay::duration")]
+    pub timeout: Duration,
+    /// how long an author stays accepted or an identifier stays unverified before checking again
+    #[serde(with = "nostr_relay::duration")]
+    pub cache_ttl: Duration,
+}
+
+impl Default for OnboardingSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            nip05: false,
+            grandfather: true,
+            nip05_url: "https://{domain}/.well-known/nostr.json?name={name}".to_owned(),
+            timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// The url of the document and the name of the identifier `<name>@<domain>`, `_@<domain>` for the domain itself
+pub fn nip05_url(template: &str, identifier: &str) -> Option<(String, String)> {
+    let identifier = identifier.trim().to_lowercase();
+    let (name, domain) = match identifier.rsplit_once('@') {
+        Some((name, domain)) => (name, domain),
+        None => ("_", identifier.as_str()),
+    };
+    let valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    // a host name, the IP literals and the ports are not fetched
+    let valid_domain = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && domain
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| tld.chars().any(|c| c.is_ascii_alphabetic()));
+    if !valid_name || !valid_domain {
+        return None;
+    }
+    let url = template.replace("{domain}", domain).replace("{name}", name);
+    Some((url, name.to_owned()))
+}
+
+/// Fetch the document of the identifier through the proxy, true if the name points to the pubkey
+pub async fn verify_nip05(
+    url: &str,
+    name: &str,
+    pubkey: &str,
+    timeout: Duration,
+    proxy: Option<&str>,
+) -> bool {
+    let client = match http_client(proxy, timeout) {
+        Ok(client) => client,
+        Err(e) => {
+            error!(error = e, "failed to verify the NIP-05 identifier");
+            return false;
+        }
+    };
+    let mut res = match client.get(url).send().await {
+        Ok(res) if res.status().is_success() => res,
+        _ => return false,
+    };
+    match res.json::<Value>().await {
+        Ok(doc) => doc["names"][name]
+            .as_str()
+            .is_some_and(|p| p.eq_ignore_ascii_case(pubkey)),
+        Err(_) => false,
+    }
+}
+
+/// The last check of an author
+#[derive(Debug, Clone)]
+struct Checked {
+    /// the identifier of the profile, empty when not required
+    nip05: String,
+    accepted: bool,
+    until: u64,
+}
+
+#[derive(Debug)]
+enum Status {
+    Accepted,
+    Rejected(&'static str),
+    /// the identifier of the profile needs the verification
+    Verify(String),
+}
+
+pub struct Onboarding {
+    pub setting: OnboardingSetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    db: Arc<Db>,
+    checked: Arc<Mutex<HashMap<String, Checked>>>,
+}
+
+impl Onboarding {
+    pub fn new(db: Arc<Db>) -> Self {
+        describe_counter!(
+            "nostr_relay_onboarding_rejected_total",
+            "The total count of events rejected by the onboarding requirements"
+        );
+        Self {
+            setting: OnboardingSetting::default(),
+            proxy: None,
+            db,
+            checked: Default::default(),
+        }
+    }
+
+    fn first(&self, value: Value) -> nostr_relay::Result<Option<Event>> {
+        let mut filter: Filter = serde_json::from_value(value.clone())?;
+        filter.exclude = Exclude::from_value(&value)?;
+        let reader = self.db.reader()?;
+        let mut iter = self.db.iter::<Event, _>(&reader, &filter)?;
+        Ok(iter.next().transpose()?)
+    }
+
+    fn status(&self, pubkey: &str) -> nostr_relay::Result<Status> {
+        let profile = self.first(json!({ "authors": [pubkey], "kinds": [0], "limit": 1 }))?;
+        if self.setting.grandfather
+            && self
+                .first(json!({ "authors": [pubkey], "!kinds": [0], "limit": 1 }))?
+                .is_some()
+        {
+            return Ok(Status::Accepted);
+        }
+        let Some(profile) = profile else {
+            return Ok(Status::Rejected(
+                "restricted: a profile (kind 0) is required before publishing",
+            ));
+        };
+        if !self.setting.nip05 {
+            return Ok(Status::Accepted);
+        }
+        let nip05 = serde_json::from_str::<Value>(profile.content())
+            .ok()
+            .and_then(|v| v["nip05"].as_str().map(str::to_owned))
+            .unwrap_or_default();
+        if nip05.is_empty() {
+            return Ok(Status::Rejected(
+                "restricted: a verified NIP-05 identifier is required",
+            ));
+        }
+        let unverified = self
+            .checked
+            .lock()
+            .get(pubkey)
+            .is_some_and(|c| !c.accepted && c.nip05 == nip05 && c.until > now());
+        if unverified {
+            return Ok(Status::Rejected(
+                "restricted: the NIP-05 identifier is not verified",
+            ));
+        }
+        Ok(Status::Verify(nip05))
+    }
+
+    fn is_accepted(&self, pubkey: &str) -> bool {
+        self.checked
+            .lock()
+            .get(pubkey)
+            .is_some_and(|c| c.accepted && c.until > now())
+    }
+}
+
+fn remember(
+    checked: &Mutex<HashMap<String, Checked>>,
+    pubkey: String,
+    nip05: String,
+    accepted: bool,
+    ttl: Duration,
+) {
+    let time = now();
+    let mut checked = checked.lock();
+    if checked.len() >= CACHE_SIZE {
+        checked.retain(|_, c| c.until > time);
+    }
+    checked.insert(
+        pubkey,
+        Checked {
+            nip05,
+            accepted,
+            until: time + ttl.as_secs(),
+        },
+    );
+}
+
+fn reject(event: &Event, message: &str) -> ExtensionMessageResult {
+    increment_counter!("nostr_relay_onboarding_rejected_total");
+    OutgoingMessage::ok(&event.id_str(), false, message).into()
+}
+
+impl Extension for Onboarding {
+    fn name(&self) -> &'static str {
+        "onboarding"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        self.proxy = w.network.proxy.clone();
+        w.set_extension(self.setting.clone());
+        self.checked.lock().clear();
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        let IncomingMessage::Event(event) = &msg.msg else {
+            return ExtensionMessageResult::Continue(msg);
+        };
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        let pubkey = event.pubkey_str();
+        if event.kind() == 0 {
+            // check the new profile next time
+            self.checked.lock().remove(&pubkey);
+            return ExtensionMessageResult::Continue(msg);
+        }
+        if self.is_accepted(&pubkey) {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        let ttl = self.setting.cache_ttl;
+        match self.status(&pubkey) {
+            Ok(Status::Accepted) => {
+                remember(&self.checked, pubkey, String::new(), true, ttl);
+                ExtensionMessageResult::Continue(msg)
+            }
+            Ok(Status::Rejected(message)) => reject(event, message),
+            Ok(Status::Verify(nip05)) => {
+                let Some((url, name)) = nip05_url(&self.setting.nip05_url, &nip05) else {
+                    return reject(event, "restricted: the NIP-05 identifier is invalid");
+                };
+                let checked = self.checked.clone();
+                let timeout = self.setting.timeout;
+                let proxy = self.proxy.clone();
+                ExtensionMessageResult::Wait(Box::pin(async move {
+                    let verified =
+                        verify_nip05(&url, &name, &pubkey, timeout, proxy.as_deref()).await;
+                    remember(&checked, pubkey, nip05, verified, ttl);
+                    match &msg.msg {
+                        IncomingMessage::Event(event) if !verified => {
+                            reject(event, "restricted: the NIP-05 identifier is not verified")
+                        }
+                        _ => ExtensionMessageResult::Continue(msg),
+                    }
+                }))
+            }
+            Err(e) => {
+                error!(error = e.to_string(), "failed to check the onboarding");
+                OutgoingMessage::ok(&event.id_str(), false, "error: failed to check the author")
+                    .into()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web::{web, HttpResponse};
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::db::secp256k1::{rand::thread_rng, KeyPair};
+
+    #[test]
+    fn url() {
+        let template = OnboardingSetting::default().nip05_url;
+        assert_eq!(
+            nip05_url(&template, "Bob@Example.com"),
+            Some((
+                "https://example.com/.well-known/nostr.json?name=bob".to_owned(),
+                "bob".to_owned()
+            ))
+        );
+        assert_eq!(
+            nip05_url(&template, "example.com").unwrap().1,
+            "_".to_owned()
+        );
+        assert!(nip05_url(&template, "bob@example.com/x").is_none());
+        assert!(nip05_url(&template, "b?b@example.com").is_none());
+        assert!(nip05_url(&template, "bob@localhost").is_none());
+        assert!(nip05_url(&template, "@example.com").is_none());
+        assert!(nip05_url(&template, "_@127.0.0.1").is_none());
+        assert!(nip05_url(&template, "_@example.com:8080").is_none());
+        assert!(nip05_url(&template, "_@[::1]").is_none());
+        assert!(nip05_url(&template, "bob@example..com").is_none());
+    }
+
+    // the message of the OK
+    async fn send(
+        framed: &mut actix_codec::Framed<
+            impl actix_codec::AsyncRead + actix_codec::AsyncWrite + Unpin,
+            awc::ws::Codec,
+        >,
+        event: &Event,
+    ) -> String {
+        let msg = ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into());
+        framed.send(msg).await.unwrap();
+        let ws::Frame::Text(text) = framed.next().await.unwrap().unwrap() else {
+            panic!("invalid frame type");
+        };
+        let ok: (String, String, bool, String) = serde_json::from_slice(&text).unwrap();
+        ok.3
+    }
+
+    fn event(key_pair: &KeyPair, kind: u16, content: Value) -> Event {
+        let content = if content.is_null() {
+            String::new()
+        } else {
+            content.to_string()
+        };
+        Event::create(key_pair, now(), kind, vec![], content).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn profile() -> Result<()> {
+        let app = create_test_app("onboarding")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({ "onboarding": { "enabled": true } }))?;
+        }
+        let alice = KeyPair::new_global(&mut thread_rng());
+        let bob = KeyPair::new_global(&mut thread_rng());
+        // stored before the requirement
+        app.db.batch_put([&event(&bob, 1, json!("stored"))])?;
+        let extension = Onboarding::new(app.db.clone());
+        let app = app.add_extension(extension);
+        let mut srv = actix_test::start(move || app.clone().web_app());
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        assert_eq!(
+            send(&mut framed, &event(&alice, 1, Value::Null)).await,
+            "restricted: a profile (kind 0) is required before publishing"
+        );
+        assert_eq!(send(&mut framed, &event(&bob, 1, Value::Null)).await, "");
+        let profile = event(&alice, 0, json!({ "name": "alice" }));
+        assert_eq!(send(&mut framed, &profile).await, "");
+        assert_eq!(send(&mut framed, &event(&alice, 1, Value::Null)).await, "");
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn nip05() -> Result<()> {
+        let alice = KeyPair::new_global(&mut thread_rng());
+        let bob = KeyPair::new_global(&mut thread_rng());
+        let alice_pubkey = alice.x_only_public_key().0.to_string();
+        let names = web::Data::new(json!({ "names": { "alice": alice_pubkey } }));
+        let nip05 = actix_test::start(move || {
+            actix_web::App::new().app_data(names.clone()).route(
+                "/.well-known/nostr.json",
+                web::get().to(|names: web::Data<Value>| async move {
+                    HttpResponse::Ok().json(names.get_ref())
+                }),
+            )
+        });
+
+        let app = create_test_app("onboarding_nip05")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(json!({ "onboarding": {
+                "enabled": true,
+                "nip05": true,
+                "nip05_url": nip05.url("/.well-known/nostr.json?name={name}"),
+            } }))?;
+        }
+        let extension = Onboarding::new(app.db.clone());
+        let app = app.add_extension(extension);
+        let mut srv = actix_test::start(move || app.clone().web_app());
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        assert_eq!(
+            send(&mut framed, &event(&alice, 0, json!({ "name": "alice" }))).await,
+            ""
+        );
+        assert_eq!(
+            send(&mut framed, &event(&alice, 1, Value::Null)).await,
+            "restricted: a verified NIP-05 identifier is required"
+        );
+        // replaces the profile
+        let profile = json!({ "nip05": "alice@example.com" }).to_string();
+        let profile = Event::create(&alice, now() + 1, 0, vec![], profile)?;
+        assert_eq!(send(&mut framed, &profile).await, "");
+        assert_eq!(send(&mut framed, &event(&alice, 1, Value::Null)).await, "");
+
+        let profile = json!({ "nip05": "bob@example.com" });
+        assert_eq!(send(&mut framed, &event(&bob, 0, profile)).await, "");
+        for _ in 0..2 {
+            assert_eq!(
+                send(&mut framed, &event(&bob, 1, Value::Null)).await,
+                "restricted: the NIP-05 identifier is not verified"
+            );
+        }
+
+        // through the proxy of the network setting
+        let url = nip05.url("/.well-known/nostr.json?name=alice");
+        let timeout = Duration::from_secs(1);
+        assert!(verify_nip05(&url, "alice", &alice_pubkey, timeout, None).await);
+        let proxy = Some("http://127.0.0.1:1");
+        assert!(!verify_nip05(&url, "alice", &alice_pubkey, timeout, proxy).await);
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+//! Adaptive [NIP-13](https://nips.be/13) proof of work, the difficulty is required under ingest pressure
+//! and from the ips over the soft limit, scaled by how far the ip is over it
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{now, Event},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PowSetting {
+    pub enabled: bool,
+    /// the difficulty required of all events, 0 only under pressure
+    pub min_difficulty: u8,
+    /// the relay is under pressure when it receives more events per second, disabled when 0
+    pub load_events: u32,
+    /// the difficulty required under pressure
+    pub load_difficulty: u8,
+    /// the events per minute of an ip before it is asked for the work, disabled when 0
+    pub soft_events: u32,
+    /// the difficulty added for each `soft_events` over the soft limit
+    pub step: u8,
+    pub max_difficulty: u8,
+}
+
+impl Default for PowSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_difficulty: 0,
+            load_events: 0,
+            load_difficulty: 16,
+            soft_events: 60,
+            step: 4,
+            max_difficulty: 28,
+        }
+    }
+}
+
+impl PowSetting {
+    /// The difficulty required of the ip with the events in the minute, under pressure or not
+    pub fn required(&self, ip_events: u32, pressure: bool) -> u8 {
+        let mut difficulty = self.min_difficulty;
+        if pressure {
+            difficulty = difficulty.max(self.load_difficulty);
+        }
+        if self.soft_events > 0 && ip_events > self.soft_events {
+            let over = ((ip_events - 1) / self.soft_events).min(u8::MAX as u32) as u8;
+            difficulty = difficulty.max(self.step.saturating_mul(over));
+        }
+        difficulty.min(self.max_difficulty)
+    }
+}
+
+/// The leading zero bits of the event id
+pub fn difficulty(id: &[u8]) -> u8 {
+    let mut bits = 0;
+    for b in id {
+        bits += b.leading_zeros();
+        if *b != 0 {
+            break;
+        }
+    }
+    bits as u8
+}
+
+/// Check the work of the event, the committed target of the nonce tag must reach the difficulty too,
+/// so the work for a lower difficulty is not accepted by luck
+pub fn check(event: &Event, required: u8) -> Result<(), String> {
+    if required == 0 {
+        return Ok(());
+    }
+    let actual = difficulty(event.id());
+    if actual < required {
+        return Err(format!(
+            "pow: difficulty {} is less than {}",
+            actual, required
+        ));
+    }
+    let target = event
+        .tags()
+        .iter()
+        .find(|t| t.len() > 2 && t[0] == "nonce")
+        .and_then(|t| t[2].parse::<u8>().ok());
+    if let Some(target) = target {
+        if target < required {
+            return Err(format!(
+                "pow: committed target {} is less than {}",
+                target, required
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The events counted in the current second and minute
+#[derive(Debug, Default)]
+struct Counter {
+    second: u64,
+    events: u32,
+    /// the events of the last second
+    last: u32,
+    minute: u64,
+    ips: HashMap<String, u32>,
+}
+
+impl Counter {
+    /// Count the event of the ip, returns the events of the ip in the minute and the events of the last second
+    fn count(&mut self, ip: &str, time: u64) -> (u32, u32) {
+        if self.second != time {
+            self.last = if self.second + 1 == time {
+                self.events
+            } else {
+                0
+            };
+            self.second = time;
+            self.events = 0;
+        }
+        self.events += 1;
+        if self.minute != time / 60 {
+            self.minute = time / 60;
+            self.ips.clear();
+        }
+        let ip_events = self.ips.entry(ip.to_owned()).or_default();
+        *ip_events += 1;
+        (*ip_events, self.last.max(self.events))
+    }
+}
+
+/// The difficulty last told to the session
+struct PowState(u8);
+
+#[derive(Debug)]
+pub struct Pow {
+    pub setting: PowSetting,
+    counter: Mutex<Counter>,
+}
+
+impl Default for Pow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pow {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_pow_rejected_total",
+            "The total count of events rejected by insufficient proof of work"
+        );
+        Self {
+            setting: PowSetting::default(),
+            counter: Mutex::new(Counter::default()),
+        }
+    }
+}
+
+impl Extension for Pow {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        if self.setting.enabled {
+            w.add_nip(13);
+        }
+        w.set_extension(self.setting.clone());
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        session: &mut Session,
+        ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        if let IncomingMessage::Event(event) = &msg.msg {
+            let (ip_events, rate) = self.counter.lock().count(session.ip(), now());
+            let pressure = self.setting.load_events > 0 && rate > self.setting.load_events;
+            let required = self.setting.required(ip_events, pressure);
+            // tell the client once the difficulty changes
+            let told = session.get::<PowState>().map_or(0, |s| s.0);
+            if required != told {
+                session.set(PowState(required));
+                if required > told {
+                    session.text(
+                        ctx,
+                        OutgoingMessage::notice(&format!(
+                            "pow: difficulty {} is required for the next events",
+                            required
+                        )),
+                    );
+                }
+            }
+            if let Err(err) = check(event, required) {
+                increment_counter!("nostr_relay_pow_rejected_total");
+                return OutgoingMessage::ok(&event.id_str(), false, &err).into();
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::{rand::thread_rng, KeyPair};
+
+    /// Mine the event with the nonce tag committed to the target
+    fn mine(key_pair: &KeyPair, target: u8) -> Result<Event> {
+        let time = now();
+        for nonce in 0.. {
+            let tags = vec![vec![
+                "nonce".to_owned(),
+                nonce.to_string(),
+                target.to_string(),
+            ]];
+            let event = Event::create(key_pair, time, 1, tags, "".to_owned())?;
+            if difficulty(event.id()) >= target {
+                return Ok(event);
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn difficulty_bits() {
+        assert_eq!(difficulty(&[0xff; 32]), 0);
+        assert_eq!(difficulty(&[0, 0x0f, 0xff]), 12);
+        assert_eq!(difficulty(&[0, 0, 0]), 24);
+    }
+
+    #[test]
+    fn required() {
+        let setting = PowSetting {
+            min_difficulty: 2,
+            soft_events: 10,
+            ..Default::default()
+        };
+        assert_eq!(setting.required(10, false), 2);
+        assert_eq!(setting.required(11, false), 4);
+        assert_eq!(setting.required(21, false), 8);
+        assert_eq!(setting.required(11, true), 16);
+        assert_eq!(setting.required(1000, false), 28);
+
+        let mut counter = Counter::default();
+        assert_eq!(counter.count("a", 60), (1, 1));
+        assert_eq!(counter.count("a", 60), (2, 2));
+        assert_eq!(counter.count("b", 61), (1, 2));
+        assert_eq!(counter.count("a", 120), (1, 1));
+    }
+
+    #[test]
+    fn check_event() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = mine(&key_pair, 4)?;
+        assert!(check(&event, 0).is_ok());
+        assert!(check(&event, 4).is_ok());
+        // the committed target is too low
+        let event = mine(&key_pair, 1)?;
+        if difficulty(event.id()) >= 2 {
+            assert!(check(&event, 2).unwrap_err().contains("committed target"));
+        }
+        let event = Event::create(&key_pair, now(), 1, vec![], "".to_owned())?;
+        if difficulty(event.id()) < 20 {
+            assert!(check(&event, 20)
+                .unwrap_err()
+                .starts_with("pow: difficulty"));
+        }
+        Ok(())
+    }
+}
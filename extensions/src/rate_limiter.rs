@@ -2,7 +2,7 @@ use governor::{
     clock::DefaultClock, state::keyed::DashMapStateStore, Quota, RateLimiter as GovernorRateLimiter,
 };
 use metrics::{describe_counter, increment_counter};
-use nostr_relay::db::Event;
+use nostr_relay::db::{Event, Kinds};
 use nostr_relay::{
     duration::NonZeroDuration,
     message::{ClientMessage, IncomingMessage, OutgoingMessage},
@@ -10,13 +10,8 @@ use nostr_relay::{
     Extension, ExtensionMessageResult, Session,
 };
 use parking_lot::RwLock;
-use serde::{
-    de::{self, SeqAccess, Visitor},
-    Deserialize, Deserializer,
-};
+use serde::Deserialize;
 use std::{
-    fmt,
-    marker::PhantomData,
     num::NonZeroU32,
     ops::Deref,
     sync::Arc,
@@ -36,59 +31,12 @@ pub struct EventQuota {
     /// only limit for kinds
     /// support kind list: [1, 2, 3]
     /// kind ranges included(start) to excluded(end): [[0, 10000], [30000, 40000]]
-    /// mixed: [1, 2, [30000, 40000]]
-    pub kinds: Option<Vec<Range>>,
+    /// included ranges and groups: ["30000-39999", "replaceable"]
+    /// mixed: [1, 2, [30000, 40000], "ephemeral"]
+    pub kinds: Option<Kinds>,
     pub ip_whitelist: Option<Vec<String>>,
 }
 
-/// a simple range included(start)..excluded(end)
-#[derive(Debug, PartialEq, Eq)]
-pub struct Range(pub u64, pub u64);
-
-impl Range {
-    pub fn contains(&self, value: u64) -> bool {
-        value >= self.0 && value < self.1
-    }
-}
-
-struct RangeVisitor(PhantomData<()>);
-impl<'de> Visitor<'de> for RangeVisitor {
-    type Value = Range;
-
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("sequence")
-    }
-
-    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
-    where
-        E: de::Error,
-    {
-        Ok(Range(v, v + 1))
-    }
-
-    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-    where
-        A: SeqAccess<'de>,
-    {
-        let lower: u64 = seq
-            .next_element()?
-            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-        let upper: u64 = seq
-            .next_element()?
-            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-        Ok(Range(lower, upper))
-    }
-}
-
-impl<'de> Deserialize<'de> for Range {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        deserializer.deserialize_any(RangeVisitor(PhantomData))
-    }
-}
-
 impl EventQuota {
     pub fn hit(&self, event: &Event, ip: &String) -> bool {
         if let Some(list) = &self.ip_whitelist {
@@ -96,14 +44,8 @@ impl EventQuota {
                 return false;
             }
         }
-        if let Some(list) = &self.kinds {
-            for range in list {
-                if range.contains(event.kind() as u64) {
-                    return true;
-                }
-            }
-            // out of range
-            return false;
+        if let Some(kinds) = &self.kinds {
+            return kinds.contains(event.kind());
         }
         // has not condition
         true
@@ -199,7 +141,18 @@ impl Extension for Ratelimiter {
     }
 
     fn setting(&mut self, setting: &SettingWrapper) {
-        self.setting = setting.read().parse_extension(self.name());
+        let r = setting.read();
+        self.setting = r.parse_extension(self.name());
+        // the session ips are hashed in the privacy mode
+        for list in self
+            .setting
+            .event
+            .iter_mut()
+            .flat_map(|q| &mut q.ip_whitelist)
+        {
+            list.iter_mut().for_each(|ip| *ip = r.network.client_ip(ip));
+        }
+        drop(r);
         self.event_limiters = self
             .setting
             .event
@@ -214,8 +167,8 @@ impl Extension for Ratelimiter {
         session: &mut Session,
         _ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
-        // enabled
-        if self.setting.enabled {
+        // enabled, the roles with unlimited rate are exempt
+        if self.setting.enabled && !session.role().is_some_and(|(_, r)| r.unlimited_rate) {
             self.clear();
             let ip = session.ip();
             if let IncomingMessage::Event(event) = &msg.msg {
@@ -224,6 +177,7 @@ impl Extension for Ratelimiter {
                     let q = &self.setting.event[index];
                     if q.hit(event, ip) && limiter.check_key(ip).is_err() {
                         increment_counter!("nostr_relay_rate_limiter_exceeded", "command" => "EVENT", "name" => q.name.clone());
+                        session.count_dropped("rate limited");
                         return OutgoingMessage::ok(
                             &event.id_str(),
                             false,
@@ -280,20 +234,24 @@ mod tests {
     #[test]
     fn range() -> Result<()> {
         let json = "[1, 2, [30000, 40000]]";
-        let ranges: Vec<Range> = serde_json::from_str(json)?;
-        assert_eq!(ranges.len(), 3);
-        assert!(ranges[0].contains(1));
-        assert!(!ranges[0].contains(0));
-        assert!(!ranges[0].contains(2));
-        assert!(ranges[2].contains(30000));
-        assert!(ranges[2].contains(30001));
-        assert!(ranges[2].contains(39999));
-        assert!(!ranges[2].contains(40000));
-        assert_eq!(ranges, vec![Range(1, 2), Range(2, 3), Range(30000, 40000)]);
-
-        let json = "[\"1\", 2, [30000, 40000]]";
-        let ranges = serde_json::from_str::<Vec<Range>>(json);
-        assert!(ranges.is_err());
+        let kinds: Kinds = serde_json::from_str(json)?;
+        assert!(kinds.contains(1));
+        assert!(!kinds.contains(0));
+        assert!(!kinds.contains(3));
+        assert!(kinds.contains(30000));
+        assert!(kinds.contains(30001));
+        assert!(kinds.contains(39999));
+        assert!(!kinds.contains(40000));
+        assert_eq!(kinds.ranges(), &[(1, 2), (30000, 39999)]);
+
+        let json = "[\"1\", \"30000-39999\", \"replaceable\"]";
+        let kinds: Kinds = serde_json::from_str(json)?;
+        assert!(kinds.contains(10002));
+        assert!(kinds.contains(30023));
+
+        let json = "[\"x\", 2, [30000, 40000]]";
+        let kinds = serde_json::from_str::<Kinds>(json);
+        assert!(kinds.is_err());
         Ok(())
     }
 
@@ -319,7 +277,7 @@ mod tests {
             description: Default::default(),
             period: Duration::from_secs(1).try_into().unwrap(),
             limit: NonZeroU32::new(1).unwrap(),
-            kinds: Some(vec![Range(1, 100), Range(200, 300)]),
+            kinds: Some(Kinds::new(vec![(1, 99), (200, 299)])),
             ip_whitelist: Some(vec![ip.clone()]),
         };
         // ip whitelist
@@ -400,9 +358,7 @@ mod tests {
         for _ in 0..2 {
             let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
             framed
-                .send(ws::Message::Text(
-                    format!(r#"["EVENT", {}]"#, event.to_string()).into(),
-                ))
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
                 .await?;
             let notice: (String, String, bool, String) =
                 parse_text(&framed.next().await.unwrap()?)?;
@@ -412,9 +368,7 @@ mod tests {
         // rate limit
         let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
         framed
-            .send(ws::Message::Text(
-                format!(r#"["EVENT", {}]"#, event.to_string()).into(),
-            ))
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
             .await?;
         let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
         assert!(!notice.2);
@@ -424,9 +378,7 @@ mod tests {
         for _ in 0..5 {
             let event = Event::create(&key_pair, now(), 3, vec![], "test".to_owned())?;
             framed
-                .send(ws::Message::Text(
-                    format!(r#"["EVENT", {}]"#, event.to_string()).into(),
-                ))
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
                 .await?;
             let notice: (String, String, bool, String) =
                 parse_text(&framed.next().await.unwrap()?)?;
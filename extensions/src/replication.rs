@@ -0,0 +1,473 @@
+use crate::client::{http_client, CONNECT_TIMEOUT, MIN_BACKOFF};
+use actix::{clock::sleep, Arbiter};
+use actix_web::{http::header::AUTHORIZATION, web, web::Bytes, HttpRequest, HttpResponse};
+use futures_channel::oneshot;
+use futures_util::{future::select, stream, StreamExt};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::Event,
+    duration::NonZeroDuration,
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Publish},
+    setting::SettingWrapper,
+    App, Extension, ExtensionMessageResult, Session,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// the events sent in one read of the primary database
+const BATCH_SIZE: usize = 500;
+
+/// the interval of checking new events when the replica is up to date, also the heartbeat
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// min interval of saving the cursor
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// serve the changes at `/replication`
+    #[default]
+    Primary,
+    /// apply the changes of the primary and reject the events of the clients
+    Replica,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ReplicationSetting {
+    pub enabled: bool,
+    pub role: Role,
+    /// the bearer token of the replicas, required by the primary
+    pub token: Option<String>,
+    /// the http url of the primary, used by the replica, ie: "http://10.0.0.1:8080"
+    pub primary: Option<String>,
+    /// the file saving the uid of the last applied event, default "replication.cursor" in the data path
+    pub cursor_path: Option<PathBuf>,
+    /// the retry delay doubles from 1 second to the max backoff
+    pub max_backoff: NonZeroDuration,
+}
+
+impl Default for ReplicationSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            role: Role::Primary,
+            token: None,
+            primary: None,
+            cursor_path: None,
+            max_backoff: Duration::from_secs(60).try_into().unwrap(),
+        }
+    }
+}
+
+impl ReplicationSetting {
+    fn is_replica(&self) -> bool {
+        self.enabled && self.role == Role::Replica
+    }
+}
+
+/// Stream the stored events in the write order from the primary to the replicas
+pub struct Replication {
+    pub setting: ReplicationSetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    app: App,
+    /// runs the replica task, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    /// stop the replica task when dropped
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Replication {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_replication_applied_total",
+            "The total count of events applied by the replica"
+        );
+        Self {
+            setting: ReplicationSetting::default(),
+            proxy: None,
+            app,
+            arbiter: Arbiter::new(),
+            stop: None,
+        }
+    }
+}
+
+impl Extension for Replication {
+    fn name(&self) -> &'static str {
+        "replication"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        let replication: ReplicationSetting = w.parse_extension(self.name());
+        w.set_extension(replication.clone());
+        if replication == self.setting && w.network.proxy == self.proxy {
+            return;
+        }
+        self.setting = replication;
+        self.proxy = w.network.proxy.clone();
+        self.stop = None;
+        if !self.setting.is_replica() {
+            return;
+        }
+        let Some(primary) = self.setting.primary.clone() else {
+            error!("replication.primary is required by the replica");
+            return;
+        };
+
+        let path = self
+            .setting
+            .cursor_path
+            .clone()
+            .unwrap_or_else(|| w.data.path.join("replication.cursor"));
+        let (stop, stopped) = oneshot::channel();
+        let (app, token) = (self.app.clone(), self.setting.token.clone());
+        let proxy = self.proxy.clone();
+        let max_backoff = self.setting.max_backoff.into();
+        // the http client is not Send, create the task in the arbiter
+        self.arbiter.spawn_fn(move || {
+            actix::spawn(async move {
+                let task = replicate(primary, token, proxy, path, app, max_backoff);
+                select(Box::pin(task), stopped).await;
+            });
+        });
+        self.stop = Some(stop);
+    }
+
+    fn config_web(&mut self, cfg: &mut web::ServiceConfig) {
+        cfg.service(web::resource("/replication").route(web::get().to(route_replication)));
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if self.setting.is_replica() {
+            if let IncomingMessage::Event(event) = &msg.msg {
+                return OutgoingMessage::ok(&event.id_str(), false, "blocked: read-only replica")
+                    .into();
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ReplicationQuery {
+    /// the uid of the last applied event
+    after: Option<u64>,
+}
+
+/// stream the changes as json lines: {"uid": 1, "event": {}}, empty lines are heartbeats
+async fn route_replication(
+    req: HttpRequest,
+    query: web::Query<ReplicationQuery>,
+    app: web::Data<App>,
+) -> HttpResponse {
+    let token = {
+        let setting = app.setting.read();
+        match setting
+            .get_extension::<ReplicationSetting>()
+            .filter(|s| s.enabled && s.role == Role::Primary)
+        {
+            Some(s) => s.token.clone(),
+            None => return HttpResponse::NotFound().finish(),
+        }
+    };
+    let auth = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if token.is_none() || auth != token.as_deref() {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let db = app.db.clone();
+    let body = stream::unfold((db, query.after, false), |(db, after, idle)| async move {
+        if idle {
+            sleep(POLL_INTERVAL).await;
+        }
+        let read = web::block({
+            let db = db.clone();
+            move || -> nostr_relay::Result<_> {
+                let reader = db.reader()?;
+                let mut last = after;
+                let mut buf = String::new();
+                for item in db.iter_uid::<Event, _>(&reader, after).take(BATCH_SIZE) {
+                    let (uid, event) = item?;
+                    buf.push_str(&format!(r#"{{"uid":{},"event":{}}}"#, uid, event));
+                    buf.push('\n');
+                    last = Some(uid);
+                }
+                Ok((buf, last))
+            }
+        })
+        .await;
+        match read {
+            Ok(Ok((buf, last))) => {
+                let idle = buf.is_empty();
+                let chunk = if idle { "\n".to_owned() } else { buf };
+                Some((
+                    Ok::<_, actix_web::Error>(Bytes::from(chunk)),
+                    (db, last, idle),
+                ))
+            }
+            Ok(Err(err)) => {
+                error!(error = err.to_string(), "replication read failed");
+                None
+            }
+            Err(err) => {
+                error!(error = err.to_string(), "replication read failed");
+                None
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body)
+}
+
+#[derive(Deserialize)]
+struct Change {
+    uid: u64,
+    event: Event,
+}
+
+fn load_cursor(path: &PathBuf) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn save_cursor(path: &PathBuf, uid: u64) {
+    let tmp = path.with_extension("tmp");
+    if let Err(err) = fs::write(&tmp, uid.to_string()).and_then(|_| fs::rename(&tmp, path)) {
+        error!(error = err.to_string(), "failed to save replication cursor");
+    }
+}
+
+/// follow the primary, reconnect with backoff until stopped
+async fn replicate(
+    primary: String,
+    token: Option<String>,
+    proxy: Option<String>,
+    path: PathBuf,
+    app: App,
+    max_backoff: Duration,
+) {
+    let mut cursor = load_cursor(&path);
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let result = follow(
+            &primary,
+            token.as_deref(),
+            proxy.as_deref(),
+            &app,
+            &mut cursor,
+            &path,
+            &mut backoff,
+        )
+        .await;
+        if let Some(uid) = cursor {
+            save_cursor(&path, uid);
+        }
+        if let Err(err) = result {
+            warn!(primary, error = err, "replication disconnected");
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+async fn follow(
+    primary: &str,
+    token: Option<&str>,
+    proxy: Option<&str>,
+    app: &App,
+    cursor: &mut Option<u64>,
+    path: &PathBuf,
+    backoff: &mut Duration,
+) -> Result<(), String> {
+    let mut url = format!("{}/replication", primary.trim_end_matches('/'));
+    if let Some(uid) = cursor {
+        url.push_str(&format!("?after={}", uid));
+    }
+    let mut req = http_client(proxy, CONNECT_TIMEOUT)?.get(url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    let mut res = req.send().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    info!(primary, "replication connected");
+    *backoff = MIN_BACKOFF;
+
+    let mut saved = Instant::now();
+    let mut dirty = false;
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        // the primary sends a heartbeat every second
+        let chunk = match actix::clock::timeout(POLL_INTERVAL * 10, res.next()).await {
+            Ok(Some(chunk)) => chunk.map_err(|e| e.to_string())?,
+            Ok(None) => return Err("closed".to_owned()),
+            Err(_) => return Err("timeout".to_owned()),
+        };
+        // up to date when receiving a heartbeat
+        let idle = chunk.as_ref() == b"\n";
+        buf.extend_from_slice(&chunk);
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line = buf.drain(..=pos).collect::<Vec<_>>();
+            if line.len() == 1 {
+                continue;
+            }
+            let change: Change = serde_json::from_slice(&line).map_err(|e| e.to_string())?;
+            // keep the write order
+            app.server
+                .send(Publish {
+                    event: change.event,
+                    priority: false,
+                })
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+            increment_counter!("nostr_relay_replication_applied_total");
+            *cursor = Some(change.uid);
+            dirty = true;
+        }
+        if dirty && (idle || saved.elapsed() > SAVE_INTERVAL) {
+            if let Some(uid) = cursor {
+                save_cursor(path, *uid);
+            }
+            saved = Instant::now();
+            dirty = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_test_app, temp_data_path};
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::SinkExt;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+        Filter,
+    };
+
+    fn create_app(name: &str, setting: serde_json::Value) -> Result<App> {
+        let app = create_test_app(name)?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(serde_json::json!({ "replication": setting }))?;
+        }
+        Ok(app.clone().add_extension(Replication::new(app)))
+    }
+
+    fn ids(app: &App) -> Result<Vec<String>> {
+        let reader = app.db.reader()?;
+        let ids = app
+            .db
+            .iter::<Event, _>(&reader, &Filter::default())?
+            .map(|e| e.map(|e| e.id_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    #[actix_rt::test]
+    async fn replicate() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let primary = create_app(
+            "replication_primary",
+            serde_json::json!({"enabled": true, "token": "secret"}),
+        )?;
+        let stored = Event::create(&key_pair, now(), 1, vec![], "stored".to_owned())?;
+        primary.publish(stored.clone()).await?;
+
+        let mut srv = actix_test::start({
+            let primary = primary.clone();
+            move || primary.clone().web_app()
+        });
+
+        // auth
+        let res = http_client(None, CONNECT_TIMEOUT)
+            .unwrap()
+            .get(srv.url("/replication"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 401);
+
+        let dir = temp_data_path("replication_cursor")?;
+        let cursor_path = dir.path().join("replication.cursor");
+        let replica = create_app(
+            "replication_replica",
+            serde_json::json!({
+                "enabled": true,
+                "role": "replica",
+                "token": "secret",
+                "primary": srv.url("/"),
+                "cursor_path": cursor_path,
+            }),
+        )?;
+
+        // live
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let live = Event::create(&key_pair, now(), 1, vec![], "live".to_owned())?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, live).into()))
+            .await?;
+        framed.next().await.unwrap()?;
+
+        let mut list = vec![];
+        for _ in 0..50 {
+            sleep(Duration::from_millis(100)).await;
+            list = ids(&replica)?;
+            if list.len() == 2 {
+                break;
+            }
+        }
+        list.sort();
+        let mut expected = vec![stored.id_str(), live.id_str()];
+        expected.sort();
+        assert_eq!(list, expected);
+
+        // read-only
+        let mut replica_srv = actix_test::start({
+            let replica = replica.clone();
+            move || replica.clone().web_app()
+        });
+        let mut framed = replica_srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, live).into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(
+            matches!(item, ws::Frame::Text(t) if std::str::from_utf8(&t).unwrap().contains("read-only"))
+        );
+
+        // the cursor is saved when up to date
+        sleep(Duration::from_millis(1500)).await;
+        let uid = load_cursor(&cursor_path);
+        let reader = primary.db.reader()?;
+        let last = primary
+            .db
+            .iter_uid::<Event, _>(&reader, None)
+            .last()
+            .transpose()?;
+        assert_eq!(uid, last.map(|(uid, _)| uid));
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+//! Reputation of the ips and pubkeys scored by the abuse signals: auth failures, rate limit hits,
+//! rejected events and reports. The scores decay over time, the keys over the thresholds are limited
+//! or banned for a while.
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{now, Db, Event, Reputation},
+    duration::NonZeroDuration,
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    EventResult, Extension, ExtensionMessageResult, Session,
+};
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+/// NIP-56 report event kind
+const REPORT_KIND: u16 = 1984;
+
+/// The scores under it are removed
+const MIN_SCORE: f64 = 0.1;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Weights {
+    /// the AUTH and the events rejected by the auth
+    pub auth_failure: f64,
+    pub rate_limited: f64,
+    /// the other rejected events
+    pub rejected: f64,
+    /// each NIP-56 report of the pubkey
+    pub reported: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            auth_failure: 2.0,
+            rate_limited: 1.0,
+            rejected: 0.5,
+            reported: 5.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReputationSetting {
+    pub enabled: bool,
+    pub weights: Weights,
+    /// the score halves every half life
+    pub half_life: NonZeroDuration,
+    /// the events of the keys over the score are limited by `limited_events`, disabled when 0
+    pub limit_score: f64,
+    /// the events per minute of the limited keys
+    pub limited_events: u32,
+    /// the keys over the score are banned for `ban_duration`, disabled when 0
+    pub ban_score: f64,
+    pub ban_duration: NonZeroDuration,
+    /// how often the scores are saved to the db
+    pub flush_interval: NonZeroDuration,
+}
+
+impl Default for ReputationSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            weights: Weights::default(),
+            half_life: Duration::from_secs(3600).try_into().unwrap(),
+            limit_score: 20.0,
+            limited_events: 10,
+            ban_score: 50.0,
+            ban_duration: Duration::from_secs(3600).try_into().unwrap(),
+            flush_interval: Duration::from_secs(60).try_into().unwrap(),
+        }
+    }
+}
+
+/// The scores by the key, "ip:<ip>" or "pubkey:<hex>"
+#[derive(Debug)]
+pub struct Scores {
+    pub map: HashMap<String, Reputation>,
+    pub half_life: Duration,
+    /// the keys changed after the last flush
+    dirty: HashSet<String>,
+    /// the events in the current minute of the limited keys
+    limited: HashMap<String, (u64, u32)>,
+}
+
+impl Scores {
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            map: HashMap::new(),
+            half_life,
+            dirty: HashSet::new(),
+            limited: HashMap::new(),
+        }
+    }
+
+    /// The decayed score of the key at the time
+    pub fn score(&self, key: &str, time: u64) -> f64 {
+        self.map
+            .get(key)
+            .map_or(0.0, |r| decay(r, time, self.half_life))
+    }
+
+    /// Add the weight to the score of the key, ban the key when the score reaches the ban score
+    pub fn add(&mut self, key: String, weight: f64, time: u64, setting: &ReputationSetting) {
+        if weight <= 0.0 {
+            return;
+        }
+        let r = self.map.entry(key.clone()).or_default();
+        r.score = decay(r, time, self.half_life) + weight;
+        r.updated_at = time;
+        if setting.ban_score > 0.0 && r.score >= setting.ban_score && r.banned_until <= time {
+            r.banned_until = time + setting.ban_duration.as_secs();
+            increment_counter!("nostr_relay_reputation_ban_total");
+        }
+        self.dirty.insert(key);
+    }
+
+    pub fn banned(&self, key: &str, time: u64) -> bool {
+        self.map.get(key).is_some_and(|r| r.banned_until > time)
+    }
+
+    /// Count the event of the key, returns false if the limited key sent too many events in the minute
+    fn allow(&mut self, key: &str, time: u64, setting: &ReputationSetting) -> bool {
+        if setting.limit_score <= 0.0 || self.score(key, time) < setting.limit_score {
+            return true;
+        }
+        let minute = time / 60;
+        let entry = self.limited.entry(key.to_owned()).or_insert((minute, 0));
+        if entry.0 != minute {
+            *entry = (minute, 0);
+        }
+        entry.1 += 1;
+        entry.1 <= setting.limited_events
+    }
+
+    /// The keys sorted by the score descending
+    pub fn list(&self, time: u64) -> Vec<(&String, f64, &Reputation)> {
+        let mut list = self
+            .map
+            .iter()
+            .map(|(k, r)| (k, decay(r, time, self.half_life), r))
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| b.1.total_cmp(&a.1));
+        list
+    }
+
+    /// Save the changed scores, the decayed scores are removed
+    pub fn flush(&mut self, db: &Db, time: u64) -> nostr_relay::Result<()> {
+        self.limited.retain(|_, (minute, _)| *minute == time / 60);
+        let mut removed = vec![];
+        for (key, r) in &self.map {
+            if decay(r, time, self.half_life) < MIN_SCORE && r.banned_until <= time {
+                removed.push(key.clone());
+            }
+        }
+        for key in &removed {
+            self.map.remove(key);
+            self.dirty.insert(key.clone());
+        }
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        db.put_reputations(dirty.iter().map(|k| (k.as_str(), self.map.get(k))))?;
+        Ok(())
+    }
+}
+
+fn decay(r: &Reputation, time: u64, half_life: Duration) -> f64 {
+    let age = time.saturating_sub(r.updated_at) as f64;
+    r.score * 0.5f64.powf(age / half_life.as_secs_f64())
+}
+
+pub struct ReputationScores {
+    pub setting: ReputationSetting,
+    db: Arc<Db>,
+    scores: Arc<RwLock<Scores>>,
+    flushed: Mutex<Instant>,
+}
+
+impl ReputationScores {
+    pub fn new(db: Arc<Db>) -> Self {
+        describe_counter!(
+            "nostr_relay_reputation_ban_total",
+            "The total count of temporary bans by the reputation score"
+        );
+        describe_counter!(
+            "nostr_relay_reputation_rejected_total",
+            "The total count of messages rejected by the reputation score"
+        );
+        let setting = ReputationSetting::default();
+        let mut scores = Scores::new(setting.half_life.into());
+        match db.reputations() {
+            Ok(list) => scores.map.extend(list),
+            Err(e) => error!(error = e.to_string(), "failed to load the reputations"),
+        }
+        Self {
+            setting,
+            db,
+            scores: Arc::new(RwLock::new(scores)),
+            flushed: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn scores(&self) -> Arc<RwLock<Scores>> {
+        Arc::clone(&self.scores)
+    }
+
+    fn flush(&self) {
+        let mut flushed = self.flushed.lock();
+        if flushed.elapsed() < *self.setting.flush_interval {
+            return;
+        }
+        *flushed = Instant::now();
+        if let Err(e) = self.scores.write().flush(&self.db, now()) {
+            error!(error = e.to_string(), "failed to save the reputations");
+        }
+    }
+
+    /// The weight of the signal of the event answer
+    fn weight(&self, result: &EventResult) -> f64 {
+        let weights = &self.setting.weights;
+        if result.accepted {
+            0.0
+        } else if result.message.starts_with("rate-limited:") {
+            weights.rate_limited
+        } else if result.by == "auth"
+            || result.message.starts_with("auth-required:")
+            || result.message.starts_with("restricted:")
+        {
+            weights.auth_failure
+        } else {
+            weights.rejected
+        }
+    }
+}
+
+impl Extension for ReputationScores {
+    fn name(&self) -> &'static str {
+        "reputation"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        w.set_extension(self.setting.clone());
+        self.scores.write().half_life = self.setting.half_life.into();
+    }
+
+    fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.app_data(actix_web::web::Data::from(self.scores()));
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        self.flush();
+        let time = now();
+        let ip = format!("ip:{}", session.ip());
+        let mut scores = self.scores.write();
+        let err = match &msg.msg {
+            IncomingMessage::Event(event) => {
+                let pubkey = format!("pubkey:{}", event.pubkey_str());
+                if scores.banned(&ip, time) || scores.banned(&pubkey, time) {
+                    Some("blocked: low reputation")
+                } else if !scores.allow(&ip, time, &self.setting)
+                    || !scores.allow(&pubkey, time, &self.setting)
+                {
+                    Some("rate-limited: low reputation")
+                } else {
+                    None
+                }
+            }
+            _ => scores
+                .banned(&ip, time)
+                .then_some("blocked: low reputation"),
+        };
+        drop(scores);
+        match (err, &msg.msg) {
+            (Some(err), IncomingMessage::Event(event)) => {
+                increment_counter!("nostr_relay_reputation_rejected_total");
+                OutgoingMessage::ok(&event.id_str(), false, err).into()
+            }
+            (Some(err), _) => {
+                increment_counter!("nostr_relay_reputation_rejected_total");
+                OutgoingMessage::notice(err).into()
+            }
+            (None, _) => ExtensionMessageResult::Continue(msg),
+        }
+    }
+
+    fn event_result(&self, result: &EventResult, session: &Session) {
+        // the rejections by the reputation are not signals
+        if !self.setting.enabled || result.by == self.name() {
+            return;
+        }
+        let weight = self.weight(result);
+        if weight > 0.0 {
+            let time = now();
+            let mut scores = self.scores.write();
+            scores.add(format!("ip:{}", session.ip()), weight, time, &self.setting);
+            if !result.pubkey.is_empty() {
+                scores.add(
+                    format!("pubkey:{}", result.pubkey),
+                    weight,
+                    time,
+                    &self.setting,
+                );
+            }
+        }
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        _session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        if !self.setting.enabled || event.kind() != REPORT_KIND {
+            return;
+        }
+        let time = now();
+        let mut scores = self.scores.write();
+        for tag in event.tags().iter().filter(|t| t.len() > 1 && t[0] == "p") {
+            let key = format!("pubkey:{}", tag[1]);
+            scores.add(key, self.setting.weights.reported, time, &self.setting);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+
+    #[actix_rt::test]
+    async fn scores() -> Result<()> {
+        let app = create_test_app("reputation")?;
+        let setting = ReputationSetting {
+            limit_score: 2.0,
+            limited_events: 1,
+            ban_score: 4.0,
+            ..Default::default()
+        };
+        let mut scores = Scores::new(setting.half_life.into());
+        let key = "ip:127.0.0.1".to_owned();
+        scores.add(key.clone(), 1.0, 0, &setting);
+        assert_eq!(scores.score(&key, 0), 1.0);
+        // halves every hour
+        assert_eq!(scores.score(&key, 3600), 0.5);
+        assert!(scores.allow(&key, 0, &setting));
+        assert!(scores.allow(&key, 0, &setting));
+
+        // limited
+        scores.add(key.clone(), 1.0, 0, &setting);
+        assert!(scores.allow(&key, 0, &setting));
+        assert!(!scores.allow(&key, 0, &setting));
+        assert!(scores.allow(&key, 60, &setting));
+
+        // banned
+        assert!(!scores.banned(&key, 0));
+        scores.add(key.clone(), 2.0, 0, &setting);
+        assert!(scores.banned(&key, 0));
+        assert!(!scores.banned(&key, 3600));
+
+        scores.add("pubkey:00".to_owned(), 1.0, 0, &setting);
+        let list = scores.list(0);
+        assert_eq!(list[0].0, &key);
+        assert_eq!(list[1].1, 1.0);
+
+        // saved, the decayed scores are removed
+        scores.flush(&app.db, 3600 * 4)?;
+        assert_eq!(app.db.reputations()?.len(), 1);
+        scores.flush(&app.db, 3600 * 8)?;
+        assert!(app.db.reputations()?.is_empty());
+        Ok(())
+    }
+}
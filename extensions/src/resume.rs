@@ -0,0 +1,236 @@
+//! Resume tokens of the subscriptions. The EOSE carries the high-water mark of the stored events,
+//! `["EOSE", <subscription_id>, <token>]`, and a reconnected client sends
+//! `["RESUME", <subscription_id>, <token>, <filters>...]` to get only the events stored since then.
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{Db, Filter},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage, Resume, Subscription},
+    setting::{Limitation, SettingWrapper},
+    Extension, ExtensionMessageResult, Session,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::error;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ResumeSetting {
+    pub enabled: bool,
+    /// the events stored since the token are all read again when more are stored
+    pub max_scan: usize,
+}
+
+impl Default for ResumeSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_scan: 10_000,
+        }
+    }
+}
+
+/// The token of the next stored event
+pub fn encode_token(next: u64) -> String {
+    format!("{:x}", next)
+}
+
+pub fn decode_token(token: &str) -> Option<u64> {
+    u64::from_str_radix(token, 16).ok()
+}
+
+pub struct ResumeTokens {
+    pub setting: ResumeSetting,
+    db: Arc<Db>,
+    limitation: Limitation,
+}
+
+impl ResumeTokens {
+    pub fn new(db: Arc<Db>) -> Self {
+        describe_counter!(
+            "nostr_relay_resume_total",
+            "The total count of subscriptions resumed by a token"
+        );
+        Self {
+            setting: ResumeSetting::default(),
+            db,
+            limitation: Limitation::default(),
+        }
+    }
+
+    /// The token of the events stored after now
+    fn token(&self) -> nostr_relay::Result<String> {
+        let reader = self.db.reader()?;
+        let next = self.db.last_uid(&reader)?.map_or(0, |uid| uid + 1);
+        Ok(encode_token(next))
+    }
+
+    /// Parse the RESUME message to a REQ from the token
+    fn parse(&self, msg: &ClientMessage, args: &[Value]) -> Result<ClientMessage, String> {
+        let (id, token) = match args {
+            [Value::String(id), Value::String(token), ..] => (id, token),
+            _ => return Err("invalid: RESUME requires a subscription id and a token".to_owned()),
+        };
+        let filters = args[2..]
+            .iter()
+            .map(|f| Filter::deserialize(f).map_err(|e| format!("invalid: {}", e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut req = ClientMessage {
+            id: msg.id,
+            text: msg.text.clone(),
+            msg: IncomingMessage::Req(Subscription {
+                id: id.clone(),
+                filters,
+                // the unknown tokens read all stored events
+                resume: decode_token(token).map(|from| Resume {
+                    from,
+                    max_scan: self.setting.max_scan,
+                }),
+            }),
+        };
+        req.validate(&self.limitation).map_err(|e| e.to_string())?;
+        Ok(req)
+    }
+}
+
+impl Extension for ResumeTokens {
+    fn name(&self) -> &'static str {
+        "resume"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        self.limitation = w.limitation.clone();
+        w.set_extension(self.setting.clone());
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        match &msg.msg {
+            IncomingMessage::Unknown(cmd, args) if cmd == "RESUME" => {
+                match self.parse(&msg, args) {
+                    Ok(req) => {
+                        increment_counter!("nostr_relay_resume_total");
+                        ExtensionMessageResult::Continue(req)
+                    }
+                    Err(err) => OutgoingMessage::notice(&err).into(),
+                }
+            }
+            _ => ExtensionMessageResult::Continue(msg),
+        }
+    }
+
+    fn outgoing(&self, msg: &mut OutgoingMessage, _session: &Session) {
+        if !self.setting.enabled {
+            return;
+        }
+        if let Some(id) = msg.eose_id() {
+            // the subscription is live, the events stored after the token are sent live too
+            match self.token() {
+                Ok(token) => *msg = OutgoingMessage(format!(r#"["EOSE","{}","{}"]"#, id, token)),
+                Err(e) => error!(error = e.to_string(), "failed to create the resume token"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web::web;
+    use actix_web_actors::ws;
+    use anyhow::Result;
+    use futures_util::{SinkExt as _, StreamExt as _};
+    use nostr_relay::{
+        create_web_app,
+        db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        },
+    };
+
+    fn parse_text<T: serde::de::DeserializeOwned>(frame: &ws::Frame) -> Result<T> {
+        if let ws::Frame::Text(text) = &frame {
+            let data: T = serde_json::from_slice(text)?;
+            Ok(data)
+        } else {
+            Err(nostr_relay::Error::Message("invalid frame type".to_string()).into())
+        }
+    }
+
+    #[test]
+    fn token() {
+        assert_eq!(encode_token(255), "ff");
+        assert_eq!(decode_token("ff"), Some(255));
+        assert_eq!(decode_token("xyz"), None);
+    }
+
+    #[actix_rt::test]
+    async fn resume() -> Result<()> {
+        let app = create_test_app("resume")?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_str(r#"{"resume": {"enabled": true}}"#)?;
+        }
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let note = |content: &str| Event::create(&key_pair, now(), 1, vec![], content.to_owned());
+        app.db.batch_put([note("old")?])?;
+
+        let db = app.db.clone();
+        let app = app.add_extension(ResumeTokens::new(db.clone()));
+        let app = web::Data::new(app);
+        let mut srv = actix_test::start(move || create_web_app(app.clone()));
+
+        // the contents of the events until the EOSE and the token
+        async fn read(
+            framed: &mut actix_codec::Framed<
+                impl actix_codec::AsyncRead + actix_codec::AsyncWrite + Unpin,
+                awc::ws::Codec,
+            >,
+            text: String,
+        ) -> Result<(Vec<String>, Option<String>)> {
+            framed.send(ws::Message::Text(text.into())).await?;
+            let mut contents = vec![];
+            loop {
+                let list: Vec<Value> = parse_text(&framed.next().await.unwrap()?)?;
+                if list[0] == "EOSE" {
+                    return Ok((
+                        contents,
+                        list.get(2).and_then(|t| t.as_str()).map(ToOwned::to_owned),
+                    ));
+                }
+                contents.push(list[2]["content"].as_str().unwrap_or_default().to_owned());
+            }
+        }
+
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let (contents, token) =
+            read(&mut framed, r#"["REQ", "1", {"kinds": [1]}]"#.to_owned()).await?;
+        assert_eq!(contents, vec!["old"]);
+        let token = token.unwrap();
+
+        // stored while the client is away
+        db.batch_put([note("new")?])?;
+
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let text = format!(r#"["RESUME", "1", "{}", {{"kinds": [1]}}]"#, token);
+        let (contents, _) = read(&mut framed, text).await?;
+        assert_eq!(contents, vec!["new"]);
+
+        // the unknown tokens read all
+        let text = r#"["RESUME", "2", "none", {"kinds": [1]}]"#.to_owned();
+        let (contents, _) = read(&mut framed, text).await?;
+        assert_eq!(contents.len(), 2);
+        Ok(())
+    }
+}
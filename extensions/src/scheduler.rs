@@ -0,0 +1,830 @@
+//! Scheduled maintenance jobs, ie: the retention of the old events or the backups of the database.
+//!
+//! A job runs in its own thread on a cron expression or an interval with a random jitter,
+//! the run is skipped while the previous run is still running.
+//! The other extensions and the custom relays can register their jobs by [`Scheduler::add_job`].
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{
+        now,
+        secp256k1::rand::{thread_rng, Rng},
+        Event, Filter, Kinds,
+    },
+    duration::NonZeroDuration,
+    setting::SettingWrapper,
+    App, Extension,
+};
+use parking_lot::RwLock;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// A maintenance job, the parameters are the other fields of `[scheduler.jobs.<name>]`
+pub trait Job: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Run once, returns the summary of the run
+    fn run(&self, app: &App, params: &Value) -> Result<String, String>;
+}
+
+/// Cron expression with 5 fields: minute, hour, day of month, month and day of week, in UTC.
+/// A field is `*`, a value, a range `1-5`, a step `*/15` or `0-30/10`, or a list of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// the day matches both fields unless both are restricted
+    any_day: bool,
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                Some(
+                    step.parse::<u64>()
+                        .ok()
+                        .filter(|s| *s > 0)
+                        .ok_or_else(|| format!("invalid step {}", step))?,
+                ),
+            ),
+            None => (part, None),
+        };
+        let value = |s: &str| {
+            s.parse::<u64>()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("invalid value {}, must be {}-{}", s, min, max))
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (value(start)?, value(end)?)
+        } else {
+            let start = value(range)?;
+            (start, if step.is_some() { max } else { start })
+        };
+        if start > end {
+            return Err(format!("invalid range {}", range));
+        }
+        for v in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+/// (year, month, day) of the days since the unix epoch
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year as u64, month as u64, day as u64)
+}
+
+impl Cron {
+    fn day_matches(&self, days: u64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let dom = self.days & (1 << day) != 0;
+        // 1970-01-01 is a Thursday
+        let dow = self.weekdays & (1 << ((days + 4) % 7)) != 0;
+        if self.any_day {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+
+    /// The first matched minute after the time, none if no one in 5 years, ie: `0 0 30 2 *`
+    pub fn next(&self, time: u64) -> Option<u64> {
+        let limit = time + 5 * 366 * 86400;
+        let mut t = (time / 60 + 1) * 60;
+        while t < limit {
+            let days = t / 86400;
+            if !self.day_matches(days) {
+                t = (days + 1) * 86400;
+            } else if self.hours & (1 << (t % 86400 / 3600)) == 0 {
+                t = (t / 3600 + 1) * 3600;
+            } else if self.minutes & (1 << (t % 3600 / 60)) == 0 {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("invalid cron expression {}, must have 5 fields", s));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // both 0 and 7 are Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            source: fields.join(" "),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] != "*" && fields[4] != "*",
+        })
+    }
+}
+
+/// When a job runs, a cron expression `"0 3 * * *"` or an interval `"10m"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Every(NonZeroDuration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The next run after the time
+    pub fn next(&self, time: u64) -> Option<u64> {
+        match self {
+            Schedule::Every(d) => Some(time + d.as_secs().max(1)),
+            Schedule::Cron(cron) => cron.next(time),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.split_whitespace().count() > 1 {
+            Ok(Self::Cron(s.parse()?))
+        } else {
+            let d = de::value::StrDeserializer::<de::value::Error>::new(s.trim());
+            Ok(Self::Every(
+                NonZeroDuration::deserialize(d).map_err(|e| e.to_string())?,
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Every(d) => write!(f, "{}s", d.as_secs()),
+            Schedule::Cron(cron) => f.write_str(&cron.source),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct JobSetting {
+    /// the job without a schedule only runs by the management api
+    pub schedule: Option<Schedule>,
+    /// the max random delay of the runs, overrides the jitter of the scheduler
+    pub jitter: Option<NonZeroDuration>,
+    /// the parameters of the job
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SchedulerSetting {
+    pub enabled: bool,
+    /// the max random delay of the runs
+    pub jitter: Option<NonZeroDuration>,
+    /// the setting of the jobs by the name
+    pub jobs: HashMap<String, JobSetting>,
+}
+
+impl SchedulerSetting {
+    fn next_run(&self, job: &JobSetting, time: u64) -> Option<u64> {
+        let next = job.schedule.as_ref()?.next(time)?;
+        let jitter = job.jitter.or(self.jitter).map_or(0, |d| d.as_secs());
+        Some(next + thread_rng().gen_range(0..=jitter))
+    }
+
+    /// The parameters of the job in the setting
+    pub fn params(&self, name: &str) -> Value {
+        Value::Object(
+            self.jobs
+                .get(name)
+                .map(|j| j.params.clone())
+                .unwrap_or_default(),
+        )
+    }
+}
+
+/// The last run and the next run of a job
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: Option<String>,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    /// the runs skipped while the previous run is running
+    pub skipped: u64,
+    pub last_started_at: Option<u64>,
+    pub last_finished_at: Option<u64>,
+    pub last_result: Option<String>,
+    pub last_error: Option<String>,
+    pub next_run_at: Option<u64>,
+}
+
+struct Entry {
+    job: Arc<dyn Job>,
+    status: JobStatus,
+}
+
+/// The registered jobs with the status in the order of registration
+#[derive(Default)]
+pub struct Jobs {
+    entries: RwLock<Vec<Entry>>,
+}
+
+impl Jobs {
+    /// Register the job, replaces the job with the same name
+    pub fn add(&self, job: Arc<dyn Job>) {
+        let mut entries = self.entries.write();
+        let status = JobStatus {
+            name: job.name().to_owned(),
+            ..Default::default()
+        };
+        entries.retain(|e| e.status.name != status.name);
+        entries.push(Entry { job, status });
+    }
+
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.entries
+            .read()
+            .iter()
+            .map(|e| e.status.clone())
+            .collect()
+    }
+
+    /// Start the job in a new thread, fails if the job is unknown or running
+    pub fn run(self: &Arc<Self>, name: &str, app: &App, params: Value) -> Result<(), String> {
+        let job = {
+            let mut entries = self.entries.write();
+            let entry = entries
+                .iter_mut()
+                .find(|e| e.status.name == name)
+                .ok_or_else(|| format!("unknown job {}", name))?;
+            if entry.status.running {
+                return Err(format!("job {} is running", name));
+            }
+            entry.status.running = true;
+            entry.status.last_started_at = Some(now());
+            Arc::clone(&entry.job)
+        };
+        let jobs = Arc::clone(self);
+        let app = app.clone();
+        thread::spawn(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| job.run(&app, &params)))
+                .unwrap_or_else(|_| Err("panicked".to_owned()));
+            jobs.finish(job.name(), result);
+        });
+        Ok(())
+    }
+
+    fn finish(&self, name: &str, result: Result<String, String>) {
+        let mut entries = self.entries.write();
+        if let Some(entry) = entries.iter_mut().find(|e| e.status.name == name) {
+            let status = &mut entry.status;
+            status.running = false;
+            status.runs += 1;
+            status.last_finished_at = Some(now());
+            match result {
+                Ok(summary) => {
+                    info!("Job {} finished: {}", name, summary);
+                    increment_counter!("nostr_relay_job_total", "job" => name.to_owned(), "result" => "ok");
+                    status.last_result = Some(summary);
+                    status.last_error = None;
+                }
+                Err(err) => {
+                    error!(error = err, "job {} failed", name);
+                    increment_counter!("nostr_relay_job_total", "job" => name.to_owned(), "result" => "error");
+                    status.failures += 1;
+                    status.last_error = Some(err);
+                }
+            }
+        }
+    }
+
+    /// Start the due jobs, the next runs are planned again when the schedules change
+    pub fn tick(self: &Arc<Self>, app: &App, setting: &SchedulerSetting, time: u64) {
+        let mut due = vec![];
+        {
+            let mut entries = self.entries.write();
+            for entry in entries.iter_mut() {
+                let status = &mut entry.status;
+                let job = match setting.jobs.get(&status.name) {
+                    Some(job) => job,
+                    None => {
+                        status.schedule = None;
+                        status.next_run_at = None;
+                        continue;
+                    }
+                };
+                let schedule = job.schedule.as_ref().map(|s| s.to_string());
+                if status.schedule != schedule {
+                    status.schedule = schedule;
+                    status.next_run_at = setting.next_run(job, time);
+                }
+                if status.next_run_at.is_some_and(|t| t <= time) {
+                    status.next_run_at = setting.next_run(job, time);
+                    if status.running {
+                        info!("Skip job {}, the previous run is running", status.name);
+                        increment_counter!("nostr_relay_job_total", "job" => status.name.clone(), "result" => "skipped");
+                        status.skipped += 1;
+                    } else {
+                        due.push(status.name.clone());
+                    }
+                }
+            }
+        }
+        for name in due {
+            if let Err(err) = self.run(&name, app, setting.params(&name)) {
+                error!(error = err, "failed to start job {}", name);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RetentionRule {
+    #[serde(default)]
+    filter: Filter,
+    /// kinds, ranges or groups of the matched events, all when empty
+    #[serde(default)]
+    kinds: Kinds,
+    /// the matched events older than it are deleted
+    max_age: NonZeroDuration,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct RetentionParams {
+    rules: Vec<RetentionRule>,
+    /// number of events deleted in one transaction
+    batch: usize,
+}
+
+impl Default for RetentionParams {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            batch: 1000,
+        }
+    }
+}
+
+/// Delete the events older than the max age of the matched rule
+#[derive(Debug, Default)]
+pub struct Retention;
+
+impl Job for Retention {
+    fn name(&self) -> &'static str {
+        "retention"
+    }
+
+    fn run(&self, app: &App, params: &Value) -> Result<String, String> {
+        let params = RetentionParams::deserialize(params).map_err(|e| e.to_string())?;
+        let batch = params.batch.max(1);
+        let mut deleted = 0;
+        for rule in params.rules {
+            let mut filter = rule.filter;
+            let until = now().saturating_sub(rule.max_age.as_secs());
+            filter.until = Some(filter.until.map_or(until, |t| t.min(until)));
+            filter.limit = None;
+            loop {
+                let ids = {
+                    let reader = app.db.reader().map_err(|e| e.to_string())?;
+                    let iter = app
+                        .db
+                        .iter::<Event, _>(&reader, &filter)
+                        .map_err(|e| e.to_string())?;
+                    iter.filter(|e| {
+                        let kind = e.as_ref().map_or(0, |e| e.kind());
+                        e.is_err() || rule.kinds.is_empty() || rule.kinds.contains(kind)
+                    })
+                    .take(batch)
+                    .map(|e| e.map(|e| e.id().to_vec()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())?
+                };
+                app.db.batch_del(&ids).map_err(|e| e.to_string())?;
+                deleted += ids.len();
+                if ids.len() < batch {
+                    break;
+                }
+            }
+        }
+        Ok(format!("deleted {} events", deleted))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct BackupParams {
+    /// the backups are the directories `events-<timestamp>` in it
+    path: PathBuf,
+    /// number of the backups kept, 0 keeps all
+    #[serde(default = "default_keep")]
+    keep: usize,
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+/// Copy the database to a new directory and remove the oldest copies
+#[derive(Debug, Default)]
+pub struct Backup;
+
+impl Job for Backup {
+    fn name(&self) -> &'static str {
+        "backup"
+    }
+
+    fn run(&self, app: &App, params: &Value) -> Result<String, String> {
+        let params = BackupParams::deserialize(params).map_err(|e| e.to_string())?;
+        let path = params.path.join(format!("events-{}", now()));
+        if path.exists() {
+            return Err(format!("{} exists", path.display()));
+        }
+        app.db.backup(&path).map_err(|e| e.to_string())?;
+        if params.keep > 0 {
+            let mut backups = fs::read_dir(&params.path)
+                .map_err(|e| e.to_string())?
+                .filter_map(|e| {
+                    let e = e.ok()?;
+                    let name = e.file_name().into_string().ok()?;
+                    let time = name.strip_prefix("events-")?.parse::<u64>().ok()?;
+                    Some((time, e.path()))
+                })
+                .collect::<Vec<_>>();
+            backups.sort();
+            let len = backups.len();
+            for (_, old) in backups.into_iter().take(len.saturating_sub(params.keep)) {
+                fs::remove_dir_all(&old).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(path.display().to_string())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct BloomParams {
+    /// the false positive rate of the ids not stored
+    fp_rate: f64,
+}
+
+impl Default for BloomParams {
+    fn default() -> Self {
+        Self { fp_rate: 0.01 }
+    }
+}
+
+/// Rebuild the bloom filter of the stored event ids, the deleted ids are dropped from it
+#[derive(Debug, Default)]
+pub struct Bloom;
+
+impl Job for Bloom {
+    fn name(&self) -> &'static str {
+        "bloom"
+    }
+
+    fn run(&self, app: &App, params: &Value) -> Result<String, String> {
+        let params = BloomParams::deserialize(params).map_err(|e| e.to_string())?;
+        if !(params.fp_rate > 0.0 && params.fp_rate < 1.0) {
+            return Err(format!("invalid fp_rate {}", params.fp_rate));
+        }
+        let (count, size) = app
+            .db
+            .rebuild_bloom(params.fp_rate)
+            .map_err(|e| e.to_string())?;
+        Ok(format!("{} ids in {} bytes", count, size))
+    }
+}
+
+pub struct Scheduler {
+    pub setting: SchedulerSetting,
+    app: App,
+    jobs: Arc<Jobs>,
+    started: bool,
+}
+
+impl Scheduler {
+    /// The built-in jobs `retention`, `backup` and `bloom` are registered
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_job_total",
+            "The total count of scheduled job runs by the result"
+        );
+        let jobs = Arc::new(Jobs::default());
+        jobs.add(Arc::new(Retention));
+        jobs.add(Arc::new(Backup));
+        jobs.add(Arc::new(Bloom));
+        Self {
+            setting: SchedulerSetting::default(),
+            app,
+            jobs,
+            started: false,
+        }
+    }
+
+    pub fn add_job<J: Job + 'static>(self, job: J) -> Self {
+        self.jobs.add(Arc::new(job));
+        self
+    }
+
+    pub fn jobs(&self) -> Arc<Jobs> {
+        Arc::clone(&self.jobs)
+    }
+}
+
+impl Extension for Scheduler {
+    fn name(&self) -> &'static str {
+        "scheduler"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        w.set_extension(self.setting.clone());
+        drop(w);
+        if self.setting.enabled && !self.started {
+            self.started = true;
+            let app = self.app.clone();
+            let jobs = self.jobs();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                let setting = app
+                    .setting
+                    .read()
+                    .get_extension::<SchedulerSetting>()
+                    .cloned();
+                if let Some(setting) = setting.filter(|s| s.enabled) {
+                    jobs.tick(&app, &setting, now());
+                }
+            });
+        }
+    }
+
+    fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.app_data(actix_web::web::Data::from(self.jobs()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::KeyPair;
+    use std::time::Instant;
+
+    fn wait(jobs: &Jobs, name: &str) -> JobStatus {
+        let start = Instant::now();
+        loop {
+            let status = jobs.list().into_iter().find(|s| s.name == name).unwrap();
+            if !status.running || start.elapsed() > Duration::from_secs(10) {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn cron() -> Result<(), String> {
+        // 2023-01-01 00:00:00 Sunday
+        let time = 1672531200;
+        let cron: Cron = "*/15 * * * *".parse()?;
+        assert_eq!(cron.next(time), Some(time + 900));
+        assert_eq!(cron.next(time + 1), Some(time + 900));
+        let cron: Cron = "30 3 * * *".parse()?;
+        assert_eq!(cron.next(time), Some(time + 3 * 3600 + 1800));
+        // Monday
+        let cron: Cron = "0 0 * * 1".parse()?;
+        assert_eq!(cron.next(time), Some(time + 86400));
+        // Sunday
+        let cron: Cron = "0 0 * * 7".parse()?;
+        assert_eq!(cron.next(time), Some(time + 7 * 86400));
+        // the 15th or Monday
+        let cron: Cron = "0 12 15 * 1".parse()?;
+        assert_eq!(cron.next(time), Some(time + 86400 + 12 * 3600));
+        let cron: Cron = "0 0 1 3 *".parse()?;
+        assert_eq!(cron.next(time), Some(time + (31 + 28) * 86400));
+        let cron: Cron = "0 0 29 2 *".parse()?;
+        // 2024-02-29
+        assert_eq!(cron.next(time), Some(1709164800));
+        assert_eq!("0 0 30 2 *".parse::<Cron>()?.next(time), None);
+        assert!("* * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("5-1 * * * *".parse::<Cron>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn schedule() -> Result<()> {
+        let setting: SchedulerSetting = serde_json::from_str(
+            r#"{"enabled": true, "jobs": {
+                "retention": {"schedule": "0 3 * * *", "rules": []},
+                "backup": {"schedule": "6h", "jitter": "1m", "path": "/backup"}
+            }}"#,
+        )?;
+        let job = &setting.jobs["retention"];
+        assert!(matches!(job.schedule, Some(Schedule::Cron(_))));
+        assert_eq!(job.params.get("rules"), Some(&Value::Array(vec![])));
+        let job = &setting.jobs["backup"];
+        assert_eq!(job.schedule.as_ref().unwrap().to_string(), "21600s");
+        assert_eq!(job.jitter.map(|d| d.as_secs()), Some(60));
+        let next = setting.next_run(job, 0).unwrap();
+        assert!((21600..=21660).contains(&next));
+        assert!(serde_json::from_str::<Schedule>(r#""0s""#).is_err());
+        assert!(serde_json::from_str::<Schedule>(r#""* * *""#).is_err());
+        Ok(())
+    }
+
+    struct Sleep;
+
+    impl Job for Sleep {
+        fn name(&self) -> &'static str {
+            "sleep"
+        }
+
+        fn run(&self, _app: &App, params: &Value) -> Result<String, String> {
+            let ms = params["ms"].as_u64().ok_or("missing ms")?;
+            thread::sleep(Duration::from_millis(ms));
+            Ok(format!("slept {}ms", ms))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn tick() -> Result<()> {
+        let app = create_test_app("scheduler-tick")?;
+        let jobs = Arc::new(Jobs::default());
+        jobs.add(Arc::new(Sleep));
+        let setting: SchedulerSetting = serde_json::from_str(
+            r#"{"enabled": true, "jobs": {"sleep": {"schedule": "10s", "ms": 200}}}"#,
+        )?;
+        jobs.tick(&app, &setting, 100);
+        let status = &jobs.list()[0];
+        assert_eq!(status.next_run_at, Some(110));
+        assert!(!status.running);
+
+        jobs.tick(&app, &setting, 110);
+        let status = &jobs.list()[0];
+        assert!(status.running);
+        assert_eq!(status.next_run_at, Some(120));
+        // overlapped
+        jobs.tick(&app, &setting, 120);
+        assert_eq!(jobs.list()[0].skipped, 1);
+        assert!(jobs.run("sleep", &app, setting.params("sleep")).is_err());
+
+        let status = wait(&jobs, "sleep");
+        assert_eq!(status.runs, 1);
+        assert_eq!(status.last_result.as_deref(), Some("slept 200ms"));
+
+        // failed
+        jobs.run("sleep", &app, Value::Null).unwrap();
+        let status = wait(&jobs, "sleep");
+        assert_eq!(status.failures, 1);
+        assert_eq!(status.last_error.as_deref(), Some("missing ms"));
+        assert!(jobs.run("unknown", &app, Value::Null).is_err());
+
+        // unscheduled
+        jobs.tick(&app, &SchedulerSetting::default(), 130);
+        assert_eq!(jobs.list()[0].next_run_at, None);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn retention() -> Result<()> {
+        let app = create_test_app("scheduler-retention")?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let note = |kind: u16, age: u64| {
+            Event::create(&key_pair, now() - age, kind, vec![], "".to_owned())
+        };
+        app.db.batch_put([
+            note(1, 0)?,
+            note(1, 86400 * 2)?,
+            note(1, 86400 * 3)?,
+            note(7, 3600 * 2)?,
+            note(7, 60)?,
+            note(30001, 3600 * 2)?,
+            note(10001, 3600 * 2)?,
+        ])?;
+        let params = serde_json::json!({
+            "batch": 1,
+            "rules": [
+                {"filter": {"kinds": [1]}, "max_age": "1d"},
+                {"filter": {"kinds": [7]}, "max_age": "1h"},
+                {"kinds": ["30000-39999"], "max_age": "1h"},
+                {"kinds": ["replaceable"], "max_age": "1d"},
+            ]
+        });
+        assert_eq!(
+            Retention.run(&app, &params),
+            Ok("deleted 4 events".to_owned())
+        );
+        let reader = app.db.reader()?;
+        let left = app
+            .db
+            .iter::<Event, _>(&reader, &Filter::default())?
+            .count();
+        assert_eq!(left, 3);
+        assert!(Retention
+            .run(&app, &serde_json::json!({"rules": [{"max_age": "0s"}]}))
+            .is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn backup() -> Result<()> {
+        let app = create_test_app("scheduler-backup")?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        app.db
+            .batch_put([Event::create(&key_pair, now(), 1, vec![], "".to_owned())?])?;
+        let dir = tempfile::tempdir()?;
+        for time in [1, 2, 3] {
+            fs::create_dir(dir.path().join(format!("events-{}", time)))?;
+        }
+        let params = serde_json::json!({"path": dir.path(), "keep": 2});
+        let path = Backup.run(&app, &params).unwrap();
+        assert!(PathBuf::from(&path).join("data.mdb").exists());
+        let mut names = fs::read_dir(dir.path())?
+            .map(|e| Ok(e?.file_name().into_string().unwrap_or_default()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        let latest = PathBuf::from(&path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(names, vec![latest, "events-3".to_owned()]);
+        assert!(Backup.run(&app, &Value::Null).is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn bloom() -> Result<()> {
+        let app = create_test_app("scheduler-bloom")?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let note = Event::create(&key_pair, now(), 1, vec![], "".to_owned())?;
+        let other = Event::create(&key_pair, now(), 1, vec![], "other".to_owned())?;
+        app.db.batch_put([&note])?;
+        assert_eq!(
+            Bloom.run(&app, &serde_json::json!({"fp_rate": 0.001})),
+            Ok("1 ids in 179720 bytes".to_owned())
+        );
+        assert!(app.db.may_exist(note.id()));
+        assert!(!app.db.may_exist(other.id()));
+        assert!(Bloom.run(&app, &serde_json::json!({"fp_rate": 0})).is_err());
+        Ok(())
+    }
+}
@@ -1,13 +1,80 @@
 use nostr_relay::{
+    db::{set_index_options, set_tokenizer, Filter, IndexOptions, PatternOptions, Rank, Tokenizer},
+    duration::NonZeroDuration,
     message::{ClientMessage, IncomingMessage},
     setting::SettingWrapper,
     Extension, ExtensionMessageResult, Session,
 };
 use serde::Deserialize;
+use std::time::Duration;
 
 #[derive(Deserialize, Default, Debug)]
+#[serde(default)]
 pub struct SearchSetting {
     pub enabled: bool,
+    /// shared by the relays of the process, the stored events keep the words of the old options
+    pub tokenizer: Tokenizer,
+    /// the indexed kinds and the max content length
+    #[serde(flatten)]
+    pub index: IndexOptions,
+    /// the prefix `oly*` and fuzzy `word~` keywords
+    pub patterns: PatternOptions,
+    /// order the results by relevance with the `sort:relevance` option of the search
+    pub ranking: RankingSetting,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct RankingSetting {
+    pub enabled: bool,
+    /// the newest matched events are scored, the older events are not returned
+    pub candidates: u64,
+    /// the score of an event halves every half life
+    pub half_life: NonZeroDuration,
+}
+
+impl Default for RankingSetting {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            candidates: 1000,
+            half_life: NonZeroDuration::new(Duration::from_secs(7 * 86400)).unwrap(),
+        }
+    }
+}
+
+/// Take the `sort:relevance` and `sort:recent` options of [NIP-50](https://nips.be/50) out of the search
+fn parse_sort(filter: &mut Filter, ranking: &RankingSetting) {
+    let Some(search) = &filter.search else {
+        return;
+    };
+    let mut relevance = false;
+    let mut sorted = false;
+    let rest = search
+        .split_whitespace()
+        .filter(|item| match item.to_lowercase().as_str() {
+            "sort:relevance" => {
+                relevance = true;
+                sorted = true;
+                false
+            }
+            "sort:recent" => {
+                sorted = true;
+                false
+            }
+            _ => true,
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    if sorted {
+        filter.search = Some(rest);
+    }
+    if relevance && ranking.enabled {
+        filter.rank = Some(Rank {
+            candidates: ranking.candidates,
+            half_life: ranking.half_life.as_secs(),
+        });
+    }
 }
 
 #[derive(Default, Debug)]
@@ -31,6 +98,8 @@ impl Extension for Search {
         self.setting = w.parse_extension(self.name());
         if self.setting.enabled {
             w.add_nip(50);
+            set_tokenizer(self.setting.tokenizer.clone());
+            set_index_options(self.setting.index.clone());
         }
     }
 
@@ -46,8 +115,10 @@ impl Extension for Search {
                     event.build_note_words();
                 }
                 IncomingMessage::Req(sub) => {
+                    // the patterns are expanded by the readers in the transaction of the query
                     for filter in &mut sub.filters {
-                        filter.build_words();
+                        parse_sort(filter, &self.setting.ranking);
+                        filter.build_search(&self.setting.patterns);
                     }
                 }
                 _ => {}
@@ -94,7 +165,11 @@ mod tests {
             w.extra = serde_json::from_str(
                 r#"{
                 "search": {
-                    "enabled": true
+                    "enabled": true,
+                    "patterns": {
+                        "prefix": true,
+                        "fuzzy": true
+                    }
                 }
             }"#,
             )?;
@@ -121,7 +196,7 @@ mod tests {
                 vec![],
                 content.to_owned(),
             )?;
-            let msg = format!(r#"["EVENT", {}]"#, event.to_string());
+            let msg = format!(r#"["EVENT", {}]"#, event);
             framed.send(ws::Message::Text(msg.into())).await?;
             let notice: (String, String, bool, String) =
                 parse_text(&framed.next().await.unwrap()?)?;
@@ -161,6 +236,47 @@ mod tests {
         let res: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
         assert_eq!(res.0, "EOSE");
 
+        framed
+            .send(ws::Message::Text(
+                r#"["REQ", "4", {"search": "nost* chna~"}]"#.into(),
+            ))
+            .await?;
+        let res: (String, String, Event) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.2.content(), "nostr users from China");
+        let res: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.0, "EOSE");
+
+        // relevance
+        let event = Event::create(&key_pair, start + 3, 1, vec![], "nostr, nostr".to_owned())?;
+        let msg = format!(r#"["EVENT", {}]"#, event);
+        framed.send(ws::Message::Text(msg.into())).await?;
+        let notice: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert!(notice.2);
+
+        framed
+            .send(ws::Message::Text(
+                r#"["REQ", "5", {"search": "nostr sort:relevance"}]"#.into(),
+            ))
+            .await?;
+        let mut contents = vec![];
+        while contents.len() < 3 {
+            // skip the new event of the subscription "1"
+            let res: (String, String, Event) = parse_text(&framed.next().await.unwrap()?)?;
+            if res.1 == "5" {
+                contents.push(res.2.content().to_owned());
+            }
+        }
+        assert_eq!(
+            contents,
+            vec![
+                "nostr, nostr",
+                "nostr users from China",
+                "来自中国的nostr用户"
+            ]
+        );
+        let res: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
+        assert_eq!(res.0, "EOSE");
+
         // close
         framed
             .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
@@ -0,0 +1,324 @@
+//! Per-kind structural rules of the events, ie: the `d` tag of the addressable events,
+//! the `p` tag and the encrypted content of the direct messages, checked before the events are stored.
+//! The content and the tags of the custom kinds can be checked by JSON Schema documents.
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{Event, Kinds},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// The encoding of the content
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Empty,
+    Json,
+    Hex,
+    Base64,
+    /// `<base64>?iv=<base64>`
+    Nip04,
+}
+
+impl Encoding {
+    pub fn matches(&self, content: &str) -> bool {
+        match self {
+            Encoding::Empty => content.is_empty(),
+            Encoding::Json => serde_json::from_str::<serde::de::IgnoredAny>(content).is_ok(),
+            Encoding::Hex => {
+                content.len().is_multiple_of(2) && content.bytes().all(|b| b.is_ascii_hexdigit())
+            }
+            Encoding::Base64 => is_base64(content),
+            Encoding::Nip04 => content
+                .split_once("?iv=")
+                .is_some_and(|(data, iv)| is_base64(data) && is_base64(iv)),
+        }
+    }
+}
+
+fn is_base64(s: &str) -> bool {
+    let data = s.trim_end_matches('=');
+    !s.is_empty()
+        && s.len().is_multiple_of(4)
+        && s.len() - data.len() <= 2
+        && data
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+/// A compiled JSON Schema, the document in the config or the json string of the `*_schema_file` setting
+#[derive(Debug, Clone)]
+pub struct Schema(Arc<jsonschema::Validator>);
+
+impl Schema {
+    pub fn new(schema: &Value) -> Result<Self, String> {
+        jsonschema::validator_for(schema)
+            .map(|v| Self(Arc::new(v)))
+            .map_err(|e| format!("invalid schema: {}", e))
+    }
+
+    /// the first error of the value
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        self.0.validate(value).map_err(|e| {
+            let path = e.instance_path.to_string();
+            if path.is_empty() {
+                e.to_string()
+            } else {
+                format!("{} at {}", e, path)
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Schema {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = match Value::deserialize(deserializer)? {
+            Value::String(s) => serde_json::from_str(&s).map_err(de::Error::custom)?,
+            value => value,
+        };
+        Self::new(&value).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Rule {
+    /// the kinds, ranges or groups of the rule
+    pub kinds: Kinds,
+    /// the names of the tags the events must have with a value
+    pub required_tags: Vec<String>,
+    /// the encodings allowed of the content, any when empty
+    pub content: Vec<Encoding>,
+    /// the max tags of the events, no limit when 0
+    pub max_tags: usize,
+    /// the JSON Schema of the content parsed as json
+    pub content_schema: Option<Schema>,
+    /// the JSON Schema of the tags array
+    pub tags_schema: Option<Schema>,
+}
+
+impl Rule {
+    pub fn check(&self, event: &Event) -> Result<(), String> {
+        let kind = event.kind();
+        if !self.kinds.contains(kind) {
+            return Ok(());
+        }
+        let tags = event.tags();
+        if self.max_tags > 0 && tags.len() > self.max_tags {
+            return Err(format!(
+                "invalid: kind {} has more than {} tags",
+                kind, self.max_tags
+            ));
+        }
+        for name in &self.required_tags {
+            if !tags.iter().any(|t| t.len() > 1 && &t[0] == name) {
+                return Err(format!(
+                    "invalid: kind {} requires a \"{}\" tag",
+                    kind, name
+                ));
+            }
+        }
+        if !self.content.is_empty() && !self.content.iter().any(|e| e.matches(event.content())) {
+            return Err(format!(
+                "invalid: content of kind {} is not {}",
+                kind,
+                self.content
+                    .iter()
+                    .map(|e| format!("{:?}", e).to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ));
+        }
+        if let Some(schema) = &self.content_schema {
+            let content: Value = serde_json::from_str(event.content())
+                .map_err(|_| format!("invalid: content of kind {} is not json", kind))?;
+            schema
+                .check(&content)
+                .map_err(|e| format!("invalid: content of kind {}: {}", kind, e))?;
+        }
+        if let Some(schema) = &self.tags_schema {
+            schema
+                .check(&serde_json::json!(tags))
+                .map_err(|e| format!("invalid: tags of kind {}: {}", kind, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ValidationSetting {
+    pub enabled: bool,
+    /// an event is checked by all the rules of its kind
+    pub rules: Vec<Rule>,
+}
+
+impl ValidationSetting {
+    pub fn check(&self, event: &Event) -> Result<(), String> {
+        self.rules.iter().try_for_each(|r| r.check(event))
+    }
+}
+
+#[derive(Debug)]
+pub struct Validator {
+    pub setting: ValidationSetting,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        describe_counter!(
+            "nostr_relay_validation_rejected_total",
+            "The total count of events rejected by the structural rules of the kinds"
+        );
+        Self {
+            setting: ValidationSetting::default(),
+        }
+    }
+}
+
+impl Extension for Validator {
+    fn name(&self) -> &'static str {
+        "validation"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        w.set_extension(self.setting.clone());
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if !self.setting.enabled {
+            return ExtensionMessageResult::Continue(msg);
+        }
+        if let IncomingMessage::Event(event) = &msg.msg {
+            if let Err(err) = self.setting.check(event) {
+                increment_counter!("nostr_relay_validation_rejected_total");
+                return OutgoingMessage::ok(&event.id_str(), false, &err).into();
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use nostr_relay::db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+
+    #[test]
+    fn encoding() {
+        assert!(Encoding::Empty.matches(""));
+        assert!(Encoding::Json.matches(r#"{"name": "a"}"#));
+        assert!(!Encoding::Json.matches("hello"));
+        assert!(Encoding::Hex.matches("0aff"));
+        assert!(!Encoding::Hex.matches("0af"));
+        assert!(Encoding::Base64.matches("aGk="));
+        assert!(!Encoding::Base64.matches("aGk"));
+        assert!(Encoding::Nip04.matches("aGVsbG8h?iv=AAAAAAAAAAAAAAAAAAAAAA=="));
+        assert!(!Encoding::Nip04.matches("hello"));
+    }
+
+    #[test]
+    fn check() -> Result<()> {
+        let setting: ValidationSetting = serde_json::from_value(serde_json::json!({
+            "enabled": true,
+            "rules": [
+                {"kinds": ["addressable"], "required_tags": ["d"]},
+                {"kinds": [4], "required_tags": ["p"], "content": ["nip04"], "max_tags": 2},
+            ]
+        }))?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = |kind: u16, tags: Vec<Vec<&str>>, content: &str| {
+            let tags = tags
+                .into_iter()
+                .map(|t| t.into_iter().map(String::from).collect())
+                .collect();
+            Event::create(&key_pair, now(), kind, tags, content.to_owned()).unwrap()
+        };
+        assert!(setting.check(&event(1, vec![], "hi")).is_ok());
+        assert!(setting
+            .check(&event(30023, vec![vec!["d", "a"]], "hi"))
+            .is_ok());
+        assert_eq!(
+            setting.check(&event(30023, vec![vec!["d"]], "hi")),
+            Err("invalid: kind 30023 requires a \"d\" tag".to_owned())
+        );
+        let pubkey = key_pair.x_only_public_key().0.to_string();
+        let p = vec!["p", pubkey.as_str()];
+        let dm = "aGVsbG8h?iv=AAAAAAAAAAAAAAAAAAAAAA==";
+        assert!(setting.check(&event(4, vec![p.clone()], dm)).is_ok());
+        assert_eq!(
+            setting.check(&event(4, vec![p.clone()], "hello")),
+            Err("invalid: content of kind 4 is not nip04".to_owned())
+        );
+        assert_eq!(
+            setting.check(&event(4, vec![p.clone(), p.clone(), p], dm)),
+            Err("invalid: kind 4 has more than 2 tags".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn schema() -> Result<()> {
+        let content_schema = serde_json::json!({
+            "type": "object",
+            "properties": {"score": {"type": "integer"}},
+            "required": ["score"]
+        });
+        let setting: ValidationSetting = serde_json::from_value(serde_json::json!({
+            "enabled": true,
+            "rules": [{
+                "kinds": [30078],
+                // read from the file of `content_schema_file`
+                "content_schema": content_schema.to_string(),
+                "tags_schema": {"contains": {"prefixItems": [{"const": "d"}], "minItems": 2}}
+            }]
+        }))?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = |tags: Vec<Vec<String>>, content: &str| {
+            Event::create(&key_pair, now(), 30078, tags, content.to_owned()).unwrap()
+        };
+        let d = vec![vec!["d".to_owned(), "game".to_owned()]];
+        assert!(setting.check(&event(d.clone(), r#"{"score": 1}"#)).is_ok());
+        assert_eq!(
+            setting.check(&event(d.clone(), "hi")),
+            Err("invalid: content of kind 30078 is not json".to_owned())
+        );
+        let err = setting.check(&event(d, r#"{"score": "1"}"#)).unwrap_err();
+        assert!(err.starts_with("invalid: content of kind 30078: "));
+        assert!(err.ends_with("at /score"), "{}", err);
+        let err = setting
+            .check(&event(vec![], r#"{"score": 1}"#))
+            .unwrap_err();
+        assert!(err.starts_with("invalid: tags of kind 30078: "), "{}", err);
+
+        let err =
+            serde_json::from_value::<Rule>(serde_json::json!({"content_schema": {"type": 1}}))
+                .unwrap_err();
+        assert!(err.to_string().starts_with("invalid schema"));
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! [NIP-62](https://nips.be/62) requests to vanish, the events of the pubkey are deleted in the background
+//! and the older events are rejected when they are published or imported again
+use metrics::{counter, describe_counter};
+use nostr_relay::{
+    db::{now, Db, Event},
+    message::{ClientMessage, IncomingMessage, OutgoingMessage},
+    setting::SettingWrapper,
+    Extension, ExtensionMessageResult, Session,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, thread};
+use tracing::{error, info};
+
+/// Request to vanish event kind
+pub const VANISH_KIND: u16 = 62;
+
+/// The relay tag value of the requests to all relays
+pub const ALL_RELAYS: &str = "ALL_RELAYS";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VanishSetting {
+    pub enabled: bool,
+    /// the urls of this relay, the requests tagged with one of them or ALL_RELAYS are honored
+    pub urls: Vec<String>,
+    /// number of events deleted in one transaction
+    pub batch: usize,
+}
+
+impl Default for VanishSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            urls: vec![],
+            batch: 1000,
+        }
+    }
+}
+
+impl VanishSetting {
+    /// Whether the relay tags of the request target this relay
+    pub fn targets(&self, event: &Event) -> bool {
+        let normalize = |url: &str| url.trim_end_matches('/').to_ascii_lowercase();
+        event
+            .tags()
+            .iter()
+            .filter(|t| t.len() > 1 && t[0] == "relay")
+            .any(|t| {
+                t[1] == ALL_RELAYS || self.urls.iter().any(|u| normalize(u) == normalize(&t[1]))
+            })
+    }
+}
+
+/// The deletion progress of a vanished pubkey
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct VanishJob {
+    /// the events created until it are deleted
+    pub until: u64,
+    pub started_at: u64,
+    pub deleted: usize,
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+/// The jobs by the hex pubkey
+pub type VanishJobs = RwLock<HashMap<String, VanishJob>>;
+
+pub struct Vanish {
+    pub setting: VanishSetting,
+    db: Arc<Db>,
+    jobs: Arc<VanishJobs>,
+    resumed: bool,
+}
+
+impl Vanish {
+    pub fn new(db: Arc<Db>) -> Self {
+        describe_counter!(
+            "nostr_relay_vanish_deleted_total",
+            "The total count of events deleted by the requests to vanish"
+        );
+        Self {
+            setting: VanishSetting::default(),
+            db,
+            jobs: Arc::default(),
+            resumed: false,
+        }
+    }
+
+    pub fn jobs(&self) -> Arc<VanishJobs> {
+        Arc::clone(&self.jobs)
+    }
+
+    /// Record the request and delete the events of the pubkey in the background
+    pub fn vanish(&self, pubkey: &[u8], until: u64) -> nostr_relay::Result<()> {
+        let mut writer = self.db.writer()?;
+        self.db.put_vanish(&mut writer, pubkey, until)?;
+        self.db.commit(writer)?;
+        self.spawn(pubkey.to_vec(), until);
+        Ok(())
+    }
+
+    /// Delete the events of the recorded requests, ie: the jobs interrupted by a restart
+    fn resume(&self) -> nostr_relay::Result<()> {
+        let list = {
+            let reader = self.db.reader()?;
+            self.db.vanished_list(&reader)?
+        };
+        for (pubkey, until) in list {
+            self.spawn(pubkey, until);
+        }
+        Ok(())
+    }
+
+    fn spawn(&self, pubkey: Vec<u8>, until: u64) {
+        let key = hex::encode(&pubkey);
+        {
+            let mut jobs = self.jobs.write();
+            let job = jobs.entry(key.clone()).or_default();
+            // the running job deletes the events of the later request too
+            if job.started_at > 0 && !job.done {
+                job.until = job.until.max(until);
+                return;
+            }
+            *job = VanishJob {
+                until,
+                started_at: now(),
+                ..Default::default()
+            };
+        }
+        let db = Arc::clone(&self.db);
+        let jobs = Arc::clone(&self.jobs);
+        let batch = self.setting.batch.max(1);
+        thread::spawn(move || loop {
+            let until = match jobs.read().get(&key) {
+                Some(job) => job.until,
+                None => return,
+            };
+            match db.vanish(&pubkey, until, batch) {
+                Ok(0) => {
+                    if let Some(job) = jobs.write().get_mut(&key) {
+                        if job.deleted > 0 {
+                            info!("Vanished {} deleted {} events", key, job.deleted);
+                        }
+                        job.done = true;
+                    }
+                    return;
+                }
+                Ok(num) => {
+                    counter!("nostr_relay_vanish_deleted_total", num as u64);
+                    if let Some(job) = jobs.write().get_mut(&key) {
+                        job.deleted += num;
+                    }
+                }
+                Err(e) => {
+                    error!(error = e.to_string(), "failed to vanish {}", key);
+                    if let Some(job) = jobs.write().get_mut(&key) {
+                        job.error = Some(e.to_string());
+                        job.done = true;
+                    }
+                    return;
+                }
+            }
+        });
+    }
+}
+
+impl Extension for Vanish {
+    fn name(&self) -> &'static str {
+        "vanish"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        self.setting = w.parse_extension(self.name());
+        if self.setting.enabled {
+            w.add_nip(62);
+        }
+        w.set_extension(self.setting.clone());
+        drop(w);
+        if self.setting.enabled && !self.resumed {
+            self.resumed = true;
+            if let Err(e) = self.resume() {
+                error!(
+                    error = e.to_string(),
+                    "failed to resume the vanish requests"
+                );
+            }
+        }
+    }
+
+    fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.app_data(actix_web::web::Data::from(self.jobs()));
+    }
+
+    fn message(
+        &self,
+        msg: ClientMessage,
+        _session: &mut Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) -> ExtensionMessageResult {
+        if let IncomingMessage::Event(event) = &msg.msg {
+            if self.setting.enabled && event.kind() == VANISH_KIND && !self.setting.targets(event) {
+                return OutgoingMessage::ok(
+                    &event.id_str(),
+                    false,
+                    "invalid: the request to vanish does not target this relay",
+                )
+                .into();
+            }
+        }
+        ExtensionMessageResult::Continue(msg)
+    }
+
+    fn event_stored(
+        &self,
+        event: &Event,
+        _session: &Session,
+        _ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        if self.setting.enabled && event.kind() == VANISH_KIND && self.setting.targets(event) {
+            if let Err(e) = self.vanish(event.pubkey(), event.created_at()) {
+                error!(
+                    error = e.to_string(),
+                    "failed to vanish {}",
+                    event.pubkey_str()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::{rand::thread_rng, KeyPair};
+    use std::time::Duration;
+
+    #[test]
+    fn targets() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let setting = VanishSetting {
+            urls: vec!["wss://relay.example.com".to_owned()],
+            ..Default::default()
+        };
+        let request = |relay: &str| {
+            let tags = vec![vec!["relay".to_owned(), relay.to_owned()]];
+            Event::create(&key_pair, now(), VANISH_KIND, tags, "".to_owned())
+        };
+        assert!(setting.targets(&request(ALL_RELAYS)?));
+        assert!(setting.targets(&request("wss://Relay.example.com/")?));
+        assert!(!setting.targets(&request("wss://other.example.com")?));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn vanish() -> Result<()> {
+        let app = create_test_app("vanish")?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..5)
+            .map(|i| Event::create(&key_pair, 100 + i, 1, vec![], "".to_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        app.db.batch_put(&events)?;
+
+        let mut vanish = Vanish::new(app.db.clone());
+        vanish.setting.batch = 2;
+        vanish.vanish(events[0].pubkey(), 103)?;
+        let key = events[0].pubkey_str();
+        for _ in 0..100 {
+            if vanish.jobs.read()[&key].done {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let job = vanish.jobs.read()[&key].clone();
+        assert!(job.done);
+        assert_eq!(job.deleted, 4);
+        assert_eq!(job.until, 103);
+
+        let reader = app.db.reader()?;
+        assert!(app
+            .db
+            .get::<Event, _, _>(&reader, events[4].id())?
+            .is_some());
+        assert!(app
+            .db
+            .get::<Event, _, _>(&reader, events[3].id())?
+            .is_none());
+        assert_eq!(app.db.vanished(&reader, events[0].pubkey())?, Some(103));
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+//! POST the stored events matching the filter of a hook to its url, ie: notification bots,
+//! indexers or moderation pipelines, with retries and an optional HMAC-SHA256 signature.
+use crate::client::http_client;
+use actix::{clock::sleep, Arbiter};
+use actix_web::http::header::CONTENT_TYPE;
+use futures_channel::{mpsc, oneshot};
+use futures_util::{future::select, StreamExt};
+use hmac::{Hmac, Mac};
+use metrics::{describe_counter, increment_counter};
+use nostr_relay::{
+    db::{now, Event, Filter},
+    setting::SettingWrapper,
+    App, Extension, StreamMessage,
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// The unix time of the delivery, signed with the body
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+/// `sha256=<hex>` of the HMAC-SHA256 of `<timestamp>.<body>` with the secret
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+/// The id of the event, the event may be delivered more than once
+pub const EVENT_ID_HEADER: &str = "X-Webhook-Event-Id";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub url: String,
+    /// the stored events matching it are posted, the limit is ignored
+    #[serde(default)]
+    pub filter: Filter,
+    /// sign the requests when set
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct WebhooksSetting {
+    pub enabled: bool,
+    pub hooks: Vec<Hook>,
+    /// the max events waiting for the delivery of a hook, the new events are dropped when full
+    pub queue: usize,
+    /// the retries of a failed delivery, the event is dropped after them
+    pub retries: u32,
+    /// timeout of a request
+    #[serde(with = "nostr_relay::duration")]
+    pub timeout: Duration,
+    /// the first retry delay, doubled up to the max backoff
+    #[serde(with = "nostr_relay::duration")]
+    pub min_backoff: Duration,
+    #[serde(with = "nostr_relay::duration")]
+    pub max_backoff: Duration,
+}
+
+impl Default for WebhooksSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hooks: vec![],
+            queue: 1000,
+            retries: 5,
+            timeout: Duration::from_secs(10),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The value of the signature header, the receiver verifies it with the shared secret
+pub fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    // hmac accepts the keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub struct Webhooks {
+    pub setting: WebhooksSetting,
+    /// the `network.proxy` setting
+    proxy: Option<String>,
+    app: App,
+    /// runs the hook tasks, the settings are reloaded outside the actix runtime
+    arbiter: Arbiter,
+    /// stop the subscriptions of the hooks when dropped, the queued events are still delivered
+    stop: Vec<oneshot::Sender<()>>,
+}
+
+impl Webhooks {
+    pub fn new(app: App) -> Self {
+        describe_counter!(
+            "nostr_relay_webhook_total",
+            "The total count of webhook deliveries by the result"
+        );
+        Self {
+            setting: WebhooksSetting::default(),
+            proxy: None,
+            app,
+            arbiter: Arbiter::new(),
+            stop: vec![],
+        }
+    }
+}
+
+impl Extension for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let r = setting.read();
+        let webhooks: WebhooksSetting = r.parse_extension(self.name());
+        if webhooks == self.setting && r.network.proxy == self.proxy {
+            return;
+        }
+        self.setting = webhooks;
+        self.proxy = r.network.proxy.clone();
+        drop(r);
+        self.stop.clear();
+        if !self.setting.enabled {
+            return;
+        }
+        for hook in self.setting.hooks.clone() {
+            let (stop, stopped) = oneshot::channel();
+            let (setting, app) = (self.setting.clone(), self.app.clone());
+            let proxy = self.proxy.clone();
+            self.arbiter.spawn_fn(move || {
+                let (tx, rx) = mpsc::channel(setting.queue);
+                actix::spawn(deliver(hook.clone(), setting, proxy, rx));
+                actix::spawn(async move {
+                    select(Box::pin(subscribe(hook, app, tx)), stopped).await;
+                });
+            });
+            self.stop.push(stop);
+        }
+    }
+}
+
+/// queue the live events matching the filter of the hook
+async fn subscribe(hook: Hook, app: App, mut tx: mpsc::Sender<Event>) {
+    let mut filter = hook.filter;
+    // skip the stored events
+    filter.limit = Some(0);
+    let mut stream = match app.subscribe(vec![filter]).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                url = hook.url,
+                "webhook subscribe failed"
+            );
+            return;
+        }
+    };
+    while let Some(msg) = stream.next().await {
+        match msg {
+            StreamMessage::Event(event) => {
+                // the ephemeral events are not stored
+                if event.index().is_ephemeral() {
+                    continue;
+                }
+                if tx.try_send(event).is_err() {
+                    increment_counter!("nostr_relay_webhook_total", "result" => "dropped");
+                }
+            }
+            StreamMessage::Notice(notice) => {
+                warn!(url = hook.url, "webhook subscription: {}", notice)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// post the queued events one by one through the proxy until the sender is dropped
+async fn deliver(
+    hook: Hook,
+    setting: WebhooksSetting,
+    proxy: Option<String>,
+    mut rx: mpsc::Receiver<Event>,
+) {
+    let client = match http_client(proxy.as_deref(), setting.timeout) {
+        Ok(client) => client,
+        Err(err) => {
+            error!(error = err, url = hook.url, "webhook client failed");
+            return;
+        }
+    };
+    while let Some(event) = rx.next().await {
+        let body = event.to_string();
+        let mut backoff = setting.min_backoff;
+        let mut retries = 0;
+        loop {
+            match post(&client, &hook, &event.id_str(), &body).await {
+                Ok(()) => {
+                    increment_counter!("nostr_relay_webhook_total", "result" => "delivered");
+                    break;
+                }
+                Err(err) if retries >= setting.retries => {
+                    warn!(
+                        error = err,
+                        url = hook.url,
+                        "webhook dropped {}",
+                        event.id_str()
+                    );
+                    increment_counter!("nostr_relay_webhook_total", "result" => "failed");
+                    break;
+                }
+                Err(_) => {
+                    increment_counter!("nostr_relay_webhook_total", "result" => "retried");
+                    retries += 1;
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(setting.max_backoff);
+                }
+            }
+        }
+    }
+}
+
+async fn post(client: &awc::Client, hook: &Hook, id: &str, body: &str) -> Result<(), String> {
+    let timestamp = now().to_string();
+    let mut req = client
+        .post(&hook.url)
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .insert_header((EVENT_ID_HEADER, id))
+        .insert_header((TIMESTAMP_HEADER, timestamp.as_str()));
+    if let Some(secret) = &hook.secret {
+        req = req.insert_header((SIGNATURE_HEADER, signature(secret, &timestamp, body)));
+    }
+    let res = req
+        .send_body(body.to_owned())
+        .await
+        .map_err(|e| e.to_string())?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("status {}", res.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_test_app;
+    use actix_web::{web, HttpRequest, HttpResponse};
+    use anyhow::Result;
+    use nostr_relay::db::secp256k1::{rand::thread_rng, KeyPair};
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[test]
+    fn sign() {
+        assert_eq!(
+            signature("secret", "1700000000", "{}"),
+            "sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+    }
+
+    /// (signature, body) of the received requests
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+    #[actix_rt::test]
+    async fn deliver() -> Result<()> {
+        // the first request fails
+        let received = Received::default();
+        let srv = actix_test::start({
+            let received = received.clone();
+            move || {
+                let received = received.clone();
+                actix_web::App::new().route(
+                    "/hook",
+                    web::post().to(move |req: HttpRequest, body: String| {
+                        let received = received.clone();
+                        async move {
+                            let header = |name: &str| {
+                                req.headers()
+                                    .get(name)
+                                    .and_then(|h| h.to_str().ok())
+                                    .unwrap_or_default()
+                                    .to_owned()
+                            };
+                            let sig = signature("secret", &header(TIMESTAMP_HEADER), &body);
+                            assert_eq!(header(SIGNATURE_HEADER), sig);
+                            let mut list = received.lock();
+                            list.push((header(EVENT_ID_HEADER), body));
+                            if list.len() == 1 {
+                                HttpResponse::InternalServerError().finish()
+                            } else {
+                                HttpResponse::Ok().finish()
+                            }
+                        }
+                    }),
+                )
+            }
+        });
+
+        let app = create_test_app("webhooks")?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let stored = Event::create(&key_pair, now(), 1984, vec![], "stored".to_owned())?;
+        app.db.batch_put([stored])?;
+        {
+            let mut w = app.setting.write();
+            w.extra = serde_json::from_value(serde_json::json!({
+                "webhooks": {
+                    "enabled": true,
+                    "min_backoff": "10ms",
+                    "hooks": [{
+                        "url": srv.url("/hook"),
+                        "filter": {"kinds": [1984]},
+                        "secret": "secret",
+                    }],
+                }
+            }))?;
+        }
+        let app = app.clone().add_extension(Webhooks::new(app));
+        // wait for the subscription
+        sleep(Duration::from_millis(200)).await;
+
+        let report = Event::create(&key_pair, now(), 1984, vec![], "report".to_owned())?;
+        app.publish(Event::create(
+            &key_pair,
+            now(),
+            1,
+            vec![],
+            "note".to_owned(),
+        )?)
+        .await?;
+        app.publish(report.clone()).await?;
+
+        for _ in 0..50 {
+            if received.lock().len() >= 2 {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        let list = received.lock().clone();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0], list[1]);
+        assert_eq!(list[1].0, report.id_str());
+        let event: Event = serde_json::from_str(&list[1].1)?;
+        assert_eq!(event.content(), "report");
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::while_let_on_iterator)]
+
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use lmdb::{Cursor, Transaction};
 use nostr_kv::lmdb::Transaction as Txn;
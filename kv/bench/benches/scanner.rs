@@ -1,4 +1,4 @@
-#![allow(dead_code, unused)]
+#![allow(dead_code, unused, clippy::while_let_on_iterator)]
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use nostr_kv::{lmdb::Transaction, scanner::*, Error};
@@ -2,6 +2,7 @@ use rand::{distributions::Alphanumeric, Rng};
 use rayon::prelude::*;
 use std::time::Duration;
 
+#[allow(clippy::ptr_arg)]
 pub fn chunk_vec<T: Clone>(vec: &Vec<T>, size: usize) -> Vec<Vec<T>> {
     vec.chunks(size).map(|chunk| chunk.to_vec()).collect()
 }
@@ -61,6 +62,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::inconsistent_digit_grouping)]
     fn fmt() {
         use super::fmt_per_sec;
         use std::time::Duration;
@@ -9,7 +9,7 @@ use std::{
     fs,
     marker::PhantomData,
     mem::{self, MaybeUninit},
-    ops::{Bound, Deref},
+    ops::Bound,
     path::Path,
     ptr, slice,
     sync::Arc,
@@ -106,9 +106,64 @@ pub trait Transaction: Sized {
         iter
     }
 
-    fn iter(&self, tree: &Tree) -> Iter {
+    fn iter(&self, tree: &Tree) -> Iter<'_> {
         self.iter_from(tree, Bound::Unbounded::<Vec<u8>>, false)
     }
+
+    /// Statistics of the tree
+    fn stat(&self, tree: &Tree) -> Result<Stat> {
+        let mut stat = MaybeUninit::uninit();
+        unsafe {
+            lmdb_result(ffi::mdb_stat(self.txn(), tree.inner, stat.as_mut_ptr()))?;
+            Ok(stat.assume_init().into())
+        }
+    }
+}
+
+/// Statistics of a tree or the environment
+#[derive(Debug, Clone, Default)]
+pub struct Stat {
+    pub page_size: u32,
+    pub depth: u32,
+    pub branch_pages: usize,
+    pub leaf_pages: usize,
+    pub overflow_pages: usize,
+    pub entries: usize,
+}
+
+impl Stat {
+    pub fn pages(&self) -> usize {
+        self.branch_pages + self.leaf_pages + self.overflow_pages
+    }
+
+    /// Size of the used pages in bytes
+    pub fn size(&self) -> usize {
+        self.pages() * self.page_size as usize
+    }
+}
+
+impl From<ffi::MDB_stat> for Stat {
+    fn from(stat: ffi::MDB_stat) -> Self {
+        Self {
+            page_size: stat.ms_psize,
+            depth: stat.ms_depth,
+            branch_pages: stat.ms_branch_pages,
+            leaf_pages: stat.ms_leaf_pages,
+            overflow_pages: stat.ms_overflow_pages,
+            entries: stat.ms_entries,
+        }
+    }
+}
+
+/// Information of the environment
+#[derive(Debug, Clone, Default)]
+pub struct Info {
+    pub map_size: usize,
+    /// last used page number, the used size is `(last_page + 1) * page_size`
+    pub last_page: usize,
+    pub last_txn: usize,
+    pub max_readers: u32,
+    pub num_readers: u32,
 }
 
 pub struct Reader<'env> {
@@ -203,6 +258,11 @@ impl<'env> Writer<'env> {
         }
     }
 
+    /// Remove all the items of the tree
+    pub fn clear(&mut self, tree: &Tree) -> Result<()> {
+        unsafe { lmdb_result(ffi::mdb_drop(self.inner, tree.inner, 0)) }
+    }
+
     pub fn del<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K, value: Option<&[u8]>) -> Result<()> {
         let key = key.as_ref();
         let mut key_val: ffi::MDB_val = ffi::MDB_val {
@@ -359,7 +419,7 @@ unsafe impl Send for DbInner {}
 unsafe impl Sync for DbInner {}
 
 impl Db {
-    pub fn writer(&self) -> Result<Writer> {
+    pub fn writer(&self) -> Result<Writer<'_>> {
         Writer::new(&self.inner)
     }
 
@@ -387,7 +447,7 @@ impl Db {
         })
     }
 
-    pub fn reader(&self) -> Result<Reader> {
+    pub fn reader(&self) -> Result<Reader<'_>> {
         Reader::new(&self.inner)
     }
 
@@ -397,6 +457,46 @@ impl Db {
         }
         Ok(())
     }
+
+    /// Copy the environment to the empty directory while it is in use, the free pages are omitted
+    pub fn copy<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::create_dir_all(path).map_err(|e| Error::Message(e.to_string()))?;
+        let c_path = to_cpath(path)?;
+        unsafe {
+            lmdb_result(ffi::mdb_env_copy2(
+                self.inner.inner,
+                c_path.as_ptr(),
+                ffi::MDB_CP_COMPACT,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Statistics of the environment
+    pub fn stat(&self) -> Result<Stat> {
+        let mut stat = MaybeUninit::uninit();
+        unsafe {
+            lmdb_result(ffi::mdb_env_stat(self.inner.inner, stat.as_mut_ptr()))?;
+            Ok(stat.assume_init().into())
+        }
+    }
+
+    /// Information of the environment
+    pub fn info(&self) -> Result<Info> {
+        let mut info = MaybeUninit::<ffi::MDB_envinfo>::uninit();
+        unsafe {
+            lmdb_result(ffi::mdb_env_info(self.inner.inner, info.as_mut_ptr()))?;
+            let info = info.assume_init();
+            Ok(Info {
+                map_size: info.me_mapsize,
+                last_page: info.me_last_pgno,
+                last_txn: info.me_last_txnid,
+                max_readers: info.me_maxreaders,
+                num_readers: info.me_numreaders,
+            })
+        }
+    }
 }
 
 pub struct Iter<'txn> {
@@ -445,7 +545,7 @@ impl<'txn> Iter<'txn> {
                         self.op = ffi::MDB_GET_CURRENT;
                         match inner.get_by_key(start.as_ref(), ffi::MDB_SET_RANGE) {
                             Ok(Some((key, _))) => {
-                                let cmp = key.deref().cmp(start.as_ref());
+                                let cmp = key.cmp(start.as_ref());
                                 match cmp {
                                     Ordering::Greater => {
                                         self.op = ffi::MDB_PREV;
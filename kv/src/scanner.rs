@@ -243,7 +243,7 @@ where
             let mut curs = vec![self.founds.pop().unwrap()];
 
             // dedup
-            while self.founds.len() > 0 {
+            while !self.founds.is_empty() {
                 let item = &self.founds[self.founds.len() - 1];
                 if item.1.cmp(&curs[0].1).is_eq() {
                     curs.push(self.founds.pop().unwrap());
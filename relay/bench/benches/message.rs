@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use nostr_relay::message::IncomingMessage;
+use std::time::Duration;
+
+fn bench_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("message");
+    group.measurement_time(Duration::from_secs(1));
+    group.sample_size(50);
+    group.warm_up_time(Duration::from_millis(100));
+    group.throughput(Throughput::Elements(1));
+
+    let event = r###"["EVENT", {"content":"Just a resource.中文 I own it, I’ve skimmed it. I’ve read it. I think it’s complete. But I have yet to apply it. No livestock on my property yet. \n\nhttps://a.co/d/fBD7pnc","created_at":1682257408,"id":"d877f51f90134aa0ee5572b393a90126e45f00ddc72242b0f9b47e90f864748c","kind":1,"pubkey":"0cf08d280aa5fcfaf340c269abcf66357526fdc90b94b3e9ff6d347a41f090b7","sig":"7d62ec09612b3e303eb8d105a5c99b2a9df6f5497b14465c235b58db2b0db8d834ee320c6d9ede8722773cddfea926a7fa108b1c829ce2208c773ba8aa44d396","tags":[["e","180f289555764f435ab5529f384fb13a79fc8df737c1b661dbaa966195636ff0"],["p","fc87ad313d6dc741dbed5a89720a7e20000b672dba0a901d9620da4c202242dd"],["t","nostr"],["t","rust"]]}]"###;
+    let req = r###"["REQ", "sub_id", {"kinds": [1, 6, 7], "authors": ["0cf08d280aa5fcfaf340c269abcf66357526fdc90b94b3e9ff6d347a41f090b7", "fc87ad313d6dc741dbed5a89720a7e20000b672dba0a901d9620da4c202242dd"], "limit": 100}, {"#e": ["180f289555764f435ab5529f384fb13a79fc8df737c1b661dbaa966195636ff0"]}]"###;
+
+    for (name, text) in [("event", event), ("req", req)] {
+        group.bench_function(format!("{} serde_json", name), |b| {
+            b.iter(|| black_box(serde_json::from_str::<IncomingMessage>(text).unwrap()))
+        });
+        group.bench_function(format!("{} simd-json", name), |b| {
+            b.iter(|| {
+                let mut bytes = text.as_bytes().to_vec();
+                black_box(simd_json::serde::from_slice::<IncomingMessage>(&mut bytes).unwrap())
+            })
+        });
+        group.bench_function(format!("{} from_text", name), |b| {
+            b.iter(|| black_box(IncomingMessage::from_text(text).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_message);
+criterion_main!(benches);
@@ -6,12 +6,11 @@ use tracing::info;
 async fn main() -> nostr_relay::Result<()> {
     tracing_subscriber::fmt::init();
     info!("Start relay server");
-    let app_data = App::create(
-        Some("../rnostr.example.toml"),
-        true,
-        Some("NOSTR".to_owned()),
-        None,
-    )?;
+    let app_data = App::builder()
+        .config("../rnostr.example.toml")
+        .watch(true)
+        .env_prefix("NOSTR")
+        .build()?;
     app_data.web_server()?.await?;
     info!("Relay server shutdown");
     Ok(())
@@ -1,14 +1,25 @@
-use crate::{setting::SettingWrapper, Extension, Extensions, Result, Server, Setting};
-use actix::Addr;
+use crate::{
+    message::{ClientMessage, Connect, IncomingMessage, Publish, Reload, Subscription},
+    setting::{SettingWrapper, Tenant},
+    stream::Listener,
+    Error, EventStream, Extension, Extensions, RelayBuilder, Result, SeenIds, Server, Setting,
+};
+use actix::{Actor, Addr};
 use actix_cors::Cors;
 use actix_web::{
     body::MessageBody,
     dev::{ServiceFactory, ServiceRequest},
-    web, App as WebApp, HttpServer,
+    guard,
+    web::{self, ServiceConfig},
+    App as WebApp, HttpServer,
 };
-use nostr_db::Db;
+use futures_channel::mpsc;
+use nostr_db::{CheckEventResult, Db, Event, Filter};
 use parking_lot::RwLock;
-use std::{path::Path, sync::Arc};
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+};
 use tracing::info;
 
 pub mod route {
@@ -46,17 +57,24 @@ pub mod route {
         data: web::Data<App>,
     ) -> Result<HttpResponse, Error> {
         let r = data.setting.read();
-        let ip = get_ip(&req, r.network.real_ip_header.as_ref());
-        let max_size = r.limitation.max_message_length;
+        let ip = get_ip(&req, r.network.real_ip_header.as_ref()).map(|ip| r.network.client_ip(&ip));
+        // the roles may send longer messages after auth, the session validates the length
+        let max_size = r.max_message_length();
         drop(r);
 
-        let session = Session::new(ip.unwrap_or_default(), data);
+        #[allow(unused_mut)]
+        let mut session = Session::new(ip.unwrap_or_default(), data);
+        #[cfg(feature = "cbor")]
+        {
+            session.cbor = crate::cbor::requested(&req);
+        }
 
         // ws::start(session, &req, stream)
         // The default max frame size is 60k, change from setting.
-        ws::WsResponseBuilder::new(session, &req, stream)
-            .frame_size(max_size)
-            .start()
+        let builder = ws::WsResponseBuilder::new(session, &req, stream).frame_size(max_size);
+        #[cfg(feature = "cbor")]
+        let builder = builder.protocols(&[crate::cbor::PROTOCOL]);
+        builder.start()
     }
 
     pub async fn information(
@@ -97,12 +115,17 @@ pub mod route {
     }
 }
 
-/// App with data
+/// App with data, the clones share the server and the setting
+#[derive(Clone)]
 pub struct App {
     pub server: Addr<Server>,
     pub db: Arc<Db>,
     pub setting: SettingWrapper,
     pub extensions: Arc<RwLock<Extensions>>,
+    /// the ids of the recently stored events
+    pub seen: Arc<SeenIds>,
+    /// the virtual relays served by the web app before this relay
+    pub tenants: Arc<RwLock<Vec<(Tenant, App)>>>,
 }
 
 impl App {
@@ -115,22 +138,30 @@ impl App {
     ) -> Result<Self> {
         let extensions = Arc::new(RwLock::new(Extensions::default()));
         let c_extensions = Arc::clone(&extensions);
+        // the server is started after the setting is loaded
+        let server_cell: Arc<OnceLock<Addr<Server>>> = Arc::new(OnceLock::new());
+        let c_server = Arc::clone(&server_cell);
         let env_notice = setting_env_prefix
             .as_ref()
             .map(|s| {
                 format!(
-                    ", config will be overrided by ENV seting with prefix `{}_`",
+                    ", config will be overrided by ENV seting with prefix `{}__`",
                     s
                 )
             })
             .unwrap_or_default();
 
-        let setting = if watch && setting_path.is_some() {
-            let path = setting_path.as_ref().unwrap().as_ref();
+        let setting = if let Some(path) = setting_path.as_ref().filter(|_| watch) {
+            let path = path.as_ref();
             info!("Watch config file {:?}{}", path, env_notice);
             SettingWrapper::watch(path, setting_env_prefix, move |s| {
                 let mut w = c_extensions.write();
                 w.call_setting(s);
+                drop(w);
+                // re-evaluate the connected sessions with the new setting
+                if let Some(server) = c_server.get() {
+                    server.do_send(Reload);
+                }
             })?
         } else if let Some(path) = setting_path {
             info!("Load config {:?}{}", path.as_ref(), env_notice);
@@ -143,6 +174,16 @@ impl App {
             Setting::default().into()
         };
 
+        Self::open(setting, data_path, extensions, server_cell)
+    }
+
+    /// Start the server with the setting, use [`App::builder`] for the setting without a file
+    fn open<P: AsRef<Path>>(
+        setting: SettingWrapper,
+        data_path: Option<P>,
+        extensions: Arc<RwLock<Extensions>>,
+        server_cell: Arc<OnceLock<Addr<Server>>>,
+    ) -> Result<Self> {
         {
             info!("{:?}", setting.read());
         }
@@ -150,32 +191,144 @@ impl App {
         let r = setting.read();
         let path = data_path
             .map(|p| p.as_ref().to_path_buf())
-            .unwrap_or_else(|| r.data.path.clone())
-            .join("events");
+            .unwrap_or_else(|| r.data.path.clone());
+        let seen = Arc::new(SeenIds::new(r.data.seen_ids));
         drop(r);
-        let db = Arc::new(Db::open(path)?);
+        let db = Arc::new(Db::open(path.join("events"))?);
         db.check_schema()?;
 
-        let server = Server::create_with(db.clone(), setting.clone());
+        let server = Server::create_in(db.clone(), setting.clone(), path, seen.clone());
+        let _ = server_cell.set(server.clone());
 
         Ok(Self {
             server,
             setting,
             db,
             extensions,
+            seen,
+            tenants: Default::default(),
         })
     }
 
+    /// Create the app from the setting instead of a config file
+    pub fn with_setting<P: AsRef<Path>>(setting: Setting, data_path: Option<P>) -> Result<Self> {
+        Self::open(
+            setting.into(),
+            data_path,
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    /// Builder for embedding the relay in an application
+    pub fn builder() -> RelayBuilder {
+        RelayBuilder::default()
+    }
+
+    /// Publish the event through the extensions and write it without a websocket session,
+    /// the subscribers receive it when it is new.
+    pub async fn publish(&self, event: Event) -> Result<CheckEventResult> {
+        self.publish_event(event, false, false).await
+    }
+
+    /// Publish the event like [`App::publish`], it is written with the events of the priority sessions
+    pub async fn publish_priority(&self, event: Event) -> Result<CheckEventResult> {
+        self.publish_event(event, false, true).await
+    }
+
+    /// Publish the event of a trusted source like [`App::publish`], the id is checked but not the signature
+    pub async fn publish_trusted(&self, event: Event) -> Result<CheckEventResult> {
+        self.publish_event(event, true, false).await
+    }
+
+    async fn publish_event(
+        &self,
+        event: Event,
+        trusted: bool,
+        priority: bool,
+    ) -> Result<CheckEventResult> {
+        let mut msg = ClientMessage {
+            id: 0,
+            text: Default::default(),
+            msg: IncomingMessage::Event(event),
+        };
+        if trusted {
+            msg.validate_trusted(&self.setting.read().limitation)?;
+        } else {
+            msg.validate(&self.setting.read().limitation)?;
+        }
+        let IncomingMessage::Event(event) = msg.msg else {
+            unreachable!()
+        };
+        self.extensions
+            .read()
+            .call_publish(&event)
+            .map_err(Error::Message)?;
+        self.server
+            .send(Publish { event, priority })
+            .await
+            .map_err(|e| Error::Message(e.to_string()))?
+    }
+
+    /// Subscribe the stored and live events matching the filters without a websocket session,
+    /// the extensions are not called. The subscription is closed when the stream is dropped.
+    pub async fn subscribe(&self, filters: Vec<Filter>) -> Result<EventStream> {
+        let (tx, rx) = mpsc::unbounded();
+        let listener = Listener::new(tx);
+        let backfill = listener.backfill.clone();
+        let listener = listener.start();
+        let id = self
+            .server
+            .send(Connect {
+                addr: listener.clone().recipient(),
+                reload: listener.clone().recipient(),
+                stored: listener.clone().recipient(),
+                control: listener.clone().recipient(),
+                read: listener.recipient(),
+                backfill,
+                max_subscriptions: Default::default(),
+                priority: Default::default(),
+                authed: Default::default(),
+            })
+            .await
+            .map_err(|e| Error::Message(e.to_string()))?;
+        let stream = EventStream {
+            id,
+            server: self.server.clone(),
+            rx,
+        };
+        let mut msg = ClientMessage {
+            id,
+            text: Default::default(),
+            msg: IncomingMessage::Req(Subscription {
+                id: "stream".to_owned(),
+                filters,
+                resume: None,
+            }),
+        };
+        msg.validate(&self.setting.read().limitation)?;
+        self.server.do_send(msg);
+        Ok(stream)
+    }
+
     pub fn add_extension<E: Extension + 'static>(self, mut ext: E) -> Self {
         info!("Add extension {}", ext.name());
         ext.setting(&self.setting);
         {
             let mut w = self.extensions.write();
             w.add(ext);
+            w.sort(&self.setting.read().extension.order);
         }
         self
     }
 
+    /// Serve the tenant app for the requests matching the hostname and the url path of the tenant
+    pub fn add_tenant(self, tenant: Tenant, app: App) -> Self {
+        info!("Add tenant host: {:?} path: {:?}", tenant.host, tenant.path);
+        self.tenants.write().push((tenant, app));
+        self
+    }
+
     pub fn web_app(
         self,
     ) -> WebApp<
@@ -220,21 +373,40 @@ pub fn create_web_app(
         InitError = (),
     >,
 > {
-    let app = WebApp::new();
+    WebApp::new().configure(|cfg| configure(cfg, data)).wrap(
+        Cors::default()
+            .send_wildcard()
+            .allow_any_header()
+            .allow_any_origin()
+            .allow_any_method()
+            .max_age(86_400), // 24h
+    )
+}
+
+/// Configure the relay services, mount the relay into an existing actix web app:
+/// `WebApp::new().service(web::scope("/relay").configure(|cfg| configure(cfg, data)))`
+pub fn configure(cfg: &mut ServiceConfig, data: web::Data<App>) {
+    let tenants = data.tenants.read().clone();
+    for (tenant, app) in tenants {
+        let path = tenant.path.unwrap_or_default();
+        let path = path.trim_end_matches('/');
+        let mut scope = web::scope(path);
+        if let Some(host) = tenant.host {
+            scope = scope.guard(guard::Host(host));
+        }
+        cfg.service(scope.configure(|cfg| {
+            configure(cfg, web::Data::new(app));
+            if !path.is_empty() {
+                // the url of the relay without the trailing slash
+                cfg.service(web::resource("").route(web::get().to(route::index)));
+            }
+        }));
+    }
+
     let extensions = data.extensions.clone();
-    app.app_data(data)
-        .configure(|cfg| {
-            extensions.write().call_config_web(cfg);
-        })
-        .service(web::resource("/").route(web::get().to(route::index)))
-        .wrap(
-            Cors::default()
-                .send_wildcard()
-                .allow_any_header()
-                .allow_any_origin()
-                .allow_any_method()
-                .max_age(86_400), // 24h
-        )
+    cfg.app_data(data);
+    extensions.write().call_config_web(cfg);
+    cfg.service(web::resource("/").route(web::get().to(route::index)));
 }
 
 #[cfg(test)]
@@ -276,6 +448,232 @@ pub mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn builder() -> Result<()> {
+        use crate::{configure, temp_data_path, App, Setting};
+        use actix_web::web;
+
+        let mut setting = Setting::default();
+        setting.information.name = "embedded".to_owned();
+        let path = temp_data_path("builder")?;
+        let app = App::builder().setting(setting).db(path.path()).build()?;
+        assert_eq!(app.setting.read().information.name, "embedded");
+        let data = web::Data::new(app);
+
+        // mount into an existing app
+        let mut srv = actix_test::start(move || {
+            let data = data.clone();
+            actix_web::App::new()
+                .service(web::scope("/relay").configure(|cfg| configure(cfg, data)))
+                .route("/", web::get().to(|| async { "host" }))
+        });
+        let mut res = srv.get("/").send().await.unwrap();
+        assert_eq!(res.body().await?, Bytes::from_static(b"host"));
+
+        let mut res = srv
+            .get("/relay/")
+            .insert_header(("Accept", "application/nostr+json"))
+            .send()
+            .await
+            .unwrap();
+        let body = String::from_utf8(res.body().await?.to_vec())?;
+        assert!(body.contains("embedded"));
+
+        let mut framed = srv.ws_at("/relay/").await.unwrap();
+        framed.send(ws::Message::Ping("text".into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(item, ws::Frame::Pong(Bytes::copy_from_slice(b"text")));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn tenants() -> Result<()> {
+        use crate::{
+            db::{
+                now,
+                secp256k1::{rand::thread_rng, KeyPair},
+                Event, Filter,
+            },
+            setting::Tenant,
+            temp_data_path, App, Setting,
+        };
+        use actix_web::web;
+
+        let create = |name: &str| -> Result<App> {
+            let mut setting = Setting::default();
+            setting.information.name = name.to_owned();
+            let path = temp_data_path(name)?;
+            Ok(App::builder().setting(setting).db(path.path()).build()?)
+        };
+        let alice = create("alice")?;
+        let alice_db = alice.db.clone();
+        let main = create("main")?;
+        let main_db = main.db.clone();
+        let data = web::Data::new(
+            main.add_tenant(
+                Tenant {
+                    path: Some("/alice/".to_owned()),
+                    ..Default::default()
+                },
+                alice,
+            )
+            .add_tenant(
+                Tenant {
+                    host: Some("bob.example.com".to_owned()),
+                    ..Default::default()
+                },
+                create("bob")?,
+            ),
+        );
+        let mut srv = actix_test::start(move || crate::create_web_app(data.clone()));
+
+        for (path, host, name) in [
+            ("/", "localhost", "main"),
+            ("/alice", "localhost", "alice"),
+            ("/alice/", "localhost", "alice"),
+            ("/", "bob.example.com", "bob"),
+        ] {
+            let mut res = srv
+                .get(path)
+                .insert_header(("Host", host))
+                .insert_header(("Accept", "application/nostr+json"))
+                .send()
+                .await
+                .unwrap();
+            let body = String::from_utf8(res.body().await?.to_vec())?;
+            assert!(body.contains(&format!(r#""name": "{}""#, name)), "{}", body);
+        }
+
+        // the events are stored in the data of the tenant
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, now(), 1, vec![], "note".to_owned())?;
+        let mut framed = srv.ws_at("/alice").await.unwrap();
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+        let filter = Filter::default();
+        assert_eq!(
+            alice_db
+                .iter::<Event, _>(&alice_db.reader()?, &filter)?
+                .count(),
+            1
+        );
+        assert_eq!(
+            main_db
+                .iter::<Event, _>(&main_db.reader()?, &filter)?
+                .count(),
+            0
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn publish() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            CheckEventResult, Event,
+        };
+        use actix_web::web;
+
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let data = web::Data::new(create_test_app("publish")?);
+        let c_data = data.clone();
+        let mut srv = actix_test::start(move || crate::create_web_app(c_data.clone()));
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::from_static(br#"["EOSE","1"]"#))
+        );
+
+        let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
+        let res = data.publish(event.clone()).await?;
+        assert!(matches!(res, CheckEventResult::Ok(_)));
+        let ws::Frame::Text(text) = framed.next().await.unwrap()? else {
+            panic!("invalid frame type");
+        };
+        assert!(String::from_utf8(text.to_vec())?.contains(&event.id_str()));
+
+        let res = data.publish_priority(event).await?;
+        assert!(matches!(res, CheckEventResult::Duplicate));
+
+        // too new
+        let event = Event::create(&key_pair, now() + 3600, 1, vec![], "test".to_owned())?;
+        assert!(data.publish(event).await.is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn seen() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            CheckEventResult, Event,
+        };
+
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let app = create_test_app("seen")?;
+        let old = Event::create(&key_pair, now() - 10, 0, vec![], "old".to_owned())?;
+        app.publish(old.clone()).await?;
+        assert!(app.seen.contains(old.id()));
+        // replaced
+        let new = Event::create(&key_pair, now(), 0, vec![], "new".to_owned())?;
+        app.publish(new.clone()).await?;
+        assert!(!app.seen.contains(old.id()));
+        assert!(app.seen.contains(new.id()));
+        // deleted without the writer
+        app.db.vanish(
+            key_pair.x_only_public_key().0.serialize().as_slice(),
+            now(),
+            10,
+        )?;
+        assert!(!app.seen.contains(new.id()));
+        assert!(matches!(app.publish(new).await?, CheckEventResult::Ok(_)));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn subscribe() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event, Filter,
+        };
+        use crate::StreamMessage;
+
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let app = create_test_app("subscribe")?;
+        let stored = Event::create(&key_pair, now(), 1, vec![], "stored".to_owned())?;
+        app.publish(stored.clone()).await?;
+
+        let filter: Filter = serde_json::from_str(r#"{"kinds": [1]}"#)?;
+        let mut stream = app.subscribe(vec![filter]).await?;
+        assert!(
+            matches!(stream.next().await, Some(StreamMessage::Event(e)) if e.id() == stored.id())
+        );
+        assert!(matches!(stream.next().await, Some(StreamMessage::Eose)));
+
+        let live = Event::create(&key_pair, now(), 1, vec![], "live".to_owned())?;
+        app.publish(live.clone()).await?;
+        // not matched
+        let other = Event::create(&key_pair, now(), 2, vec![], "other".to_owned())?;
+        app.publish(other).await?;
+        assert!(
+            matches!(stream.next().await, Some(StreamMessage::Event(e)) if e.id() == live.id())
+        );
+        drop(stream);
+
+        let filter: Filter = serde_json::from_str(r#"{"limit": 10000}"#)?;
+        assert!(app.subscribe(vec![filter]).await.is_err());
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn connect_ws() -> Result<()> {
         let mut srv = actix_test::start(|| {
@@ -0,0 +1,76 @@
+use crate::{App, Extension, Result, Setting};
+use std::path::PathBuf;
+
+type AddExtension = Box<dyn FnOnce(App) -> App>;
+
+/// Build the relay [`App`] for embedding in an application.
+///
+/// ```no_run
+/// # async fn run() -> nostr_relay::Result<()> {
+/// let app = nostr_relay::App::builder()
+///     .config("./config/rnostr.toml")
+///     .watch(true)
+///     .db("./data")
+///     .build()?;
+/// // run standalone, or mount into an actix web app with `nostr_relay::configure`
+/// app.web_server()?.await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct RelayBuilder {
+    config: Option<PathBuf>,
+    watch: bool,
+    env_prefix: Option<String>,
+    setting: Option<Setting>,
+    db: Option<PathBuf>,
+    extensions: Vec<AddExtension>,
+}
+
+impl RelayBuilder {
+    /// read the setting from the config file
+    pub fn config<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config = Some(path.into());
+        self
+    }
+
+    /// reload the config file when changed
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// override the setting by ENV with the prefix, ie: `RNOSTR__NETWORK__PORT`
+    pub fn env_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.env_prefix = Some(prefix.into());
+        self
+    }
+
+    /// use the setting instead of a config file
+    pub fn setting(mut self, setting: Setting) -> Self {
+        self.setting = Some(setting);
+        self
+    }
+
+    /// the data path, overwrite the data path of the setting
+    pub fn db<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.db = Some(path.into());
+        self
+    }
+
+    /// add the extension, the extensions run in the adding order
+    pub fn extension<E: Extension + 'static>(mut self, ext: E) -> Self {
+        self.extensions
+            .push(Box::new(move |app: App| app.add_extension(ext)));
+        self
+    }
+
+    /// create the app and start the server actors, requires a running actix system
+    pub fn build(self) -> Result<App> {
+        let app = match self.setting {
+            Some(setting) => App::with_setting(setting, self.db)?,
+            None => App::create(self.config, self.watch, self.env_prefix, self.db)?,
+        };
+        Ok(self.extensions.into_iter().fold(app, |app, add| add(app)))
+    }
+}
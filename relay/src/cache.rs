@@ -0,0 +1,211 @@
+use nostr_db::{Event, Filter};
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// NIP-09 deletion event kind, the deleted events may match any cached filter
+const DELETION_KIND: u16 = 5;
+/// The low bits of the generation count the writes being committed, the high bits the finished writes
+const WRITING_BITS: u32 = 16;
+const WRITING_MASK: u64 = (1 << WRITING_BITS) - 1;
+
+struct Entry {
+    filter: Filter,
+    events: Arc<Vec<String>>,
+    /// the generation of the snapshot read
+    generation: u64,
+    expires: Instant,
+    hits: u64,
+}
+
+/// Shared results of the repeated filters for a short time, ie: the global feed every client requests.
+/// The entries matching the written events are removed.
+///
+/// The generation counts the writes being committed and invalidated, a reader sees the same
+/// generation without a write in progress before and after opening its transaction only if no write
+/// changed the snapshot, so the cached results served with the stored events of the snapshot are consistent with it.
+/// The writes of the writer and the deletions committed by the db may overlap.
+#[derive(Default)]
+pub struct ResultCache {
+    entries: Mutex<Vec<Entry>>,
+    generation: AtomicU64,
+}
+
+impl std::fmt::Debug for ResultCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResultCache")
+            .field("entries", &self.entries.lock().len())
+            .finish()
+    }
+}
+
+impl ResultCache {
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The generation of the snapshot opened between the two loaded generations, none if written meanwhile
+    pub fn snapshot(before: u64, after: u64) -> Option<u64> {
+        (before == after && before & WRITING_MASK == 0).then_some(before)
+    }
+
+    /// The serialized events of the same filter, cached at or before the snapshot
+    pub fn get(&self, filter: &Filter, generation: u64) -> Option<Arc<Vec<String>>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .iter_mut()
+            .find(|e| e.expires > now && e.generation <= generation && e.filter == *filter)?;
+        entry.hits += 1;
+        Some(Arc::clone(&entry.events))
+    }
+
+    /// Cache the results read at the generation, the least hit entry is replaced when full
+    pub fn insert(
+        &self,
+        filter: Filter,
+        events: Vec<String>,
+        generation: u64,
+        ttl: Duration,
+        capacity: usize,
+    ) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        // written after the read
+        if capacity == 0 || self.generation() != generation {
+            return;
+        }
+        entries.retain(|e| e.expires > now && e.filter != filter);
+        if entries.len() >= capacity {
+            if let Some(index) = entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, e)| e.hits)
+                .map(|(i, _)| i)
+            {
+                entries.swap_remove(index);
+            }
+        }
+        entries.push(Entry {
+            filter,
+            events: Arc::new(events),
+            generation,
+            expires: now + ttl,
+            hits: 0,
+        });
+    }
+
+    /// Mark the write before committing it, finished by `invalidate` or `clear`
+    pub fn begin_write(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Finish the write, one less in progress and one more finished
+    fn end_write(&self) {
+        self.generation
+            .fetch_add((1 << WRITING_BITS) - 1, Ordering::AcqRel);
+    }
+
+    /// Remove the results the written events change after committing them
+    pub fn invalidate(&self, events: &[Event]) {
+        let mut entries = self.entries.lock();
+        self.end_write();
+        if events.iter().any(|e| e.kind() == DELETION_KIND) {
+            entries.clear();
+        } else {
+            entries.retain(|entry| !events.iter().any(|e| entry.filter.match_event(e)));
+        }
+    }
+
+    /// Remove all results after committing the write, ie: deleting the expired events
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock();
+        self.end_write();
+        entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use nostr_db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn cache() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let cache = ResultCache::default();
+        let ttl = Duration::from_secs(5);
+        let notes = Filter::from_str(r#"{"kinds": [1]}"#)?;
+        let profiles = Filter::from_str(r#"{"kinds": [0]}"#)?;
+
+        let generation = cache.generation();
+        cache.insert(notes.clone(), vec!["1".to_owned()], generation, ttl, 2);
+        cache.insert(profiles.clone(), vec![], generation, ttl, 2);
+        assert_eq!(
+            cache.get(&notes, generation).unwrap().as_slice(),
+            ["1".to_owned()]
+        );
+        assert!(cache.get(&profiles, generation).is_some());
+
+        // written while the snapshot is opened
+        cache.begin_write();
+        assert_eq!(ResultCache::snapshot(generation, cache.generation()), None);
+        let note = Event::create(&key_pair, now(), 1, vec![], "".to_owned())?;
+        cache.invalidate(&[note]);
+        let next = cache.generation();
+        assert!(next > generation);
+        assert_eq!(ResultCache::snapshot(next, cache.generation()), Some(next));
+
+        // invalidated by the matched event
+        assert!(cache.get(&notes, next).is_none());
+        assert!(cache.get(&profiles, next).is_some());
+
+        // read before the write
+        cache.insert(notes.clone(), vec![], generation, ttl, 2);
+        assert!(cache.get(&notes, next).is_none());
+
+        // the newer results are not served to the older snapshot
+        cache.insert(notes.clone(), vec![], next, ttl, 2);
+        assert!(cache.get(&notes, generation).is_none());
+        assert!(cache.get(&notes, next).is_some());
+
+        // the least hit is replaced
+        for _ in 0..3 {
+            cache.get(&notes, next);
+        }
+        let all = Filter::default();
+        cache.insert(all.clone(), vec![], next, ttl, 2);
+        assert!(cache.get(&profiles, next).is_none());
+        assert!(cache.get(&notes, next).is_some());
+
+        // the overlapped writes, no snapshot until both are finished
+        cache.begin_write();
+        cache.begin_write();
+        cache.clear();
+        assert_eq!(
+            ResultCache::snapshot(cache.generation(), cache.generation()),
+            None
+        );
+        cache.clear();
+        let next = cache.generation();
+        assert_eq!(ResultCache::snapshot(next, next), Some(next));
+        cache.insert(all.clone(), vec![], next, ttl, 2);
+
+        // the deletion removes all
+        let deletion = Event::create(&key_pair, now(), 5, vec![], "".to_owned())?;
+        cache.begin_write();
+        cache.invalidate(&[deletion]);
+        assert!(cache.get(&all, cache.generation()).is_none());
+        Ok(())
+    }
+}
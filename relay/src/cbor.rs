@@ -0,0 +1,46 @@
+//! The optional binary encoding of the messages, negotiated by the websocket subprotocol.
+//! The messages are the same arrays as the json messages, encoded as CBOR in binary frames.
+use crate::{Error, Result};
+use actix_web::HttpRequest;
+use serde_json::Value;
+
+/// The websocket subprotocol of the CBOR messages
+pub const PROTOCOL: &str = "nostr.cbor";
+
+/// The client requests the CBOR messages
+pub fn requested(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|p| p.trim() == PROTOCOL)
+}
+
+/// Encode the json message
+pub fn encode(text: &str) -> Result<Vec<u8>> {
+    let value: Value = serde_json::from_str(text)?;
+    let mut buf = Vec::with_capacity(text.len());
+    ciborium::into_writer(&value, &mut buf).map_err(|e| Error::Message(e.to_string()))?;
+    Ok(buf)
+}
+
+/// Decode the message to json
+pub fn decode(bytes: &[u8]) -> Result<String> {
+    let value: Value = ciborium::from_reader(bytes).map_err(|e| Error::Message(e.to_string()))?;
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let text = r##"["REQ","1",{"#t":["nostr"],"kinds":[1],"limit":10}]"##;
+        let bytes = encode(text)?;
+        assert!(bytes.len() < text.len());
+        assert_eq!(decode(&bytes)?, text);
+        assert!(decode(b"\xff").is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,15 @@
 use crate::{
+    db::Event,
     message::{ClientMessage, OutgoingMessage},
     setting::SettingWrapper,
     Session,
 };
 use actix_web::web::ServiceConfig;
+use futures_util::future::LocalBoxFuture;
+use std::collections::HashMap;
+use tracing::info;
 
+#[allow(clippy::large_enum_variant)]
 pub enum ExtensionMessageResult {
     /// Continue run the next extension message method, the server takes over finally.
     Continue(ClientMessage),
@@ -12,14 +17,32 @@ pub enum ExtensionMessageResult {
     Stop(OutgoingMessage),
     /// Stop run the next, does not send any messages to the client.
     Ignore,
+    /// Wait for the future, ie: a network call, then handle the result as returned by the message method.
+    /// The session processes its next messages after the future resolves, the message fails after the timeout.
+    Wait(ExtensionFuture),
 }
 
+/// The future of an async extension message result, resolved by the session actor
+pub type ExtensionFuture = LocalBoxFuture<'static, ExtensionMessageResult>;
+
 impl From<OutgoingMessage> for ExtensionMessageResult {
     fn from(value: OutgoingMessage) -> Self {
         Self::Stop(value)
     }
 }
 
+/// The OK answer of an event of the session
+#[derive(Debug, Clone)]
+pub struct EventResult<'a> {
+    pub id: &'a str,
+    /// empty when the event of the answer is unknown
+    pub pubkey: &'a str,
+    pub accepted: bool,
+    pub message: &'a str,
+    /// the name of the extension that answered, "relay" for the validation and the writer
+    pub by: &'a str,
+}
+
 /// Extension for user session
 pub trait Extension: Send + Sync {
     fn name(&self) -> &'static str;
@@ -40,6 +63,37 @@ pub trait Extension: Send + Sync {
     #[allow(unused_variables)]
     fn disconnected(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {}
 
+    /// Execute for each connected session after the setting reload, re-evaluate the session with the new setting
+    #[allow(unused_variables)]
+    fn reloaded(&self, session: &mut Session, ctx: &mut <Session as actix::Actor>::Context) {}
+
+    /// Execute after the writer persisted a new event of the session,
+    /// the rejected and duplicate events are skipped
+    #[allow(unused_variables)]
+    fn event_stored(
+        &self,
+        event: &Event,
+        session: &Session,
+        ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+    }
+
+    /// Execute before a message from the server or a message method is sent to the client,
+    /// ie: strip tags of the events served to unauthenticated sessions
+    #[allow(unused_variables)]
+    fn outgoing(&self, msg: &mut OutgoingMessage, session: &Session) {}
+
+    /// Execute after the OK message of an event is sent to the client, ie: audit the accepted and rejected events
+    #[allow(unused_variables)]
+    fn event_result(&self, result: &EventResult, session: &Session) {}
+
+    /// Execute when an event is published by [`crate::App::publish`] without a session,
+    /// return the message to reject it
+    #[allow(unused_variables)]
+    fn publish(&self, event: &Event) -> Result<(), String> {
+        Ok(())
+    }
+
     /// Execute when message incoming
     #[allow(unused_variables)]
     fn message(
@@ -55,22 +109,81 @@ pub trait Extension: Send + Sync {
 /// extensions
 #[derive(Default)]
 pub struct Extensions {
-    list: Vec<Box<dyn Extension>>,
+    /// extensions with the registration index
+    list: Vec<(usize, Box<dyn Extension>)>,
+    /// extension names disabled by the setting
+    disabled: Vec<String>,
+    /// enabled or disabled at runtime, take precedence over the setting
+    overrides: HashMap<String, bool>,
 }
 
 impl Extensions {
     pub fn add<E: Extension + 'static>(&mut self, ext: E) {
-        self.list.push(Box::new(ext));
+        self.list.push((self.list.len(), Box::new(ext)));
+    }
+
+    /// extension names in the running order
+    pub fn names(&self) -> Vec<&'static str> {
+        self.list.iter().map(|(_, ext)| ext.name()).collect()
+    }
+
+    /// Whether the session hooks of the extension run
+    pub fn enabled(&self, name: &str) -> bool {
+        self.overrides
+            .get(name)
+            .copied()
+            .unwrap_or_else(|| !self.disabled.iter().any(|n| n == name))
+    }
+
+    /// Enable or disable the extension at runtime, it takes precedence over the setting.
+    /// Returns false if the extension does not exist.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        if !self.names().contains(&name) {
+            return false;
+        }
+        info!(
+            "{} extension {}",
+            if enabled { "Enable" } else { "Disable" },
+            name
+        );
+        self.overrides.insert(name.to_owned(), enabled);
+        true
+    }
+
+    /// the enabled extensions in the running order
+    fn active(&self) -> impl Iterator<Item = &Box<dyn Extension>> {
+        self.list
+            .iter()
+            .map(|(_, ext)| ext)
+            .filter(|ext| self.enabled(ext.name()))
+    }
+
+    /// Sort the extensions by the names in order, the unlisted run after them in the registration order
+    pub fn sort(&mut self, order: &[String]) {
+        let before = self.names();
+        self.list.sort_by_key(|(index, ext)| {
+            let pos = order.iter().position(|name| name == ext.name());
+            (pos.unwrap_or(usize::MAX), *index)
+        });
+        let after = self.names();
+        if before != after {
+            info!("Extension order {:?}", after);
+        }
     }
 
     pub fn call_setting(&mut self, setting: &SettingWrapper) {
-        for ext in &mut self.list {
+        for (_, ext) in &mut self.list {
             ext.setting(setting);
         }
+        let r = setting.read();
+        let order = r.extension.order.clone();
+        self.disabled = r.extension.disabled.clone();
+        drop(r);
+        self.sort(&order);
     }
 
     pub fn call_config_web(&mut self, cfg: &mut ServiceConfig) {
-        for ext in &mut self.list {
+        for (_, ext) in &mut self.list {
             ext.config_web(cfg);
         }
     }
@@ -80,7 +193,7 @@ impl Extensions {
         session: &mut Session,
         ctx: &mut <Session as actix::Actor>::Context,
     ) {
-        for ext in &self.list {
+        for ext in self.active() {
             ext.connected(session, ctx);
         }
     }
@@ -90,31 +203,154 @@ impl Extensions {
         session: &mut Session,
         ctx: &mut <Session as actix::Actor>::Context,
     ) {
-        for ext in &self.list {
+        for ext in self.active() {
             ext.disconnected(session, ctx);
         }
     }
 
+    pub fn call_reloaded(
+        &self,
+        session: &mut Session,
+        ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        for ext in self.active() {
+            ext.reloaded(session, ctx);
+        }
+    }
+
+    pub fn call_event_stored(
+        &self,
+        event: &Event,
+        session: &Session,
+        ctx: &mut <Session as actix::Actor>::Context,
+    ) {
+        for ext in self.active() {
+            ext.event_stored(event, session, ctx);
+        }
+    }
+
+    pub fn call_outgoing(&self, msg: &mut OutgoingMessage, session: &Session) {
+        for ext in self.active() {
+            ext.outgoing(msg, session);
+        }
+    }
+
+    pub fn call_event_result(&self, result: &EventResult, session: &Session) {
+        for ext in self.active() {
+            ext.event_result(result, session);
+        }
+    }
+
+    pub fn call_publish(&self, event: &Event) -> Result<(), String> {
+        for ext in self.active() {
+            ext.publish(event)?;
+        }
+        Ok(())
+    }
+
     pub fn call_message(
         &self,
         msg: ClientMessage,
         session: &mut Session,
         ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
+        self.call_message_from(0, msg, session, ctx).1
+    }
+
+    /// Run the message methods from the extension index,
+    /// returns the index of the extension that stopped or waits with the result.
+    pub(crate) fn call_message_from(
+        &self,
+        start: usize,
+        msg: ClientMessage,
+        session: &mut Session,
+        ctx: &mut <Session as actix::Actor>::Context,
+    ) -> (usize, ExtensionMessageResult) {
         let mut msg = msg;
-        for ext in &self.list {
+        for (index, (_, ext)) in self.list.iter().enumerate().skip(start) {
+            if !self.enabled(ext.name()) {
+                continue;
+            }
             match ext.message(msg, session, ctx) {
                 ExtensionMessageResult::Continue(m) => {
                     msg = m;
                 }
-                ExtensionMessageResult::Stop(o) => {
-                    return ExtensionMessageResult::Stop(o);
-                }
-                ExtensionMessageResult::Ignore => {
-                    return ExtensionMessageResult::Ignore;
+                res => {
+                    return (index, res);
                 }
             };
         }
-        ExtensionMessageResult::Continue(msg)
+        (self.list.len(), ExtensionMessageResult::Continue(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Setting;
+
+    struct Named(&'static str);
+    impl Extension for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    #[test]
+    fn order() {
+        let mut extensions = Extensions::default();
+        extensions.add(Named("metrics"));
+        extensions.add(Named("auth"));
+        extensions.add(Named("rate_limiter"));
+        extensions.add(Named("search"));
+        assert_eq!(
+            extensions.names(),
+            vec!["metrics", "auth", "rate_limiter", "search"]
+        );
+
+        let mut setting = Setting::default();
+        setting.extension.order = vec!["rate_limiter".to_owned(), "auth".to_owned()];
+        let setting: SettingWrapper = setting.into();
+        extensions.call_setting(&setting);
+        assert_eq!(
+            extensions.names(),
+            vec!["rate_limiter", "auth", "metrics", "search"]
+        );
+
+        // back to the registration order
+        setting.write().extension.order = vec![];
+        extensions.call_setting(&setting);
+        assert_eq!(
+            extensions.names(),
+            vec!["metrics", "auth", "rate_limiter", "search"]
+        );
+    }
+
+    #[test]
+    fn enabled() {
+        let mut extensions = Extensions::default();
+        extensions.add(Named("auth"));
+        extensions.add(Named("search"));
+        assert!(extensions.enabled("auth"));
+        assert!(extensions.enabled("search"));
+
+        let mut setting = Setting::default();
+        setting.extension.disabled = vec!["search".to_owned()];
+        let setting: SettingWrapper = setting.into();
+        extensions.call_setting(&setting);
+        assert!(extensions.enabled("auth"));
+        assert!(!extensions.enabled("search"));
+        assert_eq!(
+            extensions.active().map(|e| e.name()).collect::<Vec<_>>(),
+            vec!["auth"]
+        );
+
+        // runtime toggles take precedence over the setting
+        assert!(extensions.set_enabled("search", true));
+        assert!(extensions.set_enabled("auth", false));
+        assert!(!extensions.set_enabled("unknown", false));
+        extensions.call_setting(&setting);
+        assert!(!extensions.enabled("auth"));
+        assert!(extensions.enabled("search"));
     }
 }
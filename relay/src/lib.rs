@@ -1,3 +1,11 @@
+//! A nostr relay library.
+//!
+//! Build the relay with [`App::builder`], then run it standalone with [`App::web_server`]
+//! or mount it into an existing actix web app with [`configure`].
+//!
+//! The stable api for embedding is [`App`], [`RelayBuilder`], [`configure`], [`create_web_app`], [`EventStream`],
+//! the [`Extension`] trait with [`ExtensionMessageResult`], [`Session`] with [`SessionStats`], [`Setting`], [`List`], [`message`] and [`Error`]. The server actors are public for advanced use, their messages may change.
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -23,23 +31,41 @@ impl actix_web::ResponseError for Error {}
 pub type Result<T, E = Error> = core::result::Result<T, E>;
 
 mod app;
+mod builder;
+mod cache;
+#[cfg(feature = "cbor")]
+pub mod cbor;
 pub mod duration;
 mod extension;
 mod hash;
 mod list;
 pub mod message;
+pub mod privacy;
 mod reader;
+mod seen;
 mod server;
 mod session;
 pub mod setting;
+mod stream;
 mod subscriber;
 mod writer;
 
 pub use metrics;
 pub use nostr_db as db;
 pub use {
-    app::*, extension::*, list::List, reader::Reader, server::Server, server::*, session::Session,
-    setting::Setting, subscriber::Subscriber, writer::Writer,
+    app::*,
+    builder::RelayBuilder,
+    cache::ResultCache,
+    extension::*,
+    list::List,
+    reader::Reader,
+    seen::SeenIds,
+    server::Server,
+    session::{count_dropped, Session, SessionInfo, SessionStats, SubscriptionInfo},
+    setting::Setting,
+    stream::{EventStream, StreamMessage},
+    subscriber::{Subscriber, SubscriberIndex},
+    writer::Writer,
 };
 
 #[cfg(test)]
@@ -1,21 +1,42 @@
 use actix::{Message, MessageResponse, Recipient};
 use bytestring::ByteString;
-use nostr_db::{now, CheckEventResult, Event, Filter};
+use nostr_db::{now, CheckEventResult, Cursor, Event, EventSeed, Filter};
 use serde::{
     de::{self, SeqAccess, Visitor},
     Deserialize, Deserializer,
 };
 use serde_json::{json, Value};
 use std::fmt::Display;
-use std::{fmt, marker::PhantomData};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    },
+    time::Instant,
+};
 
-use crate::{setting::Limitation, Error};
+use crate::{setting::Limitation, subscriber::unconstrained, Error, SessionInfo};
 
 /// New session is created
 #[derive(Message, Clone, Debug)]
 #[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<OutgoingMessage>,
+    pub reload: Recipient<Reload>,
+    pub stored: Recipient<EventStored>,
+    /// the stored events read for the subscriptions
+    pub read: Recipient<ReadEventResult>,
+    /// the read results sent to the session and not handled yet, the readers pause while it is full
+    pub backfill: Arc<AtomicUsize>,
+    /// the max subscriptions of the session by its role, 0 the relay limitation
+    pub max_subscriptions: Arc<AtomicUsize>,
+    /// the admin actions on the session
+    pub control: Recipient<Control>,
+    /// the REQs and events of the session are processed ahead of the others
+    pub priority: Arc<AtomicBool>,
+    /// the session is authenticated by NIP-42, the dropped messages are counted by it
+    pub authed: Arc<AtomicBool>,
 }
 
 /// Session is disconnected
@@ -31,8 +52,8 @@ pub struct Disconnect {
 pub struct ClientMessage {
     /// Id of the client session
     pub id: usize,
-    /// text message
-    pub text: String,
+    /// text message, shares the buffer of the websocket frame
+    pub text: ByteString,
     /// parsed message
     pub msg: IncomingMessage,
 }
@@ -55,16 +76,30 @@ macro_rules! check_min {
 
 impl ClientMessage {
     pub fn validate(&mut self, limitation: &Limitation) -> Result<(), Error> {
-        check_max!(self.text.as_bytes().len(), limitation.max_message_length);
+        self.check(limitation, true)
+    }
+
+    /// Validate the message of a trusted source, the signature of the event is not verified
+    pub fn validate_trusted(&mut self, limitation: &Limitation) -> Result<(), Error> {
+        self.check(limitation, false)
+    }
+
+    fn check(&mut self, limitation: &Limitation, verify_sign: bool) -> Result<(), Error> {
+        check_max!(self.text.len(), limitation.max_message_length);
 
         match &mut self.msg {
             IncomingMessage::Event(event) => {
                 check_max!(event.tags().len(), limitation.max_event_tags);
-                event.validate(
+                let (now, older, newer) = (
                     now(),
                     limitation.max_event_time_older_than_now,
                     limitation.max_event_time_newer_than_now,
-                )?;
+                );
+                if verify_sign {
+                    event.validate(now, older, newer)?;
+                } else {
+                    event.validate_trusted(now, older, newer)?;
+                }
             }
 
             IncomingMessage::Req(sub) => {
@@ -74,10 +109,28 @@ impl ClientMessage {
                 for f in &mut sub.filters {
                     // fill default limit
                     f.default_limit(limitation.max_limit);
-                    check_max!(f.limit.unwrap(), limitation.max_limit);
+                    let limit = f.limit.unwrap_or_default();
+                    if limit > limitation.max_limit {
+                        return Err(Error::Invalid(format!(
+                            "limit {} is greater than the max {}",
+                            limit, limitation.max_limit
+                        )));
+                    }
+                    if let (Some(since), Some(until)) = (f.since, f.until) {
+                        if since > until {
+                            return Err(Error::Invalid(format!(
+                                "since {} is greater than until {}",
+                                since, until
+                            )));
+                        }
+                    }
                     for id in f.ids.iter() {
                         check_min!(id.len(), limitation.min_prefix);
                     }
+                    // degenerates into a scan of the whole db
+                    if limitation.reject_broad_filters && unconstrained(f) {
+                        return Err(Error::Message("blocked: filter too broad".to_owned()));
+                    }
                 }
             }
             _ => {}
@@ -135,13 +188,112 @@ impl IncomingMessage {
             IncomingMessage::Unknown(_, _) => None,
         }
     }
+
+    /// The subscription id of a REQ or COUNT text not parsed, answered with the reason in CLOSED
+    pub fn subscription_id(text: &str) -> Option<String> {
+        let list = serde_json::from_str::<Vec<Value>>(text).ok()?;
+        match (list.first()?.as_str()?, list.get(1)?.as_str()?) {
+            ("REQ" | "COUNT", id) => Some(id.to_owned()),
+            _ => None,
+        }
+    }
+
+    /// The reason of the parse error without the position, prefixed with `invalid:`
+    pub fn invalid_reason(err: &serde_json::Error) -> String {
+        let msg = err.to_string();
+        let position = format!(" at line {} column {}", err.line(), err.column());
+        let msg = msg.strip_suffix(&position).unwrap_or(&msg);
+        if msg.starts_with("invalid: ") {
+            msg.to_owned()
+        } else {
+            format!("invalid: {}", msg)
+        }
+    }
+
+    /// Parse the message text from client.
+    ///
+    /// With the `simd` feature the text is parsed once by simd-json, the errors of the messages
+    /// are the same as serde_json without the position.
+    #[cfg(feature = "simd")]
+    pub fn from_text(text: &str) -> Result<Self, serde_json::Error> {
+        use std::cell::RefCell;
+        // simd-json parses in place, the text is kept by the client message,
+        // so it's parsed in the buffer of the thread
+        thread_local!(static BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) });
+        BUF.with_borrow_mut(|buf| {
+            buf.clear();
+            buf.extend_from_slice(text.as_bytes());
+            let res = simd_json::serde::from_slice::<Self>(buf);
+            if buf.capacity() > MAX_PARSE_BUFFER {
+                *buf = Vec::new();
+            }
+            res.map_err(|e| match e.error() {
+                simd_json::ErrorType::Serde(msg) => de::Error::custom(msg),
+                _ => de::Error::custom(e),
+            })
+        })
+    }
+
+    /// Parse the message text from client.
+    #[cfg(not(feature = "simd"))]
+    pub fn from_text(text: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    /// Parse the message text from client, the content and the tags of the events
+    /// share the buffer of the text.
+    ///
+    /// The `simd` feature parses a copy of the text, it's the same as [`Self::from_text`].
+    #[cfg(not(feature = "simd"))]
+    pub fn from_bytes(text: &ByteString) -> Result<Self, serde_json::Error> {
+        let mut de = serde_json::Deserializer::from_str(text);
+        let msg = de.deserialize_seq(MessageVisitor(Some(text)))?;
+        de.end()?;
+        Ok(msg)
+    }
+
+    /// Parse the message text from client.
+    #[cfg(feature = "simd")]
+    pub fn from_bytes(text: &ByteString) -> Result<Self, serde_json::Error> {
+        Self::from_text(text)
+    }
 }
 
+/// The parse buffer of the thread is released after a larger message
+#[cfg(feature = "simd")]
+const MAX_PARSE_BUFFER: usize = 1024 * 1024;
+
 // https://github.com/serde-rs/serde/issues/1337
 
-struct MessageVisitor(PhantomData<()>);
+/// The events are parsed by [`EventSeed`] with the text of the message
+struct MessageVisitor<'a>(Option<&'a ByteString>);
+
+impl MessageVisitor<'_> {
+    /// Not all deserializers check the trailing elements (simd-json), check it by self.
+    fn end<'de, A>(&self, seq: &mut A, len: usize) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            Err(de::Error::invalid_length(len + 1, self))
+        } else {
+            Ok(())
+        }
+    }
 
-impl<'de> Visitor<'de> for MessageVisitor {
+    fn event<'de, A>(&self, seq: &mut A) -> Result<Event, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        match self.0 {
+            Some(text) => seq.next_element_seed(EventSeed(text)),
+            None => seq.next_element(),
+        }?
+        .ok_or_else(|| de::Error::invalid_length(0, self))
+    }
+}
+
+impl<'de> Visitor<'de> for MessageVisitor<'_> {
     type Value = IncomingMessage;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
@@ -156,31 +308,44 @@ impl<'de> Visitor<'de> for MessageVisitor {
             .next_element()?
             .ok_or_else(|| de::Error::invalid_length(0, &self))?;
         match t {
-            "EVENT" => Ok(IncomingMessage::Event(
-                seq.next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?,
-            )),
-            "CLOSE" => Ok(IncomingMessage::Close(
-                seq.next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?,
-            )),
+            "EVENT" => {
+                let event = self.event(&mut seq)?;
+                self.end(&mut seq, 2)?;
+                Ok(IncomingMessage::Event(event))
+            }
+            "CLOSE" => {
+                let id = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                self.end(&mut seq, 2)?;
+                Ok(IncomingMessage::Close(id))
+            }
             "REQ" => {
                 let t = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
                 let r = Vec::<Filter>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
-                Ok(IncomingMessage::Req(Subscription { id: t, filters: r }))
+                Ok(IncomingMessage::Req(Subscription {
+                    id: t,
+                    filters: r,
+                    resume: None,
+                }))
+            }
+            "AUTH" => {
+                let event = self.event(&mut seq)?;
+                self.end(&mut seq, 2)?;
+                Ok(IncomingMessage::Auth(event))
             }
-            "AUTH" => Ok(IncomingMessage::Auth(
-                seq.next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?,
-            )),
             "COUNT" => {
                 let t = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
                 let r = Vec::<Filter>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
-                Ok(IncomingMessage::Count(Subscription { id: t, filters: r }))
+                Ok(IncomingMessage::Count(Subscription {
+                    id: t,
+                    filters: r,
+                    resume: None,
+                }))
             }
             _ => Ok(IncomingMessage::Unknown(
                 t.to_string(),
@@ -195,7 +360,7 @@ impl<'de> Deserialize<'de> for IncomingMessage {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_seq(MessageVisitor(PhantomData))
+        deserializer.deserialize_seq(MessageVisitor(None))
     }
 }
 
@@ -214,6 +379,17 @@ impl<'de> Deserialize<'de> for IncomingMessage {
 pub struct Subscription {
     pub id: String,
     pub filters: Vec<Filter>,
+    /// only the events stored from the high-water mark of an earlier subscription
+    pub resume: Option<Resume>,
+}
+
+/// Resume the stored events of a subscription, see [`nostr_db::Db::iter_stored_from`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resume {
+    /// the uid of the next stored event after the EOSE of the earlier subscription
+    pub from: u64,
+    /// the stored events since then are all read when more are stored
+    pub max_scan: usize,
 }
 
 // https://github.com/serde-rs/serde/issues/1337
@@ -258,6 +434,10 @@ impl OutgoingMessage {
         Self(json!(["NOTICE", message]).to_string())
     }
 
+    pub fn closed(sub_id: &str, message: &str) -> Self {
+        Self(json!(["CLOSED", sub_id, message]).to_string())
+    }
+
     pub fn eose(sub_id: &str) -> Self {
         Self(format!(r#"["EOSE","{}"]"#, sub_id))
     }
@@ -269,6 +449,24 @@ impl OutgoingMessage {
     pub fn ok(event_id: &str, saved: bool, message: &str) -> Self {
         Self(json!(["OK", event_id, saved, message]).to_string())
     }
+
+    /// The subscription id of an EOSE message
+    pub fn eose_id(&self) -> Option<&str> {
+        self.0.strip_prefix(r#"["EOSE",""#)?.strip_suffix(r#""]"#)
+    }
+
+    /// The saved flag of an OK message
+    pub fn ok_result(&self) -> Option<bool> {
+        // ["OK","<64 hex id>",true,
+        let rest = self.0.strip_prefix(r#"["OK",""#)?.get(66..)?;
+        if rest.starts_with("true") {
+            Some(true)
+        } else if rest.starts_with("false") {
+            Some(false)
+        } else {
+            None
+        }
+    }
 }
 
 impl Display for OutgoingMessage {
@@ -295,6 +493,10 @@ impl From<OutgoingMessage> for ByteString {
 pub struct WriteEvent {
     pub id: usize,
     pub event: Event,
+    /// written ahead of the others when the writer is saturated
+    pub priority: bool,
+    /// acknowledged when it is saved in the intake journal, before the commit
+    pub ahead: bool,
 }
 
 #[derive(Message, Clone, Debug)]
@@ -304,7 +506,11 @@ pub enum WriteEventResult {
         id: usize,
         event: Event,
         result: CheckEventResult,
+        /// the client got the OK when the event was queued
+        acked: bool,
     },
+    /// The event is saved in the intake journal and committed later
+    Queued { id: usize, event_id: String },
     Message {
         id: usize,
         event: Event,
@@ -317,11 +523,35 @@ pub enum WriteEventResult {
 //     pub result: CheckEventResult,
 // }
 
+/// Read the stored events of the REQ, the REQ paused for the full backfill of the session is returned
 #[derive(Message, Clone, Debug)]
-#[rtype(result = "()")]
+#[rtype(result = "Option<ReadEvent>")]
 pub struct ReadEvent {
     pub id: usize,
     pub subscription: Subscription,
+    /// the backfill of the session, no flow control if none
+    pub backfill: Option<Arc<AtomicUsize>>,
+    /// the session is authenticated by NIP-42
+    pub authed: bool,
+    /// where the REQ paused, read again from a new snapshot when the backfill drains
+    pub paused: Option<ReadCursor>,
+}
+
+/// The position of a paused REQ
+#[derive(Clone, Debug)]
+pub struct ReadCursor {
+    /// the index of the filter being read
+    pub filter: usize,
+    /// the events of the filter sent before the pause
+    pub sent: u64,
+    /// after the last sent event of the filter, none if the filter is read from the start
+    pub cursor: Option<Cursor>,
+    /// the scan budget left for the REQ
+    pub budget: Option<u64>,
+    /// the events stored from the uid after the first snapshot are not read, see [`nostr_db::Db::last_uid`]
+    pub stored: u64,
+    /// the subscription is closed when the backfill does not drain for the timeout
+    pub since: Instant,
 }
 
 #[derive(Message, Clone, Debug)]
@@ -330,11 +560,15 @@ pub struct ReadEventResult {
     pub id: usize,
     pub sub_id: String,
     pub msg: OutgoingMessage,
+    /// the subscription is closed by the relay, the server unsubscribes it
+    pub closed: bool,
 }
 
 #[derive(MessageResponse, Clone, Debug, PartialEq, Eq)]
 pub enum Subscribed {
     Ok,
+    /// the same id and filters are subscribed, the live subscription is kept without reading the stored events
+    Duplicate,
     Overlimit,
     InvalidIdLength,
 }
@@ -344,6 +578,8 @@ pub enum Subscribed {
 pub struct Subscribe {
     pub id: usize,
     pub subscription: Subscription,
+    /// the max subscriptions of the session, the relay limitation when unset
+    pub max_subscriptions: Option<usize>,
 }
 
 #[derive(Message, Clone, Debug)]
@@ -368,11 +604,126 @@ pub struct SubscribeResult {
     pub msg: OutgoingMessage,
 }
 
+/// Send the message to all connected sessions, returns the number of sessions
+#[derive(Message, Clone, Debug)]
+#[rtype(usize)]
+pub struct Broadcast {
+    pub msg: OutgoingMessage,
+}
+
+/// The sessions selected by an admin action, all sessions when empty
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionFilter {
+    /// the sessions authenticated by NIP-42 or the others
+    pub authed: Option<bool>,
+    /// the session id
+    pub id: Option<usize>,
+    /// the client ip
+    pub ip: Option<String>,
+    /// the pubkey authenticated by NIP-42
+    pub pubkey: Option<String>,
+}
+
+impl SessionFilter {
+    /// the session of the id, ip and authenticated pubkey matches
+    pub fn matches(&self, id: usize, ip: &str, pubkey: Option<&str>) -> bool {
+        self.authed.is_none_or(|authed| authed == pubkey.is_some())
+            && self.id.is_none_or(|i| i == id)
+            && self.ip.as_ref().is_none_or(|i| i == ip)
+            && self.pubkey.as_deref().is_none_or(|p| Some(p) == pubkey)
+    }
+
+    /// Select the sessions by a session id, an ip or a hex pubkey
+    pub fn parse(selector: &str) -> Option<Self> {
+        let mut filter = Self::default();
+        if let Ok(id) = selector.parse::<usize>() {
+            filter.id = Some(id);
+        } else if let Ok(ip) = selector.parse::<std::net::IpAddr>() {
+            filter.ip = Some(ip.to_string());
+        } else if crate::privacy::is_ip_hash(selector) {
+            filter.ip = Some(selector.to_owned());
+        } else if selector.len() == 64 && selector.chars().all(|c| c.is_ascii_hexdigit()) {
+            filter.pubkey = Some(selector.to_ascii_lowercase());
+        } else {
+            return None;
+        }
+        Some(filter)
+    }
+}
+
+/// The admin action on a session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlAction {
+    /// only list the session
+    List,
+    /// send a NOTICE to the client
+    Notice(String),
+    /// disconnect the session, after the NOTICE if any
+    Kick(Option<String>),
+}
+
+/// Run the admin action on the sessions matching the filter, the matched session returns its info
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "Option<SessionInfo>")]
+pub struct Control {
+    pub filter: SessionFilter,
+    pub action: ControlAction,
+}
+
+/// Run the admin action on the connected sessions matching the filter, returns the matched sessions by id
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct ControlSessions(pub Control);
+
+/// The setting is reloaded, connected sessions are re-evaluated by the extensions
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Reload;
+
+/// Write the event without a session, returns the write result
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "Result<CheckEventResult, Error>")]
+pub struct Publish {
+    pub event: Event,
+    /// written with the events of the priority sessions
+    pub priority: bool,
+}
+
+/// The event of the session is persisted
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct EventStored {
+    pub event: Event,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
 
+    #[test]
+    fn session_filter() {
+        let pubkey = "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef";
+        let filter = SessionFilter::parse("3").unwrap();
+        assert!(filter.matches(3, "127.0.0.1", None));
+        assert!(!filter.matches(4, "127.0.0.1", None));
+        let filter = SessionFilter::parse("::1").unwrap();
+        assert!(filter.matches(3, "::1", None));
+        assert!(!filter.matches(3, "127.0.0.1", None));
+        let filter = SessionFilter::parse(&pubkey.to_uppercase()).unwrap();
+        assert!(filter.matches(3, "::1", Some(pubkey)));
+        assert!(!filter.matches(3, "::1", None));
+        assert!(SessionFilter::parse("abc").is_none());
+
+        let filter = SessionFilter {
+            authed: Some(false),
+            ..Default::default()
+        };
+        assert!(filter.matches(3, "::1", None));
+        assert!(!filter.matches(3, "::1", Some(pubkey)));
+        assert!(SessionFilter::default().matches(3, "::1", Some(pubkey)));
+    }
+
     #[test]
     fn de_incoming_message() -> Result<()> {
         // close
@@ -433,6 +784,137 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn from_text() -> Result<()> {
+        let msg = IncomingMessage::from_text(
+            r#"["EVENT", {
+            "content": "Good morning everyone \ud83d\ude03 \"quote\"",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": [["t", "nostr"]]
+          }]"#,
+        )?;
+        assert!(
+            matches!(msg, IncomingMessage::Event(ref event) if event.content() == "Good morning everyone 😃 \"quote\"")
+        );
+
+        let msg = IncomingMessage::from_text(
+            r##"["REQ", "sub_id1", {"kinds": [1], "#t": ["nostr"]}, {}]"##,
+        )?;
+        assert!(matches!(msg, IncomingMessage::Req(ref sub) if sub.filters.len() == 2));
+
+        let msg = IncomingMessage::from_text(r#"["CLOSE", "sub_id1"]"#)?;
+        assert!(matches!(msg, IncomingMessage::Close(ref id) if id == "sub_id1"));
+
+        let msg = IncomingMessage::from_text(r#"["REQ1", "sub_id1", {}]"#)?;
+        assert!(matches!(msg, IncomingMessage::Unknown(ref cmd, ref _val) if cmd == "REQ1"));
+
+        // same error as serde_json
+        let err = IncomingMessage::from_text(r#"["CLOSE", "sub_id1", "other"]"#).unwrap_err();
+        let expected = serde_json::from_str::<IncomingMessage>(r#"["CLOSE", "sub_id1", "other"]"#)
+            .unwrap_err();
+        assert_eq!(
+            IncomingMessage::invalid_reason(&err),
+            IncomingMessage::invalid_reason(&expected)
+        );
+        assert!(IncomingMessage::from_text("[").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_bytes() -> Result<()> {
+        let event = r#"{
+            "content": "Good morning everyone \ud83d\ude03 \"quote\"",
+            "created_at": 1680690006,
+            "id": "332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d",
+            "kind": 1,
+            "pubkey": "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef",
+            "sig": "ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f",
+            "tags": [["t", "nostr"]]
+          }"#;
+        for text in [
+            format!(r#"["EVENT", {}]"#, event),
+            format!(r#"["AUTH", {}]"#, event),
+        ] {
+            let msg = IncomingMessage::from_bytes(&text.clone().into())?;
+            let expected = IncomingMessage::from_text(&text)?;
+            let (event, expected) = match (msg, expected) {
+                (IncomingMessage::Event(e1), IncomingMessage::Event(e2))
+                | (IncomingMessage::Auth(e1), IncomingMessage::Auth(e2)) => (e1, e2),
+                _ => unreachable!(),
+            };
+            assert_eq!(event.to_string(), expected.to_string());
+            assert_eq!(event.tags(), expected.tags());
+        }
+
+        let null_tag = format!(
+            r#"["EVENT", {}]"#,
+            event.replace(r#"["t", "nostr"]"#, r#"["e", null]"#)
+        );
+        for text in [
+            r#"["EVENT", {}, {}]"#,
+            r#"["EVENT", {"kind": 1}]"#,
+            r#"["EVENT"]"#,
+            r#"["CLOSE", "sub_id1", "other"]"#,
+            r#"["REQ", "sub_id1", {}]]"#,
+            &null_tag,
+        ] {
+            // same error as serde_json
+            let err = IncomingMessage::from_bytes(&text.into()).unwrap_err();
+            let expected = IncomingMessage::from_text(text).unwrap_err();
+            assert_eq!(
+                IncomingMessage::invalid_reason(&err),
+                IncomingMessage::invalid_reason(&expected)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_filter() -> Result<()> {
+        let text = r#"["REQ", "sub_id1", {"authors": ["xyz"]}]"#;
+        let err = IncomingMessage::from_text(text).unwrap_err();
+        assert_eq!(
+            IncomingMessage::subscription_id(text),
+            Some("sub_id1".to_owned())
+        );
+        assert_eq!(
+            IncomingMessage::invalid_reason(&err),
+            "invalid: authors must be 64 character hex strings"
+        );
+        assert_eq!(IncomingMessage::subscription_id(r#"["EVENT", {}]"#), None);
+
+        let mut msg = ClientMessage {
+            id: 0,
+            text: Default::default(),
+            msg: IncomingMessage::from_text(r#"["REQ", "sub_id1", {"since": 10, "until": 5}]"#)?,
+        };
+        let err = msg.validate(&Limitation::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid: since 10 is greater than until 5");
+
+        let limitation = Limitation {
+            reject_broad_filters: true,
+            ..Default::default()
+        };
+        let mut msg = ClientMessage {
+            id: 0,
+            text: Default::default(),
+            msg: IncomingMessage::from_text(r#"["REQ", "sub_id1", {"since": 10}]"#)?,
+        };
+        let err = msg.validate(&limitation).unwrap_err();
+        assert_eq!(err.to_string(), "blocked: filter too broad");
+        let mut msg = ClientMessage {
+            id: 0,
+            text: Default::default(),
+            msg: IncomingMessage::from_text(r#"["REQ", "sub_id1", {"kinds": [1]}]"#)?,
+        };
+        assert!(msg.validate(&limitation).is_ok());
+        Ok(())
+    }
+
     #[test]
     fn se_outgoing_message() -> Result<()> {
         let msg = OutgoingMessage::notice("hello");
@@ -0,0 +1,55 @@
+//! Privacy mode, the client ips are keyed-hashed before they are used by the rate limits,
+//! the bans, the logs and the audit records, so the plaintext ips are never persisted.
+use hmac::{Hmac, Mac};
+use nostr_db::secp256k1::rand::random;
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+/// The hex length of an ip hash
+pub const IP_HASH_LEN: usize = 32;
+
+/// The keyed hash of the ip, the empty key is a random key of the process
+pub fn hash_ip(key: &str, ip: &str) -> String {
+    static RANDOM_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+    let key = if key.is_empty() {
+        RANDOM_KEY.get_or_init(random)
+    } else {
+        key.as_bytes()
+    };
+    // hmac accepts the keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(ip.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..IP_HASH_LEN / 2])
+}
+
+/// The value is an ip hash of the privacy mode
+pub fn is_ip_hash(s: &str) -> bool {
+    s.len() == IP_HASH_LEN && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        let hash = hash_ip("secret", "127.0.0.1");
+        assert!(is_ip_hash(&hash));
+        assert_eq!(hash, hash_ip("secret", "127.0.0.1"));
+        assert_ne!(hash, hash_ip("secret", "127.0.0.2"));
+        assert_ne!(hash, hash_ip("other", "127.0.0.1"));
+        assert_eq!(hash_ip("", "127.0.0.1"), hash_ip("", "127.0.0.1"));
+        assert!(!is_ip_hash("127.0.0.1"));
+
+        let mut network = crate::setting::Network {
+            ip_hash_key: "secret".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(network.client_ip("127.0.0.1"), "127.0.0.1");
+        network.hash_ip = true;
+        assert_eq!(network.client_ip("127.0.0.1"), hash);
+        // hashed once
+        assert_eq!(network.client_ip(&hash), hash);
+        assert_eq!(network.client_ip(""), "");
+    }
+}
@@ -1,8 +1,40 @@
-use crate::{message::*, setting::SettingWrapper, Result};
+use crate::{count_dropped, message::*, setting::SettingWrapper, Result, ResultCache};
 use actix::prelude::*;
-use metrics::histogram;
-use nostr_db::Db;
-use std::{sync::Arc, time::Instant};
+use metrics::{histogram, increment_counter};
+#[cfg(feature = "search")]
+use nostr_db::{now, Event, Filter, Rank};
+use nostr_db::{Cursor, Db};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// The stored events of a REQ are sent in chunks, the reader pauses after a chunk
+/// while the session has not handled the results over the buffer
+struct Flow {
+    chunk: usize,
+    buffer: usize,
+    sent: usize,
+}
+
+impl Flow {
+    /// Count the sent event, true if the backfill of the session is full
+    fn full(&mut self, backfill: Option<&AtomicUsize>) -> bool {
+        self.sent += 1;
+        self.sent.is_multiple_of(self.chunk.max(1)) && self.backfill_full(backfill)
+    }
+
+    /// True if the backfill of the session is over the buffer
+    fn backfill_full(&self, backfill: Option<&AtomicUsize>) -> bool {
+        match backfill {
+            Some(backfill) if self.buffer > 0 => backfill.load(Ordering::Acquire) >= self.buffer,
+            _ => false,
+        }
+    }
+}
 
 /// Requst by filter
 /// Concurrent read events from db
@@ -10,39 +42,282 @@ pub struct Reader {
     pub db: Arc<Db>,
     pub addr: Recipient<ReadEventResult>,
     pub setting: SettingWrapper,
+    /// the results shared by the readers
+    pub cache: Option<Arc<ResultCache>>,
 }
 
 impl Reader {
     pub fn new(db: Arc<Db>, addr: Recipient<ReadEventResult>, setting: SettingWrapper) -> Self {
-        Self { db, addr, setting }
+        Self {
+            db,
+            addr,
+            setting,
+            cache: None,
+        }
     }
 
-    pub fn read(&self, msg: &ReadEvent) -> Result<()> {
+    /// The filters of the REQ read one snapshot until the REQ pauses, so a replaced or deleted event
+    /// is not seen twice while the results are streamed. The REQ pauses when the backfill of the session
+    /// is full, the transaction is closed and the REQ is returned with its position. The rest of the REQ
+    /// is read from a new snapshot without the events stored since the first, they are sent to the
+    /// subscription as they are stored, so an event may be missed if it is deleted during the pause.
+    /// The results of the cache, the ranking and the resume are sent whole, the REQ pauses after them.
+    pub fn read(&self, msg: &ReadEvent) -> Result<Option<ReadEvent>> {
+        let before = self.cache.as_ref().map(|c| c.generation());
         let reader = self.db.reader()?;
-        let timeout = self.setting.read().data.db_query_timeout;
-        for filter in &msg.subscription.filters {
+        // the cached results are consistent with the snapshot only if nothing was written meanwhile
+        let generation = self
+            .cache
+            .as_ref()
+            .zip(before)
+            .and_then(|(c, before)| ResultCache::snapshot(before, c.generation()));
+        let r = self.setting.read();
+        let timeout = r.data.db_query_timeout;
+        let paused = msg.paused.as_ref();
+        let mut budget = paused.map_or(r.data.db_scan_budget, |p| p.budget);
+        let mut flow = Flow {
+            chunk: r.data.backfill_chunk,
+            buffer: r.data.backfill_buffer,
+            sent: 0,
+        };
+        // the cache is of the new snapshot after a pause
+        let cache = self
+            .cache
+            .as_ref()
+            .zip(r.data.result_cache)
+            .zip(generation)
+            .filter(|_| paused.is_none())
+            .map(|((cache, ttl), generation)| {
+                (
+                    cache,
+                    ttl,
+                    generation,
+                    r.data.result_cache_size,
+                    r.data.result_cache_events,
+                )
+            });
+        drop(r);
+        // the token of another database is newer than the snapshot
+        let next = self.db.last_uid(&reader)?.map_or(0, |uid| uid + 1);
+        // the events stored after the first snapshot are not read again
+        let stored = paused.map_or(next, |p| p.stored.min(next));
+        let resume = msg.subscription.resume.filter(|r| r.from <= next);
+        let first = paused.map_or(0, |p| p.filter);
+        let filters = &msg.subscription.filters;
+        for (index, filter) in filters.iter().enumerate().skip(first) {
             let start = Instant::now();
-            let mut iter = self.db.iter::<String, _>(&reader, filter)?;
-            if let Some(time) = timeout {
-                iter.scan_time(time.into(), 2000);
+            // the filter after the cursor
+            let from = paused.filter(|p| p.filter == index);
+            // the REQ pauses before the next filter
+            let list_sent = |flow: &Flow, budget| {
+                (index + 1 < filters.len() && flow.backfill_full(msg.backfill.as_deref()))
+                    .then(|| self.pause(msg, index + 1, 0, None, budget, stored))
+            };
+            #[cfg(feature = "search")]
+            if let (Some(rank), Some(_)) = (filter.rank, &filter.search) {
+                let (events, exceeded) =
+                    self.read_ranked(&reader, filter, rank, timeout, &mut budget, stored)?;
+                self.send_events(msg, &events, &mut flow);
+                if exceeded {
+                    self.close_budget(msg);
+                    return Ok(None);
+                }
+                histogram!("nostr_relay_db_get", start.elapsed());
+                if let Some(paused) = list_sent(&flow, budget) {
+                    return Ok(Some(paused));
+                }
+                continue;
+            }
+            if let Some(resume) = resume {
+                if let Some((events, _)) = self.db.iter_stored_range::<String, _>(
+                    &reader,
+                    resume.from..stored,
+                    filter,
+                    resume.max_scan,
+                )? {
+                    self.send_events(msg, &events, &mut flow);
+                    histogram!("nostr_relay_db_get", start.elapsed());
+                    if let Some(paused) = list_sent(&flow, budget) {
+                        return Ok(Some(paused));
+                    }
+                    continue;
+                }
+            }
+            if let Some(events) =
+                cache.and_then(|(cache, _, generation, ..)| cache.get(filter, generation))
+            {
+                increment_counter!("nostr_relay_result_cache_hit_total");
+                self.send_events(msg, &events[..], &mut flow);
+                if let Some(paused) = list_sent(&flow, budget) {
+                    return Ok(Some(paused));
+                }
+                continue;
             }
-            for event in iter {
-                let event = event?;
-                self.addr.do_send(ReadEventResult {
-                    id: msg.id,
-                    sub_id: msg.subscription.id.clone(),
-                    msg: OutgoingMessage::event(&msg.subscription.id, &event),
-                });
+            let skip = from.map_or(0, |p| p.sent);
+            let mut iter = match from.and_then(|p| p.cursor) {
+                Some(cursor) => {
+                    // the rest of the limit after the cursor
+                    let mut filter = filter.clone();
+                    filter.limit = filter.limit.map(|limit| limit.saturating_sub(skip));
+                    if filter.limit == Some(0) {
+                        continue;
+                    }
+                    self.db
+                        .iter_after::<String, _>(&reader, &filter, Some(&cursor))?
+                }
+                None => self.db.iter::<String, _>(&reader, filter)?,
+            };
+            iter.stored_before(stored);
+            iter.scan_limits(timeout.map(Into::into), budget, 2000);
+            let mut events = cache.map(|_| vec![]);
+            let mut sent = skip;
+            while let Some(event) = iter.next() {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(nostr_db::Error::ScanBudget) => {
+                        self.close_budget(msg);
+                        return Ok(None);
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                sent += 1;
+                if self.send_event(msg, &event, &mut flow) {
+                    if let Some(budget) = &mut budget {
+                        *budget = budget.saturating_sub(iter.stats().scan_index);
+                    }
+                    let cursor = iter.cursor();
+                    return Ok(Some(self.pause(msg, index, sent, cursor, budget, stored)));
+                }
+                if let (Some(list), Some((.., max))) = (&mut events, cache) {
+                    if list.len() < max {
+                        list.push(event);
+                    } else {
+                        events = None;
+                    }
+                }
+            }
+            if let Some(budget) = &mut budget {
+                *budget = budget.saturating_sub(iter.stats().scan_index);
+            }
+            if let (Some(events), Some((cache, ttl, generation, size, _))) = (events, cache) {
+                cache.insert(filter.clone(), events, generation, ttl.into(), size);
             }
             histogram!("nostr_relay_db_get", start.elapsed());
         }
+        self.send(msg, OutgoingMessage::eose(&msg.subscription.id));
+        Ok(None)
+    }
+
+    /// The REQ read again from the position when the backfill of the session drains
+    fn pause(
+        &self,
+        msg: &ReadEvent,
+        filter: usize,
+        sent: u64,
+        cursor: Option<Cursor>,
+        budget: Option<u64>,
+        stored: u64,
+    ) -> ReadEvent {
+        increment_counter!("nostr_relay_backfill_paused_total");
+        ReadEvent {
+            paused: Some(ReadCursor {
+                filter,
+                sent,
+                cursor,
+                budget,
+                stored,
+                since: Instant::now(),
+            }),
+            ..msg.clone()
+        }
+    }
+
+    /// Send the result counted in the backfill of the session
+    fn send(&self, msg: &ReadEvent, out: OutgoingMessage) {
+        self.deliver(msg, out, false);
+    }
+
+    fn deliver(&self, msg: &ReadEvent, out: OutgoingMessage, closed: bool) {
+        if let Some(backfill) = &msg.backfill {
+            backfill.fetch_add(1, Ordering::AcqRel);
+        }
         self.addr.do_send(ReadEventResult {
             id: msg.id,
             sub_id: msg.subscription.id.clone(),
-            msg: OutgoingMessage::eose(&msg.subscription.id),
+            msg: out,
+            closed,
         });
+    }
 
-        Ok(())
+    /// Send the event, true if the reader pauses for the full backfill of the session
+    fn send_event(&self, msg: &ReadEvent, event: &str, flow: &mut Flow) -> bool {
+        self.send(msg, OutgoingMessage::event(&msg.subscription.id, event));
+        flow.full(msg.backfill.as_deref())
+    }
+
+    /// Send the listed events without pausing
+    fn send_events<E: AsRef<str>>(&self, msg: &ReadEvent, events: &[E], flow: &mut Flow) {
+        for event in events {
+            self.send_event(msg, event.as_ref(), flow);
+        }
+    }
+
+    /// Close the subscription scanning more index entries than the budget, the found events were sent
+    fn close_budget(&self, msg: &ReadEvent) {
+        increment_counter!("nostr_relay_scan_budget_exceeded_total");
+        count_dropped("scan budget", msg.authed);
+        self.deliver(
+            msg,
+            OutgoingMessage::closed(&msg.subscription.id, "error: scan budget exceeded"),
+            true,
+        );
+    }
+
+    #[cfg(feature = "search")]
+    /// score the newest matched events, the events by the relevance and whether the budget is exceeded
+    fn read_ranked<T: nostr_db::kv::lmdb::Transaction>(
+        &self,
+        reader: &T,
+        filter: &Filter,
+        rank: Rank,
+        timeout: Option<crate::duration::NonZeroDuration>,
+        budget: &mut Option<u64>,
+        stored: u64,
+    ) -> Result<(Vec<String>, bool)> {
+        let mut candidates = filter.clone();
+        candidates.limit = Some(rank.candidates);
+        candidates.desc = true;
+        let mut iter = self.db.iter::<Event, _>(reader, &candidates)?;
+        iter.stored_before(stored);
+        iter.scan_limits(timeout.map(Into::into), *budget, 2000);
+        let time = now();
+        let mut events = vec![];
+        // the events found in the budget are ranked and sent before the subscription is closed
+        let mut exceeded = false;
+        for event in iter.by_ref() {
+            match event {
+                Ok(event) => events.push((filter.relevance(&event, time, rank.half_life), event)),
+                Err(nostr_db::Error::ScanBudget) => {
+                    exceeded = true;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if let Some(budget) = budget {
+            *budget = budget.saturating_sub(iter.stats().scan_index);
+        }
+        events.sort_by(|a, b| {
+            b.0.total_cmp(&a.0)
+                .then_with(|| b.1.created_at().cmp(&a.1.created_at()))
+        });
+        let limit = filter.limit.unwrap_or(rank.candidates) as usize;
+        let events = events
+            .into_iter()
+            .take(limit)
+            .map(|(_, event)| event.to_string())
+            .collect();
+        Ok((events, exceeded))
     }
 }
 
@@ -52,14 +327,17 @@ impl Actor for Reader {
 }
 
 impl Handler<ReadEvent> for Reader {
-    type Result = ();
-    fn handle(&mut self, msg: ReadEvent, _: &mut Self::Context) {
-        if let Err(err) = self.read(&msg) {
-            self.addr.do_send(ReadEventResult {
-                id: msg.id,
-                sub_id: msg.subscription.id,
-                msg: OutgoingMessage::notice(&format!("get event error: {}", err)),
-            });
+    type Result = Option<ReadEvent>;
+    fn handle(&mut self, msg: ReadEvent, _: &mut Self::Context) -> Self::Result {
+        match self.read(&msg) {
+            Ok(paused) => paused,
+            Err(err) => {
+                self.send(
+                    &msg,
+                    OutgoingMessage::notice(&format!("get event error: {}", err)),
+                );
+                None
+            }
         }
     }
 }
@@ -70,9 +348,12 @@ mod tests {
     use crate::{temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
-    use nostr_db::{Event, Filter};
+    use nostr_db::{
+        secp256k1::{rand::thread_rng, KeyPair},
+        Event, Filter,
+    };
     use parking_lot::RwLock;
-    use std::{str::FromStr, time::Duration};
+    use std::{collections::HashSet, str::FromStr, time::Duration};
 
     #[derive(Default)]
     struct Receiver(Arc<RwLock<Vec<ReadEventResult>>>);
@@ -107,10 +388,11 @@ mod tests {
         let receiver = Receiver::default();
         let messages = receiver.0.clone();
         let receiver = receiver.start();
-        let addr = receiver.recipient();
+        let addr = receiver.clone().recipient();
 
+        let db2 = Arc::clone(&db);
         let reader = SyncArbiter::start(3, move || {
-            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+            Reader::new(Arc::clone(&db2), addr.clone(), Setting::default().into())
         });
 
         for i in 0..4 {
@@ -122,14 +404,167 @@ mod tests {
                         filters: vec![Filter {
                             ..Default::default()
                         }],
+                        resume: None,
                     },
+                    backfill: None,
+                    authed: false,
+                    paused: None,
                 })
                 .await?;
         }
 
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(messages.read().len(), 8);
+
+        // the client does not read the results, the REQ pauses after a chunk
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..2)
+            .map(|i| Event::create(&key_pair, 1000 + i, 1, vec![], "".to_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let mut setting = Setting::default();
+        setting.data.backfill_chunk = 2;
+        setting.data.backfill_buffer = 1;
+        let reader = Reader::new(Arc::clone(&db), receiver.recipient(), setting.into());
+        let backfill = Arc::new(AtomicUsize::new(0));
+        let paused = reader
+            .read(&ReadEvent {
+                id: 5,
+                subscription: Subscription {
+                    id: "5".to_owned(),
+                    filters: vec![Filter::default()],
+                    resume: None,
+                },
+                backfill: Some(backfill.clone()),
+                authed: false,
+                paused: None,
+            })?
+            .unwrap();
+        assert!(matches!(
+            paused.paused,
+            Some(ReadCursor {
+                filter: 0,
+                sent: 2,
+                cursor: Some(_),
+                ..
+            })
+        ));
+        assert_eq!(backfill.load(Ordering::Acquire), 2);
+
+        // stored during the pause, sent to the subscription live
+        db.batch_put([Event::create(
+            &key_pair,
+            2_000_000_000,
+            1,
+            vec![],
+            "".to_owned(),
+        )?])?;
+
+        // read again from the position when the session handled the results
+        backfill.store(0, Ordering::Release);
+        assert!(reader.read(&paused)?.is_none());
         sleep(Duration::from_millis(100)).await;
         let r = messages.read();
-        assert_eq!(r.len(), 8);
+        assert_eq!(r.len(), 12);
+        let ids = r[8..11]
+            .iter()
+            .map(|m| serde_json::from_str::<(String, String, Event)>(&m.msg.0))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            ids.iter()
+                .map(|e| e.2.id_str())
+                .collect::<HashSet<_>>()
+                .len(),
+            3
+        );
+        assert!(r[11].msg.0.starts_with(r#"["EOSE","5""#));
+        assert!(!r[11].closed);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn scan_budget() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("reader-budget")?)?);
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..10)
+            .map(|i| Event::create(&key_pair, 1000 + i, 1, vec![], "".to_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        let mut setting = Setting::default();
+        setting.data.db_scan_budget = Some(3);
+        let reader = Reader::new(Arc::clone(&db), receiver.recipient(), setting.into());
+        let read = |filters: Vec<Filter>| ReadEvent {
+            id: 1,
+            subscription: Subscription {
+                id: "1".to_owned(),
+                filters,
+                resume: None,
+            },
+            backfill: None,
+            authed: false,
+            paused: None,
+        };
+        reader.read(&read(vec![Filter::default()]))?;
+        sleep(Duration::from_millis(100)).await;
+        {
+            let r = messages.read();
+            // the results found in the budget are sent
+            assert!(!r.is_empty() && r.len() <= 4);
+            assert_eq!(
+                r.last().unwrap().msg.0,
+                r#"["CLOSED","1","error: scan budget exceeded"]"#
+            );
+            assert!(r.last().unwrap().closed);
+        }
+
+        // the budget is shared by the filters of the REQ
+        messages.write().clear();
+        let filter = Filter {
+            limit: Some(2),
+            ..Default::default()
+        };
+        reader.read(&read(vec![filter.clone()]))?;
+        sleep(Duration::from_millis(100)).await;
+        assert!(messages
+            .read()
+            .last()
+            .unwrap()
+            .msg
+            .0
+            .starts_with(r#"["EOSE""#));
+        messages.write().clear();
+        reader.read(&read(vec![filter.clone(), filter]))?;
+        sleep(Duration::from_millis(100)).await;
+        assert!(messages
+            .read()
+            .last()
+            .unwrap()
+            .msg
+            .0
+            .starts_with(r#"["CLOSED""#));
         Ok(())
     }
+
+    #[test]
+    fn flow() {
+        let mut flow = Flow {
+            chunk: 2,
+            buffer: 2,
+            sent: 0,
+        };
+        let backfill = AtomicUsize::new(0);
+        assert!(!flow.full(Some(&backfill)));
+        assert!(!flow.full(Some(&backfill)));
+        backfill.store(2, Ordering::Release);
+        // checked between the chunks
+        assert!(!flow.full(Some(&backfill)));
+        assert!(flow.full(Some(&backfill)));
+        assert!(!flow.full(None));
+        flow.buffer = 0;
+        assert!(!flow.full(Some(&backfill)));
+    }
 }
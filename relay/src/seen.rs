@@ -0,0 +1,85 @@
+use parking_lot::Mutex;
+use std::collections::HashSet;
+
+type Id = [u8; 32];
+
+/// The ids of the recently stored events, the clients send the same event to the relay repeatedly,
+/// the duplicates are answered without verifying the signature and writing the db.
+///
+/// The ids are kept in two generations, the older one is dropped when the newer one is full,
+/// so at least the `capacity / 2` most recent ids are kept.
+#[derive(Debug, Default)]
+pub struct SeenIds {
+    capacity: usize,
+    generations: Mutex<(HashSet<Id>, HashSet<Id>)>,
+}
+
+impl SeenIds {
+    /// disabled when the capacity is 0
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            generations: Default::default(),
+        }
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        let Ok(id) = Id::try_from(id) else {
+            return false;
+        };
+        if self.capacity == 0 {
+            return false;
+        }
+        let generations = self.generations.lock();
+        generations.0.contains(&id) || generations.1.contains(&id)
+    }
+
+    pub fn insert(&self, id: &[u8]) {
+        let Ok(id) = Id::try_from(id) else {
+            return;
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        let mut generations = self.generations.lock();
+        if generations.0.len() >= self.capacity.div_ceil(2) {
+            generations.1 = std::mem::take(&mut generations.0);
+        }
+        generations.0.insert(id);
+    }
+
+    /// the event is deleted
+    pub fn remove(&self, id: &[u8]) {
+        if let Ok(id) = Id::try_from(id) {
+            let mut generations = self.generations.lock();
+            generations.0.remove(&id);
+            generations.1.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generations() {
+        let seen = SeenIds::new(4);
+        for i in 0..3u8 {
+            seen.insert(&[i; 32]);
+        }
+        assert!((0..3u8).all(|i| seen.contains(&[i; 32])));
+        // the oldest generation is dropped
+        seen.insert(&[3; 32]);
+        seen.insert(&[4; 32]);
+        assert!(!seen.contains(&[0; 32]));
+        assert!((2..5u8).all(|i| seen.contains(&[i; 32])));
+        seen.remove(&[4; 32]);
+        assert!(!seen.contains(&[4; 32]));
+        assert!(!seen.contains(b"short"));
+
+        let seen = SeenIds::new(0);
+        seen.insert(&[0; 32]);
+        assert!(!seen.contains(&[0; 32]));
+    }
+}
@@ -1,8 +1,26 @@
-use crate::{message::*, setting::SettingWrapper, Reader, Subscriber, Writer};
+use crate::{
+    count_dropped, message::*, setting::SettingWrapper, Error, Reader, ResultCache, SeenIds,
+    SessionInfo, Subscriber, Writer,
+};
 use actix::prelude::*;
+use futures_channel::oneshot;
+use metrics::{gauge, increment_counter};
 use nostr_db::{CheckEventResult, Db};
-use std::{collections::HashMap, sync::Arc};
-use tracing::info;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// The default REQs read at the same time by each reader thread, the others wait in the queues of the server
+const READING_PER_THREAD: usize = 2;
+/// How often the backfills of the paused REQs are checked
+const RESUME_INTERVAL: Duration = Duration::from_millis(5);
 
 /// Server
 #[derive(Debug)]
@@ -11,26 +29,89 @@ pub struct Server {
     writer: Addr<Writer>,
     reader: Addr<Reader>,
     subscriber: Addr<Subscriber>,
-    sessions: HashMap<usize, Recipient<OutgoingMessage>>,
+    sessions: HashMap<usize, Connect>,
+    /// pending results of the events published without a session
+    publishes: HashMap<usize, oneshot::Sender<Result<CheckEventResult, Error>>>,
+    /// the REQs waiting for a reader, the priority sessions first
+    reads: VecDeque<ReadEvent>,
+    priority_reads: VecDeque<ReadEvent>,
+    /// the REQs sent to the readers and not finished
+    reading: usize,
+    max_reading: usize,
+    /// the REQs paused for the full backfills of the sessions, queued again when they drain
+    paused: Vec<ReadEvent>,
+    setting: SettingWrapper,
+    /// the events waiting for the writer
+    write_pending: Arc<AtomicUsize>,
+    write_queue: usize,
+    /// the events are acknowledged by the intake journal
+    write_ahead: bool,
+    /// the recently stored events are answered without the writer
+    seen: Arc<SeenIds>,
 }
 
 impl Server {
     pub fn create_with(db: Arc<Db>, setting: SettingWrapper) -> Addr<Server> {
         let r = setting.read();
+        let path = r.data.path.clone();
+        let seen = Arc::new(SeenIds::new(r.data.seen_ids));
+        drop(r);
+        Self::create_in(db, setting, path, seen)
+    }
+
+    /// Start the server with the data path overwriting the setting, the intake journal is saved there.
+    /// The REQs are read by the pool of `thread.reader` threads, the events are written by a dedicated thread.
+    /// The ids of the stored events are added to `seen` by the writer.
+    pub fn create_in(
+        db: Arc<Db>,
+        setting: SettingWrapper,
+        path: PathBuf,
+        seen: Arc<SeenIds>,
+    ) -> Addr<Server> {
+        let r = setting.read();
+        let write_queue = r.data.write_queue;
+        let write_ahead = r.data.write_ahead;
         let num = if r.thread.reader == 0 {
             num_cpus::get()
         } else {
             r.thread.reader
         };
+        let reading = if r.thread.reading == 0 {
+            READING_PER_THREAD
+        } else {
+            r.thread.reading
+        };
         drop(r);
 
+        // the deleted events are not duplicates
+        let c_seen = seen.clone();
+        db.on_delete(move |id| c_seen.remove(id));
+
+        let cache = Arc::new(ResultCache::default());
+        // the events deleted by the extensions, ie: the takedowns, the vanished and the retention
+        let (c_begin, c_end) = (cache.clone(), cache.clone());
+        db.on_delete_commit(move || c_begin.begin_write(), move || c_end.clear());
         Server::create(|ctx| {
-            let writer = Writer::new(Arc::clone(&db), ctx.address().recipient()).start();
+            let mut writer = Writer::new(Arc::clone(&db), ctx.address().recipient());
+            writer.cache = Some(Arc::clone(&cache));
+            writer.seen = Some(Arc::clone(&seen));
+            writer.write_batch = setting.read().data.write_batch;
+            if write_ahead {
+                if let Err(err) = writer.open_journal(path.join("intake")) {
+                    error!(error = err.to_string(), "open the intake journal error");
+                }
+            }
+            let write_pending = Arc::clone(&writer.pending);
+            info!("starting the writer thread");
+            let writer = Writer::start_in_arbiter(&Arbiter::new().handle(), |_| writer);
             let subscriber = Subscriber::new(ctx.address().recipient(), setting.clone()).start();
             let addr = ctx.address().recipient();
             info!("starting {} reader workers", num);
+            let reader_setting = setting.clone();
             let reader = SyncArbiter::start(num, move || {
-                Reader::new(Arc::clone(&db), addr.clone(), setting.clone())
+                let mut reader = Reader::new(Arc::clone(&db), addr.clone(), reader_setting.clone());
+                reader.cache = Some(Arc::clone(&cache));
+                reader
             });
 
             Server {
@@ -39,13 +120,164 @@ impl Server {
                 reader,
                 subscriber,
                 sessions: HashMap::new(),
+                publishes: HashMap::new(),
+                reads: VecDeque::new(),
+                priority_reads: VecDeque::new(),
+                reading: 0,
+                max_reading: num * reading,
+                paused: Vec::new(),
+                setting,
+                write_pending,
+                write_queue,
+                write_ahead,
+                seen,
             }
         })
     }
 
+    /// unique id of the sessions and published events
+    fn next_id(&mut self) -> usize {
+        if self.id == usize::MAX {
+            self.id = 0;
+        }
+        self.id += 1;
+        self.id
+    }
+
+    /// the session is processed ahead of the others
+    fn priority(&self, id: usize) -> bool {
+        self.sessions
+            .get(&id)
+            .is_some_and(|s| s.priority.load(Ordering::Relaxed))
+    }
+
+    /// the session is authenticated by NIP-42
+    fn authed(&self, id: usize) -> bool {
+        self.sessions
+            .get(&id)
+            .is_some_and(|s| s.authed.load(Ordering::Relaxed))
+    }
+
+    /// Queue the REQ, the queued REQs of the priority sessions are read first when the readers are busy
+    fn read(&mut self, msg: ReadEvent, ctx: &mut Context<Self>) {
+        if self.priority(msg.id) {
+            self.priority_reads.push_back(msg);
+        } else {
+            self.reads.push_back(msg);
+        }
+        self.dispatch_reads(ctx);
+    }
+
+    fn dispatch_reads(&mut self, ctx: &mut Context<Self>) {
+        while self.reading < self.max_reading {
+            let Some(msg) = self
+                .priority_reads
+                .pop_front()
+                .or_else(|| self.reads.pop_front())
+            else {
+                break;
+            };
+            self.reading += 1;
+            self.reader
+                .send(msg)
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    act.reading -= 1;
+                    if let Ok(Some(msg)) = res {
+                        act.paused.push(msg);
+                    }
+                    act.dispatch_reads(ctx);
+                    fut::ready(())
+                })
+                .spawn(ctx);
+        }
+        gauge!(
+            "nostr_relay_read_pending",
+            (self.reads.len() + self.priority_reads.len()) as f64
+        );
+    }
+
+    /// Queue the paused REQs whose sessions handled the results over the buffer,
+    /// the subscriptions of the clients not reading the results for the timeout are closed
+    fn resume_reads(&mut self, ctx: &mut Context<Self>) {
+        if self.paused.is_empty() {
+            return;
+        }
+        let r = self.setting.read();
+        let buffer = r.data.backfill_buffer;
+        let timeout: Duration = r.data.backfill_timeout.into();
+        drop(r);
+        for msg in std::mem::take(&mut self.paused) {
+            if !self.sessions.contains_key(&msg.id) {
+                continue;
+            }
+            let full = msg
+                .backfill
+                .as_ref()
+                .is_some_and(|b| buffer > 0 && b.load(Ordering::Acquire) >= buffer);
+            if !full {
+                self.read(msg, ctx);
+            } else if msg
+                .paused
+                .as_ref()
+                .is_some_and(|p| p.since.elapsed() >= timeout)
+            {
+                self.close_slow(&msg);
+            } else {
+                self.paused.push(msg);
+            }
+        }
+    }
+
+    /// Close the subscription of the client not reading the stored events
+    fn close_slow(&mut self, msg: &ReadEvent) {
+        increment_counter!("nostr_relay_backfill_closed_total");
+        count_dropped("slow client", msg.authed);
+        if let Some(backfill) = &msg.backfill {
+            backfill.fetch_add(1, Ordering::AcqRel);
+        }
+        self.read_result(ReadEventResult {
+            id: msg.id,
+            sub_id: msg.subscription.id.clone(),
+            msg: OutgoingMessage::closed(
+                &msg.subscription.id,
+                "error: the client does not read the stored events",
+            ),
+            closed: true,
+        });
+    }
+
+    /// The paused REQ of the subscription is not read again
+    fn unpause(&mut self, id: usize, sub_id: &str) {
+        self.paused
+            .retain(|msg| msg.id != id || msg.subscription.id != sub_id);
+    }
+
+    fn read_result(&mut self, msg: ReadEventResult) {
+        if msg.closed {
+            self.subscriber.do_send(Unsubscribe {
+                id: msg.id,
+                sub_id: Some(msg.sub_id.clone()),
+            });
+        }
+        if let Some(session) = self.sessions.get(&msg.id) {
+            session.read.do_send(msg);
+        }
+    }
+
+    /// The writer queue is full for the session, the anonymous events are shed first
+    fn overloaded(&self, priority: bool) -> bool {
+        let max = if priority {
+            self.write_queue
+        } else {
+            self.write_queue / 2
+        };
+        self.write_queue > 0 && self.write_pending.load(Ordering::Relaxed) >= max
+    }
+
     fn send_to_client(&self, id: usize, msg: OutgoingMessage) {
-        if let Some(addr) = self.sessions.get(&id) {
-            addr.do_send(msg);
+        if let Some(session) = self.sessions.get(&id) {
+            session.addr.do_send(msg);
         }
     }
 }
@@ -57,6 +289,7 @@ impl Actor for Server {
     type Context = Context<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         ctx.set_mailbox_capacity(10000);
+        ctx.run_interval(RESUME_INTERVAL, |act, ctx| act.resume_reads(ctx));
         info!("Actor server started");
     }
 }
@@ -67,13 +300,10 @@ impl Actor for Server {
 impl Handler<Connect> for Server {
     type Result = usize;
     fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) -> Self::Result {
-        if self.id == usize::MAX {
-            self.id = 0;
-        }
-        self.id += 1;
-        self.sessions.insert(self.id, msg.addr);
+        let id = self.next_id();
+        self.sessions.insert(id, msg);
         // send id back
-        self.id
+        id
     }
 }
 
@@ -84,6 +314,7 @@ impl Handler<Disconnect> for Server {
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
         // remove address
         self.sessions.remove(&msg.id);
+        self.paused.retain(|m| m.id != msg.id);
 
         // clear subscriptions
         self.subscriber.do_send(Unsubscribe {
@@ -101,29 +332,75 @@ impl Handler<ClientMessage> for Server {
             IncomingMessage::Event(event) => {
                 // save all event
                 // save ephemeral for check duplicate, disconnection recovery, will be deleted
-                self.writer.do_send(WriteEvent { id: msg.id, event })
+                let priority = self.priority(msg.id);
+                if self.seen.contains(event.id()) {
+                    increment_counter!("nostr_relay_seen_duplicate_total");
+                    self.send_to_client(
+                        msg.id,
+                        OutgoingMessage::ok(&event.id_str(), true, "duplicate: event exists"),
+                    );
+                } else if self.overloaded(priority) {
+                    increment_counter!("nostr_relay_write_shed_total");
+                    count_dropped("write queue full", self.authed(msg.id));
+                    self.send_to_client(
+                        msg.id,
+                        OutgoingMessage::ok(
+                            &event.id_str(),
+                            false,
+                            "rate-limited: the relay is overloaded, try again later",
+                        ),
+                    );
+                } else {
+                    self.writer.do_send(WriteEvent {
+                        id: msg.id,
+                        event,
+                        priority,
+                        ahead: self.write_ahead,
+                    })
+                }
+            }
+            IncomingMessage::Close(id) => {
+                self.unpause(msg.id, &id);
+                self.subscriber.do_send(Unsubscribe {
+                    id: msg.id,
+                    sub_id: Some(id),
+                })
             }
-            IncomingMessage::Close(id) => self.subscriber.do_send(Unsubscribe {
-                id: msg.id,
-                sub_id: Some(id),
-            }),
             IncomingMessage::Req(subscription) => {
                 let session_id = msg.id;
                 let read_event = ReadEvent {
                     id: msg.id,
                     subscription: subscription.clone(),
+                    backfill: self.sessions.get(&msg.id).map(|s| s.backfill.clone()),
+                    authed: self.authed(msg.id),
+                    paused: None,
                 };
+                let max_subscriptions = self
+                    .sessions
+                    .get(&msg.id)
+                    .map(|s| s.max_subscriptions.load(Ordering::Relaxed))
+                    .filter(|max| *max > 0);
                 self.subscriber
                     .send(Subscribe {
                         id: msg.id,
                         subscription,
+                        max_subscriptions,
                     })
                     .into_actor(self)
-                    .then(move |res, act, _ctx| {
+                    .then(move |res, act, ctx| {
                         match res {
                             Ok(res) => match res {
                                 Subscribed::Ok => {
-                                    act.reader.do_send(read_event);
+                                    // the stored events of the replaced REQ are not sent
+                                    act.unpause(session_id, &read_event.subscription.id);
+                                    act.read(read_event, ctx);
+                                }
+                                Subscribed::Duplicate => {
+                                    // the stored events were sent for the same REQ
+                                    act.send_to_client(
+                                        session_id,
+                                        OutgoingMessage::eose(&read_event.subscription.id),
+                                    );
                                 }
                                 Subscribed::Overlimit => {
                                     act.send_to_client(
@@ -162,7 +439,19 @@ impl Handler<WriteEventResult> for Server {
     type Result = ();
     fn handle(&mut self, msg: WriteEventResult, _: &mut Self::Context) {
         match msg {
-            WriteEventResult::Write { id, event, result } => {
+            WriteEventResult::Write {
+                id,
+                event,
+                result,
+                acked,
+            } => {
+                if let Some(tx) = self.publishes.remove(&id) {
+                    if let CheckEventResult::Ok(_num) = result {
+                        self.subscriber.do_send(Dispatch { id, event });
+                    }
+                    let _ = tx.send(Ok(result));
+                    return;
+                }
                 let event_id = event.id_str();
                 let out_msg = match &result {
                     CheckEventResult::Ok(_num) => OutgoingMessage::ok(&event_id, true, ""),
@@ -178,14 +467,31 @@ impl Handler<WriteEventResult> for Server {
                     CheckEventResult::ReplaceIgnored => {
                         OutgoingMessage::ok(&event_id, false, "replaced: have newer event")
                     }
+                    CheckEventResult::TakenDown => {
+                        OutgoingMessage::ok(&event_id, false, "blocked: event was taken down")
+                    }
                 };
-                self.send_to_client(id, out_msg);
+                if !acked {
+                    self.send_to_client(id, out_msg);
+                }
                 // dispatch event to subscriber
                 if let CheckEventResult::Ok(_num) = result {
+                    if let Some(session) = self.sessions.get(&id) {
+                        session.stored.do_send(EventStored {
+                            event: event.clone(),
+                        });
+                    }
                     self.subscriber.do_send(Dispatch { id, event });
                 }
             }
+            WriteEventResult::Queued { id, event_id } => {
+                self.send_to_client(id, OutgoingMessage::ok(&event_id, true, ""));
+            }
             WriteEventResult::Message { id, event: _, msg } => {
+                if let Some(tx) = self.publishes.remove(&id) {
+                    let _ = tx.send(Err(Error::Str("write event error")));
+                    return;
+                }
                 self.send_to_client(id, msg);
             }
         }
@@ -195,7 +501,7 @@ impl Handler<WriteEventResult> for Server {
 impl Handler<ReadEventResult> for Server {
     type Result = ();
     fn handle(&mut self, msg: ReadEventResult, _: &mut Self::Context) {
-        self.send_to_client(msg.id, msg.msg);
+        self.read_result(msg);
     }
 }
 
@@ -206,14 +512,97 @@ impl Handler<SubscribeResult> for Server {
     }
 }
 
+impl Handler<Broadcast> for Server {
+    type Result = usize;
+    fn handle(&mut self, msg: Broadcast, _: &mut Self::Context) -> Self::Result {
+        for session in self.sessions.values() {
+            session.addr.do_send(msg.msg.clone());
+        }
+        self.sessions.len()
+    }
+}
+
+impl Handler<ControlSessions> for Server {
+    type Result = ResponseFuture<Vec<SessionInfo>>;
+    fn handle(&mut self, msg: ControlSessions, _: &mut Self::Context) -> Self::Result {
+        let sends = self
+            .sessions
+            .values()
+            .map(|session| session.control.send(msg.0.clone()))
+            .collect::<Vec<_>>();
+        Box::pin(async move {
+            let mut sessions = futures_util::future::join_all(sends)
+                .await
+                .into_iter()
+                .filter_map(|res| res.ok().flatten())
+                .collect::<Vec<_>>();
+            sessions.sort_by_key(|s| s.id);
+            sessions
+        })
+    }
+}
+
+impl Handler<Publish> for Server {
+    type Result = ResponseFuture<Result<CheckEventResult, Error>>;
+    fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
+        let id = self.next_id();
+        let (tx, rx) = oneshot::channel();
+        self.publishes.insert(id, tx);
+        // published by the extensions and the embedding app
+        self.writer.do_send(WriteEvent {
+            id,
+            event: msg.event,
+            priority: msg.priority,
+            ahead: false,
+        });
+        Box::pin(async move { rx.await.map_err(|_| Error::Str("writer stopped"))? })
+    }
+}
+
+impl Handler<Reload> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: Reload, _: &mut Self::Context) {
+        for session in self.sessions.values() {
+            session.reload.do_send(msg.clone());
+        }
+    }
+}
+
+/// Send the event stored by another process to the subscriptions without writing it,
+/// used by the extensions sharing the database
+impl Handler<Dispatch> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: Dispatch, _: &mut Self::Context) {
+        self.subscriber.do_send(msg);
+    }
+}
+
+/// Stop the live events of the session subscriptions, used by the extensions
+impl Handler<Unsubscribe> for Server {
+    type Result = ();
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) {
+        // the paused reads of the subscriptions are dropped
+        match &msg.sub_id {
+            Some(sub_id) => self.unpause(msg.id, sub_id),
+            None => self.paused.retain(|m| m.id != msg.id),
+        }
+        self.subscriber.do_send(msg);
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::get_first)]
 mod tests {
     use super::*;
     use crate::{temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
+    use nostr_db::{
+        secp256k1::{rand::thread_rng, KeyPair},
+        Event,
+    };
     use parking_lot::RwLock;
-    use std::time::Duration;
+    use std::{str::FromStr, time::Duration};
 
     #[derive(Default)]
     struct Receiver(Arc<RwLock<Vec<OutgoingMessage>>>);
@@ -228,6 +617,48 @@ mod tests {
         }
     }
 
+    impl Handler<Reload> for Receiver {
+        type Result = ();
+        fn handle(&mut self, _msg: Reload, _ctx: &mut Self::Context) {
+            self.0.write().push(OutgoingMessage("reload".to_owned()));
+        }
+    }
+
+    impl Handler<EventStored> for Receiver {
+        type Result = ();
+        fn handle(&mut self, msg: EventStored, _ctx: &mut Self::Context) {
+            self.0
+                .write()
+                .push(OutgoingMessage(format!("stored {}", msg.event.id_str())));
+        }
+    }
+
+    impl Handler<ReadEventResult> for Receiver {
+        type Result = ();
+        fn handle(&mut self, msg: ReadEventResult, _ctx: &mut Self::Context) {
+            self.0.write().push(msg.msg);
+        }
+    }
+
+    impl Handler<Control> for Receiver {
+        type Result = Option<SessionInfo>;
+        fn handle(&mut self, msg: Control, _ctx: &mut Self::Context) -> Option<SessionInfo> {
+            if !msg.filter.matches(1, "127.0.0.1", None) {
+                return None;
+            }
+            if let ControlAction::Notice(text) = msg.action {
+                self.0.write().push(OutgoingMessage::notice(&text));
+            }
+            Some(SessionInfo {
+                id: 1,
+                ip: "127.0.0.1".to_owned(),
+                pubkey: None,
+                stats: Default::default(),
+                subscriptions: vec![],
+            })
+        }
+    }
+
     #[actix_rt::test]
     async fn message() -> Result<()> {
         let db = Arc::new(Db::open(temp_data_path("server")?)?);
@@ -257,18 +688,38 @@ mod tests {
         let receiver = Receiver::default();
         let messages = receiver.0.clone();
         let receiver = receiver.start();
-        let addr = receiver.recipient();
+        let addr = receiver.clone().recipient();
+        let reload = receiver.clone().recipient();
+        let stored = receiver.clone().recipient();
+        let control = receiver.clone().recipient();
+        let read = receiver.recipient();
 
         let server = Server::create_with(db, Setting::default().into());
 
-        let id = server.send(Connect { addr }).await?;
+        let id = server
+            .send(Connect {
+                addr,
+                reload,
+                stored,
+                read,
+                backfill: Default::default(),
+                max_subscriptions: Default::default(),
+                control,
+                priority: Default::default(),
+                authed: Default::default(),
+            })
+            .await?;
         assert_eq!(id, 1);
 
         // Unsupported
         {
             let text = r#"["UNKNOWN"]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text: text.into(),
+                msg,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
@@ -283,7 +734,11 @@ mod tests {
         {
             let text = r#"["REQ", "1", {}]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text: text.into(),
+                msg,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
@@ -296,15 +751,20 @@ mod tests {
             // write
             let text = format!(r#"["EVENT", {}]"#, note);
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text: text.into(),
+                msg,
+            };
             server.send(client_msg.clone()).await?;
             sleep(Duration::from_millis(200)).await;
             {
                 let mut w = messages.write();
-                assert_eq!(w.len(), 2);
+                assert_eq!(w.len(), 3);
                 assert!(w.get(0).unwrap().0.contains("OK"));
+                assert!(w.get(1).unwrap().0.starts_with("stored"));
                 // subscription message
-                assert!(w.get(1).unwrap().0.contains("EVENT"));
+                assert!(w.get(2).unwrap().0.contains("EVENT"));
                 w.clear();
             }
             // repeat write
@@ -318,19 +778,39 @@ mod tests {
                 w.clear();
             }
 
+            // dispatch without writing
+            server
+                .send(Dispatch {
+                    id: 0,
+                    event: Event::from_str(note)?,
+                })
+                .await?;
+            sleep(Duration::from_millis(50)).await;
+            {
+                let mut w = messages.write();
+                assert_eq!(w.len(), 1);
+                assert!(w.first().unwrap().0.contains("EVENT"));
+                w.clear();
+            }
+
             // ephemeral event
             {
                 let text = format!(r#"["EVENT", {}]"#, ephemeral_note);
                 let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-                let client_msg = ClientMessage { id, text, msg };
+                let client_msg = ClientMessage {
+                    id,
+                    text: text.into(),
+                    msg,
+                };
                 server.send(client_msg.clone()).await?;
                 sleep(Duration::from_millis(200)).await;
                 {
                     let mut w = messages.write();
-                    assert_eq!(w.len(), 2);
+                    assert_eq!(w.len(), 3);
                     assert!(w.get(0).unwrap().0.contains("OK"));
+                    assert!(w.get(1).unwrap().0.starts_with("stored"));
                     // subscription message
-                    assert!(w.get(1).unwrap().0.contains("EVENT"));
+                    assert!(w.get(2).unwrap().0.contains("EVENT"));
                     w.clear();
                 }
                 // repeat
@@ -349,7 +829,11 @@ mod tests {
 
             let text = r#"["CLOSE", "1"]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text: text.into(),
+                msg,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
@@ -364,7 +848,11 @@ mod tests {
         {
             let text = r#"["REQ", "1", {}]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text: text.into(),
+                msg,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
@@ -377,6 +865,114 @@ mod tests {
             }
         }
 
+        // broadcast
+        {
+            let num = server
+                .send(Broadcast {
+                    msg: OutgoingMessage::notice("restart"),
+                })
+                .await?;
+            assert_eq!(num, 1);
+            sleep(Duration::from_millis(50)).await;
+            let w = messages.read();
+            assert_eq!(w.len(), 1);
+            assert!(w.first().unwrap().0.contains("restart"));
+        }
+
+        // reload
+        {
+            messages.write().clear();
+            server.send(Reload).await?;
+            sleep(Duration::from_millis(50)).await;
+            let w = messages.read();
+            assert_eq!(w.len(), 1);
+            assert_eq!(w.first().unwrap().0, "reload");
+        }
+
+        // control the sessions by the filter
+        {
+            messages.write().clear();
+            let control = |authed| {
+                ControlSessions(Control {
+                    filter: SessionFilter {
+                        authed,
+                        ..Default::default()
+                    },
+                    action: ControlAction::Notice("policy".to_owned()),
+                })
+            };
+            assert_eq!(server.send(control(Some(true))).await?.len(), 0);
+            assert_eq!(server.send(control(Some(false))).await?.len(), 1);
+            assert_eq!(server.send(control(None)).await?[0].id, 1);
+            sleep(Duration::from_millis(50)).await;
+            let w = messages.read();
+            assert_eq!(w.len(), 2);
+            assert!(w.first().unwrap().0.contains("policy"));
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn backfill() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("server-backfill")?)?);
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..3)
+            .map(|i| Event::create(&key_pair, 1000 + i, 1, vec![], "".to_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(events)?;
+        let mut setting = Setting::default();
+        setting.data.backfill_chunk = 2;
+        setting.data.backfill_buffer = 2;
+        setting.data.backfill_timeout = Duration::from_millis(100).try_into().unwrap();
+        let server = Server::create_with(db, setting.into());
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        // the receiver does not handle the results
+        let backfill = Arc::new(AtomicUsize::new(0));
+        let id = server
+            .send(Connect {
+                addr: receiver.clone().recipient(),
+                reload: receiver.clone().recipient(),
+                stored: receiver.clone().recipient(),
+                control: receiver.clone().recipient(),
+                read: receiver.recipient(),
+                backfill: backfill.clone(),
+                max_subscriptions: Default::default(),
+                priority: Default::default(),
+                authed: Default::default(),
+            })
+            .await?;
+        let req = |sub_id: &str| -> Result<ClientMessage> {
+            let text = format!(r#"["REQ", "{}", {{}}]"#, sub_id);
+            Ok(ClientMessage {
+                id,
+                msg: serde_json::from_str(&text)?,
+                text: text.into(),
+            })
+        };
+
+        // paused after a chunk, read again when the backfill drains
+        server.send(req("1")?).await?;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(messages.read().len(), 2);
+        backfill.store(0, Ordering::Release);
+        sleep(Duration::from_millis(50)).await;
+        {
+            let mut w = messages.write();
+            assert_eq!(w.len(), 4);
+            assert!(w[3].0.starts_with(r#"["EOSE","1""#));
+            w.clear();
+        }
+
+        // closed when the backfill does not drain for the timeout
+        server.send(req("2")?).await?;
+        sleep(Duration::from_millis(300)).await;
+        let w = messages.read();
+        assert_eq!(w.len(), 3);
+        assert!(w[2].0.starts_with(r#"["CLOSED","2""#));
         Ok(())
     }
 }
@@ -1,18 +1,72 @@
-use crate::{hash::NoOpHasherDefault, message::*, App, Server};
+use crate::{
+    db::now,
+    hash::NoOpHasherDefault,
+    message::*,
+    setting::{Limitation, Priority, Role},
+    App, EventResult, ExtensionMessageResult, Server,
+};
 use actix::prelude::*;
 use actix_http::ws::Item;
 use actix_web::web;
 use actix_web_actors::ws;
 use bytes::BytesMut;
+use bytestring::ByteString;
 use metrics::{decrement_gauge, increment_counter, increment_gauge};
+use serde::Serialize;
+use serde_json::Value;
 use std::{
     any::{Any, TypeId},
     collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tracing::debug;
 use ws::Message;
 
+/// Count a message dropped or rejected by the relay or a session stopped for the load,
+/// by the reason and the authenticated or anonymous client
+pub fn count_dropped(reason: &'static str, authed: bool) {
+    let client = if authed { "authed" } else { "anon" };
+    increment_counter!("nostr_relay_dropped_total", "reason" => reason, "client" => client);
+}
+
+/// Counters of a session
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionStats {
+    /// unix timestamp of the connection
+    pub connected_at: u64,
+    /// text messages from the client
+    pub messages_received: u64,
+    /// messages sent to the client
+    pub messages_sent: u64,
+    /// events answered with OK true, including duplicates
+    pub events_accepted: u64,
+    /// events answered with OK false
+    pub events_rejected: u64,
+}
+
+/// A connected session listed by the admin actions
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub ip: String,
+    /// the pubkey authenticated by NIP-42
+    pub pubkey: Option<String>,
+    #[serde(flatten)]
+    pub stats: SessionStats,
+    /// the active subscriptions with the filters sent by the client
+    pub subscriptions: Vec<SubscriptionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriptionInfo {
+    pub id: String,
+    pub filters: Vec<Value>,
+}
+
 pub struct Session {
     ip: String,
 
@@ -41,6 +95,39 @@ pub struct Session {
 
     /// Buffer for constructing continuation messages
     cont: Option<BytesMut>,
+
+    /// the continuation message is binary
+    cont_binary: bool,
+
+    /// the messages are CBOR in binary frames
+    #[cfg(feature = "cbor")]
+    pub(crate) cbor: bool,
+
+    stats: SessionStats,
+
+    /// active subscriptions by id, the text of the REQ is parsed when listed
+    subscriptions: HashMap<String, ByteString>,
+
+    /// NIPs used by the client, added by the extensions
+    nips: Vec<u32>,
+
+    /// the pubkeys of the events sent to the server by event id, removed when the writer answers
+    pending: HashMap<String, String>,
+
+    /// the stored events read for the subscriptions and not sent yet
+    backfill: Arc<AtomicUsize>,
+
+    /// the pubkey authenticated by NIP-42, the limits of its role apply
+    pubkey: Option<String>,
+
+    /// the max subscriptions of the session, shared with the server
+    max_subscriptions: Arc<AtomicUsize>,
+
+    /// the messages of the session are processed ahead of the others, shared with the server
+    priority: Arc<AtomicBool>,
+
+    /// the session is authenticated, shared with the server
+    authed: Arc<AtomicBool>,
 }
 
 impl Session {
@@ -56,6 +143,13 @@ impl Session {
             .and_then(|boxed| boxed.downcast_ref())
     }
 
+    /// get mutable extension data
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.data
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|boxed| boxed.downcast_mut())
+    }
+
     /// Get session id
     pub fn id(&self) -> usize {
         self.id
@@ -66,6 +160,124 @@ impl Session {
         &self.ip
     }
 
+    /// Get the counters
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /// Number of active subscriptions
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// Record the pubkey authenticated by NIP-42, the limits of its role apply
+    pub fn authenticated(&mut self, pubkey: String) {
+        self.pubkey = Some(pubkey);
+        self.authed.store(true, Ordering::Relaxed);
+        if let Some((name, _)) = self.role() {
+            increment_counter!("nostr_relay_session_role_total", "role" => name);
+        }
+        self.update_priority();
+    }
+
+    /// Close all subscriptions of the session without a CLOSED, ie: the session lost the permission to read
+    pub fn unsubscribe_all(&mut self) {
+        self.subscriptions.clear();
+        self.app.server.do_send(Unsubscribe {
+            id: self.id,
+            sub_id: None,
+        });
+    }
+
+    /// Count the dropped message of the session by the reason
+    pub fn count_dropped(&self, reason: &'static str) {
+        count_dropped(reason, self.pubkey.is_some());
+    }
+
+    /// The session is processed ahead of the others by the priority setting
+    pub fn priority(&self) -> bool {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    fn update_priority(&self) {
+        let setting = self.app.setting.read();
+        let priority = match setting.data.priority {
+            Priority::Off => false,
+            Priority::Roles => self.pubkey().is_some_and(|p| setting.role(p).is_some()),
+            Priority::Authed => self.pubkey.is_some(),
+        };
+        self.priority.store(priority, Ordering::Relaxed);
+    }
+
+    /// The pubkey authenticated by NIP-42
+    pub fn pubkey(&self) -> Option<&str> {
+        self.pubkey.as_deref()
+    }
+
+    /// The role of the authenticated pubkey
+    pub fn role(&self) -> Option<(String, Role)> {
+        let pubkey = self.pubkey.as_ref()?;
+        self.app
+            .setting
+            .read()
+            .role(pubkey)
+            .map(|(name, role)| (name.clone(), role.clone()))
+    }
+
+    /// The relay limitation with the overrides of the role
+    pub fn limitation(&self) -> Limitation {
+        self.app.setting.read().limitation_of(self.pubkey())
+    }
+
+    /// The id, ip, counters and subscriptions of the session
+    pub fn info(&self) -> SessionInfo {
+        let mut subscriptions = self
+            .subscriptions
+            .iter()
+            .map(|(id, text)| SubscriptionInfo {
+                id: id.clone(),
+                filters: serde_json::from_str::<Vec<Value>>(text)
+                    .map(|req| req.into_iter().skip(2).collect())
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        subscriptions.sort_by(|a, b| a.id.cmp(&b.id));
+        SessionInfo {
+            id: self.id,
+            ip: self.ip.clone(),
+            pubkey: self.pubkey.clone(),
+            stats: self.stats.clone(),
+            subscriptions,
+        }
+    }
+
+    /// NIPs used by the client, ie: 42 after AUTH
+    pub fn nips(&self) -> &[u32] {
+        &self.nips
+    }
+
+    /// Send the message to the client in the negotiated encoding, without the extension outgoing methods
+    pub fn text<T: Into<ByteString>>(&self, ctx: &mut ws::WebsocketContext<Self>, text: T) {
+        let text = text.into();
+        #[cfg(feature = "cbor")]
+        if self.cbor {
+            match crate::cbor::encode(&text) {
+                Ok(bytes) => ctx.binary(bytes),
+                Err(err) => debug!("Session cbor encode {} {}", self.id, err),
+            }
+            return;
+        }
+        ctx.text(text);
+    }
+
+    /// Record a NIP used by the client
+    pub fn add_nip(&mut self, nip: u32) {
+        if !self.nips.contains(&nip) {
+            self.nips.push(nip);
+            self.nips.sort();
+        }
+    }
+
     pub fn new(ip: String, app: web::Data<App>) -> Session {
         let setting = app.setting.read();
         let heartbeat_timeout = setting.network.heartbeat_timeout.into();
@@ -81,6 +293,21 @@ impl Session {
             app,
             data: HashMap::default(),
             cont: None,
+            cont_binary: false,
+            #[cfg(feature = "cbor")]
+            cbor: false,
+            stats: SessionStats {
+                connected_at: now(),
+                ..Default::default()
+            },
+            subscriptions: HashMap::new(),
+            nips: Vec::new(),
+            pending: HashMap::default(),
+            backfill: Default::default(),
+            pubkey: None,
+            max_subscriptions: Default::default(),
+            priority: Default::default(),
+            authed: Default::default(),
         }
     }
 
@@ -93,6 +320,7 @@ impl Session {
                 // heartbeat timed out
                 // stop actor
                 increment_counter!("nostr_relay_session_stop_total", "reason" => "heartbeat timeout");
+                act.count_dropped("heartbeat timeout");
                 ctx.stop();
                 // don't try to send a ping
                 return;
@@ -102,8 +330,9 @@ impl Session {
         });
     }
 
-    fn handle_message(&mut self, text: String, ctx: &mut ws::WebsocketContext<Self>) {
-        let msg = serde_json::from_str::<IncomingMessage>(&text);
+    fn handle_message(&mut self, text: ByteString, ctx: &mut ws::WebsocketContext<Self>) {
+        self.stats.messages_received += 1;
+        let msg = IncomingMessage::from_bytes(&text);
         match msg {
             Ok(msg) => {
                 if let Some(cmd) = msg.known_command() {
@@ -116,42 +345,212 @@ impl Session {
                     text,
                     msg,
                 };
-                {
-                    let r = self.app.setting.read();
-                    if let Err(err) = msg.validate(&r.limitation) {
-                        if let IncomingMessage::Event(event) = &msg.msg {
-                            ctx.text(OutgoingMessage::ok(
-                                &event.id_str(),
-                                false,
-                                &err.to_string(),
-                            ));
-                        } else {
-                            ctx.text(OutgoingMessage::notice(&err.to_string()));
+                // the signature of a recently stored event was verified, the id is still checked
+                let res = match &msg.msg {
+                    IncomingMessage::Event(event) if self.app.seen.contains(event.id()) => {
+                        msg.validate_trusted(&self.limitation())
+                    }
+                    _ => msg.validate(&self.limitation()),
+                };
+                if let Err(err) = res {
+                    match &msg.msg {
+                        IncomingMessage::Event(event) => {
+                            let out = OutgoingMessage::ok(&event.id_str(), false, &err.to_string());
+                            self.answer(out, "relay", Some(&event.pubkey_str()), ctx);
+                        }
+                        IncomingMessage::Req(sub) => {
+                            self.send(OutgoingMessage::closed(&sub.id, &err.to_string()), ctx);
                         }
-                        return;
+                        _ => self.send(OutgoingMessage::notice(&err.to_string()), ctx),
                     }
+                    return;
                 }
 
-                match self
-                    .app
-                    .clone()
-                    .extensions
-                    .read()
-                    .call_message(msg, self, ctx)
+                self.call_message(0, msg, ctx);
+            }
+            Err(err) => match IncomingMessage::subscription_id(&text) {
+                // the filters are invalid, the client learns why the subscription is closed
+                Some(id) => {
+                    let reason = IncomingMessage::invalid_reason(&err);
+                    self.send(OutgoingMessage::closed(&id, &reason), ctx);
+                }
+                None => {
+                    self.send(
+                        OutgoingMessage::notice(&format!("json error: {}", err)),
+                        ctx,
+                    );
+                }
+            },
+        };
+    }
+
+    /// decode the binary message of the negotiated encoding
+    #[cfg_attr(not(feature = "cbor"), allow(unused_variables))]
+    fn handle_binary(&mut self, bytes: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        #[cfg(feature = "cbor")]
+        if self.cbor {
+            match crate::cbor::decode(bytes) {
+                Ok(text) => {
+                    debug!("Session cbor {} {} {}", self.id, self.ip, text);
+                    self.handle_message(text.into(), ctx);
+                }
+                Err(err) => {
+                    self.send(
+                        OutgoingMessage::notice(&format!("cbor error: {}", err)),
+                        ctx,
+                    );
+                }
+            }
+            return;
+        }
+        self.text(ctx, OutgoingMessage::notice("Not support binary message"));
+    }
+
+    /// send the message to the client after the extension outgoing methods
+    fn send(&mut self, msg: OutgoingMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        self.answer(msg, "relay", None, ctx);
+    }
+
+    /// send the message answered by the extension, the pubkey of an OK message
+    /// is looked up in the pending events when unknown
+    fn answer(
+        &mut self,
+        mut msg: OutgoingMessage,
+        by: &str,
+        pubkey: Option<&str>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let app = self.app.clone();
+        let extensions = app.extensions.read();
+        extensions.call_outgoing(&mut msg, self);
+        self.stats.messages_sent += 1;
+        if let Some(accepted) = msg.ok_result() {
+            if accepted {
+                self.stats.events_accepted += 1;
+            } else {
+                self.stats.events_rejected += 1;
+            }
+            if let Ok((_, id, _, message)) =
+                serde_json::from_str::<(String, String, bool, String)>(&msg.0)
+            {
+                let pending = self.pending.remove(&id);
+                let result = EventResult {
+                    id: &id,
+                    pubkey: pubkey.or(pending.as_deref()).unwrap_or_default(),
+                    accepted,
+                    message: &message,
+                    by,
+                };
+                extensions.call_event_result(&result, self);
+            }
+        }
+        self.text(ctx, msg);
+    }
+
+    /// track the subscriptions sent to the server, as the subscriber limits them
+    fn track_subscription(&mut self, msg: &ClientMessage) {
+        match &msg.msg {
+            IncomingMessage::Req(sub) => {
+                let limit = self.limitation().max_subscriptions;
+                self.max_subscriptions.store(limit, Ordering::Relaxed);
+                if !sub.id.is_empty()
+                    && sub.id.len() <= 64
+                    && (self.subscriptions.len() < limit
+                        || self.subscriptions.contains_key(&sub.id))
                 {
-                    crate::ExtensionMessageResult::Continue(msg) => {
-                        self.server.do_send(msg);
-                    }
-                    crate::ExtensionMessageResult::Stop(out) => {
-                        ctx.text(out);
-                    }
-                    crate::ExtensionMessageResult::Ignore => {
-                        // ignore
+                    self.subscriptions.insert(sub.id.clone(), msg.text.clone());
+                }
+            }
+            IncomingMessage::Close(id) => {
+                self.subscriptions.remove(id);
+            }
+            _ => {}
+        }
+    }
+
+    /// run the extension message methods from the index, then send the message to the server
+    fn call_message(
+        &mut self,
+        start: usize,
+        msg: ClientMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let text = msg.text.clone();
+        let (index, result) = self
+            .app
+            .clone()
+            .extensions
+            .read()
+            .call_message_from(start, msg, self, ctx);
+        self.message_result(index, text, result, ctx);
+    }
+
+    /// the name of the extension at the running index
+    fn extension_name(&self, index: usize) -> &'static str {
+        self.app
+            .extensions
+            .read()
+            .names()
+            .get(index)
+            .copied()
+            .unwrap_or("relay")
+    }
+
+    fn message_result(
+        &mut self,
+        index: usize,
+        text: ByteString,
+        result: ExtensionMessageResult,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match result {
+            ExtensionMessageResult::Continue(msg) => {
+                if index < self.app.extensions.read().names().len() {
+                    self.call_message(index + 1, msg, ctx);
+                } else {
+                    self.track_subscription(&msg);
+                    if let IncomingMessage::Event(event) = &msg.msg {
+                        // the writer answers every event
+                        self.pending.insert(event.id_str(), event.pubkey_str());
                     }
-                };
+                    self.server.do_send(msg);
+                }
             }
-            Err(err) => {
-                ctx.text(OutgoingMessage::notice(&format!("json error: {}", err)));
+            ExtensionMessageResult::Stop(out) => {
+                if out.ok_result().is_some() {
+                    let pubkey = match IncomingMessage::from_bytes(&text) {
+                        Ok(IncomingMessage::Event(event)) => Some(event.pubkey_str()),
+                        _ => None,
+                    };
+                    self.answer(out, self.extension_name(index), pubkey.as_deref(), ctx);
+                } else {
+                    self.send(out, ctx);
+                }
+            }
+            ExtensionMessageResult::Ignore => {
+                // ignore
+            }
+            ExtensionMessageResult::Wait(fut) => {
+                let timeout: Duration = self.app.setting.read().extension.timeout.into();
+                // wait blocks the next messages of the session until resolved
+                ctx.wait(actix::clock::timeout(timeout, fut).into_actor(self).map(
+                    move |res, act, ctx| match res {
+                        Ok(result) => act.message_result(index, text, result, ctx),
+                        Err(_) => {
+                            increment_counter!("nostr_relay_extension_timeout_total");
+                            act.count_dropped("extension timeout");
+                            let err = "error: extension timeout";
+                            match IncomingMessage::from_bytes(&text) {
+                                Ok(IncomingMessage::Event(event)) => {
+                                    let out = OutgoingMessage::ok(&event.id_str(), false, err);
+                                    let by = act.extension_name(index);
+                                    act.answer(out, by, Some(&event.pubkey_str()), ctx)
+                                }
+                                _ => act.text(ctx, OutgoingMessage::notice(err)),
+                            }
+                        }
+                    },
+                ));
             }
         };
     }
@@ -162,7 +561,65 @@ impl Handler<OutgoingMessage> for Session {
     type Result = ();
 
     fn handle(&mut self, msg: OutgoingMessage, ctx: &mut Self::Context) {
-        ctx.text(msg);
+        self.send(msg, ctx);
+    }
+}
+
+/// The mailbox is not handled while the websocket is not writable, the readers pause meanwhile
+impl Handler<ReadEventResult> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReadEventResult, ctx: &mut Self::Context) {
+        self.backfill.fetch_sub(1, Ordering::AcqRel);
+        self.send(msg.msg, ctx);
+    }
+}
+
+/// Re-evaluate the session after the setting reload
+impl Handler<Reload> for Session {
+    type Result = ();
+
+    fn handle(&mut self, _: Reload, ctx: &mut Self::Context) {
+        self.update_priority();
+        self.app.clone().extensions.read().call_reloaded(self, ctx);
+    }
+}
+
+/// Run the admin action when the session matches
+impl Handler<Control> for Session {
+    type Result = Option<SessionInfo>;
+
+    fn handle(&mut self, msg: Control, ctx: &mut Self::Context) -> Option<SessionInfo> {
+        if !msg.filter.matches(self.id, &self.ip, self.pubkey()) {
+            return None;
+        }
+        let info = self.info();
+        match msg.action {
+            ControlAction::List => {}
+            ControlAction::Notice(text) => self.send(OutgoingMessage::notice(&text), ctx),
+            ControlAction::Kick(notice) => {
+                if let Some(text) = notice {
+                    self.send(OutgoingMessage::notice(&text), ctx);
+                }
+                increment_counter!("nostr_relay_session_stop_total", "reason" => "kicked");
+                ctx.close(Some(ws::CloseCode::Policy.into()));
+                ctx.stop();
+            }
+        }
+        Some(info)
+    }
+}
+
+/// Run the extension hooks after the event of the session is persisted
+impl Handler<EventStored> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: EventStored, ctx: &mut Self::Context) {
+        self.app
+            .clone()
+            .extensions
+            .read()
+            .call_event_stored(&msg.event, self, ctx);
     }
 }
 
@@ -180,7 +637,15 @@ impl Actor for Session {
         let addr = ctx.address();
         self.server
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                reload: addr.clone().recipient(),
+                stored: addr.clone().recipient(),
+                control: addr.clone().recipient(),
+                read: addr.recipient(),
+                backfill: self.backfill.clone(),
+                max_subscriptions: self.max_subscriptions.clone(),
+                priority: self.priority.clone(),
+                authed: self.authed.clone(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -229,7 +694,8 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
             Err(err) => {
                 match err {
                     ws::ProtocolError::Overflow => {
-                        ctx.text(OutgoingMessage::notice("payload reached size limit."));
+                        self.count_dropped("frame too large");
+                        self.text(ctx, OutgoingMessage::notice("payload reached size limit."));
                     }
                     _ => {
                         debug!("Session error {} {} {:?}", self.id, self.ip, err);
@@ -250,7 +716,6 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
                 self.hb = Instant::now();
             }
             ws::Message::Text(text) => {
-                let text = text.to_string();
                 debug!("Session text {} {} {}", self.id, self.ip, text);
                 self.handle_message(text, ctx);
             }
@@ -259,8 +724,8 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
                 increment_counter!("nostr_relay_session_stop_total", "reason" => "message close");
                 ctx.stop();
             }
-            ws::Message::Binary(_) => {
-                ctx.text(OutgoingMessage::notice("Not support binary message"));
+            ws::Message::Binary(bytes) => {
+                self.handle_binary(&bytes, ctx);
             }
             ws::Message::Continuation(cont) => match cont {
                 Item::FirstText(buf) => {
@@ -268,8 +733,11 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
                     bytes.extend_from_slice(&buf);
                     self.cont = Some(bytes);
                 }
-                Item::FirstBinary(_) => {
-                    ctx.text(OutgoingMessage::notice("Not support binary message"));
+                Item::FirstBinary(buf) => {
+                    let mut bytes = BytesMut::new();
+                    bytes.extend_from_slice(&buf);
+                    self.cont = Some(bytes);
+                    self.cont_binary = true;
                 }
                 Item::Continue(buf) => {
                     if let Some(bytes) = &mut self.cont {
@@ -279,7 +747,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
                 Item::Last(buf) => {
                     if let Some(mut bytes) = self.cont.take() {
                         bytes.extend_from_slice(&buf);
-                        if let Ok(text) = String::from_utf8(bytes.to_vec()) {
+                        if std::mem::take(&mut self.cont_binary) {
+                            self.handle_binary(&bytes, ctx);
+                        } else if let Ok(text) = ByteString::try_from(bytes.freeze()) {
                             debug!("Session text {} {} {}", self.id, self.ip, text);
                             self.handle_message(text, ctx);
                         }
@@ -381,7 +851,7 @@ mod tests {
             _session: &mut Session,
             _ctx: &mut <Session as actix::Actor>::Context,
         ) -> ExtensionMessageResult {
-            ExtensionMessageResult::Stop(OutgoingMessage(msg.text))
+            ExtensionMessageResult::Stop(OutgoingMessage(msg.text.to_string()))
         }
 
         fn name(&self) -> &'static str {
@@ -406,6 +876,356 @@ mod tests {
         Ok(())
     }
 
+    /// continue after the delay in milliseconds of the subscription id
+    struct Delay;
+    impl Extension for Delay {
+        fn message(
+            &self,
+            msg: ClientMessage,
+            _session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            let delay = match &msg.msg {
+                IncomingMessage::Req(sub) => sub.id.parse().unwrap_or_default(),
+                _ => 0,
+            };
+            ExtensionMessageResult::Wait(Box::pin(async move {
+                sleep(Duration::from_millis(delay)).await;
+                ExtensionMessageResult::Continue(msg)
+            }))
+        }
+
+        fn name(&self) -> &'static str {
+            "Delay"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn async_extension() -> Result<()> {
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("async_extension").unwrap();
+            {
+                let mut w = data.setting.write();
+                w.extension.timeout = Duration::from_millis(500).try_into().unwrap();
+            }
+            data.add_extension(Delay).add_extension(Echo).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+
+        // the messages keep the order
+        let slow = r#"["REQ", "300", {}]"#;
+        let fast = r#"["REQ", "0", {}]"#;
+        framed.send(ws::Message::Text(slow.into())).await?;
+        framed.send(ws::Message::Text(fast.into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(slow.as_bytes()))
+        );
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(fast.as_bytes()))
+        );
+
+        // timeout
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1000", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(
+                br#"["NOTICE","error: extension timeout"]"#
+            ))
+        );
+        Ok(())
+    }
+
+    struct Stored;
+    impl Extension for Stored {
+        fn event_stored(
+            &self,
+            event: &crate::db::Event,
+            _session: &Session,
+            ctx: &mut <Session as actix::Actor>::Context,
+        ) {
+            ctx.text(format!("stored {}", event.id_str()));
+        }
+
+        fn name(&self) -> &'static str {
+            "Stored"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn event_stored() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        };
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("event_stored").unwrap();
+            data.add_extension(Stored).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
+        let text = format!(r#"["EVENT", {}]"#, event);
+        framed.send(ws::Message::Text(text.clone().into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::from(format!("stored {}", event.id_str())))
+        );
+
+        // duplicate
+        framed.send(ws::Message::Text(text.into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+        sleep(Duration::from_millis(100)).await;
+        framed
+            .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(item, ws::Frame::Close(Some(ws::CloseCode::Normal.into())));
+        Ok(())
+    }
+
+    struct Rewrite;
+    impl Extension for Rewrite {
+        fn outgoing(&self, msg: &mut OutgoingMessage, _session: &Session) {
+            msg.0 = msg.0.replace("EOSE", "eose");
+        }
+
+        fn name(&self) -> &'static str {
+            "Rewrite"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn outgoing() -> Result<()> {
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("outgoing").unwrap();
+            data.add_extension(Rewrite).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(br#"["eose","1"]"#))
+        );
+        Ok(())
+    }
+
+    struct Stats;
+    impl Extension for Stats {
+        fn message(
+            &self,
+            msg: ClientMessage,
+            session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            if let IncomingMessage::Req(sub) = &msg.msg {
+                if sub.id == "stats" {
+                    let stats = session.stats();
+                    return OutgoingMessage(format!(
+                        "{} {} {} {} {} {:?}",
+                        stats.messages_received,
+                        stats.messages_sent,
+                        stats.events_accepted,
+                        stats.events_rejected,
+                        session.subscriptions(),
+                        session.nips(),
+                    ))
+                    .into();
+                }
+            }
+            ExtensionMessageResult::Continue(msg)
+        }
+
+        fn name(&self) -> &'static str {
+            "Stats"
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[actix_rt::test]
+    async fn cbor() -> Result<()> {
+        use crate::{
+            cbor::{decode, encode, PROTOCOL},
+            db::{
+                now,
+                secp256k1::{rand::thread_rng, KeyPair},
+                Event,
+            },
+        };
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let mut srv = actix_test::start(|| create_test_app("cbor").unwrap().web_app());
+        srv.client_headers()
+            .unwrap()
+            .insert("Sec-WebSocket-Protocol".parse()?, PROTOCOL.parse()?);
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Binary(encode(r#"["REQ", "1", {}]"#)?.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Binary(b) if decode(&b)? == r#"["EOSE","1"]"#));
+
+        let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
+        let text = format!(r#"["EVENT", {}]"#, event);
+        framed
+            .send(ws::Message::Binary(encode(&text)?.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        let ok = format!(r#"["OK","{}",true,""]"#, event.id_str());
+        assert!(matches!(item, ws::Frame::Binary(b) if decode(&b)? == ok));
+        let item = framed.next().await.unwrap()?;
+        let ws::Frame::Binary(b) = item else {
+            panic!("not binary")
+        };
+        let (cmd, id, live): (String, String, Event) = serde_json::from_str(&decode(&b)?)?;
+        assert_eq!((cmd.as_str(), id.as_str()), ("EVENT", "1"));
+        assert_eq!(live.id(), event.id());
+
+        // the text messages are answered in CBOR
+        framed
+            .send(ws::Message::Text(r#"["CLOSE", "1"]"#.into()))
+            .await?;
+        framed.send(ws::Message::Binary(b"\xff"[..].into())).await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(
+            matches!(item, ws::Frame::Binary(b) if decode(&b)?.starts_with(r#"["NOTICE","cbor error"#))
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn stats() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        };
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("stats").unwrap();
+            data.add_extension(Stats).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"EOSE\"")));
+
+        let event = Event::create(&key_pair, now(), 1, vec![], "test".to_owned())?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+        // the live event of the subscription
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"EVENT\"")));
+
+        let event = Event::create(&key_pair, now() + 3600, 1, vec![], "test".to_owned())?;
+        framed
+            .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+
+        framed
+            .send(ws::Message::Text(r#"["CLOSE", "1"]"#.into()))
+            .await?;
+        framed
+            .send(ws::Message::Text(r#"["REQ", "stats", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::copy_from_slice(b"5 4 1 1 0 []"))
+        );
+        Ok(())
+    }
+
+    /// reject the events with the content "reject", record the answers
+    #[derive(Default, Clone)]
+    struct Results(std::sync::Arc<parking_lot::Mutex<Vec<String>>>);
+    impl Extension for Results {
+        fn message(
+            &self,
+            msg: ClientMessage,
+            _session: &mut Session,
+            _ctx: &mut <Session as actix::Actor>::Context,
+        ) -> ExtensionMessageResult {
+            if let IncomingMessage::Event(event) = &msg.msg {
+                if event.content() == "reject" {
+                    return OutgoingMessage::ok(&event.id_str(), false, "blocked: test").into();
+                }
+            }
+            ExtensionMessageResult::Continue(msg)
+        }
+
+        fn event_result(&self, result: &EventResult, session: &Session) {
+            self.0.lock().push(format!(
+                "{} {} {} {} {}",
+                &result.pubkey[..4],
+                result.accepted,
+                result.message,
+                result.by,
+                session.ip()
+            ));
+        }
+
+        fn name(&self) -> &'static str {
+            "Results"
+        }
+    }
+
+    #[actix_rt::test]
+    async fn event_result() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        };
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let results = Results::default();
+        let list = results.0.clone();
+        let mut srv = actix_test::start(move || {
+            let data = create_test_app("event_result").unwrap();
+            data.add_extension(results.clone()).web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        let mut pubkey = String::new();
+        for (time, content) in [(now(), "test"), (now(), "reject"), (now() + 3600, "test")] {
+            let event = Event::create(&key_pair, time, 1, vec![], content.to_owned())?;
+            pubkey = event.pubkey_str()[..4].to_owned();
+            framed
+                .send(ws::Message::Text(format!(r#"["EVENT", {}]"#, event).into()))
+                .await?;
+            let item = framed.next().await.unwrap()?;
+            assert!(matches!(item, ws::Frame::Text(t) if t.starts_with(b"[\"OK\"")));
+        }
+        let list = list.lock().clone();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0], format!("{} true  relay 127.0.0.1", pubkey));
+        assert_eq!(
+            list[1],
+            format!("{} false blocked: test Results 127.0.0.1", pubkey)
+        );
+        assert!(list[2].starts_with(&format!("{} false invalid", pubkey)));
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn max_size() -> Result<()> {
         let text = r#"["REQ", "1", {}]"#;
@@ -470,4 +1290,54 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn continuation_event() -> Result<()> {
+        use crate::db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        };
+        let event = Event::create(
+            &KeyPair::new_global(&mut thread_rng()),
+            now(),
+            1,
+            vec![vec!["t".to_owned(), "nostr".to_owned()]],
+            "Good morning 😃".to_owned(),
+        )?;
+        let text = format!(r#"["EVENT", {}]"#, event);
+        // split in the emoji
+        let split = text.find('😃').unwrap() + 2;
+
+        let mut srv = actix_test::start(|| {
+            let data = create_test_app("continuation_event").unwrap();
+            data.web_app()
+        });
+        let mut framed = srv.ws_at("/").await.unwrap();
+        for item in [
+            Item::FirstText(Bytes::copy_from_slice(&text.as_bytes()[..10])),
+            Item::Continue(Bytes::copy_from_slice(&text.as_bytes()[10..split])),
+            Item::Last(Bytes::copy_from_slice(&text.as_bytes()[split..])),
+        ] {
+            framed.send(ws::Message::Continuation(item)).await?;
+        }
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::from(format!(
+                r#"["OK","{}",true,""]"#,
+                event.id_str()
+            )))
+        );
+
+        framed
+            .send(ws::Message::Text(r#"["REQ", "1", {}]"#.into()))
+            .await?;
+        let item = framed.next().await.unwrap()?;
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::from(format!(r#"["EVENT","1",{}]"#, event)))
+        );
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 use crate::Error;
 use crate::{duration::NonZeroDuration, hash::NoOpHasherDefault, Result};
-use config::{Config, Environment, File, FileFormat};
+use config::{Config, ConfigError, Environment, File, FileFormat};
 use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use parking_lot::RwLock;
 use serde::de::DeserializeOwned;
@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs,
     ops::Deref,
     path::{Path, PathBuf},
@@ -62,6 +62,58 @@ pub struct Data {
 
     /// Query filter timeout time
     pub db_query_timeout: Option<NonZeroDuration>,
+
+    /// Max index entries scanned by the filters of a REQ, the REQ is closed with the results found
+    /// when it is exceeded. Default no limit
+    pub db_scan_budget: Option<u64>,
+
+    /// Share the results of the same filters for the time, default no cache.
+    pub result_cache: Option<NonZeroDuration>,
+    /// max number of the cached filters
+    pub result_cache_size: usize,
+    /// the results with more events are not cached
+    pub result_cache_events: usize,
+
+    /// the stored events of a REQ sent between checking the session buffer
+    pub backfill_chunk: usize,
+    /// the REQ pauses while more results are not sent to the client, 0 no flow control,
+    /// the reader is freed and the REQ is read again from its position when they are sent
+    pub backfill_buffer: usize,
+    /// the subscription is closed when the client does not read the results for the time
+    pub backfill_timeout: NonZeroDuration,
+
+    /// The sessions whose REQs and events are processed ahead of the others when the readers
+    /// or the writer are saturated
+    pub priority: Priority,
+    /// max events written in a transaction, the priority events first, the others wait for the next one.
+    /// 0 no limit, read at starting
+    pub write_batch: usize,
+    /// max events waiting for the writer, the anonymous events are rejected with `rate-limited:`
+    /// when half of the queue is used, the priority events when it is full. 0 no limit, read at starting
+    pub write_queue: usize,
+    /// acknowledge the events when they are saved in the intake journal `$path/intake`,
+    /// the journal is replayed at starting if the relay stopped before the commit. Read at starting
+    pub write_ahead: bool,
+    /// the ids of the recently stored events kept in memory, the events sent again are answered
+    /// with `duplicate:` without verifying the signature and writing the db. 0 disabled, read at starting
+    pub seen_ids: usize,
+
+    /// a new event matching several subscriptions of a session is sent once,
+    /// with the first matching subscription found
+    pub fanout_once: bool,
+}
+
+/// The sessions processed ahead of the anonymous traffic
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// no priority, first come first served
+    Off,
+    /// the sessions authenticated with the pubkey of a role
+    Roles,
+    /// the sessions authenticated by NIP-42
+    #[default]
+    Authed,
 }
 
 impl Default for Data {
@@ -69,6 +121,19 @@ impl Default for Data {
         Self {
             path: PathBuf::from("./data"),
             db_query_timeout: None,
+            db_scan_budget: None,
+            result_cache: None,
+            result_cache_size: 100,
+            result_cache_events: 500,
+            backfill_chunk: 100,
+            backfill_buffer: 1000,
+            backfill_timeout: Duration::from_secs(30).try_into().unwrap(),
+            priority: Priority::default(),
+            write_batch: 10000,
+            write_queue: 0,
+            write_ahead: false,
+            seen_ids: 100000,
+            fanout_once: false,
         }
     }
 }
@@ -81,6 +146,31 @@ pub struct Thread {
     pub http: usize,
     /// number of read event threads
     pub reader: usize,
+    /// the REQs read at the same time by each reader thread, the others wait in the queue of the server
+    pub reading: usize,
+}
+
+/// extensions config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ExtensionSetting {
+    /// extension names in the order they process messages,
+    /// the unlisted extensions run after them in the registration order
+    pub order: Vec<String>,
+    /// extension names skipped when processing sessions and messages, hot reloaded without dropping connections
+    pub disabled: Vec<String>,
+    /// how long the session waits for an async extension message result
+    pub timeout: NonZeroDuration,
+}
+
+impl Default for ExtensionSetting {
+    fn default() -> Self {
+        Self {
+            order: Default::default(),
+            disabled: Default::default(),
+            timeout: Duration::from_secs(5).try_into().unwrap(),
+        }
+    }
 }
 
 /// network config
@@ -101,8 +191,31 @@ pub struct Network {
 
     pub real_ip_header: Option<String>,
 
+    /// privacy mode, the client ips are keyed-hashed before they are used by the rate limits,
+    /// the bans, the logs and the audit records
+    pub hash_ip: bool,
+
+    /// the key of the ip hashes, ie: set by `ip_hash_key_file`, a random key of the process when empty,
+    /// the hashes and the bans of the hashed ips change with the key
+    pub ip_hash_key: String,
+
     /// redirect to other site when user access the http index page
     pub index_redirect_to: Option<String>,
+
+    /// SOCKS5 proxy of the outbound connections of the extensions, ie: "socks5://127.0.0.1:9050".
+    /// The proxy resolves the hostnames
+    pub proxy: Option<String>,
+}
+
+impl Network {
+    /// The ip of the client used by the relay, keyed-hashed in the privacy mode
+    pub fn client_ip(&self, ip: &str) -> String {
+        if self.hash_ip && !ip.is_empty() && !crate::privacy::is_ip_hash(ip) {
+            crate::privacy::hash_ip(&self.ip_hash_key, ip)
+        } else {
+            ip.to_owned()
+        }
+    }
 }
 
 impl Default for Network {
@@ -113,7 +226,10 @@ impl Default for Network {
             heartbeat_interval: Duration::from_secs(60).try_into().unwrap(),
             heartbeat_timeout: Duration::from_secs(120).try_into().unwrap(),
             real_ip_header: None,
+            hash_ip: false,
+            ip_hash_key: String::new(),
             index_redirect_to: None,
+            proxy: None,
         }
     }
 }
@@ -139,6 +255,8 @@ pub struct Limitation {
     pub max_event_time_older_than_now: u64,
     /// Events newer than this will be rejected. default 15 minutes, 0 ignore
     pub max_event_time_newer_than_now: u64,
+    /// the REQs with a filter without ids, authors, kinds, tags or search are closed, default false
+    pub reject_broad_filters: bool,
 }
 
 impl Default for Limitation {
@@ -153,18 +271,56 @@ impl Default for Limitation {
             max_event_tags: 5000,
             max_event_time_older_than_now: 94608000,
             max_event_time_newer_than_now: 900,
+            reject_broad_filters: false,
         }
     }
 }
 
+/// Elevated limits of the pubkeys of a role, ie: the admin, moderator or trusted bots of the operator.
+/// The limits apply to the sessions after NIP-42 auth, the unset limits are the relay limitation.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Role {
+    /// hex pubkeys of the role
+    pub pubkeys: Vec<String>,
+    pub max_subscriptions: Option<usize>,
+    pub max_message_length: Option<usize>,
+    /// exempt from the event rate limits
+    pub unlimited_rate: bool,
+    /// blossom storage quota in bytes, 0 unlimited
+    pub quota: Option<u64>,
+}
+
+/// A virtual relay served by the process, selected by the hostname or the url path of the request
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Tenant {
+    /// serve the requests of the hostname, ie: "alice.example.com"
+    pub host: Option<String>,
+    /// serve the requests under the url path, ie: "/alice"
+    pub path: Option<String>,
+    /// config file of the tenant, relative to the directory of the main config file.
+    /// The tenant has its own data path, information, limitation and extension settings,
+    /// the host, port and threads of the main config are used.
+    pub config: PathBuf,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct Setting {
+    /// Config files merged after the main config file, glob patterns relative to its directory.
+    /// The later files take precedence, matched files of a pattern are sorted by name.
+    pub include: Vec<String>,
     pub information: Information,
     pub data: Data,
     pub thread: Thread,
     pub network: Network,
     pub limitation: Limitation,
+    pub extension: ExtensionSetting,
+    /// virtual relays served by the process, ie: one for each customer
+    pub tenants: Vec<Tenant>,
+    /// roles with elevated limits by name
+    pub roles: BTreeMap<String, Role>,
 
     /// flatten extensions setting to json::Value
     #[serde(flatten)]
@@ -185,11 +341,15 @@ pub struct Setting {
 
 impl PartialEq for Setting {
     fn eq(&self, other: &Self) -> bool {
-        self.information == other.information
+        self.include == other.include
+            && self.information == other.information
             && self.data == other.data
             && self.thread == other.thread
             && self.network == other.network
             && self.limitation == other.limitation
+            && self.extension == other.extension
+            && self.tenants == other.tenants
+            && self.roles == other.roles
             && self.extra == other.extra
     }
 }
@@ -249,6 +409,16 @@ impl SettingWrapper {
             .parent()
             .ok_or_else(|| Error::Message("failed to get config dir".to_owned()))?;
 
+        // watch the dirs of the included files, new files in the dirs are loaded too
+        let mut dirs = vec![dir.to_path_buf()];
+        for pattern in Setting::include_patterns(&file)? {
+            if let Some(dir) = pattern.parent().filter(|d| d.is_dir()) {
+                if !dirs.iter().any(|d| d == dir) {
+                    dirs.push(dir.to_path_buf());
+                }
+            }
+        }
+
         let mut watcher = RecommendedWatcher::new(
             move |result: Result<Event, notify::Error>| match result {
                 Ok(event) => {
@@ -257,7 +427,10 @@ impl SettingWrapper {
                     let is_modify = matches!(event.kind, EventKind::Modify(ModifyKind::Any));
                     #[cfg(not(target_os = "windows"))]
                     let is_modify = matches!(event.kind, EventKind::Modify(ModifyKind::Data(_)));
-                    if is_modify && event.paths.contains(&c_file) {
+                    let is_config =
+                        |files: &Vec<PathBuf>| event.paths.iter().any(|p| files.contains(p));
+                    let files = Setting::files(&c_file).unwrap_or_else(|_| vec![c_file.clone()]);
+                    if is_modify && is_config(&files) {
                         match c_setting.reload(&c_file, env_prefix.clone()) {
                             Ok(_) => {
                                 info!("Reload config success {:?}", c_file);
@@ -280,7 +453,9 @@ impl SettingWrapper {
             notify::Config::default(),
         )?;
 
-        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        for dir in dirs {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
         // save watcher
         setting.watcher = Some(Arc::new(watcher));
 
@@ -333,6 +508,35 @@ impl Setting {
             .and_then(|boxed| boxed.downcast_ref())
     }
 
+    /// The role of the pubkey, the first by name when it has several
+    pub fn role(&self, pubkey: &str) -> Option<(&String, &Role)> {
+        self.roles
+            .iter()
+            .find(|(_, role)| role.pubkeys.iter().any(|p| p == pubkey))
+    }
+
+    /// The limitation of the pubkey with the overrides of its role
+    pub fn limitation_of(&self, pubkey: Option<&str>) -> Limitation {
+        let mut limitation = self.limitation.clone();
+        if let Some((_, role)) = pubkey.and_then(|p| self.role(p)) {
+            if let Some(max) = role.max_subscriptions {
+                limitation.max_subscriptions = max;
+            }
+            if let Some(max) = role.max_message_length {
+                limitation.max_message_length = max;
+            }
+        }
+        limitation
+    }
+
+    /// The max message length of all roles, the websocket frame size before auth
+    pub fn max_message_length(&self) -> usize {
+        self.roles
+            .values()
+            .filter_map(|role| role.max_message_length)
+            .fold(self.limitation.max_message_length, usize::max)
+    }
+
     /// nip-11 information json
     pub fn render_information(&self) -> Result<String> {
         let info = &self.information;
@@ -357,28 +561,142 @@ impl Setting {
 
     /// read config from file and env
     pub fn read<P: AsRef<Path>>(file: P, env_prefix: Option<String>) -> Result<Self> {
+        let config = Self::build(file, env_prefix)?;
+        let mut setting: Setting = config.try_deserialize()?;
+        setting.correct();
+        Ok(setting)
+    }
+
+    /// read the raw config value from file and env, unknown keys are kept
+    pub fn read_value<P: AsRef<Path>>(file: P, env_prefix: Option<String>) -> Result<Value> {
+        let config = Self::build(file, env_prefix)?;
+        Ok(config.try_deserialize()?)
+    }
+
+    /// the config file and the included files in merge order
+    pub fn files<P: AsRef<Path>>(file: P) -> Result<Vec<PathBuf>> {
+        let mut files = vec![file.as_ref().to_path_buf()];
+        for pattern in Self::include_patterns(&file)? {
+            let pattern = pattern
+                .to_str()
+                .ok_or_else(|| Error::Message(format!("invalid include {:?}", pattern)))?;
+            let mut paths = glob::glob(pattern)
+                .map_err(|e| Error::Message(format!("invalid include {}: {}", pattern, e)))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::Message(e.to_string()))?;
+            paths.sort();
+            files.extend(paths);
+        }
+        Ok(files)
+    }
+
+    fn include_patterns<P: AsRef<Path>>(file: P) -> Result<Vec<PathBuf>> {
+        let file = file.as_ref();
+        let config = Config::builder()
+            .add_source(File::with_name(file.to_str().unwrap()))
+            .build()?;
+        let patterns = match config.get::<Vec<String>>("include") {
+            Ok(patterns) => patterns,
+            Err(ConfigError::NotFound(_)) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let dir = file.parent().unwrap_or(Path::new(""));
+        Ok(patterns.iter().map(|p| dir.join(p)).collect())
+    }
+
+    fn build<P: AsRef<Path>>(file: P, env_prefix: Option<String>) -> Result<Config> {
         let builder = Config::builder();
+        let files = Self::files(&file)?;
         let mut config = builder
             // Use serde default feature, ignore the following code
             // // use defaults
             // .add_source(Config::try_from(&Self::default())?)
             // override with file contents
             .add_source(File::with_name(file.as_ref().to_str().unwrap()));
+        for path in &files[1..] {
+            config = config.add_source(File::from(path.as_path()));
+        }
         if let Some(prefix) = env_prefix {
             config = config.add_source(Self::env_source(&prefix));
         }
+        Self::load_secrets(config.build()?)
+    }
 
-        let config = config.build()?;
-        let mut setting: Setting = config.try_deserialize()?;
-        setting.correct();
-        Ok(setting)
+    /// Set the `xxx` setting to the content of the file of the `xxx_file` setting, so secrets can stay out of the config.
+    /// The file name with a `credential:` prefix is read from the systemd `$CREDENTIALS_DIRECTORY`.
+    fn load_secrets(config: Config) -> Result<Config> {
+        fn secret_files(value: &Value, path: &str, files: &mut Vec<(String, String)>) {
+            match value {
+                Value::Object(map) => {
+                    let join = |key: &str| {
+                        if path.is_empty() {
+                            key.to_owned()
+                        } else {
+                            format!("{}.{}", path, key)
+                        }
+                    };
+                    for (key, val) in map {
+                        match (key.strip_suffix("_file"), val.as_str()) {
+                            (Some(name), Some(file)) if !name.is_empty() => {
+                                files.push((join(name), file.to_owned()))
+                            }
+                            _ => secret_files(val, &join(key), files),
+                        }
+                    }
+                }
+                Value::Array(list) => {
+                    for (i, val) in list.iter().enumerate() {
+                        secret_files(val, &format!("{}[{}]", path, i), files);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let value: Value = config.clone().try_deserialize()?;
+        let mut files = vec![];
+        secret_files(&value, "", &mut files);
+        if files.is_empty() {
+            return Ok(config);
+        }
+        let mut builder = Config::builder().add_source(config);
+        for (key, file) in files {
+            let path = match file.strip_prefix("credential:") {
+                Some(name) => {
+                    PathBuf::from(std::env::var("CREDENTIALS_DIRECTORY").map_err(|_| {
+                        Error::Message(format!("CREDENTIALS_DIRECTORY is not set for {}", key))
+                    })?)
+                    .join(name)
+                }
+                None => PathBuf::from(file),
+            };
+            let secret = fs::read_to_string(&path).map_err(|e| {
+                Error::Message(format!(
+                    "failed to read secret {} from {:?}: {}",
+                    key, path, e
+                ))
+            })?;
+            builder = builder.set_override(key, secret.trim_end_matches(['\r', '\n']))?;
+        }
+        Ok(builder.build()?)
     }
 
+    /// Both `PREFIX_SECTION__KEY` and `PREFIX__SECTION__KEY` override the `section.key` setting
     fn env_source(prefix: &str) -> Environment {
+        let double = format!("{}__", prefix);
+        let vars = std::env::vars()
+            .map(|(key, val)| match key.get(..double.len()) {
+                Some(p) if p.eq_ignore_ascii_case(&double) => {
+                    (format!("{}_{}", prefix, &key[double.len()..]), val)
+                }
+                _ => (key, val),
+            })
+            .collect();
         Environment::with_prefix(prefix)
             .try_parsing(true)
             .prefix_separator("_")
             .separator("__")
+            .source(Some(vars))
         // .list_separator(" ")
         // .with_list_parse_key("")
     }
@@ -443,6 +761,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn roles() -> Result<()> {
+        let setting = Setting::from_str(
+            r#"
+            [limitation]
+            max_subscriptions = 5
+            [roles.admin]
+            pubkeys = ["aa"]
+            max_subscriptions = 100
+            max_message_length = 1048576
+            unlimited_rate = true
+            [roles.trusted]
+            pubkeys = ["aa", "bb"]
+            quota = 0
+            "#,
+            FileFormat::Toml,
+        )?;
+        assert_eq!(setting.role("aa").unwrap().0, "admin");
+        assert_eq!(setting.role("bb").unwrap().1.quota, Some(0));
+        assert!(setting.role("cc").is_none());
+        assert_eq!(setting.limitation_of(Some("aa")).max_subscriptions, 100);
+        assert_eq!(setting.limitation_of(Some("bb")).max_subscriptions, 5);
+        assert_eq!(setting.limitation_of(None).max_message_length, 524288);
+        assert_eq!(setting.max_message_length(), 1048576);
+        Ok(())
+    }
+
     #[test]
     fn render() -> Result<()> {
         let mut def = Setting::default();
@@ -492,6 +837,12 @@ mod tests {
                 ("NOSTR_information__contact", Some("test")),
                 ("NOSTR_INFORMATION__PUBKEY", Some("test")),
                 ("NOSTR_NETWORK__PORT", Some("1")),
+                ("NOSTR__LIMITATION__MAX_LIMIT", Some("10")),
+                ("NOSTR__AUTH__ENABLED", Some("true")),
+                (
+                    "NOSTR__AUTH__REQ__IP_WHITELIST",
+                    Some("127.0.0.1 127.0.0.2"),
+                ),
             ],
             || {
                 let setting = Setting::read(&file, Some("NOSTR".to_owned())).unwrap();
@@ -500,8 +851,135 @@ mod tests {
                 assert_eq!(setting.information.contact, Some("test".to_string()));
                 assert_eq!(setting.information.pubkey, Some("test".to_string()));
                 assert_eq!(setting.network.port, 1);
+                assert_eq!(setting.limitation.max_limit, 10);
+                assert_eq!(setting.extra["auth"]["enabled"], json!(true));
+                assert_eq!(
+                    setting.extra["auth"]["req"]["ip_whitelist"],
+                    json!("127.0.0.1 127.0.0.2")
+                );
+
+                let setting = Setting::from_env("NOSTR".to_owned()).unwrap();
+                assert_eq!(setting.network.port, 1);
+                assert_eq!(setting.extra["auth"]["enabled"], json!(true));
             },
         );
+
+        fs::write(
+            &file,
+            r#"
+        [network]
+        prot = 1
+        [unknown]
+        name = "nostr"
+        "#,
+        )?;
+        let value = Setting::read_value(&file, None)?;
+        assert_eq!(value["network"]["prot"], json!(1));
+        assert_eq!(value["unknown"]["name"], json!("nostr"));
+        Ok(())
+    }
+
+    #[test]
+    fn include() -> Result<()> {
+        let dir = Builder::new()
+            .prefix("nostr-relay-config-test-include")
+            .tempdir()?;
+        let file = dir.path().join("rnostr.toml");
+        fs::create_dir(dir.path().join("conf.d"))?;
+        fs::write(
+            &file,
+            r#"
+        include = ["conf.d/*.toml"]
+        [information]
+        name = "nostr"
+        description = "main"
+        [network]
+        port = 1
+        "#,
+        )?;
+        fs::write(
+            dir.path().join("conf.d/b.toml"),
+            r#"
+        [network]
+        port = 3
+        "#,
+        )?;
+        fs::write(
+            dir.path().join("conf.d/a.toml"),
+            r#"
+        [information]
+        description = "a"
+        [network]
+        port = 2
+        [auth]
+        enabled = true
+        "#,
+        )?;
+
+        let files = Setting::files(&file)?;
+        assert_eq!(files.len(), 3);
+        assert!(files[1].ends_with("a.toml"));
+
+        let setting = Setting::read(&file, None)?;
+        assert_eq!(setting.information.name, "nostr");
+        assert_eq!(setting.information.description, "a");
+        assert_eq!(setting.network.port, 3);
+        assert_eq!(setting.extra["auth"]["enabled"], json!(true));
+        assert!(!setting.extra.contains_key("include"));
+
+        temp_env::with_var("NOSTR__NETWORK__PORT", Some("4"), || {
+            let setting = Setting::read(&file, Some("NOSTR".to_owned())).unwrap();
+            assert_eq!(setting.network.port, 4);
+        });
+
+        // watch included files
+        let setting = SettingWrapper::watch(&file, None, |_s| {})?;
+        fs::write(
+            dir.path().join("conf.d/c.toml"),
+            r#"
+        [network]
+        port = 5
+        "#,
+        )?;
+        sleep(Duration::from_secs(1));
+        assert_eq!(setting.read().network.port, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn secret() -> Result<()> {
+        let dir = Builder::new()
+            .prefix("nostr-relay-config-test-secret")
+            .tempdir()?;
+        let file = dir.path().join("rnostr.toml");
+        let secret = dir.path().join("metrics_auth");
+        fs::write(&secret, "auth_key\n")?;
+        fs::write(
+            &file,
+            format!(
+                r#"
+        [metrics]
+        enabled = true
+        auth_file = {:?}
+        [[rate_limiter.event]]
+        description_file = "credential:limit"
+        "#,
+                secret
+            ),
+        )?;
+        fs::write(dir.path().join("limit"), "limited")?;
+
+        temp_env::with_var("CREDENTIALS_DIRECTORY", Some(dir.path()), || {
+            let setting = Setting::read(&file, None).unwrap();
+            assert_eq!(setting.extra["metrics"]["auth"], json!("auth_key"));
+            assert_eq!(
+                setting.extra["rate_limiter"]["event"][0]["description"],
+                json!("limited")
+            );
+        });
+
+        // missing secret file
+        assert!(Setting::read(&file, None).is_err());
         Ok(())
     }
 
@@ -0,0 +1,131 @@
+use crate::{
+    message::{Control, Disconnect, EventStored, OutgoingMessage, ReadEventResult, Reload},
+    Server, SessionInfo,
+};
+use actix::prelude::*;
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_util::Stream;
+use nostr_db::Event;
+use serde_json::Value;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+};
+
+/// Message of the subscription created by [`crate::App::subscribe`]
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum StreamMessage {
+    /// a stored or live event matching the filters
+    Event(Event),
+    /// all stored events are sent, the next events are live
+    Eose,
+    /// notice from the relay, ie: the subscription is rejected
+    Notice(String),
+}
+
+impl StreamMessage {
+    fn parse(msg: &OutgoingMessage) -> Option<Self> {
+        let mut list: Vec<Value> = serde_json::from_str(&msg.0).ok()?;
+        match list.first()?.as_str()? {
+            "EVENT" => serde_json::from_value(list.get_mut(2)?.take())
+                .ok()
+                .map(Self::Event),
+            "EOSE" => Some(Self::Eose),
+            "NOTICE" | "CLOSED" => Some(Self::Notice(list.last()?.as_str()?.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+/// The session of a subscription without websocket, forwards the messages to the stream
+pub(crate) struct Listener {
+    tx: UnboundedSender<OutgoingMessage>,
+    pub(crate) backfill: Arc<AtomicUsize>,
+}
+
+impl Listener {
+    pub(crate) fn new(tx: UnboundedSender<OutgoingMessage>) -> Self {
+        Self {
+            tx,
+            backfill: Default::default(),
+        }
+    }
+}
+
+impl Actor for Listener {
+    type Context = Context<Self>;
+}
+
+impl Handler<OutgoingMessage> for Listener {
+    type Result = ();
+    fn handle(&mut self, msg: OutgoingMessage, ctx: &mut Self::Context) {
+        if self.tx.unbounded_send(msg).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Handler<ReadEventResult> for Listener {
+    type Result = ();
+    fn handle(&mut self, msg: ReadEventResult, ctx: &mut Self::Context) {
+        self.backfill.fetch_sub(1, Ordering::AcqRel);
+        if self.tx.unbounded_send(msg.msg).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Handler<Reload> for Listener {
+    type Result = ();
+    fn handle(&mut self, _: Reload, _: &mut Self::Context) {}
+}
+
+/// The listener is not a client session
+impl Handler<Control> for Listener {
+    type Result = Option<SessionInfo>;
+    fn handle(&mut self, _: Control, _: &mut Self::Context) -> Option<SessionInfo> {
+        None
+    }
+}
+
+impl Handler<EventStored> for Listener {
+    type Result = ();
+    fn handle(&mut self, _: EventStored, _: &mut Self::Context) {}
+}
+
+/// Stream of the stored and live events matching the filters, unsubscribe when dropped
+pub struct EventStream {
+    pub(crate) id: usize,
+    pub(crate) server: Addr<Server>,
+    pub(crate) rx: UnboundedReceiver<OutgoingMessage>,
+}
+
+impl Stream for EventStream {
+    type Item = StreamMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.rx).poll_next(cx) {
+                Poll::Ready(Some(msg)) => {
+                    if let Some(msg) = StreamMessage::parse(&msg) {
+                        return Poll::Ready(Some(msg));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        // remove the listener and the subscription
+        self.server.do_send(Disconnect { id: self.id });
+    }
+}
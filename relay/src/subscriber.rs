@@ -1,12 +1,48 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     rc::{Rc, Weak},
 };
 
 use crate::{message::*, setting::SettingWrapper};
 use actix::prelude::*;
+use metrics::{gauge, histogram, increment_counter};
 use nostr_db::{EventIndex, Filter};
 
+/// The ids, authors or tag values of a filter counted as a large list
+const LARGE_LIST: usize = 256;
+
+/// The filter has no ids, authors, kinds, tags or search, it scans all events and matches every new event
+pub fn unconstrained(filter: &Filter) -> bool {
+    filter.ids.is_empty()
+        && filter.authors.is_empty()
+        && filter.kinds.is_empty()
+        && filter.tags.is_empty()
+        && filter.search.is_none()
+}
+
+/// The shapes of the filter expensive to query or to match the new events, for the metrics
+pub fn expensive_shapes(filter: &Filter) -> Vec<&'static str> {
+    let mut shapes = vec![];
+    if filter.ids.is_empty() && filter.authors.is_empty() && filter.kinds.is_empty() {
+        if unconstrained(filter) {
+            // scans all events and matches every new event
+            shapes.push("unconstrained");
+        } else {
+            shapes.push("no_author_kind");
+        }
+    }
+    if filter.ids.len() > LARGE_LIST {
+        shapes.push("large_ids");
+    }
+    if filter.authors.len() > LARGE_LIST {
+        shapes.push("large_authors");
+    }
+    if filter.tags.values().map(|v| v.len()).sum::<usize>() > LARGE_LIST {
+        shapes.push("large_tags");
+    }
+    shapes
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 struct Key {
     session_id: usize,
@@ -42,6 +78,8 @@ pub struct SubscriberIndex {
     tags: HashMap<Vec<u8>, HashMap<Key, Weak<Filter>>>,
     kinds: HashMap<u16, HashMap<Key, Weak<Filter>>>,
     others: HashMap<Key, Weak<Filter>>,
+    /// the number of the subscriptions
+    len: usize,
 }
 
 impl SubscriberIndex {
@@ -156,6 +194,12 @@ impl SubscriberIndex {
         }
 
         if let Some(subs) = self.subscriptions.get(&session_id) {
+            // clients resend the same REQ, ie: on reconnecting
+            if subs.get(&sub_id).is_some_and(|old| {
+                old.len() == filters.len() && old.iter().zip(&filters).all(|(a, b)| **a == *b)
+            }) {
+                return Subscribed::Duplicate;
+            }
             if subs.len() >= limit {
                 return Subscribed::Overlimit;
             }
@@ -170,7 +214,9 @@ impl SubscriberIndex {
         let map = self.subscriptions.entry(session_id).or_default();
 
         // NIP01: overwrite the previous subscription
-        map.insert(sub_id, filters);
+        if map.insert(sub_id, filters).is_none() {
+            self.len += 1;
+        }
         Subscribed::Ok
     }
 
@@ -178,16 +224,27 @@ impl SubscriberIndex {
         self.uninstall_index(session_id, sub_id);
         if let Some(sub_id) = sub_id {
             if let Some(map) = self.subscriptions.get_mut(&session_id) {
-                map.remove(sub_id);
+                if map.remove(sub_id).is_some() {
+                    self.len -= 1;
+                }
                 if map.is_empty() {
                     self.subscriptions.remove(&session_id);
                 }
             }
-        } else {
-            self.subscriptions.remove(&session_id);
+        } else if let Some(map) = self.subscriptions.remove(&session_id) {
+            self.len -= map.len();
         }
     }
 
+    /// The number of the subscriptions of all sessions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     pub fn lookup(&self, event: &EventIndex, mut f: impl FnMut(&usize, &String)) {
         let mut dup = HashMap::new();
 
@@ -279,12 +336,30 @@ impl Actor for Subscriber {
 impl Handler<Subscribe> for Subscriber {
     type Result = Subscribed;
     fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Subscribed {
-        self.index.add(
+        let filters = msg.subscription.filters.len();
+        let shapes = msg
+            .subscription
+            .filters
+            .iter()
+            .flat_map(expensive_shapes)
+            .collect::<Vec<_>>();
+        let result = self.index.add(
             msg.id,
             msg.subscription.id,
             msg.subscription.filters,
-            self.setting.read().limitation.max_subscriptions,
-        )
+            msg.max_subscriptions
+                .unwrap_or_else(|| self.setting.read().limitation.max_subscriptions),
+        );
+        if result == Subscribed::Ok {
+            histogram!("nostr_relay_subscription_filters", filters as f64);
+            for shape in shapes {
+                increment_counter!("nostr_relay_expensive_filter_total", "shape" => shape);
+            }
+            gauge!("nostr_relay_subscriptions", self.index.len() as f64);
+        } else if result == Subscribed::Duplicate {
+            increment_counter!("nostr_relay_subscription_duplicate_total");
+        }
+        result
     }
 }
 
@@ -292,6 +367,7 @@ impl Handler<Unsubscribe> for Subscriber {
     type Result = ();
     fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) {
         self.index.remove(msg.id, msg.sub_id.as_ref());
+        gauge!("nostr_relay_subscriptions", self.index.len() as f64);
     }
 }
 
@@ -301,7 +377,14 @@ impl Handler<Dispatch> for Subscriber {
         let event = &msg.event;
         let index = event.index();
         let event_str = event.to_string();
+        let once = self.setting.read().data.fanout_once;
+        let mut sent = HashSet::new();
         self.index.lookup(index, |session_id, sub_id| {
+            // the other subscriptions of the session matching the event are skipped
+            if once && !sent.insert(*session_id) {
+                increment_counter!("nostr_relay_fanout_deduplicated_total");
+                return;
+            }
             self.addr.do_send(SubscribeResult {
                 id: *session_id,
                 msg: OutgoingMessage::event(sub_id, &event_str),
@@ -380,11 +463,29 @@ mod tests {
                     filters: vec![Filter {
                         ..Default::default()
                     }],
+                    resume: None,
                 },
+                max_subscriptions: None,
             })
             .await?;
         assert_eq!(res, Subscribed::Ok);
 
+        // duplicate
+        let res = subscriber
+            .send(Subscribe {
+                id: 0,
+                subscription: Subscription {
+                    id: 0.to_string(),
+                    filters: vec![Filter {
+                        ..Default::default()
+                    }],
+                    resume: None,
+                },
+                max_subscriptions: None,
+            })
+            .await?;
+        assert_eq!(res, Subscribed::Duplicate);
+
         // overwrite
         let res = subscriber
             .send(Subscribe {
@@ -392,9 +493,12 @@ mod tests {
                 subscription: Subscription {
                     id: 0.to_string(),
                     filters: vec![Filter {
+                        kinds: vec![1].into(),
                         ..Default::default()
                     }],
+                    resume: None,
                 },
+                max_subscriptions: None,
             })
             .await?;
         assert_eq!(res, Subscribed::Ok);
@@ -408,7 +512,9 @@ mod tests {
                         kinds: vec![1000].into(),
                         ..Default::default()
                     }],
+                    resume: None,
                 },
+                max_subscriptions: None,
             })
             .await?;
         assert_eq!(res, Subscribed::Ok);
@@ -422,7 +528,9 @@ mod tests {
                         kinds: vec![1000].into(),
                         ..Default::default()
                     }],
+                    resume: None,
                 },
+                max_subscriptions: None,
             })
             .await?;
         assert_eq!(res, Subscribed::InvalidIdLength);
@@ -437,7 +545,9 @@ mod tests {
                         kinds: vec![1000].into(),
                         ..Default::default()
                     }],
+                    resume: None,
                 },
+                max_subscriptions: None,
             })
             .await?;
         assert_eq!(res, Subscribed::InvalidIdLength);
@@ -457,6 +567,43 @@ mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn fanout_once() -> Result<()> {
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        let mut setting = Setting::default();
+        setting.data.fanout_once = true;
+        let subscriber = Subscriber::new(receiver.recipient(), setting.into()).start();
+        for (id, sub_id) in [(0, "all"), (0, "notes"), (1, "notes")] {
+            subscriber
+                .send(Subscribe {
+                    id,
+                    subscription: Subscription {
+                        id: sub_id.to_owned(),
+                        filters: vec![Filter {
+                            kinds: vec![1].into(),
+                            ..Default::default()
+                        }],
+                        resume: None,
+                    },
+                    max_subscriptions: None,
+                })
+                .await?;
+        }
+        let event = Event::from_str(
+            r#"{"content":"","created_at":1680690006,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[]}"#,
+        )?;
+        subscriber.send(Dispatch { id: 0, event }).await?;
+
+        sleep(Duration::from_millis(100)).await;
+        // once per session
+        let mut ids = messages.read().iter().map(|m| m.id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+        Ok(())
+    }
+
     fn lookup(index: &SubscriberIndex, event: &str) -> Result<Vec<(usize, String)>> {
         let event = Event::from_str(event)?;
         let mut result = vec![];
@@ -593,7 +740,7 @@ mod tests {
             )?],
             5,
         );
-        // override
+        // override with the same filters is kept
         let ok = index.add(
             4,
             "tag2".to_owned(),
@@ -608,7 +755,7 @@ mod tests {
             )?],
             5,
         );
-        assert_eq!(ok, Subscribed::Ok);
+        assert_eq!(ok, Subscribed::Duplicate);
         assert_eq!(index.others.len(), 2);
         assert_eq!(index.ids.len(), 2);
         assert_eq!(index.authors.len(), 2);
@@ -693,4 +840,35 @@ mod tests {
         assert_eq!(index.tags.len(), 0);
         Ok(())
     }
+    #[test]
+    fn shapes_and_len() -> Result<()> {
+        let shapes = |s: &str| Filter::from_str(s).map(|f| expensive_shapes(&f));
+        assert_eq!(shapes("{}")?, vec!["unconstrained"]);
+        assert_eq!(shapes(r##"{"#t": ["nostr"]}"##)?, vec!["no_author_kind"]);
+        assert_eq!(shapes(r#"{"kinds": [1]}"#)?, Vec::<&str>::new());
+        let authors = (0..300)
+            .map(|i| format!("\"{:064x}\"", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            shapes(&format!(r#"{{"authors": [{}]}}"#, authors))?,
+            vec!["large_authors"]
+        );
+
+        let mut index = SubscriberIndex::default();
+        index.add(1, "a".to_owned(), vec![Filter::from_str("{}")?], 5);
+        index.add(1, "b".to_owned(), vec![Filter::from_str("{}")?], 5);
+        // overwrite
+        index.add(1, "b".to_owned(), vec![Filter::from_str("{}")?], 5);
+        index.add(2, "a".to_owned(), vec![Filter::from_str("{}")?], 5);
+        assert_eq!(index.len(), 3);
+        index.remove(1, Some(&"c".to_owned()));
+        assert_eq!(index.len(), 3);
+        index.remove(2, Some(&"a".to_owned()));
+        assert_eq!(index.len(), 2);
+        index.remove(1, None);
+        assert_eq!(index.len(), 0);
+        assert!(index.is_empty());
+        Ok(())
+    }
 }
@@ -1,26 +1,111 @@
-use crate::{message::*, Result};
+use crate::{message::*, Result, ResultCache, SeenIds};
 use actix::prelude::*;
-use metrics::{histogram, increment_counter};
-use nostr_db::{now, CheckEventResult, Db};
+use metrics::{gauge, histogram, increment_counter};
+use nostr_db::{now, CheckEventResult, Db, Event};
 use std::{
-    sync::Arc,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-/// Single-threaded write events, delete expired events
-/// Batch write can improve tps
+// Single-threaded write events, delete expired events
+// Batch write can improve tps
 
 const WRITE_INTERVAL_MS: u64 = 100;
 const DEL_INTERVAL_SECONDS: u64 = 60;
 const EPHEMERAL_EXPIRED_SECONDS: u64 = 60 * 5;
+/// The committed events kept in the journal before it is rewritten
+const JOURNAL_COMPACT_MIN: usize = 1000;
+/// The writes of an acknowledged event before it is given up
+const JOURNAL_MAX_RETRIES: usize = 3;
 
 pub struct Writer {
     pub db: Arc<Db>,
     pub addr: Recipient<WriteEventResult>,
     pub events: Vec<WriteEvent>,
+    /// the events of the priority sessions, written first
+    pub priority_events: Vec<WriteEvent>,
+    /// max events written in a transaction, 0 no limit
+    pub write_batch: usize,
     pub write_interval_ms: u64,
     pub del_interval_seconds: u64,
+    /// the cached results changed by the written events are removed
+    pub cache: Option<Arc<ResultCache>>,
+    /// the events waiting for the next transactions, shared with the server
+    pub pending: Arc<AtomicUsize>,
+    /// the events acknowledged before the commit
+    pub journal: Option<Journal>,
+    /// the failed writes of the acknowledged events, they are queued again
+    pub retries: HashMap<String, usize>,
+    /// the ids of the stored events are added after the commit
+    pub seen: Option<Arc<SeenIds>>,
+}
+
+/// Append only file of the events acknowledged and not committed yet, one json event per line
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// the events in the file, some are committed already
+    len: usize,
+}
+
+impl Journal {
+    /// Open the journal, return the events of the file not known to be committed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<Event>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut events = vec![];
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                match line?.parse::<Event>() {
+                    Ok(event) => events.push(event),
+                    // the last line is partly written when the process was killed
+                    Err(err) => warn!(error = err.to_string(), "skip the journal line"),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((
+            Self {
+                path,
+                file,
+                len: events.len(),
+            },
+            events,
+        ))
+    }
+
+    fn append(&mut self, event: &Event) -> Result<()> {
+        self.file.write_all(format!("{}\n", event).as_bytes())?;
+        // on the disk before the event is acknowledged
+        self.file.sync_data()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Replace the file with the events not committed
+    fn compact<'a>(&mut self, events: impl Iterator<Item = &'a Event>) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        let mut len = 0;
+        for event in events {
+            writeln!(file, "{}", event)?;
+            len += 1;
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        drop(file);
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = len;
+        Ok(())
+    }
 }
 
 impl Writer {
@@ -29,16 +114,138 @@ impl Writer {
             db,
             addr,
             events: Vec::new(),
+            priority_events: Vec::new(),
+            write_batch: 0,
             write_interval_ms: WRITE_INTERVAL_MS,
             del_interval_seconds: DEL_INTERVAL_SECONDS,
+            cache: None,
+            pending: Default::default(),
+            journal: None,
+            retries: HashMap::new(),
+            seen: None,
         }
     }
 
+    /// Acknowledge the events when they are appended to the journal,
+    /// the events left by the last run are queued again
+    pub fn open_journal<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let (journal, events) = Journal::open(path)?;
+        if !events.is_empty() {
+            info!("replay {} events of the intake journal", events.len());
+        }
+        for event in events {
+            // no session waits for the result
+            self.queue(WriteEvent {
+                id: 0,
+                event,
+                priority: false,
+                ahead: true,
+            });
+        }
+        self.journal = Some(journal);
+        Ok(())
+    }
+
+    fn queue(&mut self, msg: WriteEvent) {
+        if msg.priority {
+            self.priority_events.push(msg);
+        } else {
+            self.events.push(msg);
+        }
+        self.update_pending();
+    }
+
+    fn update_pending(&self) {
+        let pending = self.events.len() + self.priority_events.len();
+        self.pending.store(pending, Ordering::Relaxed);
+        gauge!("nostr_relay_write_pending", pending as f64);
+    }
+
+    /// Save the event in the journal and acknowledge it, false if it waits for the commit
+    fn append(&mut self, msg: &WriteEvent) -> bool {
+        let Some(journal) = &mut self.journal else {
+            return false;
+        };
+        match journal.append(&msg.event) {
+            Ok(()) => {
+                self.addr.do_send(WriteEventResult::Queued {
+                    id: msg.id,
+                    event_id: msg.event.id_str(),
+                });
+                true
+            }
+            Err(err) => {
+                error!(error = err.to_string(), "append the intake journal error");
+                false
+            }
+        }
+    }
+
+    /// Queue the acknowledged event again when it is not stored, the session is not waiting for it
+    fn retry(&mut self, event: Event) {
+        let eid = event.id_str();
+        let retries = self.retries.entry(eid.clone()).or_default();
+        *retries += 1;
+        if *retries > JOURNAL_MAX_RETRIES {
+            self.retries.remove(&eid);
+            error!(
+                event_id = eid,
+                "drop the acknowledged event after the failed writes"
+            );
+            return;
+        }
+        warn!(event_id = eid, "write the acknowledged event again");
+        self.queue(WriteEvent {
+            id: 0,
+            event,
+            priority: false,
+            ahead: true,
+        });
+    }
+
+    /// Remove the committed events from the journal, the file is rewritten when most of it is committed
+    fn trim_journal(&mut self) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            let pending = self
+                .events
+                .iter()
+                .chain(self.priority_events.iter())
+                .filter(|e| e.ahead);
+            let num = pending.clone().count();
+            if (num == 0 && journal.len > 0) || journal.len > num * 2 + JOURNAL_COMPACT_MIN {
+                journal.compact(pending.map(|e| &e.event))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The events of the next transaction, the priority events first, the oldest of the others
+    /// when the batch is full
+    fn batch(&mut self) -> Vec<WriteEvent> {
+        let max = if self.write_batch == 0 {
+            usize::MAX
+        } else {
+            self.write_batch
+        };
+        let num = self.priority_events.len().min(max);
+        let mut batch = self.priority_events.drain(..num).collect::<Vec<_>>();
+        let num = self.events.len().min(max - batch.len());
+        // written in the reverse order of the batch
+        batch.splice(0..0, self.events.drain(..num));
+        self.update_pending();
+        batch
+    }
+
     pub fn write(&mut self) -> Result<()> {
-        if !self.events.is_empty() {
+        if !self.events.is_empty() || !self.priority_events.is_empty() {
             let start = Instant::now();
-            let mut writer = self.db.writer()?;
-            while let Some(event) = self.events.pop() {
+            let db = Arc::clone(&self.db);
+            let mut writer = db.writer()?;
+            let mut written = vec![];
+            // sent when committed, the readers of other threads see the events
+            let mut results = vec![];
+            let mut events = self.batch();
+            while let Some(event) = events.pop() {
                 let res = self.db.put(&mut writer, &event.event);
                 debug!(
                     "write event: {} {} {:?}",
@@ -51,17 +258,31 @@ impl Writer {
                     Ok(result) => {
                         if let CheckEventResult::Ok(_num) = result {
                             increment_counter!("nostr_relay_new_event");
+                            if self.cache.is_some() {
+                                written.push(event.event.clone());
+                            }
                         }
-                        self.addr.do_send(WriteEventResult::Write {
+                        // in the order of the puts, the ids deleted by the later events are removed by the db
+                        self.see(&event.event, &result);
+                        results.push(WriteEventResult::Write {
                             id: event.id,
                             event: event.event,
                             result,
+                            acked: event.ahead,
                         });
                     }
                     Err(err) => {
-                        error!(error = err.to_string(), "write event error");
+                        error!(
+                            error = err.to_string(),
+                            event_id = event.event.id_str(),
+                            "write event error"
+                        );
+                        if event.ahead {
+                            self.retry(event.event);
+                            continue;
+                        }
                         let eid = event.event.id_str();
-                        self.addr.do_send(WriteEventResult::Message {
+                        results.push(WriteEventResult::Message {
                             id: event.id,
                             event: event.event,
                             msg: OutgoingMessage::ok(&eid, false, "write event error"),
@@ -69,12 +290,81 @@ impl Writer {
                     }
                 }
             }
-            self.db.commit(writer)?;
+            let res = match &self.cache {
+                Some(cache) if !written.is_empty() => {
+                    cache.begin_write();
+                    let res = self.db.commit(writer);
+                    cache.invalidate(&written);
+                    res
+                }
+                _ => self.db.commit(writer),
+            };
+            if let (Err(_), Some(seen)) = (&res, &self.seen) {
+                // not stored
+                for result in &results {
+                    if let WriteEventResult::Write { event, .. } = result {
+                        seen.remove(event.id());
+                    }
+                }
+            }
+            for result in results {
+                let result = match (&res, result) {
+                    (Ok(_), result) => {
+                        if let WriteEventResult::Write {
+                            event, acked: true, ..
+                        } = &result
+                        {
+                            if !self.retries.is_empty() {
+                                self.retries.remove(&event.id_str());
+                            }
+                        }
+                        result
+                    }
+                    (
+                        Err(_),
+                        WriteEventResult::Write {
+                            event, acked: true, ..
+                        },
+                    ) => {
+                        self.retry(event);
+                        continue;
+                    }
+                    (Err(_), WriteEventResult::Write { id, event, .. }) => {
+                        let eid = event.id_str();
+                        WriteEventResult::Message {
+                            id,
+                            event,
+                            msg: OutgoingMessage::ok(&eid, false, "write event error"),
+                        }
+                    }
+                    (Err(_), result) => result,
+                };
+                self.addr.do_send(result);
+            }
+            res?;
             histogram!("nostr_relay_db_write", start.elapsed());
+            self.trim_journal()?;
         }
         Ok(())
     }
 
+    /// remember the stored event, the events deleted by it are forgotten by the delete hook of the db,
+    /// the ephemeral and expiring events are removed later so they are not remembered
+    fn see(&self, event: &Event, result: &CheckEventResult) {
+        let Some(seen) = &self.seen else {
+            return;
+        };
+        if event.index().expiration().is_some() || event.index().is_ephemeral() {
+            return;
+        }
+        if matches!(
+            result,
+            CheckEventResult::Ok(_) | CheckEventResult::Duplicate
+        ) {
+            seen.insert(event.id());
+        }
+    }
+
     pub fn do_write(&mut self) {
         if let Err(err) = self.write() {
             error!(error = err.to_string(), "write events error");
@@ -91,8 +381,7 @@ impl Writer {
             let id = id?;
             ids.push(id);
         }
-        self.db.batch_del(ids)?;
-        Ok(())
+        self.del(ids)
     }
 
     pub fn del_ephemeral(&self) -> Result<()> {
@@ -105,7 +394,21 @@ impl Writer {
             let id = id?;
             ids.push(id);
         }
-        self.db.batch_del(ids)?;
+        self.del(ids)
+    }
+
+    fn del(&self, ids: Vec<Vec<u8>>) -> Result<()> {
+        if !ids.is_empty() {
+            match &self.cache {
+                Some(cache) => {
+                    cache.begin_write();
+                    let res = self.db.batch_del(ids);
+                    cache.clear();
+                    res?;
+                }
+                None => self.db.batch_del(ids)?,
+            }
+        }
         Ok(())
     }
 
@@ -142,14 +445,22 @@ impl Actor for Writer {
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         info!("Actor writer stopped");
         // save event when stopped
-        self.do_write();
+        while !self.events.is_empty() || !self.priority_events.is_empty() {
+            if let Err(err) = self.write() {
+                error!(error = err.to_string(), "write events error");
+                break;
+            }
+        }
     }
 }
 
 impl Handler<WriteEvent> for Writer {
     type Result = ();
-    fn handle(&mut self, msg: WriteEvent, _: &mut Self::Context) {
-        self.events.push(msg);
+    fn handle(&mut self, mut msg: WriteEvent, _: &mut Self::Context) {
+        if msg.ahead {
+            msg.ahead = self.append(&msg);
+        }
+        self.queue(msg);
     }
 }
 
@@ -161,7 +472,10 @@ mod tests {
     use crate::temp_data_path;
     use actix_rt::time::sleep;
     use anyhow::Result;
-    use nostr_db::{Event, Filter};
+    use nostr_db::{
+        secp256k1::{rand::thread_rng, KeyPair},
+        Event, Filter,
+    };
     use parking_lot::RwLock;
 
     #[derive(Default)]
@@ -198,9 +512,11 @@ mod tests {
         let receiver = receiver.start();
         let addr = receiver.recipient();
 
+        let seen = Arc::new(SeenIds::new(10));
         let mut writer = Writer::new(Arc::clone(&db), addr.clone());
         writer.del_interval_seconds = 1;
         writer.write_interval_ms = 100;
+        writer.seen = Some(Arc::clone(&seen));
         let writer = writer.start();
 
         for i in 0..4 {
@@ -208,6 +524,8 @@ mod tests {
                 .send(WriteEvent {
                     id: i,
                     event: event.clone(),
+                    priority: false,
+                    ahead: false,
                 })
                 .await?;
         }
@@ -227,6 +545,8 @@ mod tests {
                   "tags": [["t", "nostr"]]
                 }
               "#)?,
+              priority: false,
+              ahead: false,
           })
           .await?;
         // ephemeral
@@ -244,6 +564,8 @@ mod tests {
                   "tags": [["t", "nostr"]]
                 }}
               "#, now()))?,
+              priority: false,
+              ahead: false,
           })
           .await?;
 
@@ -262,12 +584,19 @@ mod tests {
                   "tags": [["t", "nostr"], ["expiration", "10"]]
                 }
               "#)?,
+              priority: false,
+              ahead: false,
           })
           .await?;
 
         sleep(Duration::from_millis(200)).await;
-        let r = messages.read();
-        assert_eq!(r.len(), 7);
+        assert_eq!(messages.read().len(), 7);
+        assert!(seen.contains(event.id()));
+        // the ephemeral and expiring events
+        for id in ["d71", "d72", "d73"] {
+            let id = hex::decode(format!("{}{}", &event.id_str()[..61], id))?;
+            assert!(!seen.contains(&id));
+        }
         {
             let txn = db.reader()?;
             let iter = db.iter::<Event, _>(
@@ -293,4 +622,104 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn priority() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer-priority")?)?);
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient());
+        writer.write_batch = 2;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = |i: u64, priority: bool| {
+            Ok::<_, anyhow::Error>(WriteEvent {
+                id: i as usize,
+                event: Event::create(&key_pair, 1000 + i, 1, vec![], "".to_owned())?,
+                priority,
+                ahead: false,
+            })
+        };
+        for i in 0..3 {
+            writer.events.push(event(i, false)?);
+        }
+        writer.priority_events.push(event(3, true)?);
+        let stored = |time: u64| -> Result<bool> {
+            let reader = db.reader()?;
+            let filter = Filter {
+                since: Some(time),
+                until: Some(time),
+                ..Default::default()
+            };
+            let count = db.iter::<Event, _>(&reader, &filter)?.count();
+            Ok(count == 1)
+        };
+
+        // the priority event and the oldest of the others
+        writer.write()?;
+        assert!(stored(1003)? && stored(1000)?);
+        assert!(!stored(1001)? && !stored(1002)?);
+        assert_eq!(writer.events.len(), 2);
+        writer.write()?;
+        assert!(stored(1001)? && stored(1002)?);
+        assert!(writer.events.is_empty());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn journal() -> Result<()> {
+        let path = temp_data_path("writer-journal")?;
+        let db = Arc::new(Db::open(path.path().join("events"))?);
+        let journal = path.path().join("intake");
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let receiver = receiver.start();
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+
+        {
+            let mut writer = Writer::new(Arc::clone(&db), receiver.clone().recipient());
+            writer.open_journal(&journal)?;
+            for i in 0..3 {
+                let mut msg = WriteEvent {
+                    id: i,
+                    event: Event::create(&key_pair, 1000 + i as u64, 1, vec![], "".to_owned())?,
+                    priority: false,
+                    ahead: true,
+                };
+                msg.ahead = writer.append(&msg);
+                writer.queue(msg);
+            }
+            assert_eq!(writer.pending.load(Ordering::Relaxed), 3);
+            // stopped before the commit
+        }
+        sleep(Duration::from_millis(100)).await;
+        {
+            let r = messages.read();
+            assert_eq!(r.len(), 3);
+            assert!(matches!(r[0], WriteEventResult::Queued { id: 0, .. }));
+        }
+
+        // replayed at starting
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient());
+        writer.open_journal(&journal)?;
+        assert_eq!(writer.events.len(), 3);
+        writer.write()?;
+        let reader = db.reader()?;
+        assert_eq!(db.iter::<Event, _>(&reader, &Filter::default())?.count(), 3);
+        assert_eq!(fs::metadata(&journal)?.len(), 0);
+        sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            messages.read()[3],
+            WriteEventResult::Write { acked: true, .. }
+        ));
+
+        // the failed event is queued again until it is given up
+        let event = Event::create(&key_pair, 2000, 1, vec![], "".to_owned())?;
+        for _ in 0..JOURNAL_MAX_RETRIES {
+            writer.retry(event.clone());
+        }
+        assert_eq!(writer.events.len(), JOURNAL_MAX_RETRIES);
+        writer.retry(event);
+        assert_eq!(writer.events.len(), JOURNAL_MAX_RETRIES);
+        assert!(writer.retries.is_empty());
+        Ok(())
+    }
 }
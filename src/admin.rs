@@ -0,0 +1,481 @@
+use crate::{stats::parse_duration, Error, Result};
+use clap::{Parser, Subcommand};
+use nostr_db::now;
+use nostr_db::secp256k1::{KeyPair, SECP256K1};
+use nostr_extensions::{management::CONTENT_TYPE, nip98::auth_header};
+use serde_json::{json, Value};
+use std::{fs, path::PathBuf, time::Duration};
+
+/// admin options
+#[derive(Debug, Clone, Parser)]
+pub struct AdminOpts {
+    /// Relay url, websocket urls are converted to http
+    #[arg(long, value_name = "URL", default_value = "http://127.0.0.1:8080")]
+    pub url: String,
+
+    /// hex secret key of a pubkey in the management admin_pubkeys setting
+    #[arg(
+        long,
+        value_name = "HEX",
+        env = "RNOSTR_ADMIN_KEY",
+        hide_env_values = true
+    )]
+    pub key: Option<String>,
+
+    /// read the hex secret key from the file
+    #[arg(long, value_name = "PATH", conflicts_with = "key")]
+    pub key_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: AdminCommands,
+}
+
+/// [NIP-86](https://nips.be/86) relay management commands
+#[derive(Debug, Clone, Subcommand)]
+pub enum AdminCommands {
+    /// Ban a pubkey, its new events and the messages of the sessions authenticated with it are rejected
+    BanPubkey {
+        #[arg(value_name = "PUBKEY")]
+        pubkey: String,
+        #[arg(long)]
+        reason: Option<String>,
+        /// lift the ban after the duration, ie: 7d
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        expires: Option<Duration>,
+    },
+    /// Ban an ip, the messages of its sessions are rejected
+    BanIp {
+        #[arg(value_name = "IP")]
+        ip: String,
+        #[arg(long)]
+        reason: Option<String>,
+        /// lift the ban after the duration, ie: 7d
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        expires: Option<Duration>,
+    },
+    /// Remove a pubkey from the ban list
+    Unban {
+        /// the pubkey, or the event id with --event, or the ip with --ip
+        #[arg(value_name = "PUBKEY")]
+        pubkey: String,
+        /// allow a deleted event to be published again
+        #[arg(long, value_name = "BOOL", conflicts_with = "ip")]
+        event: bool,
+        /// unban an ip
+        #[arg(long, value_name = "BOOL")]
+        ip: bool,
+    },
+    /// Delete an event and reject it when it is published again
+    DeleteEvent {
+        #[arg(value_name = "ID")]
+        id: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Remove an event for good, its tombstone rejects it when it is published, mirrored or imported again
+    Takedown {
+        #[arg(value_name = "ID")]
+        id: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// List the tombstones of the events taken down
+    Tombstones,
+    /// List banned pubkeys and events
+    ListBans,
+    /// Send a NOTICE to all connected clients, or the authed or unauthed only
+    Notice {
+        #[arg(value_name = "MESSAGE")]
+        message: String,
+        /// the sessions authenticated by NIP-42 only
+        #[arg(long, value_name = "BOOL", conflicts_with = "unauthed")]
+        authed: bool,
+        /// the sessions not authenticated only
+        #[arg(long, value_name = "BOOL")]
+        unauthed: bool,
+    },
+    /// List the connected sessions with their counters and subscriptions
+    Sessions {
+        /// only the sessions of a session id, an ip or an authenticated pubkey, or "authed" or "unauthed"
+        #[arg(value_name = "FILTER")]
+        filter: Option<String>,
+    },
+    /// Disconnect the sessions of a session id, an ip or an authenticated pubkey
+    Kick {
+        #[arg(value_name = "ID|IP|PUBKEY")]
+        session: String,
+        /// send a NOTICE before disconnecting
+        #[arg(long, value_name = "MESSAGE")]
+        notice: Option<String>,
+    },
+    /// List extensions in the running order
+    ListExtensions,
+    /// Enable an extension at runtime
+    Enable {
+        #[arg(value_name = "EXTENSION")]
+        name: String,
+    },
+    /// Disable an extension at runtime, the connections are kept
+    Disable {
+        #[arg(value_name = "EXTENSION")]
+        name: String,
+    },
+    /// Print or set the maintenance mode, the events are rejected or accepted and dropped while the REQs are served
+    Maintenance {
+        #[arg(value_name = "MODE", value_parser = ["off", "reject", "drop"])]
+        mode: Option<String>,
+    },
+    /// List the deletion progress of the NIP-62 requests to vanish
+    ListVanished,
+    /// List the reputation scores of the ips and pubkeys, the highest first
+    Reputation {
+        /// only the scores at least it
+        #[arg(long, default_value_t = 0.0)]
+        min: f64,
+    },
+    /// Mint invite codes admitting new pubkeys, printed one per line
+    Invite {
+        #[arg(long, default_value_t = 1)]
+        count: u64,
+        /// the times a code can be redeemed, unlimited when 0
+        #[arg(long, default_value_t = 1)]
+        uses: u32,
+        /// the code expires after the duration, ie: 30d
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        expires: Option<Duration>,
+        #[arg(long)]
+        note: Option<String>,
+        /// mint the codes on behalf of the member, the redeemers are recorded as invited by it
+        #[arg(long, value_name = "PUBKEY")]
+        by: Option<String>,
+    },
+    /// List the invite codes
+    ListInvites,
+    /// Remove an invite code, the admitted pubkeys are kept
+    RevokeInvite {
+        #[arg(value_name = "CODE")]
+        code: String,
+    },
+    /// List the pubkeys admitted by the invite codes
+    ListMembers,
+    /// Print the members by the inviters
+    InviteTree,
+    /// Remove an admitted pubkey and the codes minted by it, its new events are rejected
+    RemoveMember {
+        #[arg(value_name = "PUBKEY")]
+        pubkey: String,
+        /// remove everyone invited by it too
+        #[arg(long)]
+        cascade: bool,
+    },
+}
+
+pub fn admin_opts(opts: AdminOpts) -> anyhow::Result<()> {
+    let secret = match (&opts.key, &opts.key_file) {
+        (Some(key), _) => key.clone(),
+        (None, Some(path)) => fs::read_to_string(path)?,
+        (None, None) => {
+            return Err(Error::Message(
+                "a secret key is required, use --key, --key-file or RNOSTR_ADMIN_KEY".to_owned(),
+            )
+            .into())
+        }
+    };
+    let key_pair = KeyPair::from_seckey_str(SECP256K1, secret.trim())
+        .map_err(|e| Error::Message(format!("invalid secret key: {}", e)))?;
+
+    let call = |method: &str, params: Value| admin(&opts.url, &key_pair, method, params);
+    match opts.command {
+        AdminCommands::BanPubkey {
+            pubkey,
+            reason,
+            expires,
+        } => {
+            let params = json!([pubkey, reason.unwrap_or_default(), expires_at(expires)]);
+            call("banpubkey", params)?;
+            println!("banned {}", pubkey);
+        }
+        AdminCommands::BanIp {
+            ip,
+            reason,
+            expires,
+        } => {
+            call(
+                "blockip",
+                json!([ip, reason.unwrap_or_default(), expires_at(expires)]),
+            )?;
+            println!("banned {}", ip);
+        }
+        AdminCommands::Unban { pubkey, event, ip } => {
+            let method = if event {
+                "allowevent"
+            } else if ip {
+                "unblockip"
+            } else {
+                "unbanpubkey"
+            };
+            if call(method, json!([pubkey]))? == json!(true) {
+                println!("unbanned {}", pubkey);
+            } else {
+                println!("{} is not banned", pubkey);
+            }
+        }
+        AdminCommands::DeleteEvent { id, reason } => {
+            call("banevent", json!([id, reason.unwrap_or_default()]))?;
+            println!("deleted {}", id);
+        }
+        AdminCommands::Takedown { id, reason } => {
+            let removed = call("takedownevent", json!([id, reason.unwrap_or_default()]))?;
+            if removed == json!(true) {
+                println!("taken down {}", id);
+            } else {
+                println!("tombstone of {}, the event is not stored", id);
+            }
+        }
+        AdminCommands::Tombstones => {
+            let list = call("listtombstones", json!([]))?;
+            for item in list.as_array().into_iter().flatten() {
+                println!(
+                    "{} {} taken down at {}",
+                    item["id"].as_str().unwrap_or_default(),
+                    item["reason"].as_str().unwrap_or_default(),
+                    item["created_at"]
+                );
+            }
+        }
+        AdminCommands::ListBans => {
+            for (kind, key, method) in [
+                ("pubkey", "pubkey", "listbannedpubkeys"),
+                ("ip", "ip", "listblockedips"),
+                ("event", "id", "listbannedevents"),
+            ] {
+                let list = call(method, json!([]))?;
+                for item in list.as_array().into_iter().flatten() {
+                    let id = item[key].as_str().unwrap_or_default();
+                    let reason = item["reason"].as_str().unwrap_or_default();
+                    match item["expires_at"].as_u64() {
+                        Some(time) => println!("{} {} {} (expires at {})", kind, id, reason, time),
+                        None => println!("{} {} {}", kind, id, reason),
+                    }
+                }
+            }
+        }
+        AdminCommands::Notice {
+            message,
+            authed,
+            unauthed,
+        } => {
+            let sessions = if authed {
+                "authed"
+            } else if unauthed {
+                "unauthed"
+            } else {
+                "all"
+            };
+            let num = call("notice", json!([message, sessions]))?;
+            println!("sent to {} sessions", num);
+        }
+        AdminCommands::Sessions { filter } => {
+            let list = call("listsessions", json!([filter]))?;
+            for item in list.as_array().into_iter().flatten() {
+                println!(
+                    "{} {} {} connected at {}, received {} sent {} accepted {} rejected {}",
+                    item["id"],
+                    item["ip"].as_str().unwrap_or_default(),
+                    item["pubkey"].as_str().unwrap_or("-"),
+                    item["connected_at"],
+                    item["messages_received"],
+                    item["messages_sent"],
+                    item["events_accepted"],
+                    item["events_rejected"],
+                );
+                for sub in item["subscriptions"].as_array().into_iter().flatten() {
+                    println!(
+                        "  {} {}",
+                        sub["id"].as_str().unwrap_or_default(),
+                        sub["filters"]
+                    );
+                }
+            }
+        }
+        AdminCommands::Kick { session, notice } => {
+            let num = call("kicksession", json!([session, notice]))?;
+            println!("kicked {} sessions", num);
+        }
+        AdminCommands::ListExtensions => {
+            let list = call("listextensions", json!([]))?;
+            for item in list.as_array().into_iter().flatten() {
+                let name = item["name"].as_str().unwrap_or_default();
+                let state = if item["enabled"] == json!(true) {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                println!("{} {}", name, state);
+            }
+        }
+        AdminCommands::Enable { name } => {
+            call("enableextension", json!([name]))?;
+            println!("enabled {}", name);
+        }
+        AdminCommands::Disable { name } => {
+            call("disableextension", json!([name]))?;
+            println!("disabled {}", name);
+        }
+        AdminCommands::Maintenance { mode } => {
+            let mode = call("maintenance", json!([mode]))?;
+            println!("maintenance {}", mode.as_str().unwrap_or_default());
+        }
+        AdminCommands::ListVanished => {
+            let list = call("listvanished", json!([]))?;
+            for item in list.as_array().into_iter().flatten() {
+                let pubkey = item["pubkey"].as_str().unwrap_or_default();
+                let state = match item["error"].as_str() {
+                    Some(error) => error,
+                    None if item["done"] == json!(true) => "done",
+                    None => "deleting",
+                };
+                println!(
+                    "{} until {} deleted {} {}",
+                    pubkey, item["until"], item["deleted"], state
+                );
+            }
+        }
+        AdminCommands::Reputation { min } => {
+            let list = call("listreputations", json!([min]))?;
+            for item in list.as_array().into_iter().flatten() {
+                let key = item["key"].as_str().unwrap_or_default();
+                let score = item["score"].as_f64().unwrap_or_default();
+                match item["banned_until"].as_u64().filter(|t| *t > now()) {
+                    Some(until) => println!("{} {:.2} banned until {}", key, score, until),
+                    None => println!("{} {:.2}", key, score),
+                }
+            }
+        }
+        AdminCommands::Invite {
+            count,
+            uses,
+            expires,
+            note,
+            by,
+        } => {
+            let params = json!([
+                count,
+                note.unwrap_or_default(),
+                expires_at(expires),
+                uses,
+                by.unwrap_or_default()
+            ]);
+            let codes = call("createinvites", params)?;
+            for code in codes.as_array().into_iter().flatten() {
+                println!("{}", code.as_str().unwrap_or_default());
+            }
+        }
+        AdminCommands::ListInvites => {
+            let list = call("listinvites", json!([]))?;
+            for item in list.as_array().into_iter().flatten() {
+                let code = item["code"].as_str().unwrap_or_default();
+                let uses = match item["max_uses"].as_u64().unwrap_or_default() {
+                    0 => format!("{}", item["uses"]),
+                    max => format!("{}/{}", item["uses"], max),
+                };
+                let note = item["note"].as_str().unwrap_or_default();
+                match item["expires_at"].as_u64().filter(|t| *t > 0) {
+                    Some(time) => println!("{} used {} {} (expires at {})", code, uses, note, time),
+                    None => println!("{} used {} {}", code, uses, note),
+                }
+            }
+        }
+        AdminCommands::RevokeInvite { code } => {
+            if call("revokeinvite", json!([code]))? == json!(true) {
+                println!("revoked {}", code);
+            } else {
+                println!("{} is not found", code);
+            }
+        }
+        AdminCommands::ListMembers => {
+            let list = call("listmembers", json!([]))?;
+            for item in list.as_array().into_iter().flatten() {
+                let line = format!(
+                    "{} {} admitted at {}",
+                    item["pubkey"].as_str().unwrap_or_default(),
+                    item["code"].as_str().unwrap_or_default(),
+                    item["admitted_at"]
+                );
+                match item["invited_by"].as_str().filter(|s| !s.is_empty()) {
+                    Some(by) => println!("{} invited by {}", line, by),
+                    None => println!("{}", line),
+                }
+            }
+        }
+        AdminCommands::InviteTree => {
+            let tree = call("invitetree", json!([]))?;
+            print_tree(&tree, 0);
+        }
+        AdminCommands::RemoveMember { pubkey, cascade } => {
+            let removed = call("removemember", json!([pubkey, cascade]))?;
+            let removed = removed.as_array().cloned().unwrap_or_default();
+            if removed.is_empty() {
+                println!("{} is not a member", pubkey);
+            }
+            for p in removed {
+                println!("removed {}", p.as_str().unwrap_or_default());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// print the invite nodes indented by the depth
+fn print_tree(nodes: &Value, depth: usize) {
+    for node in nodes.as_array().into_iter().flatten() {
+        println!(
+            "{}{} {}",
+            "  ".repeat(depth),
+            node["pubkey"].as_str().unwrap_or_default(),
+            node["code"].as_str().unwrap_or_default()
+        );
+        print_tree(&node["invited"], depth + 1);
+    }
+}
+
+/// the unix timestamp after the duration, 0 never expires
+fn expires_at(expires: Option<Duration>) -> u64 {
+    expires.map_or(0, |d| now() + d.as_secs())
+}
+
+/// Call the management api of the relay with a [NIP-98](https://nips.be/98) signed request, returns the result.
+pub fn admin(url: &str, key_pair: &KeyPair, method: &str, params: Value) -> Result<Value> {
+    let url = if let Some(u) = url.strip_prefix("ws://") {
+        format!("http://{}", u)
+    } else if let Some(u) = url.strip_prefix("wss://") {
+        format!("https://{}", u)
+    } else {
+        url.to_owned()
+    };
+    let body = json!({ "method": method, "params": params }).to_string();
+    let auth = auth_header(key_pair, &url, "POST", body.as_bytes())?;
+
+    let res = actix_rt::System::new().block_on(async move {
+        let mut res = awc::Client::default()
+            .post(&url)
+            .insert_header(("Content-Type", CONTENT_TYPE))
+            .insert_header(("Authorization", auth))
+            .send_body(body)
+            .await
+            .map_err(|e| Error::Message(e.to_string()))?;
+        let status = res.status();
+        let body = res
+            .body()
+            .await
+            .map_err(|e| Error::Message(e.to_string()))?;
+        Ok::<_, Error>((status, body))
+    });
+    let (status, body) = res?;
+    let res: Value = serde_json::from_slice(&body)
+        .map_err(|_| Error::Message(format!("{} {}", status, String::from_utf8_lossy(&body))))?;
+    if let Some(error) = res["error"].as_str() {
+        return Err(Error::Message(error.to_owned()));
+    }
+    Ok(res["result"].clone())
+}
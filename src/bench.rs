@@ -1,9 +1,17 @@
-use crate::Result;
-use clap::Parser;
-use nostr_db::{Db, Filter, Stats};
+use crate::{Error, Result};
+use clap::{Parser, ValueEnum};
+use hdrhistogram::Histogram;
+use nostr_db::{now, CheckEventResult, Db, Event, Filter, Stats};
+use nostr_relay::{message::OutgoingMessage, SubscriberIndex};
+use rand::{distributions::Alphanumeric, rngs::ThreadRng, Rng};
 use rayon::prelude::*;
+use serde::Serialize;
 use std::{
+    fmt::{self, Display},
+    hint::black_box,
     path::PathBuf,
+    str::FromStr,
+    thread,
     time::{Duration, Instant},
 };
 
@@ -14,19 +22,130 @@ pub struct BenchOpts {
     #[arg(value_name = "PATH")]
     pub path: PathBuf,
 
-    /// [NIP-01](https://nips.be/1) Filter
+    /// [NIP-01](https://nips.be/1) Filter, only used by the query profile
     #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
     pub filter: Filter,
 
-    /// only bench the count method
+    /// only bench the count method, only used by the query profile
     #[arg(long, value_name = "BOOL")]
     pub count: bool,
+
+    /// workload profile
+    #[arg(short = 'p', long, value_enum, default_value_t = Profile::Query)]
+    pub profile: Profile,
+
+    /// number of worker threads, default is the number of cpus
+    #[arg(short = 't', long, value_name = "NUM")]
+    pub threads: Option<usize>,
+
+    /// bench duration in seconds
+    #[arg(short = 'd', long, value_name = "SECONDS", default_value_t = 5)]
+    pub duration: u64,
+
+    /// content size of generated events in bytes, a fixed size "N" or a range "MIN-MAX"
+    #[arg(long, value_name = "SIZE", default_value = "20-1000")]
+    pub event_size: EventSize,
+
+    /// distribution of the content size in the range
+    #[arg(long, value_enum, default_value_t = SizeDist::Uniform)]
+    pub size_dist: SizeDist,
+
+    /// number of events written in one transaction
+    #[arg(long, value_name = "NUM", default_value_t = 100)]
+    pub batch: usize,
+
+    /// number of live subscriptions the written events are dispatched to in the mixed profile
+    #[arg(long, value_name = "NUM", default_value_t = 1000)]
+    pub subscriptions: usize,
+
+    /// write the generated events to the database at PATH, the signatures of them are zeroed.
+    /// The write and mixed profiles write to a copy of the database in a temporary directory by default,
+    /// don't use it with the database of a running relay
+    #[arg(long)]
+    pub in_place: bool,
+
+    /// output format of the write, read and mixed profile result
+    #[arg(short = 'o', long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+}
+
+/// Workload profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Repeat the filter of the --filter option
+    Query,
+    /// Write generated events
+    Write,
+    /// Query with a mix of common client filters
+    Read,
+    /// Write generated events and dispatch them to live subscriptions, while querying with the read mix
+    Mixed,
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(value) = self.to_possible_value() {
+            f.write_str(value.get_name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Distribution of the generated content size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SizeDist {
+    /// Every size in the range is equally likely
+    Uniform,
+    /// Most events are small, with a long tail of large ones
+    Skewed,
+}
+
+/// Content size range of generated events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventSize {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl FromStr for EventSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| s.trim().parse::<usize>().map_err(|e| e.to_string());
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => {
+                let size = parse(s)?;
+                (size, size)
+            }
+        };
+        if min > max {
+            return Err(format!("min size {} is greater than max size {}", min, max));
+        }
+        Ok(Self { min, max })
+    }
+}
+
+impl EventSize {
+    fn sample(&self, dist: SizeDist, rng: &mut ThreadRng) -> usize {
+        let r: f64 = rng.gen();
+        let r = match dist {
+            SizeDist::Uniform => r,
+            SizeDist::Skewed => r * r * r,
+        };
+        self.min + ((self.max - self.min) as f64 * r) as usize
+    }
 }
 
 pub fn bench_opts(mut opts: BenchOpts) -> anyhow::Result<u64> {
-    opts.filter.build_words();
-    let count = bench(&opts.path, &opts.filter, opts.count)?;
-    Ok(count)
+    if opts.profile == Profile::Query {
+        opts.filter.build_words();
+        let count = bench(&opts.path, &opts.filter, opts.count)?;
+        return Ok(count);
+    }
+    let report = bench_workload(&opts)?;
+    report.print(opts.output)?;
+    Ok(report.ops.iter().map(|op| op.count).sum())
 }
 
 pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
@@ -106,6 +225,424 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
     Ok(res.0)
 }
 
+/// Authors and event ids that generated events and filters refer to
+struct Pool {
+    authors: Vec<[u8; 32]>,
+    ids: Vec<[u8; 32]>,
+}
+
+impl Pool {
+    const SIZE: usize = 1000;
+
+    /// Load from the latest events in the database, and fill up with random keys
+    fn load(db: &Db) -> Result<Self> {
+        let mut authors = vec![];
+        let mut ids = vec![];
+        let filter = Filter::from_str(&format!(r#"{{"limit":{}}}"#, Self::SIZE))?;
+        let reader = db.reader()?;
+        let iter = db.iter::<Event, _>(&reader, &filter)?;
+        for event in iter {
+            let event = event?;
+            authors.push(*event.pubkey());
+            ids.push(*event.id());
+        }
+        authors.sort();
+        authors.dedup();
+
+        let mut rng = rand::thread_rng();
+        while authors.len() < Self::SIZE {
+            authors.push(rng.gen());
+        }
+        while ids.len() < Self::SIZE {
+            ids.push(rng.gen());
+        }
+        Ok(Self { authors, ids })
+    }
+
+    /// Active authors are picked more often
+    fn author(&self, rng: &mut ThreadRng) -> &[u8; 32] {
+        let r: f64 = rng.gen();
+        &self.authors[((r * r) * self.authors.len() as f64) as usize]
+    }
+
+    fn id(&self, rng: &mut ThreadRng) -> &[u8; 32] {
+        &self.ids[rng.gen_range(0..self.ids.len())]
+    }
+
+    fn event(&self, opts: &BenchOpts, rng: &mut ThreadRng) -> Result<Event> {
+        let kind = match rng.gen_range(0..100) {
+            0..=69 => 1,
+            70..=84 => 7,
+            85..=89 => 6,
+            90..=94 => 0,
+            _ => 3,
+        };
+        let mut tags = vec![];
+        if kind != 0 && rng.gen_bool(0.5) {
+            tags.push(vec!["e".to_owned(), hex::encode(self.id(rng))]);
+            tags.push(vec!["p".to_owned(), hex::encode(self.author(rng))]);
+        }
+        let size = opts.event_size.sample(opts.size_dist, rng);
+        let content = rng
+            .sample_iter(&Alphanumeric)
+            .take(size)
+            .map(char::from)
+            .collect();
+        Ok(Event::new(
+            rng.gen(),
+            *self.author(rng),
+            now(),
+            kind,
+            tags,
+            content,
+            [0; 64],
+        )?)
+    }
+
+    /// A filter from the mix of common client requests
+    fn filter(&self, rng: &mut ThreadRng) -> Result<Filter> {
+        let json = match rng.gen_range(0..100) {
+            // home timeline
+            0..=34 => {
+                let authors = (0..10)
+                    .map(|_| format!(r#""{}""#, hex::encode(self.author(rng))))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(r#"{{"authors":[{}],"kinds":[1,6],"limit":50}}"#, authors)
+            }
+            // thread replies and reactions
+            35..=54 => format!(
+                r##"{{"#e":["{}"],"kinds":[1,7],"limit":500}}"##,
+                hex::encode(self.id(rng))
+            ),
+            // profile metadata
+            55..=69 => format!(
+                r#"{{"authors":["{}"],"kinds":[0],"limit":1}}"#,
+                hex::encode(self.author(rng))
+            ),
+            // notifications
+            70..=84 => format!(
+                r##"{{"#p":["{}"],"limit":50}}"##,
+                hex::encode(self.author(rng))
+            ),
+            // global feed
+            85..=94 => r#"{"kinds":[1],"limit":100}"#.to_owned(),
+            // events by id
+            _ => {
+                let ids = (0..5)
+                    .map(|_| format!(r#""{}""#, hex::encode(self.id(rng))))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(r#"{{"ids":[{}]}}"#, ids)
+            }
+        };
+        Ok(Filter::from_str(&json)?)
+    }
+}
+
+/// Counters of one worker
+struct Counter {
+    /// EVENTs acked or REQs answered, one latency sample of each
+    ops: u64,
+    /// events stored or returned
+    events: u64,
+    matches: u64,
+    /// latency in microseconds
+    latency: Histogram<u64>,
+}
+
+impl Counter {
+    fn new() -> Self {
+        Self {
+            ops: 0,
+            events: 0,
+            matches: 0,
+            // up to one hour
+            latency: Histogram::new_with_bounds(1, 3_600_000_000, 3)
+                .expect("valid histogram bounds"),
+        }
+    }
+
+    /// record the latency of the ops
+    fn record(&mut self, elapsed: Duration, ops: u64) {
+        self.latency
+            .saturating_record_n(elapsed.as_micros() as u64, ops);
+        self.ops += ops;
+    }
+
+    fn merge(&mut self, other: Counter) {
+        self.ops += other.ops;
+        self.events += other.events;
+        self.matches += other.matches;
+        self.latency
+            .add(other.latency)
+            .expect("histograms of the same bounds");
+    }
+
+    fn report(&self, op: &'static str, elapsed: &Duration) -> OpReport {
+        let secs = elapsed.as_secs_f64();
+        let quantile = |q: f64| self.latency.value_at_quantile(q);
+        OpReport {
+            op,
+            count: self.ops,
+            ops_per_sec: self.ops as f64 / secs,
+            events: self.events,
+            events_per_sec: self.events as f64 / secs,
+            mean_us: self.latency.mean(),
+            p50_us: quantile(0.5),
+            p95_us: quantile(0.95),
+            p99_us: quantile(0.99),
+            max_us: self.latency.max(),
+        }
+    }
+}
+
+/// Output format of the bench result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+/// Result of one kind of operation
+#[derive(Debug, Clone, Serialize)]
+pub struct OpReport {
+    /// "event" for EVENT ack latency, "req" for REQ to EOSE latency
+    pub op: &'static str,
+    /// the EVENTs acked or the REQs answered, the latency is of each of them
+    pub count: u64,
+    pub ops_per_sec: f64,
+    /// the events stored or returned
+    pub events: u64,
+    pub events_per_sec: f64,
+    pub mean_us: f64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Result of a workload bench
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub profile: Profile,
+    pub writers: usize,
+    pub readers: usize,
+    pub duration: f64,
+    pub subscriptions: usize,
+    pub matched: u64,
+    pub ops: Vec<OpReport>,
+}
+
+impl Report {
+    const CSV_HEADER: &'static str = "profile,writers,readers,duration,op,count,ops_per_sec,events,events_per_sec,mean_us,p50_us,p95_us,p99_us,max_us";
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Text => {
+                println!(
+                    "Profile: {}, writers: {}, readers: {}, duration: {:.1}s",
+                    self.profile, self.writers, self.readers, self.duration
+                );
+                for op in &self.ops {
+                    println!(
+                        "{}: {}, {}, events: {}, {}",
+                        op.op,
+                        op.count,
+                        fmt_rate(op.ops_per_sec),
+                        op.events,
+                        fmt_rate(op.events_per_sec),
+                    );
+                    println!(
+                        "Latency: mean {:.0}µs, p50 {}µs, p95 {}µs, p99 {}µs, max {}µs",
+                        op.mean_us, op.p50_us, op.p95_us, op.p99_us, op.max_us
+                    );
+                }
+                if self.subscriptions > 0 {
+                    println!(
+                        "Subscriptions: {}, matched: {}, {}",
+                        self.subscriptions,
+                        self.matched,
+                        fmt_rate(self.matched as f64 / self.duration)
+                    );
+                }
+            }
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string(self)?);
+            }
+            OutputFormat::Csv => {
+                println!("{}", Self::CSV_HEADER);
+                for op in &self.ops {
+                    println!(
+                        "{},{},{},{:.3},{},{},{:.1},{},{:.1},{:.1},{},{},{},{}",
+                        self.profile,
+                        self.writers,
+                        self.readers,
+                        self.duration,
+                        op.op,
+                        op.count,
+                        op.ops_per_sec,
+                        op.events,
+                        op.events_per_sec,
+                        op.mean_us,
+                        op.p50_us,
+                        op.p95_us,
+                        op.p99_us,
+                        op.max_us
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The subscriptions of a generated session
+const SESSION_SUBSCRIPTIONS: usize = 10;
+
+fn write_worker(
+    db: &Db,
+    pool: &Pool,
+    subscriptions: &[Filter],
+    opts: &BenchOpts,
+    end: Instant,
+) -> Result<Counter> {
+    let mut rng = rand::thread_rng();
+    let mut counter = Counter::new();
+    // the subscriptions of the sessions indexed as the relay subscriber
+    let mut index = SubscriberIndex::default();
+    for (i, filter) in subscriptions.iter().enumerate() {
+        index.add(
+            i / SESSION_SUBSCRIPTIONS,
+            i.to_string(),
+            vec![filter.clone()],
+            SESSION_SUBSCRIPTIONS,
+        );
+    }
+    while Instant::now() < end {
+        let events = (0..opts.batch)
+            .map(|_| pool.event(opts, &mut rng))
+            .collect::<Result<Vec<_>>>()?;
+        let now = Instant::now();
+        // in one transaction as the relay writer
+        let mut writer = db.writer()?;
+        for event in &events {
+            if let CheckEventResult::Ok(_) = db.put(&mut writer, event)? {
+                counter.events += 1;
+            }
+        }
+        db.commit(writer)?;
+        // every event in the batch is acked after the commit
+        counter.record(now.elapsed(), events.len() as u64);
+        // dispatch the stored events to the subscriptions
+        if !index.is_empty() {
+            for event in &events {
+                let json = event.to_string();
+                index.lookup(event.index(), |_, sub_id| {
+                    black_box(OutgoingMessage::event(sub_id, &json));
+                    counter.matches += 1;
+                });
+            }
+        }
+    }
+    Ok(counter)
+}
+
+fn read_worker(db: &Db, pool: &Pool, end: Instant) -> Result<Counter> {
+    let mut rng = rand::thread_rng();
+    let mut counter = Counter::new();
+    while Instant::now() < end {
+        let filter = pool.filter(&mut rng)?;
+        let now = Instant::now();
+        let reader = db.reader()?;
+        let iter = db.iter::<String, _>(&reader, &filter)?;
+        for event in iter {
+            let _json: String = event?;
+            counter.events += 1;
+        }
+        counter.record(now.elapsed(), 1);
+    }
+    Ok(counter)
+}
+
+/// Bench the write, read or mixed profile
+pub fn bench_workload(opts: &BenchOpts) -> Result<Report> {
+    let source = Db::open(&opts.path)?;
+    source.check_schema()?;
+    // the generated events are not signed, they are written to a copy unless it's in place
+    let copy = if opts.profile != Profile::Read && !opts.in_place {
+        Some(tempfile::Builder::new().prefix("rnostr-bench").tempdir()?)
+    } else {
+        None
+    };
+    let db = match &copy {
+        Some(dir) => {
+            source.backup(dir.path())?;
+            drop(source);
+            Db::open(dir.path())?
+        }
+        None => source,
+    };
+    let pool = Pool::load(&db)?;
+    let threads = opts
+        .threads
+        .unwrap_or_else(rayon::current_num_threads)
+        .max(1);
+    let (writers, readers) = match opts.profile {
+        Profile::Write => (threads, 0),
+        Profile::Read => (0, threads),
+        // lmdb has a single writer, the rest of the threads read
+        _ => (1, threads.saturating_sub(1).max(1)),
+    };
+    let subscriptions = if opts.profile == Profile::Mixed {
+        let mut rng = rand::thread_rng();
+        (0..opts.subscriptions)
+            .map(|_| pool.filter(&mut rng))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        vec![]
+    };
+
+    let start = Instant::now();
+    let end = start + Duration::from_secs(opts.duration);
+    let (write, read) = thread::scope(|s| {
+        let write = (0..writers)
+            .map(|_| s.spawn(|| write_worker(&db, &pool, &subscriptions, opts, end)))
+            .collect::<Vec<_>>();
+        let read = (0..readers)
+            .map(|_| s.spawn(|| read_worker(&db, &pool, end)))
+            .collect::<Vec<_>>();
+        let join = |handles: Vec<thread::ScopedJoinHandle<Result<Counter>>>| {
+            let mut total = Counter::new();
+            for handle in handles {
+                total.merge(handle.join().expect("bench worker panicked")?);
+            }
+            Ok::<_, Error>(total)
+        };
+        (join(write), join(read))
+    });
+    let (write, read) = (write?, read?);
+    let elapsed = start.elapsed();
+
+    let mut ops = vec![];
+    if writers > 0 {
+        ops.push(write.report("event", &elapsed));
+    }
+    if readers > 0 {
+        ops.push(read.report("req", &elapsed));
+    }
+    Ok(Report {
+        profile: opts.profile,
+        writers,
+        readers,
+        duration: elapsed.as_secs_f64(),
+        subscriptions: subscriptions.len(),
+        matched: write.matches,
+        ops,
+    })
+}
+
 pub fn fmt_num(count: f64) -> String {
     if count < 1_000.0 {
         format!("{:.1}", count)
@@ -119,6 +656,100 @@ pub fn fmt_num(count: f64) -> String {
 }
 
 pub fn fmt_per_sec(count: u64, dur: &Duration) -> String {
-    let count = (count as f64) / (dur.as_nanos() as f64) * 1_000_000_000.0;
-    format!("{}/s", fmt_num(count))
+    fmt_rate((count as f64) / (dur.as_nanos() as f64) * 1_000_000_000.0)
+}
+
+pub fn fmt_rate(per_sec: f64) -> String {
+    format!("{}/s", fmt_num(per_sec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::path::Path;
+
+    fn opts(path: &Path, args: &[&str]) -> BenchOpts {
+        let mut list = vec!["bench", path.to_str().unwrap(), "-d", "1", "-t", "2"];
+        list.extend(args);
+        BenchOpts::parse_from(list)
+    }
+
+    fn count(path: &Path) -> Result<u64> {
+        let db = Db::open(path)?;
+        let reader = db.reader()?;
+        let iter = db.iter::<Vec<u8>, _>(&reader, &Filter::default())?;
+        Ok(iter.size()?.0)
+    }
+
+    #[test]
+    fn event_size() {
+        assert_eq!(
+            EventSize::from_str("10").unwrap(),
+            EventSize { min: 10, max: 10 }
+        );
+        assert_eq!(
+            EventSize::from_str("10-20").unwrap(),
+            EventSize { min: 10, max: 20 }
+        );
+        assert!(EventSize::from_str("20-10").is_err());
+        let mut rng = rand::thread_rng();
+        let size = EventSize { min: 10, max: 20 };
+        for dist in [SizeDist::Uniform, SizeDist::Skewed] {
+            assert!((10..=20).contains(&size.sample(dist, &mut rng)));
+        }
+    }
+
+    #[test]
+    fn counter() {
+        let mut counter = Counter::new();
+        counter.record(Duration::from_micros(100), 10);
+        counter.record(Duration::from_micros(200), 1);
+        let mut other = Counter::new();
+        other.record(Duration::from_micros(300), 1);
+        counter.merge(other);
+        let report = counter.report("event", &Duration::from_secs(2));
+        assert_eq!(report.count, 12);
+        assert_eq!(report.count, counter.latency.len());
+        assert_eq!(report.ops_per_sec, 6.0);
+        assert_eq!(report.p50_us, 100);
+        assert_eq!(report.max_us, 300);
+    }
+
+    #[test]
+    fn write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        Db::open(dir.path())?;
+
+        // the copy is written
+        let report = bench_workload(&opts(dir.path(), &["-p", "write", "--batch", "10"]))?;
+        assert_eq!(report.writers, 2);
+        // every event of the batches
+        assert!(report.ops[0].count > 0);
+        assert_eq!(report.ops[0].count % 10, 0);
+        assert!(report.ops[0].events <= report.ops[0].count);
+        assert_eq!(count(dir.path())?, 0);
+
+        let report = bench_workload(&opts(dir.path(), &["-p", "write", "--in-place"]))?;
+        // the replaceable events are replaced
+        let stored = count(dir.path())?;
+        assert!(stored > 0 && stored <= report.ops[0].events);
+        Ok(())
+    }
+
+    #[test]
+    fn mixed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        Db::open(dir.path())?;
+        let report = bench_workload(&opts(
+            dir.path(),
+            &["-p", "mixed", "--subscriptions", "100"],
+        ))?;
+        assert_eq!((report.writers, report.readers), (1, 1));
+        assert_eq!(report.subscriptions, 100);
+        assert!(report.matched > 0);
+        assert_eq!(report.ops.len(), 2);
+        assert_eq!(count(dir.path())?, 0);
+        Ok(())
+    }
 }
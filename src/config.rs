@@ -0,0 +1,1063 @@
+use crate::{Error, Result};
+use clap::{Parser, Subcommand};
+#[cfg(feature = "blossom")]
+use nostr_extensions::blossom::BlossomSetting;
+#[cfg(feature = "cluster")]
+use nostr_extensions::cluster::ClusterSetting;
+#[cfg(any(feature = "firehose-nats", feature = "firehose-kafka"))]
+use nostr_extensions::firehose::FirehoseSetting;
+#[cfg(feature = "graphql")]
+use nostr_extensions::graphql::GraphqlSetting;
+#[cfg(feature = "grpc")]
+use nostr_extensions::grpc::GrpcSetting;
+#[cfg(feature = "mqtt")]
+use nostr_extensions::mqtt::MqttSetting;
+use nostr_extensions::{
+    audit::AuditSetting,
+    auth::{AuthSetting, Permission},
+    broadcast::BroadcastSetting,
+    clickhouse::ClickhouseSetting,
+    count::CountSetting,
+    exclude::ExcludeSetting,
+    groups::GroupsSetting,
+    invite::InviteSetting,
+    maintenance::MaintenanceSetting,
+    management::ManagementSetting,
+    metrics::MetricsSetting,
+    mirror::MirrorSetting,
+    negentropy::NegentropySetting,
+    onboarding::OnboardingSetting,
+    pow::PowSetting,
+    rate_limiter::RatelimiterSetting,
+    replication::{ReplicationSetting, Role},
+    reputation::ReputationSetting,
+    resume::ResumeSetting,
+    scheduler::SchedulerSetting,
+    search::SearchSetting,
+    validation::ValidationSetting,
+    vanish::VanishSetting,
+    webhooks::WebhooksSetting,
+};
+use nostr_relay::{
+    setting::{Data, ExtensionSetting, Information, Limitation, Network, Tenant, Thread},
+    Setting,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+/// The default config, all options are documented in the comments
+pub const DEFAULT_CONFIG: &str = include_str!("../rnostr.example.toml");
+
+/// Known keys of each config table, the tables of an array share the same path
+const KEYS: &[(&str, &[&str])] = &[
+    (
+        "",
+        &[
+            "include",
+            "information",
+            "data",
+            "thread",
+            "network",
+            "limitation",
+            "extension",
+            "metrics",
+            "auth",
+            "rate_limiter",
+            "count",
+            "search",
+            "management",
+            "broadcast",
+            "mirror",
+            "cluster",
+            "replication",
+            "negentropy",
+            "groups",
+            "blossom",
+            "audit",
+            "vanish",
+            "reputation",
+            "pow",
+            "resume",
+            "exclude",
+            "scheduler",
+            "webhooks",
+            "mqtt",
+            "firehose",
+            "clickhouse",
+            "grpc",
+            "graphql",
+            "invite",
+            "onboarding",
+            "maintenance",
+            "validation",
+            "tenants",
+            "roles",
+        ],
+    ),
+    (
+        "information",
+        &["name", "description", "pubkey", "contact", "software"],
+    ),
+    (
+        "data",
+        &[
+            "path",
+            "db_query_timeout",
+            "db_scan_budget",
+            "result_cache",
+            "result_cache_size",
+            "result_cache_events",
+            "backfill_chunk",
+            "backfill_buffer",
+            "backfill_timeout",
+            "priority",
+            "write_batch",
+            "write_queue",
+            "write_ahead",
+            "fanout_once",
+            "seen_ids",
+        ],
+    ),
+    ("thread", &["http", "reader", "reading"]),
+    (
+        "network",
+        &[
+            "host",
+            "port",
+            "heartbeat_timeout",
+            "heartbeat_interval",
+            "real_ip_header",
+            "hash_ip",
+            "ip_hash_key",
+            "index_redirect_to",
+            "proxy",
+        ],
+    ),
+    (
+        "limitation",
+        &[
+            "max_message_length",
+            "max_subscriptions",
+            "max_filters",
+            "max_limit",
+            "max_subid_length",
+            "min_prefix",
+            "max_event_tags",
+            "max_event_time_older_than_now",
+            "max_event_time_newer_than_now",
+            "reject_broad_filters",
+        ],
+    ),
+    ("extension", &["order", "disabled", "timeout"]),
+    (
+        "metrics",
+        &["enabled", "auth", "admin_pubkeys", "top_talkers"],
+    ),
+    (
+        "metrics.top_talkers",
+        &["enabled", "window", "top", "max_keys"],
+    ),
+    ("auth", &["enabled", "req", "event"]),
+    ("auth.req", PERMISSION_KEYS),
+    ("auth.event", PERMISSION_KEYS),
+    ("rate_limiter", &["enabled", "event", "clear_interval"]),
+    (
+        "rate_limiter.event",
+        &[
+            "name",
+            "description",
+            "period",
+            "limit",
+            "kinds",
+            "ip_whitelist",
+        ],
+    ),
+    ("count", &["enabled", "cache_size", "cache_ttl"]),
+    (
+        "search",
+        &[
+            "enabled",
+            "kinds",
+            "max_content_length",
+            "tokenizer",
+            "patterns",
+            "ranking",
+        ],
+    ),
+    ("search.tokenizer", &["cjk", "fold_diacritics", "emoji"]),
+    (
+        "search.patterns",
+        &[
+            "prefix",
+            "fuzzy",
+            "min_len",
+            "max_distance",
+            "fuzzy_prefix_len",
+            "max_patterns",
+            "max_expansions",
+            "max_scan",
+        ],
+    ),
+    ("search.ranking", &["enabled", "candidates", "half_life"]),
+    ("management", &["enabled", "admin_pubkeys"]),
+    (
+        "broadcast",
+        &["enabled", "relays", "queue_size", "max_backoff"],
+    ),
+    ("broadcast.relays", &["url", "filters"]),
+    (
+        "mirror",
+        &["enabled", "relays", "outbox", "max_backoff", "cursor_path"],
+    ),
+    ("mirror.relays", &["url", "filters", "trusted"]),
+    (
+        "mirror.outbox",
+        &["pubkeys", "discovery", "kinds", "max_relays", "refresh"],
+    ),
+    (
+        "cluster",
+        &["enabled", "redis", "channel", "store", "max_backoff"],
+    ),
+    (
+        "replication",
+        &[
+            "enabled",
+            "role",
+            "token",
+            "primary",
+            "cursor_path",
+            "max_backoff",
+        ],
+    ),
+    ("grpc", &["enabled", "listen", "token"]),
+    (
+        "graphql",
+        &["enabled", "token", "max_limit", "max_depth", "max_events"],
+    ),
+    ("invite", &["enabled", "tag", "member_invites"]),
+    ("maintenance", &["enabled", "drop", "message"]),
+    (
+        "onboarding",
+        &[
+            "enabled",
+            "nip05",
+            "grandfather",
+            "nip05_url",
+            "timeout",
+            "cache_ttl",
+        ],
+    ),
+    (
+        "negentropy",
+        &[
+            "enabled",
+            "max_items",
+            "frame_size_limit",
+            "interval",
+            "peers",
+        ],
+    ),
+    ("negentropy.peers", &["url", "filters", "direction"]),
+    (
+        "groups",
+        &["enabled", "secret_key", "creators", "creator_role", "roles"],
+    ),
+    (
+        "blossom",
+        &[
+            "enabled",
+            "path",
+            "s3",
+            "url",
+            "uploaders",
+            "max_size",
+            "quota",
+        ],
+    ),
+    (
+        "blossom.s3",
+        &["endpoint", "region", "bucket", "access_key", "secret_key"],
+    ),
+    ("audit", &["enabled", "path", "accepted", "retention"]),
+    ("vanish", &["enabled", "urls", "batch"]),
+    (
+        "reputation",
+        &[
+            "enabled",
+            "weights",
+            "half_life",
+            "limit_score",
+            "limited_events",
+            "ban_score",
+            "ban_duration",
+            "flush_interval",
+        ],
+    ),
+    (
+        "reputation.weights",
+        &["auth_failure", "rate_limited", "rejected", "reported"],
+    ),
+    ("resume", &["enabled", "max_scan"]),
+    ("exclude", &["enabled", "max_values"]),
+    ("scheduler", &["enabled", "jitter", "jobs"]),
+    (
+        "webhooks",
+        &[
+            "enabled",
+            "hooks",
+            "queue",
+            "retries",
+            "timeout",
+            "min_backoff",
+            "max_backoff",
+        ],
+    ),
+    ("webhooks.hooks", &["url", "filter", "secret"]),
+    (
+        "mqtt",
+        &[
+            "enabled",
+            "url",
+            "client_id",
+            "topic",
+            "filter",
+            "qos",
+            "retain",
+            "keep_alive",
+            "max_backoff",
+        ],
+    ),
+    (
+        "firehose",
+        &[
+            "enabled",
+            "url",
+            "topic",
+            "partition",
+            "filter",
+            "batch",
+            "flush_interval",
+            "timeout",
+            "spill_path",
+            "max_spill",
+            "max_backoff",
+        ],
+    ),
+    (
+        "clickhouse",
+        &[
+            "enabled",
+            "url",
+            "database",
+            "table",
+            "user",
+            "password",
+            "interval",
+            "batch",
+            "cursor_path",
+            "max_backoff",
+        ],
+    ),
+    (
+        "pow",
+        &[
+            "enabled",
+            "min_difficulty",
+            "load_events",
+            "load_difficulty",
+            "soft_events",
+            "step",
+            "max_difficulty",
+        ],
+    ),
+    ("validation", &["enabled", "rules"]),
+    (
+        "validation.rules",
+        &[
+            "kinds",
+            "required_tags",
+            "content",
+            "max_tags",
+            "content_schema",
+            "tags_schema",
+        ],
+    ),
+    ("tenants", &["host", "path", "config"]),
+];
+
+/// Names of the extensions added by the relay command
+const EXTENSIONS: &[&str] = &[
+    "metrics",
+    "auth",
+    "rate_limiter",
+    "count",
+    "search",
+    "management",
+    "broadcast",
+    "mirror",
+    "cluster",
+    "replication",
+    "negentropy",
+    "groups",
+    "blossom",
+    "audit",
+    "vanish",
+    "reputation",
+    "pow",
+    "resume",
+    "exclude",
+    "scheduler",
+    "webhooks",
+    "mqtt",
+    "firehose",
+    "clickhouse",
+    "grpc",
+    "graphql",
+    "invite",
+    "onboarding",
+    "maintenance",
+    "validation",
+];
+
+/// Keys of each role, the role names are free
+const ROLE_KEYS: &[&str] = &[
+    "pubkeys",
+    "max_subscriptions",
+    "max_message_length",
+    "unlimited_rate",
+    "quota",
+];
+
+const PERMISSION_KEYS: &[&str] = &[
+    "ip_whitelist",
+    "pubkey_whitelist",
+    "ip_blacklist",
+    "pubkey_blacklist",
+    "event_pubkey_whitelist",
+    "event_pubkey_blacklist",
+];
+
+/// Config maintenance commands
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Check the config before starting the relay
+    Check(CheckOpts),
+    /// Print the default config with all options documented
+    Init(InitOpts),
+}
+
+/// config check options
+#[derive(Debug, Clone, Parser)]
+pub struct CheckOpts {
+    /// Nostr relay config path
+    #[arg(
+        short = 'c',
+        value_name = "PATH",
+        default_value = "./config/rnostr.toml"
+    )]
+    pub config: PathBuf,
+}
+
+/// config init options
+#[derive(Debug, Clone, Parser)]
+pub struct InitOpts {
+    /// write the config to the file instead of stdout
+    #[arg(value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// overwrite the file if it exists
+    #[arg(long, value_name = "BOOL")]
+    pub force: bool,
+}
+
+/// check the config, return the problems found
+pub fn check_opts(opts: CheckOpts) -> anyhow::Result<Vec<String>> {
+    Ok(check(&opts.config)?)
+}
+
+pub fn init_opts(opts: InitOpts) -> anyhow::Result<()> {
+    match opts.output {
+        Some(path) => {
+            if path.exists() && !opts.force {
+                return Err(Error::Message(format!(
+                    "{} already exists, use --force to overwrite it",
+                    path.display()
+                ))
+                .into());
+            }
+            fs::write(path, DEFAULT_CONFIG)?;
+        }
+        None => print!("{}", DEFAULT_CONFIG),
+    }
+    Ok(())
+}
+
+/// Check the config file with the same env overrides as the relay.
+/// Unknown keys, invalid values of the relay and extension settings, invalid pubkeys and IPs are reported as "key: problem".
+/// The tenant configs are checked too, their problems are prefixed with "tenants[index]".
+pub fn check(path: &PathBuf) -> Result<Vec<String>> {
+    let value = Setting::read_value(path, Some("RNOSTR".to_owned()))?;
+    let mut problems = check_value(&value);
+
+    if let Some(tenants) = parse::<Vec<Tenant>>(&value, "tenants", &mut problems) {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut data_paths = vec![data_path(&value)];
+        for (i, tenant) in tenants.iter().enumerate() {
+            let key = format!("tenants[{}]", i);
+            match &tenant.path {
+                Some(path) if !path.starts_with('/') => {
+                    problems.push(format!("{}.path: must start with /", key))
+                }
+                None if tenant.host.is_none() => {
+                    problems.push(format!("{}: requires host or path", key))
+                }
+                _ => {}
+            }
+            match Setting::read_value(dir.join(&tenant.config), None) {
+                Ok(value) => {
+                    problems.extend(
+                        check_value(&value)
+                            .into_iter()
+                            .map(|p| format!("{}: {}", key, p)),
+                    );
+                    if value.get("tenants").is_some() {
+                        problems.push(format!("{}: nested tenants are not supported", key));
+                    }
+                    let path = data_path(&value);
+                    if data_paths.contains(&path) {
+                        problems.push(format!("{}: data.path {:?} is already used", key, path));
+                    }
+                    data_paths.push(path);
+                }
+                Err(e) => problems.push(format!("{}.config: {}", key, e)),
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// the data path of the config, default when invalid
+fn data_path(value: &Value) -> PathBuf {
+    value
+        .get("data")
+        .and_then(|data| serde_json::from_value::<Data>(data.clone()).ok())
+        .unwrap_or_default()
+        .path
+}
+
+fn check_value(value: &Value) -> Vec<String> {
+    let mut problems = vec![];
+    unknown_keys(value, "", &mut problems);
+
+    let _: Option<Data> = parse(value, "data", &mut problems);
+    let _: Option<Thread> = parse(value, "thread", &mut problems);
+    let _: Option<Limitation> = parse(value, "limitation", &mut problems);
+    if let Some(metrics) = parse::<MetricsSetting>(value, "metrics", &mut problems) {
+        if let Some(list) = &metrics.admin_pubkeys {
+            check_list(
+                "metrics.admin_pubkeys",
+                list,
+                "pubkey",
+                valid_pubkey,
+                &mut problems,
+            );
+        }
+        let top = &metrics.top_talkers;
+        if top.enabled && (top.top == 0 || top.max_keys == 0) {
+            problems
+                .push("metrics.top_talkers: top and max_keys must be greater than 0".to_owned());
+        }
+    }
+    let _: Option<CountSetting> = parse(value, "count", &mut problems);
+    if let Some(search) = parse::<SearchSetting>(value, "search", &mut problems) {
+        let patterns = &search.patterns;
+        if (patterns.prefix || patterns.fuzzy) && patterns.min_len == 0 {
+            problems.push("search.patterns.min_len: must be greater than 0".to_owned());
+        }
+        if search.enabled && search.index.kinds.is_empty() {
+            problems.push("search.kinds: no kind is indexed".to_owned());
+        }
+        if search.ranking.enabled && search.ranking.candidates == 0 {
+            problems.push("search.ranking.candidates: must be greater than 0".to_owned());
+        }
+    }
+
+    if let Some(info) = parse::<Information>(value, "information", &mut problems) {
+        if let Some(pubkey) = &info.pubkey {
+            if !valid_pubkey(pubkey) {
+                problems.push(format!("information.pubkey: invalid pubkey {:?}", pubkey));
+            }
+        }
+    }
+
+    if let Some(roles) = value.get("roles").and_then(|v| v.as_object()) {
+        for (name, role) in roles {
+            let key = format!("roles.{}", name);
+            for k in role.as_object().into_iter().flat_map(|m| m.keys()) {
+                if !ROLE_KEYS.contains(&k.as_str()) {
+                    problems.push(format!("{}.{}: unknown key", key, k));
+                }
+            }
+            match serde_json::from_value::<nostr_relay::setting::Role>(role.clone()) {
+                Ok(role) => {
+                    for pubkey in &role.pubkeys {
+                        if !valid_pubkey(pubkey) {
+                            problems.push(format!("{}.pubkeys: invalid pubkey {:?}", key, pubkey));
+                        }
+                    }
+                }
+                Err(e) => problems.push(format!("{}: {}", key, e)),
+            }
+        }
+    }
+
+    if let Some(network) = parse::<Network>(value, "network", &mut problems) {
+        if network.heartbeat_timeout <= network.heartbeat_interval {
+            problems
+                .push("network.heartbeat_timeout: must bigger than heartbeat_interval".to_owned());
+        }
+        if let Some(proxy) = &network.proxy {
+            check_list(
+                "network.proxy",
+                std::slice::from_ref(proxy),
+                "socks5 proxy",
+                |s| s.starts_with("socks5://") || s.starts_with("socks5h://"),
+                &mut problems,
+            );
+        }
+    }
+
+    if let Some(extension) = parse::<ExtensionSetting>(value, "extension", &mut problems) {
+        check_list(
+            "extension.order",
+            &extension.order,
+            "extension",
+            |name| EXTENSIONS.contains(&name),
+            &mut problems,
+        );
+        check_list(
+            "extension.disabled",
+            &extension.disabled,
+            "extension",
+            |name| EXTENSIONS.contains(&name),
+            &mut problems,
+        );
+    }
+
+    if let Some(auth) = parse::<AuthSetting>(value, "auth", &mut problems) {
+        for (key, permission) in [("auth.req", &auth.req), ("auth.event", &auth.event)] {
+            if let Some(permission) = permission {
+                check_permission(key, permission, &mut problems);
+            }
+        }
+    }
+
+    if let Some(limiter) = parse::<RatelimiterSetting>(value, "rate_limiter", &mut problems) {
+        for (i, quota) in limiter.event.iter().enumerate() {
+            if let Some(list) = &quota.ip_whitelist {
+                let key = format!("rate_limiter.event[{}].ip_whitelist", i);
+                check_list(&key, list, "ip", valid_ip, &mut problems);
+            }
+        }
+    }
+
+    if let Some(management) = parse::<ManagementSetting>(value, "management", &mut problems) {
+        if let Some(list) = &management.admin_pubkeys {
+            check_list(
+                "management.admin_pubkeys",
+                list,
+                "pubkey",
+                valid_pubkey,
+                &mut problems,
+            );
+        }
+    }
+
+    if let Some(broadcast) = parse::<BroadcastSetting>(value, "broadcast", &mut problems) {
+        let urls = broadcast
+            .relays
+            .into_iter()
+            .map(|relay| relay.url)
+            .collect::<Vec<_>>();
+        check_list(
+            "broadcast.relays.url",
+            &urls,
+            "websocket url",
+            valid_ws_url,
+            &mut problems,
+        );
+    }
+
+    if let Some(mirror) = parse::<MirrorSetting>(value, "mirror", &mut problems) {
+        for relay in &mirror.relays {
+            if let Err(e) = relay.parse_filters() {
+                problems.push(format!("mirror.relays.filters: {}", e));
+            }
+        }
+        check_list(
+            "mirror.outbox.pubkeys",
+            &mirror.outbox.pubkeys,
+            "pubkey",
+            valid_pubkey,
+            &mut problems,
+        );
+        let urls = mirror
+            .relays
+            .into_iter()
+            .map(|relay| relay.url)
+            .collect::<Vec<_>>();
+        check_list(
+            "mirror.relays.url",
+            &urls,
+            "websocket url",
+            valid_ws_url,
+            &mut problems,
+        );
+        check_list(
+            "mirror.outbox.discovery",
+            &mirror.outbox.discovery,
+            "websocket url",
+            valid_ws_url,
+            &mut problems,
+        );
+    }
+
+    #[cfg(feature = "cluster")]
+    if let Some(cluster) = parse::<ClusterSetting>(value, "cluster", &mut problems) {
+        check_list(
+            "cluster.redis",
+            std::slice::from_ref(&cluster.redis),
+            "redis url",
+            |s| s.starts_with("redis://"),
+            &mut problems,
+        );
+    }
+    #[cfg(not(feature = "cluster"))]
+    not_built(value, "cluster", "\"cluster\"", &mut problems);
+
+    if let Some(replication) = parse::<ReplicationSetting>(value, "replication", &mut problems) {
+        if replication.enabled {
+            if replication.token.is_none() {
+                problems.push("replication.token: required".to_owned());
+            }
+            if replication.role == Role::Replica {
+                match &replication.primary {
+                    Some(primary) => check_list(
+                        "replication.primary",
+                        std::slice::from_ref(primary),
+                        "http url",
+                        valid_http_url,
+                        &mut problems,
+                    ),
+                    None => {
+                        problems.push("replication.primary: required by the replica".to_owned())
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(negentropy) = parse::<NegentropySetting>(value, "negentropy", &mut problems) {
+        for peer in &negentropy.peers {
+            if let Err(e) = peer.parse_filters() {
+                problems.push(format!("negentropy.peers.filters: {}", e));
+            }
+        }
+        let urls = negentropy
+            .peers
+            .into_iter()
+            .map(|peer| peer.url)
+            .collect::<Vec<_>>();
+        check_list(
+            "negentropy.peers.url",
+            &urls,
+            "websocket url",
+            valid_ws_url,
+            &mut problems,
+        );
+    }
+
+    if let Some(groups) = parse::<GroupsSetting>(value, "groups", &mut problems) {
+        if let Some(secret_key) = &groups.secret_key {
+            // same format as a pubkey
+            if !valid_pubkey(secret_key) {
+                problems.push("groups.secret_key: invalid secret key".to_owned());
+            }
+        }
+        if let Some(creators) = &groups.creators {
+            check_list(
+                "groups.creators",
+                creators,
+                "pubkey",
+                valid_pubkey,
+                &mut problems,
+            );
+        }
+        if !groups.roles.contains_key(&groups.creator_role) {
+            problems.push(format!(
+                "groups.creator_role: unknown role {:?}",
+                groups.creator_role
+            ));
+        }
+    }
+
+    #[cfg(feature = "blossom")]
+    if let Some(blossom) = parse::<BlossomSetting>(value, "blossom", &mut problems) {
+        if let Some(uploaders) = &blossom.uploaders {
+            check_list(
+                "blossom.uploaders",
+                uploaders,
+                "pubkey",
+                valid_pubkey,
+                &mut problems,
+            );
+        }
+        let urls = [
+            ("blossom.url", blossom.url),
+            ("blossom.s3.endpoint", blossom.s3.map(|s3| s3.endpoint)),
+        ];
+        for (key, url) in urls {
+            if let Some(url) = url {
+                check_list(key, &[url], "http url", valid_http_url, &mut problems);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "blossom"))]
+    not_built(value, "blossom", "\"blossom\"", &mut problems);
+
+    parse::<AuditSetting>(value, "audit", &mut problems);
+
+    if let Some(vanish) = parse::<VanishSetting>(value, "vanish", &mut problems) {
+        check_list(
+            "vanish.urls",
+            &vanish.urls,
+            "websocket url",
+            valid_ws_url,
+            &mut problems,
+        );
+    }
+
+    parse::<ReputationSetting>(value, "reputation", &mut problems);
+
+    parse::<PowSetting>(value, "pow", &mut problems);
+    parse::<ValidationSetting>(value, "validation", &mut problems);
+
+    parse::<ResumeSetting>(value, "resume", &mut problems);
+    parse::<ExcludeSetting>(value, "exclude", &mut problems);
+    parse::<SchedulerSetting>(value, "scheduler", &mut problems);
+
+    if let Some(webhooks) = parse::<WebhooksSetting>(value, "webhooks", &mut problems) {
+        let urls = webhooks
+            .hooks
+            .into_iter()
+            .map(|h| h.url)
+            .collect::<Vec<_>>();
+        check_list(
+            "webhooks.hooks.url",
+            &urls,
+            "http url",
+            valid_http_url,
+            &mut problems,
+        );
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt) = parse::<MqttSetting>(value, "mqtt", &mut problems) {
+        if !mqtt.url.starts_with("mqtt://") {
+            problems.push(format!("mqtt.url: invalid mqtt url {:?}", mqtt.url));
+        }
+        if mqtt.qos > 1 {
+            problems.push(format!("mqtt.qos: must be 0 or 1, got {}", mqtt.qos));
+        }
+    }
+    #[cfg(not(feature = "mqtt"))]
+    not_built(value, "mqtt", "\"mqtt\"", &mut problems);
+
+    #[cfg(any(feature = "firehose-nats", feature = "firehose-kafka"))]
+    if let Some(firehose) = parse::<FirehoseSetting>(value, "firehose", &mut problems) {
+        let sinks = [
+            ("nats", cfg!(feature = "firehose-nats")),
+            ("kafka", cfg!(feature = "firehose-kafka")),
+        ];
+        match sinks
+            .iter()
+            .find(|(scheme, _)| firehose.url.starts_with(&format!("{}://", scheme)))
+        {
+            Some((_, true)) => {}
+            Some((scheme, false)) => problems.push(format!(
+                "firehose.url: {} is not built, build with the \"firehose-{}\" feature",
+                scheme, scheme
+            )),
+            None => problems.push(format!(
+                "firehose.url: invalid nats or kafka url {:?}",
+                firehose.url
+            )),
+        }
+    }
+    #[cfg(not(any(feature = "firehose-nats", feature = "firehose-kafka")))]
+    not_built(
+        value,
+        "firehose",
+        "\"firehose-nats\" or \"firehose-kafka\"",
+        &mut problems,
+    );
+
+    if let Some(clickhouse) = parse::<ClickhouseSetting>(value, "clickhouse", &mut problems) {
+        check_list(
+            "clickhouse.url",
+            &[clickhouse.url],
+            "http url",
+            valid_http_url,
+            &mut problems,
+        );
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = parse::<GrpcSetting>(value, "grpc", &mut problems) {
+        let port = grpc.listen.rsplit_once(':').map(|(_, p)| p.parse::<u16>());
+        if !matches!(port, Some(Ok(_))) {
+            problems.push(format!("grpc.listen: invalid address {:?}", grpc.listen));
+        }
+    }
+    #[cfg(not(feature = "grpc"))]
+    not_built(value, "grpc", "\"grpc\"", &mut problems);
+
+    #[cfg(feature = "graphql")]
+    if let Some(graphql) = parse::<GraphqlSetting>(value, "graphql", &mut problems) {
+        if graphql.max_depth == 0 {
+            problems.push("graphql.max_depth: must be greater than 0".to_owned());
+        }
+    }
+    #[cfg(not(feature = "graphql"))]
+    not_built(value, "graphql", "\"graphql\"", &mut problems);
+
+    if let Some(invite) = parse::<InviteSetting>(value, "invite", &mut problems) {
+        if invite.tag.is_empty() {
+            problems.push("invite.tag: must not be empty".to_owned());
+        }
+    }
+
+    if let Some(onboarding) = parse::<OnboardingSetting>(value, "onboarding", &mut problems) {
+        if onboarding.nip05 && !onboarding.nip05_url.contains("{name}") {
+            problems.push("onboarding.nip05_url: must contain {name}".to_owned());
+        }
+    }
+
+    if let Some(maintenance) = parse::<MaintenanceSetting>(value, "maintenance", &mut problems) {
+        if maintenance.enabled && !maintenance.drop && maintenance.message.is_empty() {
+            problems.push("maintenance.message: must not be empty".to_owned());
+        }
+    }
+
+    problems
+}
+
+fn parse<T: DeserializeOwned>(value: &Value, key: &str, problems: &mut Vec<String>) -> Option<T> {
+    let value = value.get(key)?;
+    match serde_json::from_value(value.clone()) {
+        Ok(setting) => Some(setting),
+        Err(e) => {
+            problems.push(format!("{}: {}", key, e));
+            None
+        }
+    }
+}
+
+fn unknown_keys(value: &Value, path: &str, problems: &mut Vec<String>) {
+    let (Some(map), Some((_, known))) = (value.as_object(), KEYS.iter().find(|(p, _)| *p == path))
+    else {
+        return;
+    };
+    for (key, val) in map {
+        let child = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", path, key)
+        };
+        // the secret of the setting is read from the file
+        let name = key.strip_suffix("_file").unwrap_or(key);
+        if !known.contains(&name) {
+            problems.push(format!("{}: unknown key", child));
+            continue;
+        }
+        match val {
+            Value::Array(list) => list
+                .iter()
+                .for_each(|val| unknown_keys(val, &child, problems)),
+            val => unknown_keys(val, &child, problems),
+        }
+    }
+}
+
+fn check_permission(key: &str, permission: &Permission, problems: &mut Vec<String>) {
+    let lists = [
+        ("ip_whitelist", &permission.ip_whitelist, "ip"),
+        ("ip_blacklist", &permission.ip_blacklist, "ip"),
+        ("pubkey_whitelist", &permission.pubkey_whitelist, "pubkey"),
+        ("pubkey_blacklist", &permission.pubkey_blacklist, "pubkey"),
+        (
+            "event_pubkey_whitelist",
+            &permission.event_pubkey_whitelist,
+            "pubkey",
+        ),
+        (
+            "event_pubkey_blacklist",
+            &permission.event_pubkey_blacklist,
+            "pubkey",
+        ),
+    ];
+    for (name, list, kind) in lists {
+        if let Some(list) = list {
+            let valid = if kind == "ip" { valid_ip } else { valid_pubkey };
+            check_list(&format!("{}.{}", key, name), list, kind, valid, problems);
+        }
+    }
+}
+
+/// the extension is enabled but the feature of it is not built
+#[allow(dead_code)]
+fn not_built(value: &Value, key: &str, feature: &str, problems: &mut Vec<String>) {
+    if value.pointer(&format!("/{}/enabled", key)) == Some(&Value::Bool(true)) {
+        problems.push(format!(
+            "{}: enabled but not built, build with the {} feature",
+            key, feature
+        ));
+    }
+}
+
+fn check_list(
+    key: &str,
+    list: &[String],
+    kind: &str,
+    valid: fn(&str) -> bool,
+    problems: &mut Vec<String>,
+) {
+    for item in list.iter() {
+        if !valid(item) {
+            problems.push(format!("{}: invalid {} {:?}", key, kind, item));
+        }
+    }
+}
+
+/// the relay compares the lowercase hex of pubkeys
+fn valid_pubkey(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn valid_ws_url(s: &str) -> bool {
+    s.starts_with("ws://") || s.starts_with("wss://")
+}
+
+fn valid_http_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+fn valid_ip(s: &str) -> bool {
+    s.parse::<IpAddr>().is_ok()
+}
@@ -0,0 +1,423 @@
+use crate::{Error, Result};
+use clap::{Parser, Subcommand};
+use indicatif::ProgressBar;
+use nostr_db::{Db, Event, Filter};
+use rand::Rng;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Database maintenance commands
+#[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum DbCommands {
+    /// Verify the id and signature of stored events
+    #[command(arg_required_else_help = true)]
+    Verify(VerifyOpts),
+    /// Print statistics of the stored events and the lmdb storage
+    #[command(arg_required_else_help = true)]
+    Stats(StatsOpts),
+}
+
+/// verify options
+#[derive(Debug, Clone, Parser)]
+pub struct VerifyOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// [NIP-01](https://nips.be/1) Filter of the events to verify
+    #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
+    pub filter: Filter,
+
+    /// fraction of the events to verify, between 0 and 1
+    #[arg(long, value_name = "RATE", default_value_t = 1.0)]
+    pub sample: f64,
+
+    /// append the invalid events to this jsonl file and delete them from the database
+    #[arg(long, value_name = "PATH")]
+    pub quarantine: Option<PathBuf>,
+}
+
+/// stats options
+#[derive(Debug, Clone, Parser)]
+pub struct StatsOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// number of top authors to list
+    #[arg(long, value_name = "NUM", default_value_t = 10)]
+    pub top: usize,
+
+    /// print as json instead of tables
+    #[arg(long, value_name = "BOOL")]
+    pub json: bool,
+}
+
+/// Counts of a verify
+#[derive(Debug, Clone, Default)]
+pub struct VerifyStats {
+    pub scanned: u64,
+    pub checked: u64,
+    pub invalid: u64,
+    pub quarantined: u64,
+}
+
+impl Display for VerifyStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "scanned: {}, checked: {}, invalid: {}, quarantined: {}",
+            self.scanned, self.checked, self.invalid, self.quarantined
+        )
+    }
+}
+
+pub fn verify_opts(mut opts: VerifyOpts) -> anyhow::Result<VerifyStats> {
+    opts.filter.build_words();
+    let total_size = crate::count(&opts.path, &opts.filter)?;
+    let pb = ProgressBar::new(total_size);
+    let stats = verify(
+        &opts.path,
+        &opts.filter,
+        opts.sample,
+        opts.quarantine.as_deref(),
+        |stats| {
+            pb.set_position(stats.scanned);
+            pb.set_message(stats.to_string());
+        },
+    )?;
+    pb.finish_and_clear();
+    Ok(stats)
+}
+
+/// Verify the id hash and signature of the events matching the filter, print the invalid ones.
+pub fn verify<F: Fn(&VerifyStats)>(
+    path: &PathBuf,
+    filter: &Filter,
+    sample: f64,
+    quarantine: Option<&Path>,
+    f: F,
+) -> Result<VerifyStats> {
+    if !(0.0..=1.0).contains(&sample) {
+        return Err(Error::Message("sample must be between 0 and 1".to_owned()));
+    }
+
+    fn check(chunk: &mut Vec<Event>, stats: &mut VerifyStats, invalid: &mut Vec<Event>) {
+        stats.checked += chunk.len() as u64;
+        let bad = chunk
+            .par_drain(..)
+            .filter_map(
+                |event| match event.verify_id().and_then(|_| event.verify_sign()) {
+                    Ok(_) => None,
+                    Err(err) => Some((event, err)),
+                },
+            )
+            .collect::<Vec<_>>();
+        for (event, err) in bad {
+            println!("invalid {} {}", event.id_str(), err);
+            stats.invalid += 1;
+            invalid.push(event);
+        }
+    }
+
+    let db = Db::open(path)?;
+    let mut stats = VerifyStats::default();
+    let mut invalid = vec![];
+    {
+        let chunk_size = 10000;
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut rng = rand::thread_rng();
+        let reader = db.reader()?;
+        let iter = db.iter::<Event, _>(&reader, filter)?;
+        for event in iter {
+            let event = event?;
+            stats.scanned += 1;
+            if sample < 1.0 && !rng.gen_bool(sample) {
+                continue;
+            }
+            chunk.push(event);
+            if chunk.len() == chunk_size {
+                check(&mut chunk, &mut stats, &mut invalid);
+                f(&stats);
+            }
+        }
+        check(&mut chunk, &mut stats, &mut invalid);
+        f(&stats);
+    }
+
+    if let Some(quarantine) = quarantine {
+        if !invalid.is_empty() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(quarantine)?;
+            for event in &invalid {
+                writeln!(file, "{}", event)?;
+            }
+            file.flush()?;
+            db.batch_del(invalid.iter().map(|e| e.id()))?;
+            db.flush()?;
+            stats.quarantined = invalid.len() as u64;
+        }
+    }
+    Ok(stats)
+}
+
+/// Count and json size of events
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventCount {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl EventCount {
+    pub(crate) fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KindStats {
+    pub kind: u16,
+    #[serde(flatten)]
+    pub events: EventCount,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorStats {
+    pub pubkey: String,
+    #[serde(flatten)]
+    pub events: EventCount,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeStats {
+    pub name: &'static str,
+    pub entries: usize,
+    pub depth: u32,
+    pub pages: usize,
+    pub size: usize,
+}
+
+/// Size of the full-text search index
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchIndexStats {
+    /// indexed events
+    pub events: usize,
+    /// word entries of the indexed events
+    pub entries: usize,
+    pub size: usize,
+    /// size of the index relative to the event data
+    pub data_ratio: f64,
+}
+
+/// Page usage of the lmdb file
+#[derive(Debug, Clone, Serialize)]
+pub struct LmdbStats {
+    pub page_size: u32,
+    pub map_size: usize,
+    /// pages allocated in the file
+    pub used_pages: usize,
+    /// pages holding tree data, the rest is free for reuse
+    pub tree_pages: usize,
+    pub utilization: f64,
+}
+
+/// Statistics of a database
+#[derive(Debug, Clone, Serialize)]
+pub struct DbStats {
+    #[serde(flatten)]
+    pub events: EventCount,
+    pub oldest: Option<u64>,
+    pub newest: Option<u64>,
+    pub kinds: Vec<KindStats>,
+    pub top_authors_by_count: Vec<AuthorStats>,
+    pub top_authors_by_bytes: Vec<AuthorStats>,
+    pub trees: Vec<TreeStats>,
+    pub search: SearchIndexStats,
+    pub lmdb: LmdbStats,
+}
+
+impl DbStats {
+    pub fn print(&self) {
+        println!(
+            "Events: {}, bytes: {}, oldest: {}, newest: {}",
+            self.events.count,
+            self.events.bytes,
+            fmt_opt(self.oldest),
+            fmt_opt(self.newest)
+        );
+
+        println!("\n{:>8} {:>12} {:>14}", "kind", "count", "bytes");
+        for k in &self.kinds {
+            println!(
+                "{:>8} {:>12} {:>14}",
+                k.kind, k.events.count, k.events.bytes
+            );
+        }
+
+        for (title, authors) in [
+            ("top authors by count", &self.top_authors_by_count),
+            ("top authors by bytes", &self.top_authors_by_bytes),
+        ] {
+            println!("\n{:<64} {:>12} {:>14}", title, "count", "bytes");
+            for a in authors {
+                println!(
+                    "{:<64} {:>12} {:>14}",
+                    a.pubkey, a.events.count, a.events.bytes
+                );
+            }
+        }
+
+        println!(
+            "\n{:<12} {:>12} {:>6} {:>10} {:>14}",
+            "tree", "entries", "depth", "pages", "size"
+        );
+        for t in &self.trees {
+            println!(
+                "{:<12} {:>12} {:>6} {:>10} {:>14}",
+                t.name, t.entries, t.depth, t.pages, t.size
+            );
+        }
+
+        let s = &self.search;
+        println!(
+            "\nSearch index: events: {}, entries: {}, size: {}, {:.1}% of the event data",
+            s.events,
+            s.entries,
+            s.size,
+            s.data_ratio * 100.0
+        );
+
+        let l = &self.lmdb;
+        println!(
+            "\nLmdb: page size: {}, map size: {}, used pages: {}, tree pages: {}, utilization: {:.1}%",
+            l.page_size,
+            l.map_size,
+            l.used_pages,
+            l.tree_pages,
+            l.utilization * 100.0
+        );
+    }
+}
+
+fn fmt_opt(v: Option<u64>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_else(|| "-".to_owned())
+}
+
+pub fn stats_opts(opts: StatsOpts) -> anyhow::Result<DbStats> {
+    let stats = stats(&opts.path, opts.top)?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        stats.print();
+    }
+    Ok(stats)
+}
+
+/// Scan all events for the per kind and per author counts, and read the lmdb statistics.
+pub fn stats(path: &PathBuf, top: usize) -> Result<DbStats> {
+    let db = Db::open(path)?;
+    let (env, info) = db.env_stat()?;
+    let trees = db.tree_stats()?;
+
+    let mut events = EventCount::default();
+    let mut oldest = None;
+    let mut newest = None;
+    let mut kinds: HashMap<u16, EventCount> = HashMap::new();
+    let mut authors: HashMap<[u8; 32], EventCount> = HashMap::new();
+    {
+        let reader = db.reader()?;
+        let iter = db.iter::<String, _>(&reader, &Filter::default())?;
+        for json in iter {
+            let json = json?;
+            let event = Event::from_str(&json)?;
+            let time = event.created_at();
+            oldest = Some(oldest.map_or(time, |t: u64| t.min(time)));
+            newest = Some(newest.map_or(time, |t: u64| t.max(time)));
+            events.add(json.len());
+            kinds.entry(event.kind()).or_default().add(json.len());
+            authors.entry(*event.pubkey()).or_default().add(json.len());
+        }
+    }
+
+    let mut kinds = kinds
+        .into_iter()
+        .map(|(kind, events)| KindStats { kind, events })
+        .collect::<Vec<_>>();
+    kinds.sort_by(|a, b| {
+        b.events
+            .count
+            .cmp(&a.events.count)
+            .then(a.kind.cmp(&b.kind))
+    });
+
+    let top_authors = |key: fn(&EventCount) -> u64| {
+        let mut list = authors.iter().collect::<Vec<_>>();
+        list.sort_by(|a, b| key(b.1).cmp(&key(a.1)).then(a.0.cmp(b.0)));
+        list.into_iter()
+            .take(top)
+            .map(|(pubkey, events)| AuthorStats {
+                pubkey: hex::encode(pubkey),
+                events: events.clone(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let top_authors_by_count = top_authors(|e| e.count);
+    let top_authors_by_bytes = top_authors(|e| e.bytes);
+
+    // the main tree holds the names of the sub trees
+    let tree_pages = env.pages() + trees.iter().map(|(_, t)| t.pages()).sum::<usize>();
+    let used_pages = info.last_page + 1;
+    let tree = |name| trees.iter().find(|t| t.0 == name).map(|t| &t.1);
+    let search = match (tree("word"), tree("uid_word"), tree("data")) {
+        (Some(word), Some(uid_word), Some(data)) => {
+            let size = word.size() + uid_word.size();
+            SearchIndexStats {
+                events: uid_word.entries,
+                entries: word.entries,
+                size,
+                data_ratio: size as f64 / data.size().max(1) as f64,
+            }
+        }
+        _ => SearchIndexStats::default(),
+    };
+    let trees = trees
+        .into_iter()
+        .map(|(name, t)| TreeStats {
+            name,
+            entries: t.entries,
+            depth: t.depth,
+            pages: t.pages(),
+            size: t.size(),
+        })
+        .collect();
+
+    Ok(DbStats {
+        events,
+        oldest,
+        newest,
+        kinds,
+        top_authors_by_count,
+        top_authors_by_bytes,
+        trees,
+        search,
+        lmdb: LmdbStats {
+            page_size: env.page_size,
+            map_size: info.map_size,
+            used_pages,
+            tree_pages,
+            utilization: tree_pages as f64 / used_pages as f64,
+        },
+    })
+}
@@ -0,0 +1,132 @@
+use crate::Result;
+use futures_util::StreamExt;
+use nostr_db::Filter;
+use nostr_relay::{App, StreamMessage};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::{fs::FileTypeExt, net::UnixListener, net::UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// the lines waiting for the writer, the new lines are dropped when full
+const QUEUE: usize = 10000;
+
+/// a reader of the unix socket slower than it is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where the json lines of the accepted events are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirehoseTarget {
+    /// a file or a fifo, the fifo is opened again when the reader is gone
+    File(PathBuf),
+    /// a unix socket listening at the path, every connected client receives the lines
+    Unix(PathBuf),
+}
+
+impl FromStr for FirehoseTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty path".to_owned());
+        }
+        Ok(match s.strip_prefix("unix:") {
+            Some(path) => FirehoseTarget::Unix(path.into()),
+            None => FirehoseTarget::File(s.into()),
+        })
+    }
+}
+
+/// Write every accepted event as a json line to the target in real time.
+/// The lines are dropped while the reader is slower than the relay or not connected.
+pub fn firehose(app: &App, target: FirehoseTarget) -> Result<()> {
+    let (tx, rx) = mpsc::sync_channel::<String>(QUEUE);
+    match target {
+        FirehoseTarget::File(path) => {
+            info!("Firehose to {:?}", path);
+            thread::spawn(move || write_file(path, rx));
+        }
+        FirehoseTarget::Unix(path) => {
+            // remove the socket of the last run
+            if fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            info!("Firehose listening on {:?}", path);
+            let clients = Arc::new(Mutex::new(Vec::<UnixStream>::new()));
+            let accepted = clients.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                        accepted.lock().unwrap().push(stream);
+                    }
+                }
+            });
+            thread::spawn(move || {
+                for line in rx {
+                    clients
+                        .lock()
+                        .unwrap()
+                        .retain_mut(|stream| stream.write_all(line.as_bytes()).is_ok());
+                }
+            });
+        }
+    }
+
+    let app = app.clone();
+    actix_rt::spawn(async move {
+        let filter = Filter {
+            // skip the stored events
+            limit: Some(0),
+            ..Default::default()
+        };
+        let mut stream = match app.subscribe(vec![filter]).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!(error = err.to_string(), "firehose subscribe failed");
+                return;
+            }
+        };
+        while let Some(msg) = stream.next().await {
+            if let StreamMessage::Event(event) = msg {
+                let mut line = event.to_string();
+                line.push('\n');
+                if let Err(mpsc::TrySendError::Disconnected(_)) = tx.try_send(line) {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// append the lines to the file, open it again after a failed write
+fn write_file(path: PathBuf, rx: mpsc::Receiver<String>) {
+    let mut file: Option<File> = None;
+    for line in rx {
+        if file.is_none() {
+            // opening a fifo blocks until a reader is connected
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(f) => file = Some(f),
+                Err(err) => {
+                    warn!(error = err.to_string(), "firehose open failed");
+                    continue;
+                }
+            }
+        }
+        if let Some(f) = &mut file {
+            if let Err(err) = f.write_all(line.as_bytes()) {
+                if err.kind() != ErrorKind::BrokenPipe {
+                    warn!(error = err.to_string(), "firehose write failed");
+                }
+                file = None;
+            }
+        }
+    }
+}
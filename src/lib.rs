@@ -1,19 +1,52 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use clio::{Input, Output};
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use nostr_db::{Db, Event, Filter, FromEventData};
+use nostr_db::{now, CheckEventResult, Db, Event, Filter, FromEventData};
 use rayon::prelude::*;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, Write},
+    fmt::Display,
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, SyncSender},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+mod admin;
 mod bench;
+mod config;
+mod db;
+mod firehose;
+#[cfg(feature = "parquet")]
+mod parquet;
 mod relay;
+mod search;
+mod stats;
+mod sync;
+mod tail;
+mod throttle;
 
+pub use admin::*;
 pub use bench::*;
+pub use config::*;
+pub use db::*;
+pub use firehose::*;
+#[cfg(feature = "parquet")]
+pub use parquet::*;
 pub use relay::*;
+pub use search::*;
+pub use stats::*;
+pub use sync::*;
+pub use tail::*;
+pub use throttle::*;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -25,12 +58,27 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Clio(#[from] clio::Error),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    WebSocket(Box<tungstenite::Error>),
+    #[cfg(feature = "parquet")]
+    #[error(transparent)]
+    Parquet(#[from] ::parquet::errors::ParquetError),
     #[error("event error{0}")]
     Event(String),
     #[error("{0}")]
     Message(String),
 }
 
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(err))
+    }
+}
+
 pub type Result<T, E = Error> = core::result::Result<T, E>;
 
 /// import options
@@ -44,11 +92,91 @@ pub struct ImportOpts {
     #[arg(long, value_name = "BOOL")]
     pub search: bool,
 
-    /// input jsonl data file, use '-' for stdin
+    /// relay config file, the search words are split by its `search.tokenizer` options
+    #[arg(long, value_name = "PATH", requires = "search")]
+    pub config: Option<PathBuf>,
+
+    /// skip the signature verification of a trusted dump, the event ids are still checked
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// import into a running relay, commit after holding the write lock for the duration at most
+    /// and yield to the live writes of the relay adaptively
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, num_args = 0..=1, default_missing_value = "50ms")]
+    pub throttle: Option<Duration>,
+
+    /// threads parsing and verifying the events ahead of the single writer, all cores by default
+    #[arg(long, value_name = "NUM")]
+    pub threads: Option<usize>,
+
+    /// checkpoint file, an interrupted import resumes from it, it is removed when the import finishes
+    #[arg(long, value_name = "PATH")]
+    pub checkpoint: Option<PathBuf>,
+
+    /// decompress the input, by default it is detected from the ".gz" or ".zst" extension of the input file
+    #[arg(short = 'c', long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// import from a nostr-rs-relay sqlite database instead of the input file, hidden and expired events are skipped
+    #[arg(long, value_name = "PATH")]
+    pub from_sqlite: Option<PathBuf>,
+
+    /// input jsonl data file, such as the output of `rnostr export` or `strfry export`, use '-' for stdin
     #[clap(value_parser, default_value = "-")]
     pub input: Input,
 }
 
+/// Counts of an import
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportStats {
+    /// lines read from the input
+    pub lines: u64,
+    pub accepted: u64,
+    pub duplicate: u64,
+    pub invalid: u64,
+    /// deleted or replaced by a newer event
+    pub ignored: u64,
+}
+
+impl Display for ImportStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "accepted: {}, duplicate: {}, invalid: {}, ignored: {}",
+            self.accepted, self.duplicate, self.invalid, self.ignored
+        )
+    }
+}
+
+/// Committed position of an import
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// id of the last committed event
+    pub last_id: Option<String>,
+    pub stats: ImportStats,
+}
+
+impl Checkpoint {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// write to a temporary file and rename, so a crash never leaves a partial checkpoint
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let data = serde_json::to_vec(self)?;
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
 /// export options
 #[derive(Debug, Clone, Parser)]
 pub struct ExportOpts {
@@ -64,60 +192,266 @@ pub struct ExportOpts {
     #[arg(long, value_name = "BOOL")]
     pub desc: Option<bool>,
 
-    /// output jsonl data file, use '-' for stdout
-    #[clap(value_parser, default_value = "-")]
-    pub output: Output,
+    /// output format
+    #[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+    pub format: ExportFormat,
+
+    /// compress the output, by default it is detected from the ".gz" or ".zst" extension of the output file,
+    /// the parquet pages are compressed with zstd by default
+    #[arg(short = 'c', long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// max rows of a parquet row group
+    #[arg(long, value_name = "NUM", default_value = "100000")]
+    pub row_group: usize,
+
+    /// read the created_at ranges of the events in parallel read transactions, the output keeps the order,
+    /// a filter with a limit or a search is read by one thread
+    #[arg(long, value_name = "NUM", default_value_t = 1)]
+    pub threads: usize,
+
+    /// output jsonl data file, use '-' for stdout, or the output directory of the parquet files
+    #[arg(value_name = "OUTPUT", default_value = "-")]
+    pub output: PathBuf,
+}
+
+/// Format of the exported events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Jsonl,
+    /// files partitioned by the day and the kind, ie: "day=2023-05-01/kind=1/part-0.parquet"
+    Parquet,
+}
+
+/// Compression of the exported or imported jsonl data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// detect from the file extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// delete options
+#[derive(Debug, Clone, Parser)]
+pub struct DeleteOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// [NIP-01](https://nips.be/1) Filter of the events to delete
+    #[arg(short = 'f', long, value_name = "FILTER")]
+    pub filter: Filter,
+
+    /// number of events deleted in one transaction
+    #[arg(long, value_name = "NUM", default_value_t = 10000)]
+    pub batch: u64,
+
+    /// print the matching events as jsonl instead of deleting them
+    #[arg(long, value_name = "BOOL")]
+    pub dry_run: bool,
 }
 
 /// import
-pub fn import_opts(opts: ImportOpts) -> anyhow::Result<usize> {
-    fn run_import_opts<F: Fn(usize)>(opts: ImportOpts, f: F) -> anyhow::Result<usize> {
-        let count = import(&opts.path, opts.input, 10000, opts.search, f)?;
-        Ok(count)
+pub fn import_opts(opts: ImportOpts) -> anyhow::Result<ImportStats> {
+    if let Some(threads) = opts.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+    if let Some(config) = &opts.config {
+        set_search_options(config)?;
+    }
+
+    fn run_import_opts<F: Fn(&ImportStats) + Sync>(
+        opts: ImportOpts,
+        f: F,
+    ) -> anyhow::Result<ImportStats> {
+        let checkpoint = opts.checkpoint.as_deref();
+        let verify = !opts.no_verify;
+        let throttle = opts.throttle.map(Throttle::new);
+        let stats = if let Some(sqlite) = &opts.from_sqlite {
+            import_sqlite(
+                &opts.path,
+                sqlite,
+                10000,
+                opts.search,
+                verify,
+                throttle.clone(),
+                checkpoint,
+                f,
+            )?
+        } else {
+            let compress = opts
+                .compress
+                .unwrap_or_else(|| Compression::from_path(opts.input.path()));
+            import(
+                &opts.path,
+                opts.input,
+                compress,
+                10000,
+                opts.search,
+                verify,
+                throttle,
+                checkpoint,
+                f,
+            )?
+        };
+        Ok(stats)
     }
 
-    if matches!(opts.input, Input::File(_, _)) {
+    let total_size = if let Some(sqlite) = &opts.from_sqlite {
+        Some(count_sqlite(sqlite)?)
+    } else if matches!(opts.input, Input::File(_, _)) {
         let path = opts.input.path();
-        let total_size = count_lines(path)? as u64;
+        let compress = opts
+            .compress
+            .unwrap_or_else(|| Compression::from_path(path));
+        Some(count_lines(path, compress)? as u64)
+    } else {
+        None
+    };
+
+    if let Some(total_size) = total_size {
         let pb = create_pb(total_size);
-        let total = run_import_opts(opts, |c| {
-            if c % 1000 == 0 {
-                pb.set_position(c as u64);
-            }
+        let stats = run_import_opts(opts, |stats| {
+            pb.set_position(stats.lines);
+            pb.set_message(stats.to_string());
         })?;
-        pb.finish_with_message("finished");
-        Ok(total)
+        pb.finish();
+        Ok(stats)
     } else {
         run_import_opts(opts, |_| {})
     }
 }
 
-fn count_lines<P: AsRef<Path>>(path: P) -> std::io::Result<usize> {
+fn count_lines<P: AsRef<Path>>(path: P, compress: Compression) -> std::io::Result<usize> {
     let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    let reader = decompress(file, compress)?;
     let lines = reader.lines();
     Ok(lines.count())
 }
 
-pub fn import<F: Fn(usize)>(
+fn decompress<'a, R: Read + 'a>(
+    input: R,
+    compress: Compression,
+) -> std::io::Result<Box<dyn BufRead + 'a>> {
+    Ok(match compress {
+        Compression::None => Box::new(BufReader::new(input)),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(input))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(input)?)),
+    })
+}
+
+/// Import jsonl events, commit every `batch` lines.
+/// Lines that are not json objects are skipped, unknown fields of events such as the strfry "fried" data are ignored.
+/// With a checkpoint file, the lines committed by an interrupted import are skipped.
+/// The ids are always checked, the signatures only when `verify`.
+#[allow(clippy::too_many_arguments)]
+pub fn import<F: Fn(&ImportStats) + Sync>(
     path: &PathBuf,
     input: Input,
+    compress: Compression,
     batch: usize,
     search: bool,
+    verify: bool,
+    throttle: Option<Throttle>,
+    checkpoint: Option<&Path>,
     f: F,
-) -> Result<usize> {
+) -> Result<ImportStats> {
+    let lines = decompress(input, compress)?
+        .lines()
+        .map(|line| line.map_err(Error::from));
+    import_lines(path, lines, batch, search, verify, throttle, checkpoint, f)
+}
+
+/// events of a nostr-rs-relay database that are not hidden by deletion or expired
+const SQLITE_EVENTS: &str =
+    "FROM event WHERE hidden != TRUE AND (expires_at IS NULL OR expires_at > ?1)";
+
+fn count_sqlite(sqlite: &Path) -> Result<u64> {
+    let conn = Connection::open_with_flags(sqlite, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let count = conn.query_row(
+        &format!("SELECT COUNT(*) {}", SQLITE_EVENTS),
+        [now()],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+/// Import from a nostr-rs-relay sqlite database, its content column keeps the event json.
+#[allow(clippy::too_many_arguments)]
+pub fn import_sqlite<F: Fn(&ImportStats) + Sync>(
+    path: &PathBuf,
+    sqlite: &Path,
+    batch: usize,
+    search: bool,
+    verify: bool,
+    throttle: Option<Throttle>,
+    checkpoint: Option<&Path>,
+    f: F,
+) -> Result<ImportStats> {
+    let conn = Connection::open_with_flags(sqlite, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // order by the row id, so a checkpoint skips the same rows
+    let mut stmt = conn.prepare(&format!("SELECT content {} ORDER BY id", SQLITE_EVENTS))?;
+    let lines = stmt
+        .query_map([now()], |row| row.get::<_, String>(0))?
+        .map(|line| line.map_err(Error::from));
+    import_lines(path, lines, batch, search, verify, throttle, checkpoint, f)
+}
+
+/// Import json events of the lines, see [`import`].
+/// With a throttle, the transactions are committed by the time of holding the write lock too.
+#[allow(clippy::too_many_arguments)]
+pub fn import_lines<I, F>(
+    path: &PathBuf,
+    mut lines: I,
+    batch: usize,
+    search: bool,
+    verify: bool,
+    mut throttle: Option<Throttle>,
+    checkpoint: Option<&Path>,
+    f: F,
+) -> Result<ImportStats>
+where
+    I: Iterator<Item = Result<String>>,
+    F: Fn(&ImportStats) + Sync,
+{
     let db = Db::open(path)?;
     db.check_schema()?;
-    let reader = BufReader::new(input);
-    let lines = reader.lines();
-    let mut batches = vec![];
-    let mut count = 0;
+    let mut point = match checkpoint {
+        Some(path) => Checkpoint::load(path)?.unwrap_or_default(),
+        None => Checkpoint::default(),
+    };
+    for _ in 0..point.stats.lines {
+        if lines.next().transpose()?.is_none() {
+            break;
+        }
+    }
+    f(&point.stats);
 
-    fn parse_events(batches: &Vec<String>, search: bool) -> Vec<Event> {
+    fn parse_events(batches: &Vec<String>, search: bool, verify: bool) -> Vec<Option<Event>> {
         batches
             .par_iter()
-            .filter_map(|s| {
-                let event = Event::from_data(s.as_bytes());
+            .map(|s| {
+                let event = Event::from_data(s.as_bytes()).and_then(|event| {
+                    event.verify_id()?;
+                    if verify {
+                        event.verify_sign()?;
+                        event.verify_delegation()?;
+                    }
+                    Ok(event)
+                });
                 match event {
                     Ok(mut event) => {
                         if search {
@@ -133,42 +467,112 @@ pub fn import<F: Fn(usize)>(
             })
             .collect()
     }
-    let parse_batch = 30;
-    let mut writer = db.writer()?;
-    for item in lines.enumerate() {
-        let line = item.1?;
-        let index = item.0;
-        if index > 0 && index % parse_batch == 0 {
-            // batch write
-            // count += db.batch_put()?;
-            let events = parse_events(&batches, search);
-            for event in events {
-                db.put(&mut writer, event)?;
-                count += 1;
+    // the writer checks the throttle between the batches
+    let parse_batch = if throttle.is_some() { 100 } else { 1000 };
+    let point = thread::scope(|s| {
+        // (lines read, parsed events of the lines)
+        let (tx, rx) = mpsc::sync_channel::<Result<(usize, Vec<Option<Event>>)>>(4);
+        let (db, f) = (&db, &f);
+        // the single writer commits the batches in the order of the input,
+        // while the next batches are parsed and verified by the rayon threads
+        let writer = s.spawn(move || -> Result<Checkpoint> {
+            let mut uncommitted = 0;
+            let mut begin = Instant::now();
+            let mut writer = db.writer()?;
+            for parsed in rx {
+                let (read, events) = parsed?;
+                let stats = &mut point.stats;
+                for event in events {
+                    let Some(event) = event else {
+                        stats.invalid += 1;
+                        continue;
+                    };
+                    match db.put(&mut writer, &event)? {
+                        CheckEventResult::Ok(_) => stats.accepted += 1,
+                        CheckEventResult::Duplicate => stats.duplicate += 1,
+                        CheckEventResult::Invald(_) => stats.invalid += 1,
+                        CheckEventResult::Deleted
+                        | CheckEventResult::ReplaceIgnored
+                        | CheckEventResult::TakenDown => stats.ignored += 1,
+                    }
+                    point.last_id = Some(event.id_str());
+                }
+                point.stats.lines += read as u64;
+                uncommitted += read;
+                let expired = throttle.as_ref().is_some_and(|t| t.expired(begin));
+                if uncommitted >= batch || expired {
+                    db.commit(writer)?;
+                    if let Some(path) = checkpoint {
+                        point.save(path)?;
+                    }
+                    if let Some(throttle) = &throttle {
+                        thread::sleep(throttle.pause());
+                    }
+                    let start = Instant::now();
+                    writer = db.writer()?;
+                    if let Some(throttle) = &mut throttle {
+                        throttle.acquired(start.elapsed());
+                    }
+                    begin = Instant::now();
+                    uncommitted = 0;
+                }
+                f(&point.stats);
             }
+            db.commit(writer)?;
+            Ok(point)
+        });
+        let mut batches = Vec::with_capacity(parse_batch);
+        loop {
             batches.clear();
+            let mut read = 0;
+            let mut failed = None;
+            for line in lines.by_ref().take(parse_batch) {
+                match line {
+                    Ok(line) => {
+                        read += 1;
+                        if line.trim_start().starts_with('{') {
+                            batches.push(line);
+                        }
+                    }
+                    Err(err) => {
+                        failed = Some(err);
+                        break;
+                    }
+                }
+            }
+            if let Some(err) = failed {
+                // the uncommitted batches are aborted
+                let _ = tx.send(Err(err));
+                break;
+            }
+            if read == 0 {
+                break;
+            }
+            // the writer stopped by an error
+            if tx
+                .send(Ok((read, parse_events(&batches, search, verify))))
+                .is_err()
+            {
+                break;
+            }
         }
-        batches.push(line);
-        if index > 0 && index % batch == 0 {
-            db.commit(writer)?;
-            writer = db.writer()?;
+        drop(tx);
+        writer.join().expect("import writer panicked")
+    })?;
+    db.flush()?;
+    if let Some(path) = checkpoint {
+        if path.exists() {
+            fs::remove_file(path)?;
         }
-        f(index);
     }
-
-    db.commit(writer)?;
-
-    db.batch_put(parse_events(&batches, search))?;
-    count += batches.len();
-    db.flush()?;
-    Ok(count)
+    Ok(point.stats)
 }
 
 fn create_pb(total: u64) -> ProgressBar {
     let pb = ProgressBar::new(total);
     pb.set_style(
         ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})",
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} {per_sec} ({eta}) {msg}",
         )
         .unwrap()
         // .with_key("eta",  |state, w| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
@@ -189,11 +593,44 @@ pub fn export_opts(opts: ExportOpts) -> anyhow::Result<usize> {
         if let Some(desc) = opts.desc {
             opts.filter.desc = desc;
         }
-        let count = export(&opts.path, opts.output, &opts.filter, f)?;
+        #[cfg(feature = "parquet")]
+        if opts.format == ExportFormat::Parquet {
+            let compress = opts.compress.unwrap_or(Compression::Zstd);
+            let count = export_parquet(
+                &opts.path,
+                &opts.output,
+                &opts.filter,
+                compress,
+                opts.row_group,
+                f,
+            )?;
+            return Ok(count);
+        }
+        let compress = opts
+            .compress
+            .unwrap_or_else(|| Compression::from_path(&opts.output));
+        let count = export(
+            &opts.path,
+            Output::new(&opts.output)?,
+            &opts.filter,
+            compress,
+            opts.threads,
+            f,
+        )?;
         Ok(count)
     }
 
-    if matches!(opts.output, Output::File(_, _)) {
+    if opts.format == ExportFormat::Parquet {
+        if !cfg!(feature = "parquet") {
+            anyhow::bail!(
+                "the parquet export requires the relay built with the \"parquet\" feature"
+            );
+        }
+        if opts.output.as_os_str() == "-" {
+            anyhow::bail!("the parquet export requires an output directory");
+        }
+    }
+    if opts.output.as_os_str() != "-" {
         let total_size = count(&opts.path, &opts.filter)?;
         let pb = create_pb(total_size);
         let total = run_export_opts(opts, |c| {
@@ -215,23 +652,205 @@ pub fn count(path: &PathBuf, filter: &Filter) -> Result<u64> {
     Ok(iter.size()?.0)
 }
 
+/// Export the events matching the filter as jsonl,
+/// the created_at ranges are read by the threads and written in the order of the filter.
 pub fn export<F: Fn(usize)>(
     path: &PathBuf,
     mut output: Output,
     filter: &Filter,
+    compress: Compression,
+    threads: usize,
     f: F,
 ) -> Result<usize> {
+    let write_events = |db: &Db, filter: &Filter, output: &mut dyn Write, f: &F| {
+        if threads > 1 && filter.limit.is_none() && filter.search.is_none() {
+            write_events_parallel(db, filter, threads, output, f)
+        } else {
+            write_events(db, filter, output, f)
+        }
+    };
+    fn write_events<F: Fn(usize)>(
+        db: &Db,
+        filter: &Filter,
+        output: &mut dyn Write,
+        f: &F,
+    ) -> Result<usize> {
+        let reader = db.reader()?;
+        let iter = db.iter::<String, _>(&reader, filter)?;
+        let mut count = 0;
+        for event in iter {
+            count += 1;
+            let mut json: String = event?;
+            json.push('\n');
+            output.write_all(json.as_bytes())?;
+            f(count);
+        }
+        Ok(count)
+    }
+
     let db = Db::open(path)?;
+    let count = match compress {
+        Compression::None => write_events(&db, filter, &mut output, &f)?,
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(&mut output, flate2::Compression::default());
+            let count = write_events(&db, filter, &mut encoder, &f)?;
+            encoder.finish()?;
+            count
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(&mut output, 0)?;
+            let count = write_events(&db, filter, &mut encoder, &f)?;
+            encoder.finish()?;
+            count
+        }
+    };
+    output.finish()?;
+    Ok(count)
+}
+
+/// The created_at ranges of the events matching the filter, split in `count` parts at most
+fn time_ranges(db: &Db, filter: &Filter, count: u64) -> Result<Vec<(u64, u64)>> {
     let reader = db.reader()?;
-    let iter = db.iter::<String, _>(&reader, filter)?;
+    let first = |desc: bool| -> Result<Option<u64>> {
+        let mut filter = filter.clone();
+        filter.desc = desc;
+        filter.limit = Some(1);
+        let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+        Ok(iter.next().transpose()?.map(|e| e.created_at()))
+    };
+    let (Some(min), Some(max)) = (first(false)?, first(true)?) else {
+        return Ok(vec![]);
+    };
+    let span = max - min + 1;
+    let step = span.div_ceil(count.clamp(1, span));
+    let mut ranges = vec![];
+    let mut start = min;
+    while start <= max {
+        let end = start.saturating_add(step - 1).min(max);
+        ranges.push((start, end));
+        if end == u64::MAX {
+            break;
+        }
+        start = end + 1;
+    }
+    Ok(ranges)
+}
+
+/// the events of a range sent to the output at once
+const EXPORT_BATCH: usize = 1000;
+
+/// Read the time ranges in parallel, each by a read transaction of a thread,
+/// the output receives the ranges one by one in the order of the filter.
+fn write_events_parallel<F: Fn(usize)>(
+    db: &Db,
+    filter: &Filter,
+    threads: usize,
+    output: &mut dyn Write,
+    f: &F,
+) -> Result<usize> {
+    // more ranges than threads, so a dense range does not keep the others waiting
+    let mut ranges = time_ranges(db, filter, threads as u64 * 8)?;
+    if filter.desc {
+        ranges.reverse();
+    }
+    let (senders, receivers): (Vec<_>, Vec<_>) = ranges
+        .iter()
+        .map(|_| mpsc::sync_channel::<Result<Vec<String>>>(4))
+        .map(|(tx, rx)| (Mutex::new(Some(tx)), rx))
+        .unzip();
+    let next = AtomicUsize::new(0);
+    let read = |index: usize, tx: &SyncSender<Result<Vec<String>>>| -> Result<()> {
+        let (since, until) = ranges[index];
+        let mut filter = filter.clone();
+        filter.since = Some(since);
+        filter.until = Some(until);
+        let reader = db.reader()?;
+        let mut batch = Vec::with_capacity(EXPORT_BATCH);
+        for event in db.iter::<String, _>(&reader, &filter)? {
+            batch.push(event?);
+            if batch.len() == EXPORT_BATCH && tx.send(Ok(std::mem::take(&mut batch))).is_err() {
+                return Ok(());
+            }
+        }
+        let _ = tx.send(Ok(batch));
+        Ok(())
+    };
+    thread::scope(|s| {
+        for _ in 0..threads.min(ranges.len()) {
+            s.spawn(|| loop {
+                // the ranges are taken in order, so the range written next is always being read
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(tx) = senders.get(index).and_then(|tx| tx.lock().unwrap().take()) else {
+                    break;
+                };
+                if let Err(err) = read(index, &tx) {
+                    let _ = tx.send(Err(err));
+                }
+            });
+        }
+        let mut count = 0;
+        for rx in receivers {
+            for batch in rx {
+                for mut json in batch? {
+                    count += 1;
+                    json.push('\n');
+                    output.write_all(json.as_bytes())?;
+                    f(count);
+                }
+            }
+        }
+        Ok(count)
+    })
+}
+
+/// delete
+pub fn delete_opts(mut opts: DeleteOpts) -> anyhow::Result<u64> {
+    opts.filter.build_words();
+    if opts.dry_run {
+        let count = export(
+            &opts.path,
+            Output::new("-")?,
+            &opts.filter,
+            Compression::None,
+            1,
+            |_| {},
+        )?;
+        return Ok(count as u64);
+    }
+    let total_size = count(&opts.path, &opts.filter)?;
+    let pb = create_pb(total_size);
+    let total = delete(&opts.path, &opts.filter, opts.batch, |c| {
+        pb.set_position(c);
+    })?;
+    pb.finish_with_message("finished");
+    Ok(total)
+}
+
+/// Delete the events matching the filter and all their index entries, in batches of transactions.
+pub fn delete<F: Fn(u64)>(path: &PathBuf, filter: &Filter, batch: u64, f: F) -> Result<u64> {
+    let db = Db::open(path)?;
+    let mut filter = filter.clone();
+    let limit = filter.limit;
+    let batch = batch.max(1);
     let mut count = 0;
-    for event in iter {
-        count += 1;
-        let mut json: String = event?;
-        json.push('\n');
-        output.write_all(json.as_bytes())?;
+    loop {
+        // deleted events no longer match, so query the next batch from the start
+        let size = limit.map_or(batch, |l| batch.min(l - count));
+        if size == 0 {
+            break;
+        }
+        filter.limit = Some(size);
+        let ids = {
+            let reader = db.reader()?;
+            let iter = db.iter::<Vec<u8>, _>(&reader, &filter)?;
+            iter.collect::<Result<Vec<_>, _>>()?
+        };
+        if ids.is_empty() {
+            break;
+        }
+        db.batch_del(&ids)?;
+        count += ids.len() as u64;
         f(count);
     }
-    output.finish()?;
     Ok(count)
 }
@@ -19,12 +19,35 @@ enum Commands {
     /// Import data from jsonl file
     #[command(arg_required_else_help = true)]
     Import(ImportOpts),
-    /// Export data to jsonl file
+    /// Export data to jsonl file or parquet files
     #[command(arg_required_else_help = true)]
     Export(ExportOpts),
+    /// Delete events by filter
+    #[command(arg_required_else_help = true)]
+    Delete(DeleteOpts),
+    /// Sync events from a remote relay
+    #[command(arg_required_else_help = true)]
+    Sync(SyncOpts),
+    /// Print live events of a running relay
+    Tail(TailOpts),
     /// Benchmark filter
     #[command(arg_required_else_help = true)]
     Bench(BenchOpts),
+    /// Database maintenance
+    #[command(subcommand)]
+    Db(DbCommands),
+    /// Analytics of stored events
+    #[command(subcommand)]
+    Stats(StatsCommands),
+    /// Full-text search index maintenance
+    #[command(subcommand)]
+    Search(SearchCommands),
+    /// Manage a running relay
+    #[command(arg_required_else_help = true)]
+    Admin(AdminOpts),
+    /// Check or generate the relay config
+    #[command(subcommand)]
+    Config(ConfigCommands),
     /// Start nostr relay server
     Relay(RelayOpts),
 }
@@ -33,17 +56,66 @@ fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
     match args.command {
         Commands::Import(opts) => {
-            let total = import_opts(opts)?;
-            println!("imported {} events", total);
+            let stats = import_opts(opts)?;
+            println!("imported {} lines, {}", stats.lines, stats);
         }
         Commands::Export(opts) => {
             export_opts(opts)?;
         }
+        Commands::Delete(opts) => {
+            let dry_run = opts.dry_run;
+            let total = delete_opts(opts)?;
+            if dry_run {
+                eprintln!("would delete {} events", total);
+            } else {
+                println!("deleted {} events", total);
+            }
+        }
+        Commands::Sync(opts) => {
+            let stats = sync_opts(opts)?;
+            println!("synced {}", stats);
+        }
+        Commands::Tail(opts) => {
+            tail_opts(opts)?;
+        }
         Commands::Bench(opts) => {
             bench_opts(opts)?;
         }
+        Commands::Db(DbCommands::Verify(opts)) => {
+            let stats = verify_opts(opts)?;
+            println!("verified {}", stats);
+        }
+        Commands::Db(DbCommands::Stats(opts)) => {
+            stats_opts(opts)?;
+        }
+        Commands::Stats(StatsCommands::Top(opts)) => {
+            top_opts(opts)?;
+        }
+        Commands::Stats(StatsCommands::Series(opts)) => {
+            series_opts(opts)?;
+        }
+        Commands::Search(SearchCommands::Reindex(opts)) => {
+            let count = reindex_opts(opts)?;
+            println!("reindexed {} events", count);
+        }
+        Commands::Admin(opts) => {
+            admin_opts(opts)?;
+        }
+        Commands::Config(ConfigCommands::Check(opts)) => {
+            let problems = check_opts(opts)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    eprintln!("{}", problem);
+                }
+                anyhow::bail!("found {} problems in the config", problems.len());
+            }
+            println!("config ok");
+        }
+        Commands::Config(ConfigCommands::Init(opts)) => {
+            init_opts(opts)?;
+        }
         Commands::Relay(opts) => {
-            relay(&opts.config, opts.watch)?;
+            relay(&opts.config, opts.watch, opts.firehose)?;
         }
     }
     Ok(())
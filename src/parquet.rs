@@ -0,0 +1,282 @@
+//! Export the events to parquet files by the [parquet](https://crates.io/crates/parquet) crate,
+//! the columns are required.
+use crate::{Compression, Result};
+use nostr_db::{Db, Event, Filter};
+use parquet::{
+    basic::{Compression as Codec, GzipLevel, ZstdLevel},
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// flush all the buffered row groups when the buffered bytes exceed it
+const MAX_BUFFER: usize = 256 * 1024 * 1024;
+
+/// The columns of the exported events, the tags are a json array.
+/// The kind is not a column, it is read from the partition path like the day.
+const SCHEMA: &str = "
+message schema {
+    required binary id (UTF8);
+    required binary pubkey (UTF8);
+    required int64 created_at;
+    required binary tags (UTF8);
+    required binary content (UTF8);
+    required binary sig (UTF8);
+}
+";
+
+/// Append the written bytes to the file when flushed, so the file is not kept open
+struct Appender {
+    path: PathBuf,
+    buf: Vec<u8>,
+}
+
+impl Write for Appender {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            OpenOptions::new()
+                .append(true)
+                .open(&self.path)?
+                .write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Write the events of a partition to a parquet file, the row groups are appended
+/// to the file when flushed
+pub struct ParquetWriter {
+    writer: SerializedFileWriter<Appender>,
+    /// the values of the buffered rows by the column, except created_at
+    columns: [Vec<ByteArray>; 5],
+    created_at: Vec<i64>,
+    /// the bytes of the buffered values
+    buffered: usize,
+}
+
+impl ParquetWriter {
+    /// create the file, an existing file is truncated
+    pub fn create<P: AsRef<Path>>(path: P, compress: Compression) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(&path)?;
+        let codec = match compress {
+            Compression::None => Codec::UNCOMPRESSED,
+            Compression::Gzip => Codec::GZIP(GzipLevel::default()),
+            Compression::Zstd => Codec::ZSTD(ZstdLevel::default()),
+        };
+        let props = WriterProperties::builder()
+            .set_compression(codec)
+            .set_created_by(format!("rnostr version {}", env!("CARGO_PKG_VERSION")))
+            .build();
+        let writer = SerializedFileWriter::new(
+            Appender { path, buf: vec![] },
+            Arc::new(parse_message_type(SCHEMA)?),
+            Arc::new(props),
+        )?;
+        Ok(Self {
+            writer,
+            columns: Default::default(),
+            created_at: vec![],
+            buffered: 0,
+        })
+    }
+
+    /// buffer the event, call flush to write the row group
+    pub fn push(&mut self, event: &Event) -> Result<()> {
+        let values = [
+            event.id_str(),
+            event.pubkey_str(),
+            serde_json::to_string(event.tags())?,
+            event.content().to_owned(),
+            hex::encode(event.sig()),
+        ];
+        for (col, value) in self.columns.iter_mut().zip(values) {
+            self.buffered += value.len();
+            col.push(value.into_bytes().into());
+        }
+        self.created_at.push(event.created_at() as i64);
+        self.buffered += 8;
+        Ok(())
+    }
+
+    /// the buffered rows
+    pub fn rows(&self) -> usize {
+        self.created_at.len()
+    }
+
+    /// the bytes of the buffered rows
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// write the buffered rows as a row group
+    pub fn flush(&mut self) -> Result<()> {
+        if self.created_at.is_empty() {
+            return Ok(());
+        }
+        let mut group = self.writer.next_row_group()?;
+        let mut values = self.columns.iter_mut();
+        while let Some(mut col) = group.next_column()? {
+            match col.untyped() {
+                ColumnWriter::Int64ColumnWriter(w) => {
+                    w.write_batch(&self.created_at, None, None)?;
+                }
+                ColumnWriter::ByteArrayColumnWriter(w) => {
+                    if let Some(values) = values.next() {
+                        w.write_batch(values, None, None)?;
+                        values.clear();
+                    }
+                }
+                _ => {}
+            }
+            col.close()?;
+        }
+        group.close()?;
+        self.created_at.clear();
+        self.buffered = 0;
+        self.writer.flush()?;
+        self.writer.inner_mut().flush()?;
+        Ok(())
+    }
+
+    /// flush the buffered rows and write the footer
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+/// The UTC date of the unix time
+fn date(time: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (time / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// Export the events to parquet files partitioned by the day and the kind in the hive layout,
+/// ie: `<dir>/day=2023-05-01/kind=1/part-0.parquet`
+pub fn export_parquet<F: Fn(usize)>(
+    path: &PathBuf,
+    dir: &Path,
+    filter: &Filter,
+    compress: Compression,
+    row_group: usize,
+    f: F,
+) -> Result<usize> {
+    let db = Db::open(path)?;
+    let reader = db.reader()?;
+    let iter = db.iter::<Event, _>(&reader, filter)?;
+    let mut writers: HashMap<(String, u16), ParquetWriter> = HashMap::new();
+    let mut buffered = 0;
+    let mut count = 0;
+    for event in iter {
+        let event = event?;
+        let key = (date(event.created_at()), event.kind());
+        let writer = match writers.get_mut(&key) {
+            Some(writer) => writer,
+            None => {
+                let file = dir
+                    .join(format!("day={}", key.0))
+                    .join(format!("kind={}", key.1))
+                    .join("part-0.parquet");
+                writers
+                    .entry(key)
+                    .or_insert(ParquetWriter::create(file, compress)?)
+            }
+        };
+        let size = writer.buffered();
+        writer.push(&event)?;
+        buffered += writer.buffered() - size;
+        if writer.rows() >= row_group.max(1) {
+            buffered -= writer.buffered();
+            writer.flush()?;
+        }
+        if buffered > MAX_BUFFER {
+            for writer in writers.values_mut() {
+                writer.flush()?;
+            }
+            buffered = 0;
+        }
+        count += 1;
+        f(count);
+    }
+    for (_, writer) in writers {
+        writer.finish()?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use nostr_db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::RowAccessor,
+    };
+
+    #[test]
+    fn write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("kind=1/part-0.parquet");
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let events = (0..3)
+            .map(|i| {
+                let tags = vec![vec!["t".to_owned(), i.to_string()]];
+                Event::create(&key_pair, now(), 1, tags, format!("note {}", i))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut writer = ParquetWriter::create(&path, Compression::Zstd)?;
+        writer.push(&events[0])?;
+        writer.push(&events[1])?;
+        assert_eq!(writer.rows(), 2);
+        writer.flush()?;
+        assert_eq!((writer.rows(), writer.buffered()), (0, 0));
+        writer.push(&events[2])?;
+        writer.finish()?;
+
+        let reader = SerializedFileReader::new(File::open(&path)?)?;
+        let meta = reader.metadata();
+        assert_eq!(meta.num_row_groups(), 2);
+        assert_eq!(meta.file_metadata().num_rows(), 3);
+        let rows = reader.get_row_iter(None)?.collect::<Result<Vec<_>, _>>()?;
+        for (row, event) in rows.iter().zip(&events) {
+            assert_eq!(row.get_string(0)?, &event.id_str());
+            assert_eq!(row.get_long(2)?, event.created_at() as i64);
+            assert_eq!(row.get_string(3)?, &serde_json::to_string(event.tags())?);
+            assert_eq!(row.get_string(4)?, event.content());
+            assert_eq!(row.get_string(5)?, &hex::encode(event.sig()));
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
-use crate::Result;
+use crate::{firehose, Error, FirehoseTarget, Result};
 use clap::Parser;
 use nostr_relay::App;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
 /// Start relay options
@@ -18,27 +18,120 @@ pub struct RelayOpts {
     /// Auto reload when config changed
     #[arg(long, value_name = "BOOL")]
     pub watch: bool,
+
+    /// Write every accepted event as a json line in real time to the file or fifo,
+    /// or to the clients of a unix socket listening at the path with the "unix:" prefix, ie: "unix:/run/rnostr.sock"
+    #[arg(long, value_name = "PATH")]
+    pub firehose: Option<FirehoseTarget>,
 }
 
 #[actix_rt::main]
-pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
+pub async fn relay(
+    config: &PathBuf,
+    watch: bool,
+    firehose_target: Option<FirehoseTarget>,
+) -> Result<()> {
     tracing_subscriber::fmt::init();
     info!("Start relay server");
 
     // actix_rt::System::new().block_on(async {
     // });
 
-    let app_data = App::create(Some(config), watch, Some("RNOSTR".to_owned()), None)?;
-    let db = app_data.db.clone();
-    app_data
-        .add_extension(nostr_extensions::Metrics::new())
-        .add_extension(nostr_extensions::Auth::new())
-        .add_extension(nostr_extensions::Ratelimiter::new())
-        .add_extension(nostr_extensions::Count::new(db))
-        .add_extension(nostr_extensions::Search::new())
-        .web_server()?
-        .await?;
+    let app_data = App::builder()
+        .config(config)
+        .watch(watch)
+        .env_prefix("RNOSTR")
+        .build()?;
+    let app_data = add_extensions(app_data.add_extension(nostr_extensions::Metrics::new()));
+
+    // the tenants are read at starting
+    let tenants = app_data.setting.read().tenants.clone();
+    let dir = config.parent().unwrap_or(Path::new(""));
+    let mut data_paths = vec![app_data.setting.read().data.path.clone()];
+    let mut app_data = app_data;
+    for tenant in tenants {
+        let tenant_app = App::builder()
+            .config(dir.join(&tenant.config))
+            .watch(watch)
+            .build()?;
+        let path = tenant_app.setting.read().data.path.clone();
+        if data_paths.contains(&path) {
+            return Err(Error::Message(format!(
+                "the data path {:?} of the tenant {:?} is already used",
+                path, tenant.config
+            )));
+        }
+        data_paths.push(path);
+        app_data = app_data.add_tenant(tenant, add_extensions(tenant_app));
+    }
+
+    if let Some(target) = firehose_target {
+        firehose(&app_data, target)?;
+    }
+
+    app_data.web_server()?.await?;
     info!("Relay server shutdown");
 
     Ok(())
 }
+
+/// add the extensions of the relay, the metrics are served by the main relay only
+fn add_extensions(app: App) -> App {
+    let db = app.db.clone();
+    let mirror = nostr_extensions::Mirror::new(app.clone());
+    let replication = nostr_extensions::Replication::new(app.clone());
+    let negentropy = nostr_extensions::Negentropy::new(app.clone());
+    let groups = nostr_extensions::Groups::new(app.clone());
+    let scheduler = nostr_extensions::Scheduler::new(app.clone());
+    let webhooks = nostr_extensions::Webhooks::new(app.clone());
+    let clickhouse = nostr_extensions::Clickhouse::new(app.clone());
+    let app = app
+        .add_extension(nostr_extensions::ResumeTokens::new(db.clone()))
+        .add_extension(nostr_extensions::Excluder::new())
+        .add_extension(nostr_extensions::Maintenance::new())
+        .add_extension(nostr_extensions::Auth::new())
+        .add_extension(nostr_extensions::ReputationScores::new(db.clone()))
+        .add_extension(nostr_extensions::Ratelimiter::new())
+        .add_extension(nostr_extensions::Pow::new())
+        .add_extension(nostr_extensions::Validator::new())
+        .add_extension(nostr_extensions::Count::new(db.clone()))
+        .add_extension(nostr_extensions::Search::new())
+        .add_extension(nostr_extensions::Management::new(db.clone()))
+        .add_extension(nostr_extensions::InviteOnly::new(db.clone()))
+        .add_extension(nostr_extensions::Onboarding::new(db.clone()))
+        .add_extension(nostr_extensions::Broadcast::new())
+        .add_extension(mirror)
+        .add_extension(replication)
+        .add_extension(negentropy)
+        .add_extension(groups)
+        .add_extension(nostr_extensions::Audit::new())
+        .add_extension(nostr_extensions::Vanish::new(db))
+        .add_extension(scheduler)
+        .add_extension(webhooks)
+        .add_extension(clickhouse);
+    #[cfg(feature = "cluster")]
+    let app = {
+        let cluster = nostr_extensions::Cluster::new(app.clone());
+        app.add_extension(cluster)
+    };
+    #[cfg(feature = "blossom")]
+    let app = app.add_extension(nostr_extensions::Blossom::new());
+    #[cfg(any(feature = "firehose-nats", feature = "firehose-kafka"))]
+    let app = {
+        let firehose = nostr_extensions::Firehose::new(app.clone());
+        app.add_extension(firehose)
+    };
+    #[cfg(feature = "mqtt")]
+    let app = {
+        let mqtt = nostr_extensions::Mqtt::new(app.clone());
+        app.add_extension(mqtt)
+    };
+    #[cfg(feature = "grpc")]
+    let app = {
+        let grpc = nostr_extensions::Grpc::new(app.clone());
+        app.add_extension(grpc)
+    };
+    #[cfg(feature = "graphql")]
+    let app = app.add_extension(nostr_extensions::Graphql::new());
+    app
+}
@@ -0,0 +1,95 @@
+use crate::{create_pb, Result};
+use clap::{Parser, Subcommand};
+use nostr_db::Db;
+use nostr_extensions::search::SearchSetting;
+use std::{
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Full-text search index
+#[derive(Debug, Subcommand)]
+pub enum SearchCommands {
+    /// Rebuild the search index from the stored events
+    #[command(arg_required_else_help = true)]
+    Reindex(ReindexOpts),
+}
+
+/// reindex options
+#[derive(Debug, Clone, Parser)]
+pub struct ReindexOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// relay config file, the events are indexed by its `search` options
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// number of events indexed in one transaction
+    #[arg(long, value_name = "NUM", default_value_t = 1000)]
+    pub batch: usize,
+
+    /// max events indexed per second, 0 is unlimited. Keep it low on a running relay
+    #[arg(long, value_name = "NUM", default_value_t = 0)]
+    pub rate: u64,
+
+    /// keep the current index and replace the words of each event,
+    /// otherwise the index is cleared first and the events are not searchable until they are reindexed
+    #[arg(long, value_name = "BOOL")]
+    pub keep: bool,
+}
+
+/// Use the tokenizer and the index options of the relay config in this process
+pub fn set_search_options(config: &Path) -> Result<()> {
+    let setting = nostr_relay::Setting::read(config, Some("RNOSTR".to_owned()))?;
+    let search: SearchSetting = setting.parse_extension("search");
+    nostr_db::set_tokenizer(search.tokenizer);
+    nostr_db::set_index_options(search.index);
+    Ok(())
+}
+
+pub fn reindex_opts(opts: ReindexOpts) -> anyhow::Result<usize> {
+    if let Some(config) = &opts.config {
+        set_search_options(config)?;
+    }
+    let db = Db::open(&opts.path)?;
+    let total = db
+        .tree_stats()?
+        .into_iter()
+        .find(|(name, _)| *name == "index")
+        .map_or(0, |(_, stat)| stat.entries);
+    let pb = create_pb(total as u64);
+    let count = reindex(&db, &opts, |c| pb.set_position(c as u64))?;
+    pb.finish();
+    Ok(count)
+}
+
+/// Rebuild the search words of all events, the batches are throttled by the rate
+pub fn reindex<F: Fn(usize)>(db: &Db, opts: &ReindexOpts, f: F) -> Result<usize> {
+    if !opts.keep {
+        db.clear_words()?;
+    }
+    let batch = opts.batch.max(1);
+    let start = Instant::now();
+    let mut after = None;
+    let mut count = 0;
+    loop {
+        let (last, num) = db.reindex_words(after.as_deref(), batch)?;
+        if num == 0 {
+            break;
+        }
+        after = Some(last);
+        count += num;
+        f(count);
+        if opts.rate > 0 {
+            let expected = Duration::from_secs_f64(count as f64 / opts.rate as f64);
+            if let Some(wait) = expected.checked_sub(start.elapsed()) {
+                sleep(wait);
+            }
+        }
+    }
+    db.flush()?;
+    Ok(count)
+}
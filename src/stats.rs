@@ -0,0 +1,238 @@
+use crate::{EventCount, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use nostr_db::{now, Db, Event, Filter, TimeSeries};
+use serde::Serialize;
+use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+
+/// Analytics of stored events
+#[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum StatsCommands {
+    /// Aggregate recent events by author, kind or tag
+    #[command(arg_required_else_help = true)]
+    Top(TopOpts),
+    /// Print the rolled-up events per hour by kind, bytes per hour and new pubkeys per day
+    #[command(arg_required_else_help = true)]
+    Series(SeriesOpts),
+}
+
+/// Aggregate key of the top command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TopBy {
+    Author,
+    Kind,
+    /// the tag name and value, such as "t:nostr"
+    Tag,
+}
+
+/// top options
+#[derive(Debug, Clone, Parser)]
+pub struct TopOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// aggregate key
+    #[arg(long, value_enum, default_value_t = TopBy::Author)]
+    pub by: TopBy,
+
+    /// only count events created in this window before now, such as 1h, 7d
+    #[arg(short = 'w', long, value_name = "DURATION", default_value = "7d", value_parser = parse_duration)]
+    pub window: Duration,
+
+    /// [NIP-01](https://nips.be/1) Filter to narrow the events, such as '{"kinds":[1]}'
+    #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
+    pub filter: Filter,
+
+    /// number of rows
+    #[arg(short = 'n', long, value_name = "NUM", default_value_t = 20)]
+    pub limit: usize,
+
+    /// print as json instead of a table
+    #[arg(long, value_name = "BOOL")]
+    pub json: bool,
+}
+
+/// series options
+#[derive(Debug, Clone, Parser)]
+pub struct SeriesOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// only the hours and days starting in this window before now, such as 24h, 30d
+    #[arg(short = 'w', long, value_name = "DURATION", default_value = "7d", value_parser = parse_duration)]
+    pub window: Duration,
+
+    /// print as json instead of tables
+    #[arg(long, value_name = "BOOL")]
+    pub json: bool,
+}
+
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    duration_str::parse(s).map_err(|e| e.to_string())
+}
+
+/// A row of the top result
+#[derive(Debug, Clone, Serialize)]
+pub struct TopItem {
+    pub key: String,
+    #[serde(flatten)]
+    pub events: EventCount,
+    /// share of the events count in the window
+    pub share: f64,
+}
+
+/// Result of the top command
+#[derive(Debug, Clone, Serialize)]
+pub struct Top {
+    pub since: u64,
+    #[serde(flatten)]
+    pub events: EventCount,
+    /// number of distinct keys
+    pub keys: usize,
+    pub items: Vec<TopItem>,
+}
+
+impl Top {
+    pub fn print(&self) {
+        println!(
+            "Since: {}, events: {}, bytes: {}, keys: {}\n",
+            self.since, self.events.count, self.events.bytes, self.keys
+        );
+        let width = self
+            .items
+            .iter()
+            .map(|i| i.key.len())
+            .max()
+            .unwrap_or(0)
+            .max(3);
+        println!(
+            "{:<width$} {:>12} {:>14} {:>7}",
+            "key",
+            "count",
+            "bytes",
+            "share",
+            width = width
+        );
+        for item in &self.items {
+            println!(
+                "{:<width$} {:>12} {:>14} {:>6.2}%",
+                item.key,
+                item.events.count,
+                item.events.bytes,
+                item.share * 100.0,
+                width = width
+            );
+        }
+    }
+}
+
+pub fn top_opts(mut opts: TopOpts) -> anyhow::Result<Top> {
+    opts.filter.build_words();
+    let top = top(&opts.path, opts.by, opts.window, opts.filter, opts.limit)?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&top)?);
+    } else {
+        top.print();
+    }
+    Ok(top)
+}
+
+/// Count events created in the window by the key, sorted by the count descending.
+pub fn top(
+    path: &PathBuf,
+    by: TopBy,
+    window: Duration,
+    mut filter: Filter,
+    limit: usize,
+) -> Result<Top> {
+    let since = now().saturating_sub(window.as_secs());
+    filter.since = Some(filter.since.map_or(since, |s| s.max(since)));
+    let db = Db::open(path)?;
+    let mut total = EventCount::default();
+    let mut counts: HashMap<String, EventCount> = HashMap::new();
+    {
+        let reader = db.reader()?;
+        let iter = db.iter::<String, _>(&reader, &filter)?;
+        for json in iter {
+            let json = json?;
+            let event = Event::from_str(&json)?;
+            total.add(json.len());
+            match by {
+                TopBy::Author => counts
+                    .entry(event.pubkey_str())
+                    .or_default()
+                    .add(json.len()),
+                TopBy::Kind => counts
+                    .entry(event.kind().to_string())
+                    .or_default()
+                    .add(json.len()),
+                TopBy::Tag => {
+                    for tag in event.tags() {
+                        if tag.len() > 1 {
+                            counts
+                                .entry(format!("{}:{}", tag[0], tag[1]))
+                                .or_default()
+                                .add(json.len());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let keys = counts.len();
+    let mut items = counts.into_iter().collect::<Vec<_>>();
+    items.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(&b.0)));
+    let items = items
+        .into_iter()
+        .take(limit)
+        .map(|(key, events)| TopItem {
+            key,
+            share: events.count as f64 / total.count.max(1) as f64,
+            events,
+        })
+        .collect();
+    Ok(Top {
+        since: filter.since.unwrap_or(since),
+        events: total,
+        keys,
+        items,
+    })
+}
+
+pub fn series_opts(opts: SeriesOpts) -> anyhow::Result<TimeSeries> {
+    let db = Db::open(&opts.path)?;
+    let since = now().saturating_sub(opts.window.as_secs());
+    let series = db.time_series(&db.reader()?, since, u64::MAX)?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&series)?);
+    } else {
+        print_series(&series);
+    }
+    Ok(series)
+}
+
+fn print_series(series: &TimeSeries) {
+    println!("{:>12} {:>12} {:>14}  kinds", "hour", "events", "bytes");
+    for (hour, kinds) in &series.events {
+        let mut kinds = kinds.iter().collect::<Vec<_>>();
+        kinds.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        println!(
+            "{:>12} {:>12} {:>14}  {}",
+            hour,
+            kinds.iter().map(|k| k.1).sum::<u64>(),
+            series.bytes.get(hour).copied().unwrap_or_default(),
+            kinds
+                .iter()
+                .map(|(kind, count)| format!("{}:{}", kind, count))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    println!("\n{:>12} {:>12}", "day", "pubkeys");
+    for (day, count) in &series.pubkeys {
+        println!("{:>12} {:>12}", day, count);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{Error, Result};
+use clap::Parser;
+use indicatif::ProgressBar;
+use nostr_db::{now, CheckEventResult, Db, Event, Filter};
+use serde_json::{json, Map, Value};
+use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+use tungstenite::{connect, Message};
+
+/// sync options
+#[derive(Debug, Clone, Parser)]
+pub struct SyncOpts {
+    /// Remote relay url, such as wss://relay.example.com
+    #[arg(value_name = "URL")]
+    pub url: String,
+
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH", default_value = "data/events")]
+    pub path: PathBuf,
+
+    /// [NIP-01](https://nips.be/1) Filter of the events to sync, the until and limit are set by paging
+    #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}", value_parser = parse_filter)]
+    pub filter: Map<String, Value>,
+
+    /// max number of events requested in one REQ
+    #[arg(long, value_name = "NUM", default_value_t = 500)]
+    pub page: u64,
+}
+
+/// Counts of a sync
+#[derive(Debug, Clone, Default)]
+pub struct SyncStats {
+    /// number of REQ sent
+    pub pages: u64,
+    pub received: u64,
+    pub stored: u64,
+    pub duplicate: u64,
+    pub invalid: u64,
+    /// deleted or replaced by a newer event
+    pub ignored: u64,
+    /// until of the next page
+    pub until: u64,
+}
+
+impl Display for SyncStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pages: {}, received: {}, stored: {}, duplicate: {}, invalid: {}, ignored: {}",
+            self.pages, self.received, self.stored, self.duplicate, self.invalid, self.ignored
+        )
+    }
+}
+
+/// keep the filter json to modify the until and limit, it must be a valid filter
+pub(crate) fn parse_filter(s: &str) -> Result<Map<String, Value>, String> {
+    Filter::from_str(s).map_err(|e| e.to_string())?;
+    serde_json::from_str(s).map_err(|e| e.to_string())
+}
+
+pub fn sync_opts(opts: SyncOpts) -> anyhow::Result<SyncStats> {
+    let pb = ProgressBar::new_spinner();
+    pb.enable_steady_tick(Duration::from_millis(100));
+    let stats = sync(&opts.path, &opts.url, &opts.filter, opts.page, |stats| {
+        pb.set_message(format!("until: {}, {}", stats.until, stats));
+    })?;
+    pb.finish_and_clear();
+    Ok(stats)
+}
+
+/// Sync events from a remote relay, page backward by created_at from the filter until (or now) to since.
+/// Events are verified before they are stored, existing events are skipped.
+pub fn sync<F: Fn(&SyncStats)>(
+    path: &PathBuf,
+    url: &str,
+    filter: &Map<String, Value>,
+    page: u64,
+    f: F,
+) -> Result<SyncStats> {
+    let db = Db::open(path)?;
+    db.check_schema()?;
+    let (mut socket, _) = connect(url)?;
+    let mut stats = SyncStats {
+        until: filter
+            .get("until")
+            .and_then(Value::as_u64)
+            .unwrap_or_else(now),
+        ..Default::default()
+    };
+    let page = page.max(1);
+
+    loop {
+        let mut filter = filter.clone();
+        filter.insert("until".to_owned(), stats.until.into());
+        filter.insert("limit".to_owned(), page.into());
+        let sub_id = format!("sync{}", stats.pages);
+        socket.send(Message::Text(json!(["REQ", sub_id, filter]).to_string()))?;
+        stats.pages += 1;
+
+        let mut events = vec![];
+        loop {
+            let text = match socket.read()? {
+                Message::Text(text) => text,
+                Message::Close(_) => return Err(Error::Message("connection closed".to_owned())),
+                _ => continue,
+            };
+            let msg: Vec<Value> = serde_json::from_str(&text)?;
+            match (msg.first().and_then(Value::as_str), msg.get(1)) {
+                (Some("EVENT"), Some(id)) if id == &sub_id => {
+                    stats.received += 1;
+                    match msg.get(2).cloned().map(serde_json::from_value::<Event>) {
+                        Some(Ok(event)) if event.validate(now(), 0, 0).is_ok() => {
+                            events.push(event)
+                        }
+                        _ => stats.invalid += 1,
+                    }
+                }
+                (Some("EOSE"), Some(id)) if id == &sub_id => break,
+                (Some("CLOSED"), Some(id)) if id == &sub_id => {
+                    return Err(Error::Message(format!("subscription closed: {}", text)));
+                }
+                (Some("NOTICE"), Some(notice)) => {
+                    eprintln!("notice: {}", notice);
+                }
+                _ => {}
+            }
+        }
+        socket.send(Message::Text(json!(["CLOSE", sub_id]).to_string()))?;
+
+        let Some(oldest) = events.iter().map(|e| e.created_at()).min() else {
+            break;
+        };
+        let mut writer = db.writer()?;
+        for event in &events {
+            match db.put(&mut writer, event)? {
+                CheckEventResult::Ok(_) => stats.stored += 1,
+                CheckEventResult::Duplicate => stats.duplicate += 1,
+                CheckEventResult::Invald(_) => stats.invalid += 1,
+                CheckEventResult::Deleted
+                | CheckEventResult::ReplaceIgnored
+                | CheckEventResult::TakenDown => stats.ignored += 1,
+            }
+        }
+        db.commit(writer)?;
+
+        // the until is inclusive, keep the oldest second for the events beyond the page,
+        // move on when the whole page is in the same second
+        if oldest < stats.until {
+            stats.until = oldest;
+        } else if stats.until > 0 {
+            stats.until -= 1;
+        } else {
+            break;
+        }
+        f(&stats);
+    }
+    let _ = socket.close(None);
+    db.flush()?;
+    Ok(stats)
+}
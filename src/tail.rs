@@ -0,0 +1,94 @@
+use crate::{sync::parse_filter, Error, Result};
+use clap::Parser;
+use console::style;
+use nostr_db::Event;
+use serde_json::{json, Map, Value};
+use tungstenite::{connect, Message};
+
+/// tail options
+#[derive(Debug, Clone, Parser)]
+pub struct TailOpts {
+    /// Relay url
+    #[arg(value_name = "URL", default_value = "ws://127.0.0.1:8080")]
+    pub url: String,
+
+    /// [NIP-01](https://nips.be/1) Filter, only new events are printed unless it has a limit
+    #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}", value_parser = parse_filter)]
+    pub filter: Map<String, Value>,
+
+    /// print the raw event json
+    #[arg(long, value_name = "BOOL")]
+    pub raw: bool,
+}
+
+pub fn tail_opts(opts: TailOpts) -> anyhow::Result<()> {
+    tail(&opts.url, opts.filter, |event| {
+        if opts.raw {
+            println!("{}", event);
+        } else {
+            println!("{}", fmt_event(event));
+        }
+    })?;
+    Ok(())
+}
+
+/// Subscribe to the relay and call `f` with each event until the connection is closed.
+pub fn tail<F: Fn(&Event)>(url: &str, mut filter: Map<String, Value>, f: F) -> Result<()> {
+    let (mut socket, _) = connect(url)?;
+    filter.entry("limit").or_insert_with(|| 0.into());
+    let sub_id = "tail";
+    socket.send(Message::Text(json!(["REQ", sub_id, filter]).to_string()))?;
+
+    loop {
+        let text = match socket.read()? {
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+        let msg: Vec<Value> = serde_json::from_str(&text)?;
+        match (msg.first().and_then(Value::as_str), msg.get(1)) {
+            (Some("EVENT"), Some(id)) if id == sub_id => {
+                if let Some(event) = msg.get(2) {
+                    match serde_json::from_value::<Event>(event.clone()) {
+                        Ok(event) => f(&event),
+                        Err(e) => eprintln!("{} {} {}", style("invalid").red(), e, event),
+                    }
+                }
+            }
+            (Some("EOSE"), Some(id)) if id == sub_id => {
+                eprintln!("{}", style("-- end of stored events --").dim());
+            }
+            (Some("CLOSED"), Some(id)) if id == sub_id => {
+                return Err(Error::Message(format!("subscription closed: {}", text)));
+            }
+            (Some("NOTICE"), Some(notice)) => {
+                eprintln!("{} {}", style("notice").yellow(), notice);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// One line: time, kind, short pubkey and the first line of the content
+fn fmt_event(event: &Event) -> String {
+    let time = event.created_at();
+    let pubkey = event.pubkey_str();
+    let content = event.content().lines().next().unwrap_or_default();
+    let content = match content.char_indices().nth(120) {
+        Some((i, _)) => format!("{}…", &content[..i]),
+        None => content.to_owned(),
+    };
+    format!(
+        "{} {} {} {}",
+        style(format!(
+            "{:02}:{:02}:{:02}",
+            time / 3600 % 24,
+            time / 60 % 60,
+            time % 60
+        ))
+        .dim(),
+        style(format!("{:>5}", event.kind())).cyan(),
+        style(&pubkey[..8]).green(),
+        content
+    )
+}
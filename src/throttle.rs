@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+/// the write lock was held by another writer, ie: the relay, when the wait is longer
+const CONTENDED: Duration = Duration::from_millis(2);
+const MIN_HOLD: Duration = Duration::from_millis(5);
+const MAX_PAUSE: Duration = Duration::from_secs(2);
+
+/// Adaptive throttle of an import into a running relay, the database has a single write lock,
+/// the live events queue in the relay writer while the import holds it.
+/// The import commits when it has held the lock for the hold time, the hold time halves and the pause
+/// before the next transaction doubles when acquiring the lock waited for the relay, both recover when it did not.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    max_hold: Duration,
+    hold: Duration,
+    pause: Duration,
+}
+
+impl Throttle {
+    /// hold the write lock for `max_hold` at most
+    pub fn new(max_hold: Duration) -> Self {
+        let max_hold = max_hold.max(MIN_HOLD);
+        Self {
+            max_hold,
+            hold: max_hold,
+            pause: Duration::ZERO,
+        }
+    }
+
+    /// The transaction began at the time has held the lock long enough
+    pub fn expired(&self, begin: Instant) -> bool {
+        begin.elapsed() >= self.hold
+    }
+
+    /// The time to sleep before the next transaction, the relay writer takes the lock meanwhile
+    pub fn pause(&self) -> Duration {
+        self.pause
+    }
+
+    /// Adapt to the time waited for the write lock
+    pub fn acquired(&mut self, wait: Duration) {
+        if wait > CONTENDED {
+            self.hold = (self.hold / 2).max(MIN_HOLD);
+            self.pause = (self.pause * 2).max(wait).min(MAX_PAUSE);
+        } else {
+            self.hold = (self.hold + MIN_HOLD).min(self.max_hold);
+            self.pause /= 2;
+            if self.pause < Duration::from_millis(1) {
+                self.pause = Duration::ZERO;
+            }
+        }
+    }
+}